@@ -17,9 +17,12 @@ impl std::fmt::Debug for HexBytes {
     }
 }
 
+type EncodeFn = Box<dyn Fn(&dyn Any) -> anyhow::Result<Vec<u8>> + Sync + Send>;
+type DecodeFn = Box<dyn Fn(&[u8]) -> anyhow::Result<GoveeBlePacket> + Sync + Send>;
+
 pub struct PacketCodec {
-    encode: Box<dyn Fn(&dyn Any) -> anyhow::Result<Vec<u8>> + Sync + Send>,
-    decode: Box<dyn Fn(&[u8]) -> anyhow::Result<GoveeBlePacket> + Sync + Send>,
+    encode: EncodeFn,
+    decode: DecodeFn,
     supported_skus: &'static [&'static str],
     type_id: TypeId,
 }
@@ -51,16 +54,16 @@ pub struct PacketManager {
 }
 
 impl PacketManager {
-    fn map_for_sku(&self, sku: &str) -> MappedMutexGuard<HashMap<TypeId, Arc<PacketCodec>>> {
+    fn map_for_sku(&self, sku: &str) -> MappedMutexGuard<'_, HashMap<TypeId, Arc<PacketCodec>>> {
         MutexGuard::map(self.codec_by_sku.lock(), |codecs| {
             codecs.entry(sku.to_string()).or_insert_with(|| {
                 let mut map = HashMap::new();
 
                 for codec in &self.all_codecs {
-                    if codec.supported_skus.iter().any(|s| *s == sku) {
-                        if map.insert(codec.type_id.clone(), codec.clone()).is_some() {
-                            eprintln!("Conflicting PacketCodecs for {sku} {:?}", codec.type_id);
-                        }
+                    if codec.supported_skus.contains(&sku)
+                        && map.insert(codec.type_id, codec.clone()).is_some()
+                    {
+                        eprintln!("Conflicting PacketCodecs for {sku} {:?}", codec.type_id);
                     }
                 }
 
@@ -229,6 +232,17 @@ impl PacketManager {
             SetSceneCode::decode,
         ));
 
+        all_codecs.push(PacketCodec::new(
+            &["Generic:Light"],
+            SetSegmentColor::encode,
+            |_| anyhow::bail!("SetSegmentColor decode is not implemented"),
+        ));
+        all_codecs.push(PacketCodec::new(
+            &["Generic:Light"],
+            SetSegmentBrightness::encode,
+            |_| anyhow::bail!("SetSegmentBrightness decode is not implemented"),
+        ));
+
         all_codecs.push(packet!(
             &["Generic:Light"],
             SetDevicePower,
@@ -252,7 +266,7 @@ pub trait DecodePacketParam {
 
 impl DecodePacketParam for u8 {
     fn decode_param<'a>(&mut self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
-        *self = *data.get(0).ok_or_else(|| anyhow!("EOF"))?;
+        *self = *data.first().ok_or_else(|| anyhow!("EOF"))?;
         Ok(&data[1..])
     }
 
@@ -263,7 +277,7 @@ impl DecodePacketParam for u8 {
 
 impl DecodePacketParam for u16 {
     fn decode_param<'a>(&mut self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
-        let lo = *data.get(0).ok_or_else(|| anyhow!("EOF"))?;
+        let lo = *data.first().ok_or_else(|| anyhow!("EOF"))?;
         let hi = *data.get(1).ok_or_else(|| anyhow!("EOF"))?;
         *self = ((hi as u16) << 8) | lo as u16;
         Ok(&data[2..])
@@ -286,14 +300,14 @@ pub struct SetHumidifierNightlightParams {
     pub brightness: u8,
 }
 
-impl Into<SetHumidifierNightlightParams> for NotifyHumidifierNightlightParams {
-    fn into(self) -> SetHumidifierNightlightParams {
+impl From<NotifyHumidifierNightlightParams> for SetHumidifierNightlightParams {
+    fn from(val: NotifyHumidifierNightlightParams) -> Self {
         SetHumidifierNightlightParams {
-            on: self.on,
-            r: self.r,
-            g: self.g,
-            b: self.b,
-            brightness: self.brightness,
+            on: val.on,
+            r: val.r,
+            g: val.g,
+            b: val.b,
+            brightness: val.brightness,
         }
     }
 }
@@ -312,9 +326,9 @@ pub struct NotifyHumidifierNightlightParams {
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TargetHumidity(u8);
 
-impl Into<u8> for TargetHumidity {
-    fn into(self) -> u8 {
-        self.0
+impl From<TargetHumidity> for u8 {
+    fn from(val: TargetHumidity) -> Self {
+        val.0
     }
 }
 
@@ -417,6 +431,59 @@ impl SetSceneCode {
     }
 }
 
+/// Sets the color of some of the segments of an RGBIC device
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetSegmentColor {
+    /// The zero-based segment numbers
+    pub segments: Vec<u32>,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl SetSegmentColor {
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0x33, 0x05, 0x15, 0x01, self.r, self.g, self.b];
+        data.resize(12, 0);
+        data.append(&mut segment_mask(&self.segments, 19 - data.len())?);
+        Ok(finish(data))
+    }
+}
+
+/// Sets the brightness of some of the segments of an RGBIC device
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetSegmentBrightness {
+    /// The zero-based segment numbers
+    pub segments: Vec<u32>,
+    pub percent: u8,
+}
+
+impl SetSegmentBrightness {
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0x33, 0x05, 0x15, 0x02, self.percent];
+        data.append(&mut segment_mask(&self.segments, 19 - data.len())?);
+        Ok(finish(data))
+    }
+}
+
+/// Encodes a set of segment numbers as a little-endian bitmask of at
+/// least two bytes, which is how the segment packets address segments
+fn segment_mask(segments: &[u32], max_len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut mask = vec![0u8; 2];
+    for &segment in segments {
+        let byte = (segment / 8) as usize;
+        anyhow::ensure!(
+            byte < max_len,
+            "segment {segment} cannot be addressed by this packet"
+        );
+        if byte >= mask.len() {
+            mask.resize(byte + 1, 0);
+        }
+        mask[byte] |= 1 << (segment % 8);
+    }
+    Ok(mask)
+}
+
 #[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub struct SetDevicePower {
     pub on: bool,
@@ -478,7 +545,7 @@ impl<'de> Deserialize<'de> for Base64HexBytes {
 fn calculate_checksum(data: &[u8]) -> u8 {
     let mut checksum: u8 = 0;
     for &b in data {
-        checksum = checksum ^ b;
+        checksum ^= b;
     }
     checksum
 }
@@ -579,6 +646,46 @@ mod test {
         );
     }
 
+    #[test]
+    fn segment_commands() {
+        let color = Base64HexBytes::encode_for_sku(
+            "Generic:Light",
+            &SetSegmentColor {
+                segments: vec![0, 2, 9],
+                r: 0xff,
+                g: 0x80,
+                b: 0x00,
+            },
+        )
+        .unwrap();
+        k9::assert_equal!(
+            format!("{:02x?}", color.0 .0),
+            "[33, 05, 15, 01, ff, 80, 00, 00, 00, 00, 00, 00, 05, 02, 00, 00, 00, 00, 00, 5a]"
+        );
+
+        let brightness = Base64HexBytes::encode_for_sku(
+            "Generic:Light",
+            &SetSegmentBrightness {
+                segments: vec![1, 20],
+                percent: 50,
+            },
+        )
+        .unwrap();
+        k9::assert_equal!(
+            format!("{:02x?}", brightness.0 .0),
+            "[33, 05, 15, 02, 32, 02, 00, 10, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 01]"
+        );
+
+        assert!(Base64HexBytes::encode_for_sku(
+            "Generic:Light",
+            &SetSegmentColor {
+                segments: vec![56],
+                ..Default::default()
+            },
+        )
+        .is_err());
+    }
+
     #[test]
     fn scene_command() {
         const FOREST_SCENCE_PARAM: &str = "AyYAAQAKAgH/GQG0CgoCyBQF//8AAP//////AP//lP8AFAGWAAAAACMAAg8FAgH/FAH7AAAB+goEBP8AtP8AR///4/8AAAAAAAAAABoAAAABAgH/BQHIFBQC7hQBAP8AAAAAAAAAAA==";
@@ -0,0 +1,203 @@
+//! Decoding of the BLE advertisements that are broadcast by
+//! Govee's Bluetooth-only thermo-hygrometers.
+//!
+//! These devices don't need to be connected to; they periodically
+//! advertise their current readings in the manufacturer specific
+//! data of their advertisements.
+//! For reference, see:
+//! <https://github.com/Bluetooth-Devices/govee-ble>
+
+use serde::Serialize;
+
+/// The manufacturer id used by the H5072, H5074 and H5075
+const MANUFACTURER_ID_H507X: u16 = 0xec88;
+/// The manufacturer id used by the H5101, H5102, H5174 and H5177
+const MANUFACTURER_ID_H51XX: u16 = 0x0001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BleReading {
+    /// The temperature in degrees Celsius
+    pub temperature: f64,
+    /// The relative humidity in percent
+    pub humidity: f64,
+    /// The battery level in percent
+    pub battery: u8,
+}
+
+/// Extracts the SKU from the advertised local name of the device,
+/// such as "GVH5075_1A2B" or "Govee_H5074_1A2B"
+#[cfg_attr(not(feature = "ble"), allow(dead_code))]
+pub fn sku_from_local_name(name: &str) -> Option<String> {
+    let start = name.find("H5")?;
+    let sku = name.get(start..start + 5)?;
+    sku[1..]
+        .chars()
+        .all(|c| c.is_ascii_digit())
+        .then(|| sku.to_string())
+}
+
+/// Returns the MAC in the form `AA:BB:CC:DD:EE:FF`, or None
+/// if it isn't a MAC address
+pub fn canonical_mac(mac: &str) -> Option<String> {
+    let digits: Vec<char> = mac
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-'))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if digits.len() != 12 || !digits.iter().all(char::is_ascii_hexdigit) {
+        return None;
+    }
+    Some(
+        digits
+            .chunks(2)
+            .map(|pair| pair.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join(":"),
+    )
+}
+
+/// Decodes the manufacturer specific data of an advertisement
+/// from the device with the given SKU.
+/// Returns None if it isn't a reading that we recognize.
+#[cfg_attr(not(feature = "ble"), allow(dead_code))]
+pub fn decode_advertisement(sku: &str, manufacturer_id: u16, data: &[u8]) -> Option<BleReading> {
+    match (sku, manufacturer_id, data.len()) {
+        ("H5074", MANUFACTURER_ID_H507X, 7) => {
+            let temperature = i16::from_le_bytes([data[1], data[2]]);
+            let humidity = u16::from_le_bytes([data[3], data[4]]);
+            Some(BleReading {
+                temperature: temperature as f64 / 100.0,
+                humidity: humidity as f64 / 100.0,
+                battery: data[5],
+            })
+        }
+        ("H5072" | "H5075", MANUFACTURER_ID_H507X, 6) => {
+            let (temperature, humidity) = decode_packed_temp_humidity(&data[1..4]);
+            Some(BleReading {
+                temperature,
+                humidity,
+                battery: data[4],
+            })
+        }
+        ("H5101" | "H5102" | "H5174" | "H5177", MANUFACTURER_ID_H51XX, 6) => {
+            let (temperature, humidity) = decode_packed_temp_humidity(&data[2..5]);
+            Some(BleReading {
+                temperature,
+                humidity,
+                battery: data[5],
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Several models pack both values into a 24-bit big endian number,
+/// as temperature * 10000 + humidity * 10, with the top bit
+/// indicating that the temperature is negative
+fn decode_packed_temp_humidity(bytes: &[u8]) -> (f64, f64) {
+    let packed = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+    let is_negative = packed & 0x80_0000 != 0;
+    let value = packed & 0x7f_ffff;
+
+    let temperature = (value / 1000) as f64 / 10.0;
+    let humidity = (value % 1000) as f64 / 10.0;
+    (
+        if is_negative {
+            -temperature
+        } else {
+            temperature
+        },
+        humidity,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn local_names() {
+        k9::assert_equal!(
+            sku_from_local_name("GVH5075_1A2B"),
+            Some("H5075".to_string())
+        );
+        k9::assert_equal!(
+            sku_from_local_name("Govee_H5074_1A2B"),
+            Some("H5074".to_string())
+        );
+        k9::assert_equal!(sku_from_local_name("GVH5"), None);
+        k9::assert_equal!(sku_from_local_name("ihoment_H6199"), None);
+    }
+
+    #[test]
+    fn macs() {
+        k9::assert_equal!(
+            canonical_mac("a4:c1:38:11:22:33"),
+            Some("A4:C1:38:11:22:33".to_string())
+        );
+        k9::assert_equal!(
+            canonical_mac(" A4-C1-38-11-22-33"),
+            Some("A4:C1:38:11:22:33".to_string())
+        );
+        k9::assert_equal!(canonical_mac("A4:C1:38:11:22"), None);
+        k9::assert_equal!(canonical_mac("A4:C1:38:11:22:3G"), None);
+    }
+
+    #[test]
+    fn h5075() {
+        // 22.5C, 45.6%, 87% battery
+        k9::assert_equal!(
+            decode_advertisement("H5075", 0xec88, &[0x00, 0x03, 0x70, 0xb0, 0x57, 0x00]),
+            Some(BleReading {
+                temperature: 22.5,
+                humidity: 45.6,
+                battery: 87,
+            })
+        );
+        // -5.2C, 61.3%
+        k9::assert_equal!(
+            decode_advertisement("H5075", 0xec88, &[0x00, 0x80, 0xcd, 0x85, 0x40, 0x00]),
+            Some(BleReading {
+                temperature: -5.2,
+                humidity: 61.3,
+                battery: 64,
+            })
+        );
+        // Wrong length
+        k9::assert_equal!(
+            decode_advertisement("H5075", 0xec88, &[0x00, 0x03, 0x71]),
+            None
+        );
+    }
+
+    #[test]
+    fn h5074() {
+        // -1.5C is encoded as a signed little endian number
+        k9::assert_equal!(
+            decode_advertisement("H5074", 0xec88, &[0x00, 0x6a, 0xff, 0xbc, 0x1a, 0x64, 0x02]),
+            Some(BleReading {
+                temperature: -1.5,
+                humidity: 68.44,
+                battery: 100,
+            })
+        );
+    }
+
+    #[test]
+    fn h5177() {
+        k9::assert_equal!(
+            decode_advertisement("H5177", 0x0001, &[0x01, 0x01, 0x03, 0x70, 0xb0, 0x32]),
+            Some(BleReading {
+                temperature: 22.5,
+                humidity: 45.6,
+                battery: 50,
+            })
+        );
+        // The manufacturer id has to match the model
+        k9::assert_equal!(
+            decode_advertisement("H5177", 0xec88, &[0x01, 0x01, 0x03, 0x70, 0xb0, 0x32]),
+            None
+        );
+    }
+}
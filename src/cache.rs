@@ -6,7 +6,7 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use sqlite_cache::{Cache, CacheConfig};
 use std::future::Future;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -14,10 +14,10 @@ pub static CACHE: Lazy<ArcSwap<Cache>> =
     Lazy::new(|| open_cache().expect("failed to initialize cache").into());
 
 fn cache_file_name() -> PathBuf {
-    let cache_dir = std::env::var("GOVEE_CACHE_DIR")
+    let cache_dir = crate::opt_env_var::<PathBuf>("GOVEE_CACHE_DIR")
         .ok()
-        .map(PathBuf::from)
-        .or_else(|| dirs_next::cache_dir())
+        .flatten()
+        .or_else(dirs_next::cache_dir)
         .expect("failed to resolve cache dir");
 
     cache_dir.join("govee2mqtt-cache.sqlite")
@@ -25,8 +25,22 @@ fn cache_file_name() -> PathBuf {
 
 fn open_cache() -> anyhow::Result<Arc<Cache>> {
     let cache_file = cache_file_name();
-    let conn = sqlite_cache::rusqlite::Connection::open(&cache_file)
-        .expect(&format!("failed to open {cache_file:?}"));
+    match open_cache_file(&cache_file) {
+        Ok(cache) => Ok(cache),
+        Err(err) => {
+            // The cache only holds things that we can fetch again,
+            // so rather than failing, start over with an empty one
+            log::warn!("Discarding cache file {cache_file:?} which cannot be used: {err:#}");
+            std::fs::remove_file(&cache_file)
+                .with_context(|| format!("removing cache file {cache_file:?}"))?;
+            open_cache_file(&cache_file)
+        }
+    }
+}
+
+fn open_cache_file(cache_file: &Path) -> anyhow::Result<Arc<Cache>> {
+    let conn = sqlite_cache::rusqlite::Connection::open(cache_file)
+        .with_context(|| format!("failed to open {cache_file:?}"))?;
     Ok(Arc::new(Cache::new(
         // We have low cardinality and can be pretty relaxed
         CacheConfig {
@@ -42,7 +56,7 @@ pub fn purge_cache() -> anyhow::Result<()> {
     let cache_file = cache_file_name();
     std::fs::remove_file(&cache_file)
         .with_context(|| format!("removing cache file {cache_file:?}"))?;
-    CACHE.store(open_cache()?.into());
+    CACHE.store(open_cache()?);
     Ok(())
 }
 
@@ -96,6 +110,53 @@ pub fn invalidate_key(topic: &str, key: &str) -> anyhow::Result<()> {
     Ok(topic.delete(key)?)
 }
 
+/// How long a marker is retained; effectively forever
+const MARKER_TTL: Duration = Duration::from_secs(10 * 365 * 86400);
+
+/// Returns true if set_marker was previously called for this key
+pub fn has_marker(topic: &str, key: &str) -> anyhow::Result<bool> {
+    let topic = CACHE.load().topic(topic)?;
+    Ok(topic.get(key)?.is_some())
+}
+
+/// Records that something happened, so that it can be checked with
+/// has_marker across restarts
+pub fn set_marker(topic: &str, key: &str) -> anyhow::Result<()> {
+    let topic = CACHE.load().topic(topic)?;
+    let data = serde_json::to_string(&Utc::now())?;
+    Ok(topic.set(key, data.as_bytes(), MARKER_TTL)?)
+}
+
+/// Persists value under key, replacing any prior value
+pub fn store_value<T: Serialize>(
+    topic: &str,
+    key: &str,
+    value: &T,
+    ttl: Duration,
+) -> anyhow::Result<()> {
+    let topic = CACHE.load().topic(topic)?;
+    let data = serde_json::to_string(value)?;
+    Ok(topic.set(key, data.as_bytes(), ttl)?)
+}
+
+/// Loads a value that was persisted via store_value. A value that
+/// cannot be parsed, perhaps because it was written by a different
+/// version, is discarded with a warning.
+pub fn load_value<T: DeserializeOwned>(topic_name: &str, key: &str) -> anyhow::Result<Option<T>> {
+    let topic = CACHE.load().topic(topic_name)?;
+    let Some(current) = topic.get(key)? else {
+        return Ok(None);
+    };
+    match serde_json::from_slice(&current.data) {
+        Ok(value) => Ok(Some(value)),
+        Err(err) => {
+            log::warn!("Discarding unparseable {topic_name} cache entry for {key}: {err:#}");
+            topic.delete(key)?;
+            Ok(None)
+        }
+    }
+}
+
 /// Cache an item with a soft TTL; we'll retry the operation
 /// if the TTL has expired, but allow stale reads
 pub async fn cache_get<T, Fut>(options: CacheGetOptions<'_>, future: Fut) -> anyhow::Result<T>
@@ -137,8 +198,9 @@ where
                 result: CacheResult::Ok(value.clone()),
             };
 
+            // Keep the entry for as long as it is good for
             let data = serde_json::to_string_pretty(&entry)?;
-            updater.write(data.as_bytes(), options.hard_ttl)?;
+            updater.write(data.as_bytes(), options.hard_ttl.max(ttl))?;
             Ok(value)
         }
         Ok(CacheComputeResult::Value(value)) => {
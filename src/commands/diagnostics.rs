@@ -0,0 +1,499 @@
+use crate::lan_api::Client as LanClient;
+use crate::platform_api::DeviceCapability;
+use crate::service::state::State;
+use chrono::Utc;
+use serde_json::{json, Map, Value as JsonValue};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Collects the information that is needed to triage a problem into
+/// a directory, with credentials and other personal data scrubbed,
+/// so that it can be attached to an issue
+#[derive(clap::Parser, Debug)]
+pub struct DiagnosticsCommand {
+    /// The directory in which to write the diagnostics.
+    /// The default is a new directory named for the current time.
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Only include information about the device with this id
+    #[arg(long)]
+    device: Option<String>,
+
+    /// Skip LAN discovery
+    #[arg(long)]
+    skip_lan: bool,
+}
+
+const REDACTED: &str = "REDACTED";
+
+/// Field and variable names whose values are always scrubbed
+const SENSITIVE_NAMES: &[&str] = &[
+    "password",
+    "passwd",
+    "token",
+    "secret",
+    "email",
+    "apikey",
+    "iotkey",
+    "iotcert",
+    "topic",
+    "accountid",
+    "username",
+    "user",
+    "verificationcode",
+];
+
+fn is_sensitive_name(name: &str) -> bool {
+    let name: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    SENSITIVE_NAMES
+        .iter()
+        .any(|sensitive| name.contains(sensitive))
+}
+
+fn looks_like_email(word: &str) -> bool {
+    match word.split_once('@') {
+        Some((user, domain)) => !user.is_empty() && domain.contains('.'),
+        None => false,
+    }
+}
+
+/// Scrubs credentials and personal data from the diagnostics
+struct Redactor {
+    /// Values which are known to be secret, such as the configured
+    /// credentials, which are removed wherever they appear
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    fn new<S: Into<String>>(secrets: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            secrets: secrets
+                .into_iter()
+                .map(Into::into)
+                // Very short values would match too much
+                .filter(|s: &String| s.len() >= 4)
+                .collect(),
+        }
+    }
+
+    fn redact_str(&self, s: &str) -> String {
+        let mut result = s.to_string();
+        for secret in &self.secrets {
+            result = result.replace(secret.as_str(), REDACTED);
+        }
+        if result.contains('@') {
+            result = result
+                .split(' ')
+                .map(|word| {
+                    if looks_like_email(word) {
+                        REDACTED
+                    } else {
+                        word
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
+        result
+    }
+
+    fn redact_json(&self, value: JsonValue) -> JsonValue {
+        match value {
+            JsonValue::Object(map) => JsonValue::Object(
+                map.into_iter()
+                    .map(|(key, value)| {
+                        let value = if is_sensitive_name(&key) && !value.is_null() {
+                            JsonValue::String(REDACTED.to_string())
+                        } else {
+                            self.redact_json(value)
+                        };
+                        (key, value)
+                    })
+                    .collect::<Map<_, _>>(),
+            ),
+            JsonValue::Array(items) => {
+                JsonValue::Array(items.into_iter().map(|v| self.redact_json(v)).collect())
+            }
+            JsonValue::String(s) => {
+                // The undocumented API embeds JSON documents in strings
+                if s.starts_with('{') || s.starts_with('[') {
+                    if let Ok(embedded) = serde_json::from_str::<JsonValue>(&s) {
+                        return self.redact_json(embedded);
+                    }
+                }
+                JsonValue::String(self.redact_str(&s))
+            }
+            value => value,
+        }
+    }
+
+    /// Redacts the values of sensitive environment variables
+    fn redact_env(&self, vars: impl IntoIterator<Item = (String, String)>) -> JsonValue {
+        let mut map = Map::new();
+        for (name, value) in vars {
+            let value = if is_sensitive_name(&name) {
+                REDACTED.to_string()
+            } else {
+                self.redact_str(&value)
+            };
+            map.insert(name, value.into());
+        }
+        JsonValue::Object(map)
+    }
+
+    /// Redacts the values of sensitive options on the command line,
+    /// whether they are passed as `--name=value` or `--name value`
+    fn redact_command_line(&self, args: impl IntoIterator<Item = String>) -> Vec<String> {
+        let mut result = vec![];
+        let mut redact_next = false;
+        for arg in args {
+            if redact_next {
+                result.push(REDACTED.to_string());
+                redact_next = false;
+            } else if let Some(option) = arg.strip_prefix("--") {
+                match option.split_once('=') {
+                    Some((name, _)) if is_sensitive_name(name) => {
+                        result.push(format!("--{name}={REDACTED}"));
+                    }
+                    Some(_) => result.push(self.redact_str(&arg)),
+                    None => {
+                        redact_next = is_sensitive_name(option);
+                        result.push(arg);
+                    }
+                }
+            } else {
+                result.push(self.redact_str(&arg));
+            }
+        }
+        result
+    }
+}
+
+/// The environment variables that influence our configuration
+fn relevant_env_vars() -> Vec<(String, String)> {
+    let mut vars: Vec<_> = std::env::vars()
+        .filter(|(name, _)| {
+            name.starts_with("GOVEE_") || name.starts_with("RUST_LOG") || name == "TZ"
+        })
+        .collect();
+    vars.sort();
+    vars
+}
+
+fn config_file_settings(redactor: &Redactor) -> JsonValue {
+    let config = crate::config::current();
+    let mut settings: Vec<_> = config
+        .settings()
+        .map(|(name, setting)| (name.to_string(), setting.value.clone()))
+        .collect();
+    settings.sort();
+    json!({
+        "path": config.path(),
+        "settings": redactor.redact_env(settings),
+        "devices": config.devices(),
+    })
+}
+
+impl DiagnosticsCommand {
+    fn wants_device(&self, id: &str) -> bool {
+        self.device
+            .as_deref()
+            .map(|wanted| wanted.eq_ignore_ascii_case(id))
+            .unwrap_or(true)
+    }
+
+    fn write(
+        &self,
+        redactor: &Redactor,
+        dir: &Path,
+        name: &str,
+        value: JsonValue,
+    ) -> anyhow::Result<()> {
+        let path = dir.join(name);
+        let value = redactor.redact_json(value);
+        std::fs::write(&path, serde_json::to_string_pretty(&value)?)?;
+        eprintln!("Wrote {}", path.display());
+        Ok(())
+    }
+
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let dir = self.output.clone().unwrap_or_else(|| {
+            PathBuf::from(format!(
+                "govee-diagnostics-{}",
+                Utc::now().format("%Y%m%d-%H%M%S")
+            ))
+        });
+        std::fs::create_dir_all(&dir)?;
+
+        let mut secrets = vec![];
+        secrets.extend(args.api_args.opt_api_key().ok().flatten());
+        secrets.extend(args.undoc_args.opt_email().ok().flatten());
+        secrets.extend(args.undoc_args.opt_password().ok().flatten());
+        secrets.extend(args.hass_args.mqtt_password().ok().flatten());
+
+        let state = Arc::new(State::new());
+        let mut errors = vec![];
+
+        let platform = args.api_args.api_client().ok();
+        let mut platform_devices = vec![];
+        if let Some(client) = &platform {
+            match client.get_devices().await {
+                Ok(devices) => {
+                    for info in devices {
+                        if self.wants_device(&info.device) {
+                            state
+                                .device_mut(&info.sku, &info.device)
+                                .await
+                                .set_http_device_info(info.clone());
+                            platform_devices.push(info);
+                        }
+                    }
+                }
+                Err(err) => errors.push(format!("Platform API device list: {err:#}")),
+            }
+        }
+
+        let mut undoc_devices = JsonValue::Null;
+        if let Ok(client) = args.undoc_args.api_client() {
+            match client.login_account_cached().await {
+                Ok(acct) => {
+                    secrets.push(acct.token.to_string());
+                    let list = async {
+                        let info = client.get_device_list(&acct.token).await?;
+                        let raw = client.get_device_list_json(&acct.token).await?;
+                        anyhow::Ok((info, raw))
+                    };
+                    match list.await {
+                        Ok((info, raw)) => {
+                            for entry in info.devices {
+                                if self.wants_device(&entry.device) {
+                                    state
+                                        .device_mut(&entry.sku, &entry.device)
+                                        .await
+                                        .set_undoc_device_info(entry, None);
+                                }
+                            }
+                            undoc_devices = self.filter_undoc_devices(raw);
+                        }
+                        Err(err) => errors.push(format!("undocumented API device list: {err:#}")),
+                    }
+                }
+                Err(err) => errors.push(format!("undocumented API login: {err:#}")),
+            }
+        }
+
+        let mut lan_devices = vec![];
+        if !self.skip_lan {
+            let timeout = args.lan_disco_args.disco_timeout()?;
+            eprintln!("Waiting {timeout} seconds for LAN discovery, use --skip-lan to skip...");
+            let options = args.lan_disco_args.to_disco_options()?;
+            if !options.is_empty() {
+                let deadline = Instant::now() + Duration::from_secs(timeout);
+                let (_client, mut scan) = LanClient::new(options).await?;
+                while let Ok(Some(lan_device)) =
+                    tokio::time::timeout_at(deadline, scan.recv()).await
+                {
+                    if self.wants_device(&lan_device.device) {
+                        state
+                            .device_mut(&lan_device.sku, &lan_device.device)
+                            .await
+                            .set_lan_device(lan_device.clone());
+                        lan_devices.push(lan_device);
+                    }
+                }
+            }
+        }
+
+        let mut quirks = vec![];
+        let mut scenes = Map::new();
+        for device in state.devices().await {
+            quirks.push(json!({
+                "id": device.id,
+                "sku": device.sku,
+                "name": device.name(),
+                "quirk": device.resolve_quirk().map(|quirk| format!("{quirk:?}")),
+            }));
+
+            if let (Some(client), Some(info)) = (&platform, &device.http_device_info) {
+                fn caps_or_error(
+                    result: anyhow::Result<Vec<DeviceCapability>>,
+                ) -> anyhow::Result<JsonValue> {
+                    Ok(match result {
+                        Ok(caps) => serde_json::to_value(caps)?,
+                        Err(err) => json!({"error": format!("{err:#}")}),
+                    })
+                }
+                scenes.insert(
+                    device.id.to_string(),
+                    json!({
+                        "dynamic": caps_or_error(client.get_device_scenes(info).await)?,
+                        "diy": caps_or_error(client.get_device_diy_scenes(info).await)?,
+                    }),
+                );
+            }
+        }
+
+        let redactor = Redactor::new(secrets);
+        self.write(
+            &redactor,
+            &dir,
+            "config.json",
+            json!({
+                "version": crate::version_info::govee_version(),
+                "command_line": redactor.redact_command_line(std::env::args()),
+                "environment": redactor.redact_env(relevant_env_vars()),
+                "config_file": config_file_settings(&redactor),
+                "errors": errors,
+            }),
+        )?;
+        self.write(
+            &redactor,
+            &dir,
+            "platform-devices.json",
+            serde_json::to_value(&platform_devices)?,
+        )?;
+        self.write(&redactor, &dir, "undoc-devices.json", undoc_devices)?;
+        self.write(&redactor, &dir, "quirks.json", JsonValue::Array(quirks))?;
+        self.write(&redactor, &dir, "scenes.json", JsonValue::Object(scenes))?;
+        self.write(
+            &redactor,
+            &dir,
+            "lan-devices.json",
+            serde_json::to_value(&lan_devices)?,
+        )?;
+
+        for err in &errors {
+            eprintln!("Warning: {}", redactor.redact_str(err));
+        }
+        eprintln!(
+            "Review the files in {} before attaching them to an issue",
+            dir.display()
+        );
+
+        Ok(())
+    }
+
+    /// Applies the --device filter to the raw undocumented device list
+    fn filter_undoc_devices(&self, mut raw: JsonValue) -> JsonValue {
+        if let Some(JsonValue::Array(devices)) = raw.get_mut("devices") {
+            devices.retain(|device| {
+                device
+                    .get("device")
+                    .and_then(|id| id.as_str())
+                    .map(|id| self.wants_device(id))
+                    .unwrap_or(false)
+            });
+        }
+        raw
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redact_json() {
+        let redactor = Redactor::new(["secret-api-key", "me@example.com", "pw"]);
+        let redacted = redactor.redact_json(json!({
+            "devices": [{
+                "device": "AA:BB:CC:DD:EE:FF:42:2A",
+                "sku": "H6159",
+                "deviceExt": {
+                    "deviceSettings":
+                        "{\"topic\":\"GA/123\",\"secretCode\":\"abc\",\"wifiName\":\"home\"}",
+                },
+            }],
+            "token": "eyJhbGciOi",
+            "refreshToken": null,
+            "color": {"r": 255, "g": 0, "b": 0},
+            "message": "logged in as me@example.com using secret-api-key",
+            "note": "contact someone@elsewhere.org",
+            "pw": "pw",
+        }));
+
+        k9::assert_equal!(
+            redacted,
+            json!({
+                "devices": [{
+                    "device": "AA:BB:CC:DD:EE:FF:42:2A",
+                    "sku": "H6159",
+                    "deviceExt": {
+                        "deviceSettings": {
+                            "topic": REDACTED,
+                            "secretCode": REDACTED,
+                            "wifiName": "home",
+                        },
+                    },
+                }],
+                "token": REDACTED,
+                "refreshToken": null,
+                "color": {"r": 255, "g": 0, "b": 0},
+                "message": "logged in as REDACTED using REDACTED",
+                "note": "contact REDACTED",
+                // Short secrets are not substituted
+                "pw": "pw",
+            })
+        );
+    }
+
+    #[test]
+    fn redact_config() {
+        let redactor = Redactor::new(["hunter22"]);
+        k9::assert_equal!(
+            redactor.redact_env([
+                ("GOVEE_API_KEY".to_string(), "key".to_string()),
+                ("GOVEE_EMAIL".to_string(), "me@example.com".to_string()),
+                ("GOVEE_MQTT_PASSWORD".to_string(), "hunter22".to_string()),
+                ("GOVEE_MQTT_USER".to_string(), "mqtt".to_string()),
+                ("GOVEE_HTTP_AUTH_TOKEN".to_string(), "t0k3n".to_string()),
+                ("GOVEE_MQTT_HOST".to_string(), "mqtt.local".to_string()),
+                ("GOVEE_LAN_SCAN".to_string(), "10.0.0.1".to_string()),
+            ]),
+            json!({
+                "GOVEE_API_KEY": REDACTED,
+                "GOVEE_EMAIL": REDACTED,
+                "GOVEE_MQTT_PASSWORD": REDACTED,
+                "GOVEE_MQTT_USER": REDACTED,
+                "GOVEE_HTTP_AUTH_TOKEN": REDACTED,
+                "GOVEE_MQTT_HOST": "mqtt.local",
+                "GOVEE_LAN_SCAN": "10.0.0.1",
+            })
+        );
+
+        k9::assert_equal!(
+            redactor.redact_command_line(
+                [
+                    "govee",
+                    "--api-key",
+                    "key",
+                    "--govee-email=me@example.com",
+                    "--mqtt-host",
+                    "mqtt.local",
+                    "--govee-password",
+                    "hunter22",
+                    "diagnostics",
+                ]
+                .map(String::from)
+            ),
+            vec![
+                "govee",
+                "--api-key",
+                REDACTED,
+                "--govee-email=REDACTED",
+                "--mqtt-host",
+                "mqtt.local",
+                "--govee-password",
+                REDACTED,
+                "diagnostics",
+            ]
+        );
+    }
+}
@@ -0,0 +1,25 @@
+use crate::service::quirks::resolve_quirk;
+use std::collections::BTreeMap;
+
+/// Prints the effective quirk for a SKU, after merging the entries
+/// from the quirks file, if any, over the built-in quirks.
+/// The output is in the format of a JSON quirks file.
+#[derive(clap::Parser, Debug)]
+pub struct DumpQuirksCommand {
+    /// The SKU of the device. May be repeated.
+    #[arg(long, required = true)]
+    sku: Vec<String>,
+}
+
+impl DumpQuirksCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let mut quirks = BTreeMap::new();
+        for sku in &self.sku {
+            let quirk =
+                resolve_quirk(sku).ok_or_else(|| anyhow::anyhow!("there is no quirk for {sku}"))?;
+            quirks.insert(sku, quirk);
+        }
+        println!("{}", serde_json::to_string_pretty(&quirks)?);
+        Ok(())
+    }
+}
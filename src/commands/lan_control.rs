@@ -1,15 +1,22 @@
 use crate::ble::{Base64HexBytes, SetSceneCode};
-use crate::lan_api::{Client, DiscoOptions};
+use crate::lan_api::{Client, DiscoOptions, LanDevice};
 use crate::undoc_api::GoveeUndocumentedApi;
 use clap_num::maybe_hex;
 use std::collections::BTreeMap;
 use std::net::IpAddr;
+use tokio::time::{Duration, Instant};
 use uncased::Uncased;
 
 #[derive(clap::Parser, Debug)]
 pub struct LanControlCommand {
-    #[arg(long)]
-    pub ip: IpAddr,
+    /// The address of the device
+    #[arg(long, required_unless_present = "device_id")]
+    pub ip: Option<IpAddr>,
+
+    /// The id of the device, whose address is found by
+    /// a multicast discovery scan
+    #[arg(long, conflicts_with = "ip")]
+    pub device_id: Option<String>,
 
     #[command(subcommand)]
     cmd: SubCommand,
@@ -28,6 +35,27 @@ enum SubCommand {
     Color {
         color: csscolorparser::Color,
     },
+    /// Set the color of some segments
+    SegmentColor {
+        /// A comma separated list of segment indices, starting at 0
+        #[arg(value_delimiter = ',', required = true)]
+        segments: Vec<u32>,
+        color: csscolorparser::Color,
+    },
+    /// Set the brightness of some segments
+    SegmentBrightness {
+        /// A comma separated list of segment indices, starting at 0
+        #[arg(value_delimiter = ',', required = true)]
+        segments: Vec<u32>,
+        percent: u8,
+    },
+    /// Activate a scene by its code, as shown by `list-scenes`
+    SceneCode {
+        code: u16,
+        /// The base64 encoded scene parameter, if any
+        #[arg(long, default_value = "")]
+        param: String,
+    },
     /// Send a BLE-encoded govee packet
     /// eg: `0x33 1 0` is power off, `0x33 1 1` is power on.
     /// More usefully: you can send scene or music mode commands
@@ -48,10 +76,31 @@ enum SubCommand {
 }
 
 impl LanControlCommand {
-    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
-        let (client, _scan) = Client::new(DiscoOptions::default()).await?;
+    /// Locates the device, either by probing its address or by
+    /// waiting for it to respond to discovery
+    async fn resolve_device(&self, args: &crate::Args) -> anyhow::Result<(Client, LanDevice)> {
+        let (client, mut scan) = Client::new(DiscoOptions::default()).await?;
+
+        if let Some(ip) = self.ip {
+            let device = client.scan_ip(ip).await?;
+            return Ok((client, device));
+        }
+
+        let id = self.device_id.as_deref().unwrap_or_default();
+        let timeout = args.lan_disco_args.disco_timeout()?;
+        eprintln!("Waiting up to {timeout} seconds for {id} to respond to discovery...");
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        while let Ok(Some(device)) = tokio::time::timeout_at(deadline, scan.recv()).await {
+            if device.device.eq_ignore_ascii_case(id) {
+                eprintln!("Found {id} at {}", device.ip);
+                return Ok((client, device));
+            }
+        }
+        anyhow::bail!("{id} didn't respond to discovery");
+    }
 
-        let device = client.scan_ip(self.ip).await?;
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let (client, device) = self.resolve_device(args).await?;
 
         match &self.cmd {
             SubCommand::On => {
@@ -72,6 +121,20 @@ impl LanControlCommand {
                     .send_color_rgb(crate::lan_api::DeviceColor { r, g, b })
                     .await?;
             }
+            SubCommand::SegmentColor { segments, color } => {
+                let [r, g, b, _a] = color.to_rgba8();
+                let rgb = u32::from_be_bytes([0, r, g, b]);
+                device.send_segment_rgb(segments, rgb).await?;
+            }
+            SubCommand::SegmentBrightness { segments, percent } => {
+                device.send_segment_brightness(segments, *percent).await?;
+            }
+            SubCommand::SceneCode { code, param } => {
+                let code = SetSceneCode::new(*code, param.to_string());
+                let encoded = Base64HexBytes::encode_for_sku("Generic:Light", &code)?.base64();
+                println!("Computed {encoded:?}");
+                device.send_real(encoded).await?;
+            }
             SubCommand::Scene { list, scene } => {
                 let mut scene_code_by_name = BTreeMap::new();
                 for category in GoveeUndocumentedApi::get_scenes_for_device(&device.sku).await? {
@@ -91,6 +154,7 @@ impl LanControlCommand {
                     for name in scene_code_by_name.keys() {
                         println!("{name}");
                     }
+                    return Ok(());
                 } else {
                     let scene = Uncased::new(scene.clone().expect("scene if not list"));
                     if let Some(code) = scene_code_by_name.get(&scene) {
@@ -110,6 +174,14 @@ impl LanControlCommand {
             }
         }
 
+        // Report the resulting state, which also confirms that the
+        // device is still responding
+        let status = client
+            .query_status(&device)
+            .await
+            .map_err(|err| anyhow::anyhow!("no response from {}: {err:#}", device.ip))?;
+        println!("{status:#?}");
+
         Ok(())
     }
 }
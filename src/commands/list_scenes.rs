@@ -0,0 +1,126 @@
+use crate::platform_api::{DeviceCapability, DeviceParameters};
+use crate::undoc_api::GoveeUndocumentedApi;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+/// Lists the scenes that are known for a device, along with where
+/// each one came from and the code that is used to activate it
+#[derive(clap::Parser, Debug)]
+pub struct ListScenesCommand {
+    /// The id of the device
+    #[arg(long)]
+    device: String,
+
+    /// Print the list as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Fetch the scene lists from Govee, rather than using
+    /// the cached lists
+    #[arg(long)]
+    refresh: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct SceneItem {
+    name: String,
+    source: &'static str,
+    code: JsonValue,
+}
+
+fn scenes_from_caps(source: &'static str, caps: &[DeviceCapability], items: &mut Vec<SceneItem>) {
+    for cap in caps {
+        if let Some(DeviceParameters::Enum { options }) = &cap.parameters {
+            for opt in options {
+                items.push(SceneItem {
+                    name: opt.name.to_string(),
+                    source,
+                    code: opt.value.clone(),
+                });
+            }
+        }
+    }
+}
+
+impl ListScenesCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let client = args.api_args.api_client()?;
+        let device = client.get_device_by_id(&self.device).await?;
+
+        if self.refresh {
+            client.invalidate_scene_lists(&device)?;
+            GoveeUndocumentedApi::invalidate_scenes_for_device(&device.sku)?;
+        }
+
+        let mut items = vec![];
+        let static_caps: Vec<_> = device
+            .capabilities
+            .iter()
+            .filter(|cap| cap.instance == "lightScene" || cap.instance == "diyScene")
+            .cloned()
+            .collect();
+        scenes_from_caps("capabilities", &static_caps, &mut items);
+        scenes_from_caps(
+            "dynamic",
+            &client.get_device_scenes(&device).await?,
+            &mut items,
+        );
+        scenes_from_caps(
+            "diy",
+            &client.get_device_diy_scenes(&device).await?,
+            &mut items,
+        );
+
+        match GoveeUndocumentedApi::get_scenes_for_device(&device.sku).await {
+            Ok(categories) => {
+                for category in categories {
+                    for scene in category.scenes {
+                        for effect in &scene.light_effects {
+                            let name = if scene.light_effects.len() > 1 {
+                                format!("{}-{}", scene.scene_name, effect.scence_name)
+                            } else {
+                                scene.scene_name.to_string()
+                            };
+                            items.push(SceneItem {
+                                name,
+                                source: "undoc",
+                                code: serde_json::json!({
+                                    "code": effect.scene_code,
+                                    "param": effect.scence_param,
+                                }),
+                            });
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!(
+                    "Scene metadata for {} is not available: {err:#}",
+                    device.sku
+                );
+            }
+        }
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&items)?);
+            return Ok(());
+        }
+
+        let name_width = items.iter().map(|item| item.name.len()).max().unwrap_or(0);
+        let source_width = items
+            .iter()
+            .map(|item| item.source.len())
+            .max()
+            .unwrap_or(0);
+        for item in &items {
+            println!(
+                "{name:<name_width$} {source:<source_width$} {code}",
+                name = item.name,
+                source = item.source,
+                code = item.code,
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
+pub mod diagnostics;
+pub mod dump_quirks;
 pub mod http_control;
 pub mod lan_control;
 pub mod lan_disco;
 pub mod list;
 pub mod list_http;
+pub mod list_scenes;
 pub mod serve;
+pub mod status;
 pub mod undoc;
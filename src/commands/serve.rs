@@ -1,122 +1,980 @@
-use crate::lan_api::Client as LanClient;
-use crate::service::device::Device;
-use crate::service::hass::spawn_hass_integration;
+use crate::ble_adv::canonical_mac;
+use crate::lan_api::{truthy, Client as LanClient};
+use crate::opt_env_var;
+use crate::platform_api::{GoveeApiClient, HttpRequestFailed};
+use crate::service::command_queue::{
+    CommandIntervals, DEFAULT_CLOUD_COMMAND_INTERVAL, DEFAULT_LAN_COMMAND_INTERVAL,
+};
+use crate::service::device::{
+    set_ble_availability_timeout, set_lan_max_missed_polls, Device,
+    DEFAULT_BLE_AVAILABILITY_TIMEOUT,
+};
+use crate::service::hass::{check_hass_integration, spawn_hass_integration};
 use crate::service::http::run_http_server;
-use crate::service::iot::start_iot_client;
+use crate::service::iot::{start_iot_service, IotConnectionHealth};
+use crate::service::reload::{spawn_sighup_handler, ReloadableArgs};
 use crate::service::state::StateHandle;
 use crate::version_info::govee_version;
 use anyhow::Context;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::time::{sleep, Duration};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
 
-pub const POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
+/// The BLE listener options from the command line or environment
+struct BleOptions {
+    /// The MACs from which advertisements are accepted, or
+    /// empty to accept them from any MAC
+    allow: Vec<String>,
+    /// The availability window, in minutes
+    timeout: u32,
+}
+
+/// Parses a comma separated list of MACs, such as
+/// "A4:C1:38:11:22:33,A4:C1:38:44:55:66"
+fn parse_mac_list(list: &str) -> anyhow::Result<Vec<String>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|mac| !mac.is_empty())
+        .map(|mac| {
+            canonical_mac(mac).ok_or_else(|| anyhow::anyhow!("`{mac}` is not a MAC address"))
+        })
+        .collect()
+}
+
+pub static POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
+
+/// How often we request the status of LAN devices, in order to
+/// notice when they stop responding
+const LAN_POLL_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(clap::Parser, Debug)]
 pub struct ServeCommand {
-    /// The port on which the HTTP API will listen
+    /// The port on which the HTTP API will listen.
+    /// Use 0 to disable the HTTP API.
     #[arg(long, default_value_t = 8056)]
     http_port: u16,
+
+    /// The address on which the HTTP API will listen
+    #[arg(long, default_value = "0.0.0.0")]
+    http_bind: IpAddr,
+
+    /// How long, in minutes, the last known state of each device is
+    /// kept across restarts. Within this time, the cached state is
+    /// published at startup, rather than waiting for it to be polled.
+    /// Use 0 to disable caching device state.
+    /// You may also set this via the GOVEE_STATE_CACHE_MAX_AGE environment
+    /// variable.
+    #[arg(long)]
+    state_cache_max_age: Option<u64>,
+
+    /// A comma separated list of poll intervals for specific devices,
+    /// in the form DEVICE=SECONDS, where DEVICE is the id, name or SKU
+    /// of the device, for example: "H5179=30,Office Strip=600".
+    /// Devices with an interval of 0 are never polled, and rely on
+    /// updates that are pushed to us instead.
+    /// You may also set this via the GOVEE_POLL_INTERVALS environment
+    /// variable.
+    #[arg(long)]
+    poll_intervals: Option<String>,
+
+    /// The maximum number of devices that are polled at the same time.
+    /// You may also set this via the GOVEE_POLL_CONCURRENCY environment
+    /// variable.
+    #[arg(long)]
+    poll_concurrency: Option<usize>,
+
+    /// The minimum time, in milliseconds, between the commands sent to
+    /// a device that is reachable via the LAN API. The default is 100.
+    /// You may also set this via the GOVEE_LAN_COMMAND_INTERVAL_MS
+    /// environment variable.
+    #[arg(long)]
+    lan_command_interval_ms: Option<u64>,
+
+    /// The minimum time, in milliseconds, between the commands sent to
+    /// a device that is controlled via Govee's cloud services.
+    /// The default is 1000.
+    /// You may also set this via the GOVEE_CLOUD_COMMAND_INTERVAL_MS
+    /// environment variable.
+    #[arg(long)]
+    cloud_command_interval_ms: Option<u64>,
+
+    /// Always fetch and process the full device list, and re-publish
+    /// discovery configs even when they are unchanged. This is intended
+    /// for debugging.
+    /// You may also set GOVEE_FULL_REFRESH=true via the environment.
+    #[arg(long)]
+    full_refresh: bool,
+
+    /// Use only the LAN API, never contacting Govee's cloud services,
+    /// even if Govee credentials are configured. This is also the
+    /// case if no credentials are configured.
+    /// You may also set GOVEE_LAN_ONLY=true via the environment.
+    #[arg(long)]
+    lan_only: bool,
+
+    /// Require this bearer token for the HTTP endpoints that control
+    /// devices. By default they can be used without authentication.
+    /// You may also set this via the GOVEE_HTTP_AUTH_TOKEN environment
+    /// variable.
+    #[arg(long)]
+    http_auth_token: Option<String>,
+
+    /// Listen for the BLE advertisements of Govee's Bluetooth
+    /// thermo-hygrometers, and create sensors for each of them.
+    /// This requires govee2mqtt to be built with the `ble` feature.
+    /// You may also set GOVEE_BLE_ENABLED=true via the environment.
+    #[arg(long)]
+    ble: bool,
+
+    /// A comma separated list of the MACs of the BLE sensors to
+    /// listen to. By default, every sensor in range is picked up.
+    /// You may also set this via the GOVEE_BLE_ALLOW environment
+    /// variable.
+    #[arg(long)]
+    ble_allow: Option<String>,
+
+    /// How long, in minutes, a BLE sensor may go without advertising
+    /// before it is shown as unavailable. The default is 10.
+    /// You may also set this via the GOVEE_BLE_TIMEOUT environment
+    /// variable.
+    #[arg(long)]
+    ble_timeout: Option<u32>,
+
+    /// Validate the configuration, check that the MQTT broker and,
+    /// if configured, the platform API can be reached, print a
+    /// summary of what would be enabled, and exit without starting
+    /// the service or publishing anything
+    #[arg(long)]
+    dry_run: bool,
 }
 
-async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Result<()> {
-    let now = Utc::now();
+/// Records the outcome of one of the dry run checks,
+/// returning the value if it succeeded
+fn check<T>(problems: &mut Vec<String>, what: &str, result: anyhow::Result<T>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            println!("ERROR: {what}: {err:#}");
+            problems.push(what.to_string());
+            None
+        }
+    }
+}
 
-    if device.is_ble_only_device() == Some(true) {
-        // We can't poll this device, we have no ble support
-        return Ok(());
+fn enabled(on: bool) -> &'static str {
+    if on {
+        "enabled"
+    } else {
+        "disabled"
     }
+}
 
-    let poll_interval = device.preferred_poll_interval();
+/// The default value of --poll-concurrency
+const DEFAULT_POLL_CONCURRENCY: usize = 4;
 
-    let can_update = match &device.last_polled {
-        None => true,
-        Some(last) => now - last > poll_interval,
+/// How long polling is paused after Govee tells us that we've
+/// made too many requests
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long the AWS IoT connection must have been down before the
+/// devices whose state normally arrives via IoT are polled via the
+/// Platform API instead
+const IOT_OUTAGE_POLL_DELAY: Duration = Duration::from_secs(300);
+
+/// How long after AWS IoT reconnects we keep polling a device via the
+/// Platform API while waiting for IoT to push its state. A device that
+/// hasn't been pushed by then is likely offline.
+const IOT_RECOVERY_GRACE: Duration = Duration::from_secs(600);
+
+/// Whether the devices whose state normally arrives via AWS IoT
+/// are being polled via the Platform API, because of an outage
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+enum IotFallback {
+    /// Poll them as usual
+    #[default]
+    Inactive,
+    /// IoT has been down for a while; poll them via the Platform API
+    Outage,
+    /// IoT reconnected at the given time. Each device is polled via
+    /// the Platform API until IoT has pushed its state since then.
+    Recovering(DateTime<Utc>),
+}
+
+impl IotFallback {
+    /// Returns the next state, given the health of the IoT connection.
+    /// `pending` is true if any device that relies on IoT hasn't
+    /// been pushed since IoT recovered.
+    fn next(
+        self,
+        health: &IotConnectionHealth,
+        now: DateTime<Utc>,
+        pending: impl FnOnce(DateTime<Utc>) -> bool,
+    ) -> Self {
+        let delay = chrono::Duration::from_std(IOT_OUTAGE_POLL_DELAY).unwrap_or_default();
+        let grace = chrono::Duration::from_std(IOT_RECOVERY_GRACE).unwrap_or_default();
+        match (self, health.outage_duration(now)) {
+            (_, Some(down)) if down >= delay => Self::Outage,
+            (Self::Inactive, _) => Self::Inactive,
+            // A brief disconnect while recovering; keep polling
+            (Self::Outage | Self::Recovering(_), Some(_)) => Self::Outage,
+            (Self::Outage, None) => Self::Recovering(health.connected_since.unwrap_or(now)),
+            (Self::Recovering(since), None) => {
+                if now - since < grace && pending(since) {
+                    self
+                } else {
+                    Self::Inactive
+                }
+            }
+        }
+    }
+
+    /// Returns true if the device relies on AWS IoT for its state, but
+    /// mustn't be polled via the Platform API in its place, because its
+    /// quirk or configuration avoids the Platform API
+    fn excludes(device: &Device) -> bool {
+        device.state_arrives_via_iot() && device.avoid_platform_api()
+    }
+
+    /// Returns true if the device should be polled via the Platform
+    /// API, rather than relying on AWS IoT for its state
+    fn applies_to(self, device: &Device) -> bool {
+        device.state_arrives_via_iot()
+            && !device.avoid_platform_api()
+            && match self {
+                Self::Inactive => false,
+                Self::Outage => true,
+                Self::Recovering(since) => device
+                    .last_iot_device_status_update
+                    .is_none_or(|pushed| pushed < since),
+            }
+    }
+}
+
+/// Limits how many devices are polled concurrently, and allows
+/// polling to be paused for all devices when we are rate limited
+struct PollPool {
+    permits: Semaphore,
+    resume_at: std::sync::Mutex<Option<Instant>>,
+    /// The platform API suspension that we last logged about
+    announced_rate_limit: std::sync::Mutex<Option<DateTime<Utc>>>,
+    iot_fallback: std::sync::Mutex<IotFallback>,
+}
+
+impl PollPool {
+    fn new(concurrency: usize) -> Self {
+        Self {
+            permits: Semaphore::new(concurrency),
+            resume_at: std::sync::Mutex::new(None),
+            announced_rate_limit: std::sync::Mutex::new(None),
+            iot_fallback: std::sync::Mutex::new(IotFallback::Inactive),
+        }
+    }
+
+    /// Updates whether the devices that rely on AWS IoT are to be
+    /// polled via the Platform API, logging when that changes
+    async fn update_iot_fallback(&self, state: &StateHandle) -> IotFallback {
+        let health = state.get_iot_health().await;
+        let devices = state.devices().await;
+        let mut fallback = self.iot_fallback.lock().unwrap();
+        let prior = *fallback;
+        let next = prior.next(&health, Utc::now(), |since| {
+            devices
+                .iter()
+                .any(|d| IotFallback::Recovering(since).applies_to(d))
+        });
+
+        match (prior, next) {
+            (IotFallback::Inactive, IotFallback::Outage) => {
+                let count = devices.iter().filter(|d| next.applies_to(d)).count();
+                let excluded = devices.iter().filter(|d| IotFallback::excludes(d)).count();
+                log::warn!(
+                    "AWS IoT has been disconnected for more than {}s; polling the \
+                     {count} devices that rely on it via the Platform API",
+                    IOT_OUTAGE_POLL_DELAY.as_secs()
+                );
+                if excluded > 0 {
+                    log::warn!(
+                        "{excluded} devices that rely on AWS IoT avoid the Platform API, \
+                         per their quirks or configuration, so their state won't update \
+                         until AWS IoT reconnects"
+                    );
+                }
+            }
+            (IotFallback::Outage, IotFallback::Recovering(_)) => {
+                log::info!(
+                    "AWS IoT has reconnected; devices will stop being polled via the \
+                     Platform API once their state has been pushed"
+                );
+            }
+            (IotFallback::Recovering(_), IotFallback::Inactive) => {
+                log::info!(
+                    "AWS IoT has recovered; no longer polling its devices via the Platform API"
+                );
+            }
+            _ => {}
+        }
+
+        *fallback = next;
+        next
+    }
+
+    /// Waits until a request may be issued: both that there is
+    /// capacity in the pool, and that polling is not paused
+    async fn acquire(&self) -> anyhow::Result<SemaphorePermit<'_>> {
+        let permit = self.permits.acquire().await?;
+        loop {
+            let resume_at = *self.resume_at.lock().unwrap();
+            match resume_at {
+                Some(when) if when > Instant::now() => sleep_until(when).await,
+                _ => break,
+            }
+        }
+        Ok(permit)
+    }
+
+    /// Pauses polling of all devices
+    fn pause(&self, duration: Duration) {
+        let when = Instant::now() + duration;
+        let mut resume_at = self.resume_at.lock().unwrap();
+        if resume_at.map(|r| r < when).unwrap_or(true) {
+            resume_at.replace(when);
+        }
+    }
+}
+
+/// How often the polling loop considers which devices are due.
+/// This is reduced if a shorter poll interval is configured.
+const POLL_TICK: Duration = Duration::from_secs(60);
+const MIN_POLL_TICK: Duration = Duration::from_secs(10);
+
+/// Returns the poll intervals from the command line or environment,
+/// followed by those from the device sections of the configuration
+/// file, so that the former take precedence
+pub fn resolve_poll_intervals(
+    arg: Option<&str>,
+) -> anyhow::Result<Vec<(String, chrono::Duration)>> {
+    let intervals = match arg {
+        Some(intervals) => Some(intervals.to_string()),
+        None => opt_env_var::<String>("GOVEE_POLL_INTERVALS")?,
     };
+    let mut intervals = match intervals {
+        Some(intervals) => parse_poll_intervals(&intervals)?,
+        None => vec![],
+    };
+    for (device, config) in crate::config::current().devices() {
+        if let Some(seconds) = config.poll_interval {
+            intervals.push((
+                device.to_string(),
+                chrono::Duration::seconds(seconds as i64),
+            ));
+        }
+    }
+    Ok(intervals)
+}
 
-    if !can_update {
-        return Ok(());
+fn parse_poll_intervals(s: &str) -> anyhow::Result<Vec<(String, chrono::Duration)>> {
+    let mut result = vec![];
+    for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (device, seconds) = item
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected DEVICE=SECONDS, but got {item}"))?;
+        let seconds: i64 = seconds
+            .trim()
+            .parse()
+            .with_context(|| format!("parsing poll interval {item}"))?;
+        if seconds < 0 {
+            anyhow::bail!("poll interval {item} cannot be negative");
+        }
+        result.push((
+            device.trim().to_string(),
+            chrono::Duration::seconds(seconds),
+        ));
     }
+    Ok(result)
+}
 
-    let device_state = device.device_state();
-    let needs_update = match &device_state {
+/// Returns true if something last done at `last` is due to be done
+/// again. The polling loop only checks once per tick, so allow half
+/// a tick of leeway: otherwise an interval that is a multiple of the
+/// tick would be missed by a few milliseconds and slip to the next tick.
+fn is_poll_due(
+    last: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    interval: chrono::Duration,
+    tick: Duration,
+) -> bool {
+    match last {
         None => true,
-        Some(state) => now - state.updated > poll_interval,
-    };
+        Some(last) => {
+            let leeway = chrono::Duration::from_std(tick / 2).unwrap_or_default();
+            now - last >= interval - leeway
+        }
+    }
+}
+
+/// The default value of --state-cache-max-age, in minutes
+const DEFAULT_STATE_CACHE_MAX_AGE: u64 = 60;
+
+/// Returns true if poll_single_device has work to do for the device
+async fn device_poll_due(state: &StateHandle, device: &Device, tick: Duration) -> bool {
+    if device.is_ble_only_device() == Some(true) {
+        // We can't poll this device, we have no ble support
+        return false;
+    }
+
+    let poll_interval = state.poll_interval_for(device).await;
+    if poll_interval.is_zero() {
+        // Polling was disabled for this device
+        return false;
+    }
+
+    is_poll_due(device.last_polled, Utc::now(), poll_interval, tick)
+}
+
+/// Polls the device, if it is due. When `iot_fallback` is true, the
+/// device would usually be left to AWS IoT, but that is down, so
+/// it is polled via the Platform API instead.
+async fn poll_single_device(
+    state: &StateHandle,
+    device: &Device,
+    tick: Duration,
+    iot_fallback: bool,
+) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let poll_interval = state.poll_interval_for(device).await;
+
+    let needs_update = is_poll_due(
+        device.device_state().map(|s| s.updated),
+        now,
+        poll_interval,
+        tick,
+    );
 
     if !needs_update {
         return Ok(());
     }
 
-    let needs_platform = device.needs_platform_poll();
+    let needs_platform = iot_fallback || device.needs_platform_poll();
 
     // Don't interrogate via HTTP if we can use the LAN.
     // If we have LAN and the device is stale, it is likely
     // offline and there is little sense in burning up request
     // quota to the platform API for it
-    if device.lan_device.is_some() && !needs_platform {
+    if device.responsive_lan_device().is_some() && !needs_platform {
         log::trace!("LAN-available device {device} needs a status update; it's likely offline.");
         return Ok(());
     }
 
-    if !needs_platform {
-        if state.poll_iot_api(&device).await? {
-            return Ok(());
-        }
+    if !needs_platform && state.poll_iot_api(device).await? {
+        return Ok(());
+    }
+
+    if device.platform_api_bypassed() {
+        log::trace!("Not polling {device} via the Platform API, per its configuration");
+        return Ok(());
+    }
+
+    if state.platform_api_rate_limited_until().await.is_some() {
+        // This is logged once per suspension by poll_devices
+        return Ok(());
     }
 
-    state.poll_platform_api(&device).await?;
+    state.poll_platform_api(device).await?;
 
     Ok(())
 }
 
-async fn periodic_state_poll(state: StateHandle) -> anyhow::Result<()> {
+/// Logs the way in which the device will be controlled, taking
+/// its configuration into account
+async fn log_control_path(state: &StateHandle, device: &Device) {
+    let config = device.config();
+    let mut options = vec![];
+    if let Some(avoid) = config.avoid_platform_api {
+        options.push(format!("avoid_platform_api={avoid}"));
+    }
+    if let Some(prefer) = config.prefer_lan_control {
+        options.push(format!("prefer_lan_control={prefer}"));
+    }
+    let order = device.control_order();
+    if order != crate::service::control_path::DEFAULT_CONTROL_ORDER {
+        options.push(format!(
+            "control_order={}",
+            crate::service::control_path::format_control_order(&order)
+        ));
+    }
+    let transport = state.control_transport(device).await.unwrap_or("none");
+    if options.is_empty() {
+        log::info!("  Control: {transport}");
+    } else {
+        log::info!("  Control: {transport} ({})", options.join(", "));
+    }
+
+    if config.prefer_lan_control == Some(true) && device.lan_device.is_none() {
+        log::warn!("  prefer_lan_control is set, but the device isn't available via the LAN API");
+    }
+    if config.avoid_platform_api == Some(true) && !device.platform_api_bypassed() {
+        log::warn!(
+            "  avoid_platform_api is set, but the device can't be reached via the \
+             LAN or IoT APIs, so the Platform API will still be used"
+        );
+    } else if config.avoid_platform_api == Some(true) && transport == "iot" {
+        log::warn!(
+            "  avoid_platform_api is set and the device isn't available via the \
+             LAN API, so it will be controlled via the IoT API"
+        );
+    }
+}
+
+async fn poll_tick(state: &StateHandle) -> Duration {
+    state
+        .shortest_poll_interval()
+        .await
+        .and_then(|interval| interval.to_std().ok())
+        .map(|interval| interval.clamp(MIN_POLL_TICK, POLL_TICK))
+        .unwrap_or(POLL_TICK)
+}
+
+async fn periodic_state_poll(state: StateHandle, concurrency: usize) -> anyhow::Result<()> {
     sleep(Duration::from_secs(20)).await;
+    let mut tick = poll_tick(&state).await;
+    // Ticks are scheduled relative to when we started, rather than
+    // to when the previous round of polling finished, so that they
+    // don't drift
+    let mut ticker = tokio::time::interval(tick);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let pool = Arc::new(PollPool::new(concurrency));
+
+    let mut last_undoc_poll = Utc::now();
     loop {
-        for d in state.devices().await {
-            if let Err(err) = poll_single_device(&state, &d).await {
-                log::error!("while polling {d}: {err:#}");
+        ticker.tick().await;
+
+        // The poll intervals may have been changed by a reload
+        let new_tick = poll_tick(&state).await;
+        if new_tick != tick {
+            tick = new_tick;
+            ticker = tokio::time::interval_at(tokio::time::Instant::now() + tick, tick);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        }
+
+        if Utc::now() - last_undoc_poll > *POLL_INTERVAL {
+            last_undoc_poll = Utc::now();
+            if let Err(err) = state.poll_undoc_device_list().await {
+                log::error!("while polling undoc device list: {err:#}");
+            }
+            if let Err(err) = state.poll_platform_scene_lists().await {
+                log::error!("while polling platform scene lists: {err:#}");
             }
         }
 
-        sleep(Duration::from_secs(60)).await;
+        poll_devices(&state, &pool, tick).await;
+    }
+}
+
+/// Polls each device that is due, with up to the pool's concurrency
+/// limit in flight at once. Each device appears at most once per
+/// cycle, and we wait for the whole cycle to complete before the
+/// next one begins, so a device never has two polls in flight.
+async fn poll_devices(state: &StateHandle, pool: &Arc<PollPool>, tick: Duration) {
+    let started = Instant::now();
+    let mut tasks = tokio::task::JoinSet::new();
+
+    if let Some(until) = state.platform_api_rate_limited_until().await {
+        if pool.announced_rate_limit.lock().unwrap().replace(until) != Some(until) {
+            log::info!("Platform API is rate limited until {until}; skipping Platform API polling");
+        }
+    }
+
+    let iot_fallback = pool.update_iot_fallback(state).await;
+
+    for d in state.devices().await {
+        if !device_poll_due(state, &d, tick).await {
+            if let Err(err) = state.report_device_availability(&d, false).await {
+                log::error!("while reporting availability of {d}: {err:#}");
+            }
+            continue;
+        }
+
+        let state = state.clone();
+        let pool = pool.clone();
+        let iot_fallback = iot_fallback.applies_to(&d);
+        tasks.spawn(async move {
+            match pool.acquire().await {
+                Ok(_permit) => {
+                    if let Err(err) = poll_single_device(&state, &d, tick, iot_fallback).await {
+                        if HttpRequestFailed::is_rate_limited(&err) {
+                            log::warn!(
+                                "Rate limited while polling {d}, pausing polling for {}s",
+                                RATE_LIMIT_BACKOFF.as_secs()
+                            );
+                            pool.pause(RATE_LIMIT_BACKOFF);
+                        }
+                        log::error!("while polling {d}: {err:#}");
+                    }
+                }
+                Err(err) => log::error!("while waiting to poll {d}: {err:#}"),
+            }
+            if let Err(err) = state.report_device_availability(&d, false).await {
+                log::error!("while reporting availability of {d}: {err:#}");
+            }
+        });
+    }
+
+    let count = tasks.len();
+    while let Some(result) = tasks.join_next().await {
+        if let Err(err) = result {
+            log::error!("device poll task failed: {err:#}");
+        }
+    }
+
+    if count > 0 {
+        log::info!("Polled {count} devices in {:?}", started.elapsed());
+    }
+}
+
+async fn periodic_lan_poll(state: StateHandle) {
+    loop {
+        sleep(LAN_POLL_INTERVAL).await;
+        for d in state.devices().await {
+            if d.lan_device.is_none() {
+                continue;
+            }
+            // Each request can take several seconds to time out if the
+            // device is unreachable, so poll them concurrently
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = state.poll_lan_status(&d).await {
+                    log::error!("while polling LAN status of {d}: {err:#}");
+                }
+            });
+        }
     }
 }
 
 impl ServeCommand {
+    fn http_auth_token(&self) -> anyhow::Result<Option<String>> {
+        match &self.http_auth_token {
+            Some(token) => Ok(Some(token.to_string())),
+            None => opt_env_var::<String>("GOVEE_HTTP_AUTH_TOKEN"),
+        }
+        .map(|token| token.filter(|t| !t.is_empty()))
+    }
+
+    fn state_cache_max_age(&self) -> anyhow::Result<Option<Duration>> {
+        let minutes = match self.state_cache_max_age {
+            Some(minutes) => minutes,
+            None => {
+                opt_env_var("GOVEE_STATE_CACHE_MAX_AGE")?.unwrap_or(DEFAULT_STATE_CACHE_MAX_AGE)
+            }
+        };
+        Ok((minutes > 0).then(|| Duration::from_secs(minutes * 60)))
+    }
+
+    fn poll_intervals(&self) -> anyhow::Result<Vec<(String, chrono::Duration)>> {
+        resolve_poll_intervals(self.poll_intervals.as_deref())
+    }
+
+    fn poll_concurrency(&self) -> anyhow::Result<usize> {
+        let concurrency = match self.poll_concurrency {
+            Some(n) => n,
+            None => opt_env_var("GOVEE_POLL_CONCURRENCY")?.unwrap_or(DEFAULT_POLL_CONCURRENCY),
+        };
+        if concurrency == 0 {
+            anyhow::bail!("--poll-concurrency must be at least 1");
+        }
+        Ok(concurrency)
+    }
+
+    fn command_intervals(&self) -> anyhow::Result<CommandIntervals> {
+        let interval = |arg: Option<u64>, env: &str, default: Duration| -> anyhow::Result<_> {
+            Ok(match arg {
+                Some(ms) => Duration::from_millis(ms),
+                None => opt_env_var(env)?
+                    .map(Duration::from_millis)
+                    .unwrap_or(default),
+            })
+        };
+        Ok(CommandIntervals {
+            lan: interval(
+                self.lan_command_interval_ms,
+                "GOVEE_LAN_COMMAND_INTERVAL_MS",
+                DEFAULT_LAN_COMMAND_INTERVAL,
+            )?,
+            cloud: interval(
+                self.cloud_command_interval_ms,
+                "GOVEE_CLOUD_COMMAND_INTERVAL_MS",
+                DEFAULT_CLOUD_COMMAND_INTERVAL,
+            )?,
+        })
+    }
+
+    fn full_refresh(&self) -> anyhow::Result<bool> {
+        Ok(self.full_refresh
+            || match opt_env_var::<String>("GOVEE_FULL_REFRESH")? {
+                Some(v) => truthy(&v)?,
+                None => false,
+            })
+    }
+
+    /// Returns the BLE listener options, or None if the listener
+    /// is not enabled
+    fn ble_options(&self) -> anyhow::Result<Option<BleOptions>> {
+        let enabled = self.ble
+            || match opt_env_var::<String>("GOVEE_BLE_ENABLED")? {
+                Some(v) => truthy(&v)?,
+                None => false,
+            };
+        if !enabled {
+            return Ok(None);
+        }
+        if !cfg!(feature = "ble") {
+            anyhow::bail!(
+                "The BLE listener was requested, but this build of govee2mqtt \
+                 doesn't include the `ble` feature"
+            );
+        }
+
+        let allow = match &self.ble_allow {
+            Some(list) => Some(list.to_string()),
+            None => opt_env_var::<String>("GOVEE_BLE_ALLOW")?,
+        };
+        let allow = parse_mac_list(allow.as_deref().unwrap_or(""))?;
+
+        let timeout = match self.ble_timeout {
+            Some(minutes) => minutes,
+            None => opt_env_var("GOVEE_BLE_TIMEOUT")?.unwrap_or(DEFAULT_BLE_AVAILABILITY_TIMEOUT),
+        };
+        if timeout == 0 {
+            anyhow::bail!("--ble-timeout must be at least 1 minute");
+        }
+
+        Ok(Some(BleOptions { allow, timeout }))
+    }
+
+    fn lan_only(&self, args: &crate::Args) -> anyhow::Result<bool> {
+        let requested = self.lan_only
+            || match opt_env_var::<String>("GOVEE_LAN_ONLY")? {
+                Some(v) => truthy(&v)?,
+                None => false,
+            };
+        let has_credentials = args.api_args.opt_api_key()?.is_some()
+            || (args.undoc_args.opt_email()?.is_some()
+                && args.undoc_args.opt_password()?.is_some());
+
+        if requested && has_credentials {
+            log::info!(
+                "Running in LAN-only mode; the configured Govee credentials will not be used"
+            );
+        } else if !has_credentials {
+            log::info!("No Govee credentials are configured; running in LAN-only mode");
+        }
+        Ok(requested || !has_credentials)
+    }
+
+    /// Implements --dry-run
+    async fn check_configuration(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let mut problems = vec![];
+
+        match crate::config::current().path() {
+            Some(path) => println!("Configuration file: {}", path.display()),
+            None => println!("Configuration file: none"),
+        }
+
+        let lan_only = check(&mut problems, "LAN-only mode", self.lan_only(args)).unwrap_or(false);
+        println!(
+            "Mode: {}",
+            if lan_only {
+                "LAN only"
+            } else {
+                "Cloud and LAN"
+            }
+        );
+
+        let api_key = check(&mut problems, "API key", args.api_args.opt_api_key()).flatten();
+        match api_key.filter(|_| !lan_only) {
+            Some(key) => {
+                let client = GoveeApiClient::new(key);
+                if check(
+                    &mut problems,
+                    "Platform API",
+                    client
+                        .check_credentials()
+                        .await
+                        .context("verifying the API key"),
+                )
+                .is_some()
+                {
+                    println!("Platform API: enabled");
+                }
+            }
+            None => println!("Platform API: disabled"),
+        }
+
+        let email = check(&mut problems, "Govee email", args.undoc_args.opt_email()).flatten();
+        let password = check(
+            &mut problems,
+            "Govee password",
+            args.undoc_args.opt_password(),
+        )
+        .flatten();
+        check(
+            &mut problems,
+            "verification code",
+            args.undoc_args.verification(),
+        );
+        let account = email.is_some() && password.is_some() && !lan_only;
+        println!("Undocumented API and AWS IoT: {}", enabled(account));
+
+        if let Some(options) = check(
+            &mut problems,
+            "LAN discovery options",
+            args.lan_disco_args.to_disco_options(),
+        ) {
+            println!("LAN API: {}", enabled(!options.is_empty()));
+        }
+        check(
+            &mut problems,
+            "LAN max missed polls",
+            args.lan_disco_args.max_missed_polls(),
+        );
+        if let Some(devices) = check(
+            &mut problems,
+            "static LAN devices",
+            args.lan_disco_args.static_devices(),
+        ) {
+            for device in devices {
+                println!("  Static LAN device: {}", device.ip);
+            }
+        }
+
+        if let Some(intervals) = check(&mut problems, "poll intervals", self.poll_intervals()) {
+            let default = POLL_INTERVAL.num_seconds();
+            println!("Poll interval: {default}s");
+            for (device, interval) in intervals {
+                println!("  {device}: {}s", interval.num_seconds());
+            }
+        }
+        if let Some(n) = check(&mut problems, "poll concurrency", self.poll_concurrency()) {
+            println!("Poll concurrency: {n}");
+        }
+        if let Some(intervals) = check(&mut problems, "command intervals", self.command_intervals())
+        {
+            println!(
+                "Command interval: LAN {:?}, cloud {:?}",
+                intervals.lan, intervals.cloud
+            );
+        }
+        if let Some(age) = check(
+            &mut problems,
+            "state cache max age",
+            self.state_cache_max_age(),
+        ) {
+            match age {
+                Some(age) => println!("State cache: {} minutes", age.as_secs() / 60),
+                None => println!("State cache: disabled"),
+            }
+        }
+        check(&mut problems, "full refresh", self.full_refresh());
+        if let Some(ble) = check(&mut problems, "BLE options", self.ble_options()) {
+            match ble {
+                Some(ble) if ble.allow.is_empty() => {
+                    println!("BLE listener: enabled, for every sensor in range")
+                }
+                Some(ble) => println!("BLE listener: enabled, for {}", ble.allow.join(", ")),
+                None => println!("BLE listener: disabled"),
+            }
+        }
+
+        if let Some(token) = check(&mut problems, "HTTP auth token", self.http_auth_token()) {
+            if self.http_port == 0 {
+                println!("HTTP API: disabled");
+            } else {
+                println!(
+                    "HTTP API: {}:{}, {}",
+                    self.http_bind,
+                    self.http_port,
+                    if token.is_some() {
+                        "auth token required for control"
+                    } else {
+                        "no auth token"
+                    }
+                );
+            }
+        }
+
+        if let Some(protocol) = check(
+            &mut problems,
+            "MQTT",
+            check_hass_integration(&args.hass_args).await,
+        ) {
+            println!(
+                "MQTT: connected to {}:{} using {protocol}",
+                args.hass_args.mqtt_host()?,
+                args.hass_args.mqtt_port()?
+            );
+        }
+
+        if !problems.is_empty() {
+            anyhow::bail!(
+                "The configuration has problems with: {}",
+                problems.join(", ")
+            );
+        }
+        println!("The configuration is OK");
+        Ok(())
+    }
+
     pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        if self.dry_run {
+            return self.check_configuration(args).await;
+        }
+
         log::info!("Starting service. version {}", govee_version());
+        if let Some(path) = crate::config::current().path() {
+            log::info!("Using configuration file {}", path.display());
+        }
         let state = Arc::new(crate::service::state::State::new());
+        state.set_poll_intervals(self.poll_intervals()?).await;
+        let full_refresh = self.full_refresh()?;
+        state.set_full_refresh(full_refresh).await;
+        let lan_only = self.lan_only(args)?;
+        state.set_lan_only(lan_only).await;
+        let ble = self.ble_options()?;
+        state
+            .set_reloadable_args(ReloadableArgs {
+                api_args: args.api_args.clone(),
+                undoc_args: args.undoc_args.clone(),
+                lan_disco_args: args.lan_disco_args.clone(),
+                hass_args: args.hass_args.clone(),
+                poll_intervals: self.poll_intervals.clone(),
+            })
+            .await;
 
         // First, use the HTTP APIs to determine the list of devices and
         // their names.
 
-        if let Ok(client) = args.api_args.api_client() {
+        if let Some(client) = args.api_args.api_client().ok().filter(|_| !lan_only) {
             log::info!("Querying platform API for device list");
-            for info in client.get_devices().await? {
+            let client = client.with_full_refresh(full_refresh);
+            let list = client.get_device_list().await?;
+            state.record_platform_device_list_hash(&list.hash).await;
+            for info in list.devices {
                 let mut device = state.device_mut(&info.sku, &info.device).await;
                 device.set_http_device_info(info);
             }
 
             state.set_platform_client(client).await;
         }
-        if let Ok(client) = args.undoc_args.api_client() {
+        if let Some(client) = args.undoc_args.api_client().ok().filter(|_| !lan_only) {
             log::info!("Querying undocumented API for device + room list");
             let acct = client.login_account_cached().await?;
             let info = client.get_device_list(&acct.token).await?;
-            let mut group_by_id = HashMap::new();
-            for group in info.groups {
-                group_by_id.insert(group.group_id, group.group_name);
-            }
-            for entry in info.devices {
-                let mut device = state.device_mut(&entry.sku, &entry.device).await;
-                let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
-                device.set_undoc_device_info(entry, room_name);
-            }
+            state.apply_undoc_device_list(info).await;
 
-            start_iot_client(args, state.clone(), Some(acct)).await?;
+            start_iot_service(args, state.clone(), acct).await;
 
             state.set_undoc_client(client).await;
         }
@@ -124,20 +982,29 @@ impl ServeCommand {
         // Now start discovery
 
         let options = args.lan_disco_args.to_disco_options()?;
+        set_lan_max_missed_polls(args.lan_disco_args.max_missed_polls()?);
         if !options.is_empty() {
             log::info!("Starting LAN discovery");
             let state = state.clone();
             let (client, mut scan) = LanClient::new(options).await?;
 
             state.set_lan_client(client.clone()).await;
+            state
+                .apply_static_lan_devices(args.lan_disco_args.static_devices()?)
+                .await;
 
             tokio::spawn(async move {
                 while let Some(lan_device) = scan.recv().await {
                     log::trace!("LAN disco: {lan_device:?}");
-                    state
-                        .device_mut(&lan_device.sku, &lan_device.device)
-                        .await
-                        .set_lan_device(lan_device.clone());
+                    let device = {
+                        let mut device =
+                            state.device_mut(&lan_device.sku, &lan_device.device).await;
+                        device.set_lan_device(lan_device.clone());
+                        device.clone()
+                    };
+                    if let Err(err) = state.report_device_availability(&device, false).await {
+                        log::error!("while reporting availability of {device}: {err:#}");
+                    }
 
                     let state = state.clone();
                     let client = client.clone();
@@ -164,6 +1031,12 @@ impl ServeCommand {
             sleep(Duration::from_secs(10)).await;
         }
 
+        state
+            .set_state_cache_max_age(self.state_cache_max_age()?)
+            .await;
+        state.set_command_intervals(self.command_intervals()?).await;
+        state.restore_device_states().await;
+
         log::info!("Devices returned from Govee's APIs");
         for device in state.devices().await {
             log::info!("{device}");
@@ -213,32 +1086,186 @@ impl ServeCommand {
                 }
             } else if device.http_device_info.is_none() {
                 log::warn!("  Unknown device type. Cannot map to Home Assistant.");
-                if state.get_platform_client().await.is_none() {
+                if !lan_only && state.get_platform_client().await.is_none() {
                     log::warn!(
                         "  Recommendation: configure your Govee API Key so that \
                                   metadata can be fetched from Govee"
                     );
                 }
             }
+            log_control_path(&state, &device).await;
 
             log::info!("");
         }
 
-        // Start periodic status polling
-        {
+        // Start periodic status polling. Without the cloud
+        // APIs, only the LAN poll is relevant.
+        if !lan_only {
             let state = state.clone();
+            let concurrency = self.poll_concurrency()?;
             tokio::spawn(async move {
-                if let Err(err) = periodic_state_poll(state).await {
+                if let Err(err) = periodic_state_poll(state, concurrency).await {
                     log::error!("periodic_state_poll: {err:#}");
                 }
             });
         }
+        tokio::spawn(periodic_lan_poll(state.clone()));
+
+        if let Some(ble) = ble {
+            log::info!("Starting BLE listener");
+            set_ble_availability_timeout(ble.timeout);
+            state.set_ble_allow_list(ble.allow).await;
+            #[cfg(feature = "ble")]
+            tokio::spawn(crate::service::ble_listener::run_ble_listener(
+                state.clone(),
+            ));
+        }
 
         // start advertising on local mqtt
         spawn_hass_integration(state.clone(), &args.hass_args).await?;
+        spawn_sighup_handler(state.clone());
+
+        if self.http_port == 0 {
+            log::info!("The HTTP API is disabled");
+            std::future::pending::<()>().await;
+        }
+
+        run_http_server(
+            state.clone(),
+            self.http_bind,
+            self.http_port,
+            self.http_auth_token()?,
+        )
+        .await
+        .with_context(|| format!("Starting HTTP service on port {}", self.http_port))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn poll_intervals() {
+        k9::assert_equal!(
+            parse_poll_intervals(" H5179=30, Office Strip = 600,AA:BB=0,").unwrap(),
+            vec![
+                ("H5179".to_string(), chrono::Duration::seconds(30)),
+                ("Office Strip".to_string(), chrono::Duration::seconds(600)),
+                ("AA:BB".to_string(), chrono::Duration::seconds(0)),
+            ]
+        );
+        assert!(parse_poll_intervals("H5179").is_err());
+        assert!(parse_poll_intervals("H5179=soon").is_err());
+        assert!(parse_poll_intervals("H5179=-1").is_err());
+    }
+
+    #[test]
+    fn mac_list() {
+        k9::assert_equal!(
+            parse_mac_list(" a4:c1:38:11:22:33, A4-C1-38-44-55-66,").unwrap(),
+            vec![
+                "A4:C1:38:11:22:33".to_string(),
+                "A4:C1:38:44:55:66".to_string()
+            ]
+        );
+        k9::assert_equal!(parse_mac_list("").unwrap(), Vec::<String>::new());
+        k9::assert_equal!(
+            parse_mac_list("A4:C1:38").unwrap_err().to_string(),
+            "`A4:C1:38` is not a MAC address"
+        );
+    }
+
+    #[test]
+    fn iot_fallback() {
+        let now = Utc::now();
+        let mut health = IotConnectionHealth {
+            connected: false,
+            disconnected_since: Some(now - chrono::Duration::minutes(1)),
+            ..Default::default()
+        };
+
+        // A brief disconnect doesn't change anything
+        let fallback = IotFallback::Inactive.next(&health, now, |_| true);
+        k9::assert_equal!(fallback, IotFallback::Inactive);
+
+        let later = now + chrono::Duration::minutes(5);
+        let fallback = fallback.next(&health, later, |_| true);
+        k9::assert_equal!(fallback, IotFallback::Outage);
+
+        health.connected = true;
+        health.connected_since = Some(later);
+        health.disconnected_since = None;
+        let fallback = fallback.next(&health, later, |_| true);
+        k9::assert_equal!(fallback, IotFallback::Recovering(later));
+
+        // Until every device has been pushed, or the grace period ends
+        k9::assert_equal!(fallback.next(&health, later, |_| true), fallback);
+        k9::assert_equal!(
+            fallback.next(&health, later, |_| false),
+            IotFallback::Inactive
+        );
+        k9::assert_equal!(
+            fallback.next(&health, later + chrono::Duration::minutes(10), |_| true),
+            IotFallback::Inactive
+        );
+    }
+
+    #[test]
+    fn iot_fallback_devices() {
+        let list: crate::undoc_api::DevicesResponse =
+            crate::platform_api::from_json(include_str!("../../test-data/undoc-device-list.json"))
+                .unwrap();
+        let entry = list
+            .devices
+            .into_iter()
+            .find(|entry| entry.sku == "H6072")
+            .unwrap();
+        let device_with_sku = |sku: &str| {
+            let mut device = Device::new(sku, &entry.device);
+            device.set_undoc_device_info(entry.clone(), None);
+            device
+        };
+
+        let light = device_with_sku("H6072");
+        assert!(light.state_arrives_via_iot());
+        assert!(IotFallback::Outage.applies_to(&light));
+        assert!(!IotFallback::excludes(&light));
+
+        // Its quirk says that its Platform API data is mangled
+        let humidifier = device_with_sku("H7160");
+        assert!(humidifier.state_arrives_via_iot());
+        assert!(!IotFallback::Outage.applies_to(&humidifier));
+        assert!(IotFallback::excludes(&humidifier));
+    }
+
+    #[tokio::test]
+    async fn poll_pool_pause() {
+        let pool = PollPool::new(2);
+        let started = Instant::now();
+        pool.pause(Duration::from_millis(100));
+        // A shorter pause doesn't cut short the longer one
+        pool.pause(Duration::from_millis(10));
+
+        let (a, b) = tokio::join!(pool.acquire(), pool.acquire());
+        assert!(a.is_ok() && b.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        k9::assert_equal!(pool.permits.available_permits(), 0);
+    }
+
+    #[test]
+    fn poll_due() {
+        let tick = Duration::from_secs(60);
+        let interval = chrono::Duration::seconds(120);
+        let start = Utc::now();
+        let at = |ms: i64| start + chrono::Duration::milliseconds(ms);
 
-        run_http_server(state.clone(), self.http_port)
-            .await
-            .with_context(|| format!("Starting HTTP service on port {}", self.http_port))
+        assert!(is_poll_due(None, start, interval, tick));
+        // Ticks arrive slightly early or late relative to the last poll,
+        // but each interval should be polled exactly once
+        assert!(!is_poll_due(Some(start), at(60_010), interval, tick));
+        assert!(is_poll_due(Some(start), at(119_990), interval, tick));
+        assert!(is_poll_due(Some(start), at(120_010), interval, tick));
+        assert!(!is_poll_due(Some(at(119_990)), at(180_000), interval, tick));
     }
 }
@@ -0,0 +1,104 @@
+use crate::service::health::{HealthReport, HealthStatus};
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+/// Reports the health of a running instance, by querying its
+/// HTTP API. Exits with an error if the instance is unhealthy,
+/// so that this can be used as a container healthcheck.
+#[derive(clap::Parser, Debug)]
+pub struct StatusCommand {
+    /// The URL of the HTTP API of the running instance
+    #[arg(long, default_value = "http://127.0.0.1:8056")]
+    url: String,
+
+    /// Print the raw JSON health report
+    #[arg(long)]
+    json: bool,
+}
+
+fn describe_time(time: Option<DateTime<Utc>>) -> String {
+    match time {
+        Some(time) => time.to_string(),
+        None => "never".to_string(),
+    }
+}
+
+impl StatusCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let url = format!("{}/healthz", self.url.trim_end_matches('/'));
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?
+            .get(&url)
+            .send()
+            .await
+            .map_err(|err| anyhow::anyhow!("querying {url}: {err:#}"))?;
+        // An unhealthy instance responds with a 503, but still
+        // includes the report
+        let report: HealthReport = response.json().await?;
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            println!("Status: {:?}", report.status);
+            for warning in &report.warnings {
+                println!("Warning: {warning}");
+            }
+            println!("Mode: {}", report.mode);
+            println!(
+                "MQTT: {}",
+                if report.mqtt.connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                }
+            );
+            if let Some(iot) = &report.iot {
+                let mut line = format!(
+                    "AWS IoT: {}, {} disconnects",
+                    if iot.connected {
+                        "connected"
+                    } else {
+                        "disconnected"
+                    },
+                    iot.disconnect_count
+                );
+                if let Some(err) = &iot.last_error {
+                    line.push_str(&format!(", last error: {err}"));
+                }
+                println!("{line}");
+            }
+            if let Some(session) = &report.account_session {
+                println!(
+                    "Govee account: {} logins, {} session refreshes",
+                    session.logins, session.refreshes
+                );
+            }
+            if let Some(platform) = &report.platform_api {
+                let mut line = format!(
+                    "Platform API: last success {}",
+                    describe_time(platform.last_success)
+                );
+                if let Some(until) = platform.rate_limited_until {
+                    line.push_str(&format!(", suspended until {until}"));
+                }
+                println!("{line}");
+            }
+            if let Some(lan) = &report.lan {
+                println!(
+                    "LAN: last discovery response {}",
+                    describe_time(lan.last_discovery_response)
+                );
+            }
+            println!(
+                "Devices: {} ({} stale)",
+                report.devices.total, report.devices.stale
+            );
+        }
+
+        if report.status == HealthStatus::Unhealthy {
+            anyhow::bail!("govee2mqtt is unhealthy");
+        }
+        Ok(())
+    }
+}
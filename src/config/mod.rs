@@ -0,0 +1,494 @@
+//! The optional TOML configuration file.
+//!
+//! The file can hold any of the settings that can be passed via
+//! the environment, plus per-device sections. A setting that is
+//! present in the environment takes precedence over the file, and
+//! one that is passed on the command line takes precedence over both.
+//! Settings are looked up via `crate::opt_env_var`, so every command
+//! sees the same configuration.
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub mod toml;
+
+/// The keys that may be used in the configuration file, and the
+/// environment variables that they correspond to
+const SETTINGS: &[(&str, &str)] = &[
+    ("api_key", "GOVEE_API_KEY"),
+    ("email", "GOVEE_EMAIL"),
+    ("password", "GOVEE_PASSWORD"),
+    ("verification_code", "GOVEE_VERIFICATION_CODE"),
+    ("verification_code_file", "GOVEE_VERIFICATION_CODE_FILE"),
+    ("cache_dir", "GOVEE_CACHE_DIR"),
+    ("quirks_file", "GOVEE_QUIRKS_FILE"),
+    ("log_level", "RUST_LOG"),
+    ("log_sensitive_data", "GOVEE_LOG_SENSITIVE_DATA"),
+    ("instance_name", "GOVEE_INSTANCE_NAME"),
+    ("temperature_scale", "GOVEE_TEMPERATURE_SCALE"),
+    ("segment_lights", "GOVEE_SEGMENT_LIGHTS"),
+    ("light_transition_rate", "GOVEE_LIGHT_TRANSITION_RATE"),
+    ("coarse_cloud_transitions", "GOVEE_COARSE_CLOUD_TRANSITIONS"),
+    ("http_auth_token", "GOVEE_HTTP_AUTH_TOKEN"),
+    ("state_cache_max_age", "GOVEE_STATE_CACHE_MAX_AGE"),
+    ("poll_intervals", "GOVEE_POLL_INTERVALS"),
+    ("poll_concurrency", "GOVEE_POLL_CONCURRENCY"),
+    (
+        "cloud_command_interval_ms",
+        "GOVEE_CLOUD_COMMAND_INTERVAL_MS",
+    ),
+    ("control_order", "GOVEE_CONTROL_ORDER"),
+    ("full_refresh", "GOVEE_FULL_REFRESH"),
+    ("lan_only", "GOVEE_LAN_ONLY"),
+    ("hass.device_discovery", "GOVEE_HASS_DEVICE_DISCOVERY"),
+    ("hass.state_debounce_ms", "GOVEE_HASS_STATE_DEBOUNCE_MS"),
+    ("hass.effect_list_limit", "GOVEE_HASS_EFFECT_LIST_LIMIT"),
+    ("hass.leak_clear_secs", "GOVEE_HASS_LEAK_CLEAR_SECS"),
+    ("hass.room_lights", "GOVEE_HASS_ROOM_LIGHTS"),
+    ("hass.sensor_precision", "GOVEE_HASS_SENSOR_PRECISION"),
+    (
+        "hass.raw_state_attributes",
+        "GOVEE_HASS_RAW_STATE_ATTRIBUTES",
+    ),
+    ("mqtt.host", "GOVEE_MQTT_HOST"),
+    ("mqtt.port", "GOVEE_MQTT_PORT"),
+    ("mqtt.user", "GOVEE_MQTT_USER"),
+    ("mqtt.password", "GOVEE_MQTT_PASSWORD"),
+    ("mqtt.protocol", "GOVEE_MQTT_PROTOCOL"),
+    ("mqtt.client_id", "GOVEE_MQTT_CLIENT_ID"),
+    ("mqtt.birth_payload", "GOVEE_MQTT_BIRTH_PAYLOAD"),
+    ("mqtt.lwt_payload", "GOVEE_MQTT_LWT_PAYLOAD"),
+    ("mqtt.retain_state", "GOVEE_MQTT_RETAIN_STATE"),
+    ("mqtt.state_qos", "GOVEE_MQTT_STATE_QOS"),
+    ("mqtt.discovery_qos", "GOVEE_MQTT_DISCOVERY_QOS"),
+    ("mqtt.tls", "GOVEE_MQTT_TLS"),
+    ("mqtt.ca_file", "GOVEE_MQTT_CA_FILE"),
+    ("mqtt.cert_file", "GOVEE_MQTT_CERT_FILE"),
+    ("mqtt.key_file", "GOVEE_MQTT_KEY_FILE"),
+    ("lan.devices", "GOVEE_LAN_DEVICES"),
+    ("lan.interfaces", "GOVEE_LAN_INTERFACES"),
+    ("lan.scan", "GOVEE_LAN_SCAN"),
+    ("lan.scan_interval", "GOVEE_LAN_SCAN_INTERVAL"),
+    ("lan.no_multicast", "GOVEE_LAN_NO_MULTICAST"),
+    ("lan.broadcast_all", "GOVEE_LAN_BROADCAST_ALL"),
+    ("lan.broadcast_global", "GOVEE_LAN_BROADCAST_GLOBAL"),
+    ("lan.unicast_known", "GOVEE_LAN_UNICAST_KNOWN"),
+    ("lan.max_missed_polls", "GOVEE_LAN_MAX_MISSED_POLLS"),
+    ("lan.disco_timeout", "GOVEE_LAN_DISCO_TIMEOUT"),
+    ("lan.command_interval_ms", "GOVEE_LAN_COMMAND_INTERVAL_MS"),
+    ("ble.enabled", "GOVEE_BLE_ENABLED"),
+    ("ble.allow", "GOVEE_BLE_ALLOW"),
+    ("ble.timeout", "GOVEE_BLE_TIMEOUT"),
+];
+
+/// The table that holds the per-device sections
+const DEVICE_TABLE: &str = "device";
+
+/// A value that was read from the configuration file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setting {
+    pub value: String,
+    /// Where the value was defined, for use in error messages
+    pub location: String,
+}
+
+/// The options that can be set for an individual device, in a
+/// `[device."ID"]` section. The section name is the id, name or
+/// SKU of the device.
+#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// The number of seconds between polls of the device's state.
+    /// 0 means that the device is never polled.
+    pub poll_interval: Option<u64>,
+    /// Whether to create a light entity for each segment
+    pub segment_lights: Option<bool>,
+    /// The name to use for the device in the hass device registry,
+    /// in place of the name from the Govee app
+    pub name: Option<String>,
+    /// Capability instances for which no entity should be created
+    pub suppress: Option<Vec<String>>,
+    /// Whether entities are enabled when hass first discovers them,
+    /// keyed by integration (eg: "sensor") or entity category
+    /// (eg: "diagnostic")
+    pub enabled_by_default: Option<BTreeMap<String, bool>>,
+    /// Whether to avoid the Platform API for control and state,
+    /// overriding the built-in quirk
+    pub avoid_platform_api: Option<bool>,
+    /// Whether to send every command that the LAN API supports via
+    /// the LAN API, including scenes and segments
+    pub prefer_lan_control: Option<bool>,
+    /// The order in which to try the LAN, IoT and Platform APIs for
+    /// power, brightness and color commands, overriding
+    /// `control_order`
+    pub control_order: Option<Vec<String>>,
+    /// Added to temperature readings, in the configured temperature scale
+    pub temperature_offset: Option<f64>,
+    /// Added to humidity readings, in percentage points
+    pub humidity_offset: Option<f64>,
+    /// Whether to also apply the calibration offsets that were
+    /// set up for the device in the Govee app
+    pub use_app_calibration: Option<bool>,
+    /// Whether to publish the raw capability state as attributes
+    pub raw_state_attributes: Option<bool>,
+}
+
+impl DeviceConfig {
+    /// Fills in the options that are not set from `other`
+    fn or(self, other: &Self) -> Self {
+        Self {
+            poll_interval: self.poll_interval.or(other.poll_interval),
+            segment_lights: self.segment_lights.or(other.segment_lights),
+            name: self.name.or_else(|| other.name.clone()),
+            suppress: self.suppress.or_else(|| other.suppress.clone()),
+            enabled_by_default: self
+                .enabled_by_default
+                .or_else(|| other.enabled_by_default.clone()),
+            avoid_platform_api: self.avoid_platform_api.or(other.avoid_platform_api),
+            prefer_lan_control: self.prefer_lan_control.or(other.prefer_lan_control),
+            control_order: self.control_order.or_else(|| other.control_order.clone()),
+            temperature_offset: self.temperature_offset.or(other.temperature_offset),
+            humidity_offset: self.humidity_offset.or(other.humidity_offset),
+            use_app_calibration: self.use_app_calibration.or(other.use_app_calibration),
+            raw_state_attributes: self.raw_state_attributes.or(other.raw_state_attributes),
+        }
+    }
+
+    pub fn suppresses(&self, instance: &str) -> bool {
+        self.suppress
+            .as_ref()
+            .is_some_and(|list| list.iter().any(|item| item == instance))
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct ConfigFile {
+    path: Option<PathBuf>,
+    settings: HashMap<&'static str, Setting>,
+    devices: Vec<(String, DeviceConfig)>,
+}
+
+static CONFIG: Lazy<ArcSwap<ConfigFile>> = Lazy::new(Default::default);
+
+/// Loads the configuration file at path, or the one named by
+/// $GOVEE_CONFIG_FILE, and makes it the current configuration.
+/// If neither is set, the current configuration is left empty.
+pub fn load(path: Option<&Path>) -> anyhow::Result<()> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match std::env::var_os("GOVEE_CONFIG_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(()),
+        },
+    };
+    CONFIG.store(Arc::new(ConfigFile::read(&path)?));
+    Ok(())
+}
+
+/// Re-reads the current configuration file, if there is one, and
+/// makes it the current configuration. Returns the prior configuration,
+/// so that it can be restored if the new one is rejected.
+pub fn reload() -> anyhow::Result<Arc<ConfigFile>> {
+    let prior = current();
+    if let Some(path) = prior.path() {
+        CONFIG.store(Arc::new(ConfigFile::read(path)?));
+    }
+    Ok(prior)
+}
+
+pub fn restore(config: Arc<ConfigFile>) {
+    CONFIG.store(config);
+}
+
+/// Returns the current configuration
+pub fn current() -> Arc<ConfigFile> {
+    CONFIG.load_full()
+}
+
+/// Returns the value from the configuration file for the setting
+/// that corresponds to the environment variable `name`
+pub fn setting(name: &str) -> Option<Setting> {
+    current().settings.get(name).cloned()
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading configuration file {}", path.display()))?;
+        let mut config = Self::parse(&path.display().to_string(), &text)?;
+        config.path.replace(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// Parses text as a configuration file. `file_name` is used
+    /// to describe the location of any problems.
+    pub fn parse(file_name: &str, text: &str) -> anyhow::Result<Self> {
+        let doc = toml::parse(text).map_err(|err| anyhow::anyhow!("{file_name}: {err}"))?;
+        let mut config = Self::default();
+
+        let locate = |path: &[String]| match doc.line_of(path) {
+            Some(line) => format!("{file_name}: line {line}"),
+            None => file_name.to_string(),
+        };
+
+        let mut leaves = vec![];
+        for (key, value) in &doc.root {
+            if key == DEVICE_TABLE {
+                let JsonValue::Object(devices) = value else {
+                    anyhow::bail!(
+                        "{}: `{DEVICE_TABLE}` must be a table of device sections",
+                        locate(std::slice::from_ref(key))
+                    );
+                };
+                for (device, section) in devices {
+                    let path = vec![key.clone(), device.clone()];
+                    let device_config = serde_json_path_to_error::from_value(section.clone())
+                        .map_err(|err| {
+                            let mut field_path = path.clone();
+                            field_path.extend(
+                                err.path()
+                                    .to_string()
+                                    .split('.')
+                                    .filter(|s| !s.is_empty())
+                                    .map(str::to_string),
+                            );
+                            anyhow::anyhow!(
+                                "{}: `{}`: {}",
+                                locate(&field_path),
+                                toml::format_path(&path),
+                                err.inner()
+                            )
+                        })?;
+                    config.devices.push((device.to_string(), device_config));
+                }
+            } else {
+                collect_leaves(vec![key.clone()], value, &mut leaves);
+            }
+        }
+
+        for (path, value) in leaves {
+            let key = toml::format_path(&path);
+            let location = locate(&path);
+            let Some((_, env_name)) = SETTINGS.iter().find(|(k, _)| *k == key) else {
+                anyhow::bail!("{location}: `{key}` is not a known setting");
+            };
+            let value = setting_value(&value).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{location}: `{key}` must be a string, number, boolean or an array of those"
+                )
+            })?;
+            config.settings.insert(
+                env_name,
+                Setting {
+                    value,
+                    location: format!("`{key}` ({location})"),
+                },
+            );
+        }
+
+        Ok(config)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the settings, keyed by the name of the corresponding
+    /// environment variable
+    pub fn settings(&self) -> impl Iterator<Item = (&str, &Setting)> {
+        self.settings.iter().map(|(name, setting)| (*name, setting))
+    }
+
+    /// Returns the per-device sections, in the order in which
+    /// they appear in the file
+    pub fn devices(&self) -> &[(String, DeviceConfig)] {
+        &self.devices
+    }
+
+    /// Returns the options for a device, combining the sections that
+    /// apply to it. An option from a section for the device's id or
+    /// name takes precedence over one from a section for its SKU.
+    pub fn device_config(&self, id: &str, name: &str, sku: &str) -> DeviceConfig {
+        let mut config = DeviceConfig::default();
+        for key in [id, name, sku] {
+            for (device, section) in &self.devices {
+                if device.eq_ignore_ascii_case(key) {
+                    config = config.or(section);
+                }
+            }
+        }
+        config
+    }
+}
+
+/// Flattens nested tables into the paths of their values
+fn collect_leaves(
+    path: Vec<String>,
+    value: &JsonValue,
+    leaves: &mut Vec<(Vec<String>, JsonValue)>,
+) {
+    match value {
+        JsonValue::Object(map) => {
+            for (key, value) in map {
+                let mut path = path.clone();
+                path.push(key.clone());
+                collect_leaves(path, value, leaves);
+            }
+        }
+        _ => leaves.push((path, value.clone())),
+    }
+}
+
+/// Renders a value the way it would be written in the environment.
+/// Arrays become comma separated lists.
+fn setting_value(value: &JsonValue) -> Option<String> {
+    match value {
+        JsonValue::String(s) => Some(s.to_string()),
+        JsonValue::Bool(_) | JsonValue::Number(_) => Some(value.to_string()),
+        JsonValue::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                JsonValue::Array(_) => None,
+                item => setting_value(item),
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|items| items.join(",")),
+        JsonValue::Null | JsonValue::Object(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn settings() {
+        let config = ConfigFile::parse(
+            "govee.toml",
+            r#"
+api_key = "secret"
+mqtt.port = 1883
+
+[lan]
+devices = ["10.0.0.1", "10.0.0.2=H6199/AA:BB"]
+no_multicast = true
+
+[device."AA:BB:CC"]
+poll_interval = 30
+
+[device.H5179]
+segment_lights = true
+"#,
+        )
+        .unwrap();
+
+        k9::assert_equal!(
+            config.settings.get("GOVEE_API_KEY").unwrap().value,
+            "secret"
+        );
+        k9::assert_equal!(
+            config.settings.get("GOVEE_MQTT_PORT").unwrap(),
+            &Setting {
+                value: "1883".to_string(),
+                location: "`mqtt.port` (govee.toml: line 3)".to_string(),
+            }
+        );
+        k9::assert_equal!(
+            config.settings.get("GOVEE_LAN_DEVICES").unwrap().value,
+            "10.0.0.1,10.0.0.2=H6199/AA:BB"
+        );
+        k9::assert_equal!(
+            config.settings.get("GOVEE_LAN_NO_MULTICAST").unwrap().value,
+            "true"
+        );
+        k9::assert_equal!(
+            config.devices(),
+            &[
+                (
+                    "AA:BB:CC".to_string(),
+                    DeviceConfig {
+                        poll_interval: Some(30),
+                        ..Default::default()
+                    }
+                ),
+                (
+                    "H5179".to_string(),
+                    DeviceConfig {
+                        segment_lights: Some(true),
+                        ..Default::default()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn device_config() {
+        let config = ConfigFile::parse(
+            "govee.toml",
+            r#"
+[device.H6159]
+suppress = ["gradientToggle"]
+name = "Strip"
+
+[device."AA:BB:CC"]
+name = "Kitchen Strip"
+
+[device.H6159.enabled_by_default]
+diagnostic = false
+"#,
+        )
+        .unwrap();
+
+        let kitchen = config.device_config("AA:BB:CC", "Strip 1", "H6159");
+        k9::assert_equal!(kitchen.name.as_deref(), Some("Kitchen Strip"));
+        assert!(kitchen.suppresses("gradientToggle"));
+        assert!(!kitchen.suppresses("nightlightToggle"));
+        k9::assert_equal!(
+            kitchen.enabled_by_default,
+            Some(BTreeMap::from([("diagnostic".to_string(), false)]))
+        );
+
+        let other = config.device_config("DD:EE:FF", "Strip 2", "H6159");
+        k9::assert_equal!(other.name.as_deref(), Some("Strip"));
+
+        k9::assert_equal!(
+            config.device_config("DD:EE:FF", "Lamp", "H6008"),
+            DeviceConfig::default()
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| {
+            ConfigFile::parse("govee.toml", text)
+                .unwrap_err()
+                .to_string()
+        };
+        k9::assert_equal!(
+            error("api_key = \"a\"\n\n[mqtt]\nhots = \"b\""),
+            "govee.toml: line 4: `mqtt.hots` is not a known setting"
+        );
+        k9::assert_equal!(
+            error("[mqtt]\nport = { a = 1 }"),
+            "govee.toml: line 2: `mqtt.port.a` is not a known setting"
+        );
+        k9::assert_equal!(
+            error("[mqtt\nport = 1"),
+            "govee.toml: line 1: invalid table header\nexpected `.`, `]`"
+        );
+        k9::assert_equal!(
+            error("[device.H5179]\n\npoll_interval = \"often\""),
+            "govee.toml: line 3: `device.H5179`: invalid type: string \"often\", expected u64"
+        );
+        k9::assert_equal!(
+            error("[device.H5179]\ncolour = \"red\""),
+            "govee.toml: line 2: `device.H5179`: unknown field `colour`, expected one of `poll_interval`, `segment_lights`, `name`, `suppress`, `enabled_by_default`, `avoid_platform_api`, `prefer_lan_control`, `control_order`, `temperature_offset`, `humidity_offset`, `use_app_calibration`, `raw_state_attributes`"
+        );
+    }
+}
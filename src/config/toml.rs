@@ -0,0 +1,114 @@
+//! Parses the TOML configuration and quirks files, recording the
+//! line on which each key was defined, so that problems with the
+//! values can be reported precisely after the document has been
+//! parsed. Dates and times are kept as strings.
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
+use toml_edit::TableLike;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct Document {
+    pub root: Map<String, JsonValue>,
+    /// The line on which each key was defined, keyed by the path
+    /// to the key as formatted by `format_path`
+    lines: HashMap<String, usize>,
+}
+
+impl Document {
+    /// Returns the line on which the key at path was defined.
+    /// Keys within inline tables don't have their own lines, so for
+    /// those the line of the closest enclosing key is returned.
+    pub fn line_of(&self, path: &[String]) -> Option<usize> {
+        (1..=path.len())
+            .rev()
+            .find_map(|len| self.lines.get(&format_path(&path[..len])).copied())
+    }
+}
+
+/// Formats a key path the way that it would be written in the
+/// document, quoting any components that are not bare keys
+pub fn format_path(path: &[String]) -> String {
+    path.iter()
+        .map(|key| {
+            if !key.is_empty() && key.chars().all(is_bare_key_char) {
+                key.to_string()
+            } else {
+                format!("{key:?}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+pub fn parse(text: &str) -> Result<Document, ParseError> {
+    let line_at = |offset: usize| text[..offset.min(text.len())].matches('\n').count() + 1;
+
+    let table = toml::from_str::<toml::Table>(text).map_err(|err| ParseError {
+        line: err.span().map_or(1, |span| line_at(span.start)),
+        message: err.message().to_string(),
+    })?;
+
+    let mut lines = HashMap::new();
+    // The document has already been parsed successfully above, so this
+    // can only fail if the parsers disagree, in which case we merely
+    // lack the line numbers
+    if let Ok(doc) = toml_edit::ImDocument::parse(text) {
+        record_lines(doc.as_table(), &mut vec![], &mut lines, &line_at);
+    }
+
+    Ok(Document {
+        root: table
+            .into_iter()
+            .map(|(key, value)| (key, to_json(value)))
+            .collect(),
+        lines,
+    })
+}
+
+fn record_lines(
+    table: &dyn TableLike,
+    path: &mut Vec<String>,
+    lines: &mut HashMap<String, usize>,
+    line_at: &impl Fn(usize) -> usize,
+) {
+    for (key, item) in table.iter() {
+        path.push(key.to_string());
+        if let Some(span) = table.get_key_value(key).and_then(|(key, _)| key.span()) {
+            lines
+                .entry(format_path(path))
+                .or_insert_with(|| line_at(span.start));
+        }
+        if let Some(table) = item.as_table_like() {
+            record_lines(table, path, lines, line_at);
+        }
+        path.pop();
+    }
+}
+
+fn to_json(value: toml::Value) -> JsonValue {
+    match value {
+        toml::Value::String(s) => s.into(),
+        toml::Value::Integer(i) => i.into(),
+        toml::Value::Float(f) => f.into(),
+        toml::Value::Boolean(b) => b.into(),
+        toml::Value::Datetime(when) => when.to_string().into(),
+        toml::Value::Array(items) => items.into_iter().map(to_json).collect(),
+        toml::Value::Table(table) => JsonValue::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, to_json(value)))
+                .collect(),
+        ),
+    }
+}
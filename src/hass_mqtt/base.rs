@@ -1,26 +1,128 @@
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::topic_safe_id;
+use crate::service::hass::{
+    availability_topic, device_availability_topic, instance_naming, scoped_unique_id, topic_prefix,
+    topic_safe_device_id, topic_safe_id, InstanceNaming,
+};
+use crate::service::state::DeviceGroup;
 use crate::version_info::govee_version;
-use serde::Serialize;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
 
 const MODEL: &str = "gv2mqtt";
 const URL: &str = "https://github.com/wez/govee2mqtt";
 
-#[derive(Serialize, Clone, Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct EntityConfig {
     pub availability_topic: String,
     pub name: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub device_class: Option<&'static str>,
     pub origin: Origin,
     pub device: Device,
     pub unique_id: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub entity_category: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
 }
 
+impl EntityConfig {
+    /// Returns whether hass should enable the entity when it is first
+    /// discovered, if that was configured for the device. A setting
+    /// for the integration takes precedence over one for the category.
+    pub fn enabled_by_default(&self, integration: &str) -> Option<bool> {
+        let settings = &self.device.enabled_by_default;
+        settings
+            .get(integration)
+            .or_else(|| {
+                self.entity_category
+                    .as_ref()
+                    .and_then(|category| settings.get(category))
+            })
+            .copied()
+    }
+
+    /// Returns the list of availability topics that must all be
+    /// online for hass to consider the entity to be available.
+    /// The global topic is always included so that the last-will
+    /// continues to mark everything as unavailable.
+    pub fn availability_topics(&self) -> Vec<String> {
+        let mut topics = vec![self.availability_topic.clone()];
+        let global = availability_topic();
+        if self.availability_topic != global {
+            topics.push(global);
+        }
+        if let Some(topic) = &self.device.availability_topic {
+            topics.push(topic.clone());
+        }
+        topics
+    }
+}
+
+#[derive(Serialize)]
+struct AvailabilityEntry<'a> {
+    topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_available: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_not_available: Option<&'a str>,
+}
+
+/// Returns the payloads for the global availability topic, if they
+/// differ from the hass defaults of online and offline
+fn custom_availability_payloads() -> Option<(&'static str, &'static str)> {
+    let naming = instance_naming();
+    let defaults = InstanceNaming::default();
+    if naming.birth_payload == defaults.birth_payload && naming.lwt_payload == defaults.lwt_payload
+    {
+        None
+    } else {
+        Some((&naming.birth_payload, &naming.lwt_payload))
+    }
+}
+
+impl Serialize for EntityConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        let global = availability_topic();
+        let payloads = custom_availability_payloads();
+        let topics = self.availability_topics();
+        if topics.len() == 1 {
+            map.serialize_entry("availability_topic", &self.availability_topic)?;
+            if let Some((available, not_available)) = payloads.filter(|_| topics[0] == global) {
+                map.serialize_entry("payload_available", available)?;
+                map.serialize_entry("payload_not_available", not_available)?;
+            }
+        } else {
+            let entries: Vec<AvailabilityEntry> = topics
+                .into_iter()
+                .map(|topic| {
+                    let payloads = payloads.filter(|_| topic == global);
+                    AvailabilityEntry {
+                        topic,
+                        payload_available: payloads.map(|p| p.0),
+                        payload_not_available: payloads.map(|p| p.1),
+                    }
+                })
+                .collect();
+            map.serialize_entry("availability", &entries)?;
+            map.serialize_entry("availability_mode", "all")?;
+        }
+        map.serialize_entry("name", &self.name)?;
+        if let Some(device_class) = &self.device_class {
+            map.serialize_entry("device_class", device_class)?;
+        }
+        map.serialize_entry("origin", &self.origin)?;
+        map.serialize_entry("device", &self.device)?;
+        map.serialize_entry("unique_id", &scoped_unique_id(&self.unique_id))?;
+        if let Some(entity_category) = &self.entity_category {
+            map.serialize_entry("entity_category", entity_category)?;
+        }
+        if let Some(icon) = &self.icon {
+            map.serialize_entry("icon", icon)?;
+        }
+        map.end()
+    }
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct Origin {
     pub name: &'static str,
@@ -53,25 +155,44 @@ pub struct Device {
     pub identifiers: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub connections: Vec<(String, String)>,
+    /// The per-device availability topic; not part of the hass
+    /// device config, but consumed by EntityConfig
+    #[serde(skip)]
+    pub availability_topic: Option<String>,
+    /// Whether the device's entities are enabled by default, as
+    /// configured for the device, keyed by integration or entity
+    /// category. Not part of the hass device config, but applied
+    /// to each entity config when it is published.
+    #[serde(skip)]
+    pub enabled_by_default: BTreeMap<String, bool>,
 }
 
 impl Device {
     pub fn for_device(device: &ServiceDevice) -> Self {
+        let config = device.config();
         Self {
-            name: device.name(),
+            name: config.name.unwrap_or_else(|| device.name()),
             manufacturer: "Govee".to_string(),
             model: device.sku.to_string(),
             sw_version: None,
             suggested_area: device.room_name().map(|s| s.to_string()),
-            via_device: Some("gv2mqtt".to_string()),
+            // Sensors that are paired with a gateway are shown as
+            // being connected via it, so that it is clear why they
+            // go stale together
+            via_device: Some(match device.gateway_id() {
+                Some(gateway) => format!("{}-{}", topic_prefix(), topic_safe_device_id(gateway)),
+                None => topic_prefix().to_string(),
+            }),
             identifiers: vec![
-                format!("gv2mqtt-{}", topic_safe_id(device)),
+                format!("{}-{}", topic_prefix(), topic_safe_id(device)),
                 /*
                 device.computed_name(),
                 device.id.to_string(),
                 */
             ],
             connections: vec![],
+            availability_topic: Some(device_availability_topic(device)),
+            enabled_by_default: config.enabled_by_default.unwrap_or_default(),
         }
     }
 
@@ -83,8 +204,75 @@ impl Device {
             sw_version: Some(govee_version().to_string()),
             suggested_area: None,
             via_device: None,
-            identifiers: vec!["gv2mqtt".to_string()],
+            identifiers: vec![topic_prefix().to_string()],
+            connections: vec![],
+            availability_topic: None,
+            enabled_by_default: BTreeMap::new(),
+        }
+    }
+
+    /// The synthetic device that holds the buttons for the
+    /// one-click/tap-to-run rules from the Govee app
+    pub fn one_click_service() -> Self {
+        Self {
+            name: "Govee One-Click".to_string(),
+            manufacturer: "Govee".to_string(),
+            model: "One-Click".to_string(),
+            sw_version: None,
+            suggested_area: None,
+            via_device: Some(topic_prefix().to_string()),
+            identifiers: vec![format!("{}-one-click", topic_prefix())],
             connections: vec![],
+            availability_topic: None,
+            enabled_by_default: BTreeMap::new(),
         }
     }
+
+    /// The synthetic device that holds the light for a room of
+    /// the Govee app
+    pub fn for_room(room: &DeviceGroup) -> Self {
+        let name = room.name.trim();
+        Self {
+            name: if name.is_empty() {
+                format!("Govee Room {}", room.id)
+            } else {
+                format!("{name} Lights")
+            },
+            manufacturer: "Govee".to_string(),
+            model: "Room".to_string(),
+            sw_version: None,
+            suggested_area: Some(name.to_string()).filter(|name| !name.is_empty()),
+            via_device: Some(topic_prefix().to_string()),
+            identifiers: vec![format!("{}-room-{}", topic_prefix(), room.id)],
+            connections: vec![],
+            availability_topic: None,
+            enabled_by_default: BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn enabled_by_default() {
+        let mut config = EntityConfig {
+            entity_category: Some("diagnostic".to_string()),
+            ..Default::default()
+        };
+        k9::assert_equal!(config.enabled_by_default("sensor"), None);
+
+        config.device.enabled_by_default = BTreeMap::from([
+            ("diagnostic".to_string(), false),
+            ("light".to_string(), true),
+        ]);
+        k9::assert_equal!(config.enabled_by_default("sensor"), Some(false));
+
+        // The integration takes precedence over the category
+        k9::assert_equal!(config.enabled_by_default("light"), Some(true));
+
+        config.entity_category = None;
+        k9::assert_equal!(config.enabled_by_default("sensor"), None);
+    }
 }
@@ -1,11 +1,16 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::sensor::filter_needs_replacement;
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, topic_safe_string, HassClient,
+};
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
+use chrono::Utc;
 use serde::Serialize;
+use serde_json::Value as JsonValue;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct BinarySensorConfig {
@@ -19,6 +24,8 @@ pub struct BinarySensorConfig {
     pub payload_on: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub payload_off: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
 }
 
 impl BinarySensorConfig {
@@ -31,12 +38,51 @@ impl BinarySensorConfig {
     }
 }
 
+/// Returns true if the event instance reports a water leak, as for
+/// the H5054 leak detector and those behind the H5151 gateway
+pub fn is_leak_instance(instance: &str) -> bool {
+    instance.to_ascii_lowercase().contains("leak")
+}
+
+/// Returns the name of the sensor for the tip-over and overheat
+/// protection events of space heaters
+pub fn safety_event_name(instance: &str) -> Option<&'static str> {
+    match instance {
+        "tipOverEvent" => Some("Tipped Over"),
+        "overheatEvent" => Some("Overheated"),
+        _ => None,
+    }
+}
+
+/// Returns true if the event advertises a state with the value 0,
+/// which the device reports once the condition is resolved
+fn event_has_clear_state(cap: &DeviceCapability) -> bool {
+    cap.event_state
+        .as_ref()
+        .and_then(|state| state.pointer("/options"))
+        .and_then(|options| options.as_array())
+        .is_some_and(|options| {
+            options
+                .iter()
+                .any(|opt| opt.get("value").and_then(|v| v.as_i64()) == Some(0))
+        })
+}
+
+/// Returns true if the sensor for the event should turn off once the
+/// leak clear timeout passes without a repeated event, because the
+/// device never reports that the condition was resolved
+pub fn event_clears_after_timeout(cap: &DeviceCapability) -> bool {
+    is_leak_instance(&cap.instance)
+        || (safety_event_name(&cap.instance).is_some() && !event_has_clear_state(cap))
+}
+
 #[derive(Clone)]
 pub struct AlarmEventSensor {
     sensor: BinarySensorConfig,
     device_id: String,
     state: StateHandle,
     instance_name: String,
+    clears_after_timeout: bool,
 }
 
 impl AlarmEventSensor {
@@ -53,10 +99,18 @@ impl AlarmEventSensor {
 
         // Determine device class and name based on event type
         let (device_class, name) = match instance.instance.as_str() {
+            s if is_leak_instance(s) => (Some("moisture"), "Water Leak"),
+            s if safety_event_name(s).is_some() => (
+                Some("problem"),
+                safety_event_name(s).expect("checked in guard"),
+            ),
             "lowBatteryEvent" => (Some("battery"), "Low Battery"),
             "lackWaterEvent" => (Some("problem"), "Water Level Alert"),
             "temperatureAlarmEvent" | "tempAlarmEvent" => (Some("problem"), "Temperature Alarm"),
             "humidityAlarmEvent" | "humAlarmEvent" => (Some("problem"), "Humidity Alarm"),
+            "tankFullEvent" | "waterFullEvent" | "lackDrainEvent" => (Some("problem"), "Tank Full"),
+            "basketFullEvent" | "iceFullEvent" => (Some("problem"), "Basket Full"),
+            "filterExpiredEvent" => (Some("problem"), "Filter Replacement Needed"),
             s if s.ends_with("AlarmEvent") => (Some("problem"), "Alarm"),
             s if s.ends_with("Event") => (Some("problem"), "Alert"),
             _ => (None, "Event"),
@@ -69,21 +123,34 @@ impl AlarmEventSensor {
                 base: EntityConfig {
                     availability_topic: availability_topic(),
                     name: Some(name),
-                    entity_category: Some("diagnostic".to_string()),
+                    // Detecting leaks is what these devices are for, and
+                    // the safety events warrant a notification
+                    entity_category: (!is_leak_instance(&instance.instance)
+                        && safety_event_name(&instance.instance).is_none())
+                    .then(|| "diagnostic".to_string()),
                     origin: Origin::default(),
                     device: Device::for_device(device),
                     unique_id: unique_id.clone(),
-                    device_class: device_class.map(|s| s.to_string()),
+                    device_class,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/binary_sensor/{unique_id}/state"),
+                state_topic: format!(
+                    "{prefix}/binary_sensor/{unique_id}/state",
+                    prefix = topic_prefix()
+                ),
                 device_class,
                 payload_on: Some("ON".to_string()),
                 payload_off: Some("OFF".to_string()),
+                // The raw event, to help with working out false positives
+                json_attributes_topic: Some(format!(
+                    "{prefix}/binary_sensor/{unique_id}/attributes",
+                    prefix = topic_prefix()
+                )),
             },
             device_id: device.id.to_string(),
             state: state.clone(),
             instance_name: instance.instance.to_string(),
+            clears_after_timeout: event_clears_after_timeout(instance),
         })
     }
 }
@@ -91,7 +158,7 @@ impl AlarmEventSensor {
 #[async_trait]
 impl EntityInstance for AlarmEventSensor {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -104,15 +171,32 @@ impl EntityInstance for AlarmEventSensor {
         if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
             // Try to extract alarm state from the capability state
             // Events typically have a value field indicating if the alarm is active
-            let is_active = cap
+            let mut is_active = cap
                 .state
                 .pointer("/value")
                 .and_then(|v| v.as_i64())
                 .map(|v| v != 0)
                 .unwrap_or(false);
 
+            // Leak detectors never report that they are dry again, so
+            // the alert ends once the events stop repeating
+            if is_active && self.clears_after_timeout {
+                if let Some(timeout) = self.state.leak_clear_timeout().await {
+                    is_active = device.event_seen_within(
+                        &self.instance_name,
+                        chrono::Duration::from_std(timeout)?,
+                    );
+                }
+            }
+
             let state_value = if is_active { "ON" } else { "OFF" };
-            return self.sensor.notify_state(&client, state_value).await;
+            self.sensor.notify_state(client, state_value).await?;
+            if let Some(topic) = &self.sensor.json_attributes_topic {
+                client
+                    .publish_obj(topic, serde_json::json!({"event": cap.state}))
+                    .await?;
+            }
+            return Ok(());
         }
 
         log::trace!(
@@ -123,3 +207,403 @@ impl EntityInstance for AlarmEventSensor {
     }
 }
 
+/// Flags when the filter of a purifier needs to be replaced,
+/// based on the reported filter life
+#[derive(Clone)]
+pub struct FilterReplacementSensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl FilterReplacementSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let unique_id = format!(
+            "binary-sensor-{id}-{inst}-replace",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Filter Replacement Needed".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("problem"),
+                    icon: Some("mdi:air-filter".to_string()),
+                },
+                state_topic: format!(
+                    "{prefix}/binary_sensor/{unique_id}/state",
+                    prefix = topic_prefix()
+                ),
+                device_class: Some("problem"),
+                payload_on: Some("ON".to_string()),
+                payload_off: Some("OFF".to_string()),
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FilterReplacementSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(needs_replacement) = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(filter_needs_replacement)
+        {
+            return self
+                .sensor
+                .notify_state(client, if needs_replacement { "ON" } else { "OFF" })
+                .await;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reports whether we are connected to AWS IoT, through which
+/// many devices push their state changes
+pub struct IotConnectionSensor {
+    sensor: BinarySensorConfig,
+    state: StateHandle,
+}
+
+impl IotConnectionSensor {
+    pub fn new(state: &StateHandle) -> Self {
+        let unique_id = "global-iot-connected".to_string();
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("IoT Connected".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("connectivity"),
+                    icon: None,
+                },
+                state_topic: format!(
+                    "{prefix}/binary_sensor/{unique_id}/state",
+                    prefix = topic_prefix()
+                ),
+                device_class: Some("connectivity"),
+                payload_on: Some("ON".to_string()),
+                payload_off: Some("OFF".to_string()),
+                json_attributes_topic: None,
+            },
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for IotConnectionSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let connected = self.state.get_iot_health().await.connected;
+        self.sensor
+            .notify_state(client, if connected { "ON" } else { "OFF" })
+            .await
+    }
+}
+
+/// Reports whether a device is reachable by any path, with whether
+/// it is reachable via the LAN API, AWS IoT and the Platform API as
+/// attributes, so that it is clear when commands have to go via the
+/// cloud because the device fell off the LAN
+pub struct DeviceConnectivitySensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl DeviceConnectivitySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!(
+            "binary-sensor-{id}-connectivity",
+            id = topic_safe_id(device)
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Connectivity".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("connectivity"),
+                    icon: None,
+                },
+                state_topic: format!(
+                    "{prefix}/binary_sensor/{unique_id}/state",
+                    prefix = topic_prefix()
+                ),
+                device_class: Some("connectivity"),
+                payload_on: Some("ON".to_string()),
+                payload_off: Some("OFF".to_string()),
+                json_attributes_topic: Some(format!(
+                    "{prefix}/binary_sensor/{unique_id}/attributes",
+                    prefix = topic_prefix()
+                )),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DeviceConnectivitySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let reachability = device.reachability(Utc::now());
+        if reachability.is_unknown() {
+            return Ok(());
+        }
+
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client
+                .publish_obj(
+                    topic,
+                    serde_json::json!({
+                        "lan_reachable": reachability.lan,
+                        "iot_pushing": reachability.iot,
+                        "platform_online": reachability.platform,
+                        "ble_advertising": reachability.ble,
+                    }),
+                )
+                .await?;
+        }
+        self.sensor
+            .notify_state(client, if reachability.any() { "ON" } else { "OFF" })
+            .await
+    }
+}
+
+/// mmWave presence sensors, such as the H5127, report occupancy
+/// via an event or property capability named along the lines of
+/// bodyAppearedEvent or presenceState
+pub fn is_presence_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    instance.contains("presence")
+        || instance.contains("bodyappeared")
+        || instance.contains("occupancy")
+}
+
+/// Extracts the occupancy from the reported state of the presence
+/// capability. Event states carry a list of named states such as
+/// Presence or Absence, while properties report 1 for presence,
+/// and 0 or 2 for absence.
+fn parse_presence(state: &JsonValue) -> Option<bool> {
+    if let Some(states) = state.get("states").and_then(|s| s.as_array()) {
+        for entry in states {
+            match entry.get("name").and_then(|n| n.as_str()) {
+                Some(name) if name.eq_ignore_ascii_case("presence") => return Some(true),
+                Some(name) if name.eq_ignore_ascii_case("absence") => return Some(false),
+                _ => {}
+            }
+        }
+    }
+
+    match state.pointer("/value")? {
+        JsonValue::Bool(b) => Some(*b),
+        v => match v.as_i64()? {
+            1 => Some(true),
+            0 | 2 => Some(false),
+            _ => None,
+        },
+    }
+}
+
+#[derive(Clone)]
+pub struct PresenceSensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl PresenceSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let unique_id = format!(
+            "binary-sensor-{id}-{inst}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Occupancy".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("occupancy"),
+                    icon: None,
+                },
+                state_topic: format!(
+                    "{prefix}/binary_sensor/{unique_id}/state",
+                    prefix = topic_prefix()
+                ),
+                device_class: Some("occupancy"),
+                payload_on: Some("ON".to_string()),
+                payload_off: Some("OFF".to_string()),
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PresenceSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(present) = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| parse_presence(&cap.state))
+        {
+            return self
+                .sensor
+                .notify_state(client, if present { "ON" } else { "OFF" })
+                .await;
+        }
+
+        log::trace!(
+            "PresenceSensor::notify_state: didn't find state for {device} {instance}",
+            instance = self.instance_name
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn presence() {
+        assert!(is_presence_instance("bodyAppearedEvent"));
+        assert!(is_presence_instance("presenceState"));
+        assert!(!is_presence_instance("lackWaterEvent"));
+
+        k9::assert_equal!(
+            parse_presence(&json!({
+                "value": 2,
+                "states": [{"name": "Absence", "value": 2}],
+            })),
+            Some(false)
+        );
+        k9::assert_equal!(
+            parse_presence(&json!({"states": [{"name": "Presence", "value": 1}]})),
+            Some(true)
+        );
+        k9::assert_equal!(parse_presence(&json!({"value": 1})), Some(true));
+        k9::assert_equal!(parse_presence(&json!({"value": 0})), Some(false));
+        k9::assert_equal!(parse_presence(&json!({"value": ""})), None);
+    }
+
+    #[test]
+    fn clear_after_timeout() {
+        let event = |instance: &str, event_state: JsonValue| DeviceCapability {
+            kind: crate::platform_api::DeviceCapabilityKind::Event,
+            instance: instance.to_string(),
+            parameters: None,
+            alarm_type: Some(1),
+            event_state: Some(event_state),
+        };
+        let alarm_only = json!({"options": [{"name": "alarm", "value": 1}]});
+        let with_clear = json!({"options": [
+            {"name": "alarm", "value": 1},
+            {"name": "normal", "value": 0},
+        ]});
+
+        k9::assert_equal!(safety_event_name("lackWaterEvent"), None);
+        k9::assert_equal!(safety_event_name("tipOverProtection"), None);
+
+        assert!(event_clears_after_timeout(&event(
+            "waterLeakEvent",
+            with_clear.clone()
+        )));
+        assert!(event_clears_after_timeout(&event(
+            "tipOverEvent",
+            alarm_only.clone()
+        )));
+        assert!(!event_clears_after_timeout(&event(
+            "tipOverEvent",
+            with_clear
+        )));
+        assert!(!event_clears_after_timeout(&event(
+            "lackWaterEvent",
+            alarm_only
+        )));
+    }
+
+    #[test]
+    fn heater_safety_events() {
+        let events: Vec<DeviceCapability> = crate::platform_api::from_json(include_str!(
+            "../../test-data/heater-safety-events.json"
+        ))
+        .unwrap();
+        k9::assert_equal!(
+            events
+                .iter()
+                .map(|cap| (
+                    safety_event_name(&cap.instance),
+                    event_clears_after_timeout(cap)
+                ))
+                .collect::<Vec<_>>(),
+            vec![(Some("Tipped Over"), false), (Some("Overheated"), true)]
+        );
+    }
+}
@@ -3,12 +3,18 @@ use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, camel_case_to_space_separated, topic_safe_id, topic_safe_string, HassClient,
+    availability_topic, camel_case_to_space_separated, oneclick_topic, topic_prefix, topic_safe_id,
+    topic_safe_string, HassClient,
 };
 use crate::service::state::StateHandle;
+use crate::undoc_api::ParsedOneClick;
 use async_trait::async_trait;
 use serde::Serialize;
 
+pub fn one_click_button_unique_id(rule_id: &str) -> String {
+    format!("gv2mqtt-one-click-{}", topic_safe_string(rule_id))
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct ButtonConfig {
     #[serde(flatten)]
@@ -26,7 +32,8 @@ impl ButtonConfig {
         instance: &DeviceCapability,
     ) -> anyhow::Result<Self> {
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/{inst}",
+            "{prefix}/switch/{id}/command/{inst}",
+            prefix = topic_prefix(),
             id = topic_safe_id(device),
             inst = instance.instance
         );
@@ -72,6 +79,24 @@ impl ButtonConfig {
         }
     }
 
+    pub fn for_one_click(oc: &ParsedOneClick) -> Self {
+        let id = topic_safe_string(&oc.id);
+        Self {
+            base: EntityConfig {
+                availability_topic: availability_topic(),
+                name: Some(oc.name.to_string()),
+                entity_category: None,
+                origin: Origin::default(),
+                device: Device::one_click_service(),
+                unique_id: one_click_button_unique_id(&oc.id),
+                device_class: None,
+                icon: Some("mdi:gesture-tap-button".to_string()),
+            },
+            command_topic: format!("{}/{id}", oneclick_topic()),
+            payload_press: None,
+        }
+    }
+
     pub fn activate_work_mode_preset(
         device: &ServiceDevice,
         name: &str,
@@ -85,7 +110,8 @@ impl ButtonConfig {
             mode = topic_safe_string(mode_name),
         );
         let command_topic = format!(
-            "gv2mqtt/number/{id}/command/{mode}/{mode_num}",
+            "{prefix}/number/{id}/command/{mode}/{mode_num}",
+            prefix = topic_prefix(),
             id = topic_safe_id(device),
             mode = topic_safe_string(mode_name),
         );
@@ -111,7 +137,8 @@ impl ButtonConfig {
             id = topic_safe_id(device)
         );
         let command_topic = format!(
-            "gv2mqtt/{id}/request-platform-data",
+            "{prefix}/{id}/request-platform-data",
+            prefix = topic_prefix(),
             id = topic_safe_id(device)
         );
         Self {
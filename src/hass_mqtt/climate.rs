@@ -1,9 +1,12 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
-use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::number::NumberConfig;
+use crate::hass_mqtt::work_mode::{ParsedWorkMode, WorkMode};
 use crate::platform_api::{DeviceCapability, DeviceParameters};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, topic_safe_string, HassClient, IdParameter,
+};
 use crate::service::state::StateHandle;
 use crate::temperature::{
     TemperatureScale, TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE,
@@ -11,10 +14,11 @@ use crate::temperature::{
 use anyhow::anyhow;
 use axum::async_trait;
 use mosquitto_rs::router::{Params, Payload, State};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
-// TODO: register an actual climate entity.
+// The target temperature is exposed both as a number, for devices
+// that are not heaters, and as part of HeaterClimate for heaters.
 // I don't have one of these devices, so it is currently guesswork!
 
 pub struct TargetTemperatureEntity {
@@ -27,6 +31,9 @@ pub struct TargetTemperatureEntity {
 pub struct TemperatureConstraints {
     pub min: TemperatureValue,
     pub max: TemperatureValue,
+    /// The step between settings, in whole degrees of whichever
+    /// scale the temperature is shown in
+    pub step: f64,
 }
 
 impl TemperatureConstraints {
@@ -34,6 +41,7 @@ impl TemperatureConstraints {
         Self {
             min: self.min.as_unit(unit),
             max: self.max.as_unit(unit),
+            step: self.step,
         }
     }
 }
@@ -67,6 +75,7 @@ pub fn parse_temperature_constraints(
             Ok(TemperatureConstraints {
                 min: min.as_unit(units),
                 max: max.as_unit(units),
+                step: range.precision.max(1) as f64,
             })
         }
         _ => {
@@ -92,12 +101,14 @@ impl TargetTemperatureEntity {
 
         let name = "Target Temperature".to_string();
         let command_topic = format!(
-            "gv2mqtt/{id}/set-temperature/{inst}/{units}",
+            "{prefix}/{id}/set-temperature/{inst}/{units}",
+            prefix = topic_prefix(),
             id = topic_safe_id(device),
             inst = topic_safe_string(&instance.instance)
         );
         let state_topic = format!(
-            "gv2mqtt/{id}/advise-set-temperature",
+            "{prefix}/{id}/advise-set-temperature",
+            prefix = topic_prefix(),
             id = topic_safe_id(device),
         );
 
@@ -117,7 +128,7 @@ impl TargetTemperatureEntity {
                 command_topic,
                 min: Some(constraints.min.value().floor() as f32),
                 max: Some(constraints.max.value().ceil() as f32),
-                step: 1.0,
+                step: constraints.step as f32,
                 unit_of_measurement: Some(units.unit_of_measurement()),
             },
             device_id: device.id.to_string(),
@@ -130,7 +141,7 @@ impl TargetTemperatureEntity {
 #[async_trait]
 impl EntityInstance for TargetTemperatureEntity {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.number.publish(&state, &client).await
+        self.number.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -140,33 +151,15 @@ impl EntityInstance for TargetTemperatureEntity {
             .await
             .expect("device to exist");
 
-        let quirk = device.resolve_quirk();
-
         log::debug!("notify_state for {device} {}", self.instance_name);
 
-        if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
-            log::debug!("have: {cap:?}");
-
-            let units = cap
-                .state
-                .pointer("/value/unit")
-                .and_then(|unit| {
-                    unit.as_str()
-                        .and_then(|s| TemperatureScale::from_str(s).map(Into::into).ok())
-                })
-                .or_else(|| quirk.and_then(|q| q.platform_temperature_sensor_units))
-                .unwrap_or(TemperatureUnits::Celsius);
-
-            log::debug!("units are reported as {units:?}");
-
-            let value = match cap
-                .state
-                .pointer("/value/targetTemperature")
-                .and_then(|v| v.as_f64())
-                .map(|v| TemperatureValue::new(v, units))
-            {
+        if device
+            .get_state_capability_by_instance(&self.instance_name)
+            .is_some()
+        {
+            let pref_units = self.state.get_temperature_scale().await;
+            let value = match reported_target_temperature(&device, &self.instance_name) {
                 Some(v) => {
-                    let pref_units = self.state.get_temperature_scale().await;
                     log::debug!("reported temp is {v}, pref_units: {pref_units}");
                     let value = v.as_unit(pref_units.into()).value();
                     format!("{value:.2}")
@@ -176,13 +169,278 @@ impl EntityInstance for TargetTemperatureEntity {
 
             log::debug!("setting value to {value}");
 
-            return self.number.notify_state(&client, &value).await;
+            return self.number.notify_state(client, &value).await;
         }
 
         Ok(())
     }
 }
 
+/// Returns the target temperature reported by the platform API
+/// for the specified temperature setting instance
+fn reported_target_temperature(
+    device: &ServiceDevice,
+    instance_name: &str,
+) -> Option<TemperatureValue> {
+    let cap = device.get_state_capability_by_instance(instance_name)?;
+    log::debug!("have: {cap:?}");
+
+    let quirk = device.resolve_quirk();
+    let units = cap
+        .state
+        .pointer("/value/unit")
+        .and_then(|unit| {
+            unit.as_str()
+                .and_then(|s| TemperatureScale::from_str(s).map(Into::into).ok())
+        })
+        .or_else(|| quirk.and_then(|q| q.platform_temperature_sensor_units))
+        .unwrap_or(TemperatureUnits::Celsius);
+
+    log::debug!("units are reported as {units:?}");
+
+    cap.state
+        .pointer("/value/targetTemperature")
+        .and_then(|v| v.as_f64())
+        .map(|v| TemperatureValue::new(v, units))
+}
+
+/// Returns the current temperature reported by the device's
+/// temperature sensor capability
+fn reported_current_temperature(device: &ServiceDevice) -> Option<TemperatureValue> {
+    let cap = device.get_state_capability_by_instance("sensorTemperature")?;
+    let units = device
+        .resolve_quirk()
+        .and_then(|q| q.platform_temperature_sensor_units)
+        .unwrap_or(TemperatureUnits::Fahrenheit);
+
+    cap.state
+        .pointer("/value")
+        .and_then(|v| v.as_f64())
+        .map(|v| TemperatureValue::new(v, units))
+}
+
+/// <https://www.home-assistant.io/integrations/climate.mqtt/>
+#[derive(Serialize, Clone, Debug)]
+pub struct ClimateConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub mode_command_topic: String,
+    pub mode_state_topic: String,
+    pub modes: Vec<&'static str>,
+
+    pub temperature_command_topic: String,
+    pub temperature_state_topic: String,
+    pub current_temperature_topic: String,
+
+    pub min_temp: f64,
+    pub max_temp: f64,
+    pub temp_step: f64,
+    pub temperature_unit: &'static str,
+    pub precision: f64,
+}
+
+impl ClimateConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("climate", state, client, &self.base, self).await
+    }
+}
+
+pub const HVAC_MODE_OFF: &str = "off";
+pub const HVAC_MODE_HEAT: &str = "heat";
+pub const HVAC_MODE_FAN_ONLY: &str = "fan_only";
+
+/// Maps a device work mode to the equivalent hass hvac mode
+fn hvac_mode_for_work_mode(mode: &WorkMode) -> &'static str {
+    if mode.name.to_ascii_lowercase().contains("fan") {
+        HVAC_MODE_FAN_ONLY
+    } else {
+        HVAC_MODE_HEAT
+    }
+}
+
+/// Picks the work mode to activate for the requested hvac mode.
+/// For heat we prefer the thermostat-like Auto mode, because that
+/// is the mode in which the target temperature is honored.
+fn work_mode_for_hvac_mode<'a>(work_modes: &'a ParsedWorkMode, hvac: &str) -> Option<&'a WorkMode> {
+    if hvac == HVAC_MODE_HEAT {
+        if let Some(mode) = work_modes.mode_by_name("Auto") {
+            return Some(mode);
+        }
+    }
+    work_modes
+        .modes
+        .values()
+        .find(|mode| hvac_mode_for_work_mode(mode) == hvac)
+}
+
+pub struct HeaterClimate {
+    climate: ClimateConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl HeaterClimate {
+    pub async fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> anyhow::Result<Self> {
+        let units = state.get_temperature_scale().await;
+        let constraints = parse_temperature_constraints(instance)?.as_unit(units.into());
+        let id = topic_safe_id(device);
+
+        let mut modes = vec![HVAC_MODE_OFF];
+        if let Ok(work_modes) = ParsedWorkMode::with_device(device) {
+            for hvac in [HVAC_MODE_HEAT, HVAC_MODE_FAN_ONLY] {
+                if work_mode_for_hvac_mode(&work_modes, hvac).is_some() {
+                    modes.push(hvac);
+                }
+            }
+        }
+        if modes.len() == 1 {
+            modes.push(HVAC_MODE_HEAT);
+        }
+
+        Ok(Self {
+            climate: ClimateConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: None,
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-climate"),
+                    device_class: None,
+                    icon: None,
+                },
+                mode_command_topic: format!(
+                    "{prefix}/climate/{id}/set-mode",
+                    prefix = topic_prefix()
+                ),
+                mode_state_topic: format!(
+                    "{prefix}/climate/{id}/notify-mode",
+                    prefix = topic_prefix()
+                ),
+                modes,
+                temperature_command_topic: format!(
+                    "{prefix}/{id}/set-temperature/{inst}/{units}",
+                    prefix = topic_prefix(),
+                    inst = topic_safe_string(&instance.instance)
+                ),
+                // Shared with TargetTemperatureEntity
+                temperature_state_topic: format!(
+                    "{prefix}/{id}/advise-set-temperature",
+                    prefix = topic_prefix()
+                ),
+                current_temperature_topic: format!(
+                    "{prefix}/climate/{id}/current-temperature",
+                    prefix = topic_prefix()
+                ),
+                min_temp: constraints.min.value().floor(),
+                max_temp: constraints.max.value().ceil(),
+                temp_step: constraints.step,
+                temperature_unit: match units {
+                    TemperatureScale::Celsius => "C",
+                    TemperatureScale::Fahrenheit => "F",
+                },
+                precision: 1.0,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for HeaterClimate {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.climate.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let pref_units: TemperatureUnits = self.state.get_temperature_scale().await.into();
+
+        if let Some(v) = reported_current_temperature(&device) {
+            let value = v.as_unit(pref_units).value();
+            client
+                .publish(
+                    &self.climate.current_temperature_topic,
+                    format!("{value:.2}"),
+                )
+                .await?;
+        }
+
+        if let Some(v) = reported_target_temperature(&device, &self.instance_name) {
+            let value = v.as_unit(pref_units).value();
+            client
+                .publish(&self.climate.temperature_state_topic, format!("{value:.2}"))
+                .await?;
+        }
+
+        let is_on = device.device_state().map(|s| s.on).unwrap_or(false);
+        let mode = if !is_on {
+            Some(HVAC_MODE_OFF)
+        } else {
+            let work_modes = ParsedWorkMode::with_device(&device).ok();
+            let mode_value = device
+                .get_state_capability_by_instance("workMode")
+                .and_then(|cap| cap.state.pointer("/value/workMode").cloned());
+            match (work_modes, mode_value) {
+                (Some(work_modes), Some(mode_value)) => work_modes
+                    .mode_for_value(&mode_value)
+                    .map(hvac_mode_for_work_mode),
+                _ => Some(HVAC_MODE_HEAT),
+            }
+        };
+
+        if let Some(mode) = mode {
+            client.publish(&self.climate.mode_state_topic, mode).await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_climate_set_mode(
+    Payload(mode): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("Command: climate set-mode for {id}: {mode}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    if mode == HVAC_MODE_OFF {
+        return state.device_power_on(&device, false).await;
+    }
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let work_mode = work_mode_for_hvac_mode(&work_modes, &mode)
+        .ok_or_else(|| anyhow!("{device} has no work mode suitable for {mode}"))?;
+    let mode_num = work_mode
+        .value
+        .as_i64()
+        .ok_or_else(|| anyhow!("expected workMode to be a number"))?;
+
+    if !device.device_state().map(|s| s.on).unwrap_or(false) {
+        state.device_power_on(&device, true).await?;
+    }
+
+    state
+        .humidifier_set_parameter(&device, mode_num, work_mode.default_value())
+        .await?;
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct IdInstAndUnits {
     id: String,
@@ -200,7 +458,9 @@ pub async fn mqtt_set_temperature(
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
     log::info!("Command: set-temperature for {id}: {value}");
-    let device = state.resolve_device_for_control(&id).await?;
+    let Some(device) = state.resolve_device_for_command(&id, &instance).await? else {
+        return Ok(());
+    };
 
     let scale: TemperatureScale = units.parse()?;
     let target_value = TemperatureValue::parse_with_optional_scale(&value, Some(scale))?;
@@ -211,3 +471,29 @@ pub async fn mqtt_set_temperature(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::fixtures;
+
+    #[test]
+    fn heater_hvac_modes() {
+        let heater = fixtures::device(fixtures::LIST_DEVICES_ISSUE4, "H7131");
+        let cap = heater.capability_by_instance("workMode").unwrap();
+        let work_modes = ParsedWorkMode::with_capability(cap).unwrap();
+
+        k9::assert_equal!(
+            work_mode_for_hvac_mode(&work_modes, HVAC_MODE_HEAT).map(|m| m.name.as_str()),
+            Some("Auto")
+        );
+        k9::assert_equal!(
+            work_mode_for_hvac_mode(&work_modes, HVAC_MODE_FAN_ONLY).map(|m| m.name.as_str()),
+            Some("Fan")
+        );
+        k9::assert_equal!(
+            hvac_mode_for_work_mode(work_modes.mode_by_name("gearMode").unwrap()),
+            HVAC_MODE_HEAT
+        );
+    }
+}
@@ -1,13 +1,224 @@
-use crate::hass_mqtt::base::EntityConfig;
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, HassClient, IdParameter,
+};
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
 use serde::Serialize;
 
+/// The platform API instance that reports and sets the curtain position
+pub const COVER_POSITION_INSTANCE: &str = "position";
+/// The platform API instance that accepts open/close/stop commands
+pub const COVER_OPEN_CLOSE_INSTANCE: &str = "openClose";
+
+pub fn is_cover_instance(instance: &str) -> bool {
+    instance == COVER_POSITION_INSTANCE || instance == COVER_OPEN_CLOSE_INSTANCE
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::Display, strum_macros::EnumString)]
+#[strum(serialize_all = "UPPERCASE", ascii_case_insensitive)]
+pub enum CoverCommand {
+    Open,
+    Close,
+    Stop,
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct CoverConfig {
     #[serde(flatten)]
     pub base: EntityConfig,
-
     pub state_topic: String,
     pub position_topic: String,
     pub set_position_topic: String,
     pub command_topic: String,
+    pub position_open: u8,
+    pub position_closed: u8,
+}
+
+impl CoverConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("cover", state, client, &self.base, self).await
+    }
+}
+
+pub struct DeviceCover {
+    cover: CoverConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl DeviceCover {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let id = topic_safe_id(device);
+        let unique_id = format!("gv2mqtt-{id}-cover");
+
+        Self {
+            cover: CoverConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: None,
+                    device_class: Some("curtain"),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    entity_category: None,
+                    icon: None,
+                },
+                state_topic: format!("{prefix}/cover/{unique_id}/state", prefix = topic_prefix()),
+                position_topic: format!(
+                    "{prefix}/cover/{unique_id}/position",
+                    prefix = topic_prefix()
+                ),
+                set_position_topic: format!(
+                    "{prefix}/cover/{id}/set-position",
+                    prefix = topic_prefix()
+                ),
+                command_topic: format!("{prefix}/cover/{id}/command", prefix = topic_prefix()),
+                position_open: 100,
+                position_closed: 0,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DeviceCover {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.cover.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(range) = device
+            .http_device_info
+            .as_ref()
+            .and_then(|info| info.get_cover_position_range())
+        else {
+            return Ok(());
+        };
+
+        if let Some(cap) = device.get_state_capability_by_instance(COVER_POSITION_INSTANCE) {
+            match cap.state.pointer("/value").and_then(|v| v.as_i64()) {
+                Some(value) => {
+                    let percent = position_to_percent(value, range);
+                    client
+                        .publish(&self.cover.position_topic, percent.to_string())
+                        .await?;
+                    client
+                        .publish(
+                            &self.cover.state_topic,
+                            if percent == 0 { "closed" } else { "open" },
+                        )
+                        .await?;
+                }
+                None => {
+                    log::trace!("DeviceCover::notify_state: ignoring {cap:?}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Maps a position reported in the range advertised by the device
+/// into the 0-100 percentage used by hass
+pub fn position_to_percent(value: i64, (min, max): (u32, u32)) -> u8 {
+    let (min, max) = (min as i64, max as i64);
+    if max <= min {
+        return 0;
+    }
+    let value = value.clamp(min, max);
+    ((value - min) * 100 / (max - min)) as u8
+}
+
+/// The inverse of position_to_percent
+pub fn percent_to_position(percent: u8, (min, max): (u32, u32)) -> u32 {
+    let percent = percent.min(100) as u32;
+    if max <= min {
+        return min;
+    }
+    min + (max - min) * percent / 100
+}
+
+pub async fn mqtt_cover_command(
+    Payload(command): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{id}: cover command {command}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let command: CoverCommand = command
+        .parse()
+        .with_context(|| format!("mqtt_cover_command: parsing {command}"))?;
+
+    state
+        .device_cover_command(&device, command)
+        .await
+        .context("mqtt_cover_command: state.device_cover_command")?;
+
+    Ok(())
+}
+
+pub async fn mqtt_cover_set_position(
+    Payload(percent): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{id}: cover set position {percent}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let percent: u8 = percent
+        .parse()
+        .with_context(|| format!("mqtt_cover_set_position: parsing {percent}"))?;
+
+    state
+        .device_set_cover_position(&device, percent)
+        .await
+        .context("mqtt_cover_set_position: state.device_set_cover_position")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn position_mapping() {
+        assert_eq!(position_to_percent(0, (0, 100)), 0);
+        assert_eq!(position_to_percent(55, (0, 100)), 55);
+        assert_eq!(position_to_percent(150, (0, 100)), 100);
+        assert_eq!(position_to_percent(-3, (0, 100)), 0);
+        assert_eq!(position_to_percent(10, (1, 19)), 50);
+        assert_eq!(position_to_percent(5, (5, 5)), 0);
+
+        assert_eq!(percent_to_position(0, (0, 100)), 0);
+        assert_eq!(percent_to_position(100, (0, 100)), 100);
+        assert_eq!(percent_to_position(50, (1, 19)), 10);
+        assert_eq!(percent_to_position(200, (0, 10)), 10);
+    }
+
+    #[test]
+    fn parse_command() {
+        assert_eq!("OPEN".parse::<CoverCommand>().unwrap(), CoverCommand::Open);
+        assert_eq!(
+            "close".parse::<CoverCommand>().unwrap(),
+            CoverCommand::Close
+        );
+        assert_eq!("STOP".parse::<CoverCommand>().unwrap(), CoverCommand::Stop);
+        assert!("toggle".parse::<CoverCommand>().is_err());
+    }
 }
@@ -0,0 +1,213 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::precision::SensorPrecision;
+use crate::hass_mqtt::sensor::{SensorConfig, StateClass};
+use crate::platform_api::DeviceCapability;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, topic_safe_string, HassClient,
+};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde_json::Value as JsonValue;
+use std::sync::Mutex;
+
+/// The readings reported by plugs with power metering.
+/// Depending on the device, each of these is either a property
+/// capability in its own right, or a field of a struct property
+/// that bundles them together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeterReading {
+    Power,
+    Energy,
+    Voltage,
+    Current,
+}
+
+impl MeterReading {
+    pub const ALL: [Self; 4] = [Self::Power, Self::Energy, Self::Voltage, Self::Current];
+
+    /// The instance or struct field name used to report this reading
+    pub fn field_name(&self) -> &'static str {
+        match self {
+            Self::Power => "power",
+            Self::Energy => "energy",
+            Self::Voltage => "voltage",
+            Self::Current => "current",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Power => "Power",
+            Self::Energy => "Energy",
+            Self::Voltage => "Voltage",
+            Self::Current => "Current",
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        match self {
+            Self::Power => "W",
+            Self::Energy => "kWh",
+            Self::Voltage => "V",
+            Self::Current => "A",
+        }
+    }
+
+    fn precision(&self) -> Option<SensorPrecision> {
+        match self {
+            Self::Power => Some(SensorPrecision::Power),
+            Self::Energy => Some(SensorPrecision::Energy),
+            Self::Voltage | Self::Current => None,
+        }
+    }
+
+    /// Formats the reading for publishing
+    fn format(&self, value: f64) -> String {
+        match self.precision() {
+            Some(precision) => precision.format(value),
+            None => value.to_string(),
+        }
+    }
+
+    fn state_class(&self) -> StateClass {
+        match self {
+            Self::Energy => StateClass::TotalIncreasing,
+            _ => StateClass::Measurement,
+        }
+    }
+
+    /// Extracts this reading from the reported state of the capability
+    pub fn extract(&self, instance: &str, value: &JsonValue) -> Option<f64> {
+        match value {
+            JsonValue::Object(map) => map.get(self.field_name())?.as_f64(),
+            v if instance == self.field_name() => v.as_f64(),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the set of meter readings provided by the capability
+pub fn meter_readings_for_capability(cap: &DeviceCapability) -> Vec<MeterReading> {
+    MeterReading::ALL
+        .into_iter()
+        .filter(|reading| {
+            cap.instance == reading.field_name()
+                || cap.struct_field_by_name(reading.field_name()).is_some()
+        })
+        .collect()
+}
+
+pub struct MeterSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+    reading: MeterReading,
+    last_value: Mutex<Option<f64>>,
+}
+
+impl MeterSensor {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+        reading: MeterReading,
+    ) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-{inst}-{field}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance),
+            field = reading.field_name(),
+        );
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(reading.label().to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some(reading.field_name()),
+                    icon: None,
+                },
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
+                state_class: Some(reading.state_class()),
+                unit_of_measurement: Some(reading.unit()),
+                suggested_display_precision: reading
+                    .precision()
+                    .and_then(SensorPrecision::decimals),
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+            reading,
+            last_value: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MeterSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(value) = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(|v| self.reading.extract(&self.instance_name, v))
+        else {
+            return Ok(());
+        };
+
+        if self.reading == MeterReading::Energy {
+            // The cumulative counter restarts from zero when the
+            // device is reset. We publish the new, smaller, value
+            // as-is: hass treats a decrease in a total_increasing
+            // sensor as the start of a new meter cycle, whereas
+            // trying to compensate here would produce bogus deltas.
+            let mut last = self.last_value.lock().expect("lock not poisoned");
+            if let Some(prior) = *last {
+                if value < prior {
+                    log::info!("{device}: energy counter reset from {prior} to {value} kWh");
+                }
+            }
+            last.replace(value);
+        }
+
+        self.sensor
+            .notify_state(client, &self.reading.format(value))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn extract_readings() {
+        let value = json!({"power": 12.5, "energy": 3.25, "voltage": 120, "current": 0.1});
+        k9::assert_equal!(
+            MeterReading::ALL.map(|r| r.extract("electricity", &value)),
+            [Some(12.5), Some(3.25), Some(120.), Some(0.1)]
+        );
+
+        k9::assert_equal!(MeterReading::Power.extract("power", &json!(7)), Some(7.));
+        k9::assert_equal!(MeterReading::Energy.extract("power", &json!(7)), None);
+        k9::assert_equal!(MeterReading::Voltage.extract("voltage", &json!("")), None);
+    }
+}
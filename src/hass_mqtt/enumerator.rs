@@ -1,22 +1,52 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
-use crate::hass_mqtt::binary_sensor::AlarmEventSensor;
+use crate::hass_mqtt::binary_sensor::{
+    is_presence_instance, AlarmEventSensor, DeviceConnectivitySensor, FilterReplacementSensor,
+    IotConnectionSensor, PresenceSensor,
+};
 use crate::hass_mqtt::button::ButtonConfig;
-use crate::hass_mqtt::climate::TargetTemperatureEntity;
+use crate::hass_mqtt::climate::{HeaterClimate, TargetTemperatureEntity};
+use crate::hass_mqtt::cover::{is_cover_instance, DeviceCover};
+use crate::hass_mqtt::energy::{meter_readings_for_capability, MeterSensor};
+use crate::hass_mqtt::event::{is_momentary_press_event, DeviceEvent};
+use crate::hass_mqtt::fan::{DeviceFan, FanGearSelect};
 use crate::hass_mqtt::humidifier::Humidifier;
-use crate::hass_mqtt::instance::EntityList;
+use crate::hass_mqtt::instance::{EntityList, RemovedEntity};
 use crate::hass_mqtt::light::DeviceLight;
-use crate::hass_mqtt::number::WorkModeNumber;
-use crate::hass_mqtt::scene::SceneConfig;
-use crate::hass_mqtt::select::{SceneModeSelect, WorkModeSelect};
-use crate::hass_mqtt::sensor::{CapabilitySensor, DeviceStatusDiagnostic, GlobalFixedDiagnostic};
-use crate::hass_mqtt::switch::CapabilitySwitch;
-use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::hass_mqtt::migrate::enumerate_unique_id_migrations;
+use crate::hass_mqtt::music::{MusicModeSelect, MusicSensitivityNumber, MUSIC_MODE_INSTANCE};
+use crate::hass_mqtt::number::{
+    is_display_brightness_instance, is_mist_level_instance, is_target_humidity_instance,
+    DisplayBrightnessNumber, MistLevelNumber, TargetHumidityNumber, WorkModeNumber,
+};
+use crate::hass_mqtt::probe::{
+    is_probe_target_instance, probe_index, ProbeTargetTemperatureNumber, ProbeTemperatureSensor,
+};
+use crate::hass_mqtt::room::{room_lights_enabled, RoomLight};
+use crate::hass_mqtt::select::{
+    is_light_cycle_mode, LightCycleModeSelect, MistLevelSelect, MistPresetSelect, SceneModeSelect,
+    WorkModeSelect,
+};
+use crate::hass_mqtt::sensor::{
+    BatterySensor, CapabilitySensor, DeviceStatusDiagnostic, GlobalFixedDiagnostic, LastSeenSensor,
+    PlatformQuotaSensor, QuotaField, WifiSignalSensor, FILTER_LIFE_INSTANCE,
+};
+use crate::hass_mqtt::siren::{is_siren_instance, DeviceSiren};
+use crate::hass_mqtt::switch::{
+    is_beep_instance, is_boolean_capability, is_display_toggle_instance,
+    is_indicator_light_instance, is_lock_instance, CapabilitySwitch,
+};
+use crate::hass_mqtt::timer::{
+    is_countdown_timer_instance, CountdownTimerNumber, CountdownTimerSelect, CountdownTimerSensor,
+};
+use crate::hass_mqtt::update::FirmwareUpdate;
+use crate::hass_mqtt::valve::{is_valve_instance, DeviceValve, ValveRemainingSensor};
+use crate::hass_mqtt::work_mode::{mist_level_presets, ParsedWorkMode};
 use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceType};
-use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, oneclick_topic, purge_cache_topic};
+use crate::service::device::{Device as ServiceDevice, BATTERY_INSTANCE};
+use crate::service::hass::purge_cache_topic;
 use crate::service::state::StateHandle;
 use crate::version_info::govee_version;
 use anyhow::Context;
+use std::collections::HashSet;
 
 use uuid::Uuid;
 
@@ -24,55 +54,118 @@ pub async fn enumerate_all_entites(state: &StateHandle) -> anyhow::Result<Entity
     let mut entities = EntityList::new();
 
     enumerate_global_entities(state, &mut entities).await?;
-    enumerate_scenes(state, &mut entities).await?;
+    enumerate_one_clicks(state, &mut entities).await?;
 
     let devices = state.devices().await;
 
+    // Remove renamed entities before we publish their replacements
+    enumerate_unique_id_migrations(state, &devices, &mut entities).await;
+
     for d in &devices {
         enumerate_entities_for_device(d, state, &mut entities)
             .await
             .with_context(|| format!("Config::for_device({d})"))?;
     }
 
+    enumerate_room_lights(state, &mut entities).await;
+
     Ok(entities)
 }
 
+/// Each room from the Govee app that contains two or more lights is
+/// exposed as a light. The lights of rooms that no longer qualify,
+/// because their membership changed in the app, are removed from hass.
+pub async fn enumerate_room_lights(state: &StateHandle, entities: &mut EntityList) {
+    let mut unique_ids = HashSet::new();
+    if room_lights_enabled() {
+        for (room, lights) in state.rooms_with_lights().await {
+            let light = RoomLight::new(&room, &lights, state);
+            unique_ids.insert(light.unique_id().to_string());
+            entities.add(light);
+        }
+    }
+
+    if let Some(prior) = state.replace_room_light_ids(unique_ids.clone()).await {
+        for unique_id in prior.difference(&unique_ids) {
+            entities.add(RemovedEntity {
+                integration: "light",
+                unique_id: unique_id.to_string(),
+            });
+        }
+    }
+}
+
 async fn enumerate_global_entities(
-    _state: &StateHandle,
+    state: &StateHandle,
     entities: &mut EntityList,
 ) -> anyhow::Result<()> {
     entities.add(GlobalFixedDiagnostic::new("Version", govee_version()));
+    entities.add(GlobalFixedDiagnostic::new(
+        "Operating Mode",
+        state.operating_mode().await,
+    ));
     entities.add(ButtonConfig::new("Purge Caches", purge_cache_topic()));
+    if state.iot_started().await {
+        entities.add(IotConnectionSensor::new(state));
+    }
+    enumerate_platform_quota_sensors(state, entities).await;
     Ok(())
 }
 
-async fn enumerate_scenes(state: &StateHandle, entities: &mut EntityList) -> anyhow::Result<()> {
-    if let Some(undoc) = state.get_undoc_client().await {
-        match undoc.parse_one_clicks().await {
-            Ok(items) => {
-                for oc in items {
-                    let unique_id = format!(
+/// The platform API request quota is only relevant when we
+/// have a platform API client
+pub async fn enumerate_platform_quota_sensors(state: &StateHandle, entities: &mut EntityList) {
+    if state.get_platform_client().await.is_some() {
+        for field in QuotaField::ALL {
+            entities.add(PlatformQuotaSensor::new(field, state));
+        }
+    }
+}
+
+/// One-click rules are exposed as buttons. Rules that were removed
+/// in the Govee app since the last time that we enumerated them are
+/// removed from hass.
+async fn enumerate_one_clicks(
+    state: &StateHandle,
+    entities: &mut EntityList,
+) -> anyhow::Result<()> {
+    let Some(undoc) = state.get_undoc_client().await else {
+        return Ok(());
+    };
+    let items = match undoc.parse_one_clicks().await {
+        Ok(items) => items,
+        Err(err) => {
+            log::warn!("Failed to parse one-clicks: {err:#}");
+            return Ok(());
+        }
+    };
+
+    let mut unique_ids = HashSet::new();
+    for oc in &items {
+        let button = ButtonConfig::for_one_click(oc);
+        unique_ids.insert(button.base.unique_id.clone());
+        entities.add(button);
+    }
+
+    match state.replace_one_click_button_ids(unique_ids.clone()).await {
+        Some(prior) => {
+            for unique_id in prior.difference(&unique_ids) {
+                entities.add(RemovedEntity {
+                    integration: "button",
+                    unique_id: unique_id.to_string(),
+                });
+            }
+        }
+        None => {
+            // One-clicks used to be exposed as scenes; remove those
+            for oc in &items {
+                entities.add(RemovedEntity {
+                    integration: "scene",
+                    unique_id: format!(
                         "gv2mqtt-one-click-{}",
                         Uuid::new_v5(&Uuid::NAMESPACE_DNS, oc.name.as_bytes()).simple()
-                    );
-                    entities.add(SceneConfig {
-                        base: EntityConfig {
-                            availability_topic: availability_topic(),
-                            name: Some(oc.name.to_string()),
-                            entity_category: None,
-                            origin: Origin::default(),
-                            device: Device::this_service(),
-                            unique_id: unique_id.clone(),
-                            device_class: None,
-                            icon: None,
-                        },
-                        command_topic: oneclick_topic(),
-                        payload_on: oc.name,
-                    });
-                }
-            }
-            Err(err) => {
-                log::warn!("Failed to parse one-clicks: {err:#}");
+                    ),
+                });
             }
         }
     }
@@ -80,7 +173,7 @@ async fn enumerate_scenes(state: &StateHandle, entities: &mut EntityList) -> any
     Ok(())
 }
 
-async fn entities_for_work_mode<'a>(
+async fn entities_for_work_mode(
     d: &ServiceDevice,
     state: &StateHandle,
     cap: &DeviceCapability,
@@ -140,32 +233,75 @@ async fn entities_for_work_mode<'a>(
         }
     }
 
+    if d.device_type() == DeviceType::Humidifier {
+        if let Some((mode, presets)) = mist_level_presets(&work_modes, quirk.as_ref()) {
+            entities.add(MistPresetSelect::new(d, state, mode.value.clone(), presets));
+        }
+    }
+
     entities.add(WorkModeSelect::new(d, &work_modes, state));
 
     Ok(())
 }
 
-pub async fn enumerate_entities_for_device<'a>(
-    d: &'a ServiceDevice,
+pub async fn enumerate_entities_for_device(
+    d: &ServiceDevice,
     state: &StateHandle,
     entities: &mut EntityList,
 ) -> anyhow::Result<()> {
-    if !d.is_controllable() {
+    // BLE-only devices can't be controlled, but those whose
+    // advertisements we decode still have sensors
+    if !d.is_controllable() && d.ble_reading.is_none() {
         return Ok(());
     }
 
     entities.add(DeviceStatusDiagnostic::new(d, state));
     entities.add(ButtonConfig::request_platform_data_for_device(d));
 
+    if d.battery_percent().is_some() {
+        entities.add(BatterySensor::new(d, state));
+    }
+    if d.wifi_rssi().is_some() {
+        entities.add(WifiSignalSensor::new(d, state));
+    }
+    entities.add(LastSeenSensor::new(d, state));
+    entities.add(DeviceConnectivitySensor::new(d, state));
+    if let Some(update) = FirmwareUpdate::new(d, state) {
+        entities.add(update);
+    }
+
     if d.supports_rgb() || d.get_color_temperature_range().is_some() || d.supports_brightness() {
-        entities.add(DeviceLight::for_device(&d, state, None).await?);
+        entities.add(DeviceLight::for_device(d, state, None).await?);
+    }
+
+    let config = d.config();
+
+    if matches!(
+        d.device_type(),
+        DeviceType::AromaDiffuser | DeviceType::Humidifier
+    ) {
+        if let Some(nightlight) = d
+            .get_capability_by_instance("nightlightToggle")
+            .filter(|cap| !config.suppresses(&cap.instance))
+        {
+            entities.add(DeviceLight::for_nightlight(d, state, nightlight));
+        }
     }
 
     if matches!(
         d.device_type(),
         DeviceType::Humidifier | DeviceType::Dehumidifier
     ) {
-        entities.add(Humidifier::new(&d, state).await?);
+        entities.add(Humidifier::new(d, state).await?);
+    }
+
+    if matches!(d.device_type(), DeviceType::Fan | DeviceType::AirPurifier) {
+        if let Some(fan) = DeviceFan::new(d, state) {
+            entities.add(fan);
+        }
+        if let Some(gears) = FanGearSelect::new(d, state) {
+            entities.add(gears);
+        }
     }
 
     if d.device_type() != DeviceType::Light {
@@ -174,30 +310,110 @@ pub async fn enumerate_entities_for_device<'a>(
         }
     }
 
+    // Wi-Fi thermo-hygrometers, such as the H5179, may only report their
+    // readings via the undocumented API, and BLE-only ones only via
+    // their advertisements
+    for cap in d.unlisted_sensor_capabilities() {
+        if !config.suppresses(&cap.instance) {
+            entities.add(CapabilitySensor::new(d, state, &cap).await?);
+        }
+    }
+
     // For thermometers and sensors without Platform API data, still create a device entry
     // so they appear in Home Assistant even if we don't have full capability information yet
-    if d.http_device_info.is_none() {
-        if matches!(
+    if d.http_device_info.is_none()
+        && matches!(
             d.device_type(),
             DeviceType::Thermometer | DeviceType::Sensor
-        ) {
-            log::info!(
-                "Device {} is a {} but has no Platform API data. \
+        )
+    {
+        log::info!(
+            "Device {} is a {} but has no Platform API data. \
                  It will appear in Home Assistant but may have limited functionality until \
                  Platform API data is available.",
-                d,
-                d.device_type()
-            );
-            // At minimum, the device will have the status diagnostic sensor from line 157
-            return Ok(());
-        }
+            d,
+            d.device_type()
+        );
+        // At minimum, the device will have the status diagnostic sensor from line 157
+        return Ok(());
     }
 
     if let Some(info) = &d.http_device_info {
+        if info.supports_cover() {
+            entities.add(DeviceCover::new(d, state));
+        }
+
         for cap in &info.capabilities {
+            if config.suppresses(&cap.instance) {
+                continue;
+            }
             match &cap.kind {
+                _ if is_cover_instance(&cap.instance) => {}
+                _ if is_siren_instance(&cap.instance) => {
+                    entities.add(DeviceSiren::new(d, state, cap));
+                }
+                DeviceCapabilityKind::Range | DeviceCapabilityKind::Mode
+                    if is_mist_level_instance(&cap.instance) =>
+                {
+                    if let Some(number) = MistLevelNumber::new(d, state, cap) {
+                        entities.add(number);
+                    } else if let Some(select) = MistLevelSelect::new(d, state, cap) {
+                        entities.add(select);
+                    }
+                }
+                _ if probe_index(&cap.instance).is_some() => {
+                    let probe = probe_index(&cap.instance).expect("checked in guard");
+                    if is_probe_target_instance(&cap.instance) {
+                        entities.add(ProbeTargetTemperatureNumber::new(d, state, cap, probe).await);
+                    } else {
+                        entities.add(ProbeTemperatureSensor::new(d, state, cap, probe).await);
+                    }
+                }
+                _ if is_valve_instance(&cap.instance) => {
+                    entities.add(DeviceValve::new(d, state, cap));
+                    if let Some(remaining) = ValveRemainingSensor::new(d, state, cap) {
+                        entities.add(remaining);
+                    }
+                }
+                DeviceCapabilityKind::Mode if is_light_cycle_mode(d, cap) => {
+                    if let Some(select) = LightCycleModeSelect::new(d, state, cap) {
+                        entities.add(select);
+                    }
+                }
+                // Locks, display, beep and indicator light toggles may
+                // be reported as any kind of capability
+                _ if (is_lock_instance(d, &cap.instance)
+                    || is_display_toggle_instance(&cap.instance)
+                    || is_beep_instance(&cap.instance)
+                    || is_indicator_light_instance(&cap.instance))
+                    && is_boolean_capability(cap) =>
+                {
+                    entities.add(CapabilitySwitch::new(d, state, cap).await?);
+                }
+                _ if is_countdown_timer_instance(&cap.instance) => {
+                    entities.add(CountdownTimerSensor::new(d, state, cap));
+                    if let Some(number) = CountdownTimerNumber::new(d, state, cap) {
+                        entities.add(number);
+                    } else if let Some(select) = CountdownTimerSelect::new(d, state, cap) {
+                        entities.add(select);
+                    }
+                }
+                _ if is_display_brightness_instance(&cap.instance) => {
+                    entities.add(DisplayBrightnessNumber::new(d, state, cap));
+                }
                 DeviceCapabilityKind::Toggle | DeviceCapabilityKind::OnOff => {
-                    entities.add(CapabilitySwitch::new(&d, state, cap).await?);
+                    entities.add(CapabilitySwitch::new(d, state, cap).await?);
+                }
+                DeviceCapabilityKind::MusicSetting if cap.instance == MUSIC_MODE_INSTANCE => {
+                    if let Some(select) = MusicModeSelect::new(d, state, cap) {
+                        entities.add(select);
+                    }
+                    if let Some(number) = MusicSensitivityNumber::new(d, state, cap) {
+                        entities.add(number);
+                    }
+                }
+                DeviceCapabilityKind::Mode if is_boolean_capability(cap) => {
+                    entities.add(CapabilitySwitch::new(d, state, cap).await?);
                 }
                 DeviceCapabilityKind::ColorSetting
                 | DeviceCapabilityKind::SegmentColorSetting
@@ -205,27 +421,60 @@ pub async fn enumerate_entities_for_device<'a>(
                 | DeviceCapabilityKind::Mode
                 | DeviceCapabilityKind::DynamicScene => {}
 
+                DeviceCapabilityKind::Event | DeviceCapabilityKind::Property
+                    if is_presence_instance(&cap.instance) =>
+                {
+                    entities.add(PresenceSensor::new(d, state, cap));
+                }
+                DeviceCapabilityKind::Event if is_momentary_press_event(cap) => {
+                    // Momentary presses never "turn off", so they are
+                    // represented as an event entity rather than an
+                    // AlarmEventSensor
+                    entities.add(DeviceEvent::new(d, cap));
+                }
                 DeviceCapabilityKind::Event => {
                     // Handle alarm/event capabilities as binary sensors
                     if cap.alarm_type.is_some() || cap.event_state.is_some() {
-                        entities.add(AlarmEventSensor::new(&d, state, cap).await?);
+                        entities.add(AlarmEventSensor::new(d, state, cap).await?);
                     }
                 }
 
                 DeviceCapabilityKind::Range if cap.instance == "brightness" => {}
-                DeviceCapabilityKind::Range if cap.instance == "humidity" => {}
+                DeviceCapabilityKind::Range if is_target_humidity_instance(&cap.instance) => {
+                    if let Some(number) = TargetHumidityNumber::new(d, state, cap) {
+                        entities.add(number);
+                    }
+                }
                 DeviceCapabilityKind::WorkMode => {
                     entities_for_work_mode(d, state, cap, entities).await?;
                 }
 
+                // Reported via BatterySensor
+                DeviceCapabilityKind::Property if cap.instance == BATTERY_INSTANCE => {}
+                DeviceCapabilityKind::Property
+                    if !meter_readings_for_capability(cap).is_empty() =>
+                {
+                    for reading in meter_readings_for_capability(cap) {
+                        entities.add(MeterSensor::new(d, state, cap, reading));
+                    }
+                }
                 DeviceCapabilityKind::Property => {
-                    entities.add(CapabilitySensor::new(&d, state, cap).await?);
+                    entities.add(CapabilitySensor::new(d, state, cap).await?);
+                    if cap.instance == FILTER_LIFE_INSTANCE {
+                        entities.add(FilterReplacementSensor::new(d, state, cap));
+                    }
                 }
 
                 DeviceCapabilityKind::TemperatureSetting => {
-                    entities.add(TargetTemperatureEntity::new(&d, state, cap).await?);
+                    if d.device_type() == DeviceType::Heater {
+                        entities.add(HeaterClimate::new(d, state, cap).await?);
+                    }
+                    entities.add(TargetTemperatureEntity::new(d, state, cap).await?);
                 }
 
+                _ if is_boolean_capability(cap) => {
+                    entities.add(CapabilitySwitch::new(d, state, cap).await?);
+                }
                 kind => {
                     log::warn!(
                         "Do something about {kind:?} {} for {d} {cap:?}",
@@ -235,9 +484,11 @@ pub async fn enumerate_entities_for_device<'a>(
             }
         }
 
-        if let Some(segments) = info.supports_segmented_rgb() {
-            for n in segments {
-                entities.add(DeviceLight::for_device(&d, state, Some(n)).await?);
+        if state.segment_lights_enabled(d).await {
+            if let Some(segments) = d.segment_range() {
+                for n in segments {
+                    entities.add(DeviceLight::for_device(d, state, Some(n)).await?);
+                }
             }
         }
     }
@@ -0,0 +1,176 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::platform_api::{DeviceCapability, DeviceParameters};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, camel_case_to_space_separated, topic_prefix, topic_safe_id,
+    topic_safe_string, HassClient,
+};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EventConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub state_topic: String,
+    pub event_types: Vec<String>,
+}
+
+impl EventConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("event", state, client, &self.base, self).await
+    }
+}
+
+pub fn event_state_topic(device: &ServiceDevice, instance: &str) -> String {
+    format!(
+        "{prefix}/event/{id}/{inst}/state",
+        prefix = topic_prefix(),
+        id = topic_safe_id(device),
+        inst = topic_safe_string(instance)
+    )
+}
+
+/// Returns true if the event capability represents a momentary
+/// button/remote press rather than a latched alarm condition
+pub fn is_momentary_press_event(cap: &DeviceCapability) -> bool {
+    let instance = cap.instance.to_ascii_lowercase();
+    instance.contains("press") || instance.contains("button") || instance.contains("click")
+}
+
+/// Returns the list of event names advertised by the capability
+pub fn event_types_for_capability(cap: &DeviceCapability) -> Vec<String> {
+    let mut result = vec![];
+
+    if let Some(options) = cap
+        .event_state
+        .as_ref()
+        .and_then(|state| state.pointer("/options"))
+        .and_then(|options| options.as_array())
+    {
+        for opt in options {
+            if let Some(name) = opt.get("name").and_then(|name| name.as_str()) {
+                result.push(name.to_string());
+            }
+        }
+    }
+
+    if let Some(DeviceParameters::Enum { options }) = &cap.parameters {
+        for opt in options {
+            result.push(opt.name.to_string());
+        }
+    }
+
+    result.dedup();
+    result
+}
+
+/// Publishes a momentary event to hass. This is intentionally not
+/// gated on a change in state: each press must be delivered.
+pub async fn publish_device_event(
+    client: &HassClient,
+    device: &ServiceDevice,
+    instance: &str,
+    event_type: &str,
+) -> anyhow::Result<()> {
+    client
+        .publish_transient(
+            event_state_topic(device, instance),
+            json!({"event_type": event_type}).to_string(),
+        )
+        .await
+}
+
+pub struct DeviceEvent {
+    event: EventConfig,
+}
+
+impl DeviceEvent {
+    pub fn new(device: &ServiceDevice, instance: &DeviceCapability) -> Self {
+        let unique_id = format!(
+            "event-{id}-{inst}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        let name = camel_case_to_space_separated(
+            instance
+                .instance
+                .strip_suffix("Event")
+                .filter(|s| !s.is_empty())
+                .unwrap_or(&instance.instance),
+        );
+
+        Self {
+            event: EventConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    device_class: Some("button"),
+                    icon: None,
+                },
+                state_topic: event_state_topic(device, &instance.instance),
+                event_types: event_types_for_capability(instance),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DeviceEvent {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.event.publish(state, client).await
+    }
+
+    async fn notify_state(&self, _client: &HassClient) -> anyhow::Result<()> {
+        // Events have no state; they are published as they arrive
+        // via publish_device_event
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::DeviceCapabilityKind;
+
+    #[test]
+    fn event_types() {
+        let cap = DeviceCapability {
+            kind: DeviceCapabilityKind::Event,
+            instance: "buttonPressEvent".to_string(),
+            parameters: None,
+            alarm_type: None,
+            event_state: Some(json!({
+                "options": [
+                    {"name": "single", "value": 1},
+                    {"name": "double", "value": 2},
+                    {"name": "long", "value": 3},
+                ]
+            })),
+        };
+        assert!(is_momentary_press_event(&cap));
+        k9::assert_equal!(
+            event_types_for_capability(&cap),
+            vec![
+                "single".to_string(),
+                "double".to_string(),
+                "long".to_string()
+            ]
+        );
+
+        let cap = DeviceCapability {
+            instance: "lackWaterEvent".to_string(),
+            ..cap
+        };
+        assert!(!is_momentary_press_event(&cap));
+    }
+}
@@ -0,0 +1,571 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::select::SelectConfig;
+use crate::hass_mqtt::work_mode::{ParsedWorkMode, WorkMode};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, HassClient, IdParameter,
+};
+use crate::service::state::StateHandle;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Serialize;
+
+/// <https://www.home-assistant.io/integrations/fan.mqtt/>
+#[derive(Serialize, Clone, Debug)]
+pub struct FanConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub command_topic: String,
+    pub state_topic: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage_state_topic: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preset_modes: Vec<String>,
+}
+
+impl FanConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("fan", state, client, &self.base, self).await
+    }
+}
+
+/// A single discrete speed setting of the fan, expressed as
+/// the work mode and mode value needed to select it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FanGear {
+    pub work_mode: i64,
+    pub mode_value: i64,
+}
+
+/// Work modes that are named after their speed, in ascending order
+const SPEED_MODE_NAMES: &[&str] = &["Low", "Medium", "High", "Turbo"];
+
+/// Describes how the percentage and preset controls of the hass fan
+/// entity map onto the work modes of the device
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FanSpeeds {
+    /// In ascending order of speed
+    pub gears: Vec<FanGear>,
+    /// The name of each gear, in the same order as `gears`
+    pub gear_names: Vec<String>,
+    /// (name, work_mode, mode_value)
+    pub presets: Vec<(String, i64, i64)>,
+}
+
+/// The name of the gear at 1-based `position`, preferring the name
+/// from the device metadata
+fn gear_name(position: usize, name: Option<&str>) -> String {
+    match name {
+        Some(name) => name.to_string(),
+        None => format!("Gear {position}"),
+    }
+}
+
+fn is_speed_mode(mode: &WorkMode) -> bool {
+    let name = mode.name.to_ascii_lowercase();
+    name.contains("speed") || name.contains("gear") || name == "manual"
+}
+
+impl FanSpeeds {
+    pub fn with_work_modes(work_modes: &ParsedWorkMode) -> Self {
+        let mut result = Self::default();
+        let mut gear_mode_names = vec![];
+
+        // A mode with a range of values, such as FanSpeed 1-8
+        if let Some(mode) = work_modes.modes.values().find(|m| is_speed_mode(m)) {
+            if let Some(work_mode) = mode.value.as_i64() {
+                if let Some(range) = mode.contiguous_value_range() {
+                    for mode_value in range {
+                        result.gears.push(FanGear {
+                            work_mode,
+                            mode_value,
+                        });
+                        result.gear_names.push(gear_name(result.gears.len(), None));
+                    }
+                } else {
+                    for v in &mode.values {
+                        if let Some(mode_value) = v.value.as_i64() {
+                            result.gears.push(FanGear {
+                                work_mode,
+                                mode_value,
+                            });
+                            result
+                                .gear_names
+                                .push(gear_name(result.gears.len(), v.name.as_deref()));
+                        }
+                    }
+                }
+                if !result.gears.is_empty() {
+                    gear_mode_names.push(mode.name.as_str());
+                }
+            }
+        }
+
+        // Otherwise, modes that are named after their speed, such
+        // as Low, Medium, High
+        if result.gears.is_empty() {
+            for name in SPEED_MODE_NAMES {
+                if let Some(mode) = work_modes.mode_by_name(name) {
+                    if let Some(work_mode) = mode.value.as_i64() {
+                        result.gears.push(FanGear {
+                            work_mode,
+                            mode_value: mode.default_value(),
+                        });
+                        result.gear_names.push(mode.name.to_string());
+                        gear_mode_names.push(mode.name.as_str());
+                    }
+                }
+            }
+        }
+
+        for mode in work_modes.modes.values() {
+            if gear_mode_names.contains(&mode.name.as_str()) {
+                continue;
+            }
+            if let Some(work_mode) = mode.value.as_i64() {
+                result
+                    .presets
+                    .push((mode.name.to_string(), work_mode, mode.default_value()));
+            }
+        }
+
+        result
+    }
+
+    /// Rounds the requested percentage to the nearest supported gear.
+    /// Returns None for 0%, which means that the fan should be turned off
+    pub fn gear_for_percentage(&self, percent: u8) -> Option<FanGear> {
+        if percent == 0 || self.gears.is_empty() {
+            return None;
+        }
+        let n = self.gears.len();
+        let percent = percent.min(100) as usize;
+        let idx = ((percent * n + 50) / 100).clamp(1, n);
+        self.gears.get(idx - 1).copied()
+    }
+
+    /// Returns the percentage that represents the specified gear
+    pub fn percentage_for_gear(&self, gear: FanGear) -> Option<u8> {
+        let idx = self.gears.iter().position(|g| *g == gear)?;
+        let n = self.gears.len();
+        Some((((idx + 1) * 100 + n / 2) / n) as u8)
+    }
+
+    /// Returns the gear for the reported work mode and value.
+    /// For modes that used a fixed default value as their gear, the
+    /// mode value is not considered.
+    pub fn gear_for_mode(&self, work_mode: i64, mode_value: i64) -> Option<FanGear> {
+        let reported = FanGear {
+            work_mode,
+            mode_value,
+        };
+        if self.gears.contains(&reported) {
+            return Some(reported);
+        }
+        let gear = self.gears.iter().find(|g| g.work_mode == work_mode)?;
+        let distinct_modes = self
+            .gears
+            .iter()
+            .all(|g| g == gear || g.work_mode != work_mode);
+        distinct_modes.then_some(*gear)
+    }
+
+    /// Returns the percentage for the reported work mode and value
+    pub fn percentage_for_mode(&self, work_mode: i64, mode_value: i64) -> Option<u8> {
+        self.percentage_for_gear(self.gear_for_mode(work_mode, mode_value)?)
+    }
+
+    /// Returns the name of the gear for the reported work mode and value
+    pub fn gear_name_for_mode(&self, work_mode: i64, mode_value: i64) -> Option<&str> {
+        let gear = self.gear_for_mode(work_mode, mode_value)?;
+        let idx = self.gears.iter().position(|g| *g == gear)?;
+        self.gear_names.get(idx).map(String::as_str)
+    }
+
+    pub fn gear_by_name(&self, name: &str) -> Option<FanGear> {
+        let idx = self
+            .gear_names
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(name))?;
+        self.gears.get(idx).copied()
+    }
+
+    pub fn preset_for_mode(&self, work_mode: i64) -> Option<&str> {
+        self.presets
+            .iter()
+            .find(|(_, mode, _)| *mode == work_mode)
+            .map(|(name, _, _)| name.as_str())
+    }
+
+    pub fn preset_by_name(&self, name: &str) -> Option<(i64, i64)> {
+        self.presets
+            .iter()
+            .find(|(n, _, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, mode, value)| (*mode, *value))
+    }
+}
+
+pub struct DeviceFan {
+    fan: FanConfig,
+    speeds: FanSpeeds,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl DeviceFan {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        let work_modes = ParsedWorkMode::with_device(device).ok()?;
+        let speeds = FanSpeeds::with_work_modes(&work_modes);
+        let id = topic_safe_id(device);
+
+        let has_gears = !speeds.gears.is_empty();
+        let has_presets = !speeds.presets.is_empty();
+
+        Some(Self {
+            fan: FanConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: None,
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-fan"),
+                    entity_category: None,
+                    icon: None,
+                },
+                // command_topic controls the power state; just route it to
+                // the general power switch handler
+                command_topic: format!(
+                    "{prefix}/switch/{id}/command/powerSwitch",
+                    prefix = topic_prefix()
+                ),
+                state_topic: format!("{prefix}/fan/{id}/state", prefix = topic_prefix()),
+                percentage_command_topic: has_gears
+                    .then(|| format!("{prefix}/fan/{id}/set-percentage", prefix = topic_prefix())),
+                percentage_state_topic: has_gears.then(|| {
+                    format!(
+                        "{prefix}/fan/{id}/notify-percentage",
+                        prefix = topic_prefix()
+                    )
+                }),
+                preset_mode_command_topic: has_presets
+                    .then(|| format!("{prefix}/fan/{id}/set-preset-mode", prefix = topic_prefix())),
+                preset_mode_state_topic: has_presets.then(|| {
+                    format!(
+                        "{prefix}/fan/{id}/notify-preset-mode",
+                        prefix = topic_prefix()
+                    )
+                }),
+                preset_modes: speeds
+                    .presets
+                    .iter()
+                    .map(|(name, _, _)| name.to_string())
+                    .collect(),
+            },
+            speeds,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DeviceFan {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.fan.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(device_state) = device.device_state() {
+            client
+                .publish(
+                    &self.fan.state_topic,
+                    if device_state.on { "ON" } else { "OFF" },
+                )
+                .await?;
+        }
+
+        let Some((work_mode, mode_value)) = reported_work_mode(&device) else {
+            return Ok(());
+        };
+
+        if let Some(topic) = &self.fan.percentage_state_topic {
+            if let Some(percent) = self.speeds.percentage_for_mode(work_mode, mode_value) {
+                client.publish(topic, percent.to_string()).await?;
+            }
+        }
+
+        if let Some(topic) = &self.fan.preset_mode_state_topic {
+            let preset = self.speeds.preset_for_mode(work_mode).unwrap_or("None");
+            client.publish(topic, preset).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the work mode and mode value in the platform state
+fn reported_work_mode(device: &ServiceDevice) -> Option<(i64, i64)> {
+    let cap = device.get_state_capability_by_instance("workMode")?;
+    let work_mode = cap.state.pointer("/value/workMode")?.as_i64()?;
+    let mode_value = cap
+        .state
+        .pointer("/value/modeValue")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    Some((work_mode, mode_value))
+}
+
+/// Offers the gears of the fan by name, for automations that want a
+/// specific gear rather than a percentage. It reports the same state
+/// as the percentage of the fan entity.
+pub struct FanGearSelect {
+    select: SelectConfig,
+    speeds: FanSpeeds,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl FanGearSelect {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        let work_modes = ParsedWorkMode::with_device(device).ok()?;
+        let speeds = FanSpeeds::with_work_modes(&work_modes);
+        if speeds.gears.is_empty() {
+            return None;
+        }
+        let id = topic_safe_id(device);
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Fan Gear".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-fan-gear"),
+                    entity_category: None,
+                    icon: Some("mdi:fan".to_string()),
+                },
+                command_topic: format!("{prefix}/fan/{id}/set-gear", prefix = topic_prefix()),
+                state_topic: format!("{prefix}/fan/{id}/notify-gear", prefix = topic_prefix()),
+                options: speeds.gear_names.clone(),
+            },
+            speeds,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FanGearSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(name) = reported_work_mode(&device).and_then(|(work_mode, mode_value)| {
+            self.speeds.gear_name_for_mode(work_mode, mode_value)
+        }) {
+            client.publish(&self.select.state_topic, name).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn set_fan_work_mode(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    work_mode: i64,
+    mode_value: i64,
+) -> anyhow::Result<()> {
+    if !device.device_state().map(|s| s.on).unwrap_or(false) {
+        state.device_power_on(device, true).await?;
+    }
+    state
+        .humidifier_set_parameter(device, work_mode, mode_value)
+        .await
+}
+
+pub async fn mqtt_fan_set_percentage(
+    Payload(percent): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_percentage: {id}: {percent}");
+    let Some(device) = state.resolve_device_for_command(&id, "fan-speed").await? else {
+        return Ok(());
+    };
+    let percent: f64 = percent.trim().parse()?;
+    let percent = percent.round().clamp(0., 100.) as u8;
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let speeds = FanSpeeds::with_work_modes(&work_modes);
+
+    match speeds.gear_for_percentage(percent) {
+        Some(gear) => set_fan_work_mode(&state, &device, gear.work_mode, gear.mode_value).await,
+        None => state.device_power_on(&device, false).await,
+    }
+}
+
+pub async fn mqtt_fan_set_preset_mode(
+    Payload(preset): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_preset_mode: {id}: {preset}");
+    let Some(device) = state.resolve_device_for_command(&id, "fan-speed").await? else {
+        return Ok(());
+    };
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let speeds = FanSpeeds::with_work_modes(&work_modes);
+    let (work_mode, mode_value) = speeds
+        .preset_by_name(&preset)
+        .ok_or_else(|| anyhow!("preset {preset} not found"))?;
+
+    set_fan_work_mode(&state, &device, work_mode, mode_value).await
+}
+
+pub async fn mqtt_fan_set_gear(
+    Payload(gear): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_gear: {id}: {gear}");
+    let Some(device) = state.resolve_device_for_command(&id, "fan-speed").await? else {
+        return Ok(());
+    };
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let speeds = FanSpeeds::with_work_modes(&work_modes);
+    let gear = speeds
+        .gear_by_name(&gear)
+        .ok_or_else(|| anyhow!("gear {gear} not found"))?;
+
+    set_fan_work_mode(&state, &device, gear.work_mode, gear.mode_value).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::fixtures;
+
+    fn speeds_for_sku(sku: &str) -> FanSpeeds {
+        let device = fixtures::device(fixtures::LIST_DEVICES_ISSUE4, sku);
+        let cap = device.capability_by_instance("workMode").unwrap();
+        FanSpeeds::with_work_modes(&ParsedWorkMode::with_capability(cap).unwrap())
+    }
+
+    #[test]
+    fn tower_fan_gears() {
+        let speeds = speeds_for_sku("H7111");
+        k9::assert_equal!(speeds.gears.len(), 8);
+        k9::assert_equal!(
+            speeds
+                .presets
+                .iter()
+                .map(|(name, _, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Auto", "Custom", "Nature", "Sleep", "Storm"]
+        );
+
+        k9::assert_equal!(speeds.gear_for_percentage(0), None);
+        k9::assert_equal!(
+            speeds.gear_for_percentage(1),
+            Some(FanGear {
+                work_mode: 1,
+                mode_value: 1
+            })
+        );
+        k9::assert_equal!(
+            speeds.gear_for_percentage(50),
+            Some(FanGear {
+                work_mode: 1,
+                mode_value: 4
+            })
+        );
+        k9::assert_equal!(
+            speeds.gear_for_percentage(100),
+            Some(FanGear {
+                work_mode: 1,
+                mode_value: 8
+            })
+        );
+
+        // Round tripping a percentage must be stable, so that the hass
+        // slider doesn't drift
+        for percent in 1..=100 {
+            let gear = speeds.gear_for_percentage(percent).unwrap();
+            let reported = speeds.percentage_for_gear(gear).unwrap();
+            k9::assert_equal!(speeds.gear_for_percentage(reported), Some(gear));
+        }
+
+        k9::assert_equal!(speeds.percentage_for_mode(1, 4), Some(50));
+        k9::assert_equal!(speeds.percentage_for_mode(5, 0), None);
+        k9::assert_equal!(speeds.preset_for_mode(5), Some("Sleep"));
+
+        k9::assert_equal!(speeds.gear_names[0], "Gear 1");
+        k9::assert_equal!(speeds.gear_name_for_mode(1, 4), Some("Gear 4"));
+        k9::assert_equal!(speeds.gear_name_for_mode(5, 0), None);
+        k9::assert_equal!(
+            speeds.gear_by_name("gear 8"),
+            Some(FanGear {
+                work_mode: 1,
+                mode_value: 8
+            })
+        );
+    }
+
+    #[test]
+    fn named_speed_modes() {
+        let speeds = speeds_for_sku("H7121");
+        k9::assert_equal!(
+            speeds.gears,
+            vec![
+                FanGear {
+                    work_mode: 1,
+                    mode_value: 0
+                },
+                FanGear {
+                    work_mode: 2,
+                    mode_value: 0
+                },
+                FanGear {
+                    work_mode: 3,
+                    mode_value: 0
+                },
+            ]
+        );
+        k9::assert_equal!(speeds.percentage_for_mode(2, 0), Some(67));
+        k9::assert_equal!(speeds.percentage_for_mode(3, 123), Some(100));
+        k9::assert_equal!(speeds.preset_by_name("sleep"), Some((16, 0)));
+        k9::assert_equal!(speeds.gear_names, vec!["Low", "Medium", "High"]);
+        k9::assert_equal!(speeds.gear_name_for_mode(2, 7), Some("Medium"));
+    }
+}
@@ -1,10 +1,13 @@
 use crate::ble::TargetHumidity;
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
-use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::hass_mqtt::sensor::reported_humidity_percent;
+use crate::hass_mqtt::work_mode::{ParsedWorkMode, UNKNOWN_WORK_MODE};
 use crate::platform_api::{DeviceParameters, DeviceType, IntegerRange};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, HassClient, IdParameter,
+};
 use crate::service::state::StateHandle;
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -43,6 +46,11 @@ pub struct HumidifierConfig {
     pub modes: Vec<String>,
 
     pub state_topic: String,
+
+    /// we will publish the current humidity here, if the device
+    /// has a humidity sensor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_humidity_topic: Option<String>,
 }
 
 #[derive(Clone)]
@@ -67,31 +75,51 @@ impl Humidifier {
         // command_topic controls the power state; just route it to
         // the general power switch handler
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/powerSwitch",
+            "{prefix}/switch/{id}/command/powerSwitch",
+            prefix = topic_prefix(),
             id = topic_safe_id(device)
         );
 
         let target_humidity_command_topic = format!(
-            "gv2mqtt/humidifier/{id}/set-target",
+            "{prefix}/humidifier/{id}/set-target",
+            prefix = topic_prefix(),
             id = topic_safe_id(device)
         );
         let target_humidity_state_topic = format!(
-            "gv2mqtt/humidifier/{id}/notify-target",
+            "{prefix}/humidifier/{id}/notify-target",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{prefix}/humidifier/{id}/state",
+            prefix = topic_prefix(),
             id = topic_safe_id(device)
         );
-        let state_topic = format!("gv2mqtt/humidifier/{id}/state", id = topic_safe_id(device));
 
         let mode_command_topic = format!(
-            "gv2mqtt/humidifier/{id}/set-mode",
+            "{prefix}/humidifier/{id}/set-mode",
+            prefix = topic_prefix(),
             id = topic_safe_id(device)
         );
         let mode_state_topic = format!(
-            "gv2mqtt/humidifier/{id}/notify-mode",
+            "{prefix}/humidifier/{id}/notify-mode",
+            prefix = topic_prefix(),
             id = topic_safe_id(device)
         );
 
         let unique_id = format!("gv2mqtt-{id}-humidifier", id = topic_safe_id(device),);
 
+        let current_humidity_topic =
+            device
+                .get_capability_by_instance("sensorHumidity")
+                .map(|_| {
+                    format!(
+                        "{prefix}/humidifier/{id}/notify-current-humidity",
+                        prefix = topic_prefix(),
+                        id = topic_safe_id(device)
+                    )
+                });
+
         let mut min_humidity = None;
         let mut max_humidity = None;
 
@@ -103,17 +131,15 @@ impl Humidifier {
 
         if let Some(info) = &device.http_device_info {
             if let Some(cap) = info.capability_by_instance("humidity") {
-                match &cap.parameters {
-                    Some(DeviceParameters::Integer {
-                        range: IntegerRange { min, max, .. },
-                        unit,
-                    }) => {
-                        if unit.as_deref() == Some("unit.percent") {
-                            min_humidity.replace(*min as u8);
-                            max_humidity.replace(*max as u8);
-                        }
+                if let Some(DeviceParameters::Integer {
+                    range: IntegerRange { min, max, .. },
+                    unit,
+                }) = &cap.parameters
+                {
+                    if unit.as_deref() == Some("unit.percent") {
+                        min_humidity.replace(*min as u8);
+                        max_humidity.replace(*max as u8);
                     }
-                    _ => {}
                 }
             }
         }
@@ -148,6 +174,7 @@ impl Humidifier {
                 mode_state_topic,
                 modes,
                 state_topic,
+                current_humidity_topic,
                 optimistic,
             },
             device_id: device.id.to_string(),
@@ -191,6 +218,12 @@ impl EntityInstance for Humidifier {
             }
         }
 
+        if let Some(topic) = &self.humidifier.current_humidity_topic {
+            if let Some(humidity) = reported_humidity_percent(&device) {
+                client.publish(topic, format!("{humidity:.0}")).await?;
+            }
+        }
+
         if let Some(humidity) = device.target_humidity_percent {
             client
                 .publish(
@@ -248,7 +281,14 @@ pub async fn mqtt_device_set_work_mode(
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
     log::info!("mqtt_humidifier_set_mode: {id}: {mode}");
-    let device = state.resolve_device_for_control(&id).await?;
+    let Some(device) = state.resolve_device_for_command(&id, "work-mode").await? else {
+        return Ok(());
+    };
+
+    if mode == UNKNOWN_WORK_MODE {
+        // Not a real mode; restore the select to the reported mode
+        return state.notify_of_state_change(&device.id).await;
+    }
 
     let work_modes = ParsedWorkMode::with_device(&device)?;
     let work_mode = work_modes
@@ -275,7 +315,12 @@ pub async fn mqtt_humidifier_set_target(
 ) -> anyhow::Result<()> {
     log::info!("mqtt_humidifier_set_target: {id}: {percent}");
 
-    let device = state.resolve_device_for_control(&id).await?;
+    let Some(device) = state
+        .resolve_device_for_command(&id, "target-humidity")
+        .await?
+    else {
+        return Ok(());
+    };
 
     let use_iot = device.pollable_via_iot() && state.get_iot_client().await.is_some();
 
@@ -1,10 +1,17 @@
+use crate::cache::{has_marker, invalidate_key, set_marker};
 use crate::hass_mqtt::base::EntityConfig;
-use crate::service::hass::HassClient;
+use crate::service::hass::{scoped_unique_id, HassClient};
 use crate::service::state::StateHandle;
 use anyhow::Context;
 use async_trait::async_trait;
 use serde::Serialize;
-use std::sync::Arc;
+use serde_json::{json, Map, Value as JsonValue};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// The cache topic used to remember which devices have had their
+/// per-entity configs migrated to a device-based discovery config
+const DEVICE_DISCOVERY_MIGRATION_TOPIC: &str = "device-discovery-migrations";
 
 #[async_trait]
 pub trait EntityInstance: Send + Sync {
@@ -19,15 +26,182 @@ pub async fn publish_entity_config<T: Serialize>(
     base: &EntityConfig,
     config: &T,
 ) -> anyhow::Result<()> {
-    // TODO: remember all published topics for future GC
-
     let disco = state.get_hass_disco_prefix().await;
     let topic = format!(
         "{disco}/{integration}/{unique_id}/config",
-        unique_id = base.unique_id
+        unique_id = scoped_unique_id(&base.unique_id)
     );
 
-    client.publish_obj(topic, config).await
+    let mut config = serde_json::to_value(config)?;
+    if let (Some(enabled), JsonValue::Object(map)) =
+        (base.enabled_by_default(integration), &mut config)
+    {
+        map.insert("enabled_by_default".to_string(), enabled.into());
+    }
+
+    if let Some(discovery) = client.device_discovery() {
+        return discovery.add_component(integration, base, &config, topic);
+    }
+
+    // Avoid needlessly re-publishing a config that hass already has
+    let payload = serde_json::to_string(&config)?;
+    if state.is_discovery_config_unchanged(&topic, &payload).await {
+        log::trace!("{topic} is unchanged, not re-publishing it");
+        return Ok(());
+    }
+
+    client.publish_config_obj(&topic, &config).await?;
+    state.record_discovery_topic(&topic, true).await;
+    state.record_discovery_config(&topic, payload).await;
+
+    // The device has per-entity configs again, so they must be
+    // migrated if device-based discovery is enabled later
+    let device_id = discovery_device_id(base);
+    if let Err(err) = invalidate_key(DEVICE_DISCOVERY_MIGRATION_TOPIC, &device_id) {
+        log::warn!("Unable to reset the discovery migration of {device_id}: {err:#}");
+    }
+    Ok(())
+}
+
+/// The id under which the components of an entity are grouped for
+/// device-based discovery
+fn discovery_device_id(base: &EntityConfig) -> String {
+    base.device
+        .identifiers
+        .first()
+        .cloned()
+        .unwrap_or_else(|| base.unique_id.clone())
+}
+
+/// The components of a device-based discovery config
+#[derive(Default)]
+struct DeviceComponents {
+    device: JsonValue,
+    origin: JsonValue,
+    components: Map<String, JsonValue>,
+    /// The per-entity topics that these components would otherwise
+    /// have been published to
+    entity_topics: Vec<String>,
+}
+
+/// Collects entity configs so that they can be published as a single
+/// device-based discovery config per device, rather than one config
+/// per entity
+#[derive(Default)]
+pub struct DeviceDiscovery {
+    devices: Mutex<BTreeMap<String, DeviceComponents>>,
+}
+
+impl DeviceDiscovery {
+    fn add_component<T: Serialize>(
+        &self,
+        integration: &str,
+        base: &EntityConfig,
+        config: &T,
+        entity_topic: String,
+    ) -> anyhow::Result<()> {
+        let mut component = match serde_json::to_value(config)? {
+            JsonValue::Object(map) => map,
+            _ => anyhow::bail!("entity config for {} is not an object", base.unique_id),
+        };
+        // The device and origin are specified once for the device
+        let device = component.remove("device").unwrap_or_default();
+        let origin = component.remove("origin").unwrap_or_default();
+        component.insert("platform".to_string(), integration.into());
+
+        let device_id = discovery_device_id(base);
+
+        let mut devices = self.devices.lock().expect("lock not poisoned");
+        let entry = devices.entry(device_id).or_default();
+        entry.device = device;
+        entry.origin = origin;
+        entry.components.insert(
+            scoped_unique_id(&base.unique_id).to_string(),
+            component.into(),
+        );
+        entry.entity_topics.push(entity_topic);
+        Ok(())
+    }
+
+    /// Publishes the collected config for each device. The first time,
+    /// hass is asked to migrate each entity from its per-entity topic,
+    /// and the per-entity topic is then cleared, so that any retained
+    /// config left there by an earlier version doesn't produce a
+    /// duplicate. That is recorded, so that it only happens once per
+    /// device, rather than on every discovery cycle.
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        let devices = std::mem::take(&mut *self.devices.lock().expect("lock not poisoned"));
+        let disco = state.get_hass_disco_prefix().await;
+        let delay = tokio::time::Duration::from_millis(100);
+
+        for (device_id, device) in devices {
+            let migrate = match has_marker(DEVICE_DISCOVERY_MIGRATION_TOPIC, &device_id) {
+                Ok(migrated) => !migrated,
+                Err(err) => {
+                    log::warn!("Unable to check the discovery migration of {device_id}: {err:#}");
+                    true
+                }
+            };
+            if migrate {
+                for topic in &device.entity_topics {
+                    client
+                        .publish_config_obj(topic, json!({"migrate_discovery": true}))
+                        .await?;
+                }
+            }
+
+            let topic = format!("{disco}/device/{device_id}/config");
+            client
+                .publish_config_obj(
+                    &topic,
+                    json!({
+                        "device": device.device,
+                        "origin": device.origin,
+                        "components": device.components,
+                    }),
+                )
+                .await?;
+            state.record_discovery_topic(&topic, true).await;
+
+            if migrate {
+                for topic in &device.entity_topics {
+                    client.publish_retained(topic, "").await?;
+                }
+                if let Err(err) = set_marker(DEVICE_DISCOVERY_MIGRATION_TOPIC, &device_id) {
+                    log::warn!("Unable to record the discovery migration of {device_id}: {err:#}");
+                }
+            }
+            tokio::time::sleep(delay).await;
+        }
+        Ok(())
+    }
+}
+
+/// Removes an entity that we previously published from hass, by
+/// publishing an empty config for it. The empty config is retained
+/// so that it also replaces any retained config on the broker.
+pub struct RemovedEntity {
+    pub integration: &'static str,
+    pub unique_id: String,
+}
+
+#[async_trait]
+impl EntityInstance for RemovedEntity {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        let disco = state.get_hass_disco_prefix().await;
+        let topic = format!(
+            "{disco}/{integration}/{unique_id}/config",
+            integration = self.integration,
+            unique_id = scoped_unique_id(&self.unique_id)
+        );
+        client.publish_retained(&topic, "").await?;
+        state.record_discovery_topic(&topic, false).await;
+        Ok(())
+    }
+
+    async fn notify_state(&self, _client: &HassClient) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Default, Clone)]
@@ -53,6 +227,20 @@ impl EntityList {
         state: &StateHandle,
         client: &HassClient,
     ) -> anyhow::Result<()> {
+        if client.uses_device_discovery() {
+            let discovery = Arc::new(DeviceDiscovery::default());
+            let collector = client.collecting_into(&discovery);
+            for e in &self.entities {
+                e.publish_config(state, &collector)
+                    .await
+                    .context("EntityList::publish_config")?;
+            }
+            return discovery
+                .publish(state, client)
+                .await
+                .context("DeviceDiscovery::publish");
+        }
+
         // Allow HASS time to process each entity before registering the next
         let delay = tokio::time::Duration::from_millis(100);
         for e in &self.entities {
@@ -73,3 +261,44 @@ impl EntityList {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hass_mqtt::button::ButtonConfig;
+
+    #[test]
+    fn device_discovery_components() {
+        let discovery = DeviceDiscovery::default();
+        for name in ["Purge Caches", "Reload"] {
+            let button = ButtonConfig::new(name, "gv2mqtt/button");
+            discovery
+                .add_component(
+                    "button",
+                    &button.base,
+                    &button,
+                    format!("homeassistant/button/{}/config", button.base.unique_id),
+                )
+                .unwrap();
+        }
+
+        let devices = discovery.devices.lock().unwrap();
+        k9::assert_equal!(devices.keys().collect::<Vec<_>>(), vec!["gv2mqtt"]);
+        let device = &devices["gv2mqtt"];
+        k9::assert_equal!(device.device["name"], json!("Govee to MQTT"));
+        k9::assert_equal!(device.origin["name"], json!("gv2mqtt"));
+        k9::assert_equal!(
+            device.entity_topics,
+            vec![
+                "homeassistant/button/global-purge_caches/config",
+                "homeassistant/button/global-reload/config"
+            ]
+        );
+
+        let component = &device.components["global-purge_caches"];
+        k9::assert_equal!(component["platform"], json!("button"));
+        k9::assert_equal!(component["command_topic"], json!("gv2mqtt/button"));
+        assert!(component.get("device").is_none());
+        assert!(component.get("origin").is_none());
+    }
+}
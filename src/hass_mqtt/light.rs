@@ -1,15 +1,18 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
-use crate::platform_api::DeviceType;
+use crate::lan_api::DeviceColor;
+use crate::platform_api::{
+    is_diy_scene_name, is_snapshot_scene_name, DeviceCapability, DeviceParameters, DeviceType,
+};
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
     availability_topic, kelvin_to_mired, light_segment_state_topic, light_state_topic,
-    topic_safe_id, HassClient,
+    topic_prefix, topic_safe_id, topic_safe_string, HassClient,
 };
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
 use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value as JsonValue};
 
 /// <https://www.home-assistant.io/integrations/light.mqtt/#json-schema>
 #[derive(Serialize, Clone, Debug)]
@@ -44,6 +47,10 @@ pub struct LightConfig {
     pub max_mireds: Option<u32>,
 
     pub payload_available: String,
+
+    /// Carries the raw capability state of the device, when enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
 }
 
 impl LightConfig {
@@ -57,12 +64,39 @@ pub struct DeviceLight {
     light: LightConfig,
     device_id: String,
     state: StateHandle,
+    /// When set, this entity represents the nightlight of a device
+    /// whose primary function is not a light, and is controlled via
+    /// this toggle instance rather than the main power switch
+    nightlight_instance: Option<String>,
+}
+
+/// The capability that selects the effect shown by a nightlight
+pub const NIGHTLIGHT_SCENE_INSTANCE: &str = "nightlightScene";
+
+/// Derives the suffix for the topics and unique_id of a nightlight
+/// entity from its toggle instance, eg: nightlightToggle -> nightlight
+pub fn nightlight_topic_suffix(instance: &str) -> String {
+    topic_safe_string(
+        instance
+            .strip_suffix("Toggle")
+            .filter(|s| !s.is_empty())
+            .unwrap_or(instance),
+    )
+}
+
+pub fn nightlight_state_topic(device: &ServiceDevice, instance: &str) -> String {
+    format!(
+        "{prefix}/light/{id}/state/{suffix}",
+        prefix = topic_prefix(),
+        id = topic_safe_id(device),
+        suffix = nightlight_topic_suffix(instance)
+    )
 }
 
 #[async_trait]
 impl EntityInstance for DeviceLight {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.light.publish(&state, &client).await
+        self.light.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -76,6 +110,17 @@ impl EntityInstance for DeviceLight {
             .await
             .expect("device to exist");
 
+        if let Some(instance) = &self.nightlight_instance {
+            return match nightlight_state(&device, instance) {
+                Some(light_state) => {
+                    client
+                        .publish_obj(&self.light.state_topic, &light_state)
+                        .await
+                }
+                None => Ok(()),
+            };
+        }
+
         match device.device_state() {
             Some(device_state) => {
                 log::trace!("LightConfig::notify_state: state is {device_state:?}");
@@ -110,7 +155,7 @@ impl EntityInstance for DeviceLight {
 
                 client
                     .publish_obj(&self.light.state_topic, &light_state)
-                    .await
+                    .await?;
             }
             None => {
                 // TODO: mark as unavailable or something? Don't
@@ -118,13 +163,151 @@ impl EntityInstance for DeviceLight {
                 // as that could cause it to wake up.
                 client
                     .publish_obj(&self.light.state_topic, &json!({"state":"OFF"}))
-                    .await
+                    .await?;
             }
         }
+
+        if let Some(topic) = &self.light.json_attributes_topic {
+            client
+                .publish_obj(topic, device.raw_capability_states())
+                .await?;
+        }
+        Ok(())
     }
 }
 
+/// Computes the hass light state for a nightlight from the platform
+/// state of its capabilities, or from the nightlight state reported
+/// via IoT for devices that don't report it through the platform API
+fn nightlight_state(device: &ServiceDevice, instance: &str) -> Option<JsonValue> {
+    let cap_value = |inst: &str| {
+        device
+            .get_state_capability_by_instance(inst)
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(|v| v.as_i64())
+    };
+    let reported = device.nightlight_state.as_ref();
+
+    let on = cap_value(instance)
+        .map(|v| v != 0)
+        .or_else(|| reported.map(|p| p.on))?;
+    if !on {
+        return Some(json!({"state": "OFF"}));
+    }
+
+    let brightness = cap_value("brightness")
+        .map(|v| v as u8)
+        .or_else(|| reported.map(|p| p.brightness));
+    let color = cap_value("colorRgb")
+        .map(|v| DeviceColor {
+            r: ((v >> 16) & 0xff) as u8,
+            g: ((v >> 8) & 0xff) as u8,
+            b: (v & 0xff) as u8,
+        })
+        .or_else(|| {
+            reported.map(|p| DeviceColor {
+                r: p.r,
+                g: p.g,
+                b: p.b,
+            })
+        });
+    let effect = cap_value(NIGHTLIGHT_SCENE_INSTANCE).and_then(|value| {
+        match &device
+            .get_capability_by_instance(NIGHTLIGHT_SCENE_INSTANCE)?
+            .parameters
+        {
+            Some(DeviceParameters::Enum { options }) => options
+                .iter()
+                .find(|opt| opt.value.as_i64() == Some(value))
+                .map(|opt| opt.name.to_string()),
+            _ => None,
+        }
+    });
+
+    let mut light_state = json!({"state": "ON"});
+    if let Some(brightness) = brightness {
+        light_state["brightness"] = brightness.into();
+    }
+    if let Some(color) = color {
+        light_state["color_mode"] = "rgb".into();
+        light_state["color"] = json!({"r": color.r, "g": color.g, "b": color.b});
+    }
+    if let Some(effect) = effect {
+        light_state["effect"] = effect.into();
+    }
+    Some(light_state)
+}
+
 impl DeviceLight {
+    /// Creates a light entity for the nightlight function of a
+    /// device whose primary function is not a light
+    pub fn for_nightlight(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Self {
+        let id = topic_safe_id(device);
+        let suffix = nightlight_topic_suffix(&instance.instance);
+
+        let info = device.http_device_info.as_ref();
+        let supports_rgb = info.map(|info| info.supports_rgb()).unwrap_or(false);
+        let brightness = info.map(|info| info.supports_brightness()).unwrap_or(false);
+
+        let supported_color_modes = if supports_rgb {
+            vec!["rgb".to_string()]
+        } else if brightness {
+            vec!["brightness".to_string()]
+        } else {
+            vec!["onoff".to_string()]
+        };
+
+        let effect_list = match device
+            .get_capability_by_instance(NIGHTLIGHT_SCENE_INSTANCE)
+            .and_then(|cap| cap.parameters.as_ref())
+        {
+            Some(DeviceParameters::Enum { options }) => {
+                options.iter().map(|opt| opt.name.to_string()).collect()
+            }
+            _ => vec![],
+        };
+
+        Self {
+            light: LightConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Night Light".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-{suffix}"),
+                    entity_category: None,
+                    icon: None,
+                },
+                schema: "json".to_string(),
+                command_topic: format!(
+                    "{prefix}/light/{id}/nightlight/{inst}",
+                    prefix = topic_prefix(),
+                    inst = instance.instance
+                ),
+                state_topic: nightlight_state_topic(device, &instance.instance),
+                supported_color_modes,
+                brightness,
+                brightness_scale: 100,
+                effect: !effect_list.is_empty(),
+                effect_list,
+                payload_available: "online".to_string(),
+                max_mireds: None,
+                min_mireds: None,
+                optimistic: false,
+                icon: Some("mdi:lightbulb-night".to_string()),
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            nightlight_instance: Some(instance.instance.to_string()),
+        }
+    }
+
     pub async fn for_device(
         device: &ServiceDevice,
         state: &StateHandle,
@@ -134,9 +317,14 @@ impl DeviceLight {
         let device_type = device.device_type();
 
         let command_topic = match segment {
-            None => format!("gv2mqtt/light/{id}/command", id = topic_safe_id(device)),
+            None => format!(
+                "{prefix}/light/{id}/command",
+                prefix = topic_prefix(),
+                id = topic_safe_id(device)
+            ),
             Some(seg) => format!(
-                "gv2mqtt/light/{id}/command/{seg}",
+                "{prefix}/light/{id}/command/{seg}",
+                prefix = topic_prefix(),
                 id = topic_safe_id(device)
             ),
         };
@@ -155,14 +343,23 @@ impl DeviceLight {
         let unique_id = format!(
             "gv2mqtt-{id}{seg}",
             id = topic_safe_id(device),
-            seg = segment.map(|n| format!("-{n}")).unwrap_or(String::new())
+            seg = segment.map(|n| format!("-{n}")).unwrap_or_default()
         );
 
         let effect_list = if segment.is_some() {
             vec![]
         } else {
             match state.device_list_scenes(device).await {
-                Ok(scenes) => scenes,
+                Ok(scenes) => {
+                    let limit = state.effect_list_limit().await;
+                    if scenes.len() > limit {
+                        log::debug!(
+                            "Offering {limit} of the {} scenes of {device} as effects",
+                            scenes.len()
+                        );
+                    }
+                    limit_effect_list(scenes, limit)
+                }
                 Err(err) => {
                     log::error!("Unable to list scenes for {device}: {err:#}");
                     vec![]
@@ -198,6 +395,17 @@ impl DeviceLight {
                 .map(|info| info.supports_brightness())
                 .unwrap_or(false);
 
+        let json_attributes_topic =
+            if segment.is_none() && device.has_primary_light() && device.publishes_raw_state() {
+                Some(format!(
+                    "{prefix}/light/{id}/attributes",
+                    prefix = topic_prefix(),
+                    id = topic_safe_id(device)
+                ))
+            } else {
+                None
+            };
+
         let name = match segment {
             Some(n) => Some(format!("Segment {:03}", n + 1)),
             None if device_type == DeviceType::Humidifier => Some("Night Light".to_string()),
@@ -222,16 +430,116 @@ impl DeviceLight {
                 supported_color_modes,
                 brightness,
                 brightness_scale: 100,
-                effect: true,
+                effect: !effect_list.is_empty(),
                 effect_list,
                 payload_available: "online".to_string(),
                 max_mireds,
                 min_mireds,
                 optimistic: segment.is_some(),
                 icon,
+                json_attributes_topic,
             },
             device_id: device.id.to_string(),
             state: state.clone(),
+            nightlight_instance: None,
+        })
+    }
+}
+
+/// Picks at most `limit` of the scenes to offer as effects. The DIY
+/// scenes and snapshots, which were created by the user, are kept in
+/// preference to the stock scenes, and the order is preserved.
+fn limit_effect_list(scenes: Vec<String>, limit: usize) -> Vec<String> {
+    if scenes.len() <= limit {
+        return scenes;
+    }
+    let is_own = |scene: &str| is_diy_scene_name(scene) || is_snapshot_scene_name(scene);
+    let own = scenes.iter().filter(|scene| is_own(scene)).count();
+    let mut stock_allowance = limit.saturating_sub(own);
+    let mut own_allowance = limit.min(own);
+    scenes
+        .into_iter()
+        .filter(|scene| {
+            let allowance = if is_own(scene) {
+                &mut own_allowance
+            } else {
+                &mut stock_allowance
+            };
+            if *allowance == 0 {
+                return false;
+            }
+            *allowance -= 1;
+            true
         })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{
+        fixtures, DeviceCapabilityKind, DeviceCapabilityState, HttpDeviceState,
+    };
+
+    #[test]
+    fn effect_list_limit() {
+        let scenes: Vec<String> = [
+            "Aurora",
+            "DIY: Fade",
+            "Forest",
+            "Rainbow",
+            "Snapshot: Movie",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        k9::assert_equal!(limit_effect_list(scenes.clone(), 10), scenes);
+        k9::assert_equal!(
+            limit_effect_list(scenes.clone(), 3),
+            vec!["Aurora", "DIY: Fade", "Snapshot: Movie"]
+        );
+        k9::assert_equal!(limit_effect_list(scenes.clone(), 1), vec!["DIY: Fade"]);
+        k9::assert_equal!(limit_effect_list(scenes, 0), Vec::<String>::new());
+    }
+
+    #[test]
+    fn nightlight_suffix() {
+        assert_eq!(nightlight_topic_suffix("nightlightToggle"), "nightlight");
+        assert_eq!(nightlight_topic_suffix("Toggle"), "toggle");
+    }
+
+    #[test]
+    fn nightlight_light_state() {
+        let info = fixtures::device(fixtures::LIST_DEVICES_ISSUE4, "H7131");
+
+        let mut device = ServiceDevice::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+        assert_eq!(nightlight_state(&device, "nightlightToggle"), None);
+
+        let cap = |instance: &str, value: i64| DeviceCapabilityState {
+            kind: DeviceCapabilityKind::Property,
+            instance: instance.to_string(),
+            state: json!({"value": value}),
+        };
+        device.set_http_device_state(HttpDeviceState {
+            sku: device.sku.clone(),
+            device: device.id.clone(),
+            capabilities: vec![
+                cap("nightlightToggle", 1),
+                cap("brightness", 40),
+                cap("colorRgb", 0xff0000),
+                cap(NIGHTLIGHT_SCENE_INSTANCE, 2),
+            ],
+        });
+        k9::assert_equal!(
+            nightlight_state(&device, "nightlightToggle").unwrap(),
+            json!({
+                "state": "ON",
+                "brightness": 40,
+                "color_mode": "rgb",
+                "color": {"r": 255, "g": 0, "b": 0},
+                "effect": "Rainbow",
+            })
+        );
     }
 }
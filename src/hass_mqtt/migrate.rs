@@ -0,0 +1,165 @@
+use crate::cache::{has_marker, set_marker};
+use crate::hass_mqtt::energy::meter_readings_for_capability;
+use crate::hass_mqtt::instance::{EntityList, RemovedEntity};
+use crate::hass_mqtt::probe::probe_index;
+use crate::platform_api::{DeviceCapability, DeviceCapabilityKind};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_safe_id, topic_safe_string};
+use crate::service::state::StateHandle;
+
+/// The cache topic used to remember which migrations have been applied
+const MIGRATION_CACHE_TOPIC: &str = "unique-id-migrations";
+
+/// Describes entities whose unique_id changed between releases.
+/// When we encounter a migration that has not yet been applied, we
+/// remove the configs for the old unique_ids before publishing the
+/// new ones, so that hass doesn't keep a duplicate of each entity.
+///
+/// To rename an entity in a future release, add an entry here with
+/// a higher version, rather than editing an existing one.
+struct UniqueIdMigration {
+    version: u32,
+    name: &'static str,
+    integration: &'static str,
+    /// Returns the unique_ids that a prior release used for this device
+    old_unique_ids: fn(&ServiceDevice) -> Vec<String>,
+}
+
+impl UniqueIdMigration {
+    fn cache_key(&self) -> String {
+        format!("v{}-{}", self.version, self.name)
+    }
+}
+
+const UNIQUE_ID_MIGRATIONS: &[UniqueIdMigration] = &[
+    UniqueIdMigration {
+        version: 1,
+        name: "meter-sensors",
+        integration: "sensor",
+        old_unique_ids: |device| {
+            generic_sensor_ids(device, |cap| !meter_readings_for_capability(cap).is_empty())
+        },
+    },
+    UniqueIdMigration {
+        version: 1,
+        name: "probe-sensors",
+        integration: "sensor",
+        old_unique_ids: |device| {
+            generic_sensor_ids(device, |cap| probe_index(&cap.instance).is_some())
+        },
+    },
+];
+
+/// Before they had dedicated entities, property capabilities were
+/// all published as a generic CapabilitySensor
+fn generic_sensor_ids(
+    device: &ServiceDevice,
+    filter: impl Fn(&DeviceCapability) -> bool,
+) -> Vec<String> {
+    let Some(info) = &device.http_device_info else {
+        return vec![];
+    };
+    info.capabilities
+        .iter()
+        .filter(|cap| cap.kind == DeviceCapabilityKind::Property && filter(cap))
+        .map(|cap| {
+            format!(
+                "sensor-{id}-{inst}",
+                id = topic_safe_id(device),
+                inst = topic_safe_string(&cap.instance)
+            )
+        })
+        .collect()
+}
+
+/// Adds removals for the old unique_ids of any migrations that have
+/// not yet been applied. The migrations are recorded as pending in
+/// the state; see record_applied_migrations.
+pub async fn enumerate_unique_id_migrations(
+    state: &StateHandle,
+    devices: &[ServiceDevice],
+    entities: &mut EntityList,
+) {
+    let mut keys = vec![];
+
+    for migration in UNIQUE_ID_MIGRATIONS {
+        let key = migration.cache_key();
+        match has_marker(MIGRATION_CACHE_TOPIC, &key) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(err) => {
+                log::warn!("Unable to check unique_id migration {key}: {err:#}");
+                continue;
+            }
+        }
+
+        for device in devices {
+            for unique_id in (migration.old_unique_ids)(device) {
+                entities.add(RemovedEntity {
+                    integration: migration.integration,
+                    unique_id,
+                });
+            }
+        }
+        keys.push(key);
+    }
+
+    state.set_pending_migrations(keys).await;
+}
+
+/// Records that the pending migrations were applied. This must only
+/// be called once the removals have been published successfully, so
+/// that a failure to publish them is retried on the next startup.
+pub async fn record_applied_migrations(state: &StateHandle) {
+    for key in state.take_pending_migrations().await {
+        match set_marker(MIGRATION_CACHE_TOPIC, &key) {
+            Ok(()) => log::info!("Applied unique_id migration {key}"),
+            Err(err) => log::warn!("Unable to record unique_id migration {key}: {err:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::HttpDeviceInfo;
+    use std::collections::HashSet;
+
+    #[test]
+    fn migration_keys_are_unique() {
+        let keys: HashSet<String> = UNIQUE_ID_MIGRATIONS.iter().map(|m| m.cache_key()).collect();
+        k9::assert_equal!(keys.len(), UNIQUE_ID_MIGRATIONS.len());
+    }
+
+    #[test]
+    fn old_unique_ids() {
+        let info: HttpDeviceInfo = serde_json::from_value(serde_json::json!({
+            "sku": "H5183",
+            "device": "AA:BB:CC:DD:EE:FF:00:11",
+            "capabilities": [
+                {"type": "devices.capabilities.property", "instance": "probeTemperature1"},
+                {"type": "devices.capabilities.property", "instance": "sensorTemperature"},
+                {"type": "devices.capabilities.property", "instance": "electricity",
+                    "parameters": {"dataType": "STRUCT", "fields": [
+                        {"fieldName": "power", "dataType": "INTEGER",
+                            "range": {"min": 0, "max": 3600, "precision": 1}}
+                    ]}},
+            ]
+        }))
+        .unwrap();
+        let mut device = ServiceDevice::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+
+        let ids: Vec<Vec<String>> = UNIQUE_ID_MIGRATIONS
+            .iter()
+            .map(|m| (m.old_unique_ids)(&device))
+            .collect();
+        k9::assert_equal!(
+            ids,
+            vec![
+                vec!["sensor-AABBCCDDEEFF0011-electricity".to_string()],
+                vec!["sensor-AABBCCDDEEFF0011-probetemperature1".to_string()],
+            ]
+        );
+    }
+}
@@ -3,13 +3,25 @@ pub mod binary_sensor;
 pub mod button;
 pub mod climate;
 pub mod cover;
+pub mod energy;
 pub mod enumerator;
+pub mod event;
+pub mod fan;
 pub mod humidifier;
 pub mod instance;
 pub mod light;
+pub mod migrate;
+pub mod music;
 pub mod number;
-pub mod scene;
+pub mod precision;
+pub mod probe;
+pub mod purge;
+pub mod room;
 pub mod select;
 pub mod sensor;
+pub mod siren;
 pub mod switch;
+pub mod timer;
+pub mod update;
+pub mod valve;
 pub mod work_mode;
@@ -0,0 +1,360 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::number::NumberConfig;
+use crate::hass_mqtt::select::SelectConfig;
+use crate::platform_api::{DeviceCapability, DeviceParameters, IntegerRange};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, HassClient, IdParameter,
+};
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde_json::{json, Value as JsonValue};
+
+/// The platform API instance for music-reactive modes
+pub const MUSIC_MODE_INSTANCE: &str = "musicMode";
+const MUSIC_MODE_FIELD: &str = "musicMode";
+const SENSITIVITY_FIELD: &str = "sensitivity";
+const AUTO_COLOR_FIELD: &str = "autoColor";
+
+/// The prefix used for music modes in the list of scenes
+const MUSIC_SCENE_PREFIX: &str = "Music: ";
+
+fn music_mode_options(cap: &DeviceCapability) -> Vec<(String, JsonValue)> {
+    match cap
+        .struct_field_by_name(MUSIC_MODE_FIELD)
+        .map(|f| &f.field_type)
+    {
+        Some(DeviceParameters::Enum { options }) => options
+            .iter()
+            .map(|opt| (opt.name.to_string(), opt.value.clone()))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// The music mode and sensitivity that the device is currently using
+#[derive(Debug, Default, PartialEq)]
+struct MusicModeState {
+    mode: Option<String>,
+    sensitivity: Option<i64>,
+    auto_color: Option<JsonValue>,
+}
+
+/// Determines the current music mode. The platform API reports the
+/// control struct as the value of the capability state, but not
+/// all devices report it, so we fall back to the active scene, which
+/// tracks music modes that were activated via the light effect list.
+fn reported_music_mode(device: &ServiceDevice, cap: &DeviceCapability) -> MusicModeState {
+    let options = music_mode_options(cap);
+    let mut result = MusicModeState::default();
+
+    if let Some(value) = device
+        .get_state_capability_by_instance(MUSIC_MODE_INSTANCE)
+        .and_then(|cap| cap.state.pointer("/value"))
+    {
+        result.mode = value.get(MUSIC_MODE_FIELD).and_then(|mode| {
+            options
+                .iter()
+                .find(|(_, v)| v == mode)
+                .map(|(name, _)| name.to_string())
+        });
+        result.sensitivity = value.get(SENSITIVITY_FIELD).and_then(|v| v.as_i64());
+        result.auto_color = value.get(AUTO_COLOR_FIELD).cloned();
+    }
+
+    if result.mode.is_none() {
+        result.mode = device
+            .device_state()
+            .and_then(|s| s.scene)
+            .and_then(|scene| {
+                scene
+                    .strip_prefix(MUSIC_SCENE_PREFIX)
+                    .map(|s| s.to_string())
+            })
+            .filter(|mode| options.iter().any(|(name, _)| name == mode));
+    }
+
+    result
+}
+
+pub struct MusicModeSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl MusicModeSelect {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        cap: &DeviceCapability,
+    ) -> Option<Self> {
+        let options: Vec<String> = music_mode_options(cap)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        if options.is_empty() {
+            return None;
+        }
+
+        let id = topic_safe_id(device);
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Music Mode".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-music-mode"),
+                    entity_category: None,
+                    icon: Some("mdi:music".to_string()),
+                },
+                command_topic: format!(
+                    "{prefix}/select/{id}/set-music-mode",
+                    prefix = topic_prefix()
+                ),
+                state_topic: format!("{prefix}/select/{id}/music-mode", prefix = topic_prefix()),
+                options,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MusicModeSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(cap) = device.get_capability_by_instance(MUSIC_MODE_INSTANCE) else {
+            return Ok(());
+        };
+
+        if let Some(mode) = reported_music_mode(&device, cap).mode {
+            return client.publish(&self.select.state_topic, mode).await;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct MusicSensitivityNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl MusicSensitivityNumber {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        cap: &DeviceCapability,
+    ) -> Option<Self> {
+        let (min, max) = match &cap.struct_field_by_name(SENSITIVITY_FIELD)?.field_type {
+            DeviceParameters::Integer {
+                range: IntegerRange { min, max, .. },
+                ..
+            } => (*min as f32, *max as f32),
+            _ => (0., 100.),
+        };
+
+        let id = topic_safe_id(device);
+        Some(Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Music Sensitivity".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-music-sensitivity"),
+                    entity_category: None,
+                    icon: Some("mdi:microphone".to_string()),
+                },
+                command_topic: format!(
+                    "{prefix}/number/{id}/set-music-sensitivity",
+                    prefix = topic_prefix()
+                ),
+                state_topic: Some(format!(
+                    "{prefix}/number/{id}/music-sensitivity",
+                    prefix = topic_prefix()
+                )),
+                min: Some(min),
+                max: Some(max),
+                step: 1.0,
+                unit_of_measurement: Some("%"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MusicSensitivityNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(cap) = device.get_capability_by_instance(MUSIC_MODE_INSTANCE) else {
+            return Ok(());
+        };
+
+        if let Some(sensitivity) = reported_music_mode(&device, cap).sensitivity {
+            return self
+                .number
+                .notify_state(client, &sensitivity.to_string())
+                .await;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends the music mode control struct to the device, filling in
+/// whichever of mode and sensitivity was not specified from the
+/// current state. On success, the new mode is recorded as both the
+/// music mode state and the active scene, so that the music mode
+/// select and the light effect agree. On failure, the current state
+/// is re-published so that hass doesn't show a mode that the device
+/// rejected.
+async fn set_music_mode(
+    state: &StateHandle,
+    id: &str,
+    mode: Option<&str>,
+    sensitivity: Option<i64>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(id).await?;
+    let cap = device
+        .get_capability_by_instance(MUSIC_MODE_INSTANCE)
+        .ok_or_else(|| anyhow::anyhow!("{device} has no {MUSIC_MODE_INSTANCE}"))?;
+
+    let current = reported_music_mode(&device, cap);
+    let options = music_mode_options(cap);
+
+    let mode = mode
+        .map(|m| m.to_string())
+        .or(current.mode)
+        .or_else(|| options.first().map(|(name, _)| name.to_string()))
+        .ok_or_else(|| anyhow::anyhow!("{device} has no music modes"))?;
+    let mode_value = options
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(&mode))
+        .map(|(_, value)| value.clone())
+        .ok_or_else(|| anyhow::anyhow!("{mode} is not a valid music mode for {device}"))?;
+
+    let value = json!({
+        MUSIC_MODE_FIELD: mode_value,
+        SENSITIVITY_FIELD: sensitivity.or(current.sensitivity).unwrap_or(100),
+        AUTO_COLOR_FIELD: current.auto_color.unwrap_or(json!(1)),
+    });
+
+    if let Err(err) = state.device_control(&device, cap, value.clone()).await {
+        if let Err(notify_err) = state.notify_of_state_change(&device.id).await {
+            log::error!("notify_of_state_change for {device}: {notify_err:#}");
+        }
+        return Err(err).context("set_music_mode: state.device_control");
+    }
+
+    {
+        let mut device = state.device_mut(&device.sku, &device.id).await;
+        device.apply_capability_state(
+            cap.kind.clone(),
+            MUSIC_MODE_INSTANCE,
+            json!({"value": value}),
+        );
+        device.set_active_scene(Some(&format!("{MUSIC_SCENE_PREFIX}{mode}")));
+    }
+    state.notify_of_state_change(&device.id).await
+}
+
+pub async fn mqtt_set_music_mode(
+    Payload(mode): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_music_mode: {id}: {mode}");
+    set_music_mode(&state, &id, Some(&mode), None).await
+}
+
+pub async fn mqtt_set_music_sensitivity(
+    Payload(sensitivity): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_music_sensitivity: {id}: {sensitivity}");
+    let sensitivity: f64 = sensitivity
+        .parse()
+        .with_context(|| format!("parsing {sensitivity}"))?;
+    set_music_mode(&state, &id, None, Some(sensitivity.round() as i64)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{
+        fixtures, DeviceCapabilityKind, DeviceCapabilityState, HttpDeviceState,
+    };
+
+    #[test]
+    fn music_mode_state() {
+        let info = fixtures::device(fixtures::LIST_DEVICES_ISSUE4, "H6059");
+
+        let mut device = ServiceDevice::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+        let cap = device
+            .get_capability_by_instance(MUSIC_MODE_INSTANCE)
+            .unwrap()
+            .clone();
+
+        k9::assert_equal!(
+            music_mode_options(&cap),
+            vec![
+                ("Magic".to_string(), json!(1)),
+                ("Rhythm".to_string(), json!(2))
+            ]
+        );
+        k9::assert_equal!(
+            reported_music_mode(&device, &cap),
+            MusicModeState::default()
+        );
+
+        device.set_http_device_state(HttpDeviceState {
+            sku: device.sku.clone(),
+            device: device.id.clone(),
+            capabilities: vec![DeviceCapabilityState {
+                kind: DeviceCapabilityKind::MusicSetting,
+                instance: MUSIC_MODE_INSTANCE.to_string(),
+                state: json!({"value": {"musicMode": 2, "sensitivity": 40}}),
+            }],
+        });
+        k9::assert_equal!(
+            reported_music_mode(&device, &cap),
+            MusicModeState {
+                mode: Some("Rhythm".to_string()),
+                sensitivity: Some(40),
+                auto_color: None,
+            }
+        );
+    }
+}
@@ -1,9 +1,13 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::work_mode::{work_mode_param, ParsedWorkMode};
+use crate::platform_api::{DeviceCapability, DeviceParameters, IntegerRange};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, topic_safe_string, HassClient, IdAndInst,
+};
 use crate::service::state::StateHandle;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
 use mosquitto_rs::router::{Params, Payload, State};
 use serde::{Deserialize, Serialize};
@@ -62,7 +66,8 @@ impl WorkModeNumber {
         range: Option<Range<i64>>,
     ) -> Self {
         let command_topic = format!(
-            "gv2mqtt/number/{id}/command/{mode}/{mode_num}",
+            "{prefix}/number/{id}/command/{mode}/{mode_num}",
+            prefix = topic_prefix(),
             id = topic_safe_id(device),
             mode = topic_safe_string(mode_name),
             mode_num = work_mode
@@ -71,7 +76,8 @@ impl WorkModeNumber {
                 .unwrap_or_else(|| "work-mode-was-not-int".to_string()),
         );
         let state_topic = format!(
-            "gv2mqtt/number/{id}/state/{mode}",
+            "{prefix}/number/{id}/state/{mode}",
+            prefix = topic_prefix(),
             id = topic_safe_id(device),
             mode = topic_safe_string(mode_name)
         );
@@ -116,7 +122,7 @@ impl WorkModeNumber {
 #[async_trait]
 impl EntityInstance for WorkModeNumber {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.number.publish(&state, &client).await
+        self.number.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -132,28 +138,9 @@ impl EntityInstance for WorkModeNumber {
             .await
             .expect("device to exist");
 
-        if let Some(cap) = device.get_state_capability_by_instance("workMode") {
-            if let Some(work_mode) = cap.state.pointer("/value/workMode") {
-                if *work_mode == self.work_mode {
-                    // The current mode matches us, so it is valid to
-                    // read the current parameter for that mode
-
-                    if let Some(value) = cap.state.pointer("/value/modeValue") {
-                        if let Some(n) = value.as_i64() {
-                            client.publish(state_topic, n.to_string()).await?;
-                            return Ok(());
-                        }
-                    }
-                }
-            }
-        }
-
-        if let Some(work_mode) = self.work_mode.as_i64() {
-            // FIXME: assuming humidifier, rename that field?
-            if let Some(n) = device.humidifier_param_by_mode.get(&(work_mode as u8)) {
-                client.publish(state_topic, n.to_string()).await?;
-                return Ok(());
-            }
+        if let Some(n) = work_mode_param(&device, &self.work_mode) {
+            client.publish(state_topic, n.to_string()).await?;
+            return Ok(());
         }
 
         // We might get some data to report later, so this is just debug for now
@@ -185,7 +172,9 @@ pub async fn mqtt_number_command(
 ) -> anyhow::Result<()> {
     log::info!("{mode_name} for {id}: {value}");
     let work_mode: i64 = work_mode.parse()?;
-    let device = state.resolve_device_for_control(&id).await?;
+    let Some(device) = state.resolve_device_for_command(&id, &mode_name).await? else {
+        return Ok(());
+    };
 
     state
         .humidifier_set_parameter(&device, work_mode, value)
@@ -193,3 +182,450 @@ pub async fn mqtt_number_command(
 
     Ok(())
 }
+
+/// Returns true if the capability controls the target humidity of a
+/// humidifier or dehumidifier
+pub fn is_target_humidity_instance(instance: &str) -> bool {
+    matches!(instance, "humidity" | "targetHumidity")
+}
+
+pub struct TargetHumidityNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl TargetHumidityNumber {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Option<Self> {
+        let (min, max, step) = match &instance.parameters {
+            Some(DeviceParameters::Integer {
+                range:
+                    IntegerRange {
+                        min,
+                        max,
+                        precision,
+                    },
+                ..
+            }) => (*min, *max, (*precision).max(1)),
+            _ => return None,
+        };
+
+        let command_topic = format!(
+            "{prefix}/number/{id}/set-target-humidity/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = instance.instance
+        );
+        let state_topic = format!(
+            "{prefix}/number/{id}/target-humidity/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}-target-humidity",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Some(Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Target Humidity".to_string()),
+                    device_class: Some("humidity"),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: None,
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                min: Some(min as f32),
+                max: Some(max as f32),
+                step: step as f32,
+                unit_of_measurement: Some("%"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for TargetHumidityNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
+            if let Some(n) = cap.state.pointer("/value").and_then(|v| v.as_i64()) {
+                return self.number.notify_state(client, &n.to_string()).await;
+            }
+        }
+
+        if let Some(humidity) = device.target_humidity_percent {
+            return self
+                .number
+                .notify_state(client, &humidity.to_string())
+                .await;
+        }
+
+        log::trace!(
+            "TargetHumidityNumber::notify_state: didn't find state for {device} {instance}",
+            instance = self.instance_name
+        );
+        Ok(())
+    }
+}
+
+/// Returns the name of the currently active work mode, if known
+fn current_work_mode_name(device: &ServiceDevice) -> Option<String> {
+    let work_modes = ParsedWorkMode::with_device(device).ok()?;
+    let mode_value = match device.humidifier_work_mode {
+        Some(mode) => serde_json::json!(mode),
+        None => device
+            .get_state_capability_by_instance("workMode")?
+            .state
+            .pointer("/value/workMode")?
+            .clone(),
+    };
+    work_modes
+        .mode_for_value(&mode_value)
+        .map(|mode| mode.name.to_string())
+}
+
+pub async fn mqtt_set_target_humidity(
+    Payload(percent): Payload<i64>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_target_humidity: {id}: {instance} {percent}");
+    let Some(device) = state.resolve_device_for_command(&id, &instance).await? else {
+        return Ok(());
+    };
+
+    if let Some(mode) = current_work_mode_name(&device) {
+        if !mode.eq_ignore_ascii_case("auto") {
+            log::info!(
+                "{device} is in {mode} mode; it may ignore the new target \
+                 humidity {percent}% until Auto mode is selected"
+            );
+        }
+    }
+
+    let cap = device
+        .get_capability_by_instance(&instance)
+        .ok_or_else(|| anyhow!("{device} has no {instance}"))?;
+
+    state
+        .device_control(&device, cap, percent)
+        .await
+        .context("mqtt_set_target_humidity: state.device_control")?;
+
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .set_target_humidity(percent as u8);
+
+    Ok(())
+}
+
+/// Returns true if the capability controls the mist intensity of
+/// a diffuser or humidifier. Toggles such as warmMistToggle don't
+/// count; those are handled as switches.
+pub fn is_mist_level_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    instance.contains("mist") && !instance.ends_with("toggle")
+}
+
+pub struct MistLevelNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl MistLevelNumber {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Option<Self> {
+        let (min, max, step) = match &instance.parameters {
+            Some(DeviceParameters::Integer {
+                range:
+                    IntegerRange {
+                        min,
+                        max,
+                        precision,
+                    },
+                ..
+            }) => (*min, *max, (*precision).max(1)),
+            _ => return None,
+        };
+
+        let command_topic = format!(
+            "{prefix}/number/{id}/set-mist-level/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = instance.instance
+        );
+        let state_topic = format!(
+            "{prefix}/number/{id}/mist-level/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}-number",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Some(Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Mist Level".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:weather-fog".to_string()),
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                min: Some(min as f32),
+                max: Some(max as f32),
+                step: step as f32,
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MistLevelNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
+            if let Some(n) = cap.state.pointer("/value").and_then(|v| v.as_i64()) {
+                return self.number.notify_state(client, &n.to_string()).await;
+            }
+        }
+
+        log::trace!(
+            "MistLevelNumber::notify_state: didn't find state for {device} {instance}",
+            instance = self.instance_name
+        );
+        Ok(())
+    }
+}
+
+pub async fn mqtt_set_mist_level(
+    Payload(level): Payload<i64>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_mist_level: {id}: {instance} {level}");
+    let Some(device) = state.resolve_device_for_command(&id, &instance).await? else {
+        return Ok(());
+    };
+
+    let cap = device
+        .get_capability_by_instance(&instance)
+        .ok_or_else(|| anyhow!("{device} has no {instance}"))?;
+
+    state
+        .device_control(&device, cap, level)
+        .await
+        .context("mqtt_set_mist_level: state.device_control")?;
+
+    Ok(())
+}
+
+/// Returns true if the capability controls the brightness of the
+/// display or control panel of a device, rather than its light
+pub fn is_display_brightness_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    (instance.contains("display") || instance.contains("screen") || instance.contains("panel"))
+        && instance.contains("brightness")
+}
+
+/// The range of the display brightness. Some devices advertise the
+/// capability without a usable range; assume a percentage for those.
+fn display_brightness_range(instance: &DeviceCapability) -> (u32, u32, u32) {
+    match &instance.parameters {
+        Some(DeviceParameters::Integer {
+            range:
+                IntegerRange {
+                    min,
+                    max,
+                    precision,
+                },
+            ..
+        }) if min < max => (*min, *max, (*precision).max(1)),
+        _ => (0, 100, 1),
+    }
+}
+
+pub struct DisplayBrightnessNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl DisplayBrightnessNumber {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let (min, max, step) = display_brightness_range(instance);
+
+        let command_topic = format!(
+            "{prefix}/number/{id}/set-display-brightness/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = instance.instance
+        );
+        let state_topic = format!(
+            "{prefix}/number/{id}/display-brightness/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}-number",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Display Brightness".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:brightness-6".to_string()),
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                min: Some(min as f32),
+                max: Some(max as f32),
+                step: step as f32,
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DisplayBrightnessNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
+            if let Some(n) = cap.state.pointer("/value").and_then(|v| v.as_i64()) {
+                return self.number.notify_state(client, &n.to_string()).await;
+            }
+        }
+
+        log::trace!(
+            "DisplayBrightnessNumber::notify_state: didn't find state for {device} {instance}",
+            instance = self.instance_name
+        );
+        Ok(())
+    }
+}
+
+pub async fn mqtt_set_display_brightness(
+    Payload(level): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_display_brightness: {id}: {instance} {level}");
+    let Some(device) = state.resolve_device_for_command(&id, &instance).await? else {
+        return Ok(());
+    };
+
+    let cap = device
+        .get_capability_by_instance(&instance)
+        .ok_or_else(|| anyhow!("{device} has no {instance}"))?;
+
+    let level: f64 = level
+        .trim()
+        .parse()
+        .with_context(|| format!("parsing {level}"))?;
+    let (min, max, _) = display_brightness_range(cap);
+    let level = (level.round() as i64).clamp(min.into(), max.into());
+
+    state
+        .device_control(&device, cap, level)
+        .await
+        .context("mqtt_set_display_brightness: state.device_control")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_brightness() {
+        assert!(is_display_brightness_instance("displayBrightness"));
+        assert!(is_display_brightness_instance("screenBrightness"));
+        assert!(!is_display_brightness_instance("brightness"));
+        assert!(!is_display_brightness_instance("displayToggle"));
+
+        let cap = DeviceCapability {
+            kind: crate::platform_api::DeviceCapabilityKind::Range,
+            instance: "displayBrightness".to_string(),
+            parameters: None,
+            alarm_type: None,
+            event_state: None,
+        };
+        k9::assert_equal!(display_brightness_range(&cap), (0, 100, 1));
+    }
+}
@@ -0,0 +1,190 @@
+//! The number of decimal places to which the numeric sensors are
+//! rounded before they are published. Readings are passed through as
+//! floats from the JSON that the device reported, so without rounding
+//! a humidity of 54.3% may be published as 54.29999923706055.
+//!
+//! The defaults may be changed via `$GOVEE_HASS_SENSOR_PRECISION`, a
+//! comma separated list of `KIND=DECIMALS` entries, where `DECIMALS`
+//! may be `raw` to publish the reading without rounding. `raw` on its
+//! own turns off rounding for every kind of sensor.
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SensorPrecision {
+    Temperature,
+    Humidity,
+    Power,
+    Energy,
+}
+
+impl SensorPrecision {
+    const ALL: [Self; 4] = [Self::Temperature, Self::Humidity, Self::Power, Self::Energy];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::Humidity => "humidity",
+            Self::Power => "power",
+            Self::Energy => "energy",
+        }
+    }
+
+    fn default_decimals(self) -> u8 {
+        match self {
+            Self::Temperature | Self::Humidity | Self::Power => 1,
+            Self::Energy => 3,
+        }
+    }
+
+    /// Returns the number of decimal places for this kind of sensor,
+    /// or None if its readings are published without rounding
+    pub fn decimals(self) -> Option<u8> {
+        let list = match crate::opt_env_var::<String>("GOVEE_HASS_SENSOR_PRECISION") {
+            Ok(list) => list.unwrap_or_default(),
+            Err(err) => {
+                log::warn!("{err:#}");
+                String::new()
+            }
+        };
+        match parse_precision(&list, self) {
+            Ok(decimals) => decimals,
+            Err(err) => {
+                if WARNED.lock().insert(list.clone()) {
+                    log::warn!(
+                        "Ignoring GOVEE_HASS_SENSOR_PRECISION={list:?}: {err}. \
+                         Using the default precision"
+                    );
+                }
+                Some(self.default_decimals())
+            }
+        }
+    }
+
+    /// Formats a reading for publishing, rounded to the configured
+    /// number of decimal places
+    pub fn format(self, value: f64) -> String {
+        format_reading(value, self.decimals())
+    }
+
+    /// Rounds a reading to the configured number of decimal places
+    pub fn round(self, value: f64) -> f64 {
+        round_reading(value, self.decimals())
+    }
+}
+
+/// The invalid lists that we have already warned about, so that
+/// the warning isn't repeated for every reading
+static WARNED: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Returns the precision of `kind` from a list such as
+/// `temperature=2,energy=raw`
+fn parse_precision(list: &str, kind: SensorPrecision) -> anyhow::Result<Option<u8>> {
+    let mut decimals = Some(kind.default_decimals());
+    for entry in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        if entry.eq_ignore_ascii_case("raw") {
+            decimals = None;
+            continue;
+        }
+        let (name, value) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("`{entry}` is not of the form KIND=DECIMALS"))?;
+        let name = name.trim();
+        let entry_kind = SensorPrecision::ALL
+            .into_iter()
+            .find(|k| k.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "`{name}` is not a kind of sensor; use temperature, humidity, power or energy"
+                )
+            })?;
+        let value = value.trim();
+        let value = if value.eq_ignore_ascii_case("raw") {
+            None
+        } else {
+            Some(
+                value
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|d| *d <= 6)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "`{value}` must be a number of decimals from 0 to 6, or raw"
+                        )
+                    })?,
+            )
+        };
+        if entry_kind == kind {
+            decimals = value;
+        }
+    }
+    Ok(decimals)
+}
+
+/// Rounds `value` to `decimals` places. Whole numbers are formatted
+/// without a fractional part, so that integer readings don't gain
+/// a `.0`.
+pub fn format_reading(value: f64, decimals: Option<u8>) -> String {
+    round_reading(value, decimals).to_string()
+}
+
+/// Rounds `value` to `decimals` places, or returns it as-is if
+/// `decimals` is None
+pub fn round_reading(value: f64, decimals: Option<u8>) -> f64 {
+    match decimals {
+        Some(decimals) => {
+            let scale = 10f64.powi(decimals.into());
+            let rounded = (value * scale).round() / scale;
+            // Avoid publishing -0 for small negative readings
+            if rounded == 0. {
+                0.
+            } else {
+                rounded
+            }
+        }
+        None => value,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rounding() {
+        k9::assert_equal!(format_reading(54.29999923706055, Some(1)), "54.3");
+        k9::assert_equal!(format_reading(54.29999923706055, None), "54.29999923706055");
+        k9::assert_equal!(format_reading(12.0, Some(1)), "12");
+        k9::assert_equal!(format_reading(3.25, Some(3)), "3.25");
+        k9::assert_equal!(format_reading(71.96, Some(1)), "72");
+        k9::assert_equal!(format_reading(-0.01, Some(1)), "0");
+        k9::assert_equal!(format_reading(7., None), "7");
+    }
+
+    #[test]
+    fn precision_list() {
+        use SensorPrecision::*;
+        k9::assert_equal!(parse_precision("", Energy).unwrap(), Some(3));
+        k9::assert_equal!(
+            parse_precision("temperature=2", Temperature).unwrap(),
+            Some(2)
+        );
+        k9::assert_equal!(parse_precision("temperature=2", Humidity).unwrap(), Some(1));
+        k9::assert_equal!(parse_precision("Humidity=raw", Humidity).unwrap(), None);
+        k9::assert_equal!(parse_precision("raw", Power).unwrap(), None);
+        k9::assert_equal!(parse_precision("raw,power=0", Power).unwrap(), Some(0));
+        k9::assert_equal!(
+            parse_precision("pressure=1", Power)
+                .unwrap_err()
+                .to_string(),
+            "`pressure` is not a kind of sensor; use temperature, humidity, power or energy"
+        );
+        k9::assert_equal!(
+            parse_precision("energy=lots", Power)
+                .unwrap_err()
+                .to_string(),
+            "`lots` must be a number of decimals from 0 to 6, or raw"
+        );
+    }
+}
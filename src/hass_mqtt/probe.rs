@@ -0,0 +1,408 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::number::NumberConfig;
+use crate::hass_mqtt::precision::SensorPrecision;
+use crate::hass_mqtt::sensor::{SensorConfig, StateClass};
+use crate::platform_api::{DeviceCapability, DeviceParameters, IntegerRange};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{topic_prefix, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::state::StateHandle;
+use crate::temperature::{
+    TemperatureScale, TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::str::FromStr;
+
+// The multi-probe grill thermometers, such as the H5182 and H5183,
+// report each probe as its own pair of capabilities: the current
+// temperature and the target (alarm) temperature, with the probe
+// number embedded in the instance name, eg: probeTemperature1,
+// probeTargetTemperature2.
+
+/// Returns the 1-based probe number for a per-probe capability
+pub fn probe_index(instance: &str) -> Option<u8> {
+    if !instance.to_ascii_lowercase().contains("probe") {
+        return None;
+    }
+    let digits: String = instance
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+/// Returns true if the per-probe capability is the target alarm
+/// temperature rather than the current temperature
+pub fn is_probe_target_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    instance.contains("target") || instance.contains("alarm")
+}
+
+/// The sentinels that unplugged probes report rather than a
+/// temperature; -1, or 0xFFFF, which may have been scaled down by 100
+const UNPLUGGED_READINGS: [f64; 3] = [-1., 65535., 655.35];
+
+fn is_unplugged_reading(value: f64) -> bool {
+    UNPLUGGED_READINGS.contains(&value)
+}
+
+/// Determines the units that the device uses for the probe
+/// capability, falling back to the quirk for the sku
+fn probe_units(device: &ServiceDevice, cap: &DeviceCapability) -> TemperatureUnits {
+    let unit = match &cap.parameters {
+        Some(DeviceParameters::Integer { unit, .. }) => unit.as_deref(),
+        _ => None,
+    };
+    unit.and_then(|u| TemperatureScale::from_str(u.trim_start_matches("unit.")).ok())
+        .map(Into::into)
+        .or_else(|| {
+            device
+                .resolve_quirk()
+                .and_then(|q| q.platform_temperature_sensor_units)
+        })
+        .unwrap_or(TemperatureUnits::Fahrenheit)
+}
+
+/// Extracts the probe temperature from the reported state, which
+/// is either a bare number or a struct with a temperature field.
+/// Returns Some(None) if the probe is unplugged.
+fn parse_probe_reading(device: &ServiceDevice, instance: &str) -> Option<Option<TemperatureValue>> {
+    let cap = device.get_capability_by_instance(instance)?;
+    let value = device
+        .get_state_capability_by_instance(instance)?
+        .state
+        .pointer("/value")?
+        .clone();
+    let value = match &value {
+        JsonValue::Object(map) => map.get("temperature")?.as_f64()?,
+        v => v.as_f64()?,
+    };
+    if is_unplugged_reading(value) {
+        return Some(None);
+    }
+    Some(Some(TemperatureValue::new(value, probe_units(device, cap))))
+}
+
+fn probe_availability_topic(unique_id: &str) -> String {
+    format!(
+        "{prefix}/probe/{unique_id}/availability",
+        prefix = topic_prefix()
+    )
+}
+
+/// Publishes availability for the probe entity; unplugged probes
+/// are reported as unavailable rather than publishing the sentinel
+async fn publish_probe_availability(
+    client: &HassClient,
+    topic: &str,
+    available: bool,
+) -> anyhow::Result<()> {
+    client
+        .publish_transient(topic, if available { "online" } else { "offline" })
+        .await
+}
+
+fn probe_entity_config(
+    device: &ServiceDevice,
+    instance: &DeviceCapability,
+    unique_id: String,
+    name: String,
+) -> EntityConfig {
+    EntityConfig {
+        // Each probe has its own availability, so that an unplugged
+        // probe can show as unavailable while others continue to report
+        availability_topic: probe_availability_topic(&unique_id),
+        name: Some(name),
+        entity_category: None,
+        origin: Origin::default(),
+        device: Device::for_device(device),
+        unique_id,
+        device_class: Some(DEVICE_CLASS_TEMPERATURE),
+        icon: Some(
+            if is_probe_target_instance(&instance.instance) {
+                "mdi:thermometer-alert"
+            } else {
+                "mdi:thermometer-probe"
+            }
+            .to_string(),
+        ),
+    }
+}
+
+pub struct ProbeTemperatureSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl ProbeTemperatureSensor {
+    pub async fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+        probe: u8,
+    ) -> Self {
+        let unique_id = format!("sensor-{id}-probe{probe}", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: probe_entity_config(
+                    device,
+                    instance,
+                    unique_id.clone(),
+                    format!("Probe {probe}"),
+                ),
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some(
+                    state.get_temperature_scale().await.unit_of_measurement(),
+                ),
+                suggested_display_precision: SensorPrecision::Temperature.decimals(),
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ProbeTemperatureSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(reading) = parse_probe_reading(&device, &self.instance_name) else {
+            return Ok(());
+        };
+
+        publish_probe_availability(
+            client,
+            &self.sensor.base.availability_topic,
+            reading.is_some(),
+        )
+        .await?;
+
+        if let Some(reading) = reading {
+            let value = reading
+                .as_unit(self.state.get_temperature_scale().await.into())
+                .value();
+            self.sensor
+                .notify_state(client, &SensorPrecision::Temperature.format(value))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ProbeTargetTemperatureNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl ProbeTargetTemperatureNumber {
+    pub async fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+        probe: u8,
+    ) -> Self {
+        let id = topic_safe_id(device);
+        let units = state.get_temperature_scale().await;
+        let unique_id = format!("gv2mqtt-{id}-probe{probe}-target");
+
+        let (min, max) = match &instance.parameters {
+            Some(DeviceParameters::Integer {
+                range: IntegerRange { min, max, .. },
+                ..
+            }) => {
+                let device_units = probe_units(device, instance);
+                let min = TemperatureValue::new((*min).into(), device_units).as_unit(units.into());
+                let max = TemperatureValue::new((*max).into(), device_units).as_unit(units.into());
+                (
+                    Some(min.value().floor() as f32),
+                    Some(max.value().ceil() as f32),
+                )
+            }
+            _ => (None, None),
+        };
+
+        Self {
+            number: NumberConfig {
+                base: probe_entity_config(
+                    device,
+                    instance,
+                    unique_id.clone(),
+                    format!("Probe {probe} Target"),
+                ),
+                command_topic: format!(
+                    "{prefix}/number/{id}/set-probe-target/{inst}/{units}",
+                    prefix = topic_prefix(),
+                    inst = topic_safe_string(&instance.instance)
+                ),
+                state_topic: Some(format!(
+                    "{prefix}/number/{unique_id}/state",
+                    prefix = topic_prefix()
+                )),
+                min,
+                max,
+                step: 1.0,
+                unit_of_measurement: Some(units.unit_of_measurement()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ProbeTargetTemperatureNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(reading) = parse_probe_reading(&device, &self.instance_name) else {
+            return Ok(());
+        };
+
+        publish_probe_availability(
+            client,
+            &self.number.base.availability_topic,
+            reading.is_some(),
+        )
+        .await?;
+
+        if let Some(reading) = reading {
+            let value = reading
+                .as_unit(self.state.get_temperature_scale().await.into())
+                .value();
+            self.number
+                .notify_state(client, &format!("{value:.0}"))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProbeTargetParams {
+    id: String,
+    instance: String,
+    units: String,
+}
+
+pub async fn mqtt_set_probe_target(
+    Payload(value): Payload<String>,
+    Params(ProbeTargetParams {
+        id,
+        instance,
+        units,
+    }): Params<ProbeTargetParams>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{id}: set probe target {instance} to {value}{units}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let cap = device
+        .get_capability_by_instance(&instance)
+        .ok_or_else(|| anyhow::anyhow!("device has no {instance}"))?;
+
+    let scale = TemperatureScale::from_str(&units)?;
+    let value: f64 = value
+        .trim()
+        .parse()
+        .with_context(|| format!("parsing {value}"))?;
+    let target = TemperatureValue::new(value, scale.into())
+        .as_unit(probe_units(&device, cap))
+        .value()
+        .round() as i64;
+
+    let target = match &cap.parameters {
+        Some(DeviceParameters::Integer {
+            range: IntegerRange { min, max, .. },
+            ..
+        }) => target.clamp((*min).into(), (*max).into()),
+        _ => target,
+    };
+
+    state
+        .device_control(&device, cap, target)
+        .await
+        .context("mqtt_set_probe_target: state.device_control")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn probe_instances() {
+        k9::assert_equal!(probe_index("probeTemperature1"), Some(1));
+        k9::assert_equal!(probe_index("probe2TargetTemperature"), Some(2));
+        k9::assert_equal!(probe_index("sensorTemperature"), None);
+        k9::assert_equal!(probe_index("probeTemperature"), None);
+        assert!(is_probe_target_instance("probeTargetTemperature3"));
+        assert!(is_probe_target_instance("probeAlarmTemperature3"));
+        assert!(!is_probe_target_instance("probeTemperature3"));
+    }
+
+    #[test]
+    fn unplugged() {
+        assert!(is_unplugged_reading(-1.));
+        assert!(is_unplugged_reading(65535.));
+        assert!(is_unplugged_reading(655.35));
+        assert!(!is_unplugged_reading(0.));
+        assert!(!is_unplugged_reading(250.));
+        // Only the sentinels themselves mean that the probe is unplugged
+        assert!(!is_unplugged_reading(700.));
+        assert!(!is_unplugged_reading(-2.));
+    }
+
+    #[test]
+    fn availability() {
+        let device = ServiceDevice::new("H5183", "AA:BB:CC:DD:EE:FF:42:2A");
+        let cap = DeviceCapability {
+            kind: crate::platform_api::DeviceCapabilityKind::Property,
+            instance: "probeTemperature1".to_string(),
+            parameters: None,
+            alarm_type: None,
+            event_state: None,
+        };
+        let unique_id = "sensor-AABBCCDDEEFF422A-probe1".to_string();
+        let config = probe_entity_config(&device, &cap, unique_id.clone(), "Probe 1".to_string());
+
+        // Each probe has its own availability, but is still marked
+        // unavailable by the last-will on the global topic
+        let topics = config.availability_topics();
+        assert!(topics.contains(&probe_availability_topic(&unique_id)));
+        assert!(topics.contains(&crate::service::hass::availability_topic()));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::hass_mqtt::base::Origin;
+use crate::service::hass::availability_topic;
+use crate::service::state::StateHandle;
+use mosquitto_rs::router::{Payload, State};
+use serde::Deserialize;
+use tokio::time::Duration;
+
+/// How long to wait for the broker to deliver the retained discovery
+/// configs after we subscribe to them
+const RETAINED_SCAN_DURATION: Duration = Duration::from_secs(5);
+
+/// Returns true if the topic is a hass discovery config topic,
+/// either `<prefix>/<component>/<object_id>/config` or the form
+/// that includes a node_id
+pub fn is_discovery_config_topic(disco_prefix: &str, topic: &str) -> bool {
+    let Some(rest) = topic
+        .strip_prefix(disco_prefix)
+        .and_then(|rest| rest.strip_prefix('/'))
+    else {
+        return false;
+    };
+    let segments = rest.split('/').count();
+    rest.ends_with("/config") && (segments == 3 || segments == 4)
+}
+
+#[derive(Deserialize)]
+struct DiscoveryOrigin {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct AvailabilityEntry {
+    topic: String,
+}
+
+#[derive(Deserialize)]
+struct DiscoveryConfig {
+    origin: Option<DiscoveryOrigin>,
+    availability_topic: Option<String>,
+    #[serde(default)]
+    availability: Vec<AvailabilityEntry>,
+}
+
+/// Returns true if the retained discovery config was published by
+/// this instance: it must have our origin, and it must be gated on our
+/// availability topic, which distinguishes us from other instances
+/// that share the broker.
+fn is_our_discovery_config(payload: &[u8], availability_topic: &str) -> bool {
+    let Ok(config) = serde_json::from_slice::<DiscoveryConfig>(payload) else {
+        return false;
+    };
+    let Some(origin) = config.origin else {
+        return false;
+    };
+    if origin.name != Origin::default().name {
+        return false;
+    }
+    config.availability_topic.as_deref() == Some(availability_topic)
+        || config
+            .availability
+            .iter()
+            .any(|entry| entry.topic == availability_topic)
+}
+
+/// Someone requested that we remove retained discovery configs that
+/// we no longer publish. A payload of dry-run will only log what
+/// would be removed.
+pub async fn mqtt_purge_discovery(
+    Payload(mode): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let dry_run = mode.trim().eq_ignore_ascii_case("dry-run");
+    log::info!("mqtt_purge_discovery: dry_run={dry_run}");
+
+    let client = state
+        .get_hass_client()
+        .await
+        .expect("hass client to be present");
+    let disco = state.get_hass_disco_prefix().await;
+
+    state.begin_discovery_scan().await;
+    let patterns = [
+        format!("{disco}/+/+/config"),
+        format!("{disco}/+/+/+/config"),
+    ];
+    for pattern in &patterns {
+        client.subscribe(pattern).await?;
+    }
+    tokio::time::sleep(RETAINED_SCAN_DURATION).await;
+    for pattern in &patterns {
+        client.unsubscribe(pattern).await?;
+    }
+    let retained = state.end_discovery_scan().await;
+
+    let published = state.published_discovery_topics().await;
+    let availability = availability_topic();
+    let mut num_stale = 0;
+
+    for (topic, payload) in retained {
+        if published.contains(&topic) || !is_our_discovery_config(&payload, &availability) {
+            continue;
+        }
+        num_stale += 1;
+        if dry_run {
+            log::info!("mqtt_purge_discovery: would remove {topic}");
+        } else {
+            log::info!("mqtt_purge_discovery: removing {topic}");
+            client.publish_retained(&topic, "").await?;
+        }
+    }
+
+    log::info!(
+        "mqtt_purge_discovery: {num_stale} stale configs {}",
+        if dry_run { "found" } else { "removed" }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn discovery_topics() {
+        assert!(is_discovery_config_topic(
+            "homeassistant",
+            "homeassistant/sensor/sensor-H5179_AABB-temperature/config"
+        ));
+        assert!(is_discovery_config_topic(
+            "homeassistant",
+            "homeassistant/sensor/node/object/config"
+        ));
+        assert!(!is_discovery_config_topic(
+            "homeassistant",
+            "homeassistant/status"
+        ));
+        assert!(!is_discovery_config_topic(
+            "homeassistant",
+            "gv2mqtt/sensor/abc/config"
+        ));
+    }
+
+    #[test]
+    fn ours() {
+        let avail = "gv2mqtt/availability";
+        assert!(is_our_discovery_config(
+            br#"{"origin":{"name":"gv2mqtt","sw_version":"1","url":""},
+                "availability_topic":"gv2mqtt/availability"}"#,
+            avail
+        ));
+        assert!(is_our_discovery_config(
+            br#"{"origin":{"name":"gv2mqtt","sw_version":"1","url":""},
+                "availability":[{"topic":"gv2mqtt/availability"},
+                    {"topic":"gv2mqtt/availability/H6000_422A"}]}"#,
+            avail
+        ));
+        // Another instance sharing the broker
+        assert!(!is_our_discovery_config(
+            br#"{"origin":{"name":"gv2mqtt","sw_version":"1","url":""},
+                "availability_topic":"testing/availability"}"#,
+            avail
+        ));
+        // Another integration
+        assert!(!is_our_discovery_config(
+            br#"{"origin":{"name":"zigbee2mqtt","sw_version":"1","url":""},
+                "availability_topic":"gv2mqtt/availability"}"#,
+            avail
+        ));
+        assert!(!is_our_discovery_config(
+            br#"{"availability_topic":"gv2mqtt/availability"}"#,
+            avail
+        ));
+        assert!(!is_our_discovery_config(b"", avail));
+    }
+}
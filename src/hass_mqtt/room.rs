@@ -0,0 +1,221 @@
+//! The rooms that devices are arranged into in the Govee app, which
+//! the undocumented API reports as the groups of the device list, are
+//! exposed as a light, under a synthetic device, when they contain
+//! two or more lights. Commands for the room light are applied to
+//! each of its lights, and its state is the combined state of those
+//! lights. These are not the light groups of the Govee app, which
+//! the device list doesn't include.
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::light::LightConfig;
+use crate::service::device::{Device as ServiceDevice, DeviceState};
+use crate::service::hass::{availability_topic, kelvin_to_mired, topic_prefix, HassClient};
+use crate::service::state::{DeviceGroup, StateHandle};
+use async_trait::async_trait;
+use serde_json::{json, Value as JsonValue};
+
+pub fn room_light_unique_id(room_id: u64) -> String {
+    format!("gv2mqtt-room-{room_id}")
+}
+
+pub fn room_light_command_topic(room_id: u64) -> String {
+    format!("{prefix}/room/{room_id}/command", prefix = topic_prefix())
+}
+
+fn room_light_state_topic(room_id: u64) -> String {
+    format!("{prefix}/room/{room_id}/state", prefix = topic_prefix())
+}
+
+/// Returns true if the room lights were enabled via
+/// GOVEE_HASS_ROOM_LIGHTS
+pub fn room_lights_enabled() -> bool {
+    match crate::opt_env_var::<String>("GOVEE_HASS_ROOM_LIGHTS") {
+        Ok(Some(v)) => crate::lan_api::truthy(&v).unwrap_or(false),
+        _ => false,
+    }
+}
+
+pub struct RoomLight {
+    light: LightConfig,
+    room: DeviceGroup,
+    state: StateHandle,
+}
+
+impl RoomLight {
+    pub fn new(room: &DeviceGroup, lights: &[ServiceDevice], state: &StateHandle) -> Self {
+        let mut supported_color_modes = vec![];
+        if lights.iter().any(|light| light.supports_rgb()) {
+            supported_color_modes.push("rgb".to_string());
+        }
+
+        // Only the range that all of the lights support is offered
+        let kelvin_range = lights
+            .iter()
+            .map(|light| light.get_color_temperature_range())
+            .collect::<Option<Vec<_>>>()
+            .and_then(|ranges| {
+                ranges
+                    .into_iter()
+                    .reduce(|(min, max), (light_min, light_max)| {
+                        (min.max(light_min), max.min(light_max))
+                    })
+            });
+        let (min_mireds, max_mireds) = match kelvin_range {
+            Some((min, max)) if min < max => {
+                supported_color_modes.push("color_temp".to_string());
+                // Note that min and max are swapped by the translation
+                // from kelvin to mired
+                (Some(kelvin_to_mired(max)), Some(kelvin_to_mired(min)))
+            }
+            _ => (None, None),
+        };
+        if supported_color_modes.is_empty() {
+            supported_color_modes.push("brightness".to_string());
+        }
+
+        Self {
+            light: LightConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: None,
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_room(room),
+                    unique_id: room_light_unique_id(room.id),
+                    entity_category: None,
+                    icon: Some("mdi:lightbulb-group".to_string()),
+                },
+                schema: "json".to_string(),
+                command_topic: room_light_command_topic(room.id),
+                state_topic: room_light_state_topic(room.id),
+                supported_color_modes,
+                brightness: true,
+                brightness_scale: 100,
+                effect: false,
+                effect_list: vec![],
+                payload_available: "online".to_string(),
+                max_mireds,
+                min_mireds,
+                optimistic: false,
+                icon: None,
+                json_attributes_topic: None,
+            },
+            room: room.clone(),
+            state: state.clone(),
+        }
+    }
+
+    pub fn unique_id(&self) -> &str {
+        &self.light.base.unique_id
+    }
+}
+
+#[async_trait]
+impl EntityInstance for RoomLight {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.light.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let mut states = vec![];
+        for id in &self.room.members {
+            if let Some(device) = self.state.device_by_id(id).await {
+                if let Some(state) = device.device_state() {
+                    states.push(state);
+                }
+            }
+        }
+        client
+            .publish_obj(&self.light.state_topic, aggregate_light_state(&states))
+            .await
+    }
+}
+
+/// Combines the states of the lights in a room: the room light is on if
+/// any of them is on, with the mean brightness of those that are on,
+/// and the color of the first of them
+fn aggregate_light_state(states: &[DeviceState]) -> JsonValue {
+    let on: Vec<&DeviceState> = states
+        .iter()
+        .filter(|state| state.light_on.unwrap_or(false))
+        .collect();
+    let Some(first) = on.first() else {
+        return json!({"state": "OFF"});
+    };
+    let brightness = (on.iter().map(|state| state.brightness as f64).sum::<f64>() / on.len() as f64)
+        .round() as u8;
+
+    if first.kelvin == 0 {
+        json!({
+            "state": "ON",
+            "color_mode": "rgb",
+            "color": {
+                "r": first.color.r,
+                "g": first.color.g,
+                "b": first.color.b,
+            },
+            "brightness": brightness,
+        })
+    } else {
+        json!({
+            "state": "ON",
+            "color_mode": "color_temp",
+            "brightness": brightness,
+            "color_temp": kelvin_to_mired(first.kelvin),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lan_api::DeviceColor;
+    use chrono::Utc;
+
+    fn light(on: bool, brightness: u8, kelvin: u32) -> DeviceState {
+        DeviceState {
+            on,
+            light_on: Some(on),
+            online: None,
+            kelvin,
+            color: DeviceColor {
+                r: 10,
+                g: 20,
+                b: 30,
+            },
+            brightness,
+            scene: None,
+            source: "LAN API",
+            updated: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn aggregate_state() {
+        k9::assert_equal!(
+            aggregate_light_state(&[light(false, 80, 0), light(false, 20, 0)]),
+            json!({"state": "OFF"})
+        );
+        k9::assert_equal!(aggregate_light_state(&[]), json!({"state": "OFF"}));
+
+        // The brightness of a light that is off is ignored
+        k9::assert_equal!(
+            aggregate_light_state(&[light(false, 90, 0), light(true, 40, 0), light(true, 61, 0)]),
+            json!({
+                "state": "ON",
+                "color_mode": "rgb",
+                "color": {"r": 10, "g": 20, "b": 30},
+                "brightness": 51,
+            })
+        );
+        k9::assert_equal!(
+            aggregate_light_state(&[light(true, 50, 4000), light(true, 50, 0)]),
+            json!({
+                "state": "ON",
+                "color_mode": "color_temp",
+                "brightness": 50,
+                "color_temp": 250,
+            })
+        );
+    }
+}
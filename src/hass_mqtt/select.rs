@@ -1,14 +1,23 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
-use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::hass_mqtt::light::NIGHTLIGHT_SCENE_INSTANCE;
+use crate::hass_mqtt::switch::is_boolean_capability;
+use crate::hass_mqtt::work_mode::{
+    mist_level_presets, nearest_mist_preset, work_mode_param, MistPreset, ParsedWorkMode,
+    UNKNOWN_WORK_MODE,
+};
+use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceParameters, DeviceType};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, topic_safe_string, HassClient, IdAndInst,
+    IdParameter,
+};
 use crate::service::state::StateHandle;
 use anyhow::Context;
 use axum::async_trait;
 use mosquitto_rs::router::{Params, Payload, State};
 use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value as JsonValue};
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SelectConfig {
@@ -34,8 +43,16 @@ pub struct WorkModeSelect {
 
 impl WorkModeSelect {
     pub fn new(device: &ServiceDevice, work_modes: &ParsedWorkMode, state: &StateHandle) -> Self {
-        let command_topic = format!("gv2mqtt/{id}/set-work-mode", id = topic_safe_id(device),);
-        let state_topic = format!("gv2mqtt/{id}/notify-work-mode", id = topic_safe_id(device));
+        let command_topic = format!(
+            "{prefix}/{id}/set-work-mode",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+        );
+        let state_topic = format!(
+            "{prefix}/{id}/notify-work-mode",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device)
+        );
         let availability_topic = availability_topic();
         let unique_id = format!("gv2mqtt-{id}-workMode", id = topic_safe_id(device),);
 
@@ -53,7 +70,11 @@ impl WorkModeSelect {
                 },
                 command_topic,
                 state_topic,
-                options: work_modes.get_mode_names(),
+                options: work_modes
+                    .get_mode_names()
+                    .into_iter()
+                    .chain([UNKNOWN_WORK_MODE.to_string()])
+                    .collect(),
             },
             device_id: device.id.to_string(),
             state: state.clone(),
@@ -64,7 +85,7 @@ impl WorkModeSelect {
 #[async_trait]
 impl EntityInstance for WorkModeSelect {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.select.publish(&state, &client).await
+        self.select.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -76,23 +97,29 @@ impl EntityInstance for WorkModeSelect {
 
         if let Some(mode_value) = device.humidifier_work_mode {
             if let Ok(work_mode) = ParsedWorkMode::with_device(&device) {
-                let mode_value_json = json!(mode_value);
-                if let Some(mode) = work_mode.mode_for_value(&mode_value_json) {
-                    client
-                        .publish(&self.select.state_topic, mode.name.to_string())
-                        .await?;
-                }
+                let param = device
+                    .humidifier_param_by_mode
+                    .get(&mode_value)
+                    .map(|param| json!(param));
+                client
+                    .publish(
+                        &self.select.state_topic,
+                        work_mode.name_for_state(&json!(mode_value), param.as_ref()),
+                    )
+                    .await?;
             }
         } else {
             let work_modes = ParsedWorkMode::with_device(&device)?;
 
             if let Some(cap) = device.get_state_capability_by_instance("workMode") {
                 if let Some(mode_num) = cap.state.pointer("/value/workMode") {
-                    if let Some(mode) = work_modes.mode_for_value(mode_num) {
-                        return client
-                            .publish(&self.select.state_topic, mode.name.to_string())
-                            .await;
-                    }
+                    return client
+                        .publish(
+                            &self.select.state_topic,
+                            work_modes
+                                .name_for_state(mode_num, cap.state.pointer("/value/modeValue")),
+                        )
+                        .await;
                 }
             }
         }
@@ -100,6 +127,24 @@ impl EntityInstance for WorkModeSelect {
     }
 }
 
+/// The scene select option that is shown while no scene is active,
+/// such as after the color was changed
+pub const NO_SCENE_OPTION: &str = "None";
+
+/// Computes the options of the scene select. A reported scene that
+/// isn't in the scene list is included, so that hass accepts it as
+/// the state of the select.
+fn scene_select_options(scenes: Vec<String>, active: Option<String>) -> Vec<String> {
+    let mut options = vec![NO_SCENE_OPTION.to_string()];
+    options.extend(scenes.into_iter().filter(|s| !s.is_empty()));
+    if let Some(active) = active {
+        if !options.contains(&active) {
+            options.push(active);
+        }
+    }
+    options
+}
+
 pub struct SceneModeSelect {
     select: SelectConfig,
     device_id: String,
@@ -113,8 +158,16 @@ impl SceneModeSelect {
             return Ok(None);
         }
 
-        let command_topic = format!("gv2mqtt/{id}/set-mode-scene", id = topic_safe_id(device));
-        let state_topic = format!("gv2mqtt/{id}/notify-mode-scene", id = topic_safe_id(device));
+        let command_topic = format!(
+            "{prefix}/{id}/set-mode-scene",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{prefix}/{id}/notify-mode-scene",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device)
+        );
         let availability_topic = availability_topic();
         let unique_id = format!("gv2mqtt-{id}-mode-scene", id = topic_safe_id(device));
 
@@ -132,7 +185,10 @@ impl SceneModeSelect {
                 },
                 command_topic,
                 state_topic,
-                options: scenes,
+                options: scene_select_options(
+                    scenes,
+                    device.device_state().and_then(|state| state.scene),
+                ),
             },
             device_id: device.id.to_string(),
             state: state.clone(),
@@ -143,7 +199,7 @@ impl SceneModeSelect {
 #[async_trait]
 impl EntityInstance for SceneModeSelect {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.select.publish(&state, &client).await
+        self.select.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -157,7 +213,7 @@ impl EntityInstance for SceneModeSelect {
             client
                 .publish(
                     &self.select.state_topic,
-                    device_state.scene.as_deref().unwrap_or(""),
+                    device_state.scene.as_deref().unwrap_or(NO_SCENE_OPTION),
                 )
                 .await?;
         }
@@ -173,6 +229,13 @@ pub async fn mqtt_set_mode_scene(
 ) -> anyhow::Result<()> {
     let device = state.resolve_device_for_control(&id).await?;
 
+    if scene == NO_SCENE_OPTION {
+        // There is no command to stop a scene; pick a color instead.
+        // Re-publish the state so that the select shows the scene
+        // that is still active.
+        return state.notify_of_state_change(&device.id).await;
+    }
+
     state
         .device_set_scene(&device, &scene)
         .await
@@ -180,3 +243,489 @@ pub async fn mqtt_set_mode_scene(
 
     Ok(())
 }
+
+/// Presents a mist intensity capability whose parameters are a list
+/// of named levels, rather than a numeric range
+pub struct MistLevelSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl MistLevelSelect {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Option<Self> {
+        let options = match &instance.parameters {
+            Some(DeviceParameters::Enum { options }) => {
+                options.iter().map(|opt| opt.name.to_string()).collect()
+            }
+            _ => return None,
+        };
+
+        let command_topic = format!(
+            "{prefix}/select/{id}/set-mist-level/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = instance.instance
+        );
+        let state_topic = format!(
+            "{prefix}/select/{id}/mist-level/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}-select",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Mist Level".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:weather-fog".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MistLevelSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(value) = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| cap.state.pointer("/value"))
+        else {
+            return Ok(());
+        };
+
+        if let Some(Some(DeviceParameters::Enum { options })) = device
+            .get_capability_by_instance(&self.instance_name)
+            .map(|cap| &cap.parameters)
+        {
+            if let Some(opt) = options.iter().find(|opt| opt.value == *value) {
+                return client
+                    .publish(&self.select.state_topic, opt.name.to_string())
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_set_mist_level_preset(
+    Payload(level): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_mist_level_preset: {id}: {instance} {level}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let cap = device
+        .get_capability_by_instance(&instance)
+        .ok_or_else(|| anyhow::anyhow!("{device} has no {instance}"))?;
+
+    let value = cap
+        .parameters
+        .as_ref()
+        .and_then(|p| p.enum_parameter_by_name(&level))
+        .ok_or_else(|| anyhow::anyhow!("{level} is not a valid {instance} for {device}"))?;
+
+    state
+        .device_control(&device, cap, value)
+        .await
+        .context("mqtt_set_mist_level_preset: state.device_control")?;
+
+    Ok(())
+}
+
+/// Presents the mist level of a humidifier, which is the value of
+/// its Manual work mode, as a list of named presets. It is offered
+/// alongside the number entity for the same value, which is set to
+/// the level of the chosen preset.
+pub struct MistPresetSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+    work_mode: JsonValue,
+    presets: Vec<MistPreset>,
+}
+
+impl MistPresetSelect {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        work_mode: JsonValue,
+        presets: Vec<MistPreset>,
+    ) -> Self {
+        let command_topic = format!(
+            "{prefix}/select/{id}/set-mist-preset",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+        );
+        let state_topic = format!(
+            "{prefix}/select/{id}/mist-preset",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+        );
+        let unique_id = format!("gv2mqtt-{id}-mist-preset", id = topic_safe_id(device));
+
+        Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Mist Preset".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:weather-fog".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: presets.iter().map(|p| p.name.to_string()).collect(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            work_mode,
+            presets,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MistPresetSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        // Use the same value as the number entity, so that the two agree
+        if let Some(preset) = work_mode_param(&device, &self.work_mode)
+            .and_then(|level| nearest_mist_preset(&self.presets, level))
+        {
+            return client
+                .publish(&self.select.state_topic, preset.name.to_string())
+                .await;
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_set_mist_preset(
+    Payload(name): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_mist_preset: {id}: {name}");
+    let device = state
+        .resolve_device(&id)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("device '{id}' not found"))?;
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let (mode, presets) = mist_level_presets(&work_modes, device.resolve_quirk().as_ref())
+        .ok_or_else(|| anyhow::anyhow!("{device} has no mist level presets"))?;
+    let work_mode = mode
+        .value
+        .as_i64()
+        .ok_or_else(|| anyhow::anyhow!("{device} work mode {} is not a number", mode.name))?;
+    let preset = presets
+        .iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| anyhow::anyhow!("{name} is not a mist level preset of {device}"))?;
+
+    // Supersedes, and is superseded by, a change via the number entity
+    let Some(device) = state.resolve_device_for_command(&id, &mode.name).await? else {
+        return Ok(());
+    };
+    state
+        .humidifier_set_parameter(&device, work_mode, preset.value)
+        .await
+        .context("mqtt_set_mist_preset: state.humidifier_set_parameter")?;
+
+    Ok(())
+}
+
+/// Lights, such as the H7019 aquarium light, can have built-in cycle
+/// modes that are exposed as a mode capability of their own, distinct
+/// from the scenes that are listed in the Mode/Scene select
+pub fn is_light_cycle_mode(device: &ServiceDevice, cap: &DeviceCapability) -> bool {
+    device.device_type() == DeviceType::Light
+        && cap.kind == DeviceCapabilityKind::Mode
+        && !matches!(
+            cap.instance.as_str(),
+            "presetScene" | NIGHTLIGHT_SCENE_INSTANCE
+        )
+        && matches!(cap.parameters, Some(DeviceParameters::Enum { .. }))
+        && !is_boolean_capability(cap)
+}
+
+/// Returns the value of the option that takes the light out of
+/// its cycle, if the device has one
+fn manual_cycle_mode_value(cap: &DeviceCapability) -> Option<JsonValue> {
+    match &cap.parameters {
+        Some(DeviceParameters::Enum { options }) => options
+            .iter()
+            .find(|opt| opt.name.eq_ignore_ascii_case("manual"))
+            .map(|opt| opt.value.clone()),
+        _ => None,
+    }
+}
+
+pub struct LightCycleModeSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl LightCycleModeSelect {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Option<Self> {
+        let options = match &instance.parameters {
+            Some(DeviceParameters::Enum { options }) => {
+                options.iter().map(|opt| opt.name.to_string()).collect()
+            }
+            _ => return None,
+        };
+
+        let command_topic = format!(
+            "{prefix}/select/{id}/set-cycle-mode/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = instance.instance
+        );
+        let state_topic = format!(
+            "{prefix}/select/{id}/cycle-mode/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}-select",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Cycle Mode".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:autorenew".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for LightCycleModeSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(value) = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| cap.state.pointer("/value"))
+        else {
+            return Ok(());
+        };
+
+        if let Some(Some(DeviceParameters::Enum { options })) = device
+            .get_capability_by_instance(&self.instance_name)
+            .map(|cap| &cap.parameters)
+        {
+            if let Some(opt) = options.iter().find(|opt| opt.value == *value) {
+                return client
+                    .publish(&self.select.state_topic, opt.name.to_string())
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_set_light_cycle_mode(
+    Payload(mode): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_light_cycle_mode: {id}: {instance} {mode}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let cap = device
+        .get_capability_by_instance(&instance)
+        .ok_or_else(|| anyhow::anyhow!("{device} has no {instance}"))?;
+
+    let value = cap
+        .parameters
+        .as_ref()
+        .and_then(|p| p.enum_parameter_by_name(&mode))
+        .ok_or_else(|| anyhow::anyhow!("{mode} is not a valid {instance} for {device}"))?;
+
+    state
+        .device_control(&device, cap, value)
+        .await
+        .context("mqtt_set_light_cycle_mode: state.device_control")?;
+
+    // The cycle replaces whatever scene was running
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .set_active_scene(None);
+
+    Ok(())
+}
+
+/// Switches a light that is running one of its cycle modes back to
+/// manual control, as the app does when a color is picked
+pub async fn exit_light_cycle_mode(
+    state: &StateHandle,
+    device: &ServiceDevice,
+) -> anyhow::Result<()> {
+    let Some(info) = &device.http_device_info else {
+        return Ok(());
+    };
+
+    for cap in &info.capabilities {
+        if !is_light_cycle_mode(device, cap) {
+            continue;
+        }
+        let Some(manual) = manual_cycle_mode_value(cap) else {
+            continue;
+        };
+        let current = device
+            .get_state_capability_by_instance(&cap.instance)
+            .and_then(|cap| cap.state.pointer("/value").cloned());
+        if current.as_ref() == Some(&manual) {
+            continue;
+        }
+
+        log::info!(
+            "{device}: leaving {} cycle mode for manual color",
+            cap.instance
+        );
+        state
+            .device_control(device, cap, manual.clone())
+            .await
+            .context("exit_light_cycle_mode: state.device_control")?;
+        state
+            .device_mut(&device.sku, &device.id)
+            .await
+            .apply_capability_state(cap.kind.clone(), &cap.instance, json!({"value": manual}));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::EnumOption;
+
+    #[test]
+    fn manual_cycle_mode() {
+        let cap = DeviceCapability {
+            kind: DeviceCapabilityKind::Mode,
+            instance: "lightCycleMode".to_string(),
+            parameters: Some(DeviceParameters::Enum {
+                options: ["Manual", "Sunrise", "Coral Reef"]
+                    .into_iter()
+                    .enumerate()
+                    .map(|(n, name)| EnumOption {
+                        name: name.to_string(),
+                        value: json!(n),
+                        extras: Default::default(),
+                    })
+                    .collect(),
+            }),
+            alarm_type: None,
+            event_state: None,
+        };
+        k9::assert_equal!(manual_cycle_mode_value(&cap), Some(json!(0)));
+
+        let cap = DeviceCapability {
+            parameters: Some(DeviceParameters::Enum { options: vec![] }),
+            ..cap
+        };
+        k9::assert_equal!(manual_cycle_mode_value(&cap), None);
+    }
+
+    #[test]
+    fn scene_options() {
+        let scenes = vec!["".to_string(), "Forest".to_string(), "Ocean".to_string()];
+        k9::assert_equal!(
+            scene_select_options(scenes.clone(), None),
+            vec!["None", "Forest", "Ocean"]
+        );
+        k9::assert_equal!(
+            scene_select_options(scenes.clone(), Some("Ocean".to_string())),
+            vec!["None", "Forest", "Ocean"]
+        );
+        k9::assert_equal!(
+            scene_select_options(scenes, Some("Unknown scene (1234)".to_string())),
+            vec!["None", "Forest", "Ocean", "Unknown scene (1234)"]
+        );
+    }
+}
@@ -2,16 +2,20 @@ use crate::commands::serve::POLL_INTERVAL;
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::humidifier::DEVICE_CLASS_HUMIDITY;
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::precision::SensorPrecision;
 use crate::platform_api::DeviceCapability;
-use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::rate_limit::QuotaStatus;
+use crate::service::device::{Device as ServiceDevice, SensorAlarm};
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, topic_safe_string, HassClient,
+};
 use crate::service::quirks::HumidityUnits;
 use crate::service::state::StateHandle;
 use crate::temperature::{TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE};
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{DateTime, Timelike, Utc};
 use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value as JsonValue};
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SensorConfig {
@@ -24,6 +28,8 @@ pub struct SensorConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub unit_of_measurement: Option<&'static str>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_display_precision: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub json_attributes_topic: Option<String>,
 }
 
@@ -57,11 +63,11 @@ pub struct GlobalFixedDiagnostic {
 #[async_trait]
 impl EntityInstance for GlobalFixedDiagnostic {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.notify_state(&client, &self.value).await
+        self.sensor.notify_state(client, &self.value).await
     }
 }
 
@@ -82,9 +88,10 @@ impl GlobalFixedDiagnostic {
                     device_class: None,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
                 state_class: None,
                 unit_of_measurement: None,
+                suggested_display_precision: None,
                 json_attributes_topic: None,
             },
             value: value.into(),
@@ -92,6 +99,184 @@ impl GlobalFixedDiagnostic {
     }
 }
 
+/// Which part of the platform API request quota a
+/// PlatformQuotaSensor reports
+#[derive(Clone, Copy, Debug)]
+pub enum QuotaField {
+    MinuteRemaining,
+    MinuteReset,
+    DayRemaining,
+    DayReset,
+}
+
+impl QuotaField {
+    pub const ALL: [Self; 4] = [
+        Self::MinuteRemaining,
+        Self::MinuteReset,
+        Self::DayRemaining,
+        Self::DayReset,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::MinuteRemaining => "Platform API Requests Remaining This Minute",
+            Self::MinuteReset => "Platform API Minute Quota Reset",
+            Self::DayRemaining => "Platform API Requests Remaining Today",
+            Self::DayReset => "Platform API Daily Quota Reset",
+        }
+    }
+
+    fn is_timestamp(&self) -> bool {
+        matches!(self, Self::MinuteReset | Self::DayReset)
+    }
+
+    fn value(&self, quota: &QuotaStatus) -> Option<String> {
+        match self {
+            Self::MinuteRemaining => quota.minute_remaining.map(|n| n.to_string()),
+            Self::MinuteReset => quota.minute_reset.map(|t| t.to_rfc3339()),
+            Self::DayRemaining => quota.day_remaining.map(|n| n.to_string()),
+            Self::DayReset => quota.day_reset.map(|t| t.to_rfc3339()),
+        }
+    }
+}
+
+/// Reports the remaining platform API request quota, as indicated
+/// by the headers of its responses
+pub struct PlatformQuotaSensor {
+    sensor: SensorConfig,
+    field: QuotaField,
+    state: StateHandle,
+}
+
+impl PlatformQuotaSensor {
+    pub fn new(field: QuotaField, state: &StateHandle) -> Self {
+        let name = field.name();
+        let unique_id = format!("global-{}", topic_safe_string(name));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name.to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.clone(),
+                    device_class: field.is_timestamp().then_some("timestamp"),
+                    icon: (!field.is_timestamp()).then(|| "mdi:counter".to_string()),
+                },
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
+                state_class: (!field.is_timestamp()).then_some(StateClass::Measurement),
+                unit_of_measurement: None,
+                suggested_display_precision: None,
+                json_attributes_topic: None,
+            },
+            field,
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PlatformQuotaSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(platform) = self.state.get_platform_client().await else {
+            return Ok(());
+        };
+        // Leave the sensor alone until the headers tell us something
+        match self.field.value(&platform.quota()) {
+            Some(value) => self.sensor.notify_state(client, &value).await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub const FILTER_LIFE_INSTANCE: &str = "filterLifeTime";
+
+/// Ice makers report how full their basket is
+pub const ICE_LEVEL_INSTANCE: &str = "iceLevel";
+
+/// Kettles report the remaining keep-warm time under a couple
+/// of different names, eg: keepWarmRemainingTime, keepWarmRemain
+pub fn is_keep_warm_remaining_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    instance.contains("keepwarm") && instance.contains("remain")
+}
+
+/// Presence sensors can report how far away the detected body is
+pub fn is_detection_distance_instance(instance: &str) -> bool {
+    instance.to_ascii_lowercase().contains("distance")
+}
+
+/// Presence sensors can report how long the room has been unoccupied
+pub fn is_absence_duration_instance(instance: &str) -> bool {
+    instance.to_ascii_lowercase().contains("absence")
+}
+
+/// Extracts the remaining filter life percentage from the reported
+/// state. Some devices report a bare number, while others nest it
+/// inside an object alongside other filter information.
+pub fn filter_life_percent(value: &JsonValue) -> Option<f64> {
+    match value {
+        JsonValue::Object(map) => map
+            .iter()
+            .find(|(k, _)| {
+                let k = k.to_ascii_lowercase();
+                k.contains("life") || k.contains("percent") || k == "value"
+            })
+            .or_else(|| map.iter().find(|(_, v)| v.is_number()))
+            .and_then(|(_, v)| filter_life_percent(v)),
+        v => v.as_f64(),
+    }
+}
+
+/// Returns whether the reported state indicates that the filter
+/// needs to be replaced
+pub fn filter_needs_replacement(value: &JsonValue) -> Option<bool> {
+    if let JsonValue::Object(map) = value {
+        if let Some(flag) = map.iter().find_map(|(k, v)| {
+            let k = k.to_ascii_lowercase();
+            if k.contains("replace") || k.contains("expire") {
+                v.as_i64().map(|v| v != 0).or_else(|| v.as_bool())
+            } else {
+                None
+            }
+        }) {
+            return Some(flag);
+        }
+    }
+    filter_life_percent(value).map(|percent| percent <= 0.)
+}
+
+/// Returns the current relative humidity reported by the device,
+/// scaled to a percentage
+pub fn reported_humidity_percent(device: &ServiceDevice) -> Option<f64> {
+    let value = device
+        .get_state_capability_by_instance("sensorHumidity")?
+        .state
+        .pointer("/value")?
+        .as_f64()?;
+    let units = device
+        .resolve_quirk()
+        .and_then(|q| q.platform_humidity_sensor_units)
+        .unwrap_or_else(|| HumidityUnits::guess_from_reading(value));
+    Some(units.from_reading_to_relative_percent(value))
+}
+
+/// Returns the integer value of a capability state such as
+/// `{"value": 3}`, as published for the sensor, or an empty string
+/// if there is none
+fn integer_reading(state: &JsonValue) -> String {
+    match state.pointer("/value").and_then(|v| v.as_i64()) {
+        Some(v) => v.to_string(),
+        None => "".to_string(),
+    }
+}
+
 #[derive(Clone)]
 pub struct CapabilitySensor {
     sensor: SensorConfig,
@@ -115,24 +300,48 @@ impl CapabilitySensor {
         let unit_of_measurement = match instance.instance.as_str() {
             "sensorTemperature" => Some(state.get_temperature_scale().await.unit_of_measurement()),
             "sensorHumidity" => Some("%"),
+            FILTER_LIFE_INSTANCE => Some("%"),
+            ICE_LEVEL_INSTANCE => Some("%"),
+            s if is_keep_warm_remaining_instance(s) => Some("min"),
+            s if is_detection_distance_instance(s) => Some("cm"),
+            s if is_absence_duration_instance(s) => Some("s"),
             _ => None,
         };
 
         let device_class = match instance.instance.as_str() {
             "sensorTemperature" => Some(DEVICE_CLASS_TEMPERATURE),
             "sensorHumidity" => Some(DEVICE_CLASS_HUMIDITY),
+            s if is_keep_warm_remaining_instance(s) => Some("duration"),
+            s if is_detection_distance_instance(s) => Some("distance"),
+            s if is_absence_duration_instance(s) => Some("duration"),
             _ => None,
         };
 
         let state_class = match instance.instance.as_str() {
             "sensorTemperature" => Some(StateClass::Measurement),
             "sensorHumidity" => Some(StateClass::Measurement),
+            FILTER_LIFE_INSTANCE => Some(StateClass::Measurement),
+            ICE_LEVEL_INSTANCE => Some(StateClass::Measurement),
+            s if is_keep_warm_remaining_instance(s) => Some(StateClass::Measurement),
+            s if is_detection_distance_instance(s) => Some(StateClass::Measurement),
+            s if is_absence_duration_instance(s) => Some(StateClass::Measurement),
+            _ => None,
+        };
+
+        let precision = match instance.instance.as_str() {
+            "sensorTemperature" => Some(SensorPrecision::Temperature),
+            "sensorHumidity" => Some(SensorPrecision::Humidity),
             _ => None,
         };
 
         let name = match instance.instance.as_str() {
             "sensorTemperature" => "Temperature".to_string(),
             "sensorHumidity" => "Humidity".to_string(),
+            FILTER_LIFE_INSTANCE => "Filter Life".to_string(),
+            ICE_LEVEL_INSTANCE => "Ice Level".to_string(),
+            s if is_keep_warm_remaining_instance(s) => "Keep Warm Remaining".to_string(),
+            s if is_detection_distance_instance(s) => "Detection Distance".to_string(),
+            s if is_absence_duration_instance(s) => "Absence Duration".to_string(),
             "online" => "Connected to Govee Cloud".to_string(),
             "lowBatteryEvent" => "Low Battery".to_string(),
             "lackWaterEvent" => "Water Level".to_string(),
@@ -167,10 +376,17 @@ impl CapabilitySensor {
                     device_class,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
-                state_class: state_class,
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
+                state_class,
                 unit_of_measurement,
-                json_attributes_topic: None,
+                suggested_display_precision: precision.and_then(SensorPrecision::decimals),
+                json_attributes_topic: match instance.instance.as_str() {
+                    "sensorTemperature" | "sensorHumidity" => Some(format!(
+                        "{prefix}/sensor/{unique_id}/attributes",
+                        prefix = topic_prefix()
+                    )),
+                    _ => None,
+                },
             },
             device_id: device.id.to_string(),
             state: state.clone(),
@@ -182,7 +398,7 @@ impl CapabilitySensor {
 #[async_trait]
 impl EntityInstance for CapabilitySensor {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -193,6 +409,13 @@ impl EntityInstance for CapabilitySensor {
             .expect("device to exist");
 
         let quirk = device.resolve_quirk();
+        let config = device.config();
+        // The uncorrected reading and the calibration offset, for the
+        // sensors that are calibrated
+        let mut calibration = None;
+        // The alert thresholds that were set up in the Govee app,
+        // in the units of the published reading
+        let mut alarm = None;
 
         if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
             let value = match self.instance_name.as_str() {
@@ -208,32 +431,67 @@ impl EntityInstance for CapabilitySensor {
                         .map(|v| TemperatureValue::new(v, units))
                     {
                         Some(v) => {
-                            let value = v
-                                .as_unit(self.state.get_temperature_scale().await.into())
-                                .value();
-                            format!("{value:.2}")
+                            let units = self.state.get_temperature_scale().await.into();
+                            let raw = v.as_unit(units).value();
+                            let offset = device.temperature_offset(&config, units);
+                            calibration.replace((raw, offset));
+                            alarm = device.sensor_alarms().0.map(|alarm| {
+                                let convert = |celsius| {
+                                    SensorPrecision::Temperature.round(
+                                        TemperatureValue::new(celsius, TemperatureUnits::Celsius)
+                                            .as_unit(units)
+                                            .value(),
+                                    )
+                                };
+                                SensorAlarm {
+                                    min: convert(alarm.min),
+                                    max: convert(alarm.max),
+                                    ..alarm
+                                }
+                            });
+                            SensorPrecision::Temperature.format(raw + offset)
                         }
                         None => "".to_string(),
                     }
                 }
-                "sensorHumidity" => {
-                    let units = quirk
-                        .and_then(|q| q.platform_humidity_sensor_units)
-                        .unwrap_or(HumidityUnits::RelativePercent);
-                    match cap
-                        .state
-                        .pointer("/value")
-                        .and_then(|v| v.as_f64())
-                        .map(|v| units.from_reading_to_relative_percent(v))
-                    {
-                        Some(v) => format!("{v:.2}"),
+                "sensorHumidity" => match reported_humidity_percent(&device) {
+                    Some(raw) => {
+                        let offset = device.humidity_offset(&config);
+                        calibration.replace((raw, offset));
+                        alarm = device.sensor_alarms().1;
+                        SensorPrecision::Humidity.format((raw + offset).clamp(0., 100.))
+                    }
+                    None => "".to_string(),
+                },
+                FILTER_LIFE_INSTANCE => {
+                    match cap.state.pointer("/value").and_then(filter_life_percent) {
+                        Some(v) => format!("{v:.0}"),
                         None => "".to_string(),
                     }
                 }
+                s if s == ICE_LEVEL_INSTANCE
+                    || is_keep_warm_remaining_instance(s)
+                    || is_detection_distance_instance(s)
+                    || is_absence_duration_instance(s) =>
+                {
+                    integer_reading(&cap.state)
+                }
                 _ => cap.state.to_string(),
             };
 
-            return self.sensor.notify_state(&client, &value).await;
+            self.sensor.notify_state(client, &value).await?;
+            if let (Some(topic), Some((raw, offset))) =
+                (&self.sensor.json_attributes_topic, calibration)
+            {
+                let mut attributes = json!({"raw": raw, "offset": offset});
+                if let Some(alarm) = alarm {
+                    attributes["alarm_min"] = alarm.min.into();
+                    attributes["alarm_max"] = alarm.max.into();
+                    attributes["alarm_enabled"] = alarm.enabled.into();
+                }
+                client.publish_obj(topic, attributes).await?;
+            }
+            return Ok(());
         }
         log::trace!(
             "CapabilitySensor::notify_state: didn't find state for {device} {instance}",
@@ -265,9 +523,13 @@ impl DeviceStatusDiagnostic {
                     device_class: None,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
                 state_class: None,
-                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                suggested_display_precision: None,
+                json_attributes_topic: Some(format!(
+                    "{prefix}/sensor/{unique_id}/attributes",
+                    prefix = topic_prefix()
+                )),
                 unit_of_measurement: None,
             },
             device_id: device.id.to_string(),
@@ -279,7 +541,7 @@ impl DeviceStatusDiagnostic {
 #[async_trait]
 impl EntityInstance for DeviceStatusDiagnostic {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -311,19 +573,294 @@ impl EntityInstance for DeviceStatusDiagnostic {
             None => "Unknown".to_string(),
         };
 
-        let attributes = json!({
+        let mut attributes = json!({
             "iot": iot_state,
             "lan": lan_state,
             "http": http_state,
             "platform_metadata": platform_metadata,
             "platform_state": platform_state,
             "overall": device_state,
+            "ble": device.last_ble_reading_update.map(|updated| json!({
+                "reading": device.ble_reading,
+                "updated": updated,
+            })),
         });
 
-        self.sensor.notify_state(&client, &summary).await?;
+        // Devices without a light of their own carry their raw
+        // capability state here instead
+        if !device.has_primary_light() && device.publishes_raw_state() {
+            attributes["capabilities"] = device.raw_capability_states().into();
+        }
+
+        self.sensor.notify_state(client, &summary).await?;
         if let Some(topic) = &self.sensor.json_attributes_topic {
             client.publish_obj(topic, attributes).await?;
         }
         Ok(())
     }
 }
+
+pub struct BatterySensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl BatterySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-battery", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Battery".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("battery"),
+                    icon: None,
+                },
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some("%"),
+                suggested_display_precision: None,
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for BatterySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        // Leave the prior reading in place if the level is not
+        // present in this snapshot
+        match device.battery_percent() {
+            Some(level) => self.sensor.notify_state(client, &level.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct WifiSignalSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl WifiSignalSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-wifi-rssi", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Wi-Fi Signal".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("signal_strength"),
+                    icon: None,
+                },
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some("dBm"),
+                suggested_display_precision: None,
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for WifiSignalSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        match device.wifi_rssi() {
+            Some(rssi) => self.sensor.notify_state(client, &rssi.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Truncates `t` to the start of its minute, so that the last seen
+/// sensor changes at most once per minute, rather than with every
+/// poll or pushed update
+fn last_seen_minute(t: DateTime<Utc>) -> DateTime<Utc> {
+    t.with_second(0)
+        .and_then(|t| t.with_nanosecond(0))
+        .unwrap_or(t)
+}
+
+/// Reports when we last received any data for the device, from
+/// any source, to help track down devices whose state is stale
+pub struct LastSeenSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl LastSeenSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-last-seen", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Last Seen".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("timestamp"),
+                    icon: None,
+                },
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
+                state_class: None,
+                unit_of_measurement: None,
+                suggested_display_precision: None,
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for LastSeenSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        match device.last_seen() {
+            Some(seen) => {
+                self.sensor
+                    .notify_state(client, &last_seen_minute(seen).to_rfc3339())
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn last_seen_truncation() {
+        let seen: DateTime<Utc> = "2024-03-01T12:34:56.789Z".parse().unwrap();
+        k9::assert_equal!(
+            last_seen_minute(seen).to_rfc3339(),
+            "2024-03-01T12:34:00+00:00"
+        );
+    }
+
+    #[test]
+    fn humidity_scaling() {
+        use crate::platform_api::{DeviceCapabilityKind, DeviceCapabilityState, HttpDeviceState};
+
+        let humidity = |sku: &str, value: f64| {
+            let mut device = ServiceDevice::new(sku, "AA:BB:CC:DD:EE:FF:42:2A");
+            device.set_http_device_state(HttpDeviceState {
+                sku: sku.to_string(),
+                device: device.id.clone(),
+                capabilities: vec![DeviceCapabilityState {
+                    kind: DeviceCapabilityKind::Property,
+                    instance: "sensorHumidity".to_string(),
+                    state: json!({"value": value}),
+                }],
+            });
+            reported_humidity_percent(&device)
+        };
+
+        k9::assert_equal!(humidity("H7151", 45.), Some(45.));
+        k9::assert_equal!(humidity("H7151", 4550.), Some(45.5));
+        k9::assert_equal!(humidity("H5179", 45.5), Some(45.5));
+    }
+
+    #[tokio::test]
+    async fn ice_level() {
+        use crate::platform_api::DeviceCapabilityKind;
+
+        let device = ServiceDevice::new("H7172", "AA:BB:CC:DD:EE:FF:42:2A");
+        let cap = DeviceCapability {
+            kind: DeviceCapabilityKind::Property,
+            instance: ICE_LEVEL_INSTANCE.to_string(),
+            parameters: None,
+            alarm_type: None,
+            event_state: None,
+        };
+        let state = std::sync::Arc::new(crate::service::state::State::new());
+        let sensor = CapabilitySensor::new(&device, &state, &cap).await.unwrap();
+        k9::assert_equal!(sensor.sensor.unit_of_measurement, Some("%"));
+        k9::assert_equal!(sensor.sensor.state_class, Some(StateClass::Measurement));
+
+        // The number is published, rather than the state object
+        k9::assert_equal!(integer_reading(&json!({"value": 2})), "2");
+        k9::assert_equal!(integer_reading(&json!({"value": ""})), "");
+        k9::assert_equal!(integer_reading(&json!({})), "");
+    }
+
+    #[test]
+    fn keep_warm_instance() {
+        assert!(is_keep_warm_remaining_instance("keepWarmRemainingTime"));
+        assert!(is_keep_warm_remaining_instance("keepWarmRemain"));
+        assert!(!is_keep_warm_remaining_instance("keepWarmToggle"));
+        assert!(!is_keep_warm_remaining_instance("sensorTemperature"));
+    }
+
+    #[test]
+    fn filter_life() {
+        k9::assert_equal!(filter_life_percent(&json!(85)), Some(85.));
+        k9::assert_equal!(
+            filter_life_percent(&json!({"filterLife": 42, "needReplace": 0})),
+            Some(42.)
+        );
+        k9::assert_equal!(filter_life_percent(&json!({"value": 7})), Some(7.));
+        k9::assert_equal!(filter_life_percent(&json!("")), None);
+
+        k9::assert_equal!(filter_needs_replacement(&json!(0)), Some(true));
+        k9::assert_equal!(filter_needs_replacement(&json!(10)), Some(false));
+        k9::assert_equal!(
+            filter_needs_replacement(&json!({"filterLife": 42, "needReplace": 1})),
+            Some(true)
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::platform_api::{DeviceCapability, DeviceParameters};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, topic_safe_string, HassClient, IdAndInst,
+};
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::{Deserialize, Serialize};
+
+/// The struct field that selects the tone to play
+pub const SIREN_TONE_FIELD: &str = "tone";
+/// The struct field that specifies how long to sound the buzzer
+pub const SIREN_DURATION_FIELD: &str = "duration";
+
+pub fn is_siren_instance(instance: &str) -> bool {
+    matches!(instance, "buzzer" | "alarm")
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SirenConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub command_topic: String,
+    pub state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_tones: Option<Vec<String>>,
+    pub support_duration: bool,
+    pub support_volume_set: bool,
+}
+
+impl SirenConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("siren", state, client, &self.base, self).await
+    }
+}
+
+pub fn siren_tones(instance: &DeviceCapability) -> Option<Vec<String>> {
+    match &instance.struct_field_by_name(SIREN_TONE_FIELD)?.field_type {
+        DeviceParameters::Enum { options } => {
+            Some(options.iter().map(|opt| opt.name.to_string()).collect())
+        }
+        _ => None,
+    }
+}
+
+pub struct DeviceSiren {
+    siren: SirenConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl DeviceSiren {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let id = topic_safe_id(device);
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}",
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Self {
+            siren: SirenConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Siren".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    entity_category: None,
+                    icon: None,
+                },
+                command_topic: format!(
+                    "{prefix}/siren/{id}/command/{inst}",
+                    prefix = topic_prefix(),
+                    inst = instance.instance
+                ),
+                state_topic: format!("{prefix}/siren/{unique_id}/state", prefix = topic_prefix()),
+                available_tones: siren_tones(instance),
+                support_duration: instance
+                    .struct_field_by_name(SIREN_DURATION_FIELD)
+                    .is_some(),
+                support_volume_set: false,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DeviceSiren {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.siren.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
+            let value = cap
+                .state
+                .pointer("/value")
+                .and_then(|v| v.as_i64().or_else(|| v.pointer("/onOff")?.as_i64()));
+            if let Some(n) = value {
+                return client
+                    .publish(&self.siren.state_topic, if n != 0 { "ON" } else { "OFF" })
+                    .await;
+            }
+        }
+
+        log::trace!(
+            "DeviceSiren::notify_state: didn't find state for {device} {instance}",
+            instance = self.instance_name
+        );
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+pub struct HassSirenCommand {
+    pub state: String,
+    pub tone: Option<String>,
+    pub duration: Option<u32>,
+}
+
+impl HassSirenCommand {
+    /// hass sends a plain ON/OFF payload when no extra parameters were
+    /// specified, otherwise a JSON object
+    pub fn parse(payload: &str) -> anyhow::Result<Self> {
+        let payload = payload.trim();
+        if payload.starts_with('{') {
+            serde_json::from_str(payload).with_context(|| format!("parsing {payload}"))
+        } else {
+            Ok(Self {
+                state: payload.to_string(),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+pub async fn mqtt_siren_command(
+    Payload(payload): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{id}: siren {instance} command {payload}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let command = HassSirenCommand::parse(&payload)?;
+    let on = match command.state.as_str() {
+        "ON" | "on" => true,
+        "OFF" | "off" => false,
+        _ => anyhow::bail!("invalid {payload} for {id}"),
+    };
+
+    // Note that OFF is always sent through to the device, even if a
+    // prior ON specified a duration that has not yet elapsed
+    state
+        .device_set_siren(&device, &instance, on, command.tone, command.duration)
+        .await
+        .context("mqtt_siren_command: state.device_set_siren")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_command() {
+        k9::assert_equal!(
+            HassSirenCommand::parse("OFF").unwrap(),
+            HassSirenCommand {
+                state: "OFF".to_string(),
+                tone: None,
+                duration: None
+            }
+        );
+        k9::assert_equal!(
+            HassSirenCommand::parse(r#"{"state":"ON","tone":"alarm","duration":10}"#).unwrap(),
+            HassSirenCommand {
+                state: "ON".to_string(),
+                tone: Some("alarm".to_string()),
+                duration: Some(10)
+            }
+        );
+    }
+}
@@ -1,10 +1,10 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
-use crate::platform_api::DeviceCapability;
+use crate::platform_api::{DeviceCapability, DeviceParameters};
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, camel_case_to_space_separated, switch_instance_state_topic, topic_safe_id,
-    HassClient,
+    availability_topic, camel_case_to_space_separated, switch_instance_state_topic, topic_prefix,
+    topic_safe_id, HassClient,
 };
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
@@ -19,13 +19,124 @@ pub struct SwitchConfig {
     pub state_topic: String,
 }
 
+/// Returns true if the capability is a boolean, represented as an
+/// enum with just on and off options, regardless of its declared
+/// capability type. This allows toggles that we don't otherwise
+/// recognize to be exposed as switches.
+pub fn is_boolean_capability(cap: &DeviceCapability) -> bool {
+    match &cap.parameters {
+        Some(DeviceParameters::Enum { options }) => {
+            options.len() == 2
+                && options
+                    .iter()
+                    .any(|opt| opt.name.eq_ignore_ascii_case("on"))
+                && options
+                    .iter()
+                    .any(|opt| opt.name.eq_ignore_ascii_case("off"))
+        }
+        _ => false,
+    }
+}
+
+/// The instances that lock the physical controls of a device
+const LOCK_INSTANCES: &[&str] = &["childLock", "lockButtons", "buttonLock", "keyLock"];
+
+/// Returns true if the instance locks the physical controls of the
+/// device. Devices with other names for this can be covered by
+/// adding them to the lock_instances of their quirk.
+pub fn is_lock_instance(device: &ServiceDevice, instance: &str) -> bool {
+    LOCK_INSTANCES.contains(&instance)
+        || device
+            .resolve_quirk()
+            .map(|q| q.is_lock_instance(instance))
+            .unwrap_or(false)
+}
+
+/// Returns true if the instance turns the display or control panel
+/// of the device on or off
+pub fn is_display_toggle_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    (instance.contains("display") || instance.contains("screen"))
+        && !instance.contains("brightness")
+}
+
+/// Returns true if the instance enables the beep that appliances,
+/// such as ice makers and kettles, make when they finish.
+/// Mute toggles are excluded, as their sense is inverted.
+pub fn is_beep_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    instance.contains("beep")
+        || ((instance.contains("sound") || instance.contains("buzzer"))
+            && instance.ends_with("toggle"))
+}
+
+/// Returns true if the instance turns the indicator (status) LED of
+/// a plug or appliance on or off, or the do not disturb mode that
+/// keeps it dark. These don't affect the power of the device.
+pub fn is_indicator_light_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    instance.contains("indicator")
+        || instance.contains("statuslight")
+        || instance.contains("statusled")
+        || is_do_not_disturb_instance(&instance)
+}
+
+fn is_do_not_disturb_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    instance.contains("dnd") || instance.contains("donotdisturb")
+}
+
+fn switch_name(instance: &str, is_lock: bool) -> String {
+    match instance {
+        "gradientToggle" => "Gradient".to_string(),
+        "oscillationToggle" => "Oscillate".to_string(),
+        _ if is_lock => "Child Lock".to_string(),
+        _ if is_display_toggle_instance(instance) => "Display".to_string(),
+        _ if is_beep_instance(instance) => "Beep".to_string(),
+        _ if is_do_not_disturb_instance(instance) => "Do Not Disturb".to_string(),
+        _ if is_indicator_light_instance(instance) => "Indicator Light".to_string(),
+        _ => camel_case_to_space_separated(instance),
+    }
+}
+
+fn switch_entity_category(instance: &str, is_lock: bool) -> Option<String> {
+    match instance {
+        "gradientToggle" => Some("config".to_string()),
+        _ if is_lock
+            || is_display_toggle_instance(instance)
+            || is_beep_instance(instance)
+            || is_indicator_light_instance(instance) =>
+        {
+            Some("config".to_string())
+        }
+        _ => None,
+    }
+}
+
+fn switch_icon(instance: &str, is_lock: bool) -> Option<String> {
+    if is_lock {
+        Some("mdi:lock".to_string())
+    } else if instance == "oscillationToggle" {
+        Some("mdi:arrow-oscillating".to_string())
+    } else if is_beep_instance(instance) {
+        Some("mdi:volume-high".to_string())
+    } else if is_do_not_disturb_instance(instance) {
+        Some("mdi:minus-circle-outline".to_string())
+    } else if is_indicator_light_instance(instance) {
+        Some("mdi:led-on".to_string())
+    } else {
+        None
+    }
+}
+
 impl SwitchConfig {
     pub async fn for_device(
         device: &ServiceDevice,
         instance: &DeviceCapability,
     ) -> anyhow::Result<Self> {
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/{inst}",
+            "{prefix}/switch/{id}/command/{inst}",
+            prefix = topic_prefix(),
             id = topic_safe_id(device),
             inst = instance.instance
         );
@@ -36,17 +147,18 @@ impl SwitchConfig {
             id = topic_safe_id(device),
             inst = instance.instance
         );
+        let is_lock = is_lock_instance(device, &instance.instance);
 
         Ok(Self {
             base: EntityConfig {
                 availability_topic,
-                name: Some(camel_case_to_space_separated(&instance.instance)),
+                name: Some(switch_name(&instance.instance, is_lock)),
                 device_class: None,
                 origin: Origin::default(),
                 device: Device::for_device(device),
                 unique_id,
-                entity_category: None,
-                icon: None,
+                entity_category: switch_entity_category(&instance.instance, is_lock),
+                icon: switch_icon(&instance.instance, is_lock),
             },
             command_topic,
             state_topic,
@@ -84,7 +196,7 @@ impl CapabilitySwitch {
 #[async_trait]
 impl EntityInstance for CapabilitySwitch {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.switch.publish(&state, &client).await
+        self.switch.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -142,3 +254,64 @@ impl EntityInstance for CapabilitySwitch {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::fixtures;
+
+    #[test]
+    fn boolean_capabilities() {
+        let devices = fixtures::devices(fixtures::LIST_DEVICES_ISSUE4);
+
+        let gradient = devices
+            .iter()
+            .find_map(|d| d.capability_by_instance("gradientToggle"))
+            .unwrap();
+        assert!(is_boolean_capability(gradient));
+        k9::assert_equal!(switch_name(&gradient.instance, false), "Gradient");
+
+        let oscillation = devices
+            .iter()
+            .find_map(|d| d.capability_by_instance("oscillationToggle"))
+            .unwrap();
+        assert!(is_boolean_capability(oscillation));
+        k9::assert_equal!(switch_name(&oscillation.instance, false), "Oscillate");
+
+        let scene = devices
+            .iter()
+            .find_map(|d| d.capability_by_instance("nightlightScene"))
+            .unwrap();
+        assert!(!is_boolean_capability(scene));
+
+        k9::assert_equal!(switch_name("dreamViewToggle", false), "Dream View Toggle");
+    }
+
+    #[test]
+    fn lock_instances() {
+        let device = ServiceDevice::new("H7131", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(is_lock_instance(&device, "childLock"));
+        assert!(is_lock_instance(&device, "lockButtons"));
+        assert!(!is_lock_instance(&device, "gradientToggle"));
+        k9::assert_equal!(switch_name("lockButtons", true), "Child Lock");
+        k9::assert_equal!(switch_name("displayToggle", false), "Display");
+        k9::assert_equal!(switch_name("beepToggle", false), "Beep");
+        assert!(is_beep_instance("buzzerToggle"));
+        assert!(!is_beep_instance("buzzer"));
+        k9::assert_equal!(switch_name("indicatorLight", false), "Indicator Light");
+        k9::assert_equal!(switch_name("statusLedToggle", false), "Indicator Light");
+        k9::assert_equal!(switch_name("dndToggle", false), "Do Not Disturb");
+        k9::assert_equal!(
+            switch_entity_category("indicatorLight", false),
+            Some("config".to_string())
+        );
+        k9::assert_equal!(
+            switch_entity_category("screenToggle", false),
+            Some("config".to_string())
+        );
+        k9::assert_equal!(
+            switch_entity_category("childLock", true),
+            Some("config".to_string())
+        );
+    }
+}
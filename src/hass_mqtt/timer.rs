@@ -0,0 +1,605 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::number::NumberConfig;
+use crate::hass_mqtt::select::SelectConfig;
+use crate::hass_mqtt::sensor::{SensorConfig, StateClass};
+use crate::platform_api::{DeviceCapability, DeviceParameters, EnumOption, IntegerRange};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, topic_safe_string, HassClient, IdAndInst,
+};
+use crate::service::state::StateHandle;
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use mosquitto_rs::router::{Params, Payload, State};
+use serde_json::{json, Value as JsonValue};
+
+/// Returns true if the capability is the countdown (auto-off) timer
+/// of a plug or appliance
+pub fn is_countdown_timer_instance(instance: &str) -> bool {
+    let instance = instance.to_ascii_lowercase();
+    (instance.contains("timer") || instance.contains("countdown")) && !instance.contains("remain")
+}
+
+/// Readings larger than this are unix timestamps of when the
+/// timer will expire, rather than a number of remaining seconds
+const MIN_TIMESTAMP: i64 = 1_000_000_000;
+
+fn timestamp_to_datetime(ts: i64) -> Option<DateTime<Utc>> {
+    if ts >= MIN_TIMESTAMP * 1000 {
+        Utc.timestamp_millis_opt(ts).single()
+    } else {
+        Utc.timestamp_opt(ts, 0).single()
+    }
+}
+
+/// Computes the number of seconds remaining on the timer from its
+/// reported state. The state is either a bare number, or a struct
+/// that pairs it with whether the timer is enabled. The number is
+/// either the remaining seconds or the time at which the timer
+/// expires, which is relative to `polled`, the time at which the
+/// state was reported. Returns 0 when no timer is active.
+fn countdown_remaining_seconds(value: &JsonValue, polled: DateTime<Utc>) -> Option<i64> {
+    let reading = match value {
+        JsonValue::Object(map) => {
+            let enabled = map.iter().find_map(|(k, v)| {
+                let k = k.to_ascii_lowercase();
+                if k.contains("enable") || k == "onoff" || k == "open" {
+                    v.as_i64().map(|v| v != 0).or_else(|| v.as_bool())
+                } else {
+                    None
+                }
+            });
+            if enabled == Some(false) {
+                return Some(0);
+            }
+            map.iter()
+                .find_map(|(k, v)| {
+                    let k = k.to_ascii_lowercase();
+                    if k.contains("remain")
+                        || k.contains("left")
+                        || k.contains("end")
+                        || k.contains("time")
+                        || k.contains("duration")
+                    {
+                        v.as_i64()
+                    } else {
+                        None
+                    }
+                })
+                .or_else(|| map.values().find_map(|v| v.as_i64()))?
+        }
+        v => v.as_i64()?,
+    };
+
+    let remaining = if reading >= MIN_TIMESTAMP {
+        (timestamp_to_datetime(reading)? - polled).num_seconds()
+    } else {
+        reading
+    };
+    Some(remaining.max(0))
+}
+
+/// Returns the number of whole minutes remaining on the timer,
+/// rounded up so that a timer with less than a minute to go doesn't
+/// show as inactive
+fn countdown_remaining_minutes(device: &ServiceDevice, instance: &str) -> i64 {
+    let polled = device
+        .last_http_device_state_update
+        .unwrap_or_else(Utc::now);
+    let remaining = device
+        .get_state_capability_by_instance(instance)
+        .and_then(|cap| cap.state.pointer("/value"))
+        .and_then(|value| countdown_remaining_seconds(value, polled))
+        .unwrap_or(0);
+    (remaining + 59) / 60
+}
+
+/// The number of seconds in one unit of the timer capability. The
+/// Platform API describes the unit as eg: `unit.second`; when it
+/// doesn't say, the unit is assumed to be minutes.
+fn timer_unit_seconds(unit: Option<&str>) -> i64 {
+    let unit = unit.unwrap_or_default().to_ascii_lowercase();
+    if unit.contains("sec") {
+        1
+    } else if unit.contains("hour") {
+        3600
+    } else {
+        60
+    }
+}
+
+/// The values that can be written to the timer capability
+enum TimerParameters {
+    /// Any value in the range, in units of `unit_seconds`
+    Range {
+        min: i64,
+        max: i64,
+        unit_seconds: i64,
+    },
+    /// Only these values, which are in minutes
+    Options(Vec<EnumOption>),
+}
+
+impl TimerParameters {
+    fn from_capability(instance: &DeviceCapability) -> Option<Self> {
+        match &instance.parameters {
+            Some(DeviceParameters::Integer {
+                unit,
+                range: IntegerRange { min, max, .. },
+            }) if min < max => Some(Self::Range {
+                min: *min as i64,
+                max: *max as i64,
+                unit_seconds: timer_unit_seconds(unit.as_deref()),
+            }),
+            Some(DeviceParameters::Enum { options }) if !options.is_empty() => {
+                Some(Self::Options(options.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Returns the value to write to a timer with a range, to have it
+/// expire after `minutes`. 0 cancels the timer; other values are
+/// clamped to the range.
+fn timer_range_value(min: i64, max: i64, unit_seconds: i64, minutes: i64) -> i64 {
+    if minutes <= 0 {
+        return 0;
+    }
+    // Round up, so that eg: 1 minute doesn't become 0 hours
+    let value = (minutes * 60 + unit_seconds - 1) / unit_seconds;
+    value.clamp(min, max)
+}
+
+/// Writes `value` to the timer, and then records that it expires
+/// after `minutes`, so that the remaining time is shown right away
+/// rather than after the next poll
+async fn set_countdown_timer(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    instance: &DeviceCapability,
+    value: JsonValue,
+    minutes: i64,
+) -> anyhow::Result<()> {
+    state
+        .device_control(device, instance, value)
+        .await
+        .context("set_countdown_timer: state.device_control")?;
+
+    let expires = if minutes > 0 {
+        Utc::now().timestamp() + minutes * 60
+    } else {
+        0
+    };
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .apply_capability_state(
+            instance.kind.clone(),
+            &instance.instance,
+            json!({"value": expires}),
+        );
+    state.notify_of_state_change(&device.id).await
+}
+
+/// Reports the time remaining on the countdown timer, in minutes
+pub struct CountdownTimerSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl CountdownTimerSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-{inst}-remaining",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Timer Remaining".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("duration"),
+                    icon: Some("mdi:timer-outline".to_string()),
+                },
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some("min"),
+                suggested_display_precision: None,
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for CountdownTimerSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let minutes = countdown_remaining_minutes(&device, &self.instance_name);
+        self.sensor.notify_state(client, &minutes.to_string()).await
+    }
+}
+
+/// Sets the countdown timer of a timer capability that accepts a
+/// range of values, in minutes. Its state is the time remaining, so
+/// it counts down along with the Timer Remaining sensor.
+pub struct CountdownTimerNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl CountdownTimerNumber {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Option<Self> {
+        let Some(TimerParameters::Range {
+            max, unit_seconds, ..
+        }) = TimerParameters::from_capability(instance)
+        else {
+            return None;
+        };
+
+        let command_topic = format!(
+            "{prefix}/number/{id}/set-timer/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = instance.instance
+        );
+        let state_topic = format!(
+            "{prefix}/number/{id}/timer/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}-timer",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Some(Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Auto-Off Timer".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:timer-cog-outline".to_string()),
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                // 0 cancels the timer, regardless of the range
+                min: Some(0.),
+                max: Some((max * unit_seconds / 60) as f32),
+                step: ((unit_seconds / 60).max(1)) as f32,
+                unit_of_measurement: Some("min"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for CountdownTimerNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let minutes = countdown_remaining_minutes(&device, &self.instance_name);
+        self.number.notify_state(client, &minutes.to_string()).await
+    }
+}
+
+pub async fn mqtt_set_countdown_timer(
+    Payload(minutes): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_countdown_timer: {id}: {instance} {minutes}");
+    let Some(device) = state.resolve_device_for_command(&id, &instance).await? else {
+        return Ok(());
+    };
+
+    let cap = device
+        .get_capability_by_instance(&instance)
+        .ok_or_else(|| anyhow!("{device} has no {instance}"))?;
+    let Some(TimerParameters::Range {
+        min,
+        max,
+        unit_seconds,
+        ..
+    }) = TimerParameters::from_capability(cap)
+    else {
+        anyhow::bail!("{device} {instance} doesn't accept a range of values");
+    };
+
+    let minutes: f64 = minutes
+        .trim()
+        .parse()
+        .with_context(|| format!("parsing {minutes}"))?;
+    let minutes = minutes.round() as i64;
+    let value = timer_range_value(min, max, unit_seconds, minutes);
+
+    set_countdown_timer(
+        &state,
+        &device,
+        cap,
+        json!(value),
+        value * unit_seconds / 60,
+    )
+    .await
+}
+
+/// The option of the timer select that cancels the timer, when the
+/// capability doesn't have an option with the value 0 of its own
+pub const TIMER_OFF_OPTION: &str = "Off";
+
+/// Returns the timer option that cancels the timer, and the others
+/// along with their duration in minutes
+fn timer_select_options(options: &[EnumOption]) -> (String, Vec<(String, i64)>) {
+    let mut off = TIMER_OFF_OPTION.to_string();
+    let mut durations = vec![];
+    for opt in options {
+        match opt.value.as_i64() {
+            Some(0) => off = opt.name.to_string(),
+            Some(minutes) => durations.push((opt.name.to_string(), minutes)),
+            None => {}
+        }
+    }
+    durations.sort_by_key(|(_, minutes)| *minutes);
+    (off, durations)
+}
+
+/// Returns the option to show for a timer with `remaining` minutes
+/// to go: the shortest duration that covers it, which is the one that
+/// was chosen, unless the timer was set some other way
+fn timer_option_for_remaining(off: &str, durations: &[(String, i64)], remaining: i64) -> String {
+    if remaining <= 0 {
+        return off.to_string();
+    }
+    durations
+        .iter()
+        .find(|(_, minutes)| *minutes >= remaining)
+        .or_else(|| durations.last())
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| off.to_string())
+}
+
+/// Sets the countdown timer of a timer capability that only accepts
+/// a few durations, such as 30, 60 or 120 minutes
+pub struct CountdownTimerSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+    off: String,
+    durations: Vec<(String, i64)>,
+}
+
+impl CountdownTimerSelect {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Option<Self> {
+        let Some(TimerParameters::Options(options)) = TimerParameters::from_capability(instance)
+        else {
+            return None;
+        };
+        let (off, durations) = timer_select_options(&options);
+        if durations.is_empty() {
+            return None;
+        }
+
+        let command_topic = format!(
+            "{prefix}/select/{id}/set-timer/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = instance.instance
+        );
+        let state_topic = format!(
+            "{prefix}/select/{id}/timer/{inst}",
+            prefix = topic_prefix(),
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}-timer",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        let mut select_options = vec![off.to_string()];
+        select_options.extend(durations.iter().map(|(name, _)| name.to_string()));
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Auto-Off Timer".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:timer-cog-outline".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: select_options,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+            off,
+            durations,
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for CountdownTimerSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let remaining = countdown_remaining_minutes(&device, &self.instance_name);
+        let option = timer_option_for_remaining(&self.off, &self.durations, remaining);
+        client.publish(&self.select.state_topic, option).await
+    }
+}
+
+pub async fn mqtt_set_countdown_timer_option(
+    Payload(option): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_countdown_timer_option: {id}: {instance} {option}");
+    let Some(device) = state.resolve_device_for_command(&id, &instance).await? else {
+        return Ok(());
+    };
+
+    let cap = device
+        .get_capability_by_instance(&instance)
+        .ok_or_else(|| anyhow!("{device} has no {instance}"))?;
+    let Some(TimerParameters::Options(options)) = TimerParameters::from_capability(cap) else {
+        anyhow::bail!("{device} {instance} doesn't have a list of durations");
+    };
+    let (off, durations) = timer_select_options(&options);
+
+    let minutes = if option == off {
+        0
+    } else {
+        durations
+            .iter()
+            .find(|(name, _)| *name == option)
+            .map(|(_, minutes)| *minutes)
+            .ok_or_else(|| anyhow!("{option} is not a valid {instance} for {device}"))?
+    };
+
+    set_countdown_timer(&state, &device, cap, json!(minutes), minutes).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn remaining_seconds() {
+        let polled = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+
+        k9::assert_equal!(countdown_remaining_seconds(&json!(90), polled), Some(90));
+        k9::assert_equal!(
+            countdown_remaining_seconds(&json!(1_700_000_600), polled),
+            Some(600)
+        );
+        k9::assert_equal!(
+            countdown_remaining_seconds(&json!(1_700_000_600_000i64), polled),
+            Some(600)
+        );
+        k9::assert_equal!(
+            countdown_remaining_seconds(&json!(1_699_999_000), polled),
+            Some(0)
+        );
+        k9::assert_equal!(
+            countdown_remaining_seconds(&json!({"enable": 1, "remainTime": 300}), polled),
+            Some(300)
+        );
+        k9::assert_equal!(
+            countdown_remaining_seconds(&json!({"enable": 0, "remainTime": 300}), polled),
+            Some(0)
+        );
+        k9::assert_equal!(countdown_remaining_seconds(&json!(""), polled), None);
+
+        assert!(is_countdown_timer_instance("timer"));
+        assert!(is_countdown_timer_instance("countdownTimer"));
+        assert!(!is_countdown_timer_instance("keepWarmRemainingTime"));
+    }
+
+    #[test]
+    fn range_value() {
+        // A timer in seconds
+        k9::assert_equal!(timer_range_value(0, 86400, 1, 30), 1800);
+        k9::assert_equal!(timer_range_value(60, 86400, 1, 0), 0);
+        k9::assert_equal!(timer_range_value(0, 3600, 1, 120), 3600);
+        // A timer in hours
+        k9::assert_equal!(timer_range_value(1, 12, 3600, 90), 2);
+        k9::assert_equal!(timer_unit_seconds(Some("unit.second")), 1);
+        k9::assert_equal!(timer_unit_seconds(None), 60);
+    }
+
+    #[test]
+    fn select_options() {
+        let option = |name: &str, value: i64| EnumOption {
+            name: name.to_string(),
+            value: json!(value),
+            extras: Default::default(),
+        };
+        let (off, durations) =
+            timer_select_options(&[option("2h", 120), option("30min", 30), option("1h", 60)]);
+        k9::assert_equal!(off, "Off");
+        k9::assert_equal!(
+            durations,
+            vec![
+                ("30min".to_string(), 30),
+                ("1h".to_string(), 60),
+                ("2h".to_string(), 120)
+            ]
+        );
+
+        k9::assert_equal!(timer_option_for_remaining(&off, &durations, 0), "Off");
+        k9::assert_equal!(timer_option_for_remaining(&off, &durations, 45), "1h");
+        k9::assert_equal!(timer_option_for_remaining(&off, &durations, 60), "1h");
+        k9::assert_equal!(timer_option_for_remaining(&off, &durations, 500), "2h");
+
+        let (off, _) = timer_select_options(&[option("Cancel", 0), option("1h", 60)]);
+        k9::assert_equal!(off, "Cancel");
+    }
+}
@@ -0,0 +1,88 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{availability_topic, topic_prefix, topic_safe_id, HassClient};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+
+/// An update entity that reports the firmware version of a device.
+/// There is no command_topic, as installing updates is done via
+/// the Govee app.
+#[derive(Serialize, Clone, Debug)]
+pub struct UpdateConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub state_topic: String,
+}
+
+impl UpdateConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("update", state, client, &self.base, self).await
+    }
+}
+
+pub struct FirmwareUpdate {
+    update: UpdateConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl FirmwareUpdate {
+    /// Returns None for devices that don't report their version
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        device.firmware_version()?;
+
+        let unique_id = format!("gv2mqtt-{id}-firmware", id = topic_safe_id(device));
+        Some(Self {
+            update: UpdateConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Firmware".to_string()),
+                    device_class: Some("firmware"),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    entity_category: Some("diagnostic".to_string()),
+                    icon: None,
+                },
+                state_topic: format!("{prefix}/update/{unique_id}/state", prefix = topic_prefix()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FirmwareUpdate {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.update.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(version) = device.firmware_version() else {
+            return Ok(());
+        };
+
+        // The device list doesn't tell us about pending updates, so
+        // the only version that we know about is the installed one
+        client
+            .publish_obj(
+                &self.update.state_topic,
+                json!({
+                    "installed_version": version,
+                    "latest_version": version,
+                }),
+            )
+            .await
+    }
+}
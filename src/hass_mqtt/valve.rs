@@ -0,0 +1,262 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::sensor::{SensorConfig, StateClass};
+use crate::platform_api::{DeviceCapability, DeviceParameters};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, topic_prefix, topic_safe_id, topic_safe_string, HassClient, IdAndInst,
+};
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+/// The platform API instance used by the water timers to start/stop watering
+pub const VALVE_INSTANCE: &str = "watering";
+/// The struct field that carries the watering run time
+pub const VALVE_DURATION_FIELD: &str = "duration";
+
+pub fn is_valve_instance(instance: &str) -> bool {
+    instance == VALVE_INSTANCE
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ValveConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+    pub command_topic: String,
+    pub state_topic: String,
+    pub payload_open: String,
+    pub payload_close: String,
+    pub state_open: String,
+    pub state_closed: String,
+}
+
+impl ValveConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("valve", state, client, &self.base, self).await
+    }
+}
+
+/// Extracts the open state and the remaining run time from the
+/// reported state of the watering capability.
+/// The value is either a plain integer, or a struct whose duration
+/// field is the remaining run time and whose other field is the on/off state.
+fn parse_valve_state(value: &JsonValue) -> (Option<bool>, Option<i64>) {
+    match value {
+        JsonValue::Object(map) => {
+            let duration = map.get(VALVE_DURATION_FIELD).and_then(|v| v.as_i64());
+            let open = map
+                .iter()
+                .find(|(k, _)| k.as_str() != VALVE_DURATION_FIELD)
+                .and_then(|(_, v)| v.as_i64())
+                .map(|v| v != 0);
+            (open, duration)
+        }
+        v => (v.as_i64().map(|v| v != 0), None),
+    }
+}
+
+pub struct DeviceValve {
+    valve: ValveConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl DeviceValve {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let id = topic_safe_id(device);
+        let inst = topic_safe_string(&instance.instance);
+        let unique_id = format!("gv2mqtt-{id}-{inst}");
+
+        Self {
+            valve: ValveConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: None,
+                    device_class: Some("water"),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    entity_category: None,
+                    icon: None,
+                },
+                command_topic: format!(
+                    "{prefix}/valve/{id}/command/{inst}",
+                    prefix = topic_prefix(),
+                    inst = instance.instance
+                ),
+                state_topic: format!("{prefix}/valve/{unique_id}/state", prefix = topic_prefix()),
+                payload_open: "ON".to_string(),
+                payload_close: "OFF".to_string(),
+                state_open: "ON".to_string(),
+                state_closed: "OFF".to_string(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DeviceValve {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.valve.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
+            if let Some(value) = cap.state.pointer("/value") {
+                if let (Some(open), _) = parse_valve_state(value) {
+                    return client
+                        .publish(&self.valve.state_topic, if open { "ON" } else { "OFF" })
+                        .await;
+                }
+            }
+        }
+
+        log::trace!(
+            "DeviceValve::notify_state: didn't find state for {device} {instance}",
+            instance = self.instance_name
+        );
+        Ok(())
+    }
+}
+
+/// Reports the remaining watering time for valves whose
+/// capability carries a duration field
+pub struct ValveRemainingSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl ValveRemainingSensor {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Option<Self> {
+        let field = instance.struct_field_by_name(VALVE_DURATION_FIELD)?;
+
+        let unit_of_measurement = match &field.field_type {
+            DeviceParameters::Integer { unit, .. } => match unit.as_deref() {
+                Some("unit.minute") => "min",
+                Some("unit.hour") => "h",
+                _ => "s",
+            },
+            _ => "s",
+        };
+
+        let unique_id = format!(
+            "sensor-{id}-{inst}-remaining",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Some(Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Remaining Run Time".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("duration"),
+                    icon: Some("mdi:timer-sand".to_string()),
+                },
+                state_topic: format!("{prefix}/sensor/{unique_id}/state", prefix = topic_prefix()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some(unit_of_measurement),
+                suggested_display_precision: None,
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ValveRemainingSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
+            if let Some(value) = cap.state.pointer("/value") {
+                let (open, remaining) = parse_valve_state(value);
+                let remaining = match (open, remaining) {
+                    (Some(false), _) => 0,
+                    (_, Some(remaining)) => remaining,
+                    (_, None) => return Ok(()),
+                };
+                return self
+                    .sensor
+                    .notify_state(client, &remaining.to_string())
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_valve_command(
+    Payload(command): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{id}: valve {instance} command {command}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let open = match command.as_str() {
+        "ON" | "on" => true,
+        "OFF" | "off" => false,
+        _ => anyhow::bail!("invalid {command} for {id}"),
+    };
+
+    state
+        .device_set_valve(&device, &instance, open)
+        .await
+        .context("mqtt_valve_command: state.device_set_valve")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn valve_state() {
+        assert_eq!(parse_valve_state(&json!(1)), (Some(true), None));
+        assert_eq!(parse_valve_state(&json!(0)), (Some(false), None));
+        assert_eq!(
+            parse_valve_state(&json!({"onOff": 1, "duration": 120})),
+            (Some(true), Some(120))
+        );
+        assert_eq!(parse_valve_state(&json!("")), (None, None));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::platform_api::{DeviceCapability, DeviceParameters, EnumOption};
 use crate::service::device::Device as ServiceDevice;
+use crate::service::quirks::Quirk;
 use anyhow::anyhow;
 use serde::Deserialize;
 use serde_json::Value as JsonValue;
@@ -32,26 +33,20 @@ impl ParsedWorkMode {
             .struct_field_by_name("workMode")
             .ok_or_else(|| anyhow!("workMode not found in {cap:?}"))?;
 
-        match &wm.field_type {
-            DeviceParameters::Enum { options } => {
-                for opt in options {
-                    work_modes.add(opt.name.to_string(), opt.value.clone());
-                }
+        if let DeviceParameters::Enum { options } = &wm.field_type {
+            for opt in options {
+                work_modes.add(opt.name.to_string(), opt.value.clone());
             }
-            _ => {}
         }
 
         if let Some(mv) = cap.struct_field_by_name("modeValue") {
-            match &mv.field_type {
-                DeviceParameters::Enum { options } => {
-                    for opt in options {
-                        let mode_name = &opt.name;
-                        if let Some(work_mode) = work_modes.get_mut(mode_name) {
-                            work_mode.add_values(opt);
-                        }
+            if let DeviceParameters::Enum { options } = &mv.field_type {
+                for opt in options {
+                    let mode_name = &opt.name;
+                    if let Some(work_mode) = work_modes.get_mut(mode_name) {
+                        work_mode.add_values(opt);
                     }
                 }
-                _ => {}
             }
         }
         Ok(work_modes)
@@ -75,19 +70,19 @@ impl ParsedWorkMode {
     pub fn adjust_for_device(&mut self, sku: &str) {
         match sku {
             "H7160" | "H7143" => {
-                self.modes
-                    .get_mut("Manual")
-                    .map(|m| m.label = "Manual: Mist Level".to_string());
+                if let Some(m) = self.modes.get_mut("Manual") {
+                    m.label = "Manual: Mist Level".to_string();
+                }
             }
             "H7131" => {
-                self.modes.get_mut("gearMode").map(|m| {
+                if let Some(m) = self.modes.get_mut("gearMode") {
                     m.label = "Heat".to_string();
-                });
+                }
             }
             "H7173" => {
-                self.modes.get_mut("gearMode").map(|m| {
+                if let Some(m) = self.modes.get_mut("gearMode") {
                     m.label = "Heat".to_string();
-                });
+                }
             }
             _ => {
                 for mode in self.modes.values_mut() {
@@ -98,12 +93,25 @@ impl ParsedWorkMode {
     }
 
     pub fn mode_for_value(&self, value: &JsonValue) -> Option<&WorkMode> {
-        for mode in self.modes.values() {
-            if mode.value == *value {
-                return Some(mode);
+        self.modes
+            .values()
+            .find(|&mode| mode.value == *value)
+            .map(|v| v as _)
+    }
+
+    /// Maps the workMode and modeValue pair reported in the device
+    /// state back to the name of the mode. Pairs that don't correspond
+    /// to a mode that we know about map to UNKNOWN_WORK_MODE.
+    pub fn name_for_state(&self, work_mode: &JsonValue, mode_value: Option<&JsonValue>) -> String {
+        let Some(mode) = self.mode_for_value(work_mode) else {
+            return UNKNOWN_WORK_MODE.to_string();
+        };
+        if let Some(value) = mode_value.and_then(|v| v.as_i64()) {
+            if !mode.accepts_value(value) {
+                return UNKNOWN_WORK_MODE.to_string();
             }
         }
-        None
+        mode.name.to_string()
     }
 
     pub fn mode_by_name(&self, name: &str) -> Option<&WorkMode> {
@@ -112,12 +120,18 @@ impl ParsedWorkMode {
 
     #[allow(unused)]
     pub fn mode_by_label(&self, name: &str) -> Option<&WorkMode> {
-        for mode in self.modes.values() {
-            if mode.label() == name {
-                return Some(mode);
-            }
-        }
-        None
+        self.modes
+            .values()
+            .find(|&mode| mode.label() == name)
+            .map(|v| v as _)
+    }
+
+    /// Returns the mode that halts the current cycle, for appliances
+    /// such as ice makers that have one
+    pub fn stop_mode(&self) -> Option<&WorkMode> {
+        self.modes
+            .values()
+            .find(|&mode| mode.name.eq_ignore_ascii_case("stop"))
     }
 
     pub fn get_mode_names(&self) -> Vec<String> {
@@ -143,14 +157,98 @@ impl ParsedWorkMode {
 
     #[allow(unused)]
     pub fn modes_with_values(&self) -> impl Iterator<Item = &WorkMode> {
-        self.modes.values().filter_map(|mode| {
-            if mode.values.is_empty() {
-                None
-            } else {
-                Some(mode)
-            }
+        self.modes.values().filter(|mode| !mode.values.is_empty())
+    }
+}
+
+/// The name shown by the work mode select when the device reports
+/// a mode that we don't know about
+pub const UNKNOWN_WORK_MODE: &str = "Unknown";
+
+/// The work mode in which the mist level of a humidifier is set
+const MIST_WORK_MODE: &str = "Manual";
+
+/// A named mist level of a humidifier
+#[derive(Clone, Debug, PartialEq)]
+pub struct MistPreset {
+    pub name: String,
+    pub value: i64,
+}
+
+/// Returns the work mode in which the mist level of a humidifier is
+/// set, along with the named presets for its levels.
+/// The presets come from the names of the mode values, when the
+/// capability has them, and otherwise from the quirk. Presets for
+/// levels that the mode doesn't accept are left out.
+pub fn mist_level_presets<'a>(
+    work_modes: &'a ParsedWorkMode,
+    quirk: Option<&Quirk>,
+) -> Option<(&'a WorkMode, Vec<MistPreset>)> {
+    let mode = work_modes
+        .modes
+        .values()
+        .find(|mode| mode.name.eq_ignore_ascii_case(MIST_WORK_MODE))?;
+
+    let mut presets: Vec<MistPreset> = mode
+        .values
+        .iter()
+        .filter_map(|v| {
+            Some(MistPreset {
+                name: v.name.clone()?,
+                value: v.value.as_i64()?,
+            })
         })
+        .collect();
+    if presets.is_empty() {
+        presets = quirk
+            .and_then(|q| q.mist_level_presets)
+            .unwrap_or_default()
+            .iter()
+            .filter(|(_, level)| mode.accepts_value(*level))
+            .map(|&(name, value)| MistPreset {
+                name: name.to_string(),
+                value,
+            })
+            .collect();
     }
+    presets.sort_by_key(|p| p.value);
+
+    if presets.is_empty() {
+        None
+    } else {
+        Some((mode, presets))
+    }
+}
+
+/// Returns the preset whose level is closest to `value`, so that a
+/// level without a preset of its own still selects one. A level that
+/// is midway between two presets selects the lower one.
+pub fn nearest_mist_preset(presets: &[MistPreset], value: i64) -> Option<&MistPreset> {
+    presets.iter().min_by_key(|p| (p.value - value).abs())
+}
+
+/// Returns the value of `work_mode` in the device state: the one that
+/// the device reports, when it is in that mode, or else the one that
+/// was most recently reported or set for it
+pub fn work_mode_param(device: &ServiceDevice, work_mode: &JsonValue) -> Option<i64> {
+    if let Some(cap) = device.get_state_capability_by_instance("workMode") {
+        if cap.state.pointer("/value/workMode") == Some(work_mode) {
+            if let Some(n) = cap
+                .state
+                .pointer("/value/modeValue")
+                .and_then(|v| v.as_i64())
+            {
+                return Some(n);
+            }
+        }
+    }
+
+    let work_mode = u8::try_from(work_mode.as_i64()?).ok()?;
+    // FIXME: assuming humidifier, rename that field?
+    device
+        .humidifier_param_by_mode
+        .get(&work_mode)
+        .map(|&n| n as i64)
 }
 
 #[derive(Default, Debug)]
@@ -237,7 +335,7 @@ impl WorkMode {
         self.default_value
             .as_ref()
             .and_then(|v| v.as_i64())
-            .or_else(|| self.values.get(0).and_then(|wmv| wmv.value.as_i64()))
+            .or_else(|| self.values.first().and_then(|wmv| wmv.value.as_i64()))
             .or_else(|| self.value_range.as_ref().map(|r| r.start))
             .unwrap_or(0)
     }
@@ -262,17 +360,27 @@ impl WorkMode {
         let min = *values.iter().min()?;
         let max = *values.iter().max()?;
 
-        let mut expect = min;
-        for item in values {
+        for (expect, item) in (min..).zip(values) {
             if item != expect {
                 return None;
             }
-            expect = expect + 1;
         }
 
         Some(min..max + 1)
     }
 
+    /// Returns true if the mode value is valid for this mode. Modes
+    /// that don't advertise their values accept any value.
+    pub fn accepts_value(&self, value: i64) -> bool {
+        if let Some(range) = &self.value_range {
+            return range.contains(&value);
+        }
+        if self.values.is_empty() {
+            return true;
+        }
+        self.values.iter().any(|v| v.value.as_i64() == Some(value))
+    }
+
     pub fn should_show_as_preset(&self) -> bool {
         self.contiguous_value_range().is_none() && self.values.is_empty()
     }
@@ -281,7 +389,7 @@ impl WorkMode {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::platform_api::{from_json, DeviceCapabilityKind, StructField};
+    use crate::platform_api::{fixtures, from_json, DeviceCapabilityKind, StructField};
     use serde_json::json;
     use std::collections::HashMap;
 
@@ -371,7 +479,7 @@ ParsedWorkMode {
     #[test]
     fn test_work_mode_parser2() {
         let cap: DeviceCapability =
-            from_json(&include_str!("../../test-data/work-mode-issue-81.json")).unwrap();
+            from_json(include_str!("../../test-data/work-mode-issue-81.json")).unwrap();
 
         let wm = ParsedWorkMode::with_capability(&cap).unwrap();
 
@@ -513,7 +621,7 @@ ParsedWorkMode {
     #[test]
     fn test_work_mode_parser4() {
         let cap: DeviceCapability =
-            from_json(&include_str!("../../test-data/work-mode-issue-93.json")).unwrap();
+            from_json(include_str!("../../test-data/work-mode-issue-93.json")).unwrap();
 
         let wm = ParsedWorkMode::with_capability(&cap).unwrap();
 
@@ -593,7 +701,7 @@ ParsedWorkMode {
     #[test]
     fn test_issue100() {
         let cap: DeviceCapability =
-            from_json(&include_str!("../../test-data/work-mode-issue-100.json")).unwrap();
+            from_json(include_str!("../../test-data/work-mode-issue-100.json")).unwrap();
 
         let mut wm = ParsedWorkMode::with_capability(&cap).unwrap();
         wm.adjust_for_device("H7173");
@@ -651,4 +759,71 @@ ParsedWorkMode {
         assert_eq!(wm.mode_by_name("Boiling").unwrap().default_value(), 0);
         assert_eq!(wm.mode_by_name("DIY").unwrap().default_value(), 1);
     }
+
+    #[test]
+    fn stop_mode() {
+        let mut wm = ParsedWorkMode::default();
+        wm.add("Small".to_string(), json!(1));
+        wm.add("Large".to_string(), json!(2));
+        assert!(wm.stop_mode().is_none());
+
+        wm.add("STOP".to_string(), json!(3));
+        assert_eq!(wm.stop_mode().unwrap().value, json!(3));
+    }
+
+    #[test]
+    fn name_for_state() {
+        let cap: DeviceCapability =
+            from_json(include_str!("../../test-data/work-mode-issue-100.json")).unwrap();
+        let mut wm = ParsedWorkMode::with_capability(&cap).unwrap();
+        wm.adjust_for_device("H7173");
+
+        assert_eq!(wm.name_for_state(&json!(4), Some(&json!(2))), "Coffee");
+        assert_eq!(
+            wm.name_for_state(&json!(4), Some(&json!(9))),
+            UNKNOWN_WORK_MODE
+        );
+        assert_eq!(wm.name_for_state(&json!(2), None), "Boiling");
+        assert_eq!(wm.name_for_state(&json!(42), None), UNKNOWN_WORK_MODE);
+    }
+
+    #[test]
+    fn mist_presets() {
+        let humidifier = fixtures::device(fixtures::LIST_DEVICES_ISSUE4, "H7141");
+        let wm =
+            ParsedWorkMode::with_capability(humidifier.capability_by_instance("workMode").unwrap())
+                .unwrap();
+
+        // Manual accepts levels 1-8, so there are no presets of its own
+        assert!(mist_level_presets(&wm, None).is_none());
+
+        let names = |presets: &[MistPreset]| {
+            presets
+                .iter()
+                .map(|p| (p.name.clone(), p.value))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            ("Low".to_string(), 1),
+            ("Medium".to_string(), 4),
+            ("High".to_string(), 8),
+        ];
+
+        // Levels that the mode doesn't accept are left out
+        let quirk = Quirk {
+            mist_level_presets: Some(&[("Low", 1), ("High", 8), ("Medium", 4), ("Turbo", 9)]),
+            ..Quirk::humidifier("H7141")
+        };
+        let (mode, presets) = mist_level_presets(&wm, Some(&quirk)).unwrap();
+        k9::assert_equal!(mode.value, json!(1));
+        k9::assert_equal!(names(&presets), expected);
+
+        let nearest = |value| nearest_mist_preset(&presets, value).map(|p| p.name.as_str());
+        k9::assert_equal!(nearest(1), Some("Low"));
+        k9::assert_equal!(nearest(3), Some("Medium"));
+        k9::assert_equal!(nearest(6), Some("Medium"));
+        k9::assert_equal!(nearest(7), Some("High"));
+        k9::assert_equal!(nearest(12), Some("High"));
+        k9::assert_equal!(nearest(0), Some("Low"));
+    }
 }
@@ -1,11 +1,16 @@
-use crate::ble::{Base64HexBytes, SetSceneCode};
+use crate::ble::{Base64HexBytes, SetSceneCode, SetSegmentBrightness, SetSegmentColor};
+use crate::logging::device_log;
+use crate::metrics::{self, Counter};
 use crate::opt_env_var;
 use crate::platform_api::from_json;
+use crate::service::device::DEFAULT_LAN_MAX_MISSED_POLLS;
 use crate::undoc_api::GoveeUndocumentedApi;
 use anyhow::Context;
 use if_addrs::IfAddr;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
@@ -26,7 +31,7 @@ const CMD_PORT: u16 = 4003;
 /// The multicast group of which govee LAN-API enabled devices are members
 const MULTICAST: IpAddr = IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250));
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Debug, Clone)]
 pub struct LanDiscoArguments {
     /// Prevent the use of the default multicast broadcast address.
     /// You may also set GOVEE_LAN_NO_MULTICAST=true via the environment.
@@ -54,6 +59,95 @@ pub struct LanDiscoArguments {
     /// You may also set GOVEE_LAN_DISCO_TIMEOUT via the environment.
     #[arg(long, default_value_t = 3, global = true)]
     disco_timeout: u64,
+
+    /// How many consecutive status requests a LAN device can fail
+    /// to respond to before it is marked as unavailable. The default is 3.
+    /// You may also set GOVEE_LAN_MAX_MISSED_POLLS via the environment.
+    #[arg(long, global = true)]
+    lan_max_missed_polls: Option<u32>,
+
+    /// How often, in seconds, to send discovery packets. By default,
+    /// they are sent frequently at startup, backing off to once a minute.
+    /// You may also set GOVEE_LAN_SCAN_INTERVAL via the environment.
+    #[arg(long, global = true)]
+    lan_scan_interval: Option<u64>,
+
+    /// In addition to broadcasting, send discovery packets directly
+    /// to the address of each device that we have already discovered,
+    /// so that they continue to be found if broadcasts are unreliable.
+    /// You may also set GOVEE_LAN_UNICAST_KNOWN=true via the environment.
+    #[arg(long, global = true)]
+    lan_unicast_known: bool,
+
+    /// A device to communicate with directly at a known address, for
+    /// example, one that is on a different network segment that our
+    /// discovery packets cannot reach. Specify either `IP` or
+    /// `IP=SKU/ID`; with the SKU and device id the device is usable
+    /// even if it has never responded to a discovery packet.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_LAN_DEVICES=10.0.0.1,10.0.0.2=H6199/AA:BB:CC:DD:EE:FF:00:11
+    /// via the environment.
+    #[arg(long, global = true)]
+    lan_device: Vec<StaticLanDevice>,
+
+    /// The name or local IPv4 address of a network interface on which
+    /// to listen for and send discovery packets, rather than relying
+    /// on the system to pick one. Can be specified multiple times.
+    /// You may also set GOVEE_LAN_INTERFACES=eth0,wlan0 via the environment.
+    #[arg(long, global = true)]
+    lan_interface: Vec<String>,
+}
+
+/// A LAN device whose address is configured, rather than discovered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticLanDevice {
+    pub ip: IpAddr,
+    /// The SKU and device id, if they were configured
+    pub identity: Option<(String, String)>,
+}
+
+impl std::str::FromStr for StaticLanDevice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (ip, identity) = match s.split_once('=') {
+            Some((ip, identity)) => {
+                let (sku, id) = identity
+                    .split_once('/')
+                    .ok_or_else(|| anyhow::anyhow!("expected IP=SKU/ID, but got {s}"))?;
+                let (sku, id) = (sku.trim(), id.trim());
+                if sku.is_empty() || id.is_empty() {
+                    anyhow::bail!("expected IP=SKU/ID, but got {s}");
+                }
+                (ip, Some((sku.to_string(), id.to_string())))
+            }
+            None => (s, None),
+        };
+        let ip = ip
+            .trim()
+            .parse()
+            .with_context(|| format!("parsing {ip} as IpAddr"))?;
+        Ok(Self { ip, identity })
+    }
+}
+
+impl StaticLanDevice {
+    /// Returns a LanDevice standing in for the one that we would
+    /// discover, if the identity of the device was configured.
+    /// The version information is only known once the device has
+    /// responded to a scan.
+    pub fn to_lan_device(&self) -> Option<LanDevice> {
+        let (sku, id) = self.identity.as_ref()?;
+        Some(LanDevice {
+            ip: self.ip,
+            device: id.to_string(),
+            sku: sku.to_string(),
+            ble_version_hard: String::new(),
+            ble_version_soft: String::new(),
+            wifi_version_hard: String::new(),
+            wifi_version_soft: String::new(),
+        })
+    }
 }
 
 pub fn truthy(s: &str) -> anyhow::Result<bool> {
@@ -81,6 +175,14 @@ impl LanDiscoArguments {
             additional_addresses: self.scan.clone(),
             broadcast_all_interfaces: self.broadcast_all,
             global_broadcast: self.global_broadcast,
+            scan_interval: self.lan_scan_interval.map(Duration::from_secs),
+            unicast_known_devices: self.lan_unicast_known,
+            static_addresses: self
+                .static_devices()?
+                .into_iter()
+                .map(|device| device.ip)
+                .collect(),
+            interfaces: self.lan_interface.clone(),
         };
 
         if let Some(v) = opt_env_var::<String>("GOVEE_LAN_NO_MULTICAST")? {
@@ -95,6 +197,26 @@ impl LanDiscoArguments {
             options.global_broadcast = truthy(&v)?;
         }
 
+        if let Some(v) = opt_env_var("GOVEE_LAN_SCAN_INTERVAL")? {
+            options.scan_interval.replace(Duration::from_secs(v));
+        }
+        if options.scan_interval == Some(Duration::ZERO) {
+            anyhow::bail!("the LAN scan interval must be at least 1 second");
+        }
+
+        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_UNICAST_KNOWN")? {
+            options.unicast_known_devices = truthy(&v)?;
+        }
+
+        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_INTERFACES")? {
+            options.interfaces.extend(
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string),
+            );
+        }
+
         if let Some(v) = opt_env_var::<String>("GOVEE_LAN_SCAN")? {
             for addr in v.split(',') {
                 let ip = addr
@@ -108,6 +230,32 @@ impl LanDiscoArguments {
         Ok(options)
     }
 
+    /// Returns the statically configured LAN devices. The environment
+    /// is consulted each time, so that changes made to it when the
+    /// configuration is reloaded are picked up.
+    pub fn static_devices(&self) -> anyhow::Result<Vec<StaticLanDevice>> {
+        let mut devices = self.lan_device.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_DEVICES")? {
+            for entry in v.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                devices.push(entry.parse()?);
+            }
+        }
+        Ok(devices)
+    }
+
+    pub fn max_missed_polls(&self) -> anyhow::Result<u32> {
+        let n = match self.lan_max_missed_polls {
+            Some(n) => n,
+            None => {
+                opt_env_var("GOVEE_LAN_MAX_MISSED_POLLS")?.unwrap_or(DEFAULT_LAN_MAX_MISSED_POLLS)
+            }
+        };
+        if n == 0 {
+            anyhow::bail!("the LAN max missed polls must be at least 1");
+        }
+        Ok(n)
+    }
+
     pub fn disco_timeout(&self) -> anyhow::Result<u64> {
         if let Some(v) = opt_env_var("GOVEE_LAN_DISCO_TIMEOUT")? {
             Ok(v)
@@ -128,6 +276,18 @@ pub struct DiscoOptions {
     pub broadcast_all_interfaces: bool,
     /// Broadcast to the global broadcast address
     pub global_broadcast: bool,
+    /// Send discovery packets at this fixed interval, rather than
+    /// backing off
+    pub scan_interval: Option<Duration>,
+    /// Also send discovery packets to each device that has already
+    /// been discovered
+    pub unicast_known_devices: bool,
+    /// The addresses of statically configured devices, to which
+    /// discovery packets are always sent directly
+    pub static_addresses: Vec<IpAddr>,
+    /// The names or addresses of the interfaces to use. If empty,
+    /// the system picks the interface for each packet.
+    pub interfaces: Vec<String>,
 }
 
 impl DiscoOptions {
@@ -136,6 +296,7 @@ impl DiscoOptions {
             && self.additional_addresses.is_empty()
             && !self.broadcast_all_interfaces
             && !self.global_broadcast
+            && self.static_addresses.is_empty()
     }
 }
 
@@ -146,10 +307,28 @@ impl Default for DiscoOptions {
             additional_addresses: vec![],
             broadcast_all_interfaces: false,
             global_broadcast: false,
+            scan_interval: None,
+            unicast_known_devices: false,
+            static_addresses: vec![],
+            interfaces: vec![],
         }
     }
 }
 
+/// Discovery packets are initially sent at this interval,
+/// doubling each time up to MAX_SCAN_INTERVAL
+const INITIAL_SCAN_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+impl DiscoOptions {
+    /// Returns how long to wait before sending the next discovery
+    /// packets, given how long we waited since the prior ones
+    fn next_scan_interval(&self, current: Duration) -> Duration {
+        self.scan_interval
+            .unwrap_or_else(|| (current * 2).min(MAX_SCAN_INTERVAL))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "cmd", content = "data")]
 pub enum Request {
@@ -193,12 +372,21 @@ pub struct LanDevice {
 
 impl LanDevice {
     pub async fn send_request(&self, msg: Request) -> anyhow::Result<()> {
-        log::trace!("LanDevice::send_request to {:?} {msg:?}", self.ip);
-        let client = udp_socket_for_target(self.ip).await?;
-        let data = serde_json::to_string(&RequestMessage { msg })?;
-        client.send_to(data.as_bytes(), (self.ip, CMD_PORT)).await?;
-
-        Ok(())
+        device_log!(
+            log::Level::Trace,
+            &self.device,
+            "LanDevice::send_request to {:?} {msg:?}",
+            self.ip
+        );
+        let result = async {
+            let client = udp_socket_for_target(self.ip).await?;
+            let data = serde_json::to_string(&RequestMessage { msg })?;
+            client.send_to(data.as_bytes(), (self.ip, CMD_PORT)).await?;
+            metrics::increment(Counter::LanPacketsSent, &[("sku", &self.sku)]);
+            Ok(())
+        }
+        .await;
+        metrics::track_command("lan", &self.sku, result)
     }
 
     pub async fn send_turn(&self, on: bool) -> anyhow::Result<()> {
@@ -237,6 +425,37 @@ impl LanDevice {
         .await
     }
 
+    pub async fn send_segment_rgb(&self, segments: &[u32], rgb: u32) -> anyhow::Result<()> {
+        let [_, r, g, b] = rgb.to_be_bytes();
+        let encoded = Base64HexBytes::encode_for_sku(
+            "Generic:Light",
+            &SetSegmentColor {
+                segments: segments.to_vec(),
+                r,
+                g,
+                b,
+            },
+        )?
+        .base64();
+        self.send_real(encoded).await
+    }
+
+    pub async fn send_segment_brightness(
+        &self,
+        segments: &[u32],
+        percent: u8,
+    ) -> anyhow::Result<()> {
+        let encoded = Base64HexBytes::encode_for_sku(
+            "Generic:Light",
+            &SetSegmentBrightness {
+                segments: segments.to_vec(),
+                percent,
+            },
+        )?
+        .base64();
+        self.send_real(encoded).await
+    }
+
     pub async fn set_scene_by_name(&self, scene_name: &str) -> anyhow::Result<()> {
         for category in GoveeUndocumentedApi::get_scenes_for_device(&self.sku).await? {
             for scene in category.scenes {
@@ -321,6 +540,12 @@ struct ClientListener {
 #[derive(Default)]
 struct ClientInner {
     mux: Mutex<Vec<ClientListener>>,
+    /// The addresses of the devices that have been discovered
+    known_addresses: Mutex<HashSet<IpAddr>>,
+    /// The addresses of the statically configured devices
+    static_addresses: Mutex<Vec<IpAddr>>,
+    /// When a device most recently responded to a discovery scan
+    last_scan_response: parking_lot::Mutex<Option<chrono::DateTime<chrono::Utc>>>,
 }
 
 #[derive(Clone)]
@@ -334,7 +559,17 @@ struct Broadcaster {
     socket: UdpSocket,
 }
 
+/// When specific interfaces are configured, the local address at which
+/// we last heard from each device, so that requests to the device are
+/// sent out of the interface via which it can be reached
+static LOCAL_ADDRESSES: Lazy<parking_lot::Mutex<HashMap<IpAddr, Ipv4Addr>>> =
+    Lazy::new(Default::default);
+
 async fn udp_socket_for_target(addr: IpAddr) -> std::io::Result<UdpSocket> {
+    let local = LOCAL_ADDRESSES.lock().get(&addr).copied();
+    if let Some(local) = local {
+        return UdpSocket::bind((local, 0)).await;
+    }
     match addr {
         IpAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await,
         IpAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await,
@@ -344,11 +579,21 @@ async fn udp_socket_for_target(addr: IpAddr) -> std::io::Result<UdpSocket> {
 impl Broadcaster {
     pub async fn new(addr: IpAddr) -> std::io::Result<Self> {
         let socket = udp_socket_for_target(addr).await?;
+        Self::with_socket(addr, socket, Ipv4Addr::UNSPECIFIED)
+    }
+
+    /// Creates a broadcaster that sends via the interface that
+    /// has the address `local`
+    pub async fn on_interface(addr: IpAddr, local: Ipv4Addr) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind((local, 0)).await?;
+        Self::with_socket(addr, socket, local)
+    }
 
+    fn with_socket(addr: IpAddr, socket: UdpSocket, interface: Ipv4Addr) -> std::io::Result<Self> {
         if addr.is_multicast() {
             match addr {
                 IpAddr::V4(v4) => {
-                    socket.join_multicast_v4(v4, Ipv4Addr::UNSPECIFIED)?;
+                    socket.join_multicast_v4(v4, interface)?;
                     socket.set_multicast_loop_v4(false)?;
                 }
                 IpAddr::V6(v6) => {
@@ -367,11 +612,85 @@ impl Broadcaster {
         self.socket
             .send_to(bytes.as_ref(), (self.addr, SCAN_PORT))
             .await?;
+        metrics::increment(Counter::LanPacketsSent, &[]);
         Ok(())
     }
 }
 
-async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
+fn scan_message() -> String {
+    serde_json::to_string(&RequestMessage {
+        msg: Request::Scan {
+            account_topic: AccountTopic::Reserve,
+        },
+    })
+    .expect("to serialize scan message")
+}
+
+/// Sends discovery packets directly to each of the addresses.
+/// Responses arrive via the discovery listener, the same as those
+/// to broadcasts.
+async fn send_unicast_scans(addresses: Vec<IpAddr>) {
+    let scan = scan_message();
+    for addr in addresses {
+        log::trace!("Send unicast disco packet to {addr}");
+        let result = match Broadcaster::new(addr).await {
+            Ok(b) => b.broadcast(&scan).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            log::error!("Error sending disco packet to {addr}: {err:#}");
+        }
+    }
+}
+
+/// Resolves the configured interface names and addresses to the local
+/// IPv4 addresses of those interfaces, given the (name, address) of
+/// each of the interfaces on the system. Entries that don't match any
+/// interface are reported and skipped.
+fn resolve_interfaces(configured: &[String], ifaces: &[(String, IpAddr)]) -> Vec<Ipv4Addr> {
+    let mut addresses = vec![];
+    for entry in configured {
+        let entry_ip: Option<IpAddr> = entry.parse().ok();
+        let matched: Vec<Ipv4Addr> = ifaces
+            .iter()
+            .filter(|(name, ip)| name == entry || entry_ip == Some(*ip))
+            .filter_map(|(_, ip)| match ip {
+                IpAddr::V4(v4) => Some(*v4),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+        if matched.is_empty() {
+            log::warn!("LAN interface {entry} doesn't exist or has no IPv4 address; ignoring it");
+        }
+        for addr in matched {
+            if !addresses.contains(&addr) {
+                addresses.push(addr);
+            }
+        }
+    }
+    addresses
+}
+
+fn local_interface_addresses(configured: &[String]) -> Vec<Ipv4Addr> {
+    if configured.is_empty() {
+        return vec![];
+    }
+    match if_addrs::get_if_addrs() {
+        Ok(ifaces) => {
+            let ifaces: Vec<(String, IpAddr)> = ifaces
+                .into_iter()
+                .map(|iface| (iface.name.clone(), iface.ip()))
+                .collect();
+            resolve_interfaces(configured, &ifaces)
+        }
+        Err(err) => {
+            log::error!("get_if_addrs: {err:#}");
+            vec![]
+        }
+    }
+}
+
+async fn send_scan(options: &DiscoOptions, interfaces: &[Ipv4Addr]) -> anyhow::Result<()> {
     let mut addresses = options.additional_addresses.clone();
     if options.enable_multicast {
         addresses.push(MULTICAST);
@@ -404,20 +723,30 @@ async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
 
     let mut broadcasters = vec![];
     for addr in addresses {
-        match Broadcaster::new(addr).await {
-            Ok(b) => broadcasters.push(b),
-            Err(err) => {
-                log::error!("{addr}: {err:#}");
+        if interfaces.is_empty() {
+            match Broadcaster::new(addr).await {
+                Ok(b) => broadcasters.push(b),
+                Err(err) => {
+                    log::error!("{addr}: {err:#}");
+                }
+            }
+            continue;
+        }
+        if addr.is_ipv6() {
+            log::debug!("Not sending disco packet to {addr} as only IPv4 interfaces are used");
+            continue;
+        }
+        for local in interfaces {
+            match Broadcaster::on_interface(addr, *local).await {
+                Ok(b) => broadcasters.push(b),
+                Err(err) => {
+                    log::error!("{addr} via {local}: {err:#}");
+                }
             }
         }
     }
 
-    let scan = serde_json::to_string(&RequestMessage {
-        msg: Request::Scan {
-            account_topic: AccountTopic::Reserve,
-        },
-    })
-    .expect("to serialize scan message");
+    let scan = scan_message();
     for b in broadcasters {
         log::trace!("Send disco packet to {:?}", b.addr);
         if let Err(err) = b.broadcast(&scan).await {
@@ -431,15 +760,36 @@ async fn lan_disco(
     options: DiscoOptions,
     inner: Arc<ClientInner>,
 ) -> anyhow::Result<Receiver<LanDevice>> {
-    let listen = UdpSocket::bind(("0.0.0.0", LISTEN_PORT)).await.context(
-        "Cannot bind to UDP Port 4002, which is required \
-        for the Govee LAN API to function. Most likely cause is that you \
-        are running another integration (perhaps `Govee LAN Control`, or \
-        `homebridge-govee`) that is already bound to that port. \
-        Both cannot run on the same machine at the same time. \
-        Consider disabling `Govee LAN Control` or setting `lanDisable` in \
-        `homebridge-govee`.",
-    )?;
+    let interfaces = local_interface_addresses(&options.interfaces);
+    if !options.interfaces.is_empty() && interfaces.is_empty() {
+        log::warn!("None of the configured LAN interfaces are usable; using the default interface");
+    }
+
+    // Listen on each of the interfaces, so that we know which one
+    // each device can be reached via
+    let bind_addresses: Vec<Option<Ipv4Addr>> = if interfaces.is_empty() {
+        vec![None]
+    } else {
+        interfaces.iter().copied().map(Some).collect()
+    };
+    let mut listeners = vec![];
+    for local in bind_addresses {
+        let listen = UdpSocket::bind((local.unwrap_or(Ipv4Addr::UNSPECIFIED), LISTEN_PORT))
+            .await
+            .context(
+                "Cannot bind to UDP Port 4002, which is required \
+                for the Govee LAN API to function. Most likely cause is that you \
+                are running another integration (perhaps `Govee LAN Control`, or \
+                `homebridge-govee`) that is already bound to that port. \
+                Both cannot run on the same machine at the same time. \
+                Consider disabling `Govee LAN Control` or setting `lanDisable` in \
+                `homebridge-govee`.",
+            )?;
+        if let Some(local) = local {
+            log::info!("Using LAN interface address {local}");
+        }
+        listeners.push((local, listen));
+    }
     let (tx, rx) = channel(8);
 
     async fn process_packet(
@@ -465,47 +815,74 @@ async fn lan_disco(
         }
 
         if let Response::Scan(info) = response.msg {
+            inner.last_scan_response.lock().replace(chrono::Utc::now());
+            inner.known_addresses.lock().await.insert(info.ip);
             tx.send(info).await?;
         }
 
         Ok(())
     }
 
-    async fn run_disco(
-        options: &DiscoOptions,
+    async fn receive(
+        local: Option<Ipv4Addr>,
         listen: UdpSocket,
         tx: Sender<LanDevice>,
         inner: Arc<ClientInner>,
-    ) -> anyhow::Result<()> {
-        send_scan(options).await?;
-
-        let mut retry_interval = Duration::from_secs(2);
-        let max_retry = Duration::from_secs(60);
-        let mut last_send = Instant::now();
+    ) {
         loop {
             let mut buf = [0u8; 4096];
-
-            let deadline = last_send + retry_interval;
-            match tokio::time::timeout_at(deadline, listen.recv_from(&mut buf)).await {
-                Ok(Ok((len, addr))) => {
+            match listen.recv_from(&mut buf).await {
+                Ok((len, addr)) => {
+                    metrics::increment(Counter::LanPacketsReceived, &[]);
+                    if let Some(local) = local {
+                        LOCAL_ADDRESSES.lock().insert(addr.ip(), local);
+                    }
                     if let Err(err) = process_packet(addr, &buf[0..len], &inner, &tx).await {
                         log::error!("process_packet: {err:#}");
                     }
                 }
-                Ok(Err(err)) => {
+                Err(err) => {
                     log::error!("recv_from: {err:#}");
                 }
-                Err(_) => {
-                    send_scan(options).await?;
-                    last_send = Instant::now();
-                    retry_interval = (retry_interval * 2).min(max_retry);
+            }
+        }
+    }
+
+    async fn run_disco(
+        options: &DiscoOptions,
+        interfaces: &[Ipv4Addr],
+        inner: Arc<ClientInner>,
+    ) -> anyhow::Result<()> {
+        send_scan(options, interfaces).await?;
+        tokio::spawn(send_unicast_scans(
+            inner.static_addresses.lock().await.clone(),
+        ));
+
+        let mut retry_interval = options.scan_interval.unwrap_or(INITIAL_SCAN_INTERVAL);
+        loop {
+            tokio::time::sleep(retry_interval).await;
+
+            send_scan(options, interfaces).await?;
+            let mut addresses = inner.static_addresses.lock().await.clone();
+            if options.unicast_known_devices {
+                for addr in inner.known_addresses.lock().await.iter() {
+                    if !addresses.contains(addr) {
+                        addresses.push(*addr);
+                    }
                 }
             }
+            // Don't hold up the next round of broadcasts
+            tokio::spawn(send_unicast_scans(addresses));
+            retry_interval = options.next_scan_interval(retry_interval);
         }
     }
 
+    for (local, listen) in listeners {
+        tokio::spawn(receive(local, listen, tx.clone(), Arc::clone(&inner)));
+    }
+
     tokio::spawn(async move {
-        if let Err(err) = run_disco(&options, listen, tx, inner).await {
+        if let Err(err) = run_disco(&options, &interfaces, inner).await {
             log::error!("Error at the disco: {err:#}");
         }
     });
@@ -515,12 +892,27 @@ async fn lan_disco(
 
 impl Client {
     pub async fn new(options: DiscoOptions) -> anyhow::Result<(Self, Receiver<LanDevice>)> {
-        let inner = Arc::new(ClientInner::default());
+        let inner = Arc::new(ClientInner {
+            static_addresses: Mutex::new(options.static_addresses.clone()),
+            ..Default::default()
+        });
         let rx = lan_disco(options, Arc::clone(&inner)).await?;
 
         Ok((Self { inner }, rx))
     }
 
+    /// Replaces the set of statically configured addresses to
+    /// which discovery packets are sent
+    pub async fn set_static_addresses(&self, addresses: Vec<IpAddr>) {
+        *self.inner.static_addresses.lock().await = addresses;
+    }
+
+    /// Returns the time at which a device most recently responded
+    /// to a discovery scan
+    pub fn last_discovery_response(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        *self.inner.last_scan_response.lock()
+    }
+
     async fn add_listener(&self, addr: IpAddr) -> anyhow::Result<Receiver<Response>> {
         let (tx, rx) = channel(1);
         let mut mux = self.inner.mux.lock().await;
@@ -537,13 +929,7 @@ impl Client {
         let mut rx = self.add_listener(addr).await?;
 
         let bcast = Broadcaster::new(addr).await?;
-        let scan = serde_json::to_string(&RequestMessage {
-            msg: Request::Scan {
-                account_topic: AccountTopic::Reserve,
-            },
-        })
-        .expect("to serialize scan message");
-        bcast.broadcast(scan).await?;
+        bcast.broadcast(scan_message()).await?;
 
         loop {
             match tokio::time::timeout(Duration::from_secs(10), rx.recv()).await {
@@ -561,10 +947,21 @@ impl Client {
         let mut rx = self.add_listener(device.ip).await?;
         let deadline = Instant::now() + Duration::from_secs(10);
         while Instant::now() <= deadline {
-            log::trace!("query status of {}", device.ip);
+            device_log!(
+                log::Level::Trace,
+                &device.device,
+                "query status of {}",
+                device.ip
+            );
             device.send_request(Request::DevStatus {}).await?;
             match tokio::time::timeout(Duration::from_millis(350), rx.recv()).await {
                 Ok(Some(Response::DevStatus(status))) => {
+                    device_log!(
+                        log::Level::Trace,
+                        &device.device,
+                        "LAN status from {}: {status:?}",
+                        device.ip
+                    );
                     return Ok(status);
                 }
                 Ok(Some(_)) => {}
@@ -576,3 +973,114 @@ impl Client {
         anyhow::bail!("timed out waiting for status");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn static_devices() {
+        k9::assert_equal!(
+            "10.0.0.5".parse::<StaticLanDevice>().unwrap(),
+            StaticLanDevice {
+                ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)),
+                identity: None,
+            }
+        );
+
+        let device: StaticLanDevice = "10.0.0.6 = H6199/AA:BB:CC:DD:EE:FF:00:11".parse().unwrap();
+        k9::assert_equal!(
+            device.identity,
+            Some(("H6199".to_string(), "AA:BB:CC:DD:EE:FF:00:11".to_string()))
+        );
+        let lan_device = device.to_lan_device().unwrap();
+        k9::assert_equal!(lan_device.ip, device.ip);
+        k9::assert_equal!(lan_device.sku, "H6199");
+        k9::assert_equal!(lan_device.device, "AA:BB:CC:DD:EE:FF:00:11");
+
+        // IPv6 addresses have colons too
+        k9::assert_equal!(
+            "fe80::1=H6199/AA:BB".parse::<StaticLanDevice>().unwrap().ip,
+            "fe80::1".parse::<IpAddr>().unwrap()
+        );
+
+        assert!("10.0.0.5=H6199".parse::<StaticLanDevice>().is_err());
+        assert!("10.0.0.5=/AA:BB".parse::<StaticLanDevice>().is_err());
+        assert!("not-an-ip".parse::<StaticLanDevice>().is_err());
+    }
+
+    #[test]
+    fn interfaces() {
+        let ifaces: Vec<(String, IpAddr)> = [
+            ("lo", "127.0.0.1"),
+            ("eth0", "10.0.0.2"),
+            ("eth0", "fe80::2"),
+            ("wlan0", "192.168.1.2"),
+            ("docker0", "172.17.0.1"),
+        ]
+        .into_iter()
+        .map(|(name, ip)| (name.to_string(), ip.parse().unwrap()))
+        .collect();
+
+        k9::assert_equal!(
+            resolve_interfaces(
+                &[
+                    "eth0".to_string(),
+                    "172.17.0.1".to_string(),
+                    "missing0".to_string(),
+                    "10.0.0.2".to_string(),
+                ],
+                &ifaces
+            ),
+            vec![Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(172, 17, 0, 1)]
+        );
+        k9::assert_equal!(
+            resolve_interfaces(&["missing0".to_string()], &ifaces),
+            Vec::<Ipv4Addr>::new()
+        );
+    }
+
+    #[test]
+    fn scan_interval() {
+        let options = DiscoOptions::default();
+        k9::assert_equal!(
+            options.next_scan_interval(INITIAL_SCAN_INTERVAL),
+            Duration::from_secs(4)
+        );
+        k9::assert_equal!(
+            options.next_scan_interval(Duration::from_secs(40)),
+            MAX_SCAN_INTERVAL
+        );
+
+        let options = DiscoOptions {
+            scan_interval: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        k9::assert_equal!(
+            options.next_scan_interval(Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn max_missed_polls_from_cli() {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Args {
+            #[command(flatten)]
+            lan: LanDiscoArguments,
+        }
+
+        // An explicit argument takes precedence over the environment
+        // and the config file
+        let args = Args::parse_from(["govee", "--lan-max-missed-polls", "5"]);
+        k9::assert_equal!(args.lan.max_missed_polls().unwrap(), 5);
+
+        let args = Args::parse_from(["govee", "--lan-max-missed-polls", "0"]);
+        k9::assert_equal!(
+            args.lan.max_missed_polls().unwrap_err().to_string(),
+            "the LAN max missed polls must be at least 1"
+        );
+    }
+}
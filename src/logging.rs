@@ -0,0 +1,293 @@
+//! Sets up logging. The log filters come from $RUST_LOG, or failing
+//! that, the `log_level` setting of the configuration file, and may
+//! be changed when the configuration is reloaded.
+//!
+//! More verbose logging may also be enabled for individual devices at
+//! runtime; see `set_device_log_level` and the `device_log!` macro.
+use arc_swap::ArcSwap;
+use log::LevelFilter;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The target of the records that are logged because of a per-device
+/// log level, which bypass the usual filters
+pub const DEVICE_LOG_TARGET: &str = "govee::device";
+
+/// Forwards to an env_logger that is replaced when the filters change,
+/// since env_logger doesn't allow its filters to be changed once it
+/// has been built
+struct ReloadableLogger {
+    logger: ArcSwap<env_logger::Logger>,
+    filters: ArcSwap<String>,
+    /// Logs everything that it is given; used for `DEVICE_LOG_TARGET`
+    device_logger: env_logger::Logger,
+}
+
+static LOGGER: OnceCell<ReloadableLogger> = OnceCell::new();
+
+/// The log levels that were requested for individual devices
+static DEVICE_LEVELS: Lazy<RwLock<BTreeMap<String, LevelFilter>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+/// Avoids taking the lock for every device_log! when no
+/// per-device levels are set, which is the usual case
+static HAVE_DEVICE_LEVELS: AtomicBool = AtomicBool::new(false);
+
+impl log::Log for ReloadableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target() == DEVICE_LOG_TARGET || self.logger.load().enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.target() == DEVICE_LOG_TARGET {
+            self.device_logger.log(record)
+        } else {
+            self.logger.load().log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.logger.load().flush()
+    }
+}
+
+/// The log crate discards records above the max level before they
+/// reach the logger, so it must allow for the per-device levels
+fn update_max_level() {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let device_max = DEVICE_LEVELS
+        .read()
+        .values()
+        .copied()
+        .max()
+        .unwrap_or(LevelFilter::Off);
+    log::set_max_level(logger.logger.load().filter().max(device_max));
+}
+
+/// Returns true if `level` was enabled for `device` via
+/// `set_device_log_level`
+pub fn device_log_enabled(device: &str, level: log::Level) -> bool {
+    HAVE_DEVICE_LEVELS.load(Ordering::Relaxed)
+        && DEVICE_LEVELS
+            .read()
+            .get(device)
+            .is_some_and(|filter| level <= *filter)
+}
+
+/// Sets the level at which the messages about `device` are logged,
+/// regardless of the log filters. `Off` restores the default.
+pub fn set_device_log_level(device: &str, level: LevelFilter) {
+    {
+        let mut levels = DEVICE_LEVELS.write();
+        if level == LevelFilter::Off {
+            levels.remove(device);
+        } else {
+            levels.insert(device.to_string(), level);
+        }
+        HAVE_DEVICE_LEVELS.store(!levels.is_empty(), Ordering::Relaxed);
+    }
+    update_max_level();
+}
+
+/// Removes all of the per-device log levels
+pub fn clear_device_log_levels() {
+    DEVICE_LEVELS.write().clear();
+    HAVE_DEVICE_LEVELS.store(false, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Returns the per-device log levels, keyed by device id
+pub fn device_log_levels() -> BTreeMap<String, String> {
+    DEVICE_LEVELS
+        .read()
+        .iter()
+        .map(|(device, level)| (device.to_string(), level.as_str().to_ascii_lowercase()))
+        .collect()
+}
+
+/// A request, received via the admin log topic, to change the log
+/// level of a device
+#[derive(Debug, PartialEq)]
+pub enum DeviceLogRequest {
+    /// Sets the level of the device with this id, name or label
+    Set { device: String, level: LevelFilter },
+    /// Removes the levels of all devices
+    Clear,
+}
+
+impl DeviceLogRequest {
+    /// Parses a payload such as `{"device":"<id>","level":"trace"}`.
+    /// An empty payload, or `off`, clears the levels of all devices.
+    pub fn parse(payload: &str) -> anyhow::Result<Self> {
+        #[derive(serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Payload {
+            device: String,
+            level: String,
+        }
+
+        let payload = payload.trim();
+        if payload.is_empty() || payload.eq_ignore_ascii_case("off") {
+            return Ok(Self::Clear);
+        }
+        let Payload { device, level } = serde_json::from_str(payload)?;
+        let level = match level.trim() {
+            "" => LevelFilter::Off,
+            level => level.parse().map_err(|_| {
+                anyhow::anyhow!(
+                    "`{level}` is not a log level; use off, error, warn, info, debug or trace"
+                )
+            })?,
+        };
+        Ok(Self::Set { device, level })
+    }
+}
+
+/// Logs a message about a device, prefixed with `device=<id>` so that
+/// the messages about a device can be found with grep.
+/// The message is logged when the level is enabled by the usual log
+/// filters, or when it was enabled for the device via
+/// `set_device_log_level`.
+macro_rules! device_log {
+    ($level:expr, $device:expr, $($arg:tt)+) => {{
+        let level: log::Level = $level;
+        let device: &str = $device;
+        if $crate::logging::device_log_enabled(device, level) {
+            log::log!(
+                target: $crate::logging::DEVICE_LOG_TARGET,
+                level,
+                "device={device} {}",
+                format_args!($($arg)+)
+            );
+        } else {
+            log::log!(level, "device={device} {}", format_args!($($arg)+));
+        }
+    }};
+}
+pub(crate) use device_log;
+
+fn log_filters() -> String {
+    crate::opt_env_var::<String>("RUST_LOG")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+fn build_logger(filters: &str) -> env_logger::Logger {
+    fn resolve_timezone() -> chrono_tz::Tz {
+        std::env::var("TZ")
+            .or_else(|_| iana_time_zone::get_timezone())
+            .ok()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(chrono_tz::UTC)
+    }
+
+    let tz = resolve_timezone();
+    let utc_suffix = if tz == chrono_tz::UTC { "Z" } else { "" };
+
+    env_logger::builder()
+        // A bit of boilerplate here to get timestamps printed in local time.
+        // <https://github.com/rust-cli/env_logger/issues/158>
+        .format(move |buf, record| {
+            use chrono::Utc;
+            use std::io::Write;
+
+            let level_style = buf.default_level_style(record.level());
+            write!(
+                buf,
+                "[{}{utc_suffix} ",
+                Utc::now().with_timezone(&tz).format("%Y-%m-%dT%H:%M:%S")
+            )?;
+            write!(buf, "{level_style}{:<5}{level_style:#}", record.level())?;
+            if let Some(path) = record.module_path() {
+                write!(buf, " {}", path)?;
+            }
+            writeln!(buf, "] {}", record.args())
+        })
+        .filter_level(log::LevelFilter::Info)
+        .parse_filters(filters)
+        .parse_write_style(&std::env::var("RUST_LOG_STYLE").unwrap_or_default())
+        .build()
+}
+
+pub fn setup_logger() {
+    let filters = log_filters();
+    let logger = build_logger(&filters);
+    log::set_max_level(logger.filter());
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        logger: ArcSwap::from_pointee(logger),
+        filters: ArcSwap::from_pointee(filters),
+        device_logger: build_logger("trace"),
+    });
+    log::set_logger(logger).expect("the logger is only set up once");
+}
+
+/// Applies the log filters from the current configuration.
+/// Returns the new filters if they changed.
+pub fn reload_log_filters() -> Option<String> {
+    let logger = LOGGER.get()?;
+    let filters = log_filters();
+    if **logger.filters.load() == filters {
+        return None;
+    }
+    let new_logger = build_logger(&filters);
+    logger.logger.store(Arc::new(new_logger));
+    logger.filters.store(Arc::new(filters.clone()));
+    update_max_level();
+    Some(filters)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn device_log_request() {
+        k9::assert_equal!(
+            DeviceLogRequest::parse("").unwrap(),
+            DeviceLogRequest::Clear
+        );
+        k9::assert_equal!(
+            DeviceLogRequest::parse("OFF").unwrap(),
+            DeviceLogRequest::Clear
+        );
+        k9::assert_equal!(
+            DeviceLogRequest::parse(r#"{"device":"AA:BB","level":"trace"}"#).unwrap(),
+            DeviceLogRequest::Set {
+                device: "AA:BB".to_string(),
+                level: LevelFilter::Trace
+            }
+        );
+        k9::assert_equal!(
+            DeviceLogRequest::parse(r#"{"device":"AA:BB","level":"off"}"#).unwrap(),
+            DeviceLogRequest::Set {
+                device: "AA:BB".to_string(),
+                level: LevelFilter::Off
+            }
+        );
+        k9::assert_equal!(
+            DeviceLogRequest::parse(r#"{"device":"AA:BB","level":"loud"}"#)
+                .unwrap_err()
+                .to_string(),
+            "`loud` is not a log level; use off, error, warn, info, debug or trace"
+        );
+    }
+
+    #[test]
+    fn device_levels() {
+        k9::assert_equal!(device_log_enabled("CC:DD", log::Level::Trace), false);
+        set_device_log_level("CC:DD", LevelFilter::Debug);
+        k9::assert_equal!(device_log_enabled("CC:DD", log::Level::Debug), true);
+        k9::assert_equal!(device_log_enabled("CC:DD", log::Level::Trace), false);
+        k9::assert_equal!(
+            device_log_levels().get("CC:DD").map(String::as_str),
+            Some("debug")
+        );
+        set_device_log_level("CC:DD", LevelFilter::Off);
+        k9::assert_equal!(device_log_enabled("CC:DD", log::Level::Debug), false);
+    }
+}
@@ -3,15 +3,21 @@ use crate::platform_api::GoveeApiArguments;
 use crate::service::hass::HassArguments;
 use crate::undoc_api::UndocApiArguments;
 use clap::Parser;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 mod ble;
+mod ble_adv;
 mod cache;
 mod commands;
+mod config;
 mod hass_mqtt;
 mod lan_api;
+mod logging;
+mod metrics;
 #[macro_use]
 mod platform_api;
+mod rate_limit;
 mod rest_api;
 mod service;
 mod temperature;
@@ -21,6 +27,13 @@ mod version_info;
 #[derive(clap::Parser, Debug)]
 #[command(version = version_info::govee_version(),  propagate_version=true)]
 pub struct Args {
+    /// The path to a TOML configuration file. Settings in the
+    /// environment take precedence over those in the file.
+    /// You may also set this via the GOVEE_CONFIG_FILE environment
+    /// variable.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
     #[command(flatten)]
     api_args: GoveeApiArguments,
     #[command(flatten)]
@@ -36,29 +49,40 @@ pub struct Args {
 
 #[derive(clap::Parser, Debug)]
 pub enum SubCommand {
+    Diagnostics(commands::diagnostics::DiagnosticsCommand),
+    DumpQuirks(commands::dump_quirks::DumpQuirksCommand),
     LanControl(commands::lan_control::LanControlCommand),
     LanDisco(commands::lan_disco::LanDiscoCommand),
     ListHttp(commands::list_http::ListHttpCommand),
     List(commands::list::ListCommand),
+    ListScenes(commands::list_scenes::ListScenesCommand),
     HttpControl(commands::http_control::HttpControlCommand),
     Serve(commands::serve::ServeCommand),
+    Status(commands::status::StatusCommand),
     Undoc(commands::undoc::UndocCommand),
 }
 
 impl Args {
     pub async fn run(&self) -> anyhow::Result<()> {
         match &self.cmd {
+            SubCommand::Diagnostics(cmd) => cmd.run(self).await,
+            SubCommand::DumpQuirks(cmd) => cmd.run(self).await,
             SubCommand::LanControl(cmd) => cmd.run(self).await,
             SubCommand::LanDisco(cmd) => cmd.run(self).await,
             SubCommand::ListHttp(cmd) => cmd.run(self).await,
             SubCommand::HttpControl(cmd) => cmd.run(self).await,
             SubCommand::List(cmd) => cmd.run(self).await,
+            SubCommand::ListScenes(cmd) => cmd.run(self).await,
             SubCommand::Serve(cmd) => cmd.run(self).await,
+            SubCommand::Status(cmd) => cmd.run(self).await,
             SubCommand::Undoc(cmd) => cmd.run(self).await,
         }
     }
 }
 
+/// Returns the value of the environment variable `name`, or if it
+/// is not set, the value of the corresponding setting from the
+/// configuration file
 pub fn opt_env_var<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
 where
     <T as FromStr>::Err: std::fmt::Display,
@@ -69,47 +93,18 @@ where
                 anyhow::anyhow!("parsing ${name}: {err:#}")
             })?))
         }
-        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotPresent) => match config::setting(name) {
+            Some(setting) => {
+                Ok(Some(setting.value.parse().map_err(|err| {
+                    anyhow::anyhow!("parsing {}: {err:#}", setting.location)
+                })?))
+            }
+            None => Ok(None),
+        },
         Err(err) => anyhow::bail!("${name} is invalid: {err:#}"),
     }
 }
 
-fn setup_logger() {
-    fn resolve_timezone() -> chrono_tz::Tz {
-        std::env::var("TZ")
-            .or_else(|_| iana_time_zone::get_timezone())
-            .ok()
-            .and_then(|name| name.parse().ok())
-            .unwrap_or(chrono_tz::UTC)
-    }
-
-    let tz = resolve_timezone();
-    let utc_suffix = if tz == chrono_tz::UTC { "Z" } else { "" };
-
-    env_logger::builder()
-        // A bit of boilerplate here to get timestamps printed in local time.
-        // <https://github.com/rust-cli/env_logger/issues/158>
-        .format(move |buf, record| {
-            use chrono::Utc;
-            use std::io::Write;
-
-            let level_style = buf.default_level_style(record.level());
-            write!(
-                buf,
-                "[{}{utc_suffix} ",
-                Utc::now().with_timezone(&tz).format("%Y-%m-%dT%H:%M:%S")
-            )?;
-            write!(buf, "{level_style}{:<5}{level_style:#}", record.level())?;
-            if let Some(path) = record.module_path() {
-                write!(buf, " {}", path)?;
-            }
-            writeln!(buf, "] {}", record.args())
-        })
-        .filter_level(log::LevelFilter::Info)
-        .parse_env("RUST_LOG")
-        .init();
-}
-
 #[tokio::main(worker_threads = 2)]
 async fn main() -> anyhow::Result<()> {
     color_backtrace::install();
@@ -117,8 +112,43 @@ async fn main() -> anyhow::Result<()> {
         eprintln!("Loading environment overrides from {path:?}");
     }
 
-    setup_logger();
-
     let args = Args::parse();
+    // The configuration file may set the log level, so it is
+    // loaded before the logger is set up
+    config::load(args.config.as_deref())?;
+    logging::setup_logger();
+    service::quirks::load_user_quirks()?;
+
     args.run().await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_line() {
+        use clap::CommandFactory;
+        Args::command().debug_assert();
+
+        let args = Args::parse_from([
+            "govee",
+            "lan-control",
+            "--device-id",
+            "AA:BB",
+            "segment-color",
+            "0,1,2",
+            "red",
+        ]);
+        assert!(matches!(args.cmd, SubCommand::LanControl(_)));
+        assert!(Args::try_parse_from(["govee", "lan-control", "on"]).is_err());
+        assert!(Args::try_parse_from([
+            "govee",
+            "lan-control",
+            "--ip",
+            "10.0.0.1",
+            "segment-color"
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! A small registry of counters that is rendered in the Prometheus
+//! text exposition format by the `/metrics` HTTP endpoint.
+//! Labels are limited to coarse values such as the transport or SKU;
+//! device ids are never used as labels so that the number of series
+//! stays bounded.
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Counter {
+    PlatformApiRequests,
+    IotMessagesReceived,
+    MqttPublishes,
+    LanPacketsSent,
+    LanPacketsReceived,
+    CommandFailures,
+    CommandsSuperseded,
+    CommandsSent,
+}
+
+impl Counter {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::PlatformApiRequests => "govee_platform_api_requests_total",
+            Self::IotMessagesReceived => "govee_iot_messages_received_total",
+            Self::MqttPublishes => "govee_mqtt_publishes_total",
+            Self::LanPacketsSent => "govee_lan_packets_sent_total",
+            Self::LanPacketsReceived => "govee_lan_packets_received_total",
+            Self::CommandFailures => "govee_command_failures_total",
+            Self::CommandsSuperseded => "govee_commands_superseded_total",
+            Self::CommandsSent => "govee_commands_sent_total",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            Self::PlatformApiRequests => "Platform API requests, by HTTP status code",
+            Self::IotMessagesReceived => "Messages received from the AWS IoT broker",
+            Self::MqttPublishes => "Messages published to the Home Assistant MQTT broker",
+            Self::LanPacketsSent => "Packets sent to devices over the LAN API",
+            Self::LanPacketsReceived => "Packets received from devices over the LAN API",
+            Self::CommandFailures => "Device commands that failed, by transport",
+            Self::CommandsSuperseded => {
+                "Device commands that were dropped because a newer one replaced them"
+            }
+            Self::CommandsSent => {
+                "Device commands that were sent, by the transport that served them"
+            }
+        }
+    }
+}
+
+pub type Labels = Vec<(&'static str, String)>;
+
+/// A value computed at the time that the metrics are rendered
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub samples: Vec<(Labels, f64)>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    counters: Mutex<BTreeMap<Counter, BTreeMap<Labels, u64>>>,
+}
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::default);
+
+/// Returns the process-wide registry
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+/// Increments a counter in the process-wide registry
+pub fn increment(counter: Counter, labels: &[(&'static str, &str)]) {
+    registry().increment(counter, labels);
+}
+
+/// Counts a failed command sent via transport, passing the
+/// result through unchanged
+pub fn track_command<T>(
+    transport: &'static str,
+    sku: &str,
+    result: anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    if result.is_err() {
+        increment(
+            Counter::CommandFailures,
+            &[("transport", transport), ("sku", sku)],
+        );
+    }
+    result
+}
+
+impl Registry {
+    pub fn increment(&self, counter: Counter, labels: &[(&'static str, &str)]) {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| (*name, value.to_string()))
+            .collect();
+        *self
+            .counters
+            .lock()
+            .entry(counter)
+            .or_default()
+            .entry(labels)
+            .or_default() += 1;
+    }
+
+    /// Renders the counters, followed by the supplied gauges
+    pub fn render(&self, gauges: &[Gauge]) -> String {
+        let mut output = String::new();
+
+        for (counter, series) in self.counters.lock().iter() {
+            write_header(&mut output, counter.name(), counter.help(), "counter");
+            for (labels, value) in series {
+                write_sample(&mut output, counter.name(), labels, *value as f64);
+            }
+        }
+
+        for gauge in gauges {
+            write_header(&mut output, gauge.name, gauge.help, "gauge");
+            for (labels, value) in &gauge.samples {
+                write_sample(&mut output, gauge.name, labels, *value);
+            }
+        }
+
+        output
+    }
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(output, "# HELP {name} {help}").ok();
+    writeln!(output, "# TYPE {name} {kind}").ok();
+}
+
+fn write_sample(output: &mut String, name: &str, labels: &Labels, value: f64) {
+    output.push_str(name);
+    if !labels.is_empty() {
+        let labels = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\"", escape_label_value(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        write!(output, "{{{labels}}}").ok();
+    }
+    writeln!(output, " {value}").ok();
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let registry = Registry::default();
+        registry.increment(Counter::PlatformApiRequests, &[("status", "200")]);
+        registry.increment(Counter::PlatformApiRequests, &[("status", "200")]);
+        registry.increment(Counter::PlatformApiRequests, &[("status", "429")]);
+        registry.increment(Counter::MqttPublishes, &[]);
+        registry.increment(
+            Counter::CommandFailures,
+            &[("transport", "lan"), ("sku", "H6159")],
+        );
+
+        let gauges = [Gauge {
+            name: "govee_devices",
+            help: "Known devices, by SKU",
+            samples: vec![(vec![("sku", "H6159".to_string())], 2.0)],
+        }];
+
+        k9::snapshot!(
+            registry.render(&gauges),
+            r#"
+# HELP govee_platform_api_requests_total Platform API requests, by HTTP status code
+# TYPE govee_platform_api_requests_total counter
+govee_platform_api_requests_total{status="200"} 2
+govee_platform_api_requests_total{status="429"} 1
+# HELP govee_mqtt_publishes_total Messages published to the Home Assistant MQTT broker
+# TYPE govee_mqtt_publishes_total counter
+govee_mqtt_publishes_total 1
+# HELP govee_command_failures_total Device commands that failed, by transport
+# TYPE govee_command_failures_total counter
+govee_command_failures_total{transport="lan",sku="H6159"} 1
+# HELP govee_devices Known devices, by SKU
+# TYPE govee_devices gauge
+govee_devices{sku="H6159"} 2
+
+"#
+        );
+    }
+
+    #[test]
+    fn label_escaping() {
+        k9::assert_equal!(escape_label_value("H6159"), "H6159");
+        k9::assert_equal!(
+            escape_label_value("a \"b\" \\ c\nd"),
+            "a \\\"b\\\" \\\\ c\\nd"
+        );
+    }
+}
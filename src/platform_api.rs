@@ -1,14 +1,27 @@
-use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
+use crate::cache::{
+    cache_get, invalidate_key, load_value, store_value, CacheComputeResult, CacheGetOptions,
+};
 use crate::hass_mqtt::climate::parse_temperature_constraints;
+use crate::hass_mqtt::cover::{
+    percent_to_position, CoverCommand, COVER_OPEN_CLOSE_INSTANCE, COVER_POSITION_INSTANCE,
+};
+use crate::hass_mqtt::siren::{SIREN_DURATION_FIELD, SIREN_TONE_FIELD};
+use crate::hass_mqtt::valve::VALVE_DURATION_FIELD;
+use crate::logging::device_log;
+use crate::metrics::{self, Counter};
 use crate::opt_env_var;
+use crate::rate_limit::{parse_retry_after, QuotaStatus, RateLimited, RateLimiter};
 use crate::service::state::sort_and_dedup_scenes;
-use crate::temperature::{TemperatureUnits, TemperatureValue};
+use crate::temperature::TemperatureValue;
 use crate::undoc_api::GoveeUndocumentedApi;
 use anyhow::Context;
+use chrono::{DateTime, Utc};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
 use reqwest::Method;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{json, Value as JsonValue};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
 
@@ -27,7 +40,7 @@ fn endpoint(url: &str) -> String {
     format!("{SERVER}{url}")
 }
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Clone, Debug)]
 pub struct GoveeApiArguments {
     /// The Govee API Key. If not passed here, it will be read from
     /// the GOVEE_API_KEY environment variable.
@@ -61,32 +74,175 @@ impl GoveeApiArguments {
 #[derive(Clone)]
 pub struct GoveeApiClient {
     key: String,
+    rate_limit: Arc<RateLimiter>,
+    quota: Arc<tokio::sync::watch::Sender<QuotaStatus>>,
+    full_refresh: bool,
 }
 
+/// The device list, along with what we need to determine whether
+/// it has changed
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DeviceList {
+    pub devices: Vec<HttpDeviceInfo>,
+    /// A hash of the content of the list
+    pub hash: String,
+    /// The validators from the response, for use in conditional requests
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl DeviceList {
+    fn new(
+        devices: Vec<HttpDeviceInfo>,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let hash =
+            data_encoding::HEXLOWER.encode(&openssl::sha::sha256(&serde_json::to_vec(&devices)?));
+        Ok(Self {
+            devices,
+            hash,
+            etag,
+            last_modified,
+        })
+    }
+}
+
+/// Where the most recent device list response is kept, so that
+/// we can make conditional requests for it
+const DEVICE_LIST_VALIDATORS_KEY: &str = "device-list-validators";
+
 impl GoveeApiClient {
     pub fn new<K: Into<String>>(key: K) -> Self {
-        Self { key: key.into() }
+        Self {
+            key: key.into(),
+            rate_limit: Arc::new(RateLimiter::new("Platform API")),
+            quota: Arc::new(tokio::sync::watch::Sender::new(QuotaStatus::default())),
+            full_refresh: false,
+        }
+    }
+
+    /// Returns a client that uses a different key, but that otherwise
+    /// shares our state, such as whether we are rate limited
+    pub fn with_key<K: Into<String>>(&self, key: K) -> Self {
+        Self {
+            key: key.into(),
+            ..self.clone()
+        }
+    }
+
+    pub fn has_key(&self, key: &str) -> bool {
+        self.key == key
+    }
+
+    /// Verifies that the key is accepted, by fetching the device list
+    pub async fn check_credentials(&self) -> anyhow::Result<()> {
+        let request = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()?
+            .request(Method::GET, endpoint("/router/api/v1/user/devices"));
+        let _: GetDevicesResponse = self.send_request(request).await?;
+        Ok(())
+    }
+
+    /// When set, conditional requests are not used, so that the
+    /// full device list is always fetched
+    pub fn with_full_refresh(mut self, full_refresh: bool) -> Self {
+        self.full_refresh = full_refresh;
+        self
+    }
+
+    /// Returns the time until which requests are suspended because
+    /// we exceeded the request quota, or None if they are permitted
+    pub fn rate_limited_until(&self) -> Option<DateTime<Utc>> {
+        self.rate_limit.limited_until()
+    }
+
+    /// Returns the time at which the most recent successful
+    /// request completed
+    pub fn last_success(&self) -> Option<DateTime<Utc>> {
+        self.rate_limit.last_success()
+    }
+
+    /// Returns the request quota, as reported by the most recent responses
+    pub fn quota(&self) -> QuotaStatus {
+        *self.quota.borrow()
+    }
+
+    /// Returns a receiver that is notified whenever the quota changes
+    pub fn subscribe_quota(&self) -> tokio::sync::watch::Receiver<QuotaStatus> {
+        self.quota.subscribe()
     }
 
     pub async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
+        Ok(self.get_device_list().await?.devices)
+    }
+
+    pub async fn get_device_list(&self) -> anyhow::Result<DeviceList> {
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
-                key: "device-list",
+                key: "device-list-snapshot",
                 soft_ttl: Duration::from_secs(900),
                 hard_ttl: ONE_WEEK,
                 negative_ttl: Duration::from_secs(60),
                 allow_stale: true,
             },
-            async {
-                let url = endpoint("/router/api/v1/user/devices");
-                let resp: GetDevicesResponse = self.get_request_with_json_response(url).await?;
-                Ok(CacheComputeResult::Value(resp.data))
-            },
+            async { Ok(CacheComputeResult::Value(self.fetch_device_list().await?)) },
         )
         .await
     }
 
+    /// Fetches the device list. If we have a prior response with
+    /// validators, the request is made conditional upon the list
+    /// having changed since then.
+    async fn fetch_device_list(&self) -> anyhow::Result<DeviceList> {
+        let prior: Option<DeviceList> = if self.full_refresh {
+            None
+        } else {
+            load_value("http-api", DEVICE_LIST_VALIDATORS_KEY)?
+        };
+
+        let mut request = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()?
+            .request(Method::GET, endpoint("/router/api/v1/user/devices"));
+        if let Some(prior) = &prior {
+            if let Some(etag) = &prior.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &prior.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = self.send(request).await?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            if let Some(prior) = prior {
+                log::debug!("Platform API device list is unchanged");
+                self.rate_limit.record_success();
+                return Ok(prior);
+            }
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+
+        let resp: GetDevicesResponse = self.handle_response(response).await?;
+        let list = DeviceList::new(resp.data, etag, last_modified)?;
+        if list.etag.is_some() || list.last_modified.is_some() {
+            store_value("http-api", DEVICE_LIST_VALIDATORS_KEY, &list, ONE_WEEK)?;
+        }
+        Ok(list)
+    }
+
     pub async fn get_device_by_id<I: AsRef<str>>(&self, id: I) -> anyhow::Result<HttpDeviceInfo> {
         let id = id.as_ref();
         let devices = self.get_devices().await?;
@@ -117,10 +273,19 @@ impl GoveeApiClient {
                 },
             },
         };
+        device_log!(
+            log::Level::Debug,
+            &device.device,
+            "Platform API control {:?}",
+            request.payload.capability
+        );
 
-        let resp: ControlDeviceResponse = self
-            .request_with_json_response(Method::POST, url, &request)
-            .await?;
+        let resp: ControlDeviceResponse = metrics::track_command(
+            "platform",
+            &device.sku,
+            self.request_with_json_response(Method::POST, url, &request)
+                .await,
+        )?;
 
         log::info!("control_device result: {resp:?}");
 
@@ -143,10 +308,29 @@ impl GoveeApiClient {
         let resp: GetDeviceStateResponse = self
             .request_with_json_response(Method::POST, url, &request)
             .await?;
+        device_log!(
+            log::Level::Trace,
+            &device.device,
+            "Platform API state: {:?}",
+            resp.payload
+        );
 
         Ok(resp.payload)
     }
 
+    /// Discards the cached dynamic and DIY scene lists for the
+    /// device, so that they are fetched when next requested
+    pub fn invalidate_scene_lists(&self, device: &HttpDeviceInfo) -> anyhow::Result<()> {
+        invalidate_key(
+            "http-api",
+            &format!("scene-list-{}-{}", device.sku, device.device),
+        )?;
+        invalidate_key(
+            "http-api",
+            &format!("scene-list-diy-{}-{}", device.sku, device.device),
+        )
+    }
+
     pub async fn get_device_diy_scenes(
         &self,
         device: &HttpDeviceInfo,
@@ -229,8 +413,8 @@ impl GoveeApiClient {
     ) -> anyhow::Result<Vec<DeviceCapability>> {
         let mut result = vec![];
 
-        let scene_caps = self.get_device_scenes(&device).await?;
-        let diy_caps = self.get_device_diy_scenes(&device).await?;
+        let scene_caps = self.get_device_scenes(device).await?;
+        let diy_caps = self.get_device_diy_scenes(device).await?;
         let undoc_caps =
             match GoveeUndocumentedApi::synthesize_platform_api_scene_list(&device.sku).await {
                 Ok(caps) => caps,
@@ -291,7 +475,7 @@ impl GoveeApiClient {
             match &cap.parameters {
                 Some(DeviceParameters::Enum { options }) => {
                     for opt in options {
-                        result.push(opt.name.to_string());
+                        result.push(scene_display_name(&cap, &opt.name));
                     }
                 }
                 _ => anyhow::bail!("list_scene_names: unexpected type {cap:#?}"),
@@ -303,13 +487,10 @@ impl GoveeApiClient {
             if let Some(DeviceParameters::Struct { fields }) = &cap.parameters {
                 for f in fields {
                     if f.field_name == "musicMode" {
-                        match &f.field_type {
-                            DeviceParameters::Enum { options } => {
-                                for opt in options {
-                                    result.push(format!("Music: {}", opt.name));
-                                }
+                        if let DeviceParameters::Enum { options } = &f.field_type {
+                            for opt in options {
+                                result.push(format!("Music: {}", opt.name));
                             }
-                            _ => {}
                         }
                     }
                 }
@@ -328,7 +509,7 @@ impl GoveeApiClient {
         device: &HttpDeviceInfo,
         scene: &str,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
-        if scene == "" {
+        if scene.is_empty() {
             // Can't set no scene
             anyhow::bail!("Cannot set scene to no-scene");
         }
@@ -342,24 +523,42 @@ impl GoveeApiClient {
                             "sensitivity": 100,
                             "autoColor": 1,
                         });
-                        return self.control_device(&device, &cap, value).await;
+                        return self.control_device(device, cap, value).await;
                     }
                 }
             }
         }
 
         let caps = self.get_scene_caps(device).await?;
-        for cap in caps {
-            match &cap.parameters {
-                Some(DeviceParameters::Enum { options }) => {
-                    for opt in options {
-                        if scene.eq_ignore_ascii_case(&opt.name) {
-                            return self.control_device(&device, &cap, opt.value.clone()).await;
+        let find_scene = |matches: &dyn Fn(&DeviceCapability, &str) -> bool| -> anyhow::Result<_> {
+            for cap in &caps {
+                match &cap.parameters {
+                    Some(DeviceParameters::Enum { options }) => {
+                        for opt in options {
+                            if matches(cap, &opt.name) {
+                                return Ok(Some((cap.clone(), opt.value.clone())));
+                            }
                         }
                     }
+                    _ => anyhow::bail!("set_scene_by_name: unexpected type {cap:#?}"),
                 }
-                _ => anyhow::bail!("set_scene_by_name: unexpected type {cap:#?}"),
             }
+            Ok(None)
+        };
+
+        let found = match find_scene(&|cap, name| {
+            scene.eq_ignore_ascii_case(&scene_display_name(cap, name))
+        })? {
+            Some(found) => Some(found),
+            // DIY scenes used to be listed without their prefix;
+            // continue to accept that form for existing automations
+            None => find_scene(&|cap, name| {
+                cap.instance == DIY_SCENE_INSTANCE && scene.eq_ignore_ascii_case(name)
+            })?,
+        };
+
+        if let Some((cap, value)) = found {
+            return self.control_device(device, &cap, value).await;
         }
         anyhow::bail!("Scene '{scene}' is not available for this device");
     }
@@ -374,25 +573,8 @@ impl GoveeApiClient {
             .capability_by_instance(instance_name)
             .ok_or_else(|| anyhow::anyhow!("device has no {instance_name}"))?;
 
-        let constraints = parse_temperature_constraints(cap)?.as_unit(TemperatureUnits::Celsius);
-
-        let min = constraints.min.as_celsius();
-        let max = constraints.max.as_celsius();
-        let celsius = target.as_celsius().max(min).min(max);
-        let clamped = celsius.max(min).min(max);
-        if clamped != celsius {
-            log::info!(
-                "set_target_temperature: constraining requested {celsius} to \
-                       {clamped} because min={min} and max={max}"
-            );
-        }
-
-        let value = json!({
-            "temperature": celsius,
-            "unit": "Celsius",
-        });
-
-        self.control_device(&device, &cap, value).await
+        let value = target_temperature_value(cap, target)?;
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_work_mode(
@@ -410,7 +592,7 @@ impl GoveeApiClient {
             "modeValue": value
         });
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_toggle_state(
@@ -427,7 +609,110 @@ impl GoveeApiClient {
             .enum_parameter_by_name(if on { "on" } else { "off" })
             .ok_or_else(|| anyhow::anyhow!("{instance} has no on/off!?"))?;
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
+    }
+
+    /// Starts or stops a water timer valve. The watering capability is
+    /// either a plain on/off enum, or a struct that pairs the on/off
+    /// field with a run time duration.
+    pub async fn set_valve_state(
+        &self,
+        device: &HttpDeviceInfo,
+        instance: &str,
+        open: bool,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let cap = device
+            .capability_by_instance(instance)
+            .ok_or_else(|| anyhow::anyhow!("device has no {instance}"))?;
+
+        let value = match &cap.parameters {
+            Some(DeviceParameters::Struct { fields }) => {
+                let mut value = serde_json::Map::new();
+                for field in fields {
+                    if field.field_name == VALVE_DURATION_FIELD {
+                        if let Some(default) = &field.default_value {
+                            value.insert(field.field_name.to_string(), default.clone());
+                        }
+                    } else if let Some(v) =
+                        field
+                            .field_type
+                            .enum_parameter_by_name(if open { "on" } else { "off" })
+                    {
+                        value.insert(field.field_name.to_string(), v.into());
+                    }
+                }
+                JsonValue::Object(value)
+            }
+            _ => cap
+                .enum_parameter_by_name(if open { "on" } else { "off" })
+                .ok_or_else(|| anyhow::anyhow!("{instance} has no on/off!?"))?
+                .into(),
+        };
+
+        self.control_device(device, cap, value).await
+    }
+
+    /// Sounds or silences a buzzer. The capability is either a plain
+    /// on/off enum, or a struct that adds optional tone and duration fields.
+    pub async fn set_siren_state(
+        &self,
+        device: &HttpDeviceInfo,
+        instance: &str,
+        on: bool,
+        tone: Option<&str>,
+        duration: Option<u32>,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let cap = device
+            .capability_by_instance(instance)
+            .ok_or_else(|| anyhow::anyhow!("device has no {instance}"))?;
+        let on_off = if on { "on" } else { "off" };
+
+        let value = match &cap.parameters {
+            Some(DeviceParameters::Struct { fields }) => {
+                let mut value = serde_json::Map::new();
+                for field in fields {
+                    let name = field.field_name.as_str();
+                    let v: Option<JsonValue> = match name {
+                        SIREN_TONE_FIELD if on => match tone {
+                            Some(tone) => Some(
+                                field
+                                    .field_type
+                                    .enum_parameter_by_name(tone)
+                                    .ok_or_else(|| anyhow::anyhow!("{instance} has no {tone}"))?
+                                    .into(),
+                            ),
+                            None => field.default_value.clone(),
+                        },
+                        SIREN_DURATION_FIELD if on => match (duration, &field.field_type) {
+                            (
+                                Some(duration),
+                                DeviceParameters::Integer {
+                                    range: IntegerRange { min, max, .. },
+                                    ..
+                                },
+                            ) => Some(duration.max(*min).min(*max).into()),
+                            (Some(duration), _) => Some(duration.into()),
+                            (None, _) => field.default_value.clone(),
+                        },
+                        SIREN_TONE_FIELD | SIREN_DURATION_FIELD => None,
+                        _ => field
+                            .field_type
+                            .enum_parameter_by_name(on_off)
+                            .map(Into::into),
+                    };
+                    if let Some(v) = v {
+                        value.insert(name.to_string(), v);
+                    }
+                }
+                JsonValue::Object(value)
+            }
+            _ => cap
+                .enum_parameter_by_name(on_off)
+                .ok_or_else(|| anyhow::anyhow!("{instance} has no on/off!?"))?
+                .into(),
+        };
+
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_power_state(
@@ -453,7 +738,7 @@ impl GoveeApiClient {
             }) => (percent as u32).max(*min).min(*max),
             _ => anyhow::bail!("unexpected parameter type for brightness"),
         };
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_color_temperature(
@@ -471,7 +756,7 @@ impl GoveeApiClient {
             }) => (kelvin).max(*min).min(*max),
             _ => anyhow::bail!("unexpected parameter type for colorTemperatureK"),
         };
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_color_rgb(
@@ -485,27 +770,24 @@ impl GoveeApiClient {
             .capability_by_instance("colorRgb")
             .ok_or_else(|| anyhow::anyhow!("device has no colorRgb"))?;
         let value = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_segment_rgb(
         &self,
         device: &HttpDeviceInfo,
-        segment: u32,
-        r: u8,
-        g: u8,
-        b: u8,
+        segments: &[u32],
+        rgb: u32,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
             .capability_by_instance("segmentedColorRgb")
             .ok_or_else(|| anyhow::anyhow!("device has no segmentedColorRgb"))?;
-        let value = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
         self.control_device(
-            &device,
-            &cap,
+            device,
+            cap,
             json!({
-                "segment": vec![segment],
-                "rgb": value,
+                "segment": segments,
+                "rgb": rgb,
             }),
         )
         .await
@@ -514,7 +796,7 @@ impl GoveeApiClient {
     pub async fn set_segment_brightness(
         &self,
         device: &HttpDeviceInfo,
-        segment: u32,
+        segments: &[u32],
         percent: u8,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
@@ -528,15 +810,51 @@ impl GoveeApiClient {
         let value = (percent as u32).max(min).min(max);
 
         self.control_device(
-            &device,
-            &cap,
+            device,
+            cap,
             json!({
-                "segment": vec![segment],
+                "segment": segments,
                 "brightness": value,
             }),
         )
         .await
     }
+
+    pub async fn set_cover_position(
+        &self,
+        device: &HttpDeviceInfo,
+        percent: u8,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let cap = device
+            .capability_by_instance(COVER_POSITION_INSTANCE)
+            .ok_or_else(|| anyhow::anyhow!("device has no {COVER_POSITION_INSTANCE}"))?;
+        let range = device
+            .get_cover_position_range()
+            .ok_or_else(|| anyhow::anyhow!("unexpected parameter type for {cap:?}"))?;
+        let value = percent_to_position(percent, range);
+        self.control_device(device, cap, value).await
+    }
+
+    pub async fn set_cover_command(
+        &self,
+        device: &HttpDeviceInfo,
+        command: CoverCommand,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        if let Some(cap) = device.capability_by_instance(COVER_OPEN_CLOSE_INSTANCE) {
+            let name = command.to_string().to_lowercase();
+            let value = cap
+                .enum_parameter_by_name(&name)
+                .ok_or_else(|| anyhow::anyhow!("{COVER_OPEN_CLOSE_INSTANCE} has no {name}"))?;
+            return self.control_device(device, cap, value).await;
+        }
+
+        // No dedicated open/close control; drive the position instead
+        match command {
+            CoverCommand::Open => self.set_cover_position(device, 100).await,
+            CoverCommand::Close => self.set_cover_position(device, 0).await,
+            CoverCommand::Stop => anyhow::bail!("device has no way to stop the cover"),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -750,6 +1068,25 @@ impl HttpDeviceInfo {
         }
     }
 
+    pub fn supports_cover(&self) -> bool {
+        self.get_cover_position_range().is_some()
+            || self
+                .capability_by_instance(COVER_OPEN_CLOSE_INSTANCE)
+                .is_some()
+    }
+
+    pub fn get_cover_position_range(&self) -> Option<(u32, u32)> {
+        let cap = self.capability_by_instance(COVER_POSITION_INSTANCE)?;
+
+        match cap.parameters {
+            Some(DeviceParameters::Integer {
+                range: IntegerRange { min, max, .. },
+                ..
+            }) => Some((min, max)),
+            _ => None,
+        }
+    }
+
     pub fn get_color_temperature_range(&self) -> Option<(u32, u32)> {
         let cap = self.capability_by_instance("colorTemperatureK")?;
 
@@ -1002,6 +1339,15 @@ impl HttpRequestFailed {
     pub fn from_err(err: &anyhow::Error) -> Option<&Self> {
         err.root_cause().downcast_ref::<Self>()
     }
+
+    /// Returns true if the error indicates that we've exceeded the
+    /// request quota and should back off
+    pub fn is_rate_limited(err: &anyhow::Error) -> bool {
+        RateLimited::from_err(err).is_some()
+            || Self::from_err(err)
+                .map(|e| e.status == reqwest::StatusCode::TOO_MANY_REQUESTS)
+                .unwrap_or(false)
+    }
 }
 
 pub async fn json_body<T: serde::de::DeserializeOwned>(
@@ -1054,12 +1400,14 @@ pub async fn http_response_body<R: serde::de::DeserializeOwned>(
             )
         })?;
 
-        anyhow::bail!(
-            "request {url} status {}: {}. Response body: {}",
-            status.as_u16(),
-            status.canonical_reason().unwrap_or(""),
-            String::from_utf8_lossy(&body_bytes)
-        );
+        return Err(HttpRequestFailed {
+            status,
+            content: format!(
+                "request {url}. Response body: {}",
+                String::from_utf8_lossy(&body_bytes)
+            ),
+        }
+        .into());
     }
     json_body(response).await.with_context(|| {
         format!(
@@ -1071,19 +1419,59 @@ pub async fn http_response_body<R: serde::de::DeserializeOwned>(
 }
 
 impl GoveeApiClient {
-    async fn get_request_with_json_response<T: reqwest::IntoUrl, R: serde::de::DeserializeOwned>(
+    /// Sends the request, unless we are currently rate limited.
+    /// If the response tells us that we've exceeded the request
+    /// quota, further requests are suspended for a while.
+    async fn send_request<R: serde::de::DeserializeOwned>(
         &self,
-        url: T,
+        request: reqwest::RequestBuilder,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
-            .request(Method::GET, url)
-            .header("Govee-API-Key", &self.key)
-            .send()
-            .await?;
+        let response = self.send(request).await?;
+        self.handle_response(response).await
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> anyhow::Result<reqwest::Response> {
+        self.rate_limit.check()?;
+        let response = match request.header("Govee-API-Key", &self.key).send().await {
+            Ok(response) => response,
+            Err(err) => {
+                metrics::increment(Counter::PlatformApiRequests, &[("status", "error")]);
+                return Err(err.into());
+            }
+        };
+        metrics::increment(
+            Counter::PlatformApiRequests,
+            &[("status", response.status().as_str())],
+        );
 
-        http_response_body(response).await
+        let quota = QuotaStatus::from_headers(response.headers(), Utc::now());
+        self.quota.send_if_modified(|current| {
+            let prior = *current;
+            current.merge(&quota);
+            *current != prior
+        });
+
+        Ok(response)
+    }
+
+    async fn handle_response<R: serde::de::DeserializeOwned>(
+        &self,
+        response: reqwest::Response,
+    ) -> anyhow::Result<R> {
+        let retry_after = parse_retry_after(response.headers(), Utc::now());
+
+        let result = http_response_body(response).await;
+        match &result {
+            Ok(_) => self.rate_limit.record_success(),
+            Err(err) if HttpRequestFailed::is_rate_limited(err) => {
+                let until = self.rate_limit.record_rate_limited(retry_after);
+                log::warn!(
+                    "Platform API request quota exceeded; suspending requests until {until}"
+                );
+            }
+            Err(_) => {}
+        }
+        result
     }
 
     async fn request_with_json_response<
@@ -1096,16 +1484,140 @@ impl GoveeApiClient {
         url: T,
         body: &B,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
+        let request = reqwest::Client::builder()
             .timeout(Duration::from_secs(60))
             .build()?
             .request(method, url)
-            .header("Govee-API-Key", &self.key)
-            .json(body)
-            .send()
-            .await?;
+            .json(body);
 
-        http_response_body(response).await
+        self.send_request(request).await
+    }
+}
+
+/// The instance used for the stock scenes
+pub const LIGHT_SCENE_INSTANCE: &str = "lightScene";
+/// The instance used for scenes that were created in the Govee app
+pub const DIY_SCENE_INSTANCE: &str = "diyScene";
+/// The instance used for the device states saved as snapshots
+/// in the Govee app
+pub const SNAPSHOT_INSTANCE: &str = "snapshot";
+/// The instances whose state reports the active scene. A snapshot
+/// restores a saved device state rather than running an effect, so
+/// it isn't reported as a scene.
+pub const SCENE_INSTANCES: &[&str] = &[LIGHT_SCENE_INSTANCE, DIY_SCENE_INSTANCE];
+
+/// DIY scenes and snapshots are presented with a prefix, as their
+/// names can collide with the names of the stock scenes
+fn scene_prefix(cap: &DeviceCapability) -> &'static str {
+    match cap.instance.as_str() {
+        DIY_SCENE_INSTANCE => "DIY: ",
+        SNAPSHOT_INSTANCE => "Snapshot: ",
+        _ => "",
+    }
+}
+
+/// Computes the value of a targetTemperature control. The value is
+/// sent in the scale in which it was requested, rather than being
+/// converted to Celsius, so that a whole number of degrees Fahrenheit
+/// isn't subject to rounding on its way to the device and back.
+fn target_temperature_value(
+    cap: &DeviceCapability,
+    target: TemperatureValue,
+) -> anyhow::Result<JsonValue> {
+    let target = target.normalize();
+    let constraints = parse_temperature_constraints(cap)?.as_unit(target.unit());
+
+    let min = constraints.min.value();
+    let max = constraints.max.value();
+    let requested = target.value();
+    let clamped = requested.max(min).min(max);
+    if clamped != requested {
+        log::info!(
+            "set_target_temperature: constraining requested {requested} to \
+                   {clamped} because min={min} and max={max}"
+        );
+    }
+
+    Ok(json!({
+        "temperature": clamped,
+        "unit": target.unit().scale().platform_unit_name(),
+    }))
+}
+
+/// Returns true if the scene name refers to a snapshot
+pub fn is_snapshot_scene_name(scene: &str) -> bool {
+    scene.starts_with("Snapshot: ")
+}
+
+/// Returns true if the scene name refers to a DIY scene
+pub fn is_diy_scene_name(scene: &str) -> bool {
+    scene.starts_with("DIY: ")
+}
+
+/// The name by which a scene option is presented in hass
+fn scene_display_name(cap: &DeviceCapability, name: &str) -> String {
+    format!("{}{name}", scene_prefix(cap))
+}
+
+/// Scene options have values such as `{"id": 1606, "paramId": 1681}`,
+/// while the state of some devices reports only the id
+fn scene_code(value: &JsonValue) -> &JsonValue {
+    value.get("id").unwrap_or(value)
+}
+
+/// Returns the name by which the scene whose value was reported in
+/// the state of `instance` is presented in hass, if it is one of the
+/// scenes in `caps`
+pub fn scene_name_by_value(
+    caps: &[DeviceCapability],
+    instance: &str,
+    value: &JsonValue,
+) -> Option<String> {
+    let code = scene_code(value);
+    caps.iter()
+        .filter(|cap| cap.instance == instance)
+        .find_map(|cap| match &cap.parameters {
+            Some(DeviceParameters::Enum { options }) => options
+                .iter()
+                .find(|opt| scene_code(&opt.value) == code)
+                .map(|opt| scene_display_name(cap, &opt.name)),
+            _ => None,
+        })
+}
+
+/// The name presented in hass for a reported scene that isn't in
+/// the scene list of the device
+pub fn unknown_scene_name(value: &JsonValue) -> String {
+    match scene_code(value) {
+        JsonValue::String(code) => format!("Unknown scene ({code})"),
+        code => format!("Unknown scene ({code})"),
+    }
+}
+
+/// Device lists captured from the Platform API, for use by the tests
+/// of the modules that build entities from them
+#[cfg(test)]
+pub mod fixtures {
+    use super::{from_json, GetDevicesResponse, HttpDeviceInfo};
+
+    pub const LIST_DEVICES: &str = include_str!("../test-data/list_devices.json");
+    pub const LIST_DEVICES_2: &str = include_str!("../test-data/list_devices_2.json");
+    pub const LIST_DEVICES_ISSUE4: &str = include_str!("../test-data/list_devices_issue4.json");
+    pub const LIST_DEVICES_COLOR_TEMP: &str =
+        include_str!("../test-data/list_devices_color_temp.json");
+
+    /// Returns the devices from one of the captured device lists
+    pub fn devices(json: &str) -> Vec<HttpDeviceInfo> {
+        from_json::<GetDevicesResponse, _>(json).unwrap().data
+    }
+
+    /// Returns the device with the given SKU from one of the captured
+    /// device lists
+    pub fn device(json: &str, sku: &str) -> HttpDeviceInfo {
+        devices(json)
+            .into_iter()
+            .find(|d| d.sku == sku)
+            .unwrap_or_else(|| panic!("no {sku} in the device list"))
     }
 }
 
@@ -1117,7 +1629,7 @@ mod test {
 
     #[test]
     fn get_device_scenes() {
-        let resp: GetDeviceScenesResponse = from_json(&SCENE_LIST).unwrap();
+        let resp: GetDeviceScenesResponse = from_json(SCENE_LIST).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
@@ -1125,32 +1637,92 @@ mod test {
 
     #[test]
     fn get_device_state() {
-        let resp: GetDeviceStateResponse = from_json(&GET_DEVICE_STATE_EXAMPLE).unwrap();
+        let resp: GetDeviceStateResponse = from_json(GET_DEVICE_STATE_EXAMPLE).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
-    const LIST_DEVICES_EXAMPLE: &str = include_str!("../test-data/list_devices.json");
-    const LIST_DEVICES_EXAMPLE2: &str = include_str!("../test-data/list_devices_2.json");
-
     #[test]
     fn list_devices_issue4() {
-        let resp: GetDevicesResponse =
-            from_json(&include_str!("../test-data/list_devices_issue4.json")).unwrap();
+        let resp: GetDevicesResponse = from_json(fixtures::LIST_DEVICES_ISSUE4).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
     #[test]
     fn list_devices_2() {
-        let resp: GetDevicesResponse = from_json(&LIST_DEVICES_EXAMPLE2).unwrap();
+        let resp: GetDevicesResponse = from_json(fixtures::LIST_DEVICES_2).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
     #[test]
     fn list_devices() {
-        let resp: GetDevicesResponse = from_json(&LIST_DEVICES_EXAMPLE).unwrap();
+        let resp: GetDevicesResponse = from_json(fixtures::LIST_DEVICES).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn diy_scene_names() {
+        let resp: GetDevicesResponse = from_json(fixtures::LIST_DEVICES).unwrap();
+        let device = &resp.data[0];
+        let diy = device.capability_by_instance(DIY_SCENE_INSTANCE).unwrap();
+        k9::assert_equal!(scene_display_name(diy, "Fade"), "DIY: Fade");
+        let stock = device.capability_by_instance("lightScene").unwrap();
+        k9::assert_equal!(scene_display_name(stock, "Fade"), "Fade");
+        let snapshot = device.capability_by_instance(SNAPSHOT_INSTANCE).unwrap();
+        k9::assert_equal!(scene_display_name(snapshot, "Sunset"), "Snapshot: Sunset");
+        assert!(is_snapshot_scene_name("Snapshot: Sunset"));
+    }
+
+    #[test]
+    fn scene_by_value() {
+        let resp: GetDevicesResponse = from_json(fixtures::LIST_DEVICES).unwrap();
+        let caps = &resp.data[0].capabilities;
+        k9::assert_equal!(
+            scene_name_by_value(caps, LIGHT_SCENE_INSTANCE, &json!(3055)),
+            Some("Party".to_string())
+        );
+        k9::assert_equal!(
+            scene_name_by_value(caps, DIY_SCENE_INSTANCE, &json!({"id": 8216567})),
+            Some("DIY: Fade".to_string())
+        );
+        k9::assert_equal!(
+            scene_name_by_value(caps, SNAPSHOT_INSTANCE, &json!(0)),
+            Some("Snapshot: Sunrise".to_string())
+        );
+        k9::assert_equal!(
+            scene_name_by_value(caps, LIGHT_SCENE_INSTANCE, &json!(8216567)),
+            None
+        );
+        k9::assert_equal!(
+            unknown_scene_name(&json!({"id": 1234, "paramId": 99})),
+            "Unknown scene (1234)"
+        );
+        k9::assert_equal!(unknown_scene_name(&json!("abc")), "Unknown scene (abc)");
+    }
+
+    #[test]
+    fn target_temperature() {
+        let heater = fixtures::device(fixtures::LIST_DEVICES_ISSUE4, "H7131");
+        let cap = heater.capability_by_instance("targetTemperature").unwrap();
+
+        k9::assert_equal!(
+            target_temperature_value(cap, TemperatureValue::with_fahrenheit(68.)).unwrap(),
+            json!({"temperature": 68.0, "unit": "Fahrenheit"})
+        );
+        k9::assert_equal!(
+            target_temperature_value(cap, TemperatureValue::with_fahrenheit(67.)).unwrap(),
+            json!({"temperature": 67.0, "unit": "Fahrenheit"})
+        );
+        // The range is 5-30C, which is 41-86F
+        k9::assert_equal!(
+            target_temperature_value(cap, TemperatureValue::with_fahrenheit(100.)).unwrap(),
+            json!({"temperature": 86.0, "unit": "Fahrenheit"})
+        );
+        k9::assert_equal!(
+            target_temperature_value(cap, TemperatureValue::with_celsius(2.)).unwrap(),
+            json!({"temperature": 5.0, "unit": "Celsius"})
+        );
+    }
+
     #[test]
     fn enum_repr() {
         k9::assert_equal!(
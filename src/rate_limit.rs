@@ -0,0 +1,334 @@
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::time::Duration;
+use thiserror::Error;
+
+/// The suspension applied after the first rate limited response,
+/// if the server didn't tell us how long to wait
+const INITIAL_BACKOFF: Duration = Duration::from_secs(60);
+/// The longest that repeated rate limiting will suspend requests
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Govee reports the daily quota via the API-RateLimit headers
+/// and the per-minute quota via the X-RateLimit headers
+const DAY_REMAINING_HEADER: &str = "API-RateLimit-Remaining";
+const DAY_RESET_HEADER: &str = "API-RateLimit-Reset";
+const MINUTE_REMAINING_HEADER: &str = "X-RateLimit-Remaining";
+const MINUTE_RESET_HEADER: &str = "X-RateLimit-Reset";
+
+/// Headers that hold the time at which the request quota resets
+const RESET_HEADERS: &[&str] = &[DAY_RESET_HEADER, MINUTE_RESET_HEADER];
+
+/// The request quota as most recently reported by the server
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaStatus {
+    pub minute_remaining: Option<u64>,
+    pub minute_reset: Option<DateTime<Utc>>,
+    pub day_remaining: Option<u64>,
+    pub day_reset: Option<DateTime<Utc>>,
+}
+
+impl QuotaStatus {
+    pub fn from_headers(headers: &HeaderMap, now: DateTime<Utc>) -> Self {
+        let remaining = |name| header_str(headers, name).and_then(|v| v.parse().ok());
+        let reset = |name| {
+            header_str(headers, name)
+                .and_then(|v| v.parse().ok())
+                .and_then(|v| parse_reset_time(v, now))
+        };
+        Self {
+            minute_remaining: remaining(MINUTE_REMAINING_HEADER),
+            minute_reset: reset(MINUTE_RESET_HEADER),
+            day_remaining: remaining(DAY_REMAINING_HEADER),
+            day_reset: reset(DAY_RESET_HEADER),
+        }
+    }
+
+    /// Updates self with the values that are present in other;
+    /// values missing from other are left untouched
+    pub fn merge(&mut self, other: &Self) {
+        fn merge_field<T: Copy>(field: &mut Option<T>, other: Option<T>) {
+            if other.is_some() {
+                *field = other;
+            }
+        }
+        merge_field(&mut self.minute_remaining, other.minute_remaining);
+        merge_field(&mut self.minute_reset, other.minute_reset);
+        merge_field(&mut self.day_remaining, other.day_remaining);
+        merge_field(&mut self.day_reset, other.day_reset);
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim())
+}
+
+/// Interprets the value of a reset header, which may be either
+/// a unix timestamp, in seconds or milliseconds, or a number of
+/// seconds from now
+fn parse_reset_time(value: i64, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if value > 100_000_000_000 {
+        DateTime::from_timestamp_millis(value)
+    } else if value > 1_000_000_000 {
+        DateTime::from_timestamp(value, 0)
+    } else {
+        Some(now + chrono::Duration::seconds(value.max(0)))
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("{api} requests are suspended until {until} because the request quota was exceeded")]
+pub struct RateLimited {
+    pub api: &'static str,
+    pub until: DateTime<Utc>,
+}
+
+impl RateLimited {
+    pub fn from_err(err: &anyhow::Error) -> Option<&Self> {
+        err.root_cause().downcast_ref::<Self>()
+    }
+}
+
+#[derive(Default, Debug)]
+struct LimitState {
+    until: Option<DateTime<Utc>>,
+    /// The number of rate limited responses since the last
+    /// successful request
+    consecutive: u32,
+    /// When the most recent successful request completed
+    last_success: Option<DateTime<Utc>>,
+}
+
+/// Tracks whether an API has told us that we've exceeded our
+/// request quota, so that we can stop making requests until the
+/// quota resets, rather than prolonging the lockout
+#[derive(Debug)]
+pub struct RateLimiter {
+    api: &'static str,
+    state: Mutex<LimitState>,
+}
+
+impl RateLimiter {
+    pub fn new(api: &'static str) -> Self {
+        Self {
+            api,
+            state: Mutex::new(LimitState::default()),
+        }
+    }
+
+    /// Returns the time until which requests are suspended,
+    /// or None if requests are currently permitted
+    pub fn limited_until(&self) -> Option<DateTime<Utc>> {
+        self.state.lock().until.filter(|until| *until > Utc::now())
+    }
+
+    /// Returns an error if requests are currently suspended
+    pub fn check(&self) -> Result<(), RateLimited> {
+        match self.limited_until() {
+            Some(until) => Err(RateLimited {
+                api: self.api,
+                until,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the time at which the most recent successful
+    /// request completed
+    pub fn last_success(&self) -> Option<DateTime<Utc>> {
+        self.state.lock().last_success
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock();
+        state.consecutive = 0;
+        state.last_success.replace(Utc::now());
+    }
+
+    /// Records a rate limited response, suspending requests for the
+    /// time indicated by the server, or for an exponentially increasing
+    /// time if we keep getting rate limited. Returns the time until
+    /// which requests are suspended.
+    pub fn record_rate_limited(&self, retry_after: Option<Duration>) -> DateTime<Utc> {
+        let mut state = self.state.lock();
+        state.consecutive += 1;
+        let delay = with_jitter(
+            suspension_delay(state.consecutive, retry_after),
+            uuid::Uuid::new_v4().as_u128() as u64,
+        );
+        let until = Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default();
+        if state.until.map(|prior| prior < until).unwrap_or(true) {
+            state.until.replace(until);
+        }
+        state.until.unwrap_or(until)
+    }
+}
+
+/// Computes how long to suspend requests for after the `consecutive`th
+/// rate limited response in a row
+fn suspension_delay(consecutive: u32, retry_after: Option<Duration>) -> Duration {
+    let backoff = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(consecutive.saturating_sub(1)))
+        .min(MAX_BACKOFF);
+    match retry_after {
+        // Take the server at its word the first time, but if it
+        // keeps rate limiting us, wait longer than it asks
+        Some(retry_after) if consecutive <= 1 => retry_after,
+        Some(retry_after) => retry_after.max(backoff),
+        None => backoff,
+    }
+}
+
+/// Adds up to 10% to the delay, so that several instances sharing a
+/// quota don't all resume at the same instant
+pub fn with_jitter(delay: Duration, random: u64) -> Duration {
+    let max_jitter = (delay.as_millis() / 10) as u64;
+    if max_jitter == 0 {
+        return delay;
+    }
+    delay + Duration::from_millis(random % max_jitter)
+}
+
+/// Determines how long the server asked us to wait, either via
+/// a Retry-After header, or via a header holding the time at
+/// which the quota resets
+pub fn parse_retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    if let Some(value) = header_str(headers, RETRY_AFTER.as_str()) {
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        if let Ok(when) = DateTime::parse_from_rfc2822(value) {
+            return (when.with_timezone(&Utc) - now).to_std().ok();
+        }
+    }
+
+    RESET_HEADERS
+        .iter()
+        .filter_map(|name| header_str(headers, name)?.parse().ok())
+        .filter_map(|value| parse_reset_time(value, now))
+        .map(|when| (when - now).to_std().unwrap_or_default())
+        .next()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn backoff() {
+        k9::assert_equal!(suspension_delay(1, None), Duration::from_secs(60));
+        k9::assert_equal!(suspension_delay(2, None), Duration::from_secs(120));
+        k9::assert_equal!(suspension_delay(3, None), Duration::from_secs(240));
+        k9::assert_equal!(suspension_delay(30, None), MAX_BACKOFF);
+        k9::assert_equal!(
+            suspension_delay(1, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        k9::assert_equal!(
+            suspension_delay(2, Some(Duration::from_secs(5))),
+            Duration::from_secs(120)
+        );
+        k9::assert_equal!(
+            suspension_delay(2, Some(Duration::from_secs(600))),
+            Duration::from_secs(600)
+        );
+
+        k9::assert_equal!(
+            with_jitter(Duration::from_secs(60), 2_500),
+            Duration::from_millis(62_500)
+        );
+        k9::assert_equal!(
+            with_jitter(Duration::from_secs(60), 6_000),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = DateTime::parse_from_rfc3339("2024-01-10T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let headers = |name: &'static str, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+
+        k9::assert_equal!(parse_retry_after(&HeaderMap::new(), now), None);
+        k9::assert_equal!(
+            parse_retry_after(&headers("retry-after", "30"), now),
+            Some(Duration::from_secs(30))
+        );
+        k9::assert_equal!(
+            parse_retry_after(
+                &headers("retry-after", "Wed, 10 Jan 2024 12:01:00 GMT"),
+                now
+            ),
+            Some(Duration::from_secs(60))
+        );
+        k9::assert_equal!(
+            parse_retry_after(&headers("api-ratelimit-reset", "1704888120"), now),
+            Some(Duration::from_secs(120))
+        );
+        k9::assert_equal!(
+            parse_retry_after(&headers("x-ratelimit-reset", "1704888000500"), now),
+            Some(Duration::from_millis(500))
+        );
+        k9::assert_equal!(
+            parse_retry_after(&headers("x-ratelimit-reset", "45"), now),
+            Some(Duration::from_secs(45))
+        );
+    }
+
+    #[test]
+    fn quota() {
+        let now = DateTime::parse_from_rfc3339("2024-01-10T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("9"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("30"));
+        headers.insert("api-ratelimit-remaining", HeaderValue::from_static("9000"));
+
+        let mut quota = QuotaStatus {
+            day_reset: Some(now),
+            ..Default::default()
+        };
+        quota.merge(&QuotaStatus::from_headers(&headers, now));
+        k9::assert_equal!(
+            quota,
+            QuotaStatus {
+                minute_remaining: Some(9),
+                minute_reset: Some(now + chrono::Duration::seconds(30)),
+                day_remaining: Some(9000),
+                day_reset: Some(now),
+            }
+        );
+
+        // Missing headers leave the prior values alone
+        quota.merge(&QuotaStatus::from_headers(&HeaderMap::new(), now));
+        k9::assert_equal!(quota.day_remaining, Some(9000));
+    }
+
+    #[test]
+    fn suspension() {
+        let limiter = RateLimiter::new("test");
+        assert!(limiter.check().is_ok());
+
+        let until = limiter.record_rate_limited(Some(Duration::from_secs(600)));
+        k9::assert_equal!(limiter.limited_until(), Some(until));
+        let err: anyhow::Error = limiter.check().unwrap_err().into();
+        k9::assert_equal!(RateLimited::from_err(&err).map(|e| e.until), Some(until));
+
+        // A shorter suspension doesn't cut short the current one
+        k9::assert_equal!(limiter.record_rate_limited(Some(Duration::ZERO)), until);
+
+        limiter.record_success();
+        k9::assert_equal!(limiter.state.lock().consecutive, 0);
+    }
+}
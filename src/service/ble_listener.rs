@@ -0,0 +1,99 @@
+//! Passively listens for the BLE advertisements of Govee's
+//! thermo-hygrometers, which carry their current readings, and feeds
+//! the readings that we can decode into the state.
+use crate::ble_adv::{decode_advertisement, sku_from_local_name};
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, Manager, PeripheralId};
+use futures::StreamExt;
+use std::collections::HashMap;
+use tokio::time::{sleep, Duration};
+
+/// How often we check whether the BLE sensors have stopped advertising
+const AVAILABILITY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub async fn run_ble_listener(state: StateHandle) {
+    tokio::spawn(periodic_ble_availability(state.clone()));
+    if let Err(err) = listen(&state).await {
+        log::error!("BLE listener: {err:#}");
+    }
+}
+
+async fn listen(state: &StateHandle) -> anyhow::Result<()> {
+    let manager = Manager::new()
+        .await
+        .context("connecting to the Bluetooth stack")?;
+    let adapter = manager
+        .adapters()
+        .await
+        .context("listing Bluetooth adapters")?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No Bluetooth adapter was found"))?;
+
+    let mut events = adapter.events().await?;
+    adapter
+        .start_scan(ScanFilter::default())
+        .await
+        .context("starting BLE scan")?;
+
+    while let Some(event) = events.next().await {
+        if let CentralEvent::ManufacturerDataAdvertisement {
+            id,
+            manufacturer_data,
+        } = event
+        {
+            if let Err(err) = handle_advertisement(state, &adapter, &id, manufacturer_data).await {
+                log::warn!("while handling BLE advertisement from {id:?}: {err:#}");
+            }
+        }
+    }
+
+    anyhow::bail!("BLE event stream ended");
+}
+
+async fn handle_advertisement(
+    state: &StateHandle,
+    adapter: &Adapter,
+    id: &PeripheralId,
+    manufacturer_data: HashMap<u16, Vec<u8>>,
+) -> anyhow::Result<()> {
+    let peripheral = adapter.peripheral(id).await?;
+    let Some(properties) = peripheral.properties().await? else {
+        return Ok(());
+    };
+    // Govee's sensors advertise their SKU as part of their name
+    let Some(sku) = properties
+        .local_name
+        .as_deref()
+        .and_then(sku_from_local_name)
+    else {
+        return Ok(());
+    };
+
+    for (manufacturer_id, data) in manufacturer_data {
+        if let Some(reading) = decode_advertisement(&sku, manufacturer_id, &data) {
+            let mac = properties.address.to_string();
+            log::trace!("BLE advertisement from {sku} {mac}: {reading:?}");
+            state.ingest_ble_advertisement(&mac, &sku, reading).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Advertisements are the only sign that a BLE sensor is still in
+/// range, so we look for those that have gone quiet
+async fn periodic_ble_availability(state: StateHandle) {
+    loop {
+        sleep(AVAILABILITY_CHECK_INTERVAL).await;
+        for device in state.devices().await {
+            if device.last_ble_reading_update.is_none() {
+                continue;
+            }
+            if let Err(err) = state.report_device_availability(&device, false).await {
+                log::error!("while reporting availability of {device}: {err:#}");
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! The commands for a device are sent one at a time, in the order
+//! that they arrived, so that an automation that changes the power,
+//! brightness and color of a device together doesn't have them
+//! applied out of order. A command that is still waiting when a newer
+//! command of the same kind arrives is dropped, since the newer one
+//! would immediately replace it, and consecutive commands are spaced
+//! apart so that the device, or the Govee cloud, doesn't throttle them.
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, Instant};
+
+pub const DEFAULT_LAN_COMMAND_INTERVAL: Duration = Duration::from_millis(100);
+pub const DEFAULT_CLOUD_COMMAND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The minimum time between the end of one command and the start
+/// of the next, depending on how the device is controlled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CommandIntervals {
+    pub lan: Duration,
+    pub cloud: Duration,
+}
+
+impl Default for CommandIntervals {
+    fn default() -> Self {
+        Self {
+            lan: DEFAULT_LAN_COMMAND_INTERVAL,
+            cloud: DEFAULT_CLOUD_COMMAND_INTERVAL,
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_id: u64,
+    /// The id of the newest waiting command of each kind
+    newest: HashMap<String, u64>,
+    waiting: usize,
+    last_finished: Option<Instant>,
+}
+
+pub struct CommandQueue {
+    semaphore: Arc<Semaphore>,
+    state: Arc<Mutex<QueueState>>,
+}
+
+/// Held while a command is sent to the device. The next command
+/// is spaced from the time that this is dropped.
+pub struct CommandPermit {
+    id: u64,
+    #[allow(unused)]
+    permit: OwnedSemaphorePermit,
+    state: Arc<Mutex<QueueState>>,
+}
+
+impl CommandPermit {
+    /// Identifies the command in the logs
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for CommandPermit {
+    fn drop(&mut self) {
+        self.state.lock().last_finished.replace(Instant::now());
+    }
+}
+
+pub enum Admission {
+    Ready(CommandPermit),
+    /// A newer command of the same kind arrived while this one
+    /// was waiting
+    Superseded {
+        id: u64,
+    },
+}
+
+impl Default for CommandQueue {
+    fn default() -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(1)),
+            state: Arc::new(Mutex::new(QueueState::default())),
+        }
+    }
+}
+
+impl CommandQueue {
+    /// The number of commands that are waiting for their turn
+    pub fn depth(&self) -> usize {
+        self.state.lock().waiting
+    }
+
+    /// Waits for the commands ahead of this one to finish, and then
+    /// for `interval` to pass. When `kind` is given, the command is
+    /// superseded by a newer command of the same kind that arrives
+    /// while it is waiting; commands without a kind are always sent.
+    pub async fn admit(&self, kind: Option<&str>, interval: Duration) -> anyhow::Result<Admission> {
+        let id = {
+            let mut state = self.state.lock();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.waiting += 1;
+            if let Some(kind) = kind {
+                state.newest.insert(kind.to_string(), id);
+            }
+            id
+        };
+
+        let permit = self.semaphore.clone().acquire_owned().await;
+
+        let last_finished = {
+            let mut state = self.state.lock();
+            state.waiting -= 1;
+            if let Some(kind) = kind {
+                if state.newest.get(kind) != Some(&id) {
+                    return Ok(Admission::Superseded { id });
+                }
+                state.newest.remove(kind);
+            }
+            state.last_finished
+        };
+        let permit = permit?;
+
+        if let Some(last) = last_finished {
+            tokio::time::sleep_until(last + interval).await;
+        }
+
+        Ok(Admission::Ready(CommandPermit {
+            id,
+            permit,
+            state: self.state.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn supersede() {
+        let queue = Arc::new(CommandQueue::default());
+        let interval = Duration::ZERO;
+
+        let Admission::Ready(first) = queue.admit(Some("brightness"), interval).await.unwrap()
+        else {
+            panic!("the first command is ready");
+        };
+
+        let spawn = |kind: &'static str| {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                match queue.admit(Some(kind), interval).await.unwrap() {
+                    Admission::Ready(permit) => Some(permit.id()),
+                    Admission::Superseded { .. } => None,
+                }
+            })
+        };
+        let older = spawn("brightness");
+        let color = spawn("color");
+        let newer = spawn("brightness");
+
+        // Let them all start waiting
+        while queue.depth() < 3 {
+            tokio::task::yield_now().await;
+        }
+        drop(first);
+
+        k9::assert_equal!(older.await.unwrap(), None);
+        k9::assert_equal!(color.await.unwrap(), Some(3));
+        k9::assert_equal!(newer.await.unwrap(), Some(4));
+        k9::assert_equal!(queue.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn spacing() {
+        let queue = CommandQueue::default();
+        let interval = Duration::from_millis(50);
+
+        let Admission::Ready(first) = queue.admit(None, interval).await.unwrap() else {
+            panic!("commands without a kind are never superseded");
+        };
+        drop(first);
+        let finished = Instant::now();
+
+        let Admission::Ready(_second) = queue.admit(None, interval).await.unwrap() else {
+            panic!("commands without a kind are never superseded");
+        };
+        assert!(finished.elapsed() >= Duration::from_millis(45));
+    }
+}
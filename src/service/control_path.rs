@@ -0,0 +1,167 @@
+//! The order in which the LAN, IoT and Platform APIs are tried when
+//! controlling the power, brightness and color of a device. The built-in
+//! order may be replaced for all devices via `$GOVEE_CONTROL_ORDER`, or
+//! for individual devices via the `control_order` of their section of the
+//! configuration file. A transport that is left out of the list is never
+//! used for those commands.
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Lan,
+    Iot,
+    Platform,
+}
+
+impl Transport {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Lan => "lan",
+            Self::Iot => "iot",
+            Self::Platform => "platform",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "lan" => Some(Self::Lan),
+            "iot" => Some(Self::Iot),
+            "platform" => Some(Self::Platform),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+pub const DEFAULT_CONTROL_ORDER: &[Transport] =
+    &[Transport::Lan, Transport::Iot, Transport::Platform];
+
+/// A command was sent via a transport, but confirming that the device
+/// applied it failed. Trying the next transport would send it again,
+/// so unlike a failure to send, this ends the command.
+#[derive(thiserror::Error, Debug)]
+#[error("sent, but not confirmed: {0:#}")]
+pub struct Unconfirmed(pub anyhow::Error);
+
+impl Unconfirmed {
+    pub fn from_err(err: &anyhow::Error) -> Option<&Self> {
+        err.root_cause().downcast_ref::<Self>()
+    }
+}
+
+/// Parses a list of transports, such as `["lan", "platform"]`
+pub fn parse_control_order<S: AsRef<str>>(items: &[S]) -> anyhow::Result<Vec<Transport>> {
+    let mut order = vec![];
+    for item in items {
+        let item = item.as_ref();
+        if item.trim().is_empty() {
+            continue;
+        }
+        let transport = Transport::parse(item).ok_or_else(|| {
+            anyhow::anyhow!("`{item}` is not a transport; use lan, iot or platform")
+        })?;
+        if order.contains(&transport) {
+            anyhow::bail!("`{transport}` is listed more than once");
+        }
+        order.push(transport);
+    }
+    if order.is_empty() {
+        anyhow::bail!("the list is empty");
+    }
+    Ok(order)
+}
+
+/// The invalid lists that we have already warned about, so that
+/// the warning isn't repeated for every command
+static WARNED: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Parses `items`, which came from `source`, or warns and returns
+/// the built-in order if they are not valid
+fn order_or_default(source: &str, items: &[String]) -> Vec<Transport> {
+    match parse_control_order(items) {
+        Ok(order) => order,
+        Err(err) => {
+            if WARNED
+                .lock()
+                .insert(format!("{source}={}", items.join(",")))
+            {
+                log::warn!(
+                    "Ignoring {source} {items:?}: {err}. Using the built-in order {}",
+                    format_control_order(DEFAULT_CONTROL_ORDER)
+                );
+            }
+            DEFAULT_CONTROL_ORDER.to_vec()
+        }
+    }
+}
+
+/// Returns the order in which to try the transports for a device.
+/// `device_order` is the `control_order` from its configuration,
+/// which takes precedence over `$GOVEE_CONTROL_ORDER`.
+pub fn resolve_control_order(device_order: Option<&[String]>) -> Vec<Transport> {
+    if let Some(items) = device_order {
+        return order_or_default("the control_order of the device", items);
+    }
+    match crate::opt_env_var::<String>("GOVEE_CONTROL_ORDER") {
+        Ok(Some(list)) => {
+            let items: Vec<String> = list.split(',').map(str::to_string).collect();
+            order_or_default("GOVEE_CONTROL_ORDER", &items)
+        }
+        Ok(None) => DEFAULT_CONTROL_ORDER.to_vec(),
+        Err(err) => {
+            log::warn!("{err:#}");
+            DEFAULT_CONTROL_ORDER.to_vec()
+        }
+    }
+}
+
+pub fn format_control_order(order: &[Transport]) -> String {
+    order
+        .iter()
+        .map(|t| t.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn control_order() {
+        k9::assert_equal!(
+            parse_control_order(&["iot", " LAN "]).unwrap(),
+            vec![Transport::Iot, Transport::Lan]
+        );
+        k9::assert_equal!(
+            parse_control_order(&["lan", "ble"])
+                .unwrap_err()
+                .to_string(),
+            "`ble` is not a transport; use lan, iot or platform"
+        );
+        k9::assert_equal!(
+            parse_control_order(&["lan", "lan"])
+                .unwrap_err()
+                .to_string(),
+            "`lan` is listed more than once"
+        );
+        assert!(parse_control_order::<&str>(&[]).is_err());
+        assert!(parse_control_order(&[""]).is_err());
+
+        k9::assert_equal!(
+            resolve_control_order(Some(&["platform".to_string()])),
+            vec![Transport::Platform]
+        );
+        k9::assert_equal!(
+            resolve_control_order(Some(&[])),
+            DEFAULT_CONTROL_ORDER.to_vec()
+        );
+    }
+}
@@ -1,6 +1,6 @@
+use crate::service::command_queue::CommandPermit;
 use crate::service::device::Device;
 use tokio::sync::oneshot::Sender as OneShotSender;
-use tokio::sync::OwnedSemaphorePermit;
 
 /// The Coordinator ensures that only one task at a time can
 /// be processing requests that control or otherwise change
@@ -20,18 +20,13 @@ pub struct Coordinator {
     // These fields are not unused; we are keeping them
     // alive until we drop at which point they release
     // resources and/or trigger follow up work in other tasks.
-    #[allow(unused)]
-    permit: OwnedSemaphorePermit,
+    permit: CommandPermit,
     #[allow(unused)]
     trigger_poll: OneShotSender<()>,
 }
 
 impl Coordinator {
-    pub fn new(
-        device: Device,
-        permit: OwnedSemaphorePermit,
-        trigger_poll: OneShotSender<()>,
-    ) -> Self {
+    pub fn new(device: Device, permit: CommandPermit, trigger_poll: OneShotSender<()>) -> Self {
         Self {
             device,
             permit,
@@ -48,8 +43,17 @@ impl std::ops::Deref for Coordinator {
     }
 }
 
+impl Coordinator {
+    /// Identifies the command in the logs
+    pub fn command_id(&self) -> u64 {
+        self.permit.id()
+    }
+}
+
+/// Includes the command id, so that the outcome of a command can be
+/// matched up with the request for it when several are queued
 impl std::fmt::Display for Coordinator {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        self.device.fmt(fmt)
+        write!(fmt, "{} (command #{})", self.device, self.command_id())
     }
 }
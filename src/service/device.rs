@@ -1,15 +1,113 @@
 use crate::ble::NotifyHumidifierNightlightParams;
+use crate::ble_adv::BleReading;
 use crate::commands::serve::POLL_INTERVAL;
 use crate::lan_api::{DeviceColor, DeviceStatus as LanDeviceStatus, LanDevice};
 use crate::platform_api::{
-    DeviceCapability, DeviceCapabilityState, DeviceType, HttpDeviceInfo, HttpDeviceState,
+    DeviceCapability, DeviceCapabilityKind, DeviceCapabilityState, DeviceType, HttpDeviceInfo,
+    HttpDeviceState, SCENE_INSTANCES,
 };
-use crate::service::quirks::{resolve_quirk, Quirk, BULB};
+use crate::service::quirks::{resolve_quirk, HumidityUnits, Quirk, BULB};
+use crate::temperature::{TemperatureScale, TemperatureUnits, TemperatureValue};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The platform API instance that reports the battery level
+pub const BATTERY_INSTANCE: &str = "battery";
+
+/// LAN devices respond to the discovery scan at least once a minute,
+/// so if we haven't heard from one in this long, it is unreachable
+/// via the LAN
+fn lan_availability_timeout() -> chrono::Duration {
+    chrono::Duration::minutes(5)
+}
+
+/// The number of consecutive LAN status requests that a device
+/// can fail to respond to before we consider it to be unreachable
+/// via the LAN
+pub const DEFAULT_LAN_MAX_MISSED_POLLS: u32 = 3;
+static LAN_MAX_MISSED_POLLS: AtomicU32 = AtomicU32::new(DEFAULT_LAN_MAX_MISSED_POLLS);
+
+pub fn set_lan_max_missed_polls(n: u32) {
+    LAN_MAX_MISSED_POLLS.store(n, Ordering::Relaxed);
+}
+
+fn lan_max_missed_polls() -> u32 {
+    LAN_MAX_MISSED_POLLS.load(Ordering::Relaxed)
+}
+
+/// The IoT and platform APIs are consulted once per POLL_INTERVAL
+/// in the absence of other updates, so allow for a couple of missed
+/// polls before considering the device to be unreachable
+fn poll_availability_timeout() -> chrono::Duration {
+    *POLL_INTERVAL * 3
+}
+
+/// BLE sensors advertise their readings every few seconds, so one
+/// that we haven't heard from in this many minutes is out of range
+/// or has a flat battery
+pub const DEFAULT_BLE_AVAILABILITY_TIMEOUT: u32 = 10;
+static BLE_AVAILABILITY_TIMEOUT: AtomicU32 = AtomicU32::new(DEFAULT_BLE_AVAILABILITY_TIMEOUT);
+
+pub fn set_ble_availability_timeout(minutes: u32) {
+    BLE_AVAILABILITY_TIMEOUT.store(minutes, Ordering::Relaxed);
+}
+
+fn ble_availability_timeout() -> chrono::Duration {
+    chrono::Duration::minutes(BLE_AVAILABILITY_TIMEOUT.load(Ordering::Relaxed).into())
+}
+
+/// Whether a device is reachable via each of the paths that we have
+/// to it. A path is `None` when we have never heard from the device
+/// that way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reachability {
+    /// It responds to LAN discovery and status requests
+    pub lan: Option<bool>,
+    /// Its state changes have recently been pushed via AWS IoT
+    pub iot: Option<bool>,
+    /// The Platform API recently reported it as online
+    pub platform: Option<bool>,
+    /// We recently received one of its BLE advertisements
+    pub ble: Option<bool>,
+}
+
+impl Reachability {
+    /// Returns true if the device is reachable by any path
+    pub fn any(&self) -> bool {
+        [self.lan, self.iot, self.platform, self.ble].contains(&Some(true))
+    }
+
+    /// Returns true if we have never heard from the device
+    pub fn is_unknown(&self) -> bool {
+        self.lan.is_none() && self.iot.is_none() && self.platform.is_none() && self.ble.is_none()
+    }
+}
+
+/// The range outside of which a sensor alerts in the Govee app
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SensorAlarm {
+    pub min: f64,
+    pub max: f64,
+    /// Whether the alert is turned on
+    pub enabled: bool,
+}
+
+impl SensorAlarm {
+    /// Builds the alarm from the settings of the undocumented API,
+    /// which are in hundredths
+    fn new(min: Option<i64>, max: Option<i64>, enabled: Option<bool>) -> Option<Self> {
+        Some(Self {
+            min: min? as f64 / 100.,
+            max: max? as f64 / 100.,
+            enabled: enabled.unwrap_or(false),
+        })
+    }
+}
 
 #[derive(Default, Clone, Debug)]
 pub struct Device {
@@ -23,6 +121,9 @@ pub struct Device {
 
     pub lan_device_status: Option<LanDeviceStatus>,
     pub last_lan_device_status_update: Option<DateTime<Utc>>,
+    /// The number of consecutive LAN status requests that the
+    /// device has not responded to
+    pub lan_missed_polls: u32,
 
     pub http_device_info: Option<HttpDeviceInfo>,
     pub last_http_device_update: Option<DateTime<Utc>>,
@@ -43,7 +144,69 @@ pub struct Device {
 
     pub last_polled: Option<DateTime<Utc>>,
 
+    /// The most recent reading received via a BLE advertisement
+    pub ble_reading: Option<BleReading>,
+    pub last_ble_reading_update: Option<DateTime<Utc>>,
+
+    /// The state implied by the most recent successful command,
+    /// until a newer state is reported by the device or the cloud
+    optimistic_state: Option<DeviceState>,
+
     active_scene: Option<ActiveSceneInfo>,
+    /// The scene value most recently reported in the platform state,
+    /// so that each change of scene is only resolved once
+    reported_scene: Option<(String, JsonValue)>,
+
+    /// When each event instance was last pushed to us
+    event_times: HashMap<String, DateTime<Utc>>,
+
+    /// When we last received any data for the device, whether via
+    /// the LAN API, AWS IoT, BLE or a platform API poll. Our own
+    /// optimistic state changes don't count.
+    last_seen: Option<DateTime<Utc>>,
+
+    /// The state that was restored from the cache at startup and
+    /// hasn't yet been replaced by a report from the device
+    restored: RestoredState,
+}
+
+/// Which of the LAN, IoT and platform states of a device were
+/// restored from the cache
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+struct RestoredState {
+    lan: bool,
+    iot: bool,
+    http: bool,
+}
+
+/// The color temperature range in kelvin of most Govee lights
+const DEFAULT_COLOR_TEMP_RANGE: (u32, u32) = (2000, 9000);
+
+/// The source of the state that is assumed after a successful
+/// command, before it was confirmed
+pub const OPTIMISTIC_SOURCE: &str = "OPTIMISTIC";
+
+/// A change that a successful command is expected to have made
+/// to the state of a device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpectedChange {
+    Power(bool),
+    LightPower(bool),
+    Brightness(u8),
+    ColorTemperature(u32),
+    Color(DeviceColor),
+    WorkMode { mode: u8, param: u8 },
+}
+
+/// The last known state of a device, which is persisted so that it
+/// can be published immediately after a restart. Each state is stored
+/// with the time at which it was received.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceStateSnapshot {
+    pub saved: DateTime<Utc>,
+    lan_device_status: Option<(LanDeviceStatus, DateTime<Utc>)>,
+    iot_device_status: Option<(LanDeviceStatus, DateTime<Utc>)>,
+    http_device_state: Option<(HttpDeviceState, DateTime<Utc>)>,
 }
 
 impl std::fmt::Display for Device {
@@ -52,9 +215,10 @@ impl std::fmt::Display for Device {
     }
 }
 
-/// Govee doesn't report the active scene or music mode,
-/// so we maintain our own idea of it, clearing it when
-/// the color of the light is changed
+/// The platform state of some devices reports the active scene, but
+/// the others don't report the active scene or music mode, so we
+/// maintain our own idea of it, clearing it when the color of the
+/// light is changed
 #[derive(Clone, Debug)]
 struct ActiveSceneInfo {
     pub name: String,
@@ -122,6 +286,12 @@ impl Device {
         self.computed_name()
     }
 
+    /// Returns the options from the configuration file that apply
+    /// to this device
+    pub fn config(&self) -> crate::config::DeviceConfig {
+        crate::config::current().device_config(&self.id, &self.name(), &self.sku)
+    }
+
     /// Returns the name defined for the device in the Govee App
     pub fn govee_name(&self) -> Option<&str> {
         if let Some(info) = &self.http_device_info {
@@ -130,9 +300,184 @@ impl Device {
         None
     }
 
+    /// Returns the firmware version reported via the undocumented API
+    pub fn firmware_version(&self) -> Option<&str> {
+        self.undoc_device_info
+            .as_ref()
+            .and_then(|info| info.entry.firmware_version())
+    }
+
+    /// Returns the device id of the bluetooth gateway that the
+    /// device is paired with, if any
+    pub fn gateway_id(&self) -> Option<&str> {
+        self.undoc_device_info
+            .as_ref()?
+            .entry
+            .device_ext
+            .device_settings
+            .gateway_info
+            .as_ref()?
+            .device
+            .as_deref()
+            .filter(|id| !id.is_empty() && *id != self.id)
+    }
+
+    /// Returns the temperature and humidity calibration offsets that
+    /// were set up in the Govee app, in celsius and percentage points.
+    /// The app stores these in hundredths.
+    pub fn app_calibration(&self) -> (Option<f64>, Option<f64>) {
+        match &self.undoc_device_info {
+            Some(info) => {
+                let settings = &info.entry.device_ext.device_settings;
+                (
+                    settings.tem_cali.map(|v| v as f64 / 100.),
+                    settings.hum_cali.map(|v| v as f64 / 100.),
+                )
+            }
+            None => (None, None),
+        }
+    }
+
+    /// Returns the last temperature and humidity that the sensor
+    /// uploaded to the Govee cloud, as reported by the undocumented
+    /// API, in celsius and percent. Wi-Fi thermo-hygrometers such as
+    /// the H5179 report these in hundredths.
+    pub fn cloud_sensor_reading(&self) -> (Option<f64>, Option<f64>) {
+        match &self.undoc_device_info {
+            Some(info) => {
+                let data = &info.entry.device_ext.last_device_data;
+                (
+                    data.tem.map(|v| v as f64 / 100.),
+                    data.hum.map(|v| v as f64 / 100.),
+                )
+            }
+            None => (None, None),
+        }
+    }
+
+    /// Returns the alarm thresholds that were set up for the sensor
+    /// in the Govee app, in celsius and percent
+    pub fn sensor_alarms(&self) -> (Option<SensorAlarm>, Option<SensorAlarm>) {
+        match &self.undoc_device_info {
+            Some(info) => {
+                let settings = &info.entry.device_ext.device_settings;
+                (
+                    SensorAlarm::new(settings.tem_min, settings.tem_max, settings.tem_warning),
+                    SensorAlarm::new(settings.hum_min, settings.hum_max, settings.hum_warning),
+                )
+            }
+            None => (None, None),
+        }
+    }
+
+    /// Returns the sensor capabilities for which the only readings
+    /// come from the undocumented API or from BLE advertisements,
+    /// because the Platform API doesn't describe them for the device
+    pub fn unlisted_sensor_capabilities(&self) -> Vec<DeviceCapability> {
+        let (temperature, humidity) = self.cloud_sensor_reading();
+        let ble = self.ble_reading;
+        [
+            (
+                "sensorTemperature",
+                temperature.or(ble.map(|r| r.temperature)),
+            ),
+            ("sensorHumidity", humidity.or(ble.map(|r| r.humidity))),
+        ]
+        .into_iter()
+        .filter(|(instance, value)| {
+            value.is_some() && self.get_capability_by_instance(instance).is_none()
+        })
+        .map(|(instance, _)| DeviceCapability {
+            kind: DeviceCapabilityKind::Property,
+            instance: instance.to_string(),
+            parameters: None,
+            alarm_type: None,
+            event_state: None,
+        })
+        .collect()
+    }
+
+    /// Merges the readings from the undocumented API into the capability
+    /// state, for the sensors that have no other source of readings
+    fn apply_cloud_sensor_readings(&mut self) {
+        // A recent BLE reading is fresher than the one from the cloud
+        if self
+            .last_ble_reading_update
+            .is_some_and(|updated| Utc::now() - updated < self.preferred_poll_interval())
+        {
+            return;
+        }
+        let quirk = self.resolve_quirk();
+        let (temperature, humidity) = self.cloud_sensor_reading();
+        let temperature = temperature.map(|celsius| {
+            let units = quirk
+                .as_ref()
+                .and_then(|q| q.platform_temperature_sensor_units)
+                .unwrap_or(TemperatureUnits::Fahrenheit);
+            TemperatureValue::new(celsius, TemperatureUnits::Celsius)
+                .as_unit(units)
+                .value()
+        });
+        let humidity =
+            humidity.map(
+                |percent| match quirk.and_then(|q| q.platform_humidity_sensor_units) {
+                    Some(HumidityUnits::RelativePercentTimes100) => percent * 100.,
+                    _ => percent,
+                },
+            );
+        let capabilities = self.unlisted_sensor_capabilities();
+        for (instance, value) in [
+            ("sensorTemperature", temperature),
+            ("sensorHumidity", humidity),
+        ] {
+            if let Some(value) = value {
+                if capabilities.iter().any(|cap| cap.instance == instance) {
+                    self.apply_capability_state(
+                        DeviceCapabilityKind::Property,
+                        instance,
+                        serde_json::json!({"value": value}),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the offset to add to temperature readings that are
+    /// expressed in `units`
+    pub fn temperature_offset(
+        &self,
+        config: &crate::config::DeviceConfig,
+        units: TemperatureUnits,
+    ) -> f64 {
+        let mut offset = config.temperature_offset.unwrap_or(0.);
+        if config.use_app_calibration == Some(true) {
+            if let (Some(celsius), _) = self.app_calibration() {
+                offset += match units.scale() {
+                    TemperatureScale::Celsius => celsius,
+                    TemperatureScale::Fahrenheit => celsius * 1.8,
+                };
+            }
+        }
+        offset
+    }
+
+    /// Returns the offset to add to relative humidity readings
+    pub fn humidity_offset(&self, config: &crate::config::DeviceConfig) -> f64 {
+        let mut offset = config.humidity_offset.unwrap_or(0.);
+        if config.use_app_calibration == Some(true) {
+            if let (_, Some(percent)) = self.app_calibration() {
+                offset += percent;
+            }
+        }
+        offset
+    }
+
     pub fn room_name(&self) -> Option<&str> {
         if let Some(info) = &self.undoc_device_info {
-            return info.room_name.as_deref();
+            return info
+                .room_name
+                .as_deref()
+                .filter(|name| !name.trim().is_empty());
         }
         None
     }
@@ -169,6 +514,56 @@ impl Device {
         }
     }
 
+    /// Determines whether the device is reachable via any of the
+    /// paths that we have to it. A device that we have never received
+    /// any state for is assumed to be available, as we cannot say
+    /// otherwise.
+    pub fn is_available(&self, now: DateTime<Utc>) -> bool {
+        let reachability = self.reachability(now);
+        reachability.is_unknown() || reachability.any()
+    }
+
+    /// Determines whether the device is reachable via each of the
+    /// paths that we have to it
+    pub fn reachability(&self, now: DateTime<Utc>) -> Reachability {
+        // A response to a discovery scan counts as hearing from the
+        // device, so that a device that was re-discovered at a new
+        // address is immediately considered to be available again
+        // State restored from the cache says nothing about whether the
+        // device is reachable now
+        let lan = self
+            .last_lan_device_status_update
+            .filter(|_| !self.restored.lan)
+            .map(|updated| {
+                let last_response = self
+                    .last_lan_device_update
+                    .map_or(updated, |scanned| scanned.max(updated));
+                self.lan_missed_polls < lan_max_missed_polls()
+                    && now - last_response <= lan_availability_timeout()
+            });
+        let iot = self
+            .last_iot_device_status_update
+            .filter(|_| !self.restored.iot)
+            .map(|updated| now - updated <= poll_availability_timeout());
+        let platform = self
+            .compute_http_device_state()
+            .filter(|_| !self.restored.http)
+            .map(|state| {
+                state.online != Some(false) && now - state.updated <= poll_availability_timeout()
+            });
+
+        let ble = self
+            .last_ble_reading_update
+            .map(|updated| now - updated <= ble_availability_timeout());
+
+        Reachability {
+            lan,
+            iot,
+            platform,
+            ble,
+        }
+    }
+
     pub fn ip_addr(&self) -> Option<IpAddr> {
         self.lan_device.as_ref().map(|device| device.ip)
     }
@@ -194,6 +589,108 @@ impl Device {
     pub fn set_lan_device(&mut self, device: LanDevice) {
         self.lan_device.replace(device);
         self.last_lan_device_update.replace(Utc::now());
+        self.lan_missed_polls = 0;
+        self.mark_seen();
+    }
+
+    /// Records that data for the device was received just now
+    pub fn mark_seen(&mut self) {
+        self.last_seen.replace(Utc::now());
+    }
+
+    /// Returns when we last received any data for the device
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        self.last_seen
+    }
+
+    /// Forget the LAN device information, so that the device is
+    /// controlled via the other APIs until it is discovered again
+    pub fn clear_lan_device(&mut self) {
+        self.lan_device.take();
+        self.lan_missed_polls = 0;
+    }
+
+    /// Returns the LAN device information, unless the device has
+    /// stopped responding to LAN status requests, in which case the
+    /// other APIs should be used instead until it responds again.
+    /// A device that can't be controlled via the other APIs keeps
+    /// using the LAN API, as a last resort.
+    pub fn responsive_lan_device(&self) -> Option<&LanDevice> {
+        self.lan_device
+            .as_ref()
+            .filter(|_| self.lan_missed_polls < lan_max_missed_polls() || !self.has_cloud_control())
+    }
+
+    /// Returns true if the device can be controlled via the IoT or
+    /// Platform APIs
+    fn has_cloud_control(&self) -> bool {
+        self.http_device_info.is_some()
+            || (self.iot_api_supported() && self.undoc_device_info.is_some())
+    }
+
+    /// Returns a snapshot of the current state, if we have any
+    pub fn state_snapshot(&self) -> Option<DeviceStateSnapshot> {
+        fn pair<T: Clone>(v: &Option<T>, t: Option<DateTime<Utc>>) -> Option<(T, DateTime<Utc>)> {
+            Some((v.clone()?, t?))
+        }
+
+        let snapshot = DeviceStateSnapshot {
+            saved: Utc::now(),
+            lan_device_status: pair(&self.lan_device_status, self.last_lan_device_status_update),
+            iot_device_status: pair(&self.iot_device_status, self.last_iot_device_status_update),
+            http_device_state: pair(&self.http_device_state, self.last_http_device_state_update),
+        };
+
+        if snapshot.lan_device_status.is_none()
+            && snapshot.iot_device_status.is_none()
+            && snapshot.http_device_state.is_none()
+        {
+            return None;
+        }
+        Some(snapshot)
+    }
+
+    /// Applies state from a snapshot saved by a prior run, keeping the
+    /// times at which it was originally received, so that polling
+    /// still sees its real age. The restored state is flagged, so that
+    /// it is published, but doesn't count against the availability of
+    /// the device, until the device reports again. State that we have
+    /// already received in this run is not replaced.
+    pub fn restore_state_snapshot(&mut self, snapshot: DeviceStateSnapshot) {
+        if let (None, Some((status, updated))) =
+            (&self.lan_device_status, snapshot.lan_device_status)
+        {
+            self.lan_device_status.replace(status);
+            self.last_lan_device_status_update.replace(updated);
+            self.restored.lan = true;
+        }
+        if let (None, Some((status, updated))) =
+            (&self.iot_device_status, snapshot.iot_device_status)
+        {
+            self.iot_device_status.replace(status);
+            self.last_iot_device_status_update.replace(updated);
+            self.restored.iot = true;
+        }
+        if let (None, Some((state, updated))) =
+            (&self.http_device_state, snapshot.http_device_state)
+        {
+            self.http_device_state.replace(state);
+            self.last_http_device_state_update.replace(updated);
+            self.restored.http = true;
+        }
+    }
+
+    /// Returns true if some of the state of the device was restored
+    /// from the cache and hasn't been reported again since
+    pub fn is_state_restored(&self) -> bool {
+        self.restored != RestoredState::default()
+    }
+
+    /// Records that the device didn't respond to a LAN status
+    /// request, returning the number of consecutive misses
+    pub fn record_missed_lan_poll(&mut self) -> u32 {
+        self.lan_missed_polls += 1;
+        self.lan_missed_polls
     }
 
     /// Update the LAN device status information
@@ -205,14 +702,19 @@ impl Device {
             .unwrap_or(true);
         self.lan_device_status.replace(status);
         self.last_lan_device_status_update.replace(Utc::now());
+        self.restored.lan = false;
+        self.lan_missed_polls = 0;
         self.clear_scene_if_color_changed();
+        self.mark_seen();
         changed
     }
 
     pub fn set_iot_device_status(&mut self, status: LanDeviceStatus) {
         self.iot_device_status.replace(status);
         self.last_iot_device_status_update.replace(Utc::now());
+        self.restored.iot = false;
         self.clear_scene_if_color_changed();
+        self.mark_seen();
     }
 
     pub fn set_http_device_info(&mut self, info: HttpDeviceInfo) {
@@ -223,7 +725,127 @@ impl Device {
     pub fn set_http_device_state(&mut self, state: HttpDeviceState) {
         self.http_device_state.replace(state);
         self.last_http_device_state_update.replace(Utc::now());
+        self.restored.http = false;
         self.clear_scene_if_color_changed();
+        self.mark_seen();
+
+        // The cloud relays sensor readings from the gateway much less
+        // often than the sensor advertises them, so a recent BLE reading
+        // is fresher than whatever was just polled
+        if let (Some(reading), Some(updated)) = (self.ble_reading, self.last_ble_reading_update) {
+            if Utc::now() - updated < self.preferred_poll_interval() {
+                self.apply_ble_reading_capabilities(reading);
+            }
+        }
+        // Replacing the state drops the readings for the sensors
+        // that the Platform API doesn't report
+        self.apply_cloud_sensor_readings();
+    }
+
+    /// Returns true if the BLE advertisements from `mac` are from this
+    /// device. The ids of gateway paired sensors end with their MAC.
+    pub fn matches_ble_mac(&self, mac: &str) -> bool {
+        fn hex_only(s: &str) -> String {
+            s.chars()
+                .filter(|c| c.is_ascii_hexdigit())
+                .map(|c| c.to_ascii_uppercase())
+                .collect()
+        }
+        let mac = hex_only(mac);
+        mac.len() == 12 && hex_only(&self.id).ends_with(&mac)
+    }
+
+    /// Records a reading from a BLE advertisement, merging it into the
+    /// platform state so that the existing sensor entities update
+    pub fn set_ble_reading(&mut self, reading: BleReading) {
+        self.ble_reading.replace(reading);
+        self.apply_ble_reading_capabilities(reading);
+        self.last_ble_reading_update.replace(Utc::now());
+        self.mark_seen();
+    }
+
+    fn apply_ble_reading_capabilities(&mut self, reading: BleReading) {
+        let quirk = self.resolve_quirk();
+
+        let temperature_units = quirk
+            .as_ref()
+            .and_then(|q| q.platform_temperature_sensor_units)
+            .unwrap_or(TemperatureUnits::Fahrenheit);
+        let temperature = TemperatureValue::new(reading.temperature, TemperatureUnits::Celsius)
+            .as_unit(temperature_units)
+            .value();
+
+        let humidity = match quirk.and_then(|q| q.platform_humidity_sensor_units) {
+            Some(HumidityUnits::RelativePercentTimes100) => reading.humidity * 100.,
+            _ => reading.humidity,
+        };
+
+        // A BLE reading says nothing about whether the Platform API can
+        // reach the device, so it doesn't count as a platform update
+        let platform_updated = self.last_http_device_state_update;
+        for (instance, value) in [
+            ("sensorTemperature", temperature),
+            ("sensorHumidity", humidity),
+            (BATTERY_INSTANCE, reading.battery as f64),
+        ] {
+            self.apply_capability_state(
+                DeviceCapabilityKind::Property,
+                instance,
+                serde_json::json!({"value": value}),
+            );
+        }
+        self.last_http_device_state_update = platform_updated;
+    }
+
+    /// Applies a capability state that was pushed to us, rather than
+    /// polled via the platform API, by merging it into the platform
+    /// state so that the entities pick it up the same way
+    pub fn apply_capability_state(
+        &mut self,
+        kind: DeviceCapabilityKind,
+        instance: &str,
+        state: JsonValue,
+    ) {
+        let http_state = self
+            .http_device_state
+            .get_or_insert_with(|| HttpDeviceState {
+                sku: self.sku.clone(),
+                device: self.id.clone(),
+                capabilities: vec![],
+            });
+        match http_state
+            .capabilities
+            .iter_mut()
+            .find(|cap| cap.instance == instance)
+        {
+            Some(cap) => {
+                cap.state = state;
+            }
+            None => {
+                http_state.capabilities.push(DeviceCapabilityState {
+                    kind,
+                    instance: instance.to_string(),
+                    state,
+                });
+            }
+        }
+        self.last_http_device_state_update.replace(Utc::now());
+    }
+
+    /// Records that the event `instance` was pushed to us just now
+    pub fn record_event(&mut self, instance: &str) {
+        self.event_times.insert(instance.to_string(), Utc::now());
+    }
+
+    /// Returns true if the event `instance` was pushed to us within
+    /// the last `period`. For devices whose events are only polled,
+    /// the time at which the polled state was obtained is used instead.
+    pub fn event_seen_within(&self, instance: &str, period: chrono::Duration) -> bool {
+        let when = self.event_times.get(instance).copied().or_else(|| {
+            self.get_state_capability_by_instance(instance)
+                .and(self.last_http_device_state_update)
+        });
+        when.is_some_and(|when| Utc::now() - when < period)
     }
 
     pub fn set_undoc_device_info(
@@ -237,6 +859,7 @@ impl Device {
         });
         self.last_undoc_device_info_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
+        self.apply_cloud_sensor_readings();
     }
 
     pub fn compute_iot_device_state(&self) -> Option<DeviceState> {
@@ -302,7 +925,6 @@ impl Device {
         for cap in &state.capabilities {
             if let Ok(value) = serde_json::from_value::<IntegerValueState>(cap.state.clone()) {
                 if light_instance
-                    .as_deref()
                     .map(|inst| inst == cap.instance.as_str())
                     .unwrap_or(false)
                 {
@@ -352,6 +974,11 @@ impl Device {
     pub fn device_state(&self) -> Option<DeviceState> {
         let mut candidates = vec![];
 
+        // Listed first, so that a reported state with the same
+        // timestamp takes precedence
+        if let Some(state) = &self.optimistic_state {
+            candidates.push(state.clone());
+        }
         if let Some(state) = self.compute_lan_device_state() {
             candidates.push(state);
         }
@@ -362,7 +989,7 @@ impl Device {
             candidates.push(state);
         }
 
-        candidates.sort_by(|a, b| a.updated.cmp(&b.updated));
+        candidates.sort_by_key(|a| a.updated);
 
         candidates.pop()
     }
@@ -387,6 +1014,79 @@ impl Device {
         }
     }
 
+    /// Records the state implied by a command that the device, or the
+    /// cloud, accepted, so that it can be reported right away. It is
+    /// replaced by the next state that is reported, whether or not that
+    /// agrees with it. Returns false if the prior state is unknown, as
+    /// there would be nothing to apply the change to.
+    pub fn apply_optimistic_change(&mut self, change: ExpectedChange) -> bool {
+        if let ExpectedChange::WorkMode { mode, param } = change {
+            self.set_humidifier_work_mode_and_param(mode, param);
+            return true;
+        }
+
+        let Some(mut state) = self.device_state() else {
+            return false;
+        };
+        match change {
+            ExpectedChange::Power(on) => {
+                state.on = on;
+                if self.device_type() == DeviceType::Light {
+                    state.light_on.replace(on);
+                }
+            }
+            ExpectedChange::LightPower(on) => {
+                state.light_on.replace(on);
+            }
+            ExpectedChange::Brightness(percent) => {
+                state.brightness = percent;
+            }
+            ExpectedChange::ColorTemperature(kelvin) => {
+                state.kelvin = kelvin;
+                state.scene.take();
+                self.active_scene.take();
+            }
+            ExpectedChange::Color(color) => {
+                state.color = color;
+                state.kelvin = 0;
+                state.scene.take();
+                self.active_scene.take();
+            }
+            ExpectedChange::WorkMode { .. } => unreachable!(),
+        }
+        state.source = OPTIMISTIC_SOURCE;
+        state.updated = Utc::now();
+        self.optimistic_state.replace(state);
+        true
+    }
+
+    /// Returns the capability instance and value of the scene that the
+    /// platform state reports as active, if a scene is active and it is
+    /// different from the one that was returned previously. Devices
+    /// report an empty value when no scene is active.
+    pub fn take_reported_scene_change(&mut self) -> Option<(String, JsonValue)> {
+        let reported = self.http_device_state.as_ref().and_then(|state| {
+            state.capabilities.iter().find_map(|cap| {
+                if !SCENE_INSTANCES.contains(&cap.instance.as_str()) {
+                    return None;
+                }
+                let value = cap.state.get("value")?;
+                let is_empty = match value {
+                    JsonValue::Null => true,
+                    JsonValue::String(s) => s.is_empty(),
+                    JsonValue::Object(o) => o.is_empty(),
+                    _ => false,
+                };
+                (!is_empty).then(|| (cap.instance.to_string(), value.clone()))
+            })
+        });
+        if reported == self.reported_scene {
+            return None;
+        }
+        self.reported_scene = reported.clone();
+        reported
+    }
+
     pub fn clear_scene_if_color_changed(&mut self) {
         if let Some(info) = &self.active_scene {
             let current = self
@@ -408,6 +1108,9 @@ impl Device {
             info.device_type.clone()
         } else if let Some(q) = resolve_quirk(&self.sku) {
             q.device_type.clone()
+        } else if self.ble_reading.is_some() {
+            // We only decode the advertisements of thermo-hygrometers
+            DeviceType::Thermometer
         } else {
             DeviceType::Light
         }
@@ -430,8 +1133,17 @@ impl Device {
         }
     }
 
+    /// Returns true if the state of the device normally reaches us
+    /// via AWS IoT, rather than via the LAN API or Platform API polls
+    pub fn state_arrives_via_iot(&self) -> bool {
+        self.iot_api_supported()
+            && self.undoc_device_info.is_some()
+            && self.responsive_lan_device().is_none()
+            && (!self.needs_platform_poll() || self.platform_api_bypassed())
+    }
+
     pub fn pollable_via_lan(&self) -> bool {
-        self.lan_device.is_some()
+        self.responsive_lan_device().is_some()
     }
 
     pub fn pollable_via_iot(&self) -> bool {
@@ -439,14 +1151,42 @@ impl Device {
             return false;
         }
         let device_type = self.device_type();
-        match (device_type, self.sku.as_str()) {
-            (_, "H7160") => true,
-            (DeviceType::Light, _) => true,
-            _ => false,
-        }
+        matches!(
+            (device_type, self.sku.as_str()),
+            (_, "H7160") | (DeviceType::Light, _)
+        )
+    }
+
+    /// Returns true if the device can be controlled without the
+    /// Platform API, via the LAN or IoT APIs
+    pub fn has_non_platform_control(&self) -> bool {
+        self.responsive_lan_device().is_some()
+            || (self.iot_api_supported() && self.undoc_device_info.is_some())
+    }
+
+    /// Returns true if the device's configuration asks for the
+    /// Platform API not to be used for control and state. That is
+    /// only honored while the device can be reached some other way.
+    pub fn platform_api_bypassed(&self) -> bool {
+        self.config().avoid_platform_api == Some(true) && self.has_non_platform_control()
+    }
+
+    /// Returns true if the device's configuration asks for scenes
+    /// and segments to be sent via the LAN API too
+    pub fn prefers_lan_control(&self) -> bool {
+        self.config().prefer_lan_control == Some(true)
+    }
+
+    /// Returns the order in which to try the transports when
+    /// controlling the power, brightness and color of the device
+    pub fn control_order(&self) -> Vec<crate::service::control_path::Transport> {
+        crate::service::control_path::resolve_control_order(self.config().control_order.as_deref())
     }
 
     pub fn avoid_platform_api(&self) -> bool {
+        if let Some(avoid) = self.config().avoid_platform_api {
+            return avoid && self.has_non_platform_control();
+        }
         if let Some(quirk) = self.resolve_quirk() {
             if quirk.avoid_platform_api {
                 return true;
@@ -469,6 +1209,19 @@ impl Device {
         false
     }
 
+    /// Returns the LAN device information if segments can be
+    /// controlled via the LAN API
+    pub fn lan_segment_device(&self) -> Option<&LanDevice> {
+        let lan_segment_control = self.prefers_lan_control()
+            || self
+                .resolve_quirk()
+                .is_some_and(|quirk| quirk.lan_segment_control);
+        if !lan_segment_control {
+            return None;
+        }
+        self.responsive_lan_device()
+    }
+
     pub fn resolve_quirk(&self) -> Option<Quirk> {
         match resolve_quirk(&self.sku) {
             Some(q) => Some(q.clone()),
@@ -499,6 +1252,36 @@ impl Device {
             .and_then(|info| info.capability_by_instance(instance))
     }
 
+    /// Returns the battery level in percent, if the device reports one,
+    /// preferring the platform API state over the undocumented device settings
+    pub fn battery_percent(&self) -> Option<u8> {
+        let level = self
+            .get_state_capability_by_instance(BATTERY_INSTANCE)
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(|v| v.as_f64())
+            .map(|v| v as i64)
+            .or_else(|| {
+                self.undoc_device_info
+                    .as_ref()
+                    .and_then(|info| info.entry.device_ext.device_settings.battery)
+            })?;
+        Some(level.clamp(0, 100) as u8)
+    }
+
+    /// Returns the wifi signal strength in dBm, as reported by the
+    /// undocumented API. BLE-only devices, and devices that report a
+    /// non-negative placeholder value, yield None.
+    pub fn wifi_rssi(&self) -> Option<i64> {
+        let settings = &self
+            .undoc_device_info
+            .as_ref()?
+            .entry
+            .device_ext
+            .device_settings;
+        settings.wifi_name.as_ref()?;
+        settings.wifi_level.filter(|&level| level < 0)
+    }
+
     pub fn get_light_power_toggle_instance_name(&self) -> Option<&'static str> {
         match self.device_type() {
             DeviceType::Light => Some("powerSwitch"),
@@ -521,19 +1304,50 @@ impl Device {
         }
     }
 
+    /// Returns the range of segment indices reported by the Platform
+    /// API metadata
+    pub fn segment_range(&self) -> Option<std::ops::Range<u32>> {
+        self.http_device_info
+            .as_ref()
+            .and_then(|info| info.supports_segmented_rgb())
+    }
+
+    /// Returns the supported color temperature range in kelvin.
+    /// The range reported by the Platform API is preferred, unless
+    /// a quirk gives the exact range for the device, or the reported
+    /// range is implausible.
     pub fn get_color_temperature_range(&self) -> Option<(u32, u32)> {
-        if let Some(quirk) = self.resolve_quirk() {
+        let quirk = self.resolve_quirk();
+        if let Some(quirk) = &quirk {
+            if quirk.exact_color_temp_range {
+                return quirk.color_temp_range;
+            }
+        }
+
+        let reported = self
+            .http_device_info
+            .as_ref()
+            .and_then(|info| info.get_color_temperature_range());
+        match reported {
+            Some((min, max)) if min > 0 && min < max => return Some((min, max)),
+            Some((min, max)) => {
+                log::debug!("Ignoring implausible color temperature range {min}-{max} for {self}");
+            }
+            None => {}
+        }
+
+        if let Some(quirk) = quirk {
             return quirk.color_temp_range;
         }
 
-        if self.lan_device.is_some() {
-            // LAN API support suggests that it is a light
-            return Some((2000, 9000));
+        if self.lan_device.is_some() || reported.is_some() {
+            // LAN API support suggests that it is a light, and a
+            // colorTemperatureK capability, even with a broken
+            // range, that it supports color temperature
+            return Some(DEFAULT_COLOR_TEMP_RANGE);
         }
 
-        self.http_device_info
-            .as_ref()
-            .and_then(|info| info.get_color_temperature_range())
+        None
     }
 
     pub fn supports_brightness(&self) -> bool {
@@ -552,6 +1366,54 @@ impl Device {
             .unwrap_or(false)
     }
 
+    /// Returns true if a light entity is created for the device that
+    /// represents the device itself, rather than its nightlight
+    pub fn has_primary_light(&self) -> bool {
+        (self.supports_rgb()
+            || self.get_color_temperature_range().is_some()
+            || self.supports_brightness())
+            && !matches!(
+                self.device_type(),
+                DeviceType::AromaDiffuser | DeviceType::Humidifier | DeviceType::Dehumidifier
+            )
+    }
+
+    /// Returns true if the raw capability state should be published
+    /// as entity attributes, either because the device's configuration
+    /// asks for it, or GOVEE_HASS_RAW_STATE_ATTRIBUTES is set
+    pub fn publishes_raw_state(&self) -> bool {
+        if let Some(enabled) = self.config().raw_state_attributes {
+            return enabled;
+        }
+        match crate::opt_env_var::<String>("GOVEE_HASS_RAW_STATE_ATTRIBUTES") {
+            Ok(Some(v)) => crate::lan_api::truthy(&v).unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Returns the state of each of the device's capabilities, keyed
+    /// by instance. Large values, such as scene data, are replaced by
+    /// a note of their size so that the map stays small enough to be
+    /// published as attributes.
+    pub fn raw_capability_states(&self) -> serde_json::Map<String, JsonValue> {
+        const MAX_VALUE_LEN: usize = 256;
+
+        let mut states = serde_json::Map::new();
+        if let Some(http_state) = &self.http_device_state {
+            for cap in &http_state.capabilities {
+                let value = cap.state.get("value").unwrap_or(&cap.state);
+                let len = value.to_string().len();
+                let value = if len > MAX_VALUE_LEN {
+                    JsonValue::String(format!("<{len} bytes omitted>"))
+                } else {
+                    value.clone()
+                };
+                states.insert(cap.instance.to_string(), value);
+            }
+        }
+        states
+    }
+
     pub fn iot_api_supported(&self) -> bool {
         if let Some(quirk) = self.resolve_quirk() {
             return quirk.iot_api_supported;
@@ -587,19 +1449,13 @@ impl Device {
             return Some(false);
         }
 
-        if let Some(info) = &self.undoc_device_info {
-            Some(info.entry.device_ext.device_settings.wifi_name.is_none())
-        } else {
-            // Don't know for sure
-            None
-        }
+        self.undoc_device_info
+            .as_ref()
+            .map(|info| info.entry.device_ext.device_settings.wifi_name.is_none())
     }
 
     pub fn is_controllable(&self) -> bool {
-        match self.is_ble_only_device() {
-            Some(true) => false,
-            _ => true,
-        }
+        !matches!(self.is_ble_only_device(), Some(true))
     }
 }
 
@@ -618,4 +1474,477 @@ mod test {
         let device = Device::new("H6127", "ce");
         assert_eq!(device.name(), "H6127_CE");
     }
+
+    #[test]
+    fn battery_level() {
+        use crate::platform_api::DeviceCapabilityKind;
+
+        let mut device = Device::new("H5058", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(device.battery_percent(), None);
+
+        for (value, expect) in [(55, 55), (130, 100), (-4, 0)] {
+            device.set_http_device_state(HttpDeviceState {
+                sku: device.sku.clone(),
+                device: device.id.clone(),
+                capabilities: vec![DeviceCapabilityState {
+                    kind: DeviceCapabilityKind::Property,
+                    instance: BATTERY_INSTANCE.to_string(),
+                    state: serde_json::json!({"value": value}),
+                }],
+            });
+            assert_eq!(device.battery_percent(), Some(expect));
+        }
+    }
+
+    #[test]
+    fn event_times() {
+        let mut device = Device::new("H5054", "AA:BB:CC:DD:EE:FF:42:2A");
+        let period = chrono::Duration::minutes(5);
+        assert!(!device.event_seen_within("waterLeakEvent", period));
+
+        device.record_event("waterLeakEvent");
+        assert!(device.event_seen_within("waterLeakEvent", period));
+        assert!(!device.event_seen_within("waterLeakEvent", chrono::Duration::zero()));
+        assert!(!device.event_seen_within("lowBatteryEvent", period));
+    }
+
+    #[test]
+    fn polled_event_times() {
+        use crate::platform_api::{DeviceCapabilityKind, DeviceCapabilityState, HttpDeviceState};
+
+        let mut device = Device::new("H5054", "AA:BB:CC:DD:EE:FF:42:2A");
+        let period = chrono::Duration::minutes(5);
+        device.set_http_device_state(HttpDeviceState {
+            sku: device.sku.clone(),
+            device: device.id.clone(),
+            capabilities: vec![DeviceCapabilityState {
+                kind: DeviceCapabilityKind::Event,
+                instance: "waterLeakEvent".to_string(),
+                state: serde_json::json!({"value": 1}),
+            }],
+        });
+        assert!(device.event_seen_within("waterLeakEvent", period));
+        assert!(!device.event_seen_within("lowBatteryEvent", period));
+
+        // The poll is what ages out, in the absence of pushed events
+        device.last_http_device_state_update = Some(Utc::now() - chrono::Duration::minutes(6));
+        assert!(!device.event_seen_within("waterLeakEvent", period));
+    }
+
+    #[test]
+    fn last_seen() {
+        use crate::platform_api::{DeviceCapabilityKind, HttpDeviceState};
+
+        let mut device = Device::new("H5080", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.apply_capability_state(
+            DeviceCapabilityKind::OnOff,
+            "powerSwitch",
+            serde_json::json!({"value": 1}),
+        );
+        k9::assert_equal!(device.last_seen(), None);
+
+        device.set_http_device_state(HttpDeviceState {
+            sku: device.sku.clone(),
+            device: device.id.clone(),
+            capabilities: vec![],
+        });
+        assert!(device.last_seen().is_some());
+    }
+
+    #[test]
+    fn ble_reading() {
+        use crate::platform_api::DeviceCapabilityKind;
+
+        let mut device = Device::new("H5179", "1A:2B:A4:C1:38:11:22:33");
+        assert!(device.matches_ble_mac("a4:c1:38:11:22:33"));
+        assert!(!device.matches_ble_mac("A4:C1:38:11:22:34"));
+        assert!(!device.matches_ble_mac("22:33"));
+
+        let temperature = |device: &Device| {
+            device
+                .get_state_capability_by_instance("sensorTemperature")
+                .and_then(|cap| cap.state.pointer("/value"))
+                .and_then(|v| v.as_f64())
+        };
+
+        device.set_ble_reading(BleReading {
+            temperature: 20.0,
+            humidity: 45.5,
+            battery: 80,
+        });
+        // The platform API reports this model in Fahrenheit
+        assert_eq!(temperature(&device), Some(68.0));
+        assert_eq!(device.battery_percent(), Some(80));
+
+        // A stale value from the cloud doesn't replace the BLE reading
+        device.set_http_device_state(HttpDeviceState {
+            sku: device.sku.clone(),
+            device: device.id.clone(),
+            capabilities: vec![DeviceCapabilityState {
+                kind: DeviceCapabilityKind::Property,
+                instance: "sensorTemperature".to_string(),
+                state: serde_json::json!({"value": 50.0}),
+            }],
+        });
+        assert_eq!(temperature(&device), Some(68.0));
+    }
+
+    #[test]
+    fn cloud_sensor_readings() {
+        let list: crate::undoc_api::DevicesResponse = crate::platform_api::from_json(include_str!(
+            "../../test-data/undoc-device-list-issue-21.json"
+        ))
+        .unwrap();
+        let entry = list
+            .devices
+            .into_iter()
+            .find(|entry| entry.sku == "H5179" && entry.device_name == "Master Bedroom Temp")
+            .unwrap();
+
+        let value = |device: &Device, instance: &str| {
+            device
+                .get_state_capability_by_instance(instance)
+                .and_then(|cap| cap.state.pointer("/value"))
+                .and_then(|v| v.as_f64())
+        };
+
+        let mut device = Device::new("H5179", &entry.device);
+        device.set_undoc_device_info(entry, None);
+
+        k9::assert_equal!(
+            device
+                .unlisted_sensor_capabilities()
+                .iter()
+                .map(|cap| cap.instance.as_str())
+                .collect::<Vec<_>>(),
+            vec!["sensorTemperature", "sensorHumidity"]
+        );
+        k9::assert_equal!(device.cloud_sensor_reading(), (Some(21.2), Some(60.4)));
+        // The platform API reports this model in Fahrenheit, so the
+        // reading is stored that way too
+        let fahrenheit = value(&device, "sensorTemperature").unwrap();
+        assert!((fahrenheit - 70.16).abs() < 0.001, "{fahrenheit}");
+        k9::assert_equal!(value(&device, "sensorHumidity"), Some(60.4));
+        k9::assert_equal!(device.battery_percent(), Some(24));
+        k9::assert_equal!(
+            device.sensor_alarms(),
+            (
+                Some(SensorAlarm {
+                    min: 6.66,
+                    max: 32.77,
+                    enabled: true
+                }),
+                Some(SensorAlarm {
+                    min: 0.,
+                    max: 100.,
+                    enabled: false
+                })
+            )
+        );
+
+        // Polling the platform API doesn't lose the readings
+        device.set_http_device_state(HttpDeviceState {
+            sku: device.sku.clone(),
+            device: device.id.clone(),
+            capabilities: vec![],
+        });
+        k9::assert_equal!(value(&device, "sensorHumidity"), Some(60.4));
+    }
+
+    #[test]
+    fn raw_capability_states() {
+        use crate::platform_api::DeviceCapabilityKind;
+
+        let mut device = Device::new("H6199", "AA:BB:CC:DD:EE:FF:42:2A");
+        k9::assert_equal!(device.raw_capability_states().is_empty(), true);
+
+        device.set_http_device_state(HttpDeviceState {
+            sku: device.sku.clone(),
+            device: device.id.clone(),
+            capabilities: vec![
+                DeviceCapabilityState {
+                    kind: DeviceCapabilityKind::OnOff,
+                    instance: "powerSwitch".to_string(),
+                    state: serde_json::json!({"value": 1}),
+                },
+                DeviceCapabilityState {
+                    kind: DeviceCapabilityKind::DynamicScene,
+                    instance: "lightScene".to_string(),
+                    state: serde_json::json!({"value": "x".repeat(300)}),
+                },
+                DeviceCapabilityState {
+                    kind: DeviceCapabilityKind::Online,
+                    instance: "online".to_string(),
+                    state: serde_json::json!({"status": true}),
+                },
+            ],
+        });
+        k9::assert_equal!(
+            JsonValue::Object(device.raw_capability_states()),
+            serde_json::json!({
+                "powerSwitch": 1,
+                "lightScene": "<302 bytes omitted>",
+                "online": {"status": true},
+            })
+        );
+    }
+
+    #[test]
+    fn color_temperature_range() {
+        use crate::platform_api::{fixtures, DeviceParameters};
+
+        let device_with_info = |json: &str, sku: &str, reported: Option<(u32, u32)>| {
+            let mut info = fixtures::device(json, sku);
+            if let Some((min, max)) = reported {
+                let cap = info
+                    .capabilities
+                    .iter_mut()
+                    .find(|c| c.instance == "colorTemperatureK")
+                    .unwrap();
+                if let Some(DeviceParameters::Integer { range, .. }) = &mut cap.parameters {
+                    range.min = min;
+                    range.max = max;
+                }
+            }
+            let mut device = Device::new(sku, &info.device);
+            device.set_http_device_info(info);
+            device
+        };
+        let list_devices = fixtures::LIST_DEVICES;
+        let list_devices_2 = fixtures::LIST_DEVICES_2;
+
+        // No quirk; the reported range is used
+        let device = device_with_info(list_devices, "H6601", None);
+        k9::assert_equal!(device.get_color_temperature_range(), Some((2000, 9000)));
+        let list_devices_color_temp = fixtures::LIST_DEVICES_COLOR_TEMP;
+        let device = device_with_info(list_devices_color_temp, "H6008", None);
+        k9::assert_equal!(device.get_color_temperature_range(), Some((2700, 6500)));
+        let device = device_with_info(list_devices_color_temp, "H6022", None);
+        k9::assert_equal!(device.get_color_temperature_range(), Some((2200, 6500)));
+
+        // The reported range is preferred to that of a generic quirk
+        let device = device_with_info(list_devices_2, "H619A", Some((2700, 6500)));
+        k9::assert_equal!(device.get_color_temperature_range(), Some((2700, 6500)));
+
+        // An implausible range falls back to the quirk, then the default
+        let device = device_with_info(list_devices_2, "H619A", Some((0, 0)));
+        k9::assert_equal!(device.get_color_temperature_range(), Some((2000, 9000)));
+        let device = device_with_info(list_devices, "H6601", Some((4000, 4000)));
+        k9::assert_equal!(device.get_color_temperature_range(), Some((2000, 9000)));
+
+        // The curtain lights have a quirk, but it doesn't override
+        // the reported range
+        let device = device_with_info(fixtures::LIST_DEVICES_ISSUE4, "H70B1", None);
+        k9::assert_equal!(device.get_color_temperature_range(), Some((2000, 9000)));
+    }
+
+    #[test]
+    fn segment_range() {
+        use crate::platform_api::fixtures;
+
+        let device_with_info = |json: &str, sku: &str| {
+            let info = fixtures::device(json, sku);
+            let mut device = Device::new(sku, &info.device);
+            device.set_http_device_info(info);
+            device
+        };
+
+        let device = device_with_info(fixtures::LIST_DEVICES_ISSUE4, "H618A");
+        k9::assert_equal!(device.segment_range(), Some(0..15));
+        let device = device_with_info(fixtures::LIST_DEVICES_ISSUE4, "H70B1");
+        k9::assert_equal!(device.segment_range(), None);
+    }
+
+    #[test]
+    fn optimistic_change() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        // There is nothing to apply the change to
+        k9::assert_equal!(
+            device.apply_optimistic_change(ExpectedChange::Power(true)),
+            false
+        );
+
+        device.set_iot_device_status(LanDeviceStatus {
+            on: false,
+            brightness: 50,
+            color: DeviceColor { r: 1, g: 2, b: 3 },
+            color_temperature_kelvin: 0,
+        });
+        k9::assert_equal!(
+            device.apply_optimistic_change(ExpectedChange::Power(true)),
+            true
+        );
+        k9::assert_equal!(
+            device.apply_optimistic_change(ExpectedChange::ColorTemperature(4000)),
+            true
+        );
+        let state = device.device_state().unwrap();
+        k9::assert_equal!(state.source, OPTIMISTIC_SOURCE);
+        k9::assert_equal!(state.on, true);
+        k9::assert_equal!(state.brightness, 50);
+        k9::assert_equal!(state.kelvin, 4000);
+
+        // The next reported state replaces it, even if it disagrees
+        device.set_iot_device_status(LanDeviceStatus {
+            on: false,
+            brightness: 50,
+            color: DeviceColor { r: 1, g: 2, b: 3 },
+            color_temperature_kelvin: 0,
+        });
+        let state = device.device_state().unwrap();
+        k9::assert_equal!(state.source, "AWS IoT API");
+        k9::assert_equal!(state.on, false);
+    }
+
+    #[test]
+    fn availability() {
+        use crate::platform_api::DeviceCapabilityKind;
+
+        let now = Utc::now();
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(device.is_available(now));
+
+        device
+            .last_lan_device_status_update
+            .replace(now - chrono::Duration::minutes(10));
+        assert!(!device.is_available(now));
+
+        device.last_iot_device_status_update.replace(now);
+        assert!(device.is_available(now));
+        assert!(!device.is_available(now + *POLL_INTERVAL * 4));
+        k9::assert_equal!(
+            device.reachability(now),
+            Reachability {
+                lan: Some(false),
+                iot: Some(true),
+                platform: None,
+                ble: None,
+            }
+        );
+
+        let set_online = |device: &mut Device, online: bool| {
+            device.set_http_device_state(HttpDeviceState {
+                sku: device.sku.clone(),
+                device: device.id.clone(),
+                capabilities: vec![DeviceCapabilityState {
+                    kind: DeviceCapabilityKind::Online,
+                    instance: "online".to_string(),
+                    state: serde_json::json!({"value": online}),
+                }],
+            });
+        };
+
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        set_online(&mut device, false);
+        assert!(!device.is_available(Utc::now()));
+        set_online(&mut device, true);
+        assert!(device.is_available(Utc::now()));
+    }
+
+    #[test]
+    fn state_snapshot() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(device.state_snapshot().is_none());
+
+        device.set_lan_device_status(LanDeviceStatus {
+            on: true,
+            brightness: 42,
+            ..LanDeviceStatus::default()
+        });
+        device.set_iot_device_status(LanDeviceStatus::default());
+        let lan_updated = Utc::now() - chrono::Duration::minutes(2);
+        device.last_lan_device_status_update.replace(lan_updated);
+
+        let snapshot = device.state_snapshot().unwrap();
+        let snapshot: DeviceStateSnapshot =
+            serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+
+        let mut restored = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        restored.set_iot_device_status(LanDeviceStatus {
+            on: true,
+            ..LanDeviceStatus::default()
+        });
+        let iot_updated = restored.last_iot_device_status_update;
+        restored.restore_state_snapshot(snapshot);
+
+        // The restored state keeps its real age
+        k9::assert_equal!(restored.lan_device_status, device.lan_device_status);
+        k9::assert_equal!(restored.last_lan_device_status_update, Some(lan_updated));
+        assert!(restored.is_state_restored());
+        // State that was received in this run is kept
+        k9::assert_equal!(
+            restored.iot_device_status.as_ref().map(|s| s.on),
+            Some(true)
+        );
+        k9::assert_equal!(restored.last_iot_device_status_update, iot_updated);
+        assert!(restored.http_device_state.is_none());
+
+        // Old restored state doesn't make the device unavailable, but
+        // state that is reported later counts as usual
+        let later = Utc::now() + chrono::Duration::hours(2);
+        k9::assert_equal!(restored.reachability(Utc::now()).lan, None);
+        restored.set_lan_device_status(LanDeviceStatus::default());
+        assert!(!restored.is_state_restored());
+        k9::assert_equal!(restored.reachability(later).lan, Some(false));
+    }
+
+    #[test]
+    fn lan_missed_polls() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_lan_device_status(LanDeviceStatus::default());
+        assert!(device.is_available(Utc::now()));
+
+        for _ in 1..DEFAULT_LAN_MAX_MISSED_POLLS {
+            device.record_missed_lan_poll();
+            assert!(device.is_available(Utc::now()));
+        }
+        k9::assert_equal!(
+            device.record_missed_lan_poll(),
+            DEFAULT_LAN_MAX_MISSED_POLLS
+        );
+        assert!(!device.is_available(Utc::now()));
+
+        device.set_lan_device_status(LanDeviceStatus::default());
+        assert!(device.is_available(Utc::now()));
+
+        // A device that can only be reached via the LAN keeps using
+        // it when it stops responding, as there is nothing else
+        device.set_lan_device(LanDevice {
+            ip: "10.0.0.5".parse().unwrap(),
+            device: device.id.clone(),
+            sku: device.sku.clone(),
+            ble_version_hard: String::new(),
+            ble_version_soft: String::new(),
+            wifi_version_hard: String::new(),
+            wifi_version_soft: String::new(),
+        });
+        for _ in 0..DEFAULT_LAN_MAX_MISSED_POLLS {
+            device.record_missed_lan_poll();
+        }
+        assert!(!device.is_available(Utc::now()));
+        assert!(device.responsive_lan_device().is_some());
+
+        // Otherwise, it is controlled via the other APIs until it
+        // responds again
+        let mut info = crate::platform_api::fixtures::device(
+            crate::platform_api::fixtures::LIST_DEVICES,
+            "H6601",
+        );
+        info.device = device.id.clone();
+        device.set_http_device_info(info);
+        assert!(device.responsive_lan_device().is_none());
+        device.set_lan_device_status(LanDeviceStatus::default());
+        assert!(device.responsive_lan_device().is_some());
+
+        // Re-discovery at a new address counts as a response, even
+        // though the last status is stale
+        device.lan_missed_polls = DEFAULT_LAN_MAX_MISSED_POLLS;
+        device
+            .last_lan_device_status_update
+            .replace(Utc::now() - chrono::Duration::minutes(10));
+        assert!(!device.is_available(Utc::now()));
+        device.lan_missed_polls = 0;
+        device.last_lan_device_update.replace(Utc::now());
+        assert!(device.is_available(Utc::now()));
+    }
 }
@@ -1,38 +1,126 @@
-use crate::hass_mqtt::climate::mqtt_set_temperature;
-use crate::hass_mqtt::enumerator::{enumerate_all_entites, enumerate_entities_for_device};
+use crate::hass_mqtt::climate::{mqtt_climate_set_mode, mqtt_set_temperature};
+use crate::hass_mqtt::cover::{mqtt_cover_command, mqtt_cover_set_position};
+use crate::hass_mqtt::enumerator::{
+    enumerate_all_entites, enumerate_entities_for_device, enumerate_platform_quota_sensors,
+    enumerate_room_lights,
+};
+use crate::hass_mqtt::fan::{mqtt_fan_set_gear, mqtt_fan_set_percentage, mqtt_fan_set_preset_mode};
 use crate::hass_mqtt::humidifier::{mqtt_device_set_work_mode, mqtt_humidifier_set_target};
-use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::number::mqtt_number_command;
-use crate::hass_mqtt::select::mqtt_set_mode_scene;
+use crate::hass_mqtt::instance::{DeviceDiscovery, EntityInstance, EntityList};
+use crate::hass_mqtt::light::NIGHTLIGHT_SCENE_INSTANCE;
+use crate::hass_mqtt::migrate::record_applied_migrations;
+use crate::hass_mqtt::music::{mqtt_set_music_mode, mqtt_set_music_sensitivity};
+use crate::hass_mqtt::number::{
+    mqtt_number_command, mqtt_set_display_brightness, mqtt_set_mist_level, mqtt_set_target_humidity,
+};
+use crate::hass_mqtt::probe::mqtt_set_probe_target;
+use crate::hass_mqtt::purge::{is_discovery_config_topic, mqtt_purge_discovery};
+use crate::hass_mqtt::room::{room_lights_enabled, RoomLight};
+use crate::hass_mqtt::select::{
+    exit_light_cycle_mode, mqtt_set_light_cycle_mode, mqtt_set_mist_level_preset,
+    mqtt_set_mist_preset, mqtt_set_mode_scene,
+};
+use crate::hass_mqtt::siren::mqtt_siren_command;
+use crate::hass_mqtt::timer::{mqtt_set_countdown_timer, mqtt_set_countdown_timer_option};
+use crate::hass_mqtt::valve::mqtt_valve_command;
+use crate::lan_api::truthy;
 use crate::lan_api::DeviceColor;
+use crate::logging::{clear_device_log_levels, set_device_log_level, DeviceLogRequest};
+use crate::metrics::{self, Counter};
 use crate::opt_env_var;
-use crate::platform_api::{from_json, DeviceType};
+use crate::platform_api::{from_json, DeviceCapability, DeviceType, GoveeApiClient};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::state::StateHandle;
+use crate::service::reload::{reload_and_publish_result, reload_config_with_result};
+use crate::service::state::{
+    StateHandle, DEFAULT_EFFECT_LIST_LIMIT, DEFAULT_LEAK_CLEAR_TIMEOUT, DEFAULT_STATE_DEBOUNCE,
+};
+use crate::service::transition::{TransitionRequest, DEFAULT_TRANSITION_RATE};
 use crate::temperature::TemperatureScale;
+use crate::undoc_api::ParsedOneClick;
 use anyhow::Context;
 use async_channel::Receiver;
 use mosquitto_rs::router::{MqttRouter, Params, Payload, State};
-use mosquitto_rs::{Client, Event, QoS};
+use mosquitto_rs::{
+    Client, ClientOption, ConnectionStatus, Error as MqttError, Event, ProtocolVersion, QoS,
+    ReasonCode,
+};
+use once_cell::sync::OnceCell;
+use openssl::asn1::Asn1Time;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
 const HASS_REGISTER_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(15);
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Clone, Debug)]
 pub struct HassArguments {
     /// The mqtt broker hostname or address.
+    /// May also be specified as a URL such as mqtts://host:8883,
+    /// in which case the scheme determines whether TLS is used.
     /// You may also set this via the GOVEE_MQTT_HOST environment variable.
     #[arg(long, global = true)]
     mqtt_host: Option<String>,
 
     /// The mqtt broker port
     /// You may also set this via the GOVEE_MQTT_PORT environment variable.
-    /// If unspecified, uses 1883
+    /// If unspecified, uses 1883, or 8883 when using TLS
     #[arg(long, global = true)]
     mqtt_port: Option<u16>,
 
+    /// Connect to the mqtt broker using TLS.
+    /// You may also set GOVEE_MQTT_TLS=true via the environment.
+    #[arg(long, global = true)]
+    mqtt_tls: bool,
+
+    /// The PEM encoded CA certificate used to verify the broker.
+    /// If unspecified, the system trust store is used.
+    /// You may also set this via the GOVEE_MQTT_CA_FILE environment variable.
+    #[arg(long, global = true)]
+    mqtt_ca_file: Option<PathBuf>,
+
+    /// The PEM encoded client certificate to present to the broker.
+    /// You may also set this via the GOVEE_MQTT_CERT_FILE environment variable.
+    #[arg(long, global = true)]
+    mqtt_cert_file: Option<PathBuf>,
+
+    /// The PEM encoded private key for the client certificate.
+    /// You may also set this via the GOVEE_MQTT_KEY_FILE environment variable.
+    #[arg(long, global = true)]
+    mqtt_key_file: Option<PathBuf>,
+
+    /// The MQTT protocol version to use: "3", "5" or "auto".
+    /// auto will try MQTT v5 and fall back to v3 if the broker
+    /// doesn't accept the v5 connection.
+    /// You may also set this via the GOVEE_MQTT_PROTOCOL environment
+    /// variable. The default is auto.
+    #[arg(long, global = true)]
+    mqtt_protocol: Option<MqttProtocol>,
+
+    /// The QoS level (0, 1 or 2) used when publishing entity configs
+    /// for hass discovery. The default is 0.
+    /// You may also set this via the GOVEE_MQTT_DISCOVERY_QOS environment
+    /// variable.
+    #[arg(long, global = true)]
+    mqtt_discovery_qos: Option<u8>,
+
+    /// The QoS level (0, 1 or 2) used when publishing entity state.
+    /// The default is 0.
+    /// You may also set this via the GOVEE_MQTT_STATE_QOS environment
+    /// variable.
+    #[arg(long, global = true)]
+    mqtt_state_qos: Option<u8>,
+
+    /// Publish entity state as retained messages, so that hass can
+    /// show the last known state immediately after it restarts.
+    /// Events and availability are never retained.
+    /// You may also set GOVEE_MQTT_RETAIN_STATE=true via the environment.
+    #[arg(long, global = true)]
+    mqtt_retain_state: bool,
+
     /// The username to authenticate against the broker
     /// You may also set this via the GOVEE_MQTT_USER environment variable.
     #[arg(long, global = true)]
@@ -46,9 +134,74 @@ pub struct HassArguments {
     #[arg(long, global = true)]
     mqtt_bind_address: Option<String>,
 
+    /// The client id to use when connecting to the broker.
+    /// If unspecified, it is derived from the instance name, so that
+    /// the broker can resume our session after a restart.
+    /// You may also set this via the GOVEE_MQTT_CLIENT_ID environment
+    /// variable.
+    #[arg(long, global = true)]
+    mqtt_client_id: Option<String>,
+
+    /// The name of this instance, which is used as the prefix for
+    /// all of its topics. Set this to a different value for each
+    /// instance when running more than one instance against the same
+    /// broker. The default is gv2mqtt.
+    /// You may also set this via the GOVEE_INSTANCE_NAME environment
+    /// variable.
+    #[arg(long, global = true)]
+    instance_name: Option<String>,
+
+    /// The payload published to the availability topic when we
+    /// come online. The default is "online".
+    /// You may also set this via the GOVEE_MQTT_BIRTH_PAYLOAD environment
+    /// variable.
+    #[arg(long, global = true)]
+    mqtt_birth_payload: Option<String>,
+
+    /// The last-will payload that the broker publishes to the
+    /// availability topic if we go offline. The default is "offline".
+    /// You may also set this via the GOVEE_MQTT_LWT_PAYLOAD environment
+    /// variable.
+    #[arg(long, global = true)]
+    mqtt_lwt_payload: Option<String>,
+
     #[arg(long, global = true, default_value = "homeassistant")]
     hass_discovery_prefix: String,
 
+    /// Publish a single discovery config for each device, containing
+    /// all of its entities, rather than a config for each entity.
+    /// Requires Home Assistant 2024.11 or later.
+    /// You may also set GOVEE_HASS_DEVICE_DISCOVERY=true via the environment.
+    #[arg(long, global = true)]
+    hass_device_discovery: bool,
+
+    /// How long, in milliseconds, to wait after the first of a burst
+    /// of state changes for a device before publishing its state, so
+    /// that the burst is published once. Use 0 to publish every change
+    /// immediately. The default is 250.
+    /// You may also set this via the GOVEE_HASS_STATE_DEBOUNCE_MS
+    /// environment variable.
+    #[arg(long, global = true)]
+    hass_state_debounce_ms: Option<u64>,
+
+    /// The maximum number of scenes to offer as effects of a light
+    /// entity; the scene select entity always offers all of them.
+    /// Use 0 to offer scenes only via the select. The default is 100.
+    /// You may also set this via the GOVEE_HASS_EFFECT_LIST_LIMIT
+    /// environment variable.
+    #[arg(long, global = true)]
+    hass_effect_list_limit: Option<usize>,
+
+    /// How long, in seconds, a leak sensor stays on after the most
+    /// recent leak event, as leak detectors never report that they
+    /// are dry again. This also applies to the tip-over and overheat
+    /// sensors of heaters that don't report when the condition is
+    /// resolved. Use 0 to keep them on. The default is 300.
+    /// You may also set this via the GOVEE_HASS_LEAK_CLEAR_SECS
+    /// environment variable.
+    #[arg(long, global = true)]
+    hass_leak_clear_secs: Option<u64>,
+
     /// The temperature scale to use when showing temperature values as
     /// entities in home assistant. Can be either "C" or "F" for Celsius
     /// or Fahrenheit respectively.
@@ -56,6 +209,31 @@ pub struct HassArguments {
     /// variable.
     #[arg(long, global = true)]
     temperature_scale: Option<String>,
+
+    /// A comma separated list of the ids, names or SKUs of RGBIC devices
+    /// for which an additional light entity should be created for
+    /// each segment. Use "all" to enable this for every such device.
+    /// You may also set this via the GOVEE_SEGMENT_LIGHTS environment
+    /// variable.
+    #[arg(long, global = true)]
+    segment_lights: Option<String>,
+
+    /// How many steps per second to send when performing a light
+    /// transition requested by Home Assistant via the LAN API.
+    /// Use 0 to apply all changes immediately. The default is 10.
+    /// You may also set this via the GOVEE_LIGHT_TRANSITION_RATE
+    /// environment variable.
+    #[arg(long, global = true)]
+    light_transition_rate: Option<u32>,
+
+    /// Perform light transitions for devices that are controlled via
+    /// Govee's cloud services too, by changing the brightness in a
+    /// few steps. Each step uses up some of the request quota, so
+    /// this is off by default.
+    /// You may also set GOVEE_COARSE_CLOUD_TRANSITIONS=true via the
+    /// environment.
+    #[arg(long, global = true)]
+    coarse_cloud_transitions: bool,
 }
 
 impl HassArguments {
@@ -66,22 +244,184 @@ impl HassArguments {
         }
     }
 
-    pub fn mqtt_host(&self) -> anyhow::Result<String> {
-        self.opt_mqtt_host()?.ok_or_else(|| {
+    fn mqtt_broker(&self) -> anyhow::Result<MqttBroker> {
+        let host = self.opt_mqtt_host()?.ok_or_else(|| {
             anyhow::anyhow!(
                 "Please specify the mqtt broker either via the \
                 --mqtt-host parameter or by setting $GOVEE_MQTT_HOST"
             )
-        })
+        })?;
+        MqttBroker::parse(&host)
+    }
+
+    pub fn mqtt_host(&self) -> anyhow::Result<String> {
+        Ok(self.mqtt_broker()?.host)
     }
 
     pub fn mqtt_port(&self) -> anyhow::Result<u16> {
-        match self.mqtt_port {
+        let port = match self.mqtt_port {
+            Some(p) => Some(p),
+            None => opt_env_var("GOVEE_MQTT_PORT")?,
+        };
+        match port {
+            Some(p) => Ok(p),
+            None => {
+                let broker = self.mqtt_broker()?;
+                Ok(broker
+                    .port
+                    .unwrap_or(if self.mqtt_tls()? { 8883 } else { 1883 }))
+            }
+        }
+    }
+
+    fn opt_string_arg(arg: &Option<String>, env: &str) -> anyhow::Result<Option<String>> {
+        match arg {
+            Some(s) => Ok(Some(s.to_string())),
+            None => opt_env_var(env),
+        }
+    }
+
+    pub fn mqtt_client_id(&self) -> anyhow::Result<String> {
+        match Self::opt_string_arg(&self.mqtt_client_id, "GOVEE_MQTT_CLIENT_ID")? {
+            Some(id) => Ok(id),
+            None => Ok(format!(
+                "govee2mqtt/{}",
+                self.instance_naming()?.instance_name
+            )),
+        }
+    }
+
+    pub fn instance_naming(&self) -> anyhow::Result<InstanceNaming> {
+        let defaults = InstanceNaming::default();
+        let instance_name = Self::opt_string_arg(&self.instance_name, "GOVEE_INSTANCE_NAME")?
+            .unwrap_or(defaults.instance_name);
+        if instance_name.is_empty()
+            || instance_name.contains(|c: char| matches!(c, '/' | '+' | '#') || c.is_whitespace())
+        {
+            anyhow::bail!(
+                "{instance_name:?} is not a valid instance name; it must be non-empty \
+                and may not contain whitespace, '/', '+' or '#'"
+            );
+        }
+        Ok(InstanceNaming {
+            instance_name,
+            birth_payload: Self::opt_string_arg(
+                &self.mqtt_birth_payload,
+                "GOVEE_MQTT_BIRTH_PAYLOAD",
+            )?
+            .unwrap_or(defaults.birth_payload),
+            lwt_payload: Self::opt_string_arg(&self.mqtt_lwt_payload, "GOVEE_MQTT_LWT_PAYLOAD")?
+                .unwrap_or(defaults.lwt_payload),
+        })
+    }
+
+    pub fn mqtt_protocol(&self) -> anyhow::Result<MqttProtocol> {
+        match self.mqtt_protocol {
             Some(p) => Ok(p),
-            None => Ok(opt_env_var("GOVEE_MQTT_PORT")?.unwrap_or(1883)),
+            None => Ok(opt_env_var("GOVEE_MQTT_PROTOCOL")?.unwrap_or(MqttProtocol::Auto)),
+        }
+    }
+
+    fn qos_arg(arg: Option<u8>, env: &str) -> anyhow::Result<QoS> {
+        let level = match arg {
+            Some(level) => Some(level),
+            None => opt_env_var(env)?,
+        };
+        match level {
+            None | Some(0) => Ok(QoS::AtMostOnce),
+            Some(1) => Ok(QoS::AtLeastOnce),
+            Some(2) => Ok(QoS::ExactlyOnce),
+            Some(level) => {
+                anyhow::bail!("{level} is not a valid QoS level for {env}; use 0, 1 or 2")
+            }
         }
     }
 
+    pub fn publish_options(&self) -> anyhow::Result<PublishOptions> {
+        Ok(PublishOptions {
+            discovery_qos: Self::qos_arg(self.mqtt_discovery_qos, "GOVEE_MQTT_DISCOVERY_QOS")?,
+            state_qos: Self::qos_arg(self.mqtt_state_qos, "GOVEE_MQTT_STATE_QOS")?,
+            retain_state: self.mqtt_retain_state
+                || match opt_env_var::<String>("GOVEE_MQTT_RETAIN_STATE")? {
+                    Some(v) => truthy(&v)?,
+                    None => false,
+                },
+            device_discovery: self.hass_device_discovery
+                || match opt_env_var::<String>("GOVEE_HASS_DEVICE_DISCOVERY")? {
+                    Some(v) => truthy(&v)?,
+                    None => false,
+                },
+        })
+    }
+
+    pub fn mqtt_tls(&self) -> anyhow::Result<bool> {
+        if self.mqtt_tls {
+            return Ok(true);
+        }
+        if let Some(v) = opt_env_var::<String>("GOVEE_MQTT_TLS")? {
+            if truthy(&v)? {
+                return Ok(true);
+            }
+        }
+        Ok(self.mqtt_broker()?.tls)
+    }
+
+    fn opt_path_arg(arg: &Option<PathBuf>, env: &str) -> anyhow::Result<Option<PathBuf>> {
+        match arg {
+            Some(p) => Ok(Some(p.clone())),
+            None => opt_env_var(env),
+        }
+    }
+
+    /// Returns the TLS configuration, or None if TLS is not enabled
+    pub fn mqtt_tls_config(&self) -> anyhow::Result<Option<MqttTlsConfig>> {
+        let ca_file = Self::opt_path_arg(&self.mqtt_ca_file, "GOVEE_MQTT_CA_FILE")?;
+        let cert_file = Self::opt_path_arg(&self.mqtt_cert_file, "GOVEE_MQTT_CERT_FILE")?;
+        let key_file = Self::opt_path_arg(&self.mqtt_key_file, "GOVEE_MQTT_KEY_FILE")?;
+
+        if !self.mqtt_tls()? {
+            if ca_file.is_some() || cert_file.is_some() || key_file.is_some() {
+                log::warn!(
+                    "MQTT TLS options were specified but TLS is not enabled; \
+                    set GOVEE_MQTT_TLS=true or use an mqtts:// URL"
+                );
+            }
+            return Ok(None);
+        }
+
+        Ok(Some(MqttTlsConfig {
+            ca_file,
+            cert_file,
+            key_file,
+        }))
+    }
+
+    /// Describes each of the settings that can only take effect by
+    /// reconnecting to the broker, so that a reload can tell which of
+    /// them changed. The descriptions are compared, and never logged.
+    pub fn restart_settings(&self) -> anyhow::Result<Vec<(&'static str, String)>> {
+        Ok(vec![
+            ("MQTT host", format!("{:?}", self.opt_mqtt_host()?)),
+            ("MQTT port", format!("{:?}", self.mqtt_port().ok())),
+            ("MQTT user", format!("{:?}", self.mqtt_username()?)),
+            ("MQTT password", format!("{:?}", self.mqtt_password()?)),
+            ("MQTT protocol", format!("{:?}", self.mqtt_protocol()?)),
+            (
+                "MQTT client id",
+                format!(
+                    "{:?}",
+                    Self::opt_string_arg(&self.mqtt_client_id, "GOVEE_MQTT_CLIENT_ID")?
+                ),
+            ),
+            ("MQTT TLS", format!("{:?}", self.mqtt_tls_config().ok())),
+            (
+                "MQTT publish options",
+                format!("{:?}", self.publish_options()?),
+            ),
+            ("instance name", format!("{:?}", self.instance_naming()?)),
+        ])
+    }
+
     pub fn mqtt_username(&self) -> anyhow::Result<Option<String>> {
         match self.mqtt_username.clone() {
             Some(u) => Ok(Some(u)),
@@ -96,6 +436,70 @@ impl HassArguments {
         }
     }
 
+    /// Returns the devices for which segment lights were requested,
+    /// either in the list from the command line or environment, or
+    /// via a device section in the configuration file
+    pub fn segment_lights(&self) -> anyhow::Result<Vec<String>> {
+        let list = match &self.segment_lights {
+            Some(s) => Some(s.to_string()),
+            None => opt_env_var::<String>("GOVEE_SEGMENT_LIGHTS")?,
+        };
+        let mut devices: Vec<String> = list
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+        for (device, config) in crate::config::current().devices() {
+            if config.segment_lights == Some(true) {
+                devices.push(device.to_string());
+            }
+        }
+        Ok(devices)
+    }
+
+    pub fn state_debounce(&self) -> anyhow::Result<Duration> {
+        Ok(match self.hass_state_debounce_ms {
+            Some(ms) => Duration::from_millis(ms),
+            None => opt_env_var("GOVEE_HASS_STATE_DEBOUNCE_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_STATE_DEBOUNCE),
+        })
+    }
+
+    pub fn effect_list_limit(&self) -> anyhow::Result<usize> {
+        Ok(match self.hass_effect_list_limit {
+            Some(limit) => limit,
+            None => {
+                opt_env_var("GOVEE_HASS_EFFECT_LIST_LIMIT")?.unwrap_or(DEFAULT_EFFECT_LIST_LIMIT)
+            }
+        })
+    }
+
+    pub fn leak_clear_timeout(&self) -> anyhow::Result<Duration> {
+        Ok(match self.hass_leak_clear_secs {
+            Some(secs) => Duration::from_secs(secs),
+            None => opt_env_var("GOVEE_HASS_LEAK_CLEAR_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_LEAK_CLEAR_TIMEOUT),
+        })
+    }
+
+    pub fn coarse_cloud_transitions(&self) -> anyhow::Result<bool> {
+        Ok(self.coarse_cloud_transitions
+            || match opt_env_var::<String>("GOVEE_COARSE_CLOUD_TRANSITIONS")? {
+                Some(v) => truthy(&v)?,
+                None => false,
+            })
+    }
+
+    pub fn light_transition_rate(&self) -> anyhow::Result<u32> {
+        Ok(match self.light_transition_rate {
+            Some(rate) => rate,
+            None => opt_env_var("GOVEE_LIGHT_TRANSITION_RATE")?.unwrap_or(DEFAULT_TRANSITION_RATE),
+        })
+    }
+
     pub fn temperature_scale(&self) -> anyhow::Result<TemperatureScale> {
         match &self.temperature_scale {
             Some(s) => Ok(s.parse()?),
@@ -106,18 +510,272 @@ impl HassArguments {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MqttProtocol {
+    /// The protocol used by the underlying client library by default
+    V3,
+    V5,
+    /// Try V5, falling back to V3
+    Auto,
+}
+
+impl std::str::FromStr for MqttProtocol {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().trim_start_matches('v') {
+            "3" | "3.1" | "3.1.1" => Ok(Self::V3),
+            "5" | "5.0" => Ok(Self::V5),
+            "auto" => Ok(Self::Auto),
+            _ => anyhow::bail!("invalid MQTT protocol {s}; use one of 3, 5 or auto"),
+        }
+    }
+}
+
+impl std::fmt::Display for MqttProtocol {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::V3 => write!(fmt, "MQTT v3"),
+            Self::V5 => write!(fmt, "MQTT v5"),
+            Self::Auto => write!(fmt, "auto"),
+        }
+    }
+}
+
+/// How long to wait for the broker to respond to a v5 CONNECT when
+/// automatically selecting the protocol. Some older brokers never
+/// respond to it, rather than rejecting it or closing the connection.
+const MQTT_V5_PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Returns true if the broker rejected the connection because it
+/// doesn't support the requested protocol version. A v3 broker will
+/// respond with the v3 "unacceptable protocol version" code, whereas
+/// a v5 broker that has been configured to refuse v5 uses the v5 code.
+fn is_unsupported_protocol(status: ConnectionStatus) -> bool {
+    const V3_UNACCEPTABLE_PROTOCOL_VERSION: i32 = 1;
+    const V5_UNSUPPORTED_PROTOCOL_VERSION: i32 = 0x84;
+    matches!(
+        status.0,
+        V3_UNACCEPTABLE_PROTOCOL_VERSION | V5_UNSUPPORTED_PROTOCOL_VERSION
+    )
+}
+
+/// Describes a CONNACK code; the codes have different meanings
+/// depending on the protocol version
+fn describe_connack(protocol: MqttProtocol, status: ConnectionStatus) -> String {
+    match protocol {
+        MqttProtocol::V5 => ReasonCode(status.0).to_string(),
+        _ => status.to_string(),
+    }
+}
+
+/// Describes the reason for a disconnection. With MQTT v5 the broker
+/// can tell us why it disconnected us, in which case the code is a
+/// v5 reason code; otherwise it is a client library error code.
+fn describe_disconnect_reason(protocol: MqttProtocol, reason: ReasonCode) -> String {
+    if protocol == MqttProtocol::V5 && reason.0 >= 0x80 {
+        return format!("broker sent {reason}");
+    }
+    let desc = match reason.0 {
+        0 => "disconnected by client",
+        2 => "protocol error",
+        7 => "connection lost",
+        8 => "TLS error",
+        14 => "system error",
+        19 => "keepalive timeout",
+        _ => "client error",
+    };
+    format!("code {}: {desc}", reason.0)
+}
+
+/// The broker address, as parsed from the mqtt host parameter
+#[derive(Debug, PartialEq)]
+struct MqttBroker {
+    host: String,
+    port: Option<u16>,
+    tls: bool,
+}
+
+impl MqttBroker {
+    /// Accepts either a bare hostname or address, or an mqtt://
+    /// or mqtts:// URL, optionally with a port number
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let (tls, rest) = if let Some(rest) = s.strip_prefix("mqtts://") {
+            (true, rest)
+        } else if let Some(rest) = s.strip_prefix("mqtt://") {
+            (false, rest)
+        } else if s.contains("://") {
+            anyhow::bail!("{s} is not a valid mqtt broker; use either mqtt:// or mqtts://");
+        } else {
+            return Ok(Self {
+                host: s.to_string(),
+                port: None,
+                tls: false,
+            });
+        };
+
+        let rest = rest.trim_end_matches('/');
+        let (host, port) = match rest.rsplit_once(':') {
+            // An IPv6 address without a port
+            Some((host, _)) if host.starts_with('[') && !host.ends_with(']') => (rest, None),
+            Some((host, port)) => (
+                host,
+                Some(
+                    port.parse::<u16>()
+                        .with_context(|| format!("parsing port number from {s}"))?,
+                ),
+            ),
+            None => (rest, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            anyhow::bail!("{s} has no host name");
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+}
+
+/// The location of the system trust store, used when no CA file
+/// was specified
+const SYSTEM_CA_FILES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/ssl/cert.pem",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+];
+
+#[derive(Debug)]
+pub struct MqttTlsConfig {
+    ca_file: Option<PathBuf>,
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+}
+
+fn read_pem_certs(path: &Path) -> anyhow::Result<Vec<X509>> {
+    let pem =
+        std::fs::read(path).with_context(|| format!("reading certificate {}", path.display()))?;
+    let certs = X509::stack_from_pem(&pem)
+        .with_context(|| format!("{} is not a valid PEM certificate file", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("{} does not contain any certificates", path.display());
+    }
+    Ok(certs)
+}
+
+impl MqttTlsConfig {
+    /// Checks that the certificate and key files are usable, so that
+    /// a problem with them is reported in terms of the file rather
+    /// than as an opaque failure to connect
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(ca_file) = &self.ca_file {
+            read_pem_certs(ca_file)?;
+        }
+
+        match (&self.cert_file, &self.key_file) {
+            (None, None) => {}
+            (Some(cert_file), Some(key_file)) => {
+                let certs = read_pem_certs(cert_file)?;
+                let cert = &certs[0];
+                if cert.not_after() < Asn1Time::days_from_now(0)?.as_ref() {
+                    anyhow::bail!(
+                        "the client certificate {} expired on {}",
+                        cert_file.display(),
+                        cert.not_after()
+                    );
+                }
+
+                let pem = std::fs::read(key_file)
+                    .with_context(|| format!("reading private key {}", key_file.display()))?;
+                let key = PKey::private_key_from_pem(&pem).with_context(|| {
+                    format!(
+                        "{} is not a valid unencrypted PEM private key",
+                        key_file.display()
+                    )
+                })?;
+                if !cert.public_key()?.public_eq(&key) {
+                    anyhow::bail!(
+                        "the private key {} does not match the client certificate {}",
+                        key_file.display(),
+                        cert_file.display()
+                    );
+                }
+            }
+            (Some(_), None) => {
+                anyhow::bail!("GOVEE_MQTT_CERT_FILE requires GOVEE_MQTT_KEY_FILE to also be set")
+            }
+            (None, Some(_)) => {
+                anyhow::bail!("GOVEE_MQTT_KEY_FILE requires GOVEE_MQTT_CERT_FILE to also be set")
+            }
+        }
+
+        Ok(())
+    }
+
+    fn configure(&self, client: &Client) -> anyhow::Result<()> {
+        self.validate()?;
+
+        let ca_file = match &self.ca_file {
+            Some(ca_file) => ca_file.clone(),
+            None => SYSTEM_CA_FILES
+                .iter()
+                .map(PathBuf::from)
+                .find(|p| p.exists())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Unable to locate the system trust store; \
+                        please specify a CA certificate via GOVEE_MQTT_CA_FILE"
+                    )
+                })?,
+        };
+
+        // Hostname verification is performed by the client unless
+        // explicitly disabled, which we never do
+        client
+            .configure_tls(
+                Some(&ca_file),
+                None::<&Path>,
+                self.cert_file.as_ref(),
+                self.key_file.as_ref(),
+                None,
+            )
+            .with_context(|| format!("configuring MQTT TLS with CA {}", ca_file.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Controls how we publish to the broker
+#[derive(Clone, Copy, Debug)]
+pub struct PublishOptions {
+    discovery_qos: QoS,
+    state_qos: QoS,
+    retain_state: bool,
+    device_discovery: bool,
+}
+
 #[derive(Clone)]
 pub struct HassClient {
     client: Client,
+    options: PublishOptions,
+    /// When set, entity configs are collected here rather than
+    /// being published individually
+    discovery: Option<Arc<DeviceDiscovery>>,
 }
 
 impl HassClient {
-    async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
+    pub async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
+        // We may be (re)connecting to a hass that doesn't know about
+        // any of our entities, so publish all of them
+        state.forget_discovery_configs().await;
         let entities = enumerate_all_entites(state).await?;
 
         // Register the configs
         log::trace!("register_with_hass: register entities");
         entities.publish_config(state, self).await?;
+        record_applied_migrations(state).await;
 
         // Allow hass extra time to register the entities before
         // we mark them as available
@@ -130,9 +788,12 @@ impl HassClient {
 
         // Mark as available
         log::trace!("register_with_hass: mark as online");
-        self.publish(availability_topic(), "online")
+        self.publish_transient(availability_topic(), &instance_naming().birth_payload)
             .await
             .context("online -> availability_topic")?;
+        for device in state.devices().await {
+            state.report_device_availability(&device, true).await?;
+        }
 
         // report initial state
         log::trace!("register_with_hass: reporting state");
@@ -143,19 +804,92 @@ impl HassClient {
         Ok(())
     }
 
+    pub fn uses_device_discovery(&self) -> bool {
+        self.options.device_discovery
+    }
+
+    /// Returns a client that collects entity configs into discovery,
+    /// rather than publishing them
+    pub fn collecting_into(&self, discovery: &Arc<DeviceDiscovery>) -> Self {
+        Self {
+            client: self.client.clone(),
+            options: self.options,
+            discovery: Some(discovery.clone()),
+        }
+    }
+
+    pub fn device_discovery(&self) -> Option<&DeviceDiscovery> {
+        self.discovery.as_deref()
+    }
+
+    /// Publishes entity state, retaining it if so configured
     pub async fn publish<T: AsRef<str> + std::fmt::Display, P: AsRef<[u8]> + std::fmt::Display>(
         &self,
         topic: T,
         payload: P,
+    ) -> anyhow::Result<()> {
+        let retain = self.options.retain_state;
+        log::trace!(
+            "{topic} -> {payload}{}",
+            if retain { " (retained)" } else { "" }
+        );
+        self.client
+            .publish(topic, payload, self.options.state_qos, retain)
+            .await?;
+        metrics::increment(Counter::MqttPublishes, &[]);
+        Ok(())
+    }
+
+    /// Publishes something that must never be retained, regardless of
+    /// the configuration: availability, which would otherwise outlive
+    /// the last-will, and momentary events, which hass would otherwise
+    /// replay each time it subscribes.
+    pub async fn publish_transient<
+        T: AsRef<str> + std::fmt::Display,
+        P: AsRef<[u8]> + std::fmt::Display,
+    >(
+        &self,
+        topic: T,
+        payload: P,
     ) -> anyhow::Result<()> {
         log::trace!("{topic} -> {payload}");
         self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
+            .publish(topic, payload, self.options.state_qos, false)
             .await?;
+        metrics::increment(Counter::MqttPublishes, &[]);
         Ok(())
     }
 
-    pub async fn publish_obj<T: AsRef<str> + std::fmt::Display, P: Serialize>(
+    pub async fn subscribe(&self, pattern: &str) -> anyhow::Result<()> {
+        self.client
+            .subscribe(pattern, self.options.discovery_qos)
+            .await
+            .with_context(|| format!("subscribe to {pattern}"))
+    }
+
+    pub async fn unsubscribe(&self, pattern: &str) -> anyhow::Result<()> {
+        self.client
+            .unsubscribe(pattern)
+            .await
+            .with_context(|| format!("unsubscribe from {pattern}"))
+    }
+
+    /// Publishes a retained discovery config
+    pub async fn publish_retained<T: AsRef<str> + std::fmt::Display>(
+        &self,
+        topic: T,
+        payload: &str,
+    ) -> anyhow::Result<()> {
+        log::trace!("{topic} -> {payload} (retained)");
+        self.client
+            .publish(topic, payload, self.options.discovery_qos, true)
+            .await?;
+        metrics::increment(Counter::MqttPublishes, &[]);
+        Ok(())
+    }
+
+    /// Publishes a discovery config
+    pub async fn publish_config_obj<T: AsRef<str> + std::fmt::Display, P: Serialize>(
         &self,
         topic: T,
         payload: P,
@@ -163,11 +897,22 @@ impl HassClient {
         let payload = serde_json::to_string(&payload)?;
         log::trace!("{topic} -> {payload}");
         self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
+            .publish(topic, payload, self.options.discovery_qos, false)
             .await?;
+        metrics::increment(Counter::MqttPublishes, &[]);
         Ok(())
     }
 
+    /// Publishes entity state as JSON, retaining it if so configured
+    pub async fn publish_obj<T: AsRef<str> + std::fmt::Display, P: Serialize>(
+        &self,
+        topic: T,
+        payload: P,
+    ) -> anyhow::Result<()> {
+        let payload = serde_json::to_string(&payload)?;
+        self.publish(topic, payload).await
+    }
+
     pub async fn advise_hass_of_light_state(
         &self,
         device: &ServiceDevice,
@@ -179,6 +924,52 @@ impl HassClient {
 
         Ok(())
     }
+
+    /// Publishes the state of the room lights that the device is part of
+    pub async fn advise_hass_of_room_state(
+        &self,
+        device: &ServiceDevice,
+        state: &StateHandle,
+    ) -> anyhow::Result<()> {
+        if !room_lights_enabled() {
+            return Ok(());
+        }
+        for (room, lights) in state.rooms_with_lights().await {
+            if lights.iter().any(|light| light.id == device.id) {
+                RoomLight::new(&room, &lights, state)
+                    .notify_state(self)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-publishes the room lights after the rooms changed in the
+    /// Govee app, removing those that no longer apply
+    pub async fn republish_room_lights(&self, state: &StateHandle) -> anyhow::Result<()> {
+        let mut entities = EntityList::new();
+        enumerate_room_lights(state, &mut entities).await;
+        entities.publish_config(state, self).await?;
+        entities.notify_state(self).await?;
+
+        Ok(())
+    }
+
+    /// Re-publishes the entity configs for a device, followed by their
+    /// state, so that hass picks up changes such as a new list of
+    /// available scenes.
+    pub async fn republish_device(
+        &self,
+        device: &ServiceDevice,
+        state: &StateHandle,
+    ) -> anyhow::Result<()> {
+        let mut entities = EntityList::new();
+        enumerate_entities_for_device(device, state, &mut entities).await?;
+        entities.publish_config(state, self).await?;
+        entities.notify_state(self).await?;
+
+        Ok(())
+    }
 }
 
 pub fn topic_safe_string(s: &str) -> String {
@@ -194,7 +985,11 @@ pub fn topic_safe_string(s: &str) -> String {
 }
 
 pub fn topic_safe_id(device: &ServiceDevice) -> String {
-    let mut id = device.id.to_string();
+    topic_safe_device_id(&device.id)
+}
+
+pub fn topic_safe_device_id(id: &str) -> String {
+    let mut id = id.to_string();
     id.retain(|c| c != ':');
     id.retain(|c| c != ' ');
     id
@@ -202,34 +997,144 @@ pub fn topic_safe_id(device: &ServiceDevice) -> String {
 
 pub fn switch_instance_state_topic(device: &ServiceDevice, instance: &str) -> String {
     format!(
-        "gv2mqtt/switch/{id}/{instance}/state",
+        "{prefix}/switch/{id}/{instance}/state",
+        prefix = topic_prefix(),
         id = topic_safe_id(device)
     )
 }
 
 pub fn light_state_topic(device: &ServiceDevice) -> String {
-    format!("gv2mqtt/light/{id}/state", id = topic_safe_id(device))
+    format!(
+        "{prefix}/light/{id}/state",
+        prefix = topic_prefix(),
+        id = topic_safe_id(device)
+    )
 }
 
 pub fn light_segment_state_topic(device: &ServiceDevice, segment: u32) -> String {
     format!(
-        "gv2mqtt/light/{id}/state/{segment}",
+        "{prefix}/light/{id}/state/{segment}",
+        prefix = topic_prefix(),
         id = topic_safe_id(device)
     )
 }
 
+/// The name used to distinguish this instance from any others that
+/// share the same broker. It is used as the prefix for our topics.
+pub const DEFAULT_INSTANCE_NAME: &str = "gv2mqtt";
+
+/// How this instance names things on the broker. This is configured
+/// once, at startup, so that multiple instances can share a broker
+/// without publishing over each other.
+#[derive(Debug, PartialEq)]
+pub struct InstanceNaming {
+    pub instance_name: String,
+    pub birth_payload: String,
+    pub lwt_payload: String,
+}
+
+impl Default for InstanceNaming {
+    fn default() -> Self {
+        Self {
+            instance_name: DEFAULT_INSTANCE_NAME.to_string(),
+            birth_payload: "online".to_string(),
+            lwt_payload: "offline".to_string(),
+        }
+    }
+}
+
+static INSTANCE_NAMING: OnceCell<InstanceNaming> = OnceCell::new();
+
+pub fn instance_naming() -> &'static InstanceNaming {
+    INSTANCE_NAMING.get_or_init(InstanceNaming::default)
+}
+
+fn set_instance_naming(naming: InstanceNaming) -> anyhow::Result<()> {
+    if let Err(naming) = INSTANCE_NAMING.set(naming) {
+        if naming != *instance_naming() {
+            anyhow::bail!("the instance name was used before it was configured");
+        }
+    }
+    Ok(())
+}
+
+/// The prefix for all of our topics
+pub fn topic_prefix() -> &'static str {
+    &instance_naming().instance_name
+}
+
+/// Returns the unique_id to use in hass for an entity. When multiple
+/// instances share a broker, their entities must not collide in hass,
+/// so the unique_id is qualified by the instance name, unless we are
+/// using the default name.
+pub fn scoped_unique_id(unique_id: &str) -> Cow<'_, str> {
+    scope_unique_id(topic_prefix(), unique_id)
+}
+
+fn scope_unique_id<'a>(prefix: &str, unique_id: &'a str) -> Cow<'a, str> {
+    if prefix == DEFAULT_INSTANCE_NAME {
+        return Cow::Borrowed(unique_id);
+    }
+    match unique_id.strip_prefix(DEFAULT_INSTANCE_NAME) {
+        Some(rest) if rest.starts_with('-') => Cow::Owned(format!("{prefix}{rest}")),
+        _ => Cow::Owned(format!("{prefix}-{unique_id}")),
+    }
+}
+
 /// All entities use the same topic so that we can mark unavailable
 /// via last-will
 pub fn availability_topic() -> String {
-    "gv2mqtt/availability".to_string()
+    format!("{prefix}/availability", prefix = topic_prefix())
+}
+
+/// Each device also has its own availability topic, so that a device
+/// that has dropped off the network shows as unavailable while the
+/// bridge itself remains online
+pub fn device_availability_topic(device: &ServiceDevice) -> String {
+    format!(
+        "{prefix}/availability/{id}",
+        prefix = topic_prefix(),
+        id = topic_safe_id(device)
+    )
 }
 
 pub fn oneclick_topic() -> String {
-    "gv2mqtt/oneclick".to_string()
+    format!("{prefix}/oneclick", prefix = topic_prefix())
 }
 
 pub fn purge_cache_topic() -> String {
-    "gv2mqtt/purge-caches".to_string()
+    format!("{prefix}/purge-caches", prefix = topic_prefix())
+}
+
+pub fn reload_credentials_topic() -> String {
+    format!("{prefix}/reload-credentials", prefix = topic_prefix())
+}
+
+/// Where the outcome of reloading the configuration is published
+pub fn reload_credentials_status_topic() -> String {
+    format!(
+        "{prefix}/reload-credentials/status",
+        prefix = topic_prefix()
+    )
+}
+
+pub fn reload_topic() -> String {
+    format!("{prefix}/reload", prefix = topic_prefix())
+}
+
+/// Where the outcome of a reload requested via `reload_topic`,
+/// or via SIGHUP, is published
+pub fn reload_status_topic() -> String {
+    format!("{prefix}/reload/status", prefix = topic_prefix())
+}
+
+/// Accepts requests to change the log level of individual devices
+pub fn admin_log_topic() -> String {
+    format!("{prefix}/admin/log", prefix = topic_prefix())
+}
+
+pub fn purge_discovery_topic() -> String {
+    format!("{prefix}/purge-discovery", prefix = topic_prefix())
 }
 
 #[derive(Deserialize)]
@@ -257,6 +1162,28 @@ struct HassLightCommand {
     color: Option<DeviceColor>,
     effect: Option<String>,
     brightness: Option<u8>,
+    /// The duration of the transition, in seconds
+    transition: Option<f64>,
+}
+
+impl HassLightCommand {
+    fn transition_duration(&self) -> Option<Duration> {
+        self.transition
+            .filter(|secs| secs.is_finite() && *secs > 0.0)
+            .map(Duration::from_secs_f64)
+    }
+}
+
+/// Light commands only replace a queued command that changes the same
+/// things, so that eg: a brightness change doesn't swallow an earlier
+/// change of color
+fn light_command_kind(entity: &str, payload: &str) -> String {
+    let mut fields: Vec<String> =
+        serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(payload)
+            .map(|map| map.keys().cloned().collect())
+            .unwrap_or_default();
+    fields.sort();
+    format!("{entity}:{}", fields.join(","))
 }
 
 /// HASS is sending a command to a light
@@ -265,22 +1192,57 @@ async fn mqtt_light_command(
     Params(IdParameter { id }): Params<IdParameter>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
-    let device = state.resolve_device_for_control(&id).await?;
+    let kind = light_command_kind("light", &payload);
+    let Some(device) = state.resolve_device_for_command(&id, &kind).await? else {
+        return Ok(());
+    };
 
     let command: HassLightCommand = serde_json::from_str(&payload)?;
     log::info!("Command for {device}: {payload}");
 
+    apply_light_command(&state, &device, &command).await
+}
+
+async fn apply_light_command(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    command: &HassLightCommand,
+) -> anyhow::Result<()> {
     let is_light = device.device_type() == DeviceType::Light;
 
+    // A new command supersedes any transition that is in progress
+    state.cancel_light_transition(&device.id).await;
+    let faded_brightness = state.take_faded_brightness(&device.id).await;
+
+    if let Some(duration) = command.transition_duration() {
+        let request = TransitionRequest {
+            on: command.state != "OFF",
+            brightness: command.brightness,
+            color: command.color,
+            kelvin: command.color_temp.map(mired_to_kelvin),
+        };
+        // Scenes can't be transitioned to, and devices that are
+        // controlled via the cloud APIs apply the command immediately,
+        // as each step would use up some of the request quota
+        if is_light
+            && command.effect.is_none()
+            && state
+                .start_light_transition(device, request, faded_brightness, duration)
+                .await
+        {
+            return Ok(());
+        }
+    }
+
     if command.state == "OFF" {
         if is_light {
             state
-                .device_light_power_on(&device, false)
+                .device_light_power_on(device, false)
                 .await
                 .context("mqtt_light_command: state.device_power_on")?;
         } else {
             state
-                .device_set_brightness(&device, 0)
+                .device_set_brightness(device, 0)
                 .await
                 .context("mqtt_light_command: state.device_set_brightness")?;
         }
@@ -289,7 +1251,7 @@ async fn mqtt_light_command(
 
         if let Some(brightness) = command.brightness {
             state
-                .device_set_brightness(&device, brightness)
+                .device_set_brightness(device, brightness)
                 .await
                 .context("mqtt_light_command: state.device_set_brightness")?;
             power_on = false;
@@ -297,26 +1259,27 @@ async fn mqtt_light_command(
 
         if let Some(effect) = &command.effect {
             state
-                .device_set_scene(&device, effect)
+                .device_set_scene(device, effect)
                 .await
                 .context("mqtt_light_command: state.device_set_scene")?;
-            // It doesn't make sense to vary color properties
-            // at the same time as the scene properties, so
-            // ignore those.
-            // Brightness, set above, is ok.
-            return Ok(());
+            power_on = false;
+            // A color that is given along with the scene is applied
+            // after it, replacing it, as it would be in the app
         }
 
         if let Some(color) = &command.color {
+            exit_light_cycle_mode(state, device)
+                .await
+                .context("mqtt_light_command: exit_light_cycle_mode")?;
             state
-                .device_set_color_rgb(&device, color.r, color.g, color.b)
+                .device_set_color_rgb(device, color.r, color.g, color.b)
                 .await
                 .context("mqtt_light_command: state.device_set_color_rgb")?;
             power_on = false;
         }
         if let Some(color_temp) = command.color_temp {
             state
-                .device_set_color_temperature(&device, mired_to_kelvin(color_temp))
+                .device_set_color_temperature(device, mired_to_kelvin(color_temp))
                 .await
                 .context("mqtt_light_command: state.device_set_color_temperature")?;
             power_on = false;
@@ -325,16 +1288,23 @@ async fn mqtt_light_command(
         if power_on {
             if is_light {
                 state
-                    .device_light_power_on(&device, true)
+                    .device_light_power_on(device, true)
                     .await
                     .context("mqtt_light_command: state.device_power_on")?;
+                if let Some(brightness) = faded_brightness {
+                    // It was faded out, which left it at minimum brightness
+                    state
+                        .device_set_brightness(device, brightness)
+                        .await
+                        .context("mqtt_light_command: state.device_set_brightness")?;
+                }
             } else if command.brightness.is_none() {
                 // The device is not primarily a light and we don't have
                 // a guaranteed way to power it on without setting the
                 // brightness to something, and we know we didn't set
                 // the brightness just now, so let's turn it on 100%
                 state
-                    .device_set_brightness(&device, 100)
+                    .device_set_brightness(device, 100)
                     .await
                     .context("mqtt_light_command: state.device_set_brightness")?;
             }
@@ -344,6 +1314,156 @@ async fn mqtt_light_command(
     Ok(())
 }
 
+/// HASS is sending a command to the light for a room of devices.
+/// It is applied to each of the lights in the room, at the same time;
+/// a light that fails doesn't prevent the others from being changed.
+async fn mqtt_room_light_command(
+    Payload(payload): Payload<String>,
+    Params(RoomParameter { room }): Params<RoomParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let Some((room, lights)) = state
+        .rooms_with_lights()
+        .await
+        .into_iter()
+        .find(|(r, _)| r.id == room)
+    else {
+        anyhow::bail!("There is no room light {room}");
+    };
+    let command: HassLightCommand = serde_json::from_str(&payload)?;
+    log::info!("Command for room {}: {payload}", room.name);
+
+    let kind = light_command_kind("light", &payload);
+    let mut tasks = tokio::task::JoinSet::new();
+    for light in lights {
+        let state = state.clone();
+        let command = command.clone();
+        let kind = kind.clone();
+        tasks.spawn(async move {
+            let result = async {
+                let Some(device) = state.resolve_device_for_command(&light.id, &kind).await? else {
+                    return Ok(());
+                };
+                apply_light_command(&state, &device, &command).await
+            }
+            .await;
+            (light, result)
+        });
+    }
+
+    let mut failed = vec![];
+    while let Some(joined) = tasks.join_next().await {
+        let (light, result) = joined?;
+        if let Err(err) = result {
+            log::error!("Room {}: failed to control {light}: {err:#}", room.name);
+            failed.push(light.name());
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "Room {}: failed to control {}",
+            room.name,
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RoomParameter {
+    room: u64,
+}
+
+/// HASS is sending a command to the nightlight of a device whose
+/// primary function is not a light. The toggle instance is used for
+/// on/off so that the rest of the device is not powered on.
+/// Not all devices report the state of their nightlight, so the
+/// commanded state is recorded in place of the reported state until
+/// the device next reports it.
+async fn mqtt_light_nightlight_command(
+    Payload(payload): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let command: HassLightCommand = from_json(&payload)?;
+    log::info!("Command for {device} {instance}: {payload}");
+
+    let toggle = device
+        .get_capability_by_instance(&instance)
+        .ok_or_else(|| anyhow::anyhow!("{device} has no {instance}"))?;
+
+    if command.state == "OFF" {
+        state
+            .device_control(&device, toggle, 0)
+            .await
+            .context("mqtt_light_nightlight_command: state.device_control")?;
+        return record_nightlight_state(&state, &device, vec![(toggle, 0)]).await;
+    }
+
+    state
+        .device_control(&device, toggle, 1)
+        .await
+        .context("mqtt_light_nightlight_command: state.device_control")?;
+    let mut commanded = vec![(toggle, 1)];
+
+    if let Some(brightness) = command.brightness {
+        state
+            .device_set_brightness(&device, brightness)
+            .await
+            .context("mqtt_light_nightlight_command: state.device_set_brightness")?;
+        if let Some(cap) = device.get_capability_by_instance("brightness") {
+            commanded.push((cap, brightness.into()));
+        }
+    }
+
+    if let Some(effect) = &command.effect {
+        let scene = device
+            .get_capability_by_instance(NIGHTLIGHT_SCENE_INSTANCE)
+            .ok_or_else(|| anyhow::anyhow!("{device} has no {NIGHTLIGHT_SCENE_INSTANCE}"))?;
+        let value = scene
+            .parameters
+            .as_ref()
+            .and_then(|p| p.enum_parameter_by_name(effect))
+            .ok_or_else(|| anyhow::anyhow!("{effect} is not a valid nightlight scene"))?;
+        state
+            .device_control(&device, scene, value)
+            .await
+            .context("mqtt_light_nightlight_command: state.device_control")?;
+        commanded.push((scene, value.into()));
+    } else if let Some(color) = &command.color {
+        state
+            .device_set_color_rgb(&device, color.r, color.g, color.b)
+            .await
+            .context("mqtt_light_nightlight_command: state.device_set_color_rgb")?;
+        if let Some(cap) = device.get_capability_by_instance("colorRgb") {
+            let rgb = ((color.r as i64) << 16) | ((color.g as i64) << 8) | (color.b as i64);
+            commanded.push((cap, rgb));
+        }
+    }
+
+    record_nightlight_state(&state, &device, commanded).await
+}
+
+async fn record_nightlight_state(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    commanded: Vec<(&DeviceCapability, i64)>,
+) -> anyhow::Result<()> {
+    {
+        let mut device = state.device_mut(&device.sku, &device.id).await;
+        for (cap, value) in commanded {
+            device.apply_capability_state(
+                cap.kind.clone(),
+                &cap.instance,
+                serde_json::json!({"value": value}),
+            );
+        }
+    }
+    state.notify_of_state_change(&device.id).await
+}
+
 #[derive(Deserialize)]
 struct IdAndSeg {
     id: String,
@@ -355,43 +1475,70 @@ async fn mqtt_light_segment_command(
     Params(IdAndSeg { id, segment }): Params<IdAndSeg>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
-    let device = state.resolve_device_for_control(&id).await?;
+    let kind = light_command_kind(&format!("segment-{segment}"), &payload);
+    let Some(device) = state.resolve_device_for_command(&id, &kind).await? else {
+        return Ok(());
+    };
     let segment: u32 = segment.parse()?;
 
     let command: HassLightCommand = from_json(&payload)?;
     log::info!("Command for {device} segment {segment}: {payload}");
 
-    if let Some(client) = state.get_platform_client().await {
-        let info = device
-            .http_device_info
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("HTTP device info is missing"))?;
+    // Turning off a segment dims it rather than powering off the
+    // whole strip. Devices whose segments have no 0 brightness
+    // will dim to their minimum.
+    let brightness = if command.state == "OFF" {
+        Some(0)
+    } else {
+        command.brightness
+    };
+    let rgb = command
+        .color
+        .as_ref()
+        .map(|color| ((color.r as u32) << 16) | ((color.g as u32) << 8) | (color.b as u32));
+
+    state
+        .device_set_segment(&device, segment, rgb, brightness)
+        .await
+        .context("mqtt_light_segment_command: state.device_set_segment")?;
 
-        log::info!("Using Platform API to control {device} segment");
+    Ok(())
+}
 
-        if let Some(brightness) = command.brightness {
-            client
-                .set_segment_brightness(&info, segment, brightness)
-                .await?;
-        } else if command.state == "OFF" {
-            // Do nothing here. We used to set brightness to zero,
-            // but it is problematic:
-            // * Some devices don't have a 0
-            // * Setting it to 0 will power up the rest of the device,
-            //   so if HASS is turning off all lights in an area, the
-            //   effect is that they will turn off and then immediate
-            //   on again when there are segments involved
-            // client.set_segment_brightness(&info, segment, 0).await?;
+async fn mqtt_reload_credentials(State(state): State<StateHandle>) -> anyhow::Result<()> {
+    log::info!("mqtt_reload_credentials");
+    let result = reload_config_with_result(&state).await;
+    state
+        .get_hass_client()
+        .await
+        .expect("have hass client")
+        .publish_transient(
+            reload_credentials_status_topic(),
+            serde_json::to_string(&result)?,
+        )
+        .await
+}
+
+async fn mqtt_reload(State(state): State<StateHandle>) -> anyhow::Result<()> {
+    log::info!("mqtt_reload");
+    reload_and_publish_result(&state).await
+}
+
+async fn mqtt_admin_log(
+    Payload(payload): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    match DeviceLogRequest::parse(&payload)? {
+        DeviceLogRequest::Clear => {
+            log::info!("Restoring the default log level of all devices");
+            clear_device_log_levels();
         }
-        if let Some(color) = &command.color {
-            client
-                .set_segment_rgb(&info, segment, color.r, color.g, color.b)
-                .await?;
+        DeviceLogRequest::Set { device, level } => {
+            let device = state.resolve_device_read_only(&device).await?;
+            log::info!("Logging {device} at level {level}");
+            set_device_log_level(&device.id, level);
         }
-    } else {
-        anyhow::bail!("set segments for {device}: Platform API is not available");
     }
-
     Ok(())
 }
 
@@ -407,12 +1554,11 @@ async fn mqtt_purge_caches(State(state): State<StateHandle>) -> anyhow::Result<(
         .context("register_with_hass")
 }
 
-async fn mqtt_oneclick(
-    Payload(name): Payload<String>,
-    State(state): State<StateHandle>,
+async fn activate_one_click(
+    state: &StateHandle,
+    matches: impl Fn(&ParsedOneClick) -> bool,
+    label: &str,
 ) -> anyhow::Result<()> {
-    log::info!("mqtt_oneclick: {name}");
-
     let undoc = state
         .get_undoc_client()
         .await
@@ -420,21 +1566,43 @@ async fn mqtt_oneclick(
     let items = undoc.parse_one_clicks().await?;
     let item = items
         .iter()
-        .find(|item| item.name == name)
-        .ok_or_else(|| anyhow::anyhow!("didn't find item {name}"))?;
+        .find(|item| matches(item))
+        .ok_or_else(|| anyhow::anyhow!("didn't find one-click {label}"))?;
 
     let iot = state
         .get_iot_client()
         .await
         .ok_or_else(|| anyhow::anyhow!("AWS IoT client is not available"))?;
 
-    iot.activate_one_click(&item).await
+    if let Err(err) = iot.activate_one_click(item).await {
+        log::error!("Failed to activate one-click {}: {err:#}", item.name);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Activates a one-click by name; this is the topic used by the
+/// scene entities from earlier versions
+async fn mqtt_oneclick(
+    Payload(name): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_oneclick: {name}");
+    activate_one_click(&state, |item| item.name == name, &name).await
+}
+
+async fn mqtt_oneclick_button(
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_oneclick_button: {id}");
+    activate_one_click(&state, |item| topic_safe_string(&item.id) == id, &id).await
 }
 
 #[derive(Deserialize)]
-struct IdAndInst {
-    id: String,
-    instance: String,
+pub struct IdAndInst {
+    pub id: String,
+    pub instance: String,
 }
 
 async fn mqtt_switch_command(
@@ -443,7 +1611,9 @@ async fn mqtt_switch_command(
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
     log::info!("{instance} for {id}: {command}");
-    let device = state.resolve_device_for_control(&id).await?;
+    let Some(device) = state.resolve_device_for_command(&id, &instance).await? else {
+        return Ok(());
+    };
 
     let on = match command.as_str() {
         "ON" | "on" => true,
@@ -456,6 +1626,20 @@ async fn mqtt_switch_command(
     } else if let Some(client) = state.get_platform_client().await {
         if let Some(http_dev) = &device.http_device_info {
             client.set_toggle_state(http_dev, &instance, on).await?;
+            // Reflect the change until the next poll reports it
+            if let Some(cap) = http_dev.capability_by_instance(&instance) {
+                if let Some(value) = cap.enum_parameter_by_name(if on { "on" } else { "off" }) {
+                    state
+                        .device_mut(&device.sku, &device.id)
+                        .await
+                        .apply_capability_state(
+                            cap.kind.clone(),
+                            &instance,
+                            serde_json::json!({"value": value}),
+                        );
+                    state.notify_of_state_change(&device.id).await?;
+                }
+            }
         } else {
             anyhow::bail!("No platform state available to set {id} {instance} to {on}");
         }
@@ -467,19 +1651,11 @@ async fn mqtt_switch_command(
 }
 
 pub fn mired_to_kelvin(mired: u32) -> u32 {
-    if mired == 0 {
-        0
-    } else {
-        1000000 / mired
-    }
+    1000000u32.checked_div(mired).unwrap_or(0)
 }
 
 pub fn kelvin_to_mired(kelvin: u32) -> u32 {
-    if kelvin == 0 {
-        0
-    } else {
-        1000000 / kelvin
-    }
+    1000000u32.checked_div(kelvin).unwrap_or(0)
 }
 
 /// HASS is advising us that its status has changed
@@ -504,6 +1680,7 @@ async fn run_mqtt_loop(
     state: StateHandle,
     subscriber: Receiver<Event>,
     client: Client,
+    protocol: MqttProtocol,
 ) -> anyhow::Result<()> {
     // Give LAN disco a chance to get current state before
     // we register with hass
@@ -521,52 +1698,264 @@ async fn run_mqtt_loop(
             .await?;
 
         router
-            .route("gv2mqtt/light/:id/command", mqtt_light_command)
+            .route(
+                format!("{prefix}/light/:id/command", prefix = topic_prefix()),
+                mqtt_light_command,
+            )
             .await?;
         router
             .route(
-                "gv2mqtt/light/:id/command/:segment",
+                format!(
+                    "{prefix}/light/:id/command/:segment",
+                    prefix = topic_prefix()
+                ),
                 mqtt_light_segment_command,
             )
             .await?;
         router
-            .route("gv2mqtt/switch/:id/command/:instance", mqtt_switch_command)
+            .route(
+                format!("{prefix}/room/:room/command", prefix = topic_prefix()),
+                mqtt_room_light_command,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/light/:id/nightlight/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_light_nightlight_command,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/switch/:id/command/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_switch_command,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/valve/:id/command/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_valve_command,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/siren/:id/command/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_siren_command,
+            )
+            .await?;
+        router
+            .route(
+                format!("{prefix}/cover/:id/command", prefix = topic_prefix()),
+                mqtt_cover_command,
+            )
+            .await?;
+        router
+            .route(
+                format!("{prefix}/cover/:id/set-position", prefix = topic_prefix()),
+                mqtt_cover_set_position,
+            )
             .await?;
 
         router.route(oneclick_topic(), mqtt_oneclick).await?;
+        router
+            .route(format!("{}/:id", oneclick_topic()), mqtt_oneclick_button)
+            .await?;
         router.route(purge_cache_topic(), mqtt_purge_caches).await?;
+        router
+            .route(purge_discovery_topic(), mqtt_purge_discovery)
+            .await?;
+        router
+            .route(reload_credentials_topic(), mqtt_reload_credentials)
+            .await?;
+        router.route(reload_topic(), mqtt_reload).await?;
+        router.route(admin_log_topic(), mqtt_admin_log).await?;
         router
             .route(
-                "gv2mqtt/:id/request-platform-data",
+                format!(
+                    "{prefix}/:id/request-platform-data",
+                    prefix = topic_prefix()
+                ),
                 mqtt_request_platform_data,
             )
             .await?;
         router
             .route(
-                "gv2mqtt/number/:id/command/:mode_name/:work_mode",
+                format!(
+                    "{prefix}/number/:id/command/:mode_name/:work_mode",
+                    prefix = topic_prefix()
+                ),
                 mqtt_number_command,
             )
             .await?;
         router
-            .route("gv2mqtt/humidifier/:id/set-mode", mqtt_device_set_work_mode)
+            .route(
+                format!(
+                    "{prefix}/number/:id/set-target-humidity/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_target_humidity,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/number/:id/set-display-brightness/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_display_brightness,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/number/:id/set-mist-level/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_mist_level,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/number/:id/set-probe-target/:instance/:units",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_probe_target,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/number/:id/set-timer/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_countdown_timer,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/select/:id/set-timer/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_countdown_timer_option,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/select/:id/set-mist-level/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_mist_level_preset,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/select/:id/set-mist-preset",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_mist_preset,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/select/:id/set-cycle-mode/:instance",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_light_cycle_mode,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/select/:id/set-music-mode",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_music_mode,
+            )
+            .await?;
+        router
+            .route(
+                format!(
+                    "{prefix}/number/:id/set-music-sensitivity",
+                    prefix = topic_prefix()
+                ),
+                mqtt_set_music_sensitivity,
+            )
+            .await?;
+        router
+            .route(
+                format!("{prefix}/humidifier/:id/set-mode", prefix = topic_prefix()),
+                mqtt_device_set_work_mode,
+            )
             .await?;
         router
-            .route("gv2mqtt/:id/set-work-mode", mqtt_device_set_work_mode)
+            .route(
+                format!("{prefix}/:id/set-work-mode", prefix = topic_prefix()),
+                mqtt_device_set_work_mode,
+            )
             .await?;
         router
             .route(
-                "gv2mqtt/humidifier/:id/set-target",
+                format!(
+                    "{prefix}/humidifier/:id/set-target",
+                    prefix = topic_prefix()
+                ),
                 mqtt_humidifier_set_target,
             )
             .await?;
         router
             .route(
-                "gv2mqtt/:id/set-temperature/:instance/:units",
+                format!(
+                    "{prefix}/:id/set-temperature/:instance/:units",
+                    prefix = topic_prefix()
+                ),
                 mqtt_set_temperature,
             )
             .await?;
         router
-            .route("gv2mqtt/:id/set-mode-scene", mqtt_set_mode_scene)
+            .route(
+                format!("{prefix}/:id/set-mode-scene", prefix = topic_prefix()),
+                mqtt_set_mode_scene,
+            )
+            .await?;
+        router
+            .route(
+                format!("{prefix}/climate/:id/set-mode", prefix = topic_prefix()),
+                mqtt_climate_set_mode,
+            )
+            .await?;
+        router
+            .route(
+                format!("{prefix}/fan/:id/set-percentage", prefix = topic_prefix()),
+                mqtt_fan_set_percentage,
+            )
+            .await?;
+        router
+            .route(
+                format!("{prefix}/fan/:id/set-preset-mode", prefix = topic_prefix()),
+                mqtt_fan_set_preset_mode,
+            )
+            .await?;
+        router
+            .route(
+                format!("{prefix}/fan/:id/set-gear", prefix = topic_prefix()),
+                mqtt_fan_set_gear,
+            )
             .await?;
 
         tokio::time::sleep(HASS_REGISTER_DELAY).await;
@@ -574,7 +1963,7 @@ async fn run_mqtt_loop(
             .get_hass_client()
             .await
             .expect("have hass client")
-            .register_with_hass(&state)
+            .register_with_hass(state)
             .await
             .context("register_with_hass")?;
 
@@ -587,6 +1976,17 @@ async fn run_mqtt_loop(
     while let Ok(event) = subscriber.recv().await {
         match event {
             Event::Message(msg) => {
+                // While a discovery purge is scanning, the retained
+                // configs that it subscribed to are collected rather
+                // than dispatched
+                if msg.retain
+                    && is_discovery_config_topic(&state.get_hass_disco_prefix().await, &msg.topic)
+                    && state
+                        .record_discovery_scan_result(&msg.topic, &msg.payload)
+                        .await
+                {
+                    continue;
+                }
                 let router = router.clone();
                 let state = state.clone();
                 tokio::spawn(async move {
@@ -596,11 +1996,23 @@ async fn run_mqtt_loop(
                 });
             }
             Event::Disconnected(reason) => {
-                log::warn!("MQTT disconnected with reason={reason}");
+                log::warn!(
+                    "MQTT disconnected with reason={}",
+                    describe_disconnect_reason(protocol, reason)
+                );
+                // A v3 session keeps our subscriptions, but a v5 session
+                // ends with the connection, see ConnectOptions, and the
+                // broker may have discarded a v3 session too, so we
+                // always subscribe again
                 need_rebuild = true;
+                state.set_mqtt_connected(false).await;
             }
             Event::Connected(status) => {
-                log::info!("MQTT connected with status={status}");
+                state.set_mqtt_connected(status.0 == 0).await;
+                log::info!(
+                    "MQTT connected with status={}",
+                    describe_connack(protocol, status)
+                );
                 if need_rebuild {
                     router = rebuild_router(&client, &state).await?;
                 }
@@ -613,23 +2025,90 @@ async fn run_mqtt_loop(
     Ok(())
 }
 
-pub async fn spawn_hass_integration(
-    state: StateHandle,
-    args: &HassArguments,
-) -> anyhow::Result<()> {
-    let client = Client::with_id(
-        &format!("govee2mqtt/{}", uuid::Uuid::new_v4().simple()),
-        true,
-    )?;
+/// How we intend to use a connection to the broker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConnectionPurpose {
+    Serve,
+    /// Only checking that we can connect. This must not disturb a
+    /// running instance, so it uses a distinct client id, which
+    /// prevents the broker from disconnecting the instance, and has
+    /// no last will, which would otherwise mark the instance offline.
+    Check,
+}
 
-    state.set_temperature_scale(args.temperature_scale()?).await;
+/// The options used to create the client for a connection
+#[derive(Debug, PartialEq)]
+struct ConnectOptions {
+    client_id: String,
+    /// Whether the broker discards the session, including our
+    /// subscriptions, when we disconnect
+    clean_session: bool,
+    protocol_version: Option<ProtocolVersion>,
+}
+
+impl ConnectOptions {
+    /// `protocol` must be either V3 or V5.
+    ///
+    /// The service uses a stable client id and a persistent session, so
+    /// that the broker keeps the command subscriptions while we are
+    /// briefly disconnected. With v5, the session would also need a
+    /// Session Expiry Interval, but mosquitto-rs has no way to pass
+    /// CONNECT properties, so the interval is zero and the broker ends
+    /// the session with the connection. A check uses a clean session,
+    /// so that it doesn't leave one behind on the broker.
+    fn new(
+        args: &HassArguments,
+        protocol: MqttProtocol,
+        purpose: ConnectionPurpose,
+    ) -> anyhow::Result<Self> {
+        let (client_id, clean_session) = match purpose {
+            ConnectionPurpose::Serve => (args.mqtt_client_id()?, false),
+            ConnectionPurpose::Check => (format!("{}-check", args.mqtt_client_id()?), true),
+        };
+        Ok(Self {
+            client_id,
+            clean_session,
+            protocol_version: match protocol {
+                MqttProtocol::V5 => Some(ProtocolVersion::V5),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// Creates a client and connects it to the broker using the
+/// specified protocol, which must be either V3 or V5
+async fn connect_to_broker(
+    args: &HassArguments,
+    protocol: MqttProtocol,
+    timeout: Option<Duration>,
+    purpose: ConnectionPurpose,
+) -> anyhow::Result<Result<Client, MqttError>> {
+    let options = ConnectOptions::new(args, protocol, purpose)?;
+    let client = Client::with_id(&options.client_id, options.clean_session)?;
 
     let mqtt_host = args.mqtt_host()?;
     let mqtt_username = args.mqtt_username()?;
     let mqtt_password = args.mqtt_password()?;
     let mqtt_port = args.mqtt_port()?;
 
-    client.set_last_will(availability_topic(), "offline", QoS::AtMostOnce, false)?;
+    if let Some(version) = options.protocol_version {
+        client.set_option(&ClientOption::ProtocolVersion(version))?;
+    }
+
+    if let Some(tls) = args.mqtt_tls_config()? {
+        log::info!("Using TLS to connect to mqtt broker {mqtt_host}:{mqtt_port}");
+        tls.configure(&client)?;
+    }
+
+    if purpose == ConnectionPurpose::Serve {
+        client.set_last_will(
+            availability_topic(),
+            &instance_naming().lwt_payload,
+            QoS::AtMostOnce,
+            false,
+        )?;
+    }
 
     if mqtt_username.is_some() != mqtt_password.is_some() {
         log::error!(
@@ -637,28 +2116,152 @@ pub async fn spawn_hass_integration(
         );
     }
     client.set_username_and_password(mqtt_username.as_deref(), mqtt_password.as_deref())?;
-    client
-        .connect(
-            &mqtt_host,
-            mqtt_port.into(),
-            Duration::from_secs(120),
-            args.mqtt_bind_address.as_deref(),
-        )
-        .await
-        .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"))?;
+
+    log::info!("Connecting to mqtt broker {mqtt_host}:{mqtt_port} using {protocol}");
+    let connect = client.connect(
+        &mqtt_host,
+        mqtt_port.into(),
+        Duration::from_secs(120),
+        args.mqtt_bind_address.as_deref(),
+    );
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connect)
+            .await
+            .with_context(|| {
+                format!("timeout connecting to mqtt broker {mqtt_host}:{mqtt_port}")
+            })?,
+        None => connect.await,
+    };
+
+    Ok(result.map(|_| client))
+}
+
+/// Connects to the broker, negotiating the protocol version if
+/// required. Returns the client and the protocol that was used.
+async fn connect_with_protocol(
+    args: &HassArguments,
+    purpose: ConnectionPurpose,
+) -> anyhow::Result<(Client, MqttProtocol)> {
+    let protocol = args.mqtt_protocol()?;
+    let describe = |protocol, err: MqttError| match err {
+        MqttError::RejectedConnection(status) => anyhow::anyhow!(
+            "mqtt broker rejected the connection: {}",
+            describe_connack(protocol, status)
+        ),
+        err => anyhow::anyhow!("connecting to mqtt broker: {err:#}"),
+    };
+
+    if protocol != MqttProtocol::Auto {
+        let client = connect_to_broker(args, protocol, None, purpose)
+            .await?
+            .map_err(|err| describe(protocol, err))?;
+        return Ok((client, protocol));
+    }
+
+    match connect_to_broker(args, MqttProtocol::V5, Some(MQTT_V5_PROBE_TIMEOUT), purpose).await {
+        Ok(Ok(client)) => return Ok((client, MqttProtocol::V5)),
+        Ok(Err(MqttError::RejectedConnection(status))) if is_unsupported_protocol(status) => {
+            log::info!("mqtt broker does not support MQTT v5; falling back to MQTT v3");
+        }
+        // Brokers that don't understand v5 may also close the connection
+        // or reject it with an unrelated code, so any other failure
+        // is retried with v3 too. If the failure is unrelated to the
+        // protocol version, the v3 attempt fails in the same way.
+        Ok(Err(err)) => {
+            log::warn!(
+                "{:#}; retrying with MQTT v3",
+                describe(MqttProtocol::V5, err)
+            );
+        }
+        Err(err) => {
+            log::warn!("{err:#}; retrying with MQTT v3");
+        }
+    }
+
+    let client = connect_to_broker(args, MqttProtocol::V3, None, purpose)
+        .await?
+        .map_err(|err| describe(MqttProtocol::V3, err))?;
+    Ok((client, MqttProtocol::V3))
+}
+
+/// Publishes the quota sensors whenever an API response tells us
+/// that the remaining quota has changed
+async fn notify_platform_quota(state: StateHandle, platform: GoveeApiClient) {
+    let mut quota = platform.subscribe_quota();
+    while quota.changed().await.is_ok() {
+        let Some(client) = state.get_hass_client().await else {
+            continue;
+        };
+        let mut entities = EntityList::new();
+        enumerate_platform_quota_sensors(&state, &mut entities).await;
+        if let Err(err) = entities.notify_state(&client).await {
+            log::error!("while reporting platform API quota: {err:#}");
+        }
+    }
+}
+
+/// Validates the hass configuration and verifies that we can connect
+/// to the broker, without publishing anything.
+/// Returns the protocol that was negotiated.
+pub async fn check_hass_integration(args: &HassArguments) -> anyhow::Result<MqttProtocol> {
+    args.instance_naming()?;
+    args.temperature_scale()?;
+    args.state_debounce()?;
+    args.effect_list_limit()?;
+    args.leak_clear_timeout()?;
+    args.light_transition_rate()?;
+    args.coarse_cloud_transitions()?;
+    args.segment_lights()?;
+    args.publish_options()?;
+
+    let (client, protocol) = connect_with_protocol(args, ConnectionPurpose::Check).await?;
+    drop(client);
+    Ok(protocol)
+}
+
+pub async fn spawn_hass_integration(
+    state: StateHandle,
+    args: &HassArguments,
+) -> anyhow::Result<()> {
+    set_instance_naming(args.instance_naming()?)?;
+    state.set_temperature_scale(args.temperature_scale()?).await;
+    state.set_state_debounce(args.state_debounce()?).await;
+    state.set_effect_list_limit(args.effect_list_limit()?).await;
+    state
+        .set_leak_clear_timeout(args.leak_clear_timeout()?)
+        .await;
+    state
+        .set_transition_rate(args.light_transition_rate()?)
+        .await;
+    state
+        .set_coarse_cloud_transitions(args.coarse_cloud_transitions()?)
+        .await;
+    state
+        .set_segment_light_devices(args.segment_lights()?)
+        .await;
+
+    let (client, protocol) = connect_with_protocol(args, ConnectionPurpose::Serve).await?;
     let subscriber = client.subscriber().expect("to own the subscriber");
 
     state
         .set_hass_client(HassClient {
             client: client.clone(),
+            options: args.publish_options()?,
+            discovery: None,
         })
         .await;
+    // connect_with_protocol only returns once the broker has accepted us
+    state.set_mqtt_connected(true).await;
 
     let disco_prefix = args.hass_discovery_prefix.clone();
     state.set_hass_disco_prefix(disco_prefix).await;
 
+    if let Some(platform) = state.get_platform_client().await {
+        tokio::spawn(notify_platform_quota(state.clone(), platform));
+    }
+
     tokio::spawn(async move {
-        let res = run_mqtt_loop(state, subscriber, client).await;
+        let res = run_mqtt_loop(state, subscriber, client, protocol).await;
         if let Err(err) = res {
             log::error!("run_mqtt_loop: {err:#}");
             log::error!("FATAL: hass integration will not function.");
@@ -694,3 +2297,118 @@ fn test_camel_case_to_space_separated() {
         "Oscillation Toggle"
     );
 }
+
+#[cfg(test)]
+#[test]
+fn test_parse_mqtt_broker() {
+    let broker = |host: &str, port, tls| MqttBroker {
+        host: host.to_string(),
+        port,
+        tls,
+    };
+    k9::assert_equal!(
+        MqttBroker::parse("mqtt").unwrap(),
+        broker("mqtt", None, false)
+    );
+    k9::assert_equal!(
+        MqttBroker::parse("mqtts://broker.local").unwrap(),
+        broker("broker.local", None, true)
+    );
+    k9::assert_equal!(
+        MqttBroker::parse("mqtt://10.0.0.2:1884/").unwrap(),
+        broker("10.0.0.2", Some(1884), false)
+    );
+    k9::assert_equal!(
+        MqttBroker::parse("mqtts://[::1]:8884").unwrap(),
+        broker("::1", Some(8884), true)
+    );
+    k9::assert_equal!(
+        MqttBroker::parse("mqtts://[::1]").unwrap(),
+        broker("::1", None, true)
+    );
+    assert!(MqttBroker::parse("http://broker").is_err());
+    assert!(MqttBroker::parse("mqtt://broker:port").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_mqtt_protocol() {
+    k9::assert_equal!("3.1.1".parse::<MqttProtocol>().unwrap(), MqttProtocol::V3);
+    k9::assert_equal!("v5".parse::<MqttProtocol>().unwrap(), MqttProtocol::V5);
+    k9::assert_equal!("AUTO".parse::<MqttProtocol>().unwrap(), MqttProtocol::Auto);
+    assert!("4".parse::<MqttProtocol>().is_err());
+
+    assert!(is_unsupported_protocol(ConnectionStatus(1)));
+    assert!(is_unsupported_protocol(ConnectionStatus(0x84)));
+    assert!(!is_unsupported_protocol(ConnectionStatus(0x87)));
+
+    k9::assert_equal!(
+        describe_disconnect_reason(MqttProtocol::V3, ReasonCode(7)),
+        "code 7: connection lost"
+    );
+    k9::assert_equal!(
+        describe_disconnect_reason(MqttProtocol::V5, ReasonCode(19)),
+        "code 19: keepalive timeout"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_connect_options() {
+    use clap::Parser;
+
+    let args = HassArguments::parse_from(["govee", "--instance-name", "upstairs"]);
+    k9::assert_equal!(
+        ConnectOptions::new(&args, MqttProtocol::V3, ConnectionPurpose::Serve).unwrap(),
+        ConnectOptions {
+            client_id: "govee2mqtt/upstairs".to_string(),
+            clean_session: false,
+            protocol_version: None,
+        }
+    );
+    k9::assert_equal!(
+        ConnectOptions::new(&args, MqttProtocol::V5, ConnectionPurpose::Serve).unwrap(),
+        ConnectOptions {
+            client_id: "govee2mqtt/upstairs".to_string(),
+            clean_session: false,
+            protocol_version: Some(ProtocolVersion::V5),
+        }
+    );
+    k9::assert_equal!(
+        ConnectOptions::new(&args, MqttProtocol::V3, ConnectionPurpose::Check).unwrap(),
+        ConnectOptions {
+            client_id: "govee2mqtt/upstairs-check".to_string(),
+            clean_session: true,
+            protocol_version: None,
+        }
+    );
+
+    let args = HassArguments::parse_from(["govee", "--mqtt-client-id", "bridge"]);
+    k9::assert_equal!(
+        ConnectOptions::new(&args, MqttProtocol::V3, ConnectionPurpose::Serve)
+            .unwrap()
+            .client_id,
+        "bridge"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_scope_unique_id() {
+    k9::assert_equal!(
+        scope_unique_id("gv2mqtt", "gv2mqtt-H6000_422A-fan"),
+        "gv2mqtt-H6000_422A-fan"
+    );
+    k9::assert_equal!(
+        scope_unique_id("gv2mqtt", "sensor-H6000_422A-battery"),
+        "sensor-H6000_422A-battery"
+    );
+    k9::assert_equal!(
+        scope_unique_id("testing", "gv2mqtt-H6000_422A-fan"),
+        "testing-H6000_422A-fan"
+    );
+    k9::assert_equal!(
+        scope_unique_id("testing", "sensor-H6000_422A-battery"),
+        "testing-sensor-H6000_422A-battery"
+    );
+}
@@ -0,0 +1,223 @@
+use crate::service::iot::IotConnectionHealth;
+use crate::service::state::StateHandle;
+use crate::undoc_api::{account_session_stats, AccountSessionStats};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// Everything that is configured is working
+    Ok,
+    /// An optional subsystem is not working, but devices can
+    /// still be controlled via hass
+    Degraded,
+    /// We are not connected to the MQTT broker, so hass cannot
+    /// reach us at all
+    Unhealthy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MqttHealth {
+    pub connected: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlatformApiHealth {
+    pub last_success: Option<DateTime<Utc>>,
+    pub rate_limited_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LanHealth {
+    pub last_discovery_response: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DeviceHealth {
+    pub total: usize,
+    /// Devices that we haven't heard from recently enough to
+    /// consider them to be available
+    pub stale: usize,
+}
+
+/// A summary of the state of each subsystem, as reported by `/healthz`.
+/// Subsystems that are not configured are omitted.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    pub mode: String,
+    pub mqtt: MqttHealth,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iot: Option<IotConnectionHealth>,
+    /// How the Govee account session has been established, so that
+    /// it is apparent whether it is being refreshed rather than
+    /// logging in again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_session: Option<AccountSessionStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform_api: Option<PlatformApiHealth>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lan: Option<LanHealth>,
+    pub devices: DeviceHealth,
+    /// The log levels that were set for individual devices via
+    /// the admin log topic
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub device_log_levels: BTreeMap<String, String>,
+}
+
+impl HealthReport {
+    pub async fn gather(state: &StateHandle) -> Self {
+        let now = Utc::now();
+        let devices = state.devices().await;
+
+        let iot = if state.iot_started().await {
+            Some(state.get_iot_health().await)
+        } else {
+            None
+        };
+
+        let mut report = Self {
+            status: HealthStatus::Ok,
+            warnings: vec![],
+            mode: state.operating_mode().await.to_string(),
+            mqtt: MqttHealth {
+                connected: state.is_mqtt_connected().await,
+            },
+            iot,
+            account_session: state
+                .get_undoc_client()
+                .await
+                .map(|_| account_session_stats()),
+            platform_api: state
+                .get_platform_client()
+                .await
+                .map(|client| PlatformApiHealth {
+                    last_success: client.last_success(),
+                    rate_limited_until: client.rate_limited_until(),
+                }),
+            lan: state.get_lan_client().await.map(|client| LanHealth {
+                last_discovery_response: client.last_discovery_response(),
+            }),
+            devices: DeviceHealth {
+                total: devices.len(),
+                stale: devices
+                    .iter()
+                    .filter(|device| !device.is_available(now))
+                    .count(),
+            },
+            device_log_levels: crate::logging::device_log_levels(),
+        };
+        report.evaluate();
+        report
+    }
+
+    /// Computes the overall status and warnings from the state
+    /// of the individual subsystems
+    fn evaluate(&mut self) {
+        let mut warnings = vec![];
+
+        if let Some(iot) = &self.iot {
+            if !iot.connected {
+                warnings.push("not connected to AWS IoT".to_string());
+            }
+        }
+        if let Some(platform) = &self.platform_api {
+            if let Some(until) = platform.rate_limited_until {
+                warnings.push(format!("Platform API requests are suspended until {until}"));
+            } else if platform.last_success.is_none() {
+                warnings.push("no Platform API request has succeeded".to_string());
+            }
+        }
+
+        self.status = if !self.mqtt.connected {
+            warnings.insert(0, "not connected to the MQTT broker".to_string());
+            HealthStatus::Unhealthy
+        } else if warnings.is_empty() {
+            HealthStatus::Ok
+        } else {
+            HealthStatus::Degraded
+        };
+        self.warnings = warnings;
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.status != HealthStatus::Unhealthy
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn report() -> HealthReport {
+        HealthReport {
+            status: HealthStatus::Ok,
+            warnings: vec![],
+            mode: "Cloud and LAN".to_string(),
+            mqtt: MqttHealth { connected: true },
+            iot: Some(IotConnectionHealth {
+                connected: true,
+                ..Default::default()
+            }),
+            account_session: Some(AccountSessionStats {
+                logins: 1,
+                refreshes: 3,
+            }),
+            platform_api: Some(PlatformApiHealth {
+                last_success: Some(Utc::now()),
+                rate_limited_until: None,
+            }),
+            lan: None,
+            devices: DeviceHealth { total: 2, stale: 1 },
+            device_log_levels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn status() {
+        let mut healthy = report();
+        healthy.evaluate();
+        k9::assert_equal!(healthy.status, HealthStatus::Ok);
+        k9::assert_equal!(healthy.warnings, Vec::<String>::new());
+
+        let mut degraded = report();
+        degraded.iot.as_mut().unwrap().connected = false;
+        degraded.evaluate();
+        k9::assert_equal!(degraded.status, HealthStatus::Degraded);
+        k9::assert_equal!(degraded.warnings, vec!["not connected to AWS IoT"]);
+        assert!(degraded.is_healthy());
+
+        let mut unhealthy = report();
+        unhealthy.mqtt.connected = false;
+        unhealthy.platform_api.as_mut().unwrap().last_success = None;
+        unhealthy.evaluate();
+        k9::assert_equal!(unhealthy.status, HealthStatus::Unhealthy);
+        k9::assert_equal!(
+            unhealthy.warnings,
+            vec![
+                "not connected to the MQTT broker",
+                "no Platform API request has succeeded"
+            ]
+        );
+        assert!(!unhealthy.is_healthy());
+
+        // A subsystem that isn't configured can't degrade us
+        let mut lan_only = report();
+        lan_only.iot = None;
+        lan_only.platform_api = None;
+        lan_only.evaluate();
+        k9::assert_equal!(lan_only.status, HealthStatus::Ok);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut report = report();
+        report.evaluate();
+        let json = serde_json::to_string(&report).unwrap();
+        k9::assert_equal!(serde_json::from_str::<HealthReport>(&json).unwrap(), report);
+    }
+}
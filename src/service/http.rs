@@ -1,14 +1,23 @@
+use crate::metrics::{self, Gauge, Labels};
+use crate::platform_api::DeviceCapabilityState;
 use crate::service::coordinator::Coordinator;
 use crate::service::device::{Device, DeviceState};
+use crate::service::health::HealthReport;
 use crate::service::state::StateHandle;
 use anyhow::Context;
-use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
 use axum::http::StatusCode;
+use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Response};
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::{Json, Router};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
 use std::net::IpAddr;
+use std::sync::Arc;
 use tower_http::services::ServeDir;
 
 fn response_with_code<T: ToString + std::fmt::Display>(code: StatusCode, err: T) -> Response {
@@ -42,43 +51,93 @@ async fn resolve_device_for_control(
     id: &str,
 ) -> Result<Coordinator, Response> {
     state
-        .resolve_device_for_control(&id)
+        .resolve_device_for_control(id)
         .await
         .map_err(not_found)
 }
 
 async fn resolve_device_read_only(state: &StateHandle, id: &str) -> Result<Device, Response> {
-    state.resolve_device_read_only(&id).await.map_err(not_found)
+    state.resolve_device_read_only(id).await.map_err(not_found)
 }
 
-/// Returns a json array of device information
-async fn list_devices(State(state): State<StateHandle>) -> Result<Response, Response> {
-    let mut devices = state.devices().await;
-    devices.sort_by_key(|d| (d.room_name().map(|name| name.to_string()), d.name()));
-
-    #[derive(Serialize)]
-    struct DeviceItem {
-        pub sku: String,
-        pub id: String,
-        pub name: String,
-        pub room: Option<String>,
-        pub ip: Option<IpAddr>,
-        pub state: Option<DeviceState>,
-    }
+#[derive(Serialize)]
+struct DeviceItem {
+    pub sku: String,
+    pub id: String,
+    pub name: String,
+    pub room: Option<String>,
+    pub ip: Option<IpAddr>,
+    /// The ways in which the device can currently be controlled
+    pub control: Vec<&'static str>,
+    /// The way in which power, brightness and color commands will
+    /// be sent, taking the device's configuration into account
+    pub control_path: Option<&'static str>,
+    pub state: Option<DeviceState>,
+    /// Whether some of the state was restored from the cache at
+    /// startup, and hasn't been reported by the device since
+    pub state_restored: bool,
+    /// The most recent capability state, as reported by the platform
+    /// API or pushed to us
+    pub capabilities: Vec<DeviceCapabilityState>,
+}
 
-    let devices: Vec<_> = devices
-        .into_iter()
-        .map(|d| DeviceItem {
+impl DeviceItem {
+    async fn new(state: &StateHandle, d: Device) -> Self {
+        let control_path = state.control_transport(&d).await;
+        let mut control = vec![];
+        if d.responsive_lan_device().is_some() {
+            control.push("lan");
+        }
+        if d.iot_api_supported()
+            && d.undoc_device_info
+                .as_ref()
+                .is_some_and(|info| info.entry.device_ext.device_settings.topic.is_some())
+        {
+            control.push("iot");
+        }
+        if d.http_device_info.is_some() && !d.platform_api_bypassed() {
+            control.push("platform");
+        }
+
+        Self {
             name: d.name(),
             room: d.room_name().map(|r| r.to_string()),
             ip: d.ip_addr(),
+            control,
+            control_path,
             state: d.device_state(),
+            state_restored: d.is_state_restored(),
+            capabilities: d
+                .http_device_state
+                .map(|state| state.capabilities)
+                .unwrap_or_default(),
             sku: d.sku,
             id: d.id,
-        })
-        .collect();
+        }
+    }
+}
+
+/// Returns a json array of device information
+async fn list_devices(State(state): State<StateHandle>) -> Result<Response, Response> {
+    let mut devices = state.devices().await;
+    devices.sort_by_key(|d| (d.room_name().map(|name| name.to_string()), d.name()));
 
-    Ok(Json(devices).into_response())
+    let mut items = vec![];
+    for device in devices {
+        items.push(DeviceItem::new(&state, device).await);
+    }
+
+    Ok(Json(items).into_response())
+}
+
+/// Returns the information for a single device. The id is percent
+/// decoded by the Path extractor, so ids with colons can be encoded.
+async fn device_info(
+    State(state): State<StateHandle>,
+    Path(id): Path<String>,
+) -> Result<Response, Response> {
+    let device = resolve_device_read_only(&state, &id).await?;
+    Ok(Json(DeviceItem::new(&state, device).await).into_response())
 }
 
 /// Turns on a given device
@@ -160,6 +219,209 @@ async fn device_set_color(
     Ok(response_with_code(StatusCode::OK, "ok"))
 }
 
+/// The body of a control request: either a platform API style
+/// capability and value, or a simplified set of changes
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ControlRequest {
+    Capability {
+        /// Either this or instance names the capability instance
+        capability: Option<String>,
+        instance: Option<String>,
+        value: JsonValue,
+    },
+    Simple(SimpleControl),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct SimpleControl {
+    power: Option<bool>,
+    brightness: Option<u8>,
+    color: Option<String>,
+    color_temperature: Option<u32>,
+    scene: Option<String>,
+}
+
+impl SimpleControl {
+    /// Maps the capability instances that have dedicated control
+    /// methods to the equivalent simple control, so that they use
+    /// the same choice of transport as the MQTT commands
+    fn from_capability(instance: &str, value: &JsonValue) -> Result<Option<Self>, String> {
+        let int_value = || {
+            value
+                .as_u64()
+                .or_else(|| value.as_bool().map(|b| b as u64))
+                .ok_or_else(|| format!("invalid value {value} for {instance}"))
+        };
+        Ok(match instance {
+            "powerSwitch" => Some(Self {
+                power: Some(int_value()? != 0),
+                ..Self::default()
+            }),
+            "brightness" => Some(Self {
+                brightness: Some(int_value()?.min(100) as u8),
+                ..Self::default()
+            }),
+            "colorRgb" => {
+                let rgb = int_value()?;
+                Some(Self {
+                    color: Some(format!("#{:06x}", rgb & 0xff_ffff)),
+                    ..Self::default()
+                })
+            }
+            "colorTemperatureK" => Some(Self {
+                color_temperature: Some(int_value()? as u32),
+                ..Self::default()
+            }),
+            _ => None,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.power.is_none()
+            && self.brightness.is_none()
+            && self.color.is_none()
+            && self.color_temperature.is_none()
+            && self.scene.is_none()
+    }
+
+    /// Applies the changes, returning the transport that served them,
+    /// or the one that would have been tried first if they failed
+    async fn apply_reporting_transport(
+        &self,
+        state: &StateHandle,
+        device: &Device,
+    ) -> (Option<&'static str>, anyhow::Result<()>) {
+        let result = self.apply(state, device).await;
+        let transport = match &result {
+            Ok(()) => state.last_control_transport(device).await,
+            Err(_) => None,
+        };
+        match transport {
+            Some(transport) => (Some(transport), result),
+            None => (state.control_transport(device).await, result),
+        }
+    }
+
+    async fn apply(&self, state: &StateHandle, device: &Device) -> anyhow::Result<()> {
+        if let Some(on) = self.power {
+            state.device_power_on(device, on).await?;
+        }
+        if let Some(percent) = self.brightness {
+            state.device_set_brightness(device, percent).await?;
+        }
+        if let Some(color) = &self.color {
+            let [r, g, b, _a] = csscolorparser::parse(color)?.to_rgba8();
+            state.device_set_color_rgb(device, r, g, b).await?;
+        }
+        if let Some(kelvin) = self.color_temperature {
+            state.device_set_color_temperature(device, kelvin).await?;
+        }
+        if let Some(scene) = &self.scene {
+            state.device_set_scene(device, scene).await?;
+        }
+        Ok(())
+    }
+}
+
+fn control_response(code: StatusCode, transport: Option<&str>, msg: String) -> Response {
+    if !code.is_success() {
+        log::error!("err: {msg}");
+    }
+    let mut response = Json(serde_json::json!({
+        "code": code.as_u16(),
+        "accepted": code.is_success(),
+        "transport": transport,
+        "msg": msg,
+    }))
+    .into_response();
+    *response.status_mut() = code;
+    response
+}
+
+/// Sends a command to a given device, reporting which transport
+/// was used to send it
+async fn device_control(
+    State(state): State<StateHandle>,
+    Path(id): Path<String>,
+    body: Result<Json<ControlRequest>, JsonRejection>,
+) -> Result<Response, Response> {
+    let Json(request) = body.map_err(|err| bad_request(err.body_text()))?;
+
+    let simple = match &request {
+        ControlRequest::Simple(simple) => Some(simple),
+        ControlRequest::Capability { .. } => None,
+    };
+    if let Some(simple) = simple {
+        if simple.is_empty() {
+            return Err(bad_request("the request doesn't ask for any changes"));
+        }
+        if let Some(color) = &simple.color {
+            csscolorparser::parse(color)
+                .map_err(|err| bad_request(format!("error parsing color '{color}': {err}")))?;
+        }
+    }
+
+    let device = resolve_device_for_control(&state, &id).await?;
+
+    let (transport, result) = match request {
+        ControlRequest::Simple(simple) => simple.apply_reporting_transport(&state, &device).await,
+        ControlRequest::Capability {
+            capability,
+            instance,
+            value,
+        } => {
+            let instance = instance
+                .or(capability)
+                .ok_or_else(|| bad_request("either capability or instance is required"))?;
+            match SimpleControl::from_capability(&instance, &value).map_err(bad_request)? {
+                Some(simple) => simple.apply_reporting_transport(&state, &device).await,
+                None => {
+                    let cap = device
+                        .get_capability_by_instance(&instance)
+                        .ok_or_else(|| {
+                            bad_request(format!("{device} has no capability {instance}"))
+                        })?
+                        .clone();
+                    (
+                        Some("platform"),
+                        state.device_control(&device, &cap, value).await,
+                    )
+                }
+            }
+        }
+    };
+
+    Ok(match result {
+        Ok(()) => control_response(StatusCode::OK, transport, "ok".to_string()),
+        Err(err) => control_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            transport,
+            format!("{err:#}"),
+        ),
+    })
+}
+
+/// Rejects requests that don't carry the configured bearer token
+async fn require_auth_token(
+    State(token): State<Arc<String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|supplied| supplied.trim() == token.as_str());
+    if authorized {
+        next.run(request).await
+    } else {
+        response_with_code(StatusCode::UNAUTHORIZED, "a valid bearer token is required")
+    }
+}
+
 /// Activates the named scene for a given device
 async fn device_set_scene(
     State(state): State<StateHandle>,
@@ -220,18 +482,113 @@ async fn activate_one_click(
         .ok_or_else(|| anyhow::anyhow!("AWS IoT client is not available"))
         .map_err(generic)?;
 
-    iot.activate_one_click(&item).await.map_err(generic)?;
+    iot.activate_one_click(item).await.map_err(generic)?;
 
     Ok(response_with_code(StatusCode::OK, "ok"))
 }
 
+/// Computes the gauges that describe the current state of the service
+async fn current_gauges(state: &StateHandle) -> Vec<Gauge> {
+    let now = chrono::Utc::now();
+    let mut total: BTreeMap<String, f64> = BTreeMap::new();
+    let mut online: BTreeMap<String, f64> = BTreeMap::new();
+    for device in state.devices().await {
+        *total.entry(device.sku.clone()).or_default() += 1.0;
+        let counted = online.entry(device.sku.clone()).or_default();
+        if device.is_available(now) {
+            *counted += 1.0;
+        }
+    }
+
+    fn by_sku(counts: BTreeMap<String, f64>) -> Vec<(Labels, f64)> {
+        counts
+            .into_iter()
+            .map(|(sku, count)| (vec![("sku", sku)], count))
+            .collect()
+    }
+
+    let mut gauges = vec![
+        Gauge {
+            name: "govee_devices",
+            help: "Known devices, by SKU",
+            samples: by_sku(total),
+        },
+        Gauge {
+            name: "govee_devices_online",
+            help: "Devices that are currently available, by SKU",
+            samples: by_sku(online),
+        },
+        Gauge {
+            name: "govee_command_queue_depth",
+            help: "Device commands waiting to be sent, by SKU",
+            samples: by_sku(
+                state
+                    .command_queue_depths()
+                    .await
+                    .into_iter()
+                    .map(|(sku, depth)| (sku, depth as f64))
+                    .collect(),
+            ),
+        },
+    ];
+
+    if let Some(client) = state.get_platform_client().await {
+        let quota = client.quota();
+        let samples: Vec<(Labels, f64)> = [
+            ("minute", quota.minute_remaining),
+            ("day", quota.day_remaining),
+        ]
+        .into_iter()
+        .filter_map(|(window, remaining)| {
+            remaining.map(|remaining| (vec![("window", window.to_string())], remaining as f64))
+        })
+        .collect();
+        if !samples.is_empty() {
+            gauges.push(Gauge {
+                name: "govee_platform_api_quota_remaining",
+                help: "Platform API requests remaining in the current window",
+                samples,
+            });
+        }
+    }
+
+    gauges
+}
+
+/// Reports the metrics in the Prometheus text exposition format
+async fn prometheus_metrics(State(state): State<StateHandle>) -> Response {
+    let body = metrics::registry().render(&current_gauges(&state).await);
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+/// Summarizes the state of each subsystem, responding with a 503
+/// if we are unable to serve hass
+async fn health(State(state): State<StateHandle>) -> Response {
+    let report = HealthReport::gather(&state).await;
+    let code = if report.is_healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(report)).into_response()
+}
+
 async fn redirect_to_index() -> Response {
     axum::response::Redirect::to("/assets/index.html").into_response()
 }
 
-pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()> {
-    let app = Router::new()
-        .route("/api/devices", get(list_devices))
+pub async fn run_http_server(
+    state: StateHandle,
+    addr: IpAddr,
+    port: u16,
+    auth_token: Option<String>,
+) -> anyhow::Result<()> {
+    let control = Router::new()
+        .route("/api/devices/:id/control", post(device_control))
         .route("/api/device/:id/power/on", get(device_power_on))
         .route("/api/device/:id/power/off", get(device_power_off))
         .route(
@@ -244,16 +601,30 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
         )
         .route("/api/device/:id/color/:color", get(device_set_color))
         .route("/api/device/:id/scene/:scene", get(device_set_scene))
+        .route("/api/oneclick/activate/:scene", get(activate_one_click));
+    let control = match auth_token {
+        Some(token) => control.layer(middleware::from_fn_with_state(
+            Arc::new(token),
+            require_auth_token,
+        )),
+        None => control,
+    };
+
+    let app = Router::new()
+        .route("/api/devices", get(list_devices))
+        .route("/api/devices/:id", get(device_info))
         .route("/api/device/:id/scenes", get(device_list_scenes))
         .route("/api/oneclicks", get(list_one_clicks))
-        .route("/api/oneclick/activate/:scene", get(activate_one_click))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/healthz", get(health))
+        .merge(control)
         .route("/", get(redirect_to_index))
         .nest_service("/assets", ServeDir::new("assets"))
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+    let listener = tokio::net::TcpListener::bind((addr, port))
         .await
-        .with_context(|| format!("run_http_server: binding to port {port}"))?;
+        .with_context(|| format!("run_http_server: binding to {addr} port {port}"))?;
     let addr = listener.local_addr()?;
     log::info!("http server addr is {addr:?}");
     if let Err(err) = axum::serve(listener, app).await {
@@ -262,3 +633,144 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::service::state::State as ServiceState;
+    use std::sync::Arc;
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn device_endpoints() {
+        let state = Arc::new(ServiceState::new());
+        drop(state.device_mut("H6000", "AA:BB:CC:DD:EE:FF:42:2A").await);
+
+        let devices = body_json(list_devices(State(state.clone())).await.unwrap()).await;
+        k9::assert_equal!(devices[0]["id"], "AA:BB:CC:DD:EE:FF:42:2A");
+        k9::assert_equal!(devices[0]["control"], serde_json::json!([]));
+        k9::assert_equal!(devices[0]["control_path"], serde_json::Value::Null);
+
+        let device = body_json(
+            device_info(
+                State(state.clone()),
+                Path("AA:BB:CC:DD:EE:FF:42:2A".to_string()),
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        k9::assert_equal!(device["sku"], "H6000");
+        k9::assert_equal!(device["capabilities"], serde_json::json!([]));
+
+        let missing = device_info(State(state), Path("nope".to_string()))
+            .await
+            .unwrap_err();
+        k9::assert_equal!(missing.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let state = Arc::new(ServiceState::new());
+        drop(state.device_mut("H6000", "AA:BB:CC:DD:EE:FF:42:2A").await);
+        drop(state.device_mut("H6000", "AA:BB:CC:DD:EE:FF:42:2B").await);
+
+        let response = prometheus_metrics(State(state)).await;
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(body.contains("govee_devices{sku=\"H6000\"} 2\n"));
+        // Devices that we have yet to hear from are assumed to be online
+        assert!(body.contains("govee_devices_online{sku=\"H6000\"} 2\n"));
+        // Device ids must never be used as labels
+        assert!(!body.contains("AA:BB"));
+    }
+
+    #[tokio::test]
+    async fn health_endpoint() {
+        let state = Arc::new(ServiceState::new());
+        let response = health(State(state.clone())).await;
+        k9::assert_equal!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let report = body_json(response).await;
+        k9::assert_equal!(report["status"], "unhealthy");
+
+        state.set_mqtt_connected(true).await;
+        let response = health(State(state)).await;
+        k9::assert_equal!(response.status(), StatusCode::OK);
+        let report = body_json(response).await;
+        k9::assert_equal!(report["status"], "ok");
+        k9::assert_equal!(report["devices"]["total"], 0);
+    }
+
+    #[test]
+    fn control_requests() {
+        let request: ControlRequest = serde_json::from_str(
+            r#"{"capability": "powerSwitch", "instance": "powerSwitch", "value": 1}"#,
+        )
+        .unwrap();
+        assert!(matches!(request, ControlRequest::Capability { .. }));
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"brightness": 50, "color": "red"}"#).unwrap();
+        let ControlRequest::Simple(simple) = request else {
+            panic!("expected a simple request");
+        };
+        k9::assert_equal!(simple.brightness, Some(50));
+        k9::assert_equal!(simple.color.as_deref(), Some("red"));
+
+        // A typo shouldn't be silently ignored
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"brightnes": 50}"#).is_err());
+
+        let power = SimpleControl::from_capability("powerSwitch", &serde_json::json!(0))
+            .unwrap()
+            .unwrap();
+        k9::assert_equal!(power.power, Some(false));
+        let color = SimpleControl::from_capability("colorRgb", &serde_json::json!(0xff8000))
+            .unwrap()
+            .unwrap();
+        k9::assert_equal!(color.color.as_deref(), Some("#ff8000"));
+        assert!(
+            SimpleControl::from_capability("nightlightToggle", &serde_json::json!(1))
+                .unwrap()
+                .is_none()
+        );
+        k9::assert_equal!(
+            SimpleControl::from_capability("brightness", &serde_json::json!("high")).unwrap_err(),
+            r#"invalid value "high" for brightness"#
+        );
+    }
+
+    #[tokio::test]
+    async fn control_errors() {
+        let state = Arc::new(ServiceState::new());
+        drop(state.device_mut("H6000", "AA:BB:CC:DD:EE:FF:42:2A").await);
+
+        let empty = device_control(
+            State(state.clone()),
+            Path("AA:BB:CC:DD:EE:FF:42:2A".to_string()),
+            Ok(Json(ControlRequest::Simple(SimpleControl::default()))),
+        )
+        .await
+        .unwrap_err();
+        k9::assert_equal!(empty.status(), StatusCode::BAD_REQUEST);
+
+        let missing = device_control(
+            State(state),
+            Path("nope".to_string()),
+            Ok(Json(ControlRequest::Simple(SimpleControl {
+                power: Some(true),
+                ..SimpleControl::default()
+            }))),
+        )
+        .await
+        .unwrap_err();
+        k9::assert_equal!(missing.status(), StatusCode::NOT_FOUND);
+    }
+}
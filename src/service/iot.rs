@@ -1,15 +1,26 @@
 use crate::ble::{Base64HexBytes, GoveeBlePacket, HumidifierAutoMode, NotifyHumidifierMode};
+use crate::hass_mqtt::binary_sensor::{
+    event_clears_after_timeout, is_leak_instance, safety_event_name, IotConnectionSensor,
+};
+use crate::hass_mqtt::event::is_momentary_press_event;
+use crate::hass_mqtt::instance::EntityInstance;
 use crate::lan_api::{DeviceColor, DeviceStatus};
-use crate::platform_api::from_json;
+use crate::logging::device_log;
+use crate::metrics::{self, Counter};
+use crate::platform_api::{from_json, DeviceCapabilityKind};
 use crate::service::state::StateHandle;
-use crate::undoc_api::{ms_timestamp, DeviceEntry, LoginAccountResponse, ParsedOneClick};
+use crate::undoc_api::{
+    ms_timestamp, DeviceEntry, LoginAccountResponse, ParsedOneClick, UndocApiArguments,
+};
 use crate::Args;
 use anyhow::Context;
 use async_channel::Receiver;
+use chrono::{DateTime, Utc};
 use mosquitto_rs::{Event, QoS};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
 use std::time::Duration;
-use tokio::time::timeout;
+use tokio::time::{sleep, timeout, Instant};
 
 #[derive(Clone)]
 pub struct IotClient {
@@ -17,6 +28,28 @@ pub struct IotClient {
 }
 
 impl IotClient {
+    /// Publishes a command to the device, counting failures in the metrics
+    async fn publish_command(
+        &self,
+        device: &DeviceEntry,
+        device_topic: &str,
+        context: &'static str,
+        command: JsonValue,
+    ) -> anyhow::Result<()> {
+        let result = self
+            .client
+            .publish(
+                device_topic,
+                serde_json::to_string(&command)?,
+                QoS::AtMostOnce,
+                false,
+            )
+            .await
+            .context(context);
+        metrics::track_command("iot", &device.sku, result)?;
+        Ok(())
+    }
+
     pub fn is_device_compatible(&self, device: &DeviceEntry) -> bool {
         device.device_ext.device_settings.topic.is_some()
     }
@@ -44,7 +77,7 @@ impl IotClient {
     }
 
     pub async fn set_power_state(&self, device: &DeviceEntry, on: bool) -> anyhow::Result<()> {
-        log::trace!("set_power_state for {} to {on}", device.device);
+        device_log!(log::Level::Trace, &device.device, "set_power_state to {on}");
         let device_topic = device.device_topic()?;
 
         fn pwr(is_on: bool, on: u8, off: u8) -> u8 {
@@ -60,51 +93,49 @@ impl IotClient {
             _ => pwr(on, 1, 0),
         };
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "turn",
-                        "data": {
-                            "val": power_state,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_power_state")?;
-        Ok(())
+        self.publish_command(
+            device,
+            device_topic,
+            "IotClient::set_power_state",
+            serde_json::json!({
+                "msg": {
+                    "cmd": "turn",
+                    "data": {
+                        "val": power_state,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
     }
 
     pub async fn set_brightness(&self, device: &DeviceEntry, percent: u8) -> anyhow::Result<()> {
-        log::trace!("set_brightness for {} to {percent}", device.device);
+        device_log!(
+            log::Level::Trace,
+            &device.device,
+            "set_brightness to {percent}"
+        );
         let device_topic = device.device_topic()?;
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "brightness",
-                        "data": {
-                            "val": percent,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_brightness")?;
-        Ok(())
+        self.publish_command(
+            device,
+            device_topic,
+            "IotClient::set_brightness",
+            serde_json::json!({
+                "msg": {
+                    "cmd": "brightness",
+                    "data": {
+                        "val": percent,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
     }
 
     pub async fn set_color_temperature(
@@ -112,34 +143,35 @@ impl IotClient {
         device: &DeviceEntry,
         kelvin: u32,
     ) -> anyhow::Result<()> {
-        log::trace!("set_color_temperature for {} to {kelvin}", device.device);
+        device_log!(
+            log::Level::Trace,
+            &device.device,
+            "set_color_temperature to {kelvin}"
+        );
         let device_topic = device.device_topic()?;
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "colorwc",
-                        "data": {
-                            "color": {
-                                "r": 0,
-                                "g": 0,
-                                "b": 0,
-                            },
-                            "colorTemInKelvin": kelvin,
+        self.publish_command(
+            device,
+            device_topic,
+            "IotClient::set_color_temperature",
+            serde_json::json!({
+                "msg": {
+                    "cmd": "colorwc",
+                    "data": {
+                        "color": {
+                            "r": 0,
+                            "g": 0,
+                            "b": 0,
                         },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_color_temperature")?;
-        Ok(())
+                        "colorTemInKelvin": kelvin,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
     }
 
     pub async fn set_color_rgb(
@@ -149,34 +181,35 @@ impl IotClient {
         g: u8,
         b: u8,
     ) -> anyhow::Result<()> {
-        log::trace!("set_color_rgb for {} to {r},{g},{b}", device.device);
+        device_log!(
+            log::Level::Trace,
+            &device.device,
+            "set_color_rgb to {r},{g},{b}"
+        );
         let device_topic = device.device_topic()?;
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "colorwc",
-                        "data": {
-                            "color":{
-                                "r": r,
-                                "g": g,
-                                "b": b,
-                            },
-                            "colorTemInKelvin": 0,
+        self.publish_command(
+            device,
+            device_topic,
+            "IotClient::set_color_rgb",
+            serde_json::json!({
+                "msg": {
+                    "cmd": "colorwc",
+                    "data": {
+                        "color":{
+                            "r": r,
+                            "g": g,
+                            "b": b,
                         },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_color_rgb")?;
-        Ok(())
+                        "colorTemInKelvin": 0,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
     }
 
     pub async fn send_real(
@@ -184,29 +217,30 @@ impl IotClient {
         device: &DeviceEntry,
         commands: Vec<String>,
     ) -> anyhow::Result<()> {
-        log::trace!("send_real for {} to {commands:?}", device.device);
+        device_log!(
+            log::Level::Trace,
+            &device.device,
+            "send_real to {commands:?}"
+        );
         let device_topic = device.device_topic()?;
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "ptReal",
-                        "data": {
-                            "command": commands,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::send_real")?;
-        Ok(())
+        self.publish_command(
+            device,
+            device_topic,
+            "IotClient::send_real",
+            serde_json::json!({
+                "msg": {
+                    "cmd": "ptReal",
+                    "data": {
+                        "command": commands,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }),
+        )
+        .await
     }
 
     pub async fn activate_one_click(&self, item: &ParsedOneClick) -> anyhow::Result<()> {
@@ -220,13 +254,142 @@ impl IotClient {
                         false,
                     )
                     .await
-                    .context("sending OneClick")?;
+                    .with_context(|| format!("sending OneClick {}", item.name))?;
             }
         }
         Ok(())
     }
 }
 
+/// If we remain disconnected for this long, we assume that our
+/// certificate is no longer being accepted. libmosquitto keeps
+/// retrying in the background, but only reports the disconnect that
+/// began the outage, not each failed attempt to reconnect, so this
+/// is measured from that disconnect.
+const MAX_RECONNECT_OUTAGE: Duration = Duration::from_secs(15 * 60);
+/// Bounds how often the IoT credentials are renewed, so that a
+/// connection problem with some other root cause doesn't turn into
+/// a storm of logins against the Govee account
+const MIN_RENEWAL_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Certificates are renewed this long before they expire
+fn renewal_lead_time() -> chrono::Duration {
+    chrono::Duration::days(1)
+}
+
+/// The delay before the first attempt to reconnect, which doubles
+/// with each failed attempt, up to MAX_RECONNECT_DELAY
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(2);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+/// The state of the connection to AWS IoT, for health reporting
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct IotConnectionHealth {
+    pub connected: bool,
+    pub connected_since: Option<DateTime<Utc>>,
+    pub disconnect_count: u64,
+    pub last_error: Option<String>,
+    /// When the current outage began, if we are disconnected
+    #[serde(default)]
+    pub disconnected_since: Option<DateTime<Utc>>,
+}
+
+impl IotConnectionHealth {
+    /// Returns true if this is a reconnection following a disconnect
+    fn record_connected(&mut self, now: DateTime<Utc>) -> bool {
+        let reconnected = !self.connected && self.disconnect_count > 0;
+        if !self.connected {
+            self.connected = true;
+            self.connected_since.replace(now);
+            self.disconnected_since = None;
+        }
+        reconnected
+    }
+
+    /// Counts the time until the initial connection is made as an
+    /// outage, so that failing to connect at startup is treated
+    /// the same as losing the connection
+    fn record_connecting(&mut self, now: DateTime<Utc>) {
+        if !self.connected && self.disconnected_since.is_none() {
+            self.disconnected_since.replace(now);
+        }
+    }
+
+    fn record_disconnected(&mut self, error: String) {
+        if self.disconnected_since.is_none() {
+            self.disconnected_since.replace(Utc::now());
+        }
+        self.connected = false;
+        self.connected_since = None;
+        self.disconnect_count += 1;
+        self.last_error.replace(error);
+    }
+
+    /// Returns how long the connection has been down, if it is,
+    /// including while the initial connection is being attempted
+    pub fn outage_duration(&self, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        if self.connected {
+            return None;
+        }
+        self.disconnected_since.map(|since| now - since)
+    }
+}
+
+/// Randomizes the reconnect backoff, so that we don't reconnect in
+/// lockstep with every other client when the endpoint has an outage
+fn configure_reconnect_backoff(client: &mosquitto_rs::Client) -> anyhow::Result<()> {
+    let random = uuid::Uuid::new_v4().as_u128();
+    client
+        .set_reconnect_delay(
+            with_whole_second_jitter(INITIAL_RECONNECT_DELAY, random as u64),
+            with_whole_second_jitter(MAX_RECONNECT_DELAY, (random >> 64) as u64),
+            true,
+        )
+        .context("set_reconnect_delay")
+}
+
+/// Adds up to 10%, and at least up to a second, to the delay.
+/// mosquitto only deals in whole seconds, and would discard
+/// any fraction of a second.
+fn with_whole_second_jitter(delay: Duration, random: u64) -> Duration {
+    let max_jitter = (delay.as_secs() / 10).max(1);
+    Duration::from_secs(delay.as_secs() + random % (max_jitter + 1))
+}
+
+/// Publishes the IoT connection sensor, if hass is connected
+async fn notify_iot_health(state: &StateHandle) {
+    let Some(client) = state.get_hass_client().await else {
+        return;
+    };
+    if let Err(err) = IotConnectionSensor::new(state).notify_state(&client).await {
+        log::error!("while reporting IoT connection state: {err:#}");
+    }
+}
+
+/// Requests the state of the devices that update via IoT, as we
+/// will have missed any changes that were pushed during an outage
+async fn refresh_iot_devices(state: StateHandle) {
+    for device in state.devices().await {
+        if device.pollable_via_iot() {
+            if let Err(err) = state.poll_iot_api(&device).await {
+                log::error!("refreshing {device} after IoT reconnect: {err:#}");
+            }
+        }
+    }
+}
+
+struct IotConnection {
+    client: mosquitto_rs::Client,
+    subscriptions: Receiver<Event>,
+    /// When the client certificate expires
+    not_after: Option<DateTime<Utc>>,
+}
+
+enum IotLoopExit {
+    Closed,
+    Renew(&'static str),
+}
+
 pub async fn start_iot_client(
     args: &Args,
     state: StateHandle,
@@ -238,6 +401,84 @@ pub async fn start_iot_client(
         None => client.login_account_cached().await?,
     };
     log::trace!("{acct:#?}");
+
+    let connection = connect_iot(&args.undoc_args, &acct).await?;
+    spawn_iot_client(args.undoc_args.clone(), state, acct, connection).await;
+    Ok(())
+}
+
+/// Starts the IoT client for the service. Unlike start_iot_client,
+/// failing to connect isn't fatal: the outage is counted from startup,
+/// so that the devices that rely on IoT are polled via the Platform
+/// API meanwhile, and connecting is retried in the background.
+pub async fn start_iot_service(args: &Args, state: StateHandle, acct: LoginAccountResponse) {
+    state
+        .update_iot_health(|health| health.record_connecting(Utc::now()))
+        .await;
+
+    let undoc_args = args.undoc_args.clone();
+    let err = match connect_iot(&undoc_args, &acct).await {
+        Ok(connection) => {
+            spawn_iot_client(undoc_args, state, acct, connection).await;
+            return;
+        }
+        Err(err) => err,
+    };
+    log::error!("Failed to connect to IoT, will keep trying: {err:#}");
+    state
+        .update_iot_health(|health| health.last_error.replace(format!("connecting: {err:#}")))
+        .await;
+
+    tokio::spawn(async move {
+        let mut delay = INITIAL_RECONNECT_DELAY;
+        loop {
+            let random = uuid::Uuid::new_v4().as_u128() as u64;
+            sleep(with_whole_second_jitter(delay, random)).await;
+            match connect_iot(&undoc_args, &acct).await {
+                Ok(connection) => {
+                    spawn_iot_client(undoc_args, state, acct, connection).await;
+                    return;
+                }
+                Err(err) => {
+                    log::warn!("Failed to connect to IoT: {err:#}");
+                    state
+                        .update_iot_health(|health| {
+                            health.last_error.replace(format!("connecting: {err:#}"))
+                        })
+                        .await;
+                }
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    });
+}
+
+async fn spawn_iot_client(
+    undoc_args: UndocApiArguments,
+    state: StateHandle,
+    acct: LoginAccountResponse,
+    connection: IotConnection,
+) {
+    state
+        .set_iot_client(IotClient {
+            client: connection.client.clone(),
+        })
+        .await;
+
+    tokio::spawn(async move {
+        if let Err(err) = run_iot_client(undoc_args, state, acct, connection).await {
+            log::error!("IoT loop failed: {err:#}");
+        }
+        log::info!("IoT loop terminated");
+    });
+}
+
+/// Provisions the IoT credentials for the account and connects with them
+async fn connect_iot(
+    undoc_args: &UndocApiArguments,
+    acct: &LoginAccountResponse,
+) -> anyhow::Result<IotConnection> {
+    let client = undoc_args.api_client()?;
     let res = client.get_iot_key(&acct.token).await?;
     log::trace!("{res:#?}");
 
@@ -250,12 +491,23 @@ pub async fn start_iot_client(
         let pem = priv_key
             .private_key_to_pem_pkcs8()
             .context("to_pem_pkcs8")?;
-        std::fs::write(&args.undoc_args.govee_iot_key, &pem)?;
+        std::fs::write(&undoc_args.govee_iot_key, &pem)?;
     }
+    let mut not_after = None;
     for cert in container.cert_bags(&res.p12_pass).context("cert_bags")? {
         let cert = openssl::x509::X509::from_der(&cert).context("x509 from der")?;
         let pem = cert.to_pem().context("cert.to_pem")?;
-        std::fs::write(&args.undoc_args.govee_iot_cert, &pem)?;
+        std::fs::write(&undoc_args.govee_iot_cert, &pem)?;
+
+        match certificate_not_after(&cert) {
+            Ok(expiry) => {
+                log::info!("IoT certificate is valid until {expiry}");
+                not_after.replace(expiry);
+            }
+            Err(err) => {
+                log::warn!("Unable to determine when the IoT certificate expires: {err:#}");
+            }
+        }
     }
 
     let client = mosquitto_rs::Client::with_id(
@@ -269,13 +521,14 @@ pub async fn start_iot_client(
     .context("new client")?;
     client
         .configure_tls(
-            Some(&args.undoc_args.amazon_root_ca),
+            Some(&undoc_args.amazon_root_ca),
             None::<&std::path::Path>,
-            Some(&args.undoc_args.govee_iot_cert),
-            Some(&args.undoc_args.govee_iot_key),
+            Some(&undoc_args.govee_iot_cert),
+            Some(&undoc_args.govee_iot_key),
             None,
         )
         .context("configure_tls")?;
+    configure_reconnect_backoff(&client)?;
     log::trace!("Connecting to IoT {} port 8883", res.endpoint);
     let status = timeout(
         Duration::from_secs(60),
@@ -288,21 +541,95 @@ pub async fn start_iot_client(
 
     let subscriptions = client.subscriber().expect("first and only");
 
-    state
-        .set_iot_client(IotClient {
-            client: client.clone(),
-        })
-        .await;
+    Ok(IotConnection {
+        client,
+        subscriptions,
+        not_after,
+    })
+}
 
-    tokio::spawn(async move {
-        if let Err(err) = run_iot_subscriber(subscriptions, state, client, acct).await {
-            log::error!("IoT loop failed: {err:#}");
+fn certificate_not_after(cert: &openssl::x509::X509Ref) -> anyhow::Result<DateTime<Utc>> {
+    let epoch = openssl::asn1::Asn1Time::from_unix(0)?;
+    let diff = epoch.diff(cert.not_after())?;
+    let secs = diff.days as i64 * 86400 + diff.secs as i64;
+    DateTime::from_timestamp(secs, 0).ok_or_else(|| anyhow::anyhow!("invalid notAfter {secs}"))
+}
+
+/// Processes IoT events, renewing the credentials and swapping
+/// over to a new connection when the certificate is about to
+/// expire or appears to no longer be accepted
+async fn run_iot_client(
+    undoc_args: UndocApiArguments,
+    state: StateHandle,
+    mut acct: LoginAccountResponse,
+    mut connection: IotConnection,
+) -> anyhow::Result<()> {
+    let mut earliest_renewal = Instant::now() + MIN_RENEWAL_INTERVAL;
+    loop {
+        match run_iot_subscriber(&connection, &state, &acct, earliest_renewal).await? {
+            IotLoopExit::Closed => return Ok(()),
+            IotLoopExit::Renew(reason) => {
+                log::warn!("Renewing IoT credentials: {reason}");
+            }
         }
-        log::info!("IoT loop terminated");
-        Ok::<(), anyhow::Error>(())
-    });
 
-    Ok(())
+        earliest_renewal = Instant::now() + MIN_RENEWAL_INTERVAL;
+        match renew_iot_connection(&undoc_args).await {
+            Ok((new_acct, new_connection)) => {
+                state
+                    .set_iot_client(IotClient {
+                        client: new_connection.client.clone(),
+                    })
+                    .await;
+                // Dropping the prior connection disconnects it
+                acct = new_acct;
+                connection = new_connection;
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to renew IoT credentials, will try again in {}s: {err:#}",
+                    MIN_RENEWAL_INTERVAL.as_secs()
+                );
+                state
+                    .update_iot_health(|health| {
+                        health
+                            .last_error
+                            .replace(format!("renewing credentials: {err:#}"))
+                    })
+                    .await;
+            }
+        }
+    }
+}
+
+async fn renew_iot_connection(
+    undoc_args: &UndocApiArguments,
+) -> anyhow::Result<(LoginAccountResponse, IotConnection)> {
+    let client = undoc_args.api_client()?;
+    // Only the IoT credentials need to be refreshed; the account
+    // session is renewed by login_account_cached when it expires
+    client.invalidate_iot_key();
+    let acct = client.login_account_cached().await?;
+    let connection = connect_iot(undoc_args, &acct).await?;
+    Ok((acct, connection))
+}
+
+/// Returns the time at which to give up on reconnecting and renew the
+/// credentials, given an outage that began at `disconnected_at`
+fn outage_renewal_deadline(disconnected_at: Instant, earliest_renewal: Instant) -> Instant {
+    (disconnected_at + MAX_RECONNECT_OUTAGE).max(earliest_renewal)
+}
+
+/// Returns the time at which to renew the credentials for `connection`,
+/// given that renewal cannot happen before `earliest_renewal`
+fn renewal_deadline(
+    not_after: Option<DateTime<Utc>>,
+    earliest_renewal: Instant,
+    now: DateTime<Utc>,
+) -> Option<Instant> {
+    let renew_at = not_after? - renewal_lead_time();
+    let delay = (renew_at - now).to_std().unwrap_or_default();
+    Some((Instant::now() + delay).max(earliest_renewal))
 }
 
 #[derive(Deserialize, Debug)]
@@ -314,11 +641,54 @@ struct Packet {
     cmd: Option<String>,
     /// This is an embedded json string
     msg: Option<String>,
+    #[serde(default)]
     state: StateUpdate,
     op: Option<OpData>,
+    /// Present when the device is reporting events, such as a button
+    /// press, or pushing a change in its properties, such as occupancy
+    #[serde(default)]
+    capabilities: Vec<PushedCapability>,
 }
 
 #[derive(Deserialize, Debug)]
+struct PushedCapability {
+    #[serde(rename = "type")]
+    kind: DeviceCapabilityKind,
+    instance: String,
+    /// A list of EventCapabilityState for events, otherwise
+    /// the same `{"value": ...}` shape as the platform API state
+    #[serde(default)]
+    state: JsonValue,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct EventCapabilityState {
+    name: String,
+    value: Option<JsonValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl PushedCapability {
+    fn events(&self) -> Vec<EventCapabilityState> {
+        serde_json::from_value(self.state.clone()).unwrap_or_default()
+    }
+
+    /// Computes the platform-API-style state for this capability.
+    /// For events, `value` holds the value of the first reported
+    /// state, and `states` holds the full list.
+    fn platform_state(&self) -> JsonValue {
+        match &self.state {
+            JsonValue::Array(states) => json!({
+                "value": states.first().and_then(|s| s.get("value")).cloned(),
+                "states": states,
+            }),
+            state => state.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
 struct StateUpdate {
     #[serde(rename = "onOff")]
     pub on_off: Option<u8>,
@@ -348,6 +718,99 @@ struct OpData {
     timer_value: Vec<Base64HexBytes>,
 }
 
+impl StateUpdate {
+    fn is_empty(&self) -> bool {
+        self.on_off.is_none()
+            && self.brightness.is_none()
+            && self.color.is_none()
+            && self.color_temperature_kelvin.is_none()
+    }
+}
+
+/// Publishes momentary events to hass. Every occurrence is published,
+/// even if it is identical to the previous one.
+/// Other capabilities, such as the occupancy reported by presence
+/// sensors, are applied to the device state. Returns true if the
+/// device state was changed, so that the caller can inform hass
+/// without waiting for the next poll.
+async fn dispatch_capabilities(
+    state: &StateHandle,
+    sku: &str,
+    device_id: &str,
+    caps: &[PushedCapability],
+) -> bool {
+    let Some(device) = state.device_by_id(device_id).await else {
+        return false;
+    };
+    if !caps.is_empty() {
+        state.device_mut(sku, device_id).await.mark_seen();
+    }
+
+    let mut changed = false;
+    // Safety events are published without waiting for other changes
+    let mut urgent = false;
+
+    for cap in caps {
+        let is_press = cap.kind == DeviceCapabilityKind::Event
+            && device
+                .get_capability_by_instance(&cap.instance)
+                .map(is_momentary_press_event)
+                .unwrap_or(false);
+        if !is_press {
+            device_log!(
+                log::Level::Debug,
+                &device.id,
+                "Applying pushed {cap:?} to {device}"
+            );
+            {
+                let mut device = state.device_mut(sku, device_id).await;
+                device.apply_capability_state(
+                    cap.kind.clone(),
+                    &cap.instance,
+                    cap.platform_state(),
+                );
+                if cap.kind == DeviceCapabilityKind::Event {
+                    device.record_event(&cap.instance);
+                }
+            }
+            let clears_after_timeout = match device.get_capability_by_instance(&cap.instance) {
+                Some(event) => event_clears_after_timeout(event),
+                None => is_leak_instance(&cap.instance),
+            };
+            if clears_after_timeout {
+                state.schedule_leak_clear(device_id, &cap.instance).await;
+            }
+            if safety_event_name(&cap.instance).is_some() {
+                urgent = true;
+            }
+            changed = true;
+            continue;
+        }
+
+        for event in cap.events() {
+            if let Err(err) = state
+                .notify_of_device_event(device_id, &cap.instance, &event.name)
+                .await
+            {
+                log::error!("notify_of_device_event {device} {cap:?}: {err:#}");
+            }
+        }
+    }
+
+    if changed {
+        if let Err(err) = state.update_reported_scene(device_id).await {
+            log::warn!("while resolving the active scene of {device}: {err:#}");
+        }
+    }
+    if urgent {
+        if let Err(err) = state.publish_state_change(device_id).await {
+            log::error!("publish_state_change {device}: {err:#}");
+        }
+    }
+
+    changed
+}
+
 impl Packet {
     /// The sku can be in a couple of different places(!)
     fn sku(&self) -> Option<&str> {
@@ -371,21 +834,59 @@ impl Packet {
 }
 
 async fn run_iot_subscriber(
-    subscriptions: Receiver<Event>,
-    state: StateHandle,
-    client: mosquitto_rs::Client,
-    acct: LoginAccountResponse,
-) -> anyhow::Result<()> {
-    while let Ok(event) = subscriptions.recv().await {
+    connection: &IotConnection,
+    state: &StateHandle,
+    acct: &LoginAccountResponse,
+    earliest_renewal: Instant,
+) -> anyhow::Result<IotLoopExit> {
+    let client = &connection.client;
+    // When the current outage began, if we are disconnected
+    let mut disconnected_at: Option<Instant> = None;
+    let mut renewal_reason = "the certificate is about to expire";
+    let mut deadline = renewal_deadline(connection.not_after, earliest_renewal, Utc::now());
+
+    loop {
+        let event = tokio::select! {
+            event = connection.subscriptions.recv() => match event {
+                Ok(event) => event,
+                Err(_) => return Ok(IotLoopExit::Closed),
+            },
+            _ = sleep_until_deadline(deadline) => {
+                return Ok(IotLoopExit::Renew(renewal_reason));
+            }
+        };
+
         match event {
             Event::Message(msg) => {
+                metrics::increment(Counter::IotMessagesReceived, &[]);
                 let payload = String::from_utf8_lossy(&msg.payload);
                 log::trace!("{} -> {payload}", msg.topic);
 
                 match from_json::<Packet, _>(&msg.payload) {
                     Ok(packet) => {
-                        log::debug!("{packet:?}");
+                        match packet.sku_and_device() {
+                            Some((_, device_id)) => {
+                                device_log!(log::Level::Debug, device_id, "{packet:?}")
+                            }
+                            None => log::debug!("{packet:?}"),
+                        }
                         if let Some((sku, device_id)) = packet.sku_and_device() {
+                            if !packet.capabilities.is_empty() {
+                                let changed = dispatch_capabilities(
+                                    state,
+                                    sku,
+                                    device_id,
+                                    &packet.capabilities,
+                                )
+                                .await;
+                                if packet.op.is_none() && packet.state.is_empty() {
+                                    if changed {
+                                        state.notify_of_state_change(device_id).await?;
+                                    }
+                                    continue;
+                                }
+                            }
+
                             {
                                 let mut device = state.device_mut(sku, device_id).await;
                                 let mut state = match device.iot_device_status.clone() {
@@ -417,7 +918,11 @@ async fn run_iot_subscriber(
                                 if let Some(op) = &packet.op {
                                     for cmd in &op.command {
                                         let decoded = cmd.decode_for_sku(sku);
-                                        log::debug!("Decoded: {decoded:?} for {sku}");
+                                        device_log!(
+                                            log::Level::Debug,
+                                            device_id,
+                                            "Decoded: {decoded:?} for {sku}"
+                                        );
                                         match decoded {
                                             GoveeBlePacket::NotifyHumidifierNightlight(nl) => {
                                                 state.brightness = nl.brightness;
@@ -426,7 +931,7 @@ async fn run_iot_subscriber(
                                                     g: nl.g,
                                                     b: nl.b,
                                                 };
-                                                device.set_nightlight_state(nl.clone());
+                                                device.set_nightlight_state(nl);
                                             }
                                             GoveeBlePacket::NotifyHumidifierAutoMode(
                                                 HumidifierAutoMode { target_humidity },
@@ -478,9 +983,41 @@ async fn run_iot_subscriber(
             }
             Event::Disconnected(reason) => {
                 log::warn!("IoT disconnected with reason {reason}");
+                state
+                    .update_iot_health(|health| {
+                        health.record_disconnected(format!("disconnected with reason {reason}"))
+                    })
+                    .await;
+                notify_iot_health(state).await;
+                if let Err(err) = configure_reconnect_backoff(client) {
+                    log::warn!("{err:#}");
+                }
+
+                if disconnected_at.is_none() {
+                    let since = Instant::now();
+                    disconnected_at.replace(since);
+                    let give_up = outage_renewal_deadline(since, earliest_renewal);
+                    if deadline.is_none_or(|deadline| give_up < deadline) {
+                        renewal_reason =
+                            "unable to reconnect, the certificate may have been revoked";
+                        deadline = Some(give_up);
+                    }
+                }
             }
             Event::Connected(status) => {
                 log::info!("IoT (re)connected with status {status}");
+                if disconnected_at.take().is_some() {
+                    renewal_reason = "the certificate is about to expire";
+                    deadline = renewal_deadline(connection.not_after, earliest_renewal, Utc::now());
+                }
+
+                let reconnected = state
+                    .update_iot_health(|health| health.record_connected(Utc::now()))
+                    .await;
+                notify_iot_health(state).await;
+                if reconnected {
+                    tokio::spawn(refresh_iot_devices(state.clone()));
+                }
 
                 client
                     .subscribe(&acct.topic, mosquitto_rs::QoS::AtMostOnce)
@@ -507,5 +1044,140 @@ async fn run_iot_subscriber(
             }
         }
     }
-    Ok(())
+}
+
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn certificate_expiry() {
+        let key =
+            openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let mut builder = openssl::x509::X509Builder::new().unwrap();
+        builder
+            .set_not_after(&openssl::asn1::Asn1Time::from_unix(1_700_000_000).unwrap())
+            .unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .sign(&key, openssl::hash::MessageDigest::sha256())
+            .unwrap();
+        let cert = builder.build();
+
+        k9::assert_equal!(
+            certificate_not_after(&cert).unwrap(),
+            DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn initial_connection_outage() {
+        let startup = Utc::now();
+        let mut health = IotConnectionHealth::default();
+        k9::assert_equal!(health.outage_duration(startup), None);
+
+        health.record_connecting(startup);
+        let later = startup + chrono::Duration::minutes(5);
+        k9::assert_equal!(
+            health.outage_duration(later),
+            Some(chrono::Duration::minutes(5))
+        );
+
+        // Still the same outage when the first connection drops
+        // before being established
+        health.record_disconnected("disconnected with reason 7".to_string());
+        k9::assert_equal!(health.disconnected_since, Some(startup));
+
+        assert!(health.record_connected(later));
+        k9::assert_equal!(health.outage_duration(later), None);
+        health.record_connecting(later);
+        k9::assert_equal!(health.outage_duration(later), None);
+    }
+
+    #[test]
+    fn connection_health() {
+        let now = Utc::now();
+        let mut health = IotConnectionHealth::default();
+
+        // The initial connection isn't a reconnect
+        assert!(!health.record_connected(now));
+        k9::assert_equal!(health.connected_since, Some(now));
+
+        health.record_disconnected("disconnected with reason 7".to_string());
+        let since = health.disconnected_since;
+        health.record_disconnected("disconnected with reason 7".to_string());
+        assert!(!health.connected);
+        // Repeated disconnects are part of the same outage
+        k9::assert_equal!(health.disconnected_since, since);
+        assert!(health.outage_duration(Utc::now()).is_some());
+        k9::assert_equal!(health.disconnect_count, 2);
+        k9::assert_equal!(
+            health.last_error.as_deref(),
+            Some("disconnected with reason 7")
+        );
+
+        let later = now + chrono::Duration::minutes(5);
+        assert!(health.record_connected(later));
+        k9::assert_equal!(health.connected_since, Some(later));
+        k9::assert_equal!(health.outage_duration(later), None);
+        assert!(!health.record_connected(later));
+    }
+
+    #[test]
+    fn renewal() {
+        let now = Utc::now();
+        let earliest = Instant::now() + MIN_RENEWAL_INTERVAL;
+
+        k9::assert_equal!(renewal_deadline(None, earliest, now), None);
+
+        // Expiring soon, but the rate limit still applies
+        k9::assert_equal!(
+            renewal_deadline(Some(now + chrono::Duration::hours(2)), earliest, now),
+            Some(earliest)
+        );
+
+        let deadline =
+            renewal_deadline(Some(now + chrono::Duration::days(30)), earliest, now).unwrap();
+        let lead = Duration::from_secs(29 * 86400);
+        assert!(deadline > earliest);
+        assert!(deadline <= Instant::now() + lead);
+        assert!(deadline >= Instant::now() + lead - Duration::from_secs(60));
+    }
+
+    #[test]
+    fn reconnect_jitter() {
+        for random in [0, 1, 7, 29, 30, u64::MAX] {
+            let initial = with_whole_second_jitter(INITIAL_RECONNECT_DELAY, random);
+            assert!(initial.subsec_nanos() == 0);
+            assert!((2..=3).contains(&initial.as_secs()), "{initial:?}");
+
+            let max = with_whole_second_jitter(MAX_RECONNECT_DELAY, random);
+            assert!(max.subsec_nanos() == 0);
+            assert!((300..=330).contains(&max.as_secs()), "{max:?}");
+        }
+        k9::assert_equal!(
+            with_whole_second_jitter(MAX_RECONNECT_DELAY, 17),
+            Duration::from_secs(317)
+        );
+    }
+
+    #[test]
+    fn outage_renewal() {
+        let now = Instant::now();
+        // A long outage leads to renewal
+        k9::assert_equal!(
+            outage_renewal_deadline(now, now),
+            now + MAX_RECONNECT_OUTAGE
+        );
+        // But not within the rate limit of the last renewal
+        let earliest = now + MIN_RENEWAL_INTERVAL;
+        k9::assert_equal!(outage_renewal_deadline(now, earliest), earliest);
+    }
 }
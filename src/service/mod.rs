@@ -1,7 +1,14 @@
+#[cfg(feature = "ble")]
+pub mod ble_listener;
+pub mod command_queue;
+pub mod control_path;
 pub mod coordinator;
 pub mod device;
 pub mod hass;
+pub mod health;
 pub mod http;
 pub mod iot;
 pub mod quirks;
+pub mod reload;
 pub mod state;
+pub mod transition;
@@ -1,17 +1,33 @@
 use crate::platform_api::DeviceType;
 use crate::temperature::TemperatureUnits;
-use once_cell::sync::Lazy;
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
 #[allow(unused)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HumidityUnits {
     RelativePercent,
     RelativePercentTimes100,
 }
 
 impl HumidityUnits {
+    /// Used when there is no quirk for the device. Relative humidity
+    /// can never exceed 100%, so a larger value must be encoded as
+    /// the percentage times 100
+    pub fn guess_from_reading(value: f64) -> Self {
+        if value > 100. {
+            Self::RelativePercentTimes100
+        } else {
+            Self::RelativePercent
+        }
+    }
+
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_reading_to_relative_percent(&self, value: f64) -> f64 {
         match self {
             Self::RelativePercent => value,
@@ -20,13 +36,19 @@ impl HumidityUnits {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Quirk {
+    #[serde(skip)]
     pub sku: Cow<'static, str>,
     pub icon: Cow<'static, str>,
     pub supports_rgb: bool,
     pub supports_brightness: bool,
     pub color_temp_range: Option<(u32, u32)>,
+    /// If true, `color_temp_range` is known to be correct for this
+    /// device, and is used in preference to the range that the
+    /// Platform API reports
+    #[serde(skip)]
+    pub exact_color_temp_range: bool,
     pub avoid_platform_api: bool,
     pub ble_only: bool,
     pub lan_api_capable: bool,
@@ -38,6 +60,15 @@ pub struct Quirk {
     /// their state.
     pub iot_api_supported: bool,
     pub show_as_preset_buttons: Option<&'static [&'static str]>,
+    /// Named mist levels, ordered by level, for humidifiers whose
+    /// Platform API metadata only reports a numeric range
+    pub mist_level_presets: Option<&'static [(&'static str, i64)]>,
+    /// Additional capability instances that lock the physical
+    /// controls of the device, beyond those that we recognize
+    pub lock_instances: Option<&'static [&'static str]>,
+    /// If true, the firmware accepts the segment color and
+    /// brightness packets via the LAN API
+    pub lan_segment_control: bool,
 }
 
 impl Quirk {
@@ -51,6 +82,7 @@ impl Quirk {
             supports_rgb: false,
             supports_brightness: false,
             color_temp_range: None,
+            exact_color_temp_range: false,
             avoid_platform_api: false,
             ble_only: false,
             icon: icon.into(),
@@ -60,6 +92,9 @@ impl Quirk {
             platform_humidity_sensor_units: None,
             iot_api_supported: false,
             show_as_preset_buttons: None,
+            mist_level_presets: None,
+            lock_instances: None,
+            lan_segment_control: false,
         }
     }
 
@@ -137,6 +172,11 @@ impl Quirk {
         self
     }
 
+    pub fn with_lan_segment_control(mut self) -> Self {
+        self.lan_segment_control = true;
+        self
+    }
+
     pub fn lan_api_capable_light(sku: &'static str, icon: &'static str) -> Self {
         Self::light(sku, icon).with_lan_api()
     }
@@ -147,9 +187,16 @@ impl Quirk {
             .map(|modes| modes.contains(&mode))
             .unwrap_or(false)
     }
+
+    pub fn is_lock_instance(&self, instance: &str) -> bool {
+        self.lock_instances
+            .as_ref()
+            .map(|instances| instances.contains(&instance))
+            .unwrap_or(false)
+    }
 }
 
-static QUIRKS: Lazy<HashMap<String, Quirk>> = Lazy::new(load_quirks);
+static QUIRKS: OnceCell<HashMap<String, Quirk>> = OnceCell::new();
 
 const STRIP: &str = "mdi:led-strip-variant";
 const STRIP_ALT: &str = "mdi:led-strip";
@@ -272,9 +319,9 @@ fn load_quirks() -> HashMap<String, Quirk> {
         // Lights from the list of LAN API enabled devices
         // at <https://app-h5.govee.com/user-manual/wlan-guide>
         Quirk::lan_api_capable_light("H6072", FLOOR_LAMP),
-        Quirk::lan_api_capable_light("H619B", STRIP),
-        Quirk::lan_api_capable_light("H619C", STRIP),
-        Quirk::lan_api_capable_light("H619Z", STRIP),
+        Quirk::lan_api_capable_light("H619B", STRIP).with_lan_segment_control(),
+        Quirk::lan_api_capable_light("H619C", STRIP).with_lan_segment_control(),
+        Quirk::lan_api_capable_light("H619Z", STRIP).with_lan_segment_control(),
         Quirk::lan_api_capable_light("H7060", FLOOD),
         Quirk::lan_api_capable_light("H6046", TV_BACK),
         Quirk::lan_api_capable_light("H6047", TV_BACK),
@@ -303,13 +350,13 @@ fn load_quirks() -> HashMap<String, Quirk> {
         Quirk::lan_api_capable_light("H618C", STRIP),
         Quirk::lan_api_capable_light("H618E", STRIP),
         Quirk::lan_api_capable_light("H618F", STRIP),
-        Quirk::lan_api_capable_light("H619A", STRIP),
-        Quirk::lan_api_capable_light("H619D", STRIP),
-        Quirk::lan_api_capable_light("H619E", STRIP),
-        Quirk::lan_api_capable_light("H61A0", STRIP),
-        Quirk::lan_api_capable_light("H61A1", STRIP),
-        Quirk::lan_api_capable_light("H61A2", STRIP),
-        Quirk::lan_api_capable_light("H61A3", STRIP),
+        Quirk::lan_api_capable_light("H619A", STRIP).with_lan_segment_control(),
+        Quirk::lan_api_capable_light("H619D", STRIP).with_lan_segment_control(),
+        Quirk::lan_api_capable_light("H619E", STRIP).with_lan_segment_control(),
+        Quirk::lan_api_capable_light("H61A0", STRIP).with_lan_segment_control(),
+        Quirk::lan_api_capable_light("H61A1", STRIP).with_lan_segment_control(),
+        Quirk::lan_api_capable_light("H61A2", STRIP).with_lan_segment_control(),
+        Quirk::lan_api_capable_light("H61A3", STRIP).with_lan_segment_control(),
         Quirk::lan_api_capable_light("H61A5", STRIP),
         Quirk::lan_api_capable_light("H61A8", STRIP),
         Quirk::lan_api_capable_light("H61B2", TV_BACK),
@@ -329,6 +376,14 @@ fn load_quirks() -> HashMap<String, Quirk> {
         Quirk::lan_api_capable_light("H7061", FLOOD),
         Quirk::lan_api_capable_light("H7062", FLOOD),
         Quirk::lan_api_capable_light("H7065", SPOTLIGHT),
+        // Permanent outdoor lights and curtain lights
+        Quirk::lan_api_capable_light("H705E", OUTDOOR_LAMP),
+        Quirk::lan_api_capable_light("H706A", OUTDOOR_LAMP),
+        Quirk::lan_api_capable_light("H706B", OUTDOOR_LAMP),
+        Quirk::lan_api_capable_light("H706C", OUTDOOR_LAMP),
+        Quirk::lan_api_capable_light("H70B1", STRING),
+        Quirk::lan_api_capable_light("H70B3", STRING),
+        Quirk::lan_api_capable_light("H70B5", STRING),
     ] {
         map.insert(quirk.sku.to_string(), quirk);
     }
@@ -336,6 +391,301 @@ fn load_quirks() -> HashMap<String, Quirk> {
     map
 }
 
+/// An entry in the user's quirks file. The fields that are present
+/// replace those of the built-in quirk for the same SKU; a SKU that
+/// has no built-in quirk must specify its `device_type`, and starts
+/// out with the defaults for that type.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct QuirkOverride {
+    device_type: Option<String>,
+    icon: Option<String>,
+    supports_rgb: Option<bool>,
+    supports_brightness: Option<bool>,
+    color_temp_range: Option<(u32, u32)>,
+    avoid_platform_api: Option<bool>,
+    ble_only: Option<bool>,
+    lan_api_capable: Option<bool>,
+    platform_temperature_sensor_units: Option<TemperatureUnits>,
+    platform_humidity_sensor_units: Option<HumidityUnits>,
+    iot_api_supported: Option<bool>,
+    show_as_preset_buttons: Option<Vec<String>>,
+    mist_level_presets: Option<BTreeMap<String, i64>>,
+    lock_instances: Option<Vec<String>>,
+    lan_segment_control: Option<bool>,
+}
+
+fn parse_device_type(name: &str) -> anyhow::Result<DeviceType> {
+    for candidate in [name.to_string(), format!("devices.types.{name}")] {
+        if let Ok(device_type) = candidate.parse::<DeviceType>() {
+            if !matches!(device_type, DeviceType::Other(_)) {
+                return Ok(device_type);
+            }
+        }
+    }
+    anyhow::bail!("unknown device type {name:?}");
+}
+
+/// The quirks are loaded once per process, so the lists from the
+/// user's file can simply be leaked to give them the same lifetime
+/// as the built-in lists
+fn leak_list(list: Vec<String>) -> &'static [&'static str] {
+    let list: Vec<&'static str> = list
+        .into_iter()
+        .map(|s| &*Box::leak(s.into_boxed_str()))
+        .collect();
+    Box::leak(list.into_boxed_slice())
+}
+
+impl QuirkOverride {
+    fn apply(self, sku: &str, base: Option<&Quirk>) -> anyhow::Result<Quirk> {
+        let device_type = self
+            .device_type
+            .as_deref()
+            .map(parse_device_type)
+            .transpose()
+            .context("`device_type`")?;
+
+        let mut quirk = match (base, device_type) {
+            (Some(base), None) => base.clone(),
+            (Some(base), Some(device_type)) => Quirk {
+                device_type,
+                ..base.clone()
+            },
+            (None, Some(DeviceType::Light)) => Quirk::light(sku.to_string(), BULB),
+            (None, Some(DeviceType::Heater)) => Quirk::space_heater(sku.to_string()),
+            (None, Some(DeviceType::Humidifier)) => Quirk::humidifier(sku.to_string()),
+            (None, Some(DeviceType::Thermometer)) => Quirk::thermometer(sku.to_string()),
+            (None, Some(DeviceType::IceMaker)) => Quirk::ice_maker(sku.to_string()),
+            (None, Some(device_type)) => Quirk::device(sku.to_string(), device_type, "mdi:devices"),
+            (None, None) => {
+                anyhow::bail!("`device_type` is required for a SKU that has no built-in quirk")
+            }
+        };
+
+        if let Some((min, max)) = self.color_temp_range {
+            if min >= max {
+                anyhow::bail!(
+                    "`color_temp_range`: the minimum {min} must be less than the maximum {max}"
+                );
+            }
+            quirk.color_temp_range = Some((min, max));
+            quirk.exact_color_temp_range = true;
+        }
+        if let Some(icon) = self.icon {
+            quirk.icon = icon.into();
+        }
+        if let Some(units) = self.platform_temperature_sensor_units {
+            quirk.platform_temperature_sensor_units = Some(units);
+        }
+        if let Some(units) = self.platform_humidity_sensor_units {
+            quirk.platform_humidity_sensor_units = Some(units);
+        }
+        if let Some(modes) = self.show_as_preset_buttons {
+            quirk.show_as_preset_buttons = Some(leak_list(modes));
+        }
+        if let Some(presets) = self.mist_level_presets {
+            if presets.is_empty() {
+                anyhow::bail!("`mist_level_presets` must name at least one level");
+            }
+            let mut presets: Vec<(&'static str, i64)> = presets
+                .into_iter()
+                .map(|(name, level)| (&*Box::leak(name.into_boxed_str()), level))
+                .collect();
+            presets.sort_by_key(|&(_, level)| level);
+            quirk.mist_level_presets = Some(Box::leak(presets.into_boxed_slice()));
+        }
+        if let Some(instances) = self.lock_instances {
+            quirk.lock_instances = Some(leak_list(instances));
+        }
+        for (value, field) in [
+            (self.supports_rgb, &mut quirk.supports_rgb),
+            (self.supports_brightness, &mut quirk.supports_brightness),
+            (self.avoid_platform_api, &mut quirk.avoid_platform_api),
+            (self.ble_only, &mut quirk.ble_only),
+            (self.lan_api_capable, &mut quirk.lan_api_capable),
+            (self.iot_api_supported, &mut quirk.iot_api_supported),
+            (self.lan_segment_control, &mut quirk.lan_segment_control),
+        ] {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+
+        Ok(quirk)
+    }
+}
+
+/// Parses a quirks file, whose top level maps each SKU to the
+/// fields of its quirk, and merges its entries into quirks.
+/// The file is JSON if its name ends with `.json`, otherwise TOML.
+fn merge_quirks_file(
+    quirks: &mut HashMap<String, Quirk>,
+    file_name: &str,
+    text: &str,
+) -> anyhow::Result<()> {
+    let (entries, doc) = if file_name.ends_with(".json") {
+        let entries: Map<String, JsonValue> =
+            serde_json::from_str(text).with_context(|| format!("parsing {file_name}"))?;
+        (entries, None)
+    } else {
+        let doc = crate::config::toml::parse(text)
+            .map_err(|err| anyhow::anyhow!("{file_name}: {err}"))?;
+        (doc.root.clone(), Some(doc))
+    };
+
+    let locate = |path: &[String]| match doc.as_ref().and_then(|doc| doc.line_of(path)) {
+        Some(line) => format!("{file_name}: line {line}"),
+        None => file_name.to_string(),
+    };
+
+    for (sku, entry) in entries {
+        let overrides: QuirkOverride =
+            serde_json_path_to_error::from_value(entry).map_err(|err| {
+                let field = err.path().to_string();
+                let mut path = vec![sku.clone()];
+                path.extend(
+                    field
+                        .split('.')
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_string),
+                );
+                anyhow::anyhow!("{}: quirk for {sku}: {}", locate(&path), err.inner())
+            })?;
+        let quirk = overrides.apply(&sku, quirks.get(&sku)).map_err(|err| {
+            anyhow::anyhow!(
+                "{}: quirk for {sku}: {err:#}",
+                locate(std::slice::from_ref(&sku))
+            )
+        })?;
+        quirks.insert(sku, quirk);
+    }
+
+    Ok(())
+}
+
+/// Loads the built-in quirks, merged with those from the user's
+/// quirks file, if one is configured via `$GOVEE_QUIRKS_FILE`.
+/// This must be called before the quirks are first used.
+pub fn load_user_quirks() -> anyhow::Result<()> {
+    let mut quirks = load_quirks();
+    if let Some(path) = crate::opt_env_var::<PathBuf>("GOVEE_QUIRKS_FILE")? {
+        merge_user_quirks(&mut quirks, &path)?;
+        log::info!("Loaded quirks from {}", path.display());
+    }
+    QUIRKS
+        .set(quirks)
+        .map_err(|_| anyhow::anyhow!("the quirks were already loaded"))
+}
+
+fn merge_user_quirks(quirks: &mut HashMap<String, Quirk>, path: &Path) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading quirks file {}", path.display()))?;
+    merge_quirks_file(quirks, &path.display().to_string(), &text)
+}
+
 pub fn resolve_quirk(sku: &str) -> Option<&'static Quirk> {
-    QUIRKS.get(sku)
+    QUIRKS.get_or_init(load_quirks).get(sku)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn outdoor_lights() {
+        // Their color temperature range and segments come from the
+        // Platform API, so the quirks don't override them
+        for sku in [
+            "H705E", "H706A", "H706B", "H706C", "H70B1", "H70B3", "H70B5",
+        ] {
+            let quirk = resolve_quirk(sku).unwrap();
+            k9::assert_equal!(quirk.device_type, DeviceType::Light, "{sku}");
+            k9::assert_equal!(quirk.lan_api_capable, true, "{sku}");
+            k9::assert_equal!(quirk.iot_api_supported, true, "{sku}");
+            k9::assert_equal!(quirk.exact_color_temp_range, false, "{sku}");
+        }
+    }
+
+    #[test]
+    fn quirks_file() {
+        let mut quirks = load_quirks();
+        merge_quirks_file(
+            &mut quirks,
+            "quirks.toml",
+            r#"
+[H6141]
+avoid_platform_api = false
+color_temp_range = [2700, 6500]
+
+[H9999]
+device_type = "light"
+lan_api_capable = true
+show_as_preset_buttons = ["gearMode"]
+
+[H9998]
+device_type = "humidifier"
+mist_level_presets = { Low = 1, High = 9, Medium = 5 }
+"#,
+        )
+        .unwrap();
+
+        let h6141 = &quirks["H6141"];
+        k9::assert_equal!(h6141.avoid_platform_api, false);
+        k9::assert_equal!(h6141.color_temp_range, Some((2700, 6500)));
+        k9::assert_equal!(h6141.supports_rgb, true);
+
+        let h9999 = &quirks["H9999"];
+        k9::assert_equal!(h9999.device_type, DeviceType::Light);
+        k9::assert_equal!(h9999.lan_api_capable, true);
+        k9::assert_equal!(h9999.supports_brightness, true);
+        k9::assert_equal!(h9999.should_show_mode_as_preset("gearMode"), true);
+        k9::assert_equal!(
+            quirks["H9998"].mist_level_presets,
+            Some(&[("Low", 1), ("Medium", 5), ("High", 9)][..])
+        );
+
+        merge_quirks_file(
+            &mut quirks,
+            "quirks.json",
+            r#"{"H7131": {"device_type": "devices.types.heater", "ble_only": true}}"#,
+        )
+        .unwrap();
+        k9::assert_equal!(quirks["H7131"].ble_only, true);
+        k9::assert_equal!(quirks["H7131"].should_show_mode_as_preset("gearMode"), true);
+    }
+
+    #[test]
+    fn quirks_file_errors() {
+        let error = |file_name: &str, text: &str| {
+            format!(
+                "{:#}",
+                merge_quirks_file(&mut load_quirks(), file_name, text).unwrap_err()
+            )
+        };
+
+        k9::assert_equal!(
+            error("quirks.toml", "[H6141]\nsupports_rgb = 1\n"),
+            "quirks.toml: line 2: quirk for H6141: invalid type: integer `1`, expected a boolean"
+        );
+        k9::assert_equal!(
+            error("quirks.toml", "[H6141]\ncolour = true\n")
+                .starts_with("quirks.toml: line 2: quirk for H6141: unknown field `colour`"),
+            true
+        );
+        k9::assert_equal!(
+            error("quirks.toml", "[H9999]\nsupports_rgb = true\n"),
+            "quirks.toml: line 1: quirk for H9999: `device_type` is required \
+             for a SKU that has no built-in quirk"
+        );
+        k9::assert_equal!(
+            error("quirks.json", r#"{"H9999": {"device_type": "toaster"}}"#),
+            "quirks.json: quirk for H9999: `device_type`: unknown device type \"toaster\""
+        );
+        k9::assert_equal!(
+            error("quirks.toml", "[H6141]\ncolor_temp_range = [6500, 2700]\n"),
+            "quirks.toml: line 1: quirk for H6141: `color_temp_range`: the minimum \
+             6500 must be less than the maximum 2700"
+        );
+    }
 }
@@ -0,0 +1,379 @@
+use crate::commands::serve::resolve_poll_intervals;
+use crate::lan_api::LanDiscoArguments;
+use crate::platform_api::{GoveeApiArguments, GoveeApiClient};
+use crate::service::hass::{reload_status_topic, HassArguments};
+use crate::service::state::StateHandle;
+use crate::undoc_api::{GoveeUndocumentedApi, UndocApiArguments};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// The arguments that hold the configuration that can be reloaded.
+/// These are retained so that the configuration can be re-read from
+/// the environment when it is reloaded.
+#[derive(Clone, Debug)]
+pub struct ReloadableArgs {
+    pub api_args: GoveeApiArguments,
+    pub undoc_args: UndocApiArguments,
+    pub lan_disco_args: LanDiscoArguments,
+    pub hass_args: HassArguments,
+    /// The --poll-intervals argument of the serve command
+    pub poll_intervals: Option<String>,
+}
+
+/// The outcome of a reload, for publishing to the status topic
+#[derive(Serialize, Debug)]
+pub struct ReloadResult {
+    pub success: bool,
+    pub message: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Re-reads the configuration from the environment, including any
+/// .env file, and from the configuration file, and applies the parts
+/// of it that changed. Returns a description of each change.
+pub async fn reload_config(state: &StateHandle) -> anyhow::Result<Vec<String>> {
+    let Some(args) = state.get_reloadable_args().await else {
+        anyhow::bail!("the configuration cannot be reloaded by this command");
+    };
+
+    // Capture the settings that we're connected with before anything
+    // is re-read, so that we can tell whether they changed
+    let prior_restart_settings = args.hass_args.restart_settings().ok();
+
+    if let Ok(path) = dotenvy::dotenv_override() {
+        log::info!("Re-reading environment overrides from {path:?}");
+    }
+    let prior_config = crate::config::reload()?;
+    let devices_changed = prior_config.devices() != crate::config::current().devices();
+
+    let result = apply_config(state, &args, prior_restart_settings, devices_changed).await;
+    if result.is_err() {
+        crate::config::restore(prior_config);
+    }
+    result
+}
+
+/// Applies the re-read configuration. Everything that can fail,
+/// which is parsing it, verifying any new credentials and re-publishing
+/// the entities, is done before anything that can't, so that a failure
+/// leaves the prior configuration in place.
+async fn apply_config(
+    state: &StateHandle,
+    args: &ReloadableArgs,
+    prior_restart_settings: Option<Vec<(&'static str, String)>>,
+    devices_changed: bool,
+) -> anyhow::Result<Vec<String>> {
+    // Parse everything before applying anything, so that a mistake
+    // doesn't leave us with a partially applied configuration
+    let static_lan_devices = args.lan_disco_args.static_devices()?;
+    let poll_intervals = resolve_poll_intervals(args.poll_intervals.as_deref())?;
+    let segment_lights = args.hass_args.segment_lights()?;
+    let temperature_scale = args.hass_args.temperature_scale()?;
+    let restart_settings = args.hass_args.restart_settings()?;
+
+    let credentials = if state.is_lan_only().await {
+        NewCredentials::default()
+    } else {
+        verify_credentials(state, args).await?
+    };
+
+    // These change the entities, so they need to be re-published.
+    // They are put back if that fails.
+    let prior_segment_lights = state.get_segment_light_devices().await;
+    let prior_temperature_scale = state.get_temperature_scale().await;
+    let segment_lights_changed = prior_segment_lights != segment_lights;
+    let temperature_scale_changed = prior_temperature_scale != temperature_scale;
+    if devices_changed || segment_lights_changed || temperature_scale_changed {
+        state.set_segment_light_devices(segment_lights).await;
+        state.set_temperature_scale(temperature_scale).await;
+        if let Some(client) = state.get_hass_client().await {
+            if let Err(err) = client.register_with_hass(state).await {
+                state.set_segment_light_devices(prior_segment_lights).await;
+                state.set_temperature_scale(prior_temperature_scale).await;
+                credentials.discard();
+                return Err(err.context("re-publishing entities"));
+            }
+        }
+    }
+
+    // Nothing from here on can fail
+    let mut changes = credentials.apply(state).await;
+
+    if state.apply_static_lan_devices(static_lan_devices).await {
+        changes.push("LAN device list".to_string());
+    }
+    if state.get_poll_intervals().await != poll_intervals {
+        state.set_poll_intervals(poll_intervals).await;
+        changes.push("poll intervals".to_string());
+    }
+
+    if devices_changed {
+        changes.push("device options".to_string());
+    }
+    if segment_lights_changed {
+        changes.push("segment lights".to_string());
+    }
+    if temperature_scale_changed {
+        changes.push("temperature scale".to_string());
+    }
+
+    if let Some(filters) = crate::logging::reload_log_filters() {
+        changes.push(format!(
+            "log level ({})",
+            if filters.is_empty() { "info" } else { &filters }
+        ));
+    }
+
+    if let Some(prior) = prior_restart_settings {
+        for ((name, prior_value), (_, value)) in prior.iter().zip(&restart_settings) {
+            if prior_value != value {
+                log::warn!("The {name} was changed; restart govee2mqtt to apply it");
+                changes.push(format!("{name} (restart required)"));
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/// The credentials that changed and were verified, but which are
+/// not yet in use
+#[derive(Default)]
+struct NewCredentials {
+    platform: Option<GoveeApiClient>,
+    undoc: Option<GoveeUndocumentedApi>,
+}
+
+impl NewCredentials {
+    /// Swaps in the new credentials.
+    /// Returns a description of each credential that was changed.
+    async fn apply(self, state: &StateHandle) -> Vec<String> {
+        let mut changes = vec![];
+        if let Some(client) = self.platform {
+            state.set_platform_client(client).await;
+            changes.push("Platform API key".to_string());
+        }
+        if let Some(client) = self.undoc {
+            // login_account replaced the cached session of the prior
+            // account with one for the new credentials
+            state.set_undoc_client(client).await;
+            changes.push("Govee account credentials".to_string());
+        }
+        changes
+    }
+
+    /// Forgets the new credentials, when they won't be put into use
+    fn discard(self) {
+        if let Some(client) = self.undoc {
+            // login_account replaced the cached session of the prior
+            // account, which will need to log in again
+            client.invalidate_account_login();
+        }
+    }
+}
+
+/// Verifies the credentials that changed, without putting any of
+/// them into use, so that a failure leaves all of the prior
+/// credentials in place.
+async fn verify_credentials(
+    state: &StateHandle,
+    args: &ReloadableArgs,
+) -> anyhow::Result<NewCredentials> {
+    let platform = state.get_platform_client().await;
+    let new_platform = match (args.api_args.opt_api_key()?, &platform) {
+        (Some(key), Some(current)) if !current.has_key(&key) => {
+            // Keep the rate limiting and quota state
+            let client = current.with_key(key);
+            client
+                .check_credentials()
+                .await
+                .context("verifying the new API key")?;
+            Some(client)
+        }
+        (Some(_), None) => {
+            log::warn!("An API key was configured; restart to start using the Platform API");
+            None
+        }
+        _ => None,
+    };
+
+    let undoc = state.get_undoc_client().await;
+    let new_undoc = match (
+        args.undoc_args.opt_email()?,
+        args.undoc_args.opt_password()?,
+        &undoc,
+    ) {
+        (Some(email), Some(password), Some(current))
+            if !current.has_credentials(&email, &password) =>
+        {
+            let client = GoveeUndocumentedApi::new(email, password)
+                .with_verification(args.undoc_args.verification()?);
+            client
+                .login_account()
+                .await
+                .context("logging in with the new Govee account credentials")?;
+            Some(client)
+        }
+        (Some(_), Some(_), None) => {
+            log::warn!(
+                "Govee account credentials were configured; \
+                 restart to start using the undocumented API"
+            );
+            None
+        }
+        _ => None,
+    };
+
+    Ok(NewCredentials {
+        platform: new_platform,
+        undoc: new_undoc,
+    })
+}
+
+/// Reloads the configuration, and publishes the outcome to the
+/// reload status topic
+pub async fn reload_and_publish_result(state: &StateHandle) -> anyhow::Result<()> {
+    let result = reload_config_with_result(state).await;
+    if let Some(client) = state.get_hass_client().await {
+        client
+            .publish_transient(reload_status_topic(), serde_json::to_string(&result)?)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Set by the SIGHUP handler. Only async-signal-safe operations
+/// may be performed by a signal handler, so it just sets this,
+/// and a task watches for it to be set.
+#[cfg(unix)]
+static SIGHUP_RECEIVED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_sighup(_signal: libc::c_int) {
+    SIGHUP_RECEIVED.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Reloads the configuration each time that we receive SIGHUP
+#[cfg(unix)]
+pub fn spawn_sighup_handler(state: StateHandle) {
+    // SAFETY: the handler only stores to an atomic
+    let prior = unsafe { libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t) };
+    if prior == libc::SIG_ERR {
+        log::warn!("Unable to handle SIGHUP; use the reload topic to reload the configuration");
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            if SIGHUP_RECEIVED.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                log::info!("Received SIGHUP; reloading the configuration");
+                if let Err(err) = reload_and_publish_result(&state).await {
+                    log::error!("publishing the outcome of the reload: {err:#}");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_sighup_handler(_state: StateHandle) {}
+
+/// Runs reload_config, logging and describing the outcome
+pub async fn reload_config_with_result(state: &StateHandle) -> ReloadResult {
+    match reload_config(state).await {
+        Ok(changes) => {
+            let message = if changes.is_empty() {
+                "Configuration is unchanged".to_string()
+            } else {
+                format!("Reloaded {}", changes.join(", "))
+            };
+            log::info!("{message}");
+            ReloadResult {
+                success: true,
+                message,
+                time: Utc::now(),
+            }
+        }
+        Err(err) => {
+            log::error!("******************************************************");
+            log::error!("Failed to reload the configuration: {err:#}");
+            log::error!("Continuing to use the prior configuration.");
+            log::error!("******************************************************");
+            ReloadResult {
+                success: false,
+                message: format!("{err:#}"),
+                time: Utc::now(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::service::state::State;
+    use clap::Parser;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn unchanged_credentials() {
+        let state = Arc::new(State::new());
+        k9::assert_equal!(reload_config(&state).await.is_err(), true);
+
+        state.set_platform_client(GoveeApiClient::new("key")).await;
+        state
+            .set_undoc_client(GoveeUndocumentedApi::new("me@example.com", "secret"))
+            .await;
+        state
+            .set_reloadable_args(ReloadableArgs {
+                api_args: GoveeApiArguments {
+                    api_key: Some("key".to_string()),
+                },
+                undoc_args: UndocApiArguments {
+                    govee_email: Some("me@example.com".to_string()),
+                    govee_password: Some("secret".to_string()),
+                    govee_iot_key: Default::default(),
+                    govee_iot_cert: Default::default(),
+                    amazon_root_ca: Default::default(),
+                    govee_verification_code: None,
+                    govee_verification_code_file: None,
+                },
+                lan_disco_args: LanDiscoArguments::parse_from(["govee"]),
+                hass_args: HassArguments::parse_from(["govee"]),
+                poll_intervals: None,
+            })
+            .await;
+
+        // Nothing changed, so nothing needs to be verified or swapped
+        k9::assert_equal!(reload_config(&state).await.unwrap(), Vec::<String>::new());
+
+        // The configuration file is re-read. This is part of the same
+        // test because the configuration is process-wide.
+        let path = std::env::temp_dir().join(format!("govee-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, "[device.H5179]\npoll_interval = 30\n").unwrap();
+        crate::config::load(Some(&path)).unwrap();
+        std::fs::write(&path, "[device.H5179]\npoll_interval = 60\n").unwrap();
+        k9::assert_equal!(
+            reload_config(&state).await.unwrap(),
+            vec!["poll intervals", "device options"]
+        );
+        k9::assert_equal!(
+            state.get_poll_intervals().await,
+            vec![("H5179".to_string(), chrono::Duration::seconds(60))]
+        );
+
+        // A file with a mistake is rejected, leaving the prior
+        // configuration in place
+        std::fs::write(&path, "[device.H5179]\npoll_interval = soon\n").unwrap();
+        assert!(reload_config(&state).await.is_err());
+        k9::assert_equal!(
+            crate::config::current().devices()[0].1.poll_interval,
+            Some(60)
+        );
+
+        crate::config::restore(Default::default());
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,31 +1,195 @@
 use crate::ble::{Base64HexBytes, SetHumidifierMode, SetHumidifierNightlightParams};
-use crate::lan_api::{Client as LanClient, DeviceStatus as LanDeviceStatus, LanDevice};
-use crate::platform_api::{DeviceCapability, GoveeApiClient};
+use crate::ble_adv::{canonical_mac, BleReading};
+use crate::cache::{load_value, store_value};
+use crate::hass_mqtt::cover::CoverCommand;
+use crate::hass_mqtt::event::publish_device_event;
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::lan_api::{
+    Client as LanClient, DeviceStatus as LanDeviceStatus, LanDevice, StaticLanDevice,
+};
+use crate::logging::device_log;
+use crate::metrics::{self, Counter};
+use crate::platform_api::{
+    is_snapshot_scene_name, scene_name_by_value, unknown_scene_name, DeviceCapability, DeviceType,
+    GoveeApiClient, HttpDeviceInfo,
+};
+use crate::service::command_queue::{Admission, CommandIntervals, CommandQueue};
+use crate::service::control_path::{Transport, Unconfirmed};
 use crate::service::coordinator::Coordinator;
-use crate::service::device::Device;
-use crate::service::hass::{topic_safe_id, HassClient};
-use crate::service::iot::IotClient;
+use crate::service::device::{Device, DeviceStateSnapshot, ExpectedChange, UndocDeviceInfo};
+use crate::service::hass::{device_availability_topic, topic_safe_id, HassClient};
+use crate::service::iot::{IotClient, IotConnectionHealth};
+use crate::service::reload::ReloadableArgs;
+use crate::service::transition::{
+    cloud_step_count, run_cloud_transition, run_lan_transition, Transition, TransitionRequest,
+    Transitions,
+};
 use crate::temperature::{TemperatureScale, TemperatureValue};
-use crate::undoc_api::GoveeUndocumentedApi;
+use crate::undoc_api::{DevicesResponse, GoveeUndocumentedApi};
 use anyhow::Context;
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 use std::time::Instant;
-use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, Semaphore};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
 use tokio::time::{sleep, Duration};
 
 #[derive(Default)]
 pub struct State {
     devices_by_id: Mutex<HashMap<String, Device>>,
-    semaphore_by_id: Mutex<HashMap<String, Arc<Semaphore>>>,
+    command_queues: Mutex<HashMap<String, Arc<CommandQueue>>>,
+    command_intervals: Mutex<CommandIntervals>,
     lan_client: Mutex<Option<LanClient>>,
     platform_client: Mutex<Option<GoveeApiClient>>,
     undoc_client: Mutex<Option<GoveeUndocumentedApi>>,
     iot_client: Mutex<Option<IotClient>>,
+    iot_health: Mutex<IotConnectionHealth>,
     hass_client: Mutex<Option<HassClient>>,
+    mqtt_connected: Mutex<bool>,
     hass_discovery_prefix: Mutex<String>,
     temperature_scale: Mutex<TemperatureScale>,
+    segment_light_devices: Mutex<Vec<String>>,
+    poll_intervals: Mutex<Vec<(String, chrono::Duration)>>,
+    pending_segment_updates: Mutex<HashMap<String, PendingSegmentUpdates>>,
+    scene_lists_by_id: Mutex<HashMap<String, Vec<String>>>,
+    one_click_button_ids: Mutex<Option<HashSet<String>>>,
+    /// The unique_id migrations whose removals are being published
+    pending_migrations: Mutex<Vec<String>>,
+    device_groups: Mutex<Vec<DeviceGroup>>,
+    room_light_ids: Mutex<Option<HashSet<String>>>,
+    device_availability: Mutex<HashMap<String, bool>>,
+    published_discovery_topics: Mutex<HashSet<String>>,
+    /// The most recently published config for each discovery topic
+    published_discovery_configs: Mutex<HashMap<String, String>>,
+    platform_device_list_hash: Mutex<Option<String>>,
+    full_refresh: Mutex<bool>,
+    reloadable_args: Mutex<Option<ReloadableArgs>>,
+    static_lan_devices: Mutex<Vec<StaticLanDevice>>,
+    transitions: Mutex<Transitions>,
+    lan_only: Mutex<bool>,
+    /// The MACs from which BLE advertisements are accepted,
+    /// or empty to accept them from any MAC
+    ble_allow_list: Mutex<Vec<String>>,
+    state_debounce: Mutex<Duration>,
+    effect_list_limit: Mutex<Option<usize>>,
+    leak_clear_timeout: Mutex<Option<Duration>>,
+    /// The pending timers that clear the leak sensors, keyed by
+    /// device id and event instance
+    leak_clear_timers: Mutex<HashMap<(String, String), tokio::task::JoinHandle<()>>>,
+    state_cache_max_age: Mutex<Option<Duration>>,
+    pending_state_notifications: Coalescer,
+    discovery_scan: Mutex<Option<Vec<RetainedMessage>>>,
+    /// The transport that most recently carried out a power, brightness
+    /// or color command for each device, keyed by device id
+    served_transports: Mutex<HashMap<String, Transport>>,
+}
+
+/// The topic and payload of a retained message
+pub type RetainedMessage = (String, Vec<u8>);
+
+/// A group of devices, as arranged in the Govee app
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceGroup {
+    pub id: u64,
+    pub name: String,
+    /// The ids of the devices in the group
+    pub members: Vec<String>,
+}
+
+/// How long to wait for further segment commands before sending
+/// them to the device. hass sends a separate command for each
+/// segment when several are changed at once, eg: via an area.
+const SEGMENT_BATCH_DELAY: Duration = Duration::from_millis(250);
+
+/// The cache topic used to persist the last known device states
+const DEVICE_STATE_CACHE_TOPIC: &str = "device-state";
+
+/// The cache topic and key used to persist the unique ids of the
+/// published one-click buttons, so that the buttons of rules that
+/// were removed while we weren't running are removed on startup
+const ONE_CLICK_CACHE_TOPIC: &str = "one-click";
+const ONE_CLICK_BUTTON_IDS_KEY: &str = "button-ids";
+/// How long the one-click button ids are retained; effectively forever
+const ONE_CLICK_BUTTON_IDS_TTL: Duration = Duration::from_secs(10 * 365 * 86400);
+
+/// The default window over which bursts of state changes for a
+/// device are coalesced into a single publish
+pub const DEFAULT_STATE_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// The default maximum number of scenes in the effect list of a light.
+/// The full list is available via the scene select entity.
+pub const DEFAULT_EFFECT_LIST_LIMIT: usize = 100;
+
+/// Leak detectors never report that they are dry again; the app
+/// clears their alert after a few minutes without a repeated event
+pub const DEFAULT_LEAK_CLEAR_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Coalesces bursts of work for the same key: the first call for
+/// a key schedules the work to run after a delay, and further calls
+/// for that key that arrive before it runs are absorbed into it.
+#[derive(Default)]
+struct Coalescer {
+    pending: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Coalescer {
+    async fn schedule<F, Fut>(&self, key: &str, delay: Duration, work: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        if !self.pending.lock().await.insert(key.to_string()) {
+            return;
+        }
+        let pending = self.pending.clone();
+        let key = key.to_string();
+        tokio::spawn(async move {
+            sleep(delay).await;
+            // Remove the key before running, so that changes that
+            // arrive while the work is running schedule another run
+            pending.lock().await.remove(&key);
+            (work)().await;
+        });
+    }
+}
+
+/// Segment changes that are waiting to be sent to a device,
+/// keyed by segment number
+#[derive(Default, Debug)]
+struct PendingSegmentUpdates {
+    rgb: BTreeMap<u32, u32>,
+    brightness: BTreeMap<u32, u8>,
+}
+
+impl PendingSegmentUpdates {
+    fn is_empty(&self) -> bool {
+        self.rgb.is_empty() && self.brightness.is_empty()
+    }
+}
+
+async fn send_lan_segment_updates(
+    lan_dev: &LanDevice,
+    updates: &PendingSegmentUpdates,
+) -> anyhow::Result<()> {
+    for (brightness, segments) in group_segments_by_value(&updates.brightness) {
+        lan_dev
+            .send_segment_brightness(&segments, brightness)
+            .await?;
+    }
+    for (rgb, segments) in group_segments_by_value(&updates.rgb) {
+        lan_dev.send_segment_rgb(&segments, rgb).await?;
+    }
+    Ok(())
+}
+
+/// Groups segments that are to be set to the same value, so that
+/// they can be sent in a single request
+fn group_segments_by_value<V: Ord + Copy>(updates: &BTreeMap<u32, V>) -> BTreeMap<V, Vec<u32>> {
+    let mut result: BTreeMap<V, Vec<u32>> = BTreeMap::new();
+    for (segment, value) in updates {
+        result.entry(*value).or_default().push(*segment);
+    }
+    result
 }
 
 pub type StateHandle = Arc<State>;
@@ -35,6 +199,114 @@ impl State {
         Self::default()
     }
 
+    /// Sets the window over which state changes are coalesced.
+    /// A zero duration publishes each change immediately.
+    pub async fn set_state_debounce(&self, debounce: Duration) {
+        *self.state_debounce.lock().await = debounce;
+    }
+
+    /// Sets the maximum number of scenes that are offered as effects
+    /// of a light entity
+    pub async fn set_effect_list_limit(&self, limit: usize) {
+        self.effect_list_limit.lock().await.replace(limit);
+    }
+
+    pub async fn effect_list_limit(&self) -> usize {
+        self.effect_list_limit
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_EFFECT_LIST_LIMIT)
+    }
+
+    /// Sets how long a leak sensor stays on after the most recent
+    /// leak event. A zero duration keeps it on.
+    pub async fn set_leak_clear_timeout(&self, timeout: Duration) {
+        self.leak_clear_timeout.lock().await.replace(timeout);
+    }
+
+    /// Returns how long a leak sensor stays on after the most recent
+    /// leak event, or None if it stays on
+    pub async fn leak_clear_timeout(&self) -> Option<Duration> {
+        let timeout = self
+            .leak_clear_timeout
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_LEAK_CLEAR_TIMEOUT);
+        (!timeout.is_zero()).then_some(timeout)
+    }
+
+    /// (Re)starts the timer that turns off the leak sensor for the
+    /// event `instance` of a device, once the timeout has passed
+    /// without a repeated leak event
+    pub async fn schedule_leak_clear(self: &Arc<Self>, device_id: &str, instance: &str) {
+        let Some(timeout) = self.leak_clear_timeout().await else {
+            return;
+        };
+        let state = self.clone();
+        let id = device_id.to_string();
+        let timer = tokio::spawn(async move {
+            sleep(timeout).await;
+            if let Err(err) = state.notify_of_state_change(&id).await {
+                log::error!("while clearing the leak sensor of {id}: {err:#}");
+            }
+        });
+        if let Some(prior) = self
+            .leak_clear_timers
+            .lock()
+            .await
+            .insert((device_id.to_string(), instance.to_string()), timer)
+        {
+            prior.abort();
+        }
+    }
+
+    /// Enables persisting device state across restarts; state older
+    /// than max_age is not restored
+    pub async fn set_state_cache_max_age(&self, max_age: Option<Duration>) {
+        *self.state_cache_max_age.lock().await = max_age;
+    }
+
+    /// Applies the device states that were persisted by a prior run
+    pub async fn restore_device_states(&self) {
+        let Some(max_age) = *self.state_cache_max_age.lock().await else {
+            return;
+        };
+        let now = chrono::Utc::now();
+
+        for device in self.devices().await {
+            let snapshot: DeviceStateSnapshot =
+                match load_value(DEVICE_STATE_CACHE_TOPIC, &device.id) {
+                    Ok(Some(snapshot)) => snapshot,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        log::warn!("Unable to load cached state for {device}: {err:#}");
+                        continue;
+                    }
+                };
+            let age = (now - snapshot.saved).to_std().unwrap_or_default();
+            if age > max_age {
+                log::debug!("Ignoring cached state for {device}, which is {age:?} old");
+                continue;
+            }
+            log::info!("Using cached state for {device}, which is {age:?} old");
+            self.device_mut(&device.sku, &device.id)
+                .await
+                .restore_state_snapshot(snapshot);
+        }
+    }
+
+    async fn persist_device_state(&self, device: &Device) {
+        let Some(max_age) = *self.state_cache_max_age.lock().await else {
+            return;
+        };
+        let Some(snapshot) = device.state_snapshot() else {
+            return;
+        };
+        if let Err(err) = store_value(DEVICE_STATE_CACHE_TOPIC, &device.id, &snapshot, max_age) {
+            log::warn!("Unable to cache state for {device}: {err:#}");
+        }
+    }
+
     pub async fn set_temperature_scale(&self, scale: TemperatureScale) {
         *self.temperature_scale.lock().await = scale;
     }
@@ -43,6 +315,68 @@ impl State {
         *self.temperature_scale.lock().await
     }
 
+    pub async fn set_segment_light_devices(&self, devices: Vec<String>) {
+        *self.segment_light_devices.lock().await = devices;
+    }
+
+    pub async fn get_segment_light_devices(&self) -> Vec<String> {
+        self.segment_light_devices.lock().await.clone()
+    }
+
+    /// Returns true if per-segment light entities were requested
+    /// for the device
+    pub async fn segment_lights_enabled(&self, device: &Device) -> bool {
+        let name = device.name();
+        self.segment_light_devices.lock().await.iter().any(|item| {
+            item.eq_ignore_ascii_case("all")
+                || item.eq_ignore_ascii_case(&device.id)
+                || item.eq_ignore_ascii_case(&name)
+                || item.eq_ignore_ascii_case(&device.sku)
+        })
+    }
+
+    /// Sets the poll intervals for specific devices, keyed by id,
+    /// name or SKU
+    pub async fn set_poll_intervals(&self, intervals: Vec<(String, chrono::Duration)>) {
+        *self.poll_intervals.lock().await = intervals;
+    }
+
+    pub async fn get_poll_intervals(&self) -> Vec<(String, chrono::Duration)> {
+        self.poll_intervals.lock().await.clone()
+    }
+
+    /// Returns the interval at which the device should be polled.
+    /// An interval configured for the device itself takes precedence
+    /// over one configured for its SKU. A zero interval means that the
+    /// device should not be polled.
+    pub async fn poll_interval_for(&self, device: &Device) -> chrono::Duration {
+        let name = device.name();
+        let intervals = self.poll_intervals.lock().await;
+        intervals
+            .iter()
+            .find(|(key, _)| {
+                key.eq_ignore_ascii_case(&device.id) || key.eq_ignore_ascii_case(&name)
+            })
+            .or_else(|| {
+                intervals
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(&device.sku))
+            })
+            .map(|(_, interval)| *interval)
+            .unwrap_or_else(|| device.preferred_poll_interval())
+    }
+
+    /// Returns the shortest poll interval that was configured
+    pub async fn shortest_poll_interval(&self) -> Option<chrono::Duration> {
+        self.poll_intervals
+            .lock()
+            .await
+            .iter()
+            .map(|(_, interval)| *interval)
+            .filter(|interval| !interval.is_zero())
+            .min()
+    }
+
     pub async fn set_hass_disco_prefix(&self, prefix: String) {
         *self.hass_discovery_prefix.lock().await = prefix;
     }
@@ -53,7 +387,7 @@ impl State {
 
     /// Returns a mutable version of the specified device, creating
     /// an entry for it if necessary.
-    pub async fn device_mut(&self, sku: &str, id: &str) -> MappedMutexGuard<Device> {
+    pub async fn device_mut(&self, sku: &str, id: &str) -> MappedMutexGuard<'_, Device> {
         let devices = self.devices_by_id.lock().await;
         MutexGuard::map(devices, |devices| {
             devices
@@ -72,15 +406,32 @@ impl State {
         devices.get(id).cloned()
     }
 
-    async fn semaphore_for_device(&self, device: &Device) -> Arc<Semaphore> {
-        self.semaphore_by_id
+    async fn command_queue_for_device(&self, device: &Device) -> Arc<CommandQueue> {
+        self.command_queues
             .lock()
             .await
             .entry(device.id.clone())
-            .or_insert_with(|| Arc::new(Semaphore::new(1)))
+            .or_default()
             .clone()
     }
 
+    pub async fn set_command_intervals(&self, intervals: CommandIntervals) {
+        *self.command_intervals.lock().await = intervals;
+    }
+
+    /// Returns the number of commands waiting to be sent, by SKU
+    pub async fn command_queue_depths(&self) -> BTreeMap<String, usize> {
+        let queues = self.command_queues.lock().await;
+        let devices = self.devices_by_id.lock().await;
+        let mut depths = BTreeMap::new();
+        for (id, queue) in queues.iter() {
+            if let Some(device) = devices.get(id) {
+                *depths.entry(device.sku.to_string()).or_default() += queue.depth();
+            }
+        }
+        depths
+    }
+
     pub async fn resolve_device_read_only(self: &Arc<Self>, label: &str) -> anyhow::Result<Device> {
         self.resolve_device(label)
             .await
@@ -97,12 +448,60 @@ impl State {
         self: &Arc<Self>,
         label: &str,
     ) -> anyhow::Result<Coordinator> {
+        self.queue_command(label, None)
+            .await
+            .map(|coordinator| coordinator.expect("commands without a kind are always sent"))
+    }
+
+    /// Like resolve_device_for_control, but returns None if a newer
+    /// command of the same `kind`, eg: the brightness of the device,
+    /// arrives while this one is waiting for its turn. The caller
+    /// should then drop the command.
+    pub async fn resolve_device_for_command(
+        self: &Arc<Self>,
+        label: &str,
+        kind: &str,
+    ) -> anyhow::Result<Option<Coordinator>> {
+        self.queue_command(label, Some(kind)).await
+    }
+
+    async fn queue_command(
+        self: &Arc<Self>,
+        label: &str,
+        kind: Option<&str>,
+    ) -> anyhow::Result<Option<Coordinator>> {
         let device = self
             .resolve_device(label)
             .await
             .ok_or_else(|| anyhow::anyhow!("device '{label}' not found"))?;
-        let semaphore = self.semaphore_for_device(&device).await;
-        let permit = semaphore.acquire_owned().await?;
+        let queue = self.command_queue_for_device(&device).await;
+        let intervals = *self.command_intervals.lock().await;
+        let interval = if device.lan_device.is_some() {
+            intervals.lan
+        } else {
+            intervals.cloud
+        };
+
+        let queued = Instant::now();
+        let permit = match queue.admit(kind, interval).await? {
+            Admission::Ready(permit) => permit,
+            Admission::Superseded { id } => {
+                log::info!(
+                    "Dropping command #{id} for {device}, as a newer {} command replaces it",
+                    kind.unwrap_or_default()
+                );
+                metrics::increment(Counter::CommandsSuperseded, &[("sku", &device.sku)]);
+                return Ok(None);
+            }
+        };
+        device_log!(
+            log::Level::Debug,
+            &device.id,
+            "command #{} ({}) started after {:?}",
+            permit.id(),
+            kind.unwrap_or("-"),
+            queued.elapsed()
+        );
         let (tx, rx) = tokio::sync::oneshot::channel();
 
         // Schedule a task that will poll the device a short
@@ -115,7 +514,7 @@ impl State {
             state.poll_after_control(device_id).await
         });
 
-        Ok(Coordinator::new(device, permit, tx))
+        Ok(Some(Coordinator::new(device, permit, tx)))
     }
 
     /// Resolve a device using its name, computed name, id or label,
@@ -148,10 +547,170 @@ impl State {
         self.hass_client.lock().await.replace(client);
     }
 
+    /// Records the unique ids of the one-click buttons that are being
+    /// published, returning those that were published previously,
+    /// either by this run or, via the cache, by a prior run
+    pub async fn replace_one_click_button_ids(
+        &self,
+        ids: HashSet<String>,
+    ) -> Option<HashSet<String>> {
+        let mut current = self.one_click_button_ids.lock().await;
+        if current.as_ref() == Some(&ids) {
+            return current.clone();
+        }
+        let prior = match current.take() {
+            Some(prior) => Some(prior),
+            None => {
+                load_value(ONE_CLICK_CACHE_TOPIC, ONE_CLICK_BUTTON_IDS_KEY).unwrap_or_else(|err| {
+                    log::warn!("Unable to load the cached one-click button ids: {err:#}");
+                    None
+                })
+            }
+        };
+        if let Err(err) = store_value(
+            ONE_CLICK_CACHE_TOPIC,
+            ONE_CLICK_BUTTON_IDS_KEY,
+            &ids,
+            ONE_CLICK_BUTTON_IDS_TTL,
+        ) {
+            log::warn!("Unable to cache the one-click button ids: {err:#}");
+        }
+        current.replace(ids);
+        prior
+    }
+
+    pub async fn set_pending_migrations(&self, keys: Vec<String>) {
+        *self.pending_migrations.lock().await = keys;
+    }
+
+    pub async fn take_pending_migrations(&self) -> Vec<String> {
+        std::mem::take(&mut *self.pending_migrations.lock().await)
+    }
+
+    pub async fn replace_room_light_ids(&self, ids: HashSet<String>) -> Option<HashSet<String>> {
+        self.room_light_ids.lock().await.replace(ids)
+    }
+
+    pub async fn device_groups(&self) -> Vec<DeviceGroup> {
+        self.device_groups.lock().await.clone()
+    }
+
+    /// Returns the rooms, which the device list calls groups, that
+    /// contain two or more lights, along with those lights
+    pub async fn rooms_with_lights(&self) -> Vec<(DeviceGroup, Vec<Device>)> {
+        let mut result = vec![];
+        for group in self.device_groups().await {
+            let mut lights = vec![];
+            for id in &group.members {
+                if let Some(device) = self.device_by_id(id).await {
+                    if device.device_type() == DeviceType::Light {
+                        lights.push(device);
+                    }
+                }
+            }
+            if lights.len() >= 2 {
+                result.push((group, lights));
+            }
+        }
+        result
+    }
+
+    /// Records that we published a discovery config to the topic,
+    /// or, if !published, that we removed it
+    pub async fn record_discovery_topic(&self, topic: &str, published: bool) {
+        let mut topics = self.published_discovery_topics.lock().await;
+        if published {
+            topics.insert(topic.to_string());
+        } else {
+            topics.remove(topic);
+            self.published_discovery_configs.lock().await.remove(topic);
+        }
+    }
+
+    /// When set, discovery configs are always re-published and the
+    /// device list is always re-processed, even if they are unchanged
+    pub async fn set_full_refresh(&self, full_refresh: bool) {
+        *self.full_refresh.lock().await = full_refresh;
+    }
+
+    /// Returns true if config is what we last published to topic,
+    /// in which case there is no need to publish it again
+    pub async fn is_discovery_config_unchanged(&self, topic: &str, config: &str) -> bool {
+        if *self.full_refresh.lock().await {
+            return false;
+        }
+        self.published_discovery_configs
+            .lock()
+            .await
+            .get(topic)
+            .map(|prior| prior == config)
+            .unwrap_or(false)
+    }
+
+    pub async fn record_discovery_config(&self, topic: &str, config: String) {
+        self.published_discovery_configs
+            .lock()
+            .await
+            .insert(topic.to_string(), config);
+    }
+
+    /// Forgets which configs were published, so that they will all
+    /// be published again, such as when hass restarts
+    pub async fn forget_discovery_configs(&self) {
+        self.published_discovery_configs.lock().await.clear();
+    }
+
+    /// Records the hash of the platform API device list, returning
+    /// true if it differs from the one that was previously recorded
+    pub async fn record_platform_device_list_hash(&self, hash: &str) -> bool {
+        let full_refresh = *self.full_refresh.lock().await;
+        let mut prior = self.platform_device_list_hash.lock().await;
+        if prior.as_deref() == Some(hash) && !full_refresh {
+            return false;
+        }
+        prior.replace(hash.to_string());
+        true
+    }
+
+    pub async fn published_discovery_topics(&self) -> HashSet<String> {
+        self.published_discovery_topics.lock().await.clone()
+    }
+
+    /// Starts collecting retained discovery configs, see
+    /// record_discovery_scan_result
+    pub async fn begin_discovery_scan(&self) {
+        self.discovery_scan.lock().await.replace(vec![]);
+    }
+
+    /// Records a retained discovery config if a scan is in progress,
+    /// returning true if it was recorded
+    pub async fn record_discovery_scan_result(&self, topic: &str, payload: &[u8]) -> bool {
+        match self.discovery_scan.lock().await.as_mut() {
+            Some(results) => {
+                results.push((topic.to_string(), payload.to_vec()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn end_discovery_scan(&self) -> Vec<RetainedMessage> {
+        self.discovery_scan.lock().await.take().unwrap_or_default()
+    }
+
     pub async fn get_hass_client(&self) -> Option<HassClient> {
         self.hass_client.lock().await.clone()
     }
 
+    pub async fn set_mqtt_connected(&self, connected: bool) {
+        *self.mqtt_connected.lock().await = connected;
+    }
+
+    /// Returns true if we are currently connected to the hass broker
+    pub async fn is_mqtt_connected(&self) -> bool {
+        *self.mqtt_connected.lock().await
+    }
+
     pub async fn set_iot_client(&self, client: IotClient) {
         self.iot_client.lock().await.replace(client);
     }
@@ -160,6 +719,21 @@ impl State {
         self.iot_client.lock().await.clone()
     }
 
+    pub async fn update_iot_health<R>(&self, f: impl FnOnce(&mut IotConnectionHealth) -> R) -> R {
+        f(&mut *self.iot_health.lock().await)
+    }
+
+    pub async fn get_iot_health(&self) -> IotConnectionHealth {
+        self.iot_health.lock().await.clone()
+    }
+
+    /// Returns true if the IoT client was started, even if it
+    /// hasn't managed to connect yet
+    pub async fn iot_started(&self) -> bool {
+        self.get_iot_client().await.is_some()
+            || self.iot_health.lock().await.disconnected_since.is_some()
+    }
+
     pub async fn set_lan_client(&self, client: LanClient) {
         self.lan_client.lock().await.replace(client);
     }
@@ -176,15 +750,179 @@ impl State {
         self.platform_client.lock().await.clone()
     }
 
+    /// Returns the platform client for controlling the device,
+    /// unless its configuration asks for the Platform API to be
+    /// bypassed
+    async fn platform_client_for(&self, device: &Device) -> Option<GoveeApiClient> {
+        if device.platform_api_bypassed() {
+            log::trace!("Not using the Platform API for {device}, per its configuration");
+            return None;
+        }
+        self.get_platform_client().await
+    }
+
+    /// Returns the time until which platform API requests are suspended
+    /// because we exceeded the request quota, or None if they are permitted
+    pub async fn platform_api_rate_limited_until(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.get_platform_client()
+            .await
+            .and_then(|client| client.rate_limited_until())
+    }
+
+    pub async fn set_reloadable_args(&self, args: ReloadableArgs) {
+        self.reloadable_args.lock().await.replace(args);
+    }
+
+    pub async fn get_reloadable_args(&self) -> Option<ReloadableArgs> {
+        self.reloadable_args.lock().await.clone()
+    }
+
     pub async fn set_undoc_client(&self, client: GoveeUndocumentedApi) {
         self.undoc_client.lock().await.replace(client);
     }
 
-    #[allow(dead_code)]
     pub async fn get_undoc_client(&self) -> Option<GoveeUndocumentedApi> {
         self.undoc_client.lock().await.clone()
     }
 
+    /// Applies the device and room list returned from the undocumented
+    /// API to our devices, returning the ids of the devices that were updated,
+    /// along with whether the room that they are assigned to, or the gateway
+    /// that they are paired with, changed
+    pub async fn apply_undoc_device_list(&self, info: DevicesResponse) -> Vec<(String, bool)> {
+        let mut groups: Vec<DeviceGroup> = info
+            .groups
+            .iter()
+            .map(|group| DeviceGroup {
+                id: group.group_id,
+                name: group.group_name.clone(),
+                members: info
+                    .devices
+                    .iter()
+                    .filter(|entry| entry.group_id == group.group_id && !entry.device.is_empty())
+                    .map(|entry| entry.device.to_string())
+                    .collect(),
+            })
+            .collect();
+        for group in &mut groups {
+            group.members.sort();
+        }
+        *self.device_groups.lock().await = groups;
+
+        let mut group_by_id = HashMap::new();
+        for group in info.groups {
+            group_by_id.insert(group.group_id, group.group_name);
+        }
+        let mut updated = vec![];
+        for entry in info.devices {
+            let mut device = self.device_mut(&entry.sku, &entry.device).await;
+            let prior_room = device.room_name().map(|name| name.to_string());
+            let prior_gateway = device.gateway_id().map(|id| id.to_string());
+            let had_info = device.undoc_device_info.is_some();
+            let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
+            device.set_undoc_device_info(entry, room_name);
+            let changed = had_info
+                && (prior_room.as_deref() != device.room_name()
+                    || prior_gateway.as_deref() != device.gateway_id());
+            updated.push((device.id.to_string(), changed));
+        }
+        updated
+    }
+
+    /// Re-fetches the device list from the undocumented API, so that
+    /// the information that is only available from it, such as the
+    /// wifi signal strength, is kept current
+    pub async fn poll_undoc_device_list(self: &Arc<Self>) -> anyhow::Result<()> {
+        let Some(client) = self.get_undoc_client().await else {
+            return Ok(());
+        };
+        let acct = client.login_account_cached().await?;
+        let info = client.get_device_list(&acct.token).await?;
+        let prior_groups = self.device_groups().await;
+        let updated = self.apply_undoc_device_list(info).await;
+        if self.device_groups().await != prior_groups {
+            log::info!("The rooms changed, re-publishing the room lights");
+            if let Some(hass) = self.get_hass_client().await {
+                hass.republish_room_lights(self)
+                    .await
+                    .context("hass.republish_room_lights")?;
+            }
+        }
+        for (id, changed) in updated {
+            self.notify_of_state_change(&id)
+                .await
+                .context("state.notify_of_state_change")?;
+            if !changed {
+                continue;
+            }
+            // The suggested_area of the hass device is the room name.
+            // hass only applies it to devices that have no area yet, so
+            // this doesn't undo an area that the user assigned in hass.
+            let Some(device) = self.device_by_id(&id).await else {
+                continue;
+            };
+            log::info!(
+                "{device} is now in room {:?}, via gateway {:?}, re-publishing its entities",
+                device.room_name(),
+                device.gateway_id()
+            );
+            if let Some(hass) = self.get_hass_client().await {
+                hass.republish_device(&device, self)
+                    .await
+                    .context("hass.republish_device")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-fetches the device list from the platform API and re-publishes
+    /// the entity configs for any device whose list of scenes has changed,
+    /// such as when a snapshot or DIY scene was created or deleted in the
+    /// Govee app, so that the scene options presented in hass are current.
+    pub async fn poll_platform_scene_lists(self: &Arc<Self>) -> anyhow::Result<()> {
+        let Some(client) = self.get_platform_client().await else {
+            return Ok(());
+        };
+        let list = client.get_device_list().await?;
+        let list_changed = self.record_platform_device_list_hash(&list.hash).await;
+        if list_changed {
+            for info in list.devices {
+                let mut device = self.device_mut(&info.sku, &info.device).await;
+                device.set_http_device_info(info);
+            }
+        } else {
+            log::trace!("Platform API device list is unchanged");
+        }
+
+        for device in self.devices().await {
+            if device.http_device_info.is_none() {
+                continue;
+            }
+            let scenes = match self.device_list_scenes(&device).await {
+                Ok(scenes) => scenes,
+                Err(err) => {
+                    log::error!("while listing scenes for {device}: {err:#}");
+                    continue;
+                }
+            };
+
+            let prior = self
+                .scene_lists_by_id
+                .lock()
+                .await
+                .insert(device.id.to_string(), scenes.clone());
+            if prior.map(|prior| prior != scenes).unwrap_or(false) {
+                log::info!("Scene list for {device} changed, re-publishing its entities");
+                if let Some(hass) = self.get_hass_client().await {
+                    hass.republish_device(&device, self)
+                        .await
+                        .context("hass.republish_device")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub async fn poll_iot_api(self: &Arc<Self>, device: &Device) -> anyhow::Result<bool> {
         if let Some(iot) = self.get_iot_client().await {
             if let Some(info) = device.undoc_device_info.clone() {
@@ -233,6 +971,9 @@ impl State {
                     device.set_http_device_state(http_state);
                     device.set_last_polled();
                 }
+                if let Err(err) = self.update_reported_scene(&device.id).await {
+                    log::warn!("while resolving the active scene of {device}: {err:#}");
+                }
                 self.notify_of_state_change(&device.id)
                     .await
                     .context("state.notify_of_state_change")?;
@@ -246,44 +987,321 @@ impl State {
         Ok(false)
     }
 
-    async fn poll_lan_api<F: Fn(&LanDeviceStatus) -> bool>(
+    /// Feeds a reading from a BLE advertisement to the device with
+    /// the matching MAC, if we know of one; sensors that are paired
+    /// to a gateway otherwise only update when the cloud is polled.
+    /// Returns false if no device matched.
+    async fn ingest_ble_reading(
         self: &Arc<Self>,
-        device: &LanDevice,
-        acceptor: F,
-    ) -> anyhow::Result<()> {
-        match self.get_lan_client().await {
-            Some(client) => {
-                let deadline = Instant::now() + Duration::from_secs(5);
-                while Instant::now() <= deadline {
-                    let status = client.query_status(device).await?;
-                    let accepted = (acceptor)(&status);
-                    self.device_mut(&device.sku, &device.device)
-                        .await
-                        .set_lan_device_status(status);
-                    if accepted {
-                        break;
-                    }
-                    sleep(Duration::from_millis(100)).await;
+        mac: &str,
+        reading: BleReading,
+    ) -> anyhow::Result<bool> {
+        let device_id = {
+            let mut devices = self.devices_by_id.lock().await;
+            match devices
+                .values_mut()
+                .find(|device| device.matches_ble_mac(mac))
+            {
+                Some(device) => {
+                    log::trace!("BLE reading for {device}: {reading:?}");
+                    device.set_ble_reading(reading);
+                    device.id.clone()
                 }
-                self.notify_of_state_change(&device.device).await?;
-                Ok(())
+                None => return Ok(false),
             }
-            None => anyhow::bail!("no lan client"),
-        }
+        };
+        self.notify_of_state_change(&device_id).await?;
+        Ok(true)
     }
 
-    pub async fn device_control<V: Into<JsonValue>>(
+    /// Limits the BLE advertisements that are acted upon to those
+    /// from these MACs. All are accepted if the list is empty.
+    pub async fn set_ble_allow_list(&self, macs: Vec<String>) {
+        *self.ble_allow_list.lock().await = macs;
+    }
+
+    /// Feeds a reading decoded from the BLE advertisement of `mac` to
+    /// the matching device, adding a BLE-only device for it if it isn't
+    /// one of the devices in the Govee account.
+    /// Returns false if the MAC isn't in the allow-list.
+    #[cfg_attr(not(feature = "ble"), allow(dead_code))]
+    pub async fn ingest_ble_advertisement(
         self: &Arc<Self>,
-        device: &Device,
-        capability: &DeviceCapability,
-        value: V,
-    ) -> anyhow::Result<()> {
-        let value: JsonValue = value.into();
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to send {value:?} control to {device}");
-                client.control_device(info, capability, value).await?;
-                return Ok(());
+        mac: &str,
+        sku: &str,
+        reading: BleReading,
+    ) -> anyhow::Result<bool> {
+        let Some(mac) = canonical_mac(mac) else {
+            return Ok(false);
+        };
+        {
+            let allowed = self.ble_allow_list.lock().await;
+            if !allowed.is_empty() && !allowed.contains(&mac) {
+                log::trace!("Ignoring BLE advertisement from {mac}: not in the allow-list");
+                return Ok(false);
+            }
+        }
+        if self.ingest_ble_reading(&mac, reading).await? {
+            return Ok(true);
+        }
+
+        let device = {
+            let mut device = self.device_mut(sku, &mac).await;
+            device.set_ble_reading(reading);
+            device.clone()
+        };
+        log::info!("Discovered BLE device {device}: {reading:?}");
+        if let Some(hass) = self.get_hass_client().await {
+            hass.republish_device(&device, self)
+                .await
+                .context("hass.republish_device")?;
+        }
+        self.report_device_availability(&device, true).await?;
+        Ok(true)
+    }
+
+    /// Requests the status of a LAN device, keeping track of how many
+    /// consecutive requests it has failed to respond to, so that it
+    /// can be marked unavailable if it stops responding.
+    pub async fn poll_lan_status(self: &Arc<Self>, device: &Device) -> anyhow::Result<()> {
+        let (Some(client), Some(lan_device)) = (self.get_lan_client().await, &device.lan_device)
+        else {
+            return Ok(());
+        };
+
+        match client.query_status(lan_device).await {
+            Ok(status) => {
+                let changed = self
+                    .device_mut(&device.sku, &device.id)
+                    .await
+                    .set_lan_device_status(status);
+                if changed {
+                    self.notify_of_state_change(&device.id).await?;
+                }
+            }
+            Err(err) => {
+                let missed = self
+                    .device_mut(&device.sku, &device.id)
+                    .await
+                    .record_missed_lan_poll();
+                log::debug!("{device} missed LAN status request {missed}: {err:#}");
+            }
+        }
+
+        if let Some(device) = self.device_by_id(&device.id).await {
+            self.report_device_availability(&device, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Applies the statically configured LAN devices, registering
+    /// those whose identity is configured without waiting for them
+    /// to respond to discovery, and forgetting the LAN information
+    /// of devices that were removed from the list.
+    /// Returns true if the list changed.
+    pub async fn apply_static_lan_devices(self: &Arc<Self>, devices: Vec<StaticLanDevice>) -> bool {
+        let Some(client) = self.get_lan_client().await else {
+            if !devices.is_empty() {
+                log::warn!("LAN devices are configured, but the LAN API is not in use");
+            }
+            return false;
+        };
+
+        let prior = std::mem::replace(&mut *self.static_lan_devices.lock().await, devices.clone());
+        client
+            .set_static_addresses(devices.iter().map(|device| device.ip).collect())
+            .await;
+
+        for removed in prior.iter().filter(|device| !devices.contains(device)) {
+            for device in self.devices().await {
+                if device.ip_addr() == Some(removed.ip) {
+                    log::info!("{device} is no longer configured at {}", removed.ip);
+                    self.device_mut(&device.sku, &device.id)
+                        .await
+                        .clear_lan_device();
+                }
+            }
+        }
+
+        for added in devices.iter().filter(|device| !prior.contains(device)) {
+            if let Some(lan_device) = added.to_lan_device() {
+                let device = {
+                    let mut device = self.device_mut(&lan_device.sku, &lan_device.device).await;
+                    if device.ip_addr() != Some(lan_device.ip) {
+                        log::info!("{device} is configured at {}", lan_device.ip);
+                        device.set_lan_device(lan_device);
+                    }
+                    device.clone()
+                };
+                let state = self.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = state.poll_lan_status(&device).await {
+                        log::error!("while polling LAN status of {device}: {err:#}");
+                    }
+                });
+            }
+
+            // Ask for its details; the response is routed via discovery
+            let client = client.clone();
+            let ip = added.ip;
+            tokio::spawn(async move {
+                if let Err(err) = client.scan_ip(ip).await {
+                    log::warn!("Configured LAN device {ip} didn't respond to a scan: {err:#}");
+                }
+            });
+        }
+
+        prior != devices
+    }
+
+    /// Polls the device via the LAN API until `acceptor` accepts its
+    /// status, to confirm a command that was just sent to it.
+    /// Failures are reported as `Unconfirmed`.
+    async fn poll_lan_api<F: Fn(&LanDeviceStatus) -> bool>(
+        self: &Arc<Self>,
+        device: &LanDevice,
+        acceptor: F,
+    ) -> anyhow::Result<()> {
+        self.await_lan_status(device, acceptor)
+            .await
+            .map_err(|err| Unconfirmed(err).into())
+    }
+
+    async fn await_lan_status<F: Fn(&LanDeviceStatus) -> bool>(
+        self: &Arc<Self>,
+        device: &LanDevice,
+        acceptor: F,
+    ) -> anyhow::Result<()> {
+        match self.get_lan_client().await {
+            Some(client) => {
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while Instant::now() <= deadline {
+                    let status = client.query_status(device).await?;
+                    let accepted = (acceptor)(&status);
+                    self.device_mut(&device.sku, &device.device)
+                        .await
+                        .set_lan_device_status(status);
+                    if accepted {
+                        break;
+                    }
+                    sleep(Duration::from_millis(100)).await;
+                }
+                self.notify_of_state_change(&device.device).await?;
+                Ok(())
+            }
+            None => anyhow::bail!("no lan client"),
+        }
+    }
+
+    /// Returns the transport that the power, brightness and color
+    /// control methods will try first for the device, following its
+    /// control order
+    pub async fn control_transport(&self, device: &Device) -> Option<&'static str> {
+        for transport in device.control_order() {
+            let available = match transport {
+                Transport::Lan => device.responsive_lan_device().is_some(),
+                Transport::Iot => self.iot_control_for(device).await.is_some(),
+                Transport::Platform => self.platform_control_for(device).await.is_some(),
+            };
+            if available {
+                return Some(transport.as_str());
+            }
+        }
+        None
+    }
+
+    /// Returns the transport that most recently carried out a power,
+    /// brightness or color command for the device
+    pub async fn last_control_transport(&self, device: &Device) -> Option<&'static str> {
+        self.served_transports
+            .lock()
+            .await
+            .get(&device.id)
+            .map(|transport| transport.as_str())
+    }
+
+    /// Returns the IoT client and device info to control the device
+    /// via AWS IoT, if it can be
+    async fn iot_control_for<'a>(
+        &self,
+        device: &'a Device,
+    ) -> Option<(IotClient, &'a UndocDeviceInfo)> {
+        if !device.iot_api_supported() {
+            return None;
+        }
+        let info = device.undoc_device_info.as_ref()?;
+        Some((self.get_iot_client().await?, info))
+    }
+
+    /// Returns the client and device info to control the device via
+    /// the Platform API, if it can be
+    async fn platform_control_for<'a>(
+        &self,
+        device: &'a Device,
+    ) -> Option<(GoveeApiClient, &'a HttpDeviceInfo)> {
+        let info = device.http_device_info.as_ref()?;
+        Some((self.platform_client_for(device).await?, info))
+    }
+
+    /// Sends a command via each transport in the control order of the
+    /// device in turn, until one of them succeeds. `send` returns false
+    /// when the transport isn't available for the device, and an error
+    /// when it failed, in which case the next transport is tried, unless
+    /// the error is `Unconfirmed` because the command was already sent.
+    /// `what` describes the command for the logs.
+    async fn control_in_order<F, Fut>(
+        &self,
+        device: &Device,
+        what: &str,
+        send: F,
+    ) -> anyhow::Result<()>
+    where
+        F: Fn(Transport) -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<bool>>,
+    {
+        let mut failure = None;
+        for transport in device.control_order() {
+            let result = send(transport).await;
+            let sent = match &result {
+                Ok(sent) => *sent,
+                Err(err) => Unconfirmed::from_err(err).is_some(),
+            };
+            if sent {
+                metrics::increment(
+                    Counter::CommandsSent,
+                    &[("transport", transport.as_str()), ("sku", &device.sku)],
+                );
+                self.served_transports
+                    .lock()
+                    .await
+                    .insert(device.id.to_string(), transport);
+                return result
+                    .map(|_| ())
+                    .with_context(|| format!("setting {what} for {device} via {transport}"));
+            }
+            if let Err(err) = result {
+                log::warn!("Failed to set {what} for {device} via {transport}: {err:#}");
+                failure = Some(err);
+            }
+        }
+        match failure {
+            Some(err) => Err(err),
+            None => anyhow::bail!("Unable to control {what} for {device}"),
+        }
+    }
+
+    pub async fn device_control<V: Into<JsonValue>>(
+        self: &Arc<Self>,
+        device: &Device,
+        capability: &DeviceCapability,
+        value: V,
+    ) -> anyhow::Result<()> {
+        let value: JsonValue = value.into();
+        if let Some(client) = self.get_platform_client().await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to send {value:?} control to {device}");
+                client.control_device(info, capability, value).await?;
+                return Ok(());
             }
         }
 
@@ -311,65 +1329,160 @@ impl State {
                 )
             })?;
 
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} light power state");
-            lan_dev.send_turn(on).await?;
-            self.poll_lan_api(lan_dev, |status| status.on == on).await?;
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
+        self.control_in_order(device, "light power state", |transport| async move {
+            match transport {
+                Transport::Lan => {
+                    let Some(lan_dev) = device.responsive_lan_device() else {
+                        return Ok(false);
+                    };
+                    log::info!("Using LAN API to set {device} light power state");
+                    lan_dev.send_turn(on).await?;
+                    self.poll_lan_api(lan_dev, |status| status.on == on).await?;
+                }
+                Transport::Iot => {
+                    let Some((iot, info)) = self.iot_control_for(device).await else {
+                        return Ok(false);
+                    };
                     log::info!("Using IoT API to set {device} light power state");
                     iot.set_power_state(&info.entry, on).await?;
-                    return Ok(());
+                    self.apply_optimistic_change(device, ExpectedChange::Power(on))
+                        .await;
+                }
+                Transport::Platform => {
+                    let Some((client, info)) = self.platform_control_for(device).await else {
+                        return Ok(false);
+                    };
+                    log::info!("Using Platform API to set {device} light {instance_name} state");
+                    client.set_toggle_state(info, instance_name, on).await?;
+                    self.apply_optimistic_change(device, ExpectedChange::LightPower(on))
+                        .await;
                 }
             }
+            Ok(true)
+        })
+        .await
+    }
+
+    pub async fn device_power_on(
+        self: &Arc<Self>,
+        device: &Device,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        if !on && device.device_type() == DeviceType::IceMaker {
+            // Cutting the power mid-cycle leaves the ice maker in
+            // a bad state; ask it to stop the cycle instead
+            let stop = ParsedWorkMode::with_device(device).ok().and_then(|modes| {
+                let stop = modes.stop_mode()?;
+                Some((stop.value.as_i64()?, stop.default_value()))
+            });
+            if let Some((mode_num, value)) = stop {
+                log::info!("Using stop work mode to turn off {device}");
+                return self.humidifier_set_parameter(device, mode_num, value).await;
+            }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        self.control_in_order(device, "power state", |transport| async move {
+            match transport {
+                Transport::Lan => {
+                    let Some(lan_dev) = device.responsive_lan_device() else {
+                        return Ok(false);
+                    };
+                    log::info!("Using LAN API to set {device} power state");
+                    lan_dev.send_turn(on).await?;
+                    self.poll_lan_api(lan_dev, |status| status.on == on).await?;
+                }
+                Transport::Iot => {
+                    let Some((iot, info)) = self.iot_control_for(device).await else {
+                        return Ok(false);
+                    };
+                    log::info!("Using IoT API to set {device} power state");
+                    iot.set_power_state(&info.entry, on).await?;
+                    self.apply_optimistic_change(device, ExpectedChange::Power(on))
+                        .await;
+                }
+                Transport::Platform => {
+                    let Some((client, info)) = self.platform_control_for(device).await else {
+                        return Ok(false);
+                    };
+                    log::info!("Using Platform API to set {device} power state");
+                    client.set_power_state(info, on).await?;
+                    self.apply_optimistic_change(device, ExpectedChange::Power(on))
+                        .await;
+                }
+            }
+            Ok(true)
+        })
+        .await
+    }
+
+    pub async fn device_cover_command(
+        self: &Arc<Self>,
+        device: &Device,
+        command: CoverCommand,
+    ) -> anyhow::Result<()> {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} light {instance_name} state");
-                client.set_toggle_state(info, instance_name, on).await?;
+                log::info!("Using Platform API to {command} {device}");
+                client.set_cover_command(info, command).await?;
                 return Ok(());
             }
         }
 
-        anyhow::bail!("Unable to control light power state for {device}");
+        anyhow::bail!("Unable to {command} {device}");
     }
 
-    pub async fn device_power_on(
+    pub async fn device_set_cover_position(
         self: &Arc<Self>,
         device: &Device,
-        on: bool,
+        percent: u8,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} power state");
-            lan_dev.send_turn(on).await?;
-            self.poll_lan_api(lan_dev, |status| status.on == on).await?;
-            return Ok(());
+        if let Some(client) = self.get_platform_client().await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to set {device} cover position to {percent}");
+                client.set_cover_position(info, percent).await?;
+                return Ok(());
+            }
         }
 
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
-                    log::info!("Using IoT API to set {device} power state");
-                    iot.set_power_state(&info.entry, on).await?;
-                    return Ok(());
-                }
+        anyhow::bail!("Unable to set cover position for {device}");
+    }
+
+    pub async fn device_set_valve(
+        self: &Arc<Self>,
+        device: &Device,
+        instance_name: &str,
+        open: bool,
+    ) -> anyhow::Result<()> {
+        if let Some(client) = self.get_platform_client().await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to set {device} {instance_name} to {open}");
+                client.set_valve_state(info, instance_name, open).await?;
+                return Ok(());
             }
         }
 
+        anyhow::bail!("Unable to control {instance_name} for {device}");
+    }
+
+    pub async fn device_set_siren(
+        self: &Arc<Self>,
+        device: &Device,
+        instance_name: &str,
+        on: bool,
+        tone: Option<String>,
+        duration: Option<u32>,
+    ) -> anyhow::Result<()> {
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} power state");
-                client.set_power_state(info, on).await?;
+                log::info!("Using Platform API to set {device} {instance_name} to {on}");
+                client
+                    .set_siren_state(info, instance_name, on, tone.as_deref(), duration)
+                    .await?;
                 return Ok(());
             }
         }
 
-        anyhow::bail!("Unable to control power state for {device}");
+        anyhow::bail!("Unable to control {instance_name} for {device}");
     }
 
     pub async fn device_set_brightness(
@@ -387,32 +1500,39 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} brightness");
-            lan_dev.send_brightness(percent).await?;
-            self.poll_lan_api(lan_dev, |status| status.brightness == percent)
-                .await?;
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
+        self.control_in_order(device, "brightness", |transport| async move {
+            match transport {
+                Transport::Lan => {
+                    let Some(lan_dev) = device.responsive_lan_device() else {
+                        return Ok(false);
+                    };
+                    log::info!("Using LAN API to set {device} brightness");
+                    lan_dev.send_brightness(percent).await?;
+                    self.poll_lan_api(lan_dev, |status| status.brightness == percent)
+                        .await?;
+                }
+                Transport::Iot => {
+                    let Some((iot, info)) = self.iot_control_for(device).await else {
+                        return Ok(false);
+                    };
                     log::info!("Using IoT API to set {device} brightness");
                     iot.set_brightness(&info.entry, percent).await?;
-                    return Ok(());
+                    self.apply_optimistic_change(device, ExpectedChange::Brightness(percent))
+                        .await;
+                }
+                Transport::Platform => {
+                    let Some((client, info)) = self.platform_control_for(device).await else {
+                        return Ok(false);
+                    };
+                    log::info!("Using Platform API to set {device} brightness");
+                    client.set_brightness(info, percent).await?;
+                    self.apply_optimistic_change(device, ExpectedChange::Brightness(percent))
+                        .await;
                 }
             }
-        }
-
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} brightness");
-                client.set_brightness(info, percent).await?;
-                return Ok(());
-            }
-        }
-        anyhow::bail!("Unable to control brightness for {device}");
+            Ok(true)
+        })
+        .await
     }
 
     pub async fn device_set_color_temperature(
@@ -420,38 +1540,42 @@ impl State {
         device: &Device,
         kelvin: u32,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} color temperature");
-            lan_dev.send_color_temperature_kelvin(kelvin).await?;
-            self.poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == kelvin)
-                .await?;
-            self.device_mut(&device.sku, &device.id)
-                .await
-                .set_active_scene(None);
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
+        self.control_in_order(device, "color temperature", |transport| async move {
+            match transport {
+                Transport::Lan => {
+                    let Some(lan_dev) = device.responsive_lan_device() else {
+                        return Ok(false);
+                    };
+                    log::info!("Using LAN API to set {device} color temperature");
+                    lan_dev.send_color_temperature_kelvin(kelvin).await?;
+                    self.poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == kelvin)
+                        .await?;
+                    self.device_mut(&device.sku, &device.id)
+                        .await
+                        .set_active_scene(None);
+                }
+                Transport::Iot => {
+                    let Some((iot, info)) = self.iot_control_for(device).await else {
+                        return Ok(false);
+                    };
                     log::info!("Using IoT API to set {device} color temperature");
                     iot.set_color_temperature(&info.entry, kelvin).await?;
-                    return Ok(());
+                    self.apply_optimistic_change(device, ExpectedChange::ColorTemperature(kelvin))
+                        .await;
+                }
+                Transport::Platform => {
+                    let Some((client, info)) = self.platform_control_for(device).await else {
+                        return Ok(false);
+                    };
+                    log::info!("Using Platform API to set {device} color temperature");
+                    client.set_color_temperature(info, kelvin).await?;
+                    self.apply_optimistic_change(device, ExpectedChange::ColorTemperature(kelvin))
+                        .await;
                 }
             }
-        }
-
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} color temperature");
-                client.set_color_temperature(info, kelvin).await?;
-                self.device_mut(&device.sku, &device.id)
-                    .await
-                    .set_active_scene(None);
-                return Ok(());
-            }
-        }
-        anyhow::bail!("Unable to control color temperature for {device}");
+            Ok(true)
+        })
+        .await
     }
 
     // FIXME: this function probably shouldn't exist here
@@ -461,7 +1585,7 @@ impl State {
         apply: F,
     ) -> anyhow::Result<bool> {
         let mut params: SetHumidifierNightlightParams =
-            device.nightlight_state.clone().unwrap_or_default().into();
+            device.nightlight_state.unwrap_or_default().into();
         (apply)(&mut params);
 
         if let Ok(command) = Base64HexBytes::encode_for_sku(&device.sku, &params) {
@@ -483,6 +1607,10 @@ impl State {
         work_mode: i64,
         value: i64,
     ) -> anyhow::Result<()> {
+        let work_mode_change = ExpectedChange::WorkMode {
+            mode: work_mode as u8,
+            param: value as u8,
+        };
         if let Ok(command) = Base64HexBytes::encode_for_sku(
             &device.sku,
             &SetHumidifierMode {
@@ -493,14 +1621,16 @@ impl State {
             if let Some(iot) = self.get_iot_client().await {
                 if let Some(info) = &device.undoc_device_info {
                     iot.send_real(&info.entry, command.base64()).await?;
+                    self.apply_optimistic_change(device, work_mode_change).await;
                     return Ok(());
                 }
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 client.set_work_mode(info, work_mode, value).await?;
+                self.apply_optimistic_change(device, work_mode_change).await;
                 return Ok(());
             }
         }
@@ -526,39 +1656,66 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
-            let color = crate::lan_api::DeviceColor { r, g, b };
-            log::info!("Using LAN API to set {device} color");
-            lan_dev.send_color_rgb(color).await?;
-            self.poll_lan_api(lan_dev, |status| status.color == color)
-                .await?;
-            self.device_mut(&device.sku, &device.id)
-                .await
-                .set_active_scene(None);
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
+        let color = crate::lan_api::DeviceColor { r, g, b };
+        self.control_in_order(device, "color", |transport| async move {
+            match transport {
+                Transport::Lan => {
+                    let Some(lan_dev) = device.responsive_lan_device() else {
+                        return Ok(false);
+                    };
+                    log::info!("Using LAN API to set {device} color");
+                    lan_dev.send_color_rgb(color).await?;
+                    self.poll_lan_api(lan_dev, |status| status.color == color)
+                        .await?;
+                    self.device_mut(&device.sku, &device.id)
+                        .await
+                        .set_active_scene(None);
+                }
+                Transport::Iot => {
+                    let Some((iot, info)) = self.iot_control_for(device).await else {
+                        return Ok(false);
+                    };
                     log::info!("Using IoT API to set {device} color");
                     iot.set_color_rgb(&info.entry, r, g, b).await?;
-                    return Ok(());
+                    self.apply_optimistic_change(device, ExpectedChange::Color(color))
+                        .await;
+                }
+                Transport::Platform => {
+                    let Some((client, info)) = self.platform_control_for(device).await else {
+                        return Ok(false);
+                    };
+                    log::info!("Using Platform API to set {device} color");
+                    client.set_color_rgb(info, r, g, b).await?;
+                    self.apply_optimistic_change(device, ExpectedChange::Color(color))
+                        .await;
                 }
             }
-        }
+            Ok(true)
+        })
+        .await
+    }
 
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} color");
-                client.set_color_rgb(info, r, g, b).await?;
-                self.device_mut(&device.sku, &device.id)
-                    .await
-                    .set_active_scene(None);
-                return Ok(());
-            }
+    /// Reports the state implied by a command that the IoT or Platform
+    /// API accepted; unlike the LAN API, neither can be asked for the
+    /// resulting state right away. This is published immediately rather
+    /// than via the debounce, so that the entity reflects the command
+    /// even while a burst of other changes is being coalesced.
+    async fn apply_optimistic_change(self: &Arc<Self>, device: &Device, change: ExpectedChange) {
+        let applied = self
+            .device_mut(&device.sku, &device.id)
+            .await
+            .apply_optimistic_change(change);
+        if !applied {
+            return;
+        }
+        device_log!(
+            log::Level::Debug,
+            &device.id,
+            "assuming {change:?} until the device reports its state"
+        );
+        if let Err(err) = self.publish_state_change(&device.id).await {
+            log::error!("publish_state_change for {device}: {err:#}");
         }
-        anyhow::bail!("Unable to control color for {device}");
     }
 
     pub async fn poll_after_control(self: &Arc<Self>, id: String) {
@@ -574,6 +1731,9 @@ impl State {
         if device.pollable_via_lan() {
             return;
         }
+        if device.platform_api_bypassed() {
+            return;
+        }
 
         // Add a slight delay, as the status returned
         // by the platform API isn't guaranteed to be
@@ -581,12 +1741,213 @@ impl State {
         // right away :-/
         sleep(Duration::from_secs(5)).await;
 
+        if self.platform_api_rate_limited_until().await.is_some() {
+            log::debug!("Not polling {device} after control, as the Platform API is rate limited");
+            return;
+        }
+
         log::info!("Polling {device} to get latest state after control");
         if let Err(err) = self.poll_platform_api(&device).await {
             log::error!("Polling {device} failed: {err:#}");
         }
     }
 
+    pub async fn set_lan_only(&self, lan_only: bool) {
+        *self.lan_only.lock().await = lan_only;
+    }
+
+    /// Returns true if Govee's cloud services are not to be used
+    pub async fn is_lan_only(&self) -> bool {
+        *self.lan_only.lock().await
+    }
+
+    /// Describes which APIs are in use, for diagnostic purposes
+    pub async fn operating_mode(&self) -> &'static str {
+        if self.is_lan_only().await {
+            "LAN only"
+        } else {
+            "Cloud and LAN"
+        }
+    }
+
+    pub async fn set_transition_rate(&self, rate: u32) {
+        self.transitions.lock().await.rate = rate;
+    }
+
+    pub async fn set_coarse_cloud_transitions(&self, enabled: bool) {
+        self.transitions.lock().await.coarse_cloud = enabled;
+    }
+
+    /// Stops any transition that is in progress for the device, so
+    /// that it doesn't fight with a subsequent command
+    pub async fn cancel_light_transition(&self, id: &str) {
+        if let Some(task) = self.transitions.lock().await.tasks.remove(id) {
+            task.abort();
+        }
+    }
+
+    /// Returns the brightness that the device had before it was
+    /// faded out, if it was, forgetting it
+    pub async fn take_faded_brightness(&self, id: &str) -> Option<u8> {
+        self.transitions.lock().await.faded_brightness.remove(id)
+    }
+
+    /// Starts a transition via the LAN API, or, if coarse cloud
+    /// transitions are enabled, in a few steps via the cloud.
+    /// Returns false if the transition cannot be performed, in which
+    /// case the command should be applied immediately instead.
+    pub async fn start_light_transition(
+        self: &Arc<Self>,
+        device: &Device,
+        request: TransitionRequest,
+        faded_brightness: Option<u8>,
+        duration: Duration,
+    ) -> bool {
+        let mut transitions = self.transitions.lock().await;
+        if transitions.rate == 0 || duration.is_zero() {
+            return false;
+        }
+        let lan_device = device.responsive_lan_device().cloned();
+        let cloud_steps = if lan_device.is_none() && transitions.coarse_cloud {
+            let min_gap = self.command_intervals.lock().await.cloud;
+            cloud_step_count(duration, min_gap)
+        } else {
+            0
+        };
+        if lan_device.is_none() && cloud_steps == 0 {
+            return false;
+        }
+        let Some(transition) =
+            Transition::new(device.device_state().as_ref(), &request, faded_brightness)
+        else {
+            return false;
+        };
+
+        if transition.power_off {
+            transitions
+                .faded_brightness
+                .insert(device.id.to_string(), transition.from.brightness);
+        }
+        if request.color.is_some() || request.kelvin.is_some() {
+            self.device_mut(&device.sku, &device.id)
+                .await
+                .set_active_scene(None);
+        }
+
+        let rate = transitions.rate;
+        let state = self.clone();
+        let device = device.clone();
+        let id = device.id.to_string();
+        let task = tokio::spawn(async move {
+            let result = match &lan_device {
+                Some(lan_device) => {
+                    log::info!("Using LAN API to transition {device} over {duration:?}");
+                    run_lan_transition(&state, &device, lan_device, transition, duration, rate)
+                        .await
+                }
+                None => {
+                    log::info!("Transitioning {device} over {duration:?} in {cloud_steps} steps");
+                    run_cloud_transition(&state, &device, transition, duration, cloud_steps).await
+                }
+            };
+            if let Err(err) = result {
+                log::error!("Transition for {device} failed: {err:#}");
+            }
+        });
+        if let Some(prior) = transitions.tasks.insert(id, task.abort_handle()) {
+            prior.abort();
+        }
+        true
+    }
+
+    /// Queues up a change to a segment of an RGBIC device. The change
+    /// is sent after a short delay, combined with any other changes
+    /// to segments of the same device that arrived in the meantime.
+    pub async fn device_set_segment(
+        self: &Arc<Self>,
+        device: &Device,
+        segment: u32,
+        rgb: Option<u32>,
+        brightness: Option<u8>,
+    ) -> anyhow::Result<()> {
+        if device.lan_segment_device().is_none()
+            && (self.platform_client_for(device).await.is_none()
+                || device.http_device_info.is_none())
+        {
+            anyhow::bail!("set segments for {device}: Platform API is not available");
+        }
+
+        let mut pending = self.pending_segment_updates.lock().await;
+        let updates = pending.entry(device.id.to_string()).or_default();
+        let need_flush = updates.is_empty();
+
+        if let Some(rgb) = rgb {
+            updates.rgb.insert(segment, rgb);
+        }
+        if let Some(brightness) = brightness {
+            updates.brightness.insert(segment, brightness);
+        }
+
+        if need_flush && !updates.is_empty() {
+            let state = self.clone();
+            let device_id = device.id.to_string();
+            tokio::spawn(async move {
+                sleep(SEGMENT_BATCH_DELAY).await;
+                if let Err(err) = state.flush_segment_updates(&device_id).await {
+                    log::error!("flush_segment_updates for {device_id}: {err:#}");
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn flush_segment_updates(self: &Arc<Self>, device_id: &str) -> anyhow::Result<()> {
+        let Some(updates) = self.pending_segment_updates.lock().await.remove(device_id) else {
+            return Ok(());
+        };
+        let device = self
+            .device_by_id(device_id)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("cannot find device {device_id}!?"))?;
+
+        if let Some(lan_dev) = device.lan_segment_device() {
+            log::info!("Using LAN API to control {device} segments: {updates:?}");
+            match send_lan_segment_updates(lan_dev, &updates).await {
+                Ok(()) => return Ok(()),
+                Err(err) if device.http_device_info.is_some() => {
+                    log::warn!(
+                        "Failed to control {device} segments via the LAN API, \
+                         will try the Platform API: {err:#}"
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let client = self
+            .platform_client_for(&device)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Platform API is not available"))?;
+        let info = device
+            .http_device_info
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("HTTP device info is missing"))?;
+
+        log::info!("Using Platform API to control {device} segments: {updates:?}");
+
+        for (brightness, segments) in group_segments_by_value(&updates.brightness) {
+            client
+                .set_segment_brightness(info, &segments, brightness)
+                .await?;
+        }
+        for (rgb, segments) in group_segments_by_value(&updates.rgb) {
+            client.set_segment_rgb(info, &segments, rgb).await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn device_list_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
         // TODO: some plumbing to maintain offline scene controls for preferred-LAN control
         if let Some(client) = self.get_platform_client().await {
@@ -595,6 +1956,11 @@ impl State {
             }
         }
 
+        if self.is_lan_only().await {
+            // The scene catalog comes from Govee's servers
+            return Ok(vec![]);
+        }
+
         if let Ok(categories) = GoveeUndocumentedApi::get_scenes_for_device(&device.sku).await {
             let mut names = vec![];
             for cat in categories {
@@ -634,13 +2000,62 @@ impl State {
         anyhow::bail!("Unable to set temperature for {device}");
     }
 
+    /// Applies a snapshot, a device state saved in the Govee app.
+    /// Only the Platform API can apply a snapshot: neither a list of
+    /// snapshots from the undocumented API nor a command to apply one
+    /// via AWS IoT is known. So a snapshot can't be applied to a device
+    /// that avoids the Platform API, or whose control order leaves it out.
+    async fn device_apply_snapshot(
+        self: &Arc<Self>,
+        device: &Device,
+        scene: &str,
+    ) -> anyhow::Result<()> {
+        if !device.control_order().contains(&Transport::Platform)
+            || self.platform_control_for(device).await.is_none()
+        {
+            anyhow::bail!(
+                "Unable to apply {scene} to {device}: snapshots can only be applied \
+                 via the Platform API, which isn't used for this device"
+            );
+        }
+
+        self.control_in_order(device, "snapshot", |transport| async move {
+            match transport {
+                Transport::Lan | Transport::Iot => return Ok(false),
+                Transport::Platform => {
+                    let Some((client, info)) = self.platform_control_for(device).await else {
+                        return Ok(false);
+                    };
+                    log::info!("Using Platform API to set {device} to {scene}");
+                    client.set_scene_by_name(info, scene).await?;
+                }
+            }
+            // A snapshot restores a saved device state rather than
+            // running an effect, so there is no scene to report
+            self.device_mut(&device.sku, &device.id)
+                .await
+                .set_active_scene(None);
+            Ok(true)
+        })
+        .await
+    }
+
     pub async fn device_set_scene(
         self: &Arc<Self>,
         device: &Device,
         scene: &str,
     ) -> anyhow::Result<()> {
+        if is_snapshot_scene_name(scene) {
+            return self.device_apply_snapshot(device, scene).await;
+        }
+
         // TODO: some plumbing to maintain offline scene controls for preferred-LAN control
-        let avoid_platform_api = device.avoid_platform_api();
+        // While we are rate limited, use the LAN API if we can rather
+        // than failing outright
+        let avoid_platform_api = device.avoid_platform_api()
+            || (device.responsive_lan_device().is_some()
+                && (device.prefers_lan_control()
+                    || self.platform_api_rate_limited_until().await.is_some()));
 
         if !avoid_platform_api {
             if let Some(client) = self.get_platform_client().await {
@@ -655,7 +2070,7 @@ impl State {
             }
         }
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some(lan_dev) = device.responsive_lan_device() {
             log::info!("Using LAN API to set {device} to scene {scene}");
             lan_dev.set_scene_by_name(scene).await?;
 
@@ -668,16 +2083,162 @@ impl State {
         anyhow::bail!("Unable to set scene for {device}");
     }
 
-    // Take care not to call this while you hold a mutable device
-    // reference, as that will deadlock!
+    /// Records the scene that the device reports as active, so that a
+    /// scene activated via the Govee app or a one-click is reflected
+    /// in hass. The name is resolved from the cached scene list; a scene
+    /// that isn't in the list is named after its code, and the entities
+    /// of the device are re-published so that it is one of the options.
+    pub async fn update_reported_scene(self: &Arc<Self>, device_id: &str) -> anyhow::Result<()> {
+        let Some(device) = self.device_by_id(device_id).await else {
+            return Ok(());
+        };
+        let Some((instance, value)) = self
+            .device_mut(&device.sku, &device.id)
+            .await
+            .take_reported_scene_change()
+        else {
+            return Ok(());
+        };
+
+        let known = match (self.get_platform_client().await, &device.http_device_info) {
+            (Some(client), Some(info)) => {
+                let caps = client
+                    .get_scene_caps(info)
+                    .await
+                    .context("update_reported_scene: get_scene_caps")?;
+                scene_name_by_value(&caps, &instance, &value)
+            }
+            _ => None,
+        };
+        let is_known = known.is_some();
+        let name = known.unwrap_or_else(|| unknown_scene_name(&value));
+        device_log!(
+            log::Level::Info,
+            &device.id,
+            "{device} reports that scene {name} is active"
+        );
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_active_scene(Some(&name));
+
+        if !is_known {
+            if let Some(hass) = self.get_hass_client().await {
+                let device = self.device_by_id(device_id).await.expect("device to exist");
+                hass.republish_device(&device, self)
+                    .await
+                    .context("hass.republish_device")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Publishes a momentary event (such as a button press) that was
+    /// reported for a device.
+    /// Take care not to call this while you hold a mutable device
+    /// reference, as that will deadlock!
+    pub async fn notify_of_device_event(
+        self: &Arc<Self>,
+        device_id: &str,
+        instance: &str,
+        event_type: &str,
+    ) -> anyhow::Result<()> {
+        let Some(canonical_device) = self.device_by_id(device_id).await else {
+            anyhow::bail!("cannot find device {device_id}!?");
+        };
+
+        if let Some(hass) = self.get_hass_client().await {
+            publish_device_event(&hass, &canonical_device, instance, event_type).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publishes the state of the device to hass. Bursts of changes,
+    /// such as the several capability updates that follow a scene
+    /// change, are coalesced: the first change starts the debounce
+    /// window, and the state is published once at the end of it.
+    /// Take care not to call this while you hold a mutable device
+    /// reference, as that will deadlock!
     pub async fn notify_of_state_change(self: &Arc<Self>, device_id: &str) -> anyhow::Result<()> {
-        let Some(canonical_device) = self.device_by_id(&device_id).await else {
+        let debounce = *self.state_debounce.lock().await;
+        if debounce.is_zero() {
+            return self.publish_state_change(device_id).await;
+        }
+
+        if self.device_by_id(device_id).await.is_none() {
+            anyhow::bail!("cannot find device {device_id}!?");
+        }
+
+        let state = self.clone();
+        let id = device_id.to_string();
+        self.pending_state_notifications
+            .schedule(device_id, debounce, move || async move {
+                if let Err(err) = state.publish_state_change(&id).await {
+                    log::error!("publish_state_change for {id}: {err:#}");
+                }
+            })
+            .await;
+        Ok(())
+    }
+
+    /// Publishes the state of the device right away, rather than
+    /// after the debounce window of notify_of_state_change
+    pub async fn publish_state_change(self: &Arc<Self>, device_id: &str) -> anyhow::Result<()> {
+        let Some(canonical_device) = self.device_by_id(device_id).await else {
             anyhow::bail!("cannot find device {device_id}!?");
         };
 
+        self.report_device_availability(&canonical_device, false)
+            .await?;
+        self.persist_device_state(&canonical_device).await;
+
         if let Some(hass) = self.get_hass_client().await {
             hass.advise_hass_of_light_state(&canonical_device, self)
                 .await?;
+            hass.advise_hass_of_room_state(&canonical_device, self)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publishes the availability of the device to its availability
+    /// topic if it has changed since it was last reported, or
+    /// unconditionally if `force` is true.
+    pub async fn report_device_availability(
+        self: &Arc<Self>,
+        device: &Device,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let available = device.is_available(chrono::Utc::now());
+        let prior = self
+            .device_availability
+            .lock()
+            .await
+            .insert(device.id.to_string(), available);
+
+        if prior.unwrap_or(true) != available {
+            log::info!(
+                "{device} is now {}",
+                if available {
+                    "available"
+                } else {
+                    "unavailable"
+                }
+            );
+        }
+
+        if !force && prior == Some(available) {
+            return Ok(());
+        }
+
+        if let Some(hass) = self.get_hass_client().await {
+            hass.publish_transient(
+                device_availability_topic(device),
+                if available { "online" } else { "offline" },
+            )
+            .await
+            .context("report_device_availability")?;
         }
 
         Ok(())
@@ -689,3 +2250,413 @@ pub fn sort_and_dedup_scenes(mut scenes: Vec<String>) -> Vec<String> {
     scenes.dedup();
     scenes
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn group_segments() {
+        let updates: BTreeMap<u32, u8> = [(0, 50), (1, 50), (2, 0), (5, 50)].into_iter().collect();
+        k9::assert_equal!(
+            group_segments_by_value(&updates),
+            [(0, vec![2]), (50, vec![0, 1, 5])].into_iter().collect()
+        );
+    }
+
+    #[tokio::test]
+    async fn control_fallthrough() {
+        let state = State::new();
+        let device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:42:2A");
+        let tried = std::sync::Mutex::new(vec![]);
+
+        // A failure to send moves on to the next transport
+        state
+            .control_in_order(&device, "power state", |transport| {
+                tried.lock().unwrap().push(transport);
+                async move {
+                    match transport {
+                        Transport::Lan => anyhow::bail!("send failed"),
+                        _ => Ok(true),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        k9::assert_equal!(
+            std::mem::take(&mut *tried.lock().unwrap()),
+            vec![Transport::Lan, Transport::Iot]
+        );
+
+        // Once sent, a failure to confirm doesn't send it again
+        let err = state
+            .control_in_order(&device, "power state", |transport| {
+                tried.lock().unwrap().push(transport);
+                async move {
+                    match transport {
+                        Transport::Lan => {
+                            Err(Unconfirmed(anyhow::anyhow!("timed out polling")).into())
+                        }
+                        _ => Ok(true),
+                    }
+                }
+            })
+            .await
+            .unwrap_err();
+        k9::assert_equal!(*tried.lock().unwrap(), vec![Transport::Lan]);
+        k9::assert_equal!(
+            format!("{err:#}"),
+            "setting power state for H6072_422A (AA:BB:CC:DD:EE:FF:42:2A H6072) via lan: \
+             sent, but not confirmed: timed out polling"
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_interval_overrides() {
+        let state = State::new();
+        state
+            .set_poll_intervals(vec![
+                ("H5179".to_string(), chrono::Duration::seconds(30)),
+                (
+                    "aa:bb:cc:dd:ee:ff:00:11".to_string(),
+                    chrono::Duration::seconds(0),
+                ),
+            ])
+            .await;
+
+        let device = Device::new("H5179", "AA:BB:CC:DD:EE:FF:00:11");
+        k9::assert_equal!(
+            state.poll_interval_for(&device).await,
+            chrono::Duration::seconds(0)
+        );
+        let device = Device::new("H5179", "AA:BB:CC:DD:EE:FF:00:22");
+        k9::assert_equal!(
+            state.poll_interval_for(&device).await,
+            chrono::Duration::seconds(30)
+        );
+        let device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:00:33");
+        k9::assert_equal!(
+            state.poll_interval_for(&device).await,
+            device.preferred_poll_interval()
+        );
+        k9::assert_equal!(
+            state.shortest_poll_interval().await,
+            Some(chrono::Duration::seconds(30))
+        );
+    }
+
+    #[tokio::test]
+    async fn ble_only_devices() {
+        let state = Arc::new(State::new());
+        let reading = BleReading {
+            temperature: -5.2,
+            humidity: 61.3,
+            battery: 64,
+        };
+
+        assert!(state
+            .ingest_ble_advertisement("a4:c1:38:11:22:33", "H5075", reading)
+            .await
+            .unwrap());
+        let device = state.device_by_id("A4:C1:38:11:22:33").await.unwrap();
+        k9::assert_equal!(device.sku, "H5075");
+        k9::assert_equal!(device.device_type(), DeviceType::Thermometer);
+        k9::assert_equal!(
+            device
+                .unlisted_sensor_capabilities()
+                .into_iter()
+                .map(|cap| cap.instance)
+                .collect::<Vec<_>>(),
+            vec!["sensorTemperature", "sensorHumidity"]
+        );
+        k9::assert_equal!(device.battery_percent(), Some(64));
+
+        // It goes unavailable once it stops advertising
+        let now = chrono::Utc::now();
+        assert!(device.is_available(now));
+        let later = now + chrono::Duration::minutes(11);
+        k9::assert_equal!(device.reachability(later).ble, Some(false));
+        assert!(!device.is_available(later));
+
+        // Only the allow-listed sensors are picked up
+        state
+            .set_ble_allow_list(vec!["A4:C1:38:11:22:33".to_string()])
+            .await;
+        assert!(!state
+            .ingest_ble_advertisement("A4:C1:38:44:55:66", "H5075", reading)
+            .await
+            .unwrap());
+        assert!(state.device_by_id("A4:C1:38:44:55:66").await.is_none());
+        assert!(state
+            .ingest_ble_advertisement("A4:C1:38:11:22:33", "H5075", reading)
+            .await
+            .unwrap());
+        k9::assert_equal!(state.devices().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ble_gateway_paired_sensor() {
+        let state = Arc::new(State::new());
+        // The id of a gateway paired sensor ends with its MAC
+        drop(state.device_mut("H5075", "1A:2B:A4:C1:38:11:22:33").await);
+        state
+            .set_ble_allow_list(vec!["A4:C1:38:11:22:33".to_string()])
+            .await;
+
+        let reading = BleReading {
+            temperature: 22.5,
+            humidity: 45.6,
+            battery: 87,
+        };
+        assert!(state
+            .ingest_ble_advertisement("a4:c1:38:11:22:33", "H5075", reading)
+            .await
+            .unwrap());
+
+        // The reading goes to the existing device, rather than to a new one
+        k9::assert_equal!(state.devices().await.len(), 1);
+        let device = state.device_by_id("1A:2B:A4:C1:38:11:22:33").await.unwrap();
+        k9::assert_equal!(device.ble_reading, Some(reading));
+        k9::assert_equal!(device.battery_percent(), Some(87));
+    }
+
+    #[tokio::test]
+    async fn undoc_rooms() {
+        use crate::hass_mqtt::base::Device as HassDevice;
+
+        let list = || -> DevicesResponse {
+            crate::platform_api::from_json(include_str!("../../test-data/undoc-device-list.json"))
+                .unwrap()
+        };
+        let state = State::new();
+        let updated = state.apply_undoc_device_list(list()).await;
+        k9::assert_equal!(
+            updated.contains(&("47:13:CF:00:00:00:00:25".to_string(), false)),
+            true
+        );
+
+        let suggested_area = |device: Device| HassDevice::for_device(&device).suggested_area;
+        let bedroom = state.device_by_id("47:13:CF:00:00:00:00:25").await.unwrap();
+        k9::assert_equal!(suggested_area(bedroom), Some("Bedroom".to_string()));
+
+        let mut renamed = list();
+        renamed.groups[0].group_name = "Primary Bedroom".to_string();
+        let updated = state.apply_undoc_device_list(renamed).await;
+        k9::assert_equal!(
+            updated.contains(&("47:13:CF:00:00:00:00:25".to_string(), true)),
+            true
+        );
+        k9::assert_equal!(
+            updated.contains(&("51:2A:D1:00:00:00:00:93".to_string(), false)),
+            true
+        );
+        let bedroom = state.device_by_id("47:13:CF:00:00:00:00:25").await.unwrap();
+        k9::assert_equal!(suggested_area(bedroom), Some("Primary Bedroom".to_string()));
+
+        let mut unassigned = list();
+        unassigned.groups[0].group_name = " ".to_string();
+        state.apply_undoc_device_list(unassigned).await;
+        let bedroom = state.device_by_id("47:13:CF:00:00:00:00:25").await.unwrap();
+        k9::assert_equal!(suggested_area(bedroom), None);
+    }
+
+    #[tokio::test]
+    async fn rooms_with_lights() {
+        let list = || -> DevicesResponse {
+            crate::platform_api::from_json(include_str!("../../test-data/undoc-device-list.json"))
+                .unwrap()
+        };
+        let state = State::new();
+        state.apply_undoc_device_list(list()).await;
+
+        // Only the bedroom has two lights
+        let rooms = state.rooms_with_lights().await;
+        k9::assert_equal!(rooms.len(), 1);
+        let (bedroom, lights) = &rooms[0];
+        k9::assert_equal!(bedroom.name, "Bedroom");
+        k9::assert_equal!(
+            lights.iter().map(|d| d.id.as_str()).collect::<Vec<_>>(),
+            vec!["02:EC:CF:00:00:00:00:48", "47:13:CF:00:00:00:00:25"]
+        );
+
+        // Moving a light out of the bedroom in the app dissolves it
+        let mut moved = list();
+        let entry = moved
+            .devices
+            .iter_mut()
+            .find(|d| d.device == "02:EC:CF:00:00:00:00:48")
+            .unwrap();
+        entry.group_id = 1003;
+        state.apply_undoc_device_list(moved).await;
+        let rooms = state.rooms_with_lights().await;
+        k9::assert_equal!(rooms.len(), 1);
+        k9::assert_equal!(rooms[0].0.name, "Study");
+    }
+
+    #[tokio::test]
+    async fn gateway_via_device() {
+        use crate::hass_mqtt::base::Device as HassDevice;
+        use crate::service::hass::topic_prefix;
+
+        let list = |paired: bool| -> DevicesResponse {
+            let mut list: DevicesResponse = crate::platform_api::from_json(include_str!(
+                "../../test-data/undoc-device-list-gateway.json"
+            ))
+            .unwrap();
+            if !paired {
+                list.devices[1].device_ext.device_settings.gateway_info = None;
+            }
+            list
+        };
+        let via_device = |device: Device| HassDevice::for_device(&device).via_device;
+        let id = "3C:4D:CF:00:00:00:00:79";
+
+        let state = State::new();
+        state.apply_undoc_device_list(list(true)).await;
+        k9::assert_equal!(
+            via_device(state.device_by_id(id).await.unwrap()),
+            Some(format!("{}-1A2BCF0000000051", topic_prefix()))
+        );
+        k9::assert_equal!(
+            state
+                .device_by_id("1A:2B:CF:00:00:00:00:51")
+                .await
+                .map(|gateway| gateway.sku),
+            Some("H5151".to_string())
+        );
+
+        // Still paired with the same gateway
+        let updated = state.apply_undoc_device_list(list(true)).await;
+        k9::assert_equal!(updated.contains(&(id.to_string(), false)), true);
+
+        // Re-paired directly to wifi
+        let updated = state.apply_undoc_device_list(list(false)).await;
+        k9::assert_equal!(updated.contains(&(id.to_string(), true)), true);
+        k9::assert_equal!(
+            via_device(state.device_by_id(id).await.unwrap()),
+            Some(topic_prefix().to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn calibration_offsets() {
+        use crate::config::DeviceConfig;
+        use crate::temperature::TemperatureUnits;
+
+        let mut list: DevicesResponse =
+            crate::platform_api::from_json(include_str!("../../test-data/undoc-device-list.json"))
+                .unwrap();
+        let settings = &mut list.devices[0].device_ext.device_settings;
+        settings.tem_cali = Some(-150);
+        settings.hum_cali = Some(250);
+
+        let state = State::new();
+        state.apply_undoc_device_list(list).await;
+        let device = state.device_by_id("47:13:CF:00:00:00:00:25").await.unwrap();
+        k9::assert_equal!(device.app_calibration(), (Some(-1.5), Some(2.5)));
+
+        // The app calibration is only used when asked for
+        let config = DeviceConfig {
+            temperature_offset: Some(0.5),
+            ..DeviceConfig::default()
+        };
+        k9::assert_equal!(
+            device.temperature_offset(&config, TemperatureUnits::Celsius),
+            0.5
+        );
+        k9::assert_equal!(device.humidity_offset(&config), 0.);
+
+        let config = DeviceConfig {
+            temperature_offset: Some(0.5),
+            use_app_calibration: Some(true),
+            ..DeviceConfig::default()
+        };
+        k9::assert_equal!(
+            device.temperature_offset(&config, TemperatureUnits::Celsius),
+            -1.0
+        );
+        k9::assert_equal!(
+            device.temperature_offset(&config, TemperatureUnits::Fahrenheit),
+            0.5 - 1.5 * 1.8
+        );
+        k9::assert_equal!(device.humidity_offset(&config), 2.5);
+    }
+
+    #[tokio::test]
+    async fn unchanged_discovery() {
+        let state = State::new();
+        let topic = "homeassistant/light/x/config";
+
+        assert!(!state.is_discovery_config_unchanged(topic, "{}").await);
+        state.record_discovery_topic(topic, true).await;
+        state.record_discovery_config(topic, "{}".to_string()).await;
+        assert!(state.is_discovery_config_unchanged(topic, "{}").await);
+        assert!(
+            !state
+                .is_discovery_config_unchanged(topic, "{\"a\":1}")
+                .await
+        );
+
+        state.forget_discovery_configs().await;
+        assert!(!state.is_discovery_config_unchanged(topic, "{}").await);
+
+        assert!(state.record_platform_device_list_hash("a").await);
+        assert!(!state.record_platform_device_list_hash("a").await);
+        assert!(state.record_platform_device_list_hash("b").await);
+
+        state.set_full_refresh(true).await;
+        state.record_discovery_config(topic, "{}".to_string()).await;
+        assert!(!state.is_discovery_config_unchanged(topic, "{}").await);
+        assert!(state.record_platform_device_list_hash("b").await);
+    }
+
+    #[tokio::test]
+    async fn coalesce_bursts() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // The sleeps below complete as soon as every task is idle
+        tokio::time::pause();
+
+        let coalescer = Coalescer::default();
+        let delay = Duration::from_millis(50);
+        let published: Arc<std::sync::Mutex<Vec<String>>> = Default::default();
+
+        let burst = |topic: &'static str, count: usize| {
+            let published = published.clone();
+            let coalescer = &coalescer;
+            async move {
+                for _ in 0..count {
+                    let published = published.clone();
+                    coalescer
+                        .schedule(topic, delay, move || async move {
+                            published.lock().unwrap().push(topic.to_string());
+                        })
+                        .await;
+                    sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+
+        burst("gv2mqtt/light/a/state", 5).await;
+        burst("gv2mqtt/light/b/state", 2).await;
+        sleep(delay * 3).await;
+
+        let mut topics = published.lock().unwrap().clone();
+        topics.sort();
+        k9::assert_equal!(
+            topics,
+            vec!["gv2mqtt/light/a/state", "gv2mqtt/light/b/state"]
+        );
+
+        // Once published, a further change is published again
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        coalescer
+            .schedule("gv2mqtt/light/a/state", delay, move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .await;
+        sleep(delay * 3).await;
+        k9::assert_equal!(runs.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,366 @@
+use crate::lan_api::{DeviceColor, LanDevice};
+use crate::service::device::{Device, DeviceState};
+use crate::service::state::StateHandle;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tokio::time::MissedTickBehavior;
+
+/// The default rate, in steps per second, at which transitions
+/// are sent to LAN devices
+pub const DEFAULT_TRANSITION_RATE: u32 = 10;
+/// Bounds the number of commands that are sent for a very long
+/// transition; the steps become longer instead
+const MAX_TRANSITION_STEPS: u32 = 600;
+/// The most steps in which a transition is performed for a device
+/// that is controlled via the cloud, as each uses up request quota
+const MAX_CLOUD_TRANSITION_STEPS: u32 = 5;
+
+/// The transitions that are in progress, and what is needed to
+/// undo the effect of a transition to off
+#[derive(Default)]
+pub struct Transitions {
+    pub rate: u32,
+    /// If true, transitions are also performed, in a few coarse
+    /// steps, for devices that are controlled via the cloud
+    pub coarse_cloud: bool,
+    pub tasks: HashMap<String, AbortHandle>,
+    /// The brightness of each device before it was faded out, which
+    /// is restored when it is next turned on
+    pub faded_brightness: HashMap<String, u8>,
+}
+
+/// What Home Assistant asked the light to transition to
+#[derive(Debug, Clone, Default)]
+pub struct TransitionRequest {
+    pub on: bool,
+    pub brightness: Option<u8>,
+    pub color: Option<DeviceColor>,
+    pub kelvin: Option<u32>,
+}
+
+/// The values that are varied over the course of a transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LightLevels {
+    pub brightness: u8,
+    pub color: Option<DeviceColor>,
+    pub kelvin: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub from: LightLevels,
+    pub to: LightLevels,
+    /// The light is currently off, and must be turned on first
+    pub power_on: bool,
+    /// The light is to be turned off once the transition completes
+    pub power_off: bool,
+}
+
+impl Transition {
+    /// Works out the transition from the current state of the light.
+    /// `faded_brightness` is the brightness that the light had before
+    /// it was faded out, if it was.
+    /// Returns None if there is nothing to transition.
+    pub fn new(
+        current: Option<&DeviceState>,
+        request: &TransitionRequest,
+        faded_brightness: Option<u8>,
+    ) -> Option<Self> {
+        let current = current?;
+        let is_on = current.light_on.unwrap_or(current.on);
+
+        let from = LightLevels {
+            brightness: if is_on { current.brightness } else { 0 },
+            color: (current.kelvin == 0).then_some(current.color),
+            kelvin: (current.kelvin != 0).then_some(current.kelvin),
+        };
+
+        if !request.on {
+            if !is_on {
+                return None;
+            }
+            return Some(Self {
+                from,
+                to: LightLevels {
+                    brightness: 0,
+                    ..from
+                },
+                power_on: false,
+                power_off: true,
+            });
+        }
+
+        let brightness = match request.brightness.or(faded_brightness) {
+            Some(brightness) => brightness,
+            None if is_on => current.brightness,
+            None if current.brightness > 0 => current.brightness,
+            None => 100,
+        };
+
+        Some(Self {
+            from,
+            to: LightLevels {
+                brightness,
+                color: request
+                    .color
+                    .or(from.color.filter(|_| request.kelvin.is_none())),
+                kelvin: request
+                    .kelvin
+                    .or(from.kelvin.filter(|_| request.color.is_none())),
+            },
+            power_on: !is_on,
+            power_off: false,
+        })
+    }
+
+    /// Returns the levels at `fraction` of the way through the transition
+    pub fn levels_at(&self, fraction: f64) -> LightLevels {
+        fn lerp(from: f64, to: f64, fraction: f64) -> f64 {
+            (from + (to - from) * fraction).round()
+        }
+        let lerp_u8 = |from: u8, to: u8| lerp(from as f64, to as f64, fraction) as u8;
+
+        LightLevels {
+            brightness: lerp_u8(self.from.brightness, self.to.brightness),
+            color: match (self.from.color, self.to.color) {
+                (Some(from), Some(to)) => Some(DeviceColor {
+                    r: lerp_u8(from.r, to.r),
+                    g: lerp_u8(from.g, to.g),
+                    b: lerp_u8(from.b, to.b),
+                }),
+                (_, to) => to,
+            },
+            kelvin: match (self.from.kelvin, self.to.kelvin) {
+                (Some(from), Some(to)) => Some(lerp(from as f64, to as f64, fraction) as u32),
+                (_, to) => to,
+            },
+        }
+    }
+}
+
+/// Returns the number of steps in which to perform a transition
+/// that lasts `duration`
+fn step_count(duration: Duration, rate: u32) -> u32 {
+    ((duration.as_secs_f64() * rate as f64).round() as u32).clamp(1, MAX_TRANSITION_STEPS)
+}
+
+/// Returns the number of brightness steps in which to perform a
+/// transition via the cloud, with at least `min_gap` between them.
+/// Returns 0 if the transition is too short to be worth dividing up.
+pub fn cloud_step_count(duration: Duration, min_gap: Duration) -> u32 {
+    let steps = if min_gap.is_zero() {
+        MAX_CLOUD_TRANSITION_STEPS
+    } else {
+        (duration.as_secs_f64() / min_gap.as_secs_f64()).floor() as u32
+    };
+    match steps.min(MAX_CLOUD_TRANSITION_STEPS) {
+        0 | 1 => 0,
+        steps => steps,
+    }
+}
+
+/// Performs a transition by sending a series of LAN commands
+pub async fn run_lan_transition(
+    state: &StateHandle,
+    device: &Device,
+    lan_device: &LanDevice,
+    transition: Transition,
+    duration: Duration,
+    rate: u32,
+) -> anyhow::Result<()> {
+    let steps = step_count(duration, rate);
+    let mut ticker = tokio::time::interval(duration / steps);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // The first tick completes immediately
+    ticker.tick().await;
+
+    let mut prior = transition.from;
+    if transition.power_on {
+        lan_device.send_brightness(1).await?;
+        lan_device.send_turn(true).await?;
+        prior.brightness = 1;
+    }
+
+    for step in 1..=steps {
+        ticker.tick().await;
+        let levels = transition.levels_at(step as f64 / steps as f64);
+
+        // A brightness of 0 isn't accepted; turning off follows
+        let brightness = levels.brightness.max(1);
+        if brightness != prior.brightness {
+            lan_device.send_brightness(brightness).await?;
+        }
+        if let Some(color) = levels.color.filter(|c| Some(*c) != prior.color) {
+            lan_device.send_color_rgb(color).await?;
+        } else if let Some(kelvin) = levels.kelvin.filter(|k| Some(*k) != prior.kelvin) {
+            lan_device.send_color_temperature_kelvin(kelvin).await?;
+        }
+
+        prior = LightLevels {
+            brightness,
+            ..levels
+        };
+    }
+
+    if transition.power_off {
+        lan_device.send_turn(false).await?;
+    }
+
+    state.poll_lan_status(device).await
+}
+
+/// Performs a transition for a device that is controlled via the cloud.
+/// Only the brightness is changed gradually; the color, if any, is
+/// changed at the start.
+pub async fn run_cloud_transition(
+    state: &StateHandle,
+    device: &Device,
+    transition: Transition,
+    duration: Duration,
+    steps: u32,
+) -> anyhow::Result<()> {
+    let mut ticker = tokio::time::interval(duration / steps);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let mut prior = transition.from;
+    if transition.power_on {
+        state.device_set_brightness(device, 1).await?;
+        state.device_light_power_on(device, true).await?;
+        prior.brightness = 1;
+    }
+    if let Some(color) = transition.to.color.filter(|c| Some(*c) != prior.color) {
+        state
+            .device_set_color_rgb(device, color.r, color.g, color.b)
+            .await?;
+    } else if let Some(kelvin) = transition.to.kelvin.filter(|k| Some(*k) != prior.kelvin) {
+        state.device_set_color_temperature(device, kelvin).await?;
+    }
+
+    for step in 1..=steps {
+        // The first tick completes immediately
+        ticker.tick().await;
+        let brightness = transition
+            .levels_at(step as f64 / steps as f64)
+            .brightness
+            .max(1);
+        if brightness != prior.brightness {
+            state.device_set_brightness(device, brightness).await?;
+            prior.brightness = brightness;
+        }
+    }
+
+    if transition.power_off {
+        ticker.tick().await;
+        state.device_light_power_on(device, false).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+
+    fn light(on: bool, brightness: u8) -> DeviceState {
+        DeviceState {
+            on,
+            light_on: None,
+            online: None,
+            kelvin: 0,
+            color: DeviceColor { r: 0, g: 0, b: 0 },
+            brightness,
+            scene: None,
+            source: "LAN API",
+            updated: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn transition_levels() {
+        let fade_out =
+            Transition::new(Some(&light(true, 80)), &TransitionRequest::default(), None).unwrap();
+        assert!(fade_out.power_off);
+        k9::assert_equal!(fade_out.levels_at(0.5).brightness, 40);
+        k9::assert_equal!(fade_out.levels_at(1.0).brightness, 0);
+
+        // Already off, so there's nothing to do
+        assert!(
+            Transition::new(Some(&light(false, 80)), &TransitionRequest::default(), None).is_none()
+        );
+
+        // Turning on restores the brightness from before the fade out
+        let fade_in = Transition::new(
+            Some(&light(false, 1)),
+            &TransitionRequest {
+                on: true,
+                ..Default::default()
+            },
+            Some(80),
+        )
+        .unwrap();
+        assert!(fade_in.power_on);
+        k9::assert_equal!(fade_in.from.brightness, 0);
+        k9::assert_equal!(fade_in.to.brightness, 80);
+
+        let recolor = Transition::new(
+            Some(&light(true, 50)),
+            &TransitionRequest {
+                on: true,
+                color: Some(DeviceColor {
+                    r: 200,
+                    g: 100,
+                    b: 0,
+                }),
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        k9::assert_equal!(
+            recolor.levels_at(0.25),
+            LightLevels {
+                brightness: 50,
+                color: Some(DeviceColor { r: 50, g: 25, b: 0 }),
+                kelvin: None,
+            }
+        );
+
+        // Switching from a color to a color temperature can't be
+        // interpolated, so it happens at the first step
+        let warm = Transition::new(
+            Some(&light(true, 50)),
+            &TransitionRequest {
+                on: true,
+                kelvin: Some(2700),
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        k9::assert_equal!(warm.levels_at(0.1).color, None);
+        k9::assert_equal!(warm.levels_at(0.1).kelvin, Some(2700));
+    }
+
+    #[test]
+    fn cloud_steps() {
+        let gap = Duration::from_secs(1);
+        k9::assert_equal!(cloud_step_count(Duration::from_millis(500), gap), 0);
+        k9::assert_equal!(cloud_step_count(Duration::from_millis(1500), gap), 0);
+        k9::assert_equal!(cloud_step_count(Duration::from_secs(3), gap), 3);
+        k9::assert_equal!(
+            cloud_step_count(Duration::from_secs(60), gap),
+            MAX_CLOUD_TRANSITION_STEPS
+        );
+    }
+
+    #[test]
+    fn steps() {
+        k9::assert_equal!(step_count(Duration::from_secs(2), 10), 20);
+        k9::assert_equal!(step_count(Duration::from_millis(10), 10), 1);
+        k9::assert_equal!(
+            step_count(Duration::from_secs(3600), 10),
+            MAX_TRANSITION_STEPS
+        );
+    }
+}
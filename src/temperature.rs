@@ -5,7 +5,7 @@ pub const UNIT_FAHRENHEIT: &str = "°F";
 pub const DEVICE_CLASS_TEMPERATURE: &str = "temperature";
 
 #[allow(unused)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum TemperatureUnits {
     Celsius,
     CelsiusTimes100,
@@ -21,7 +21,7 @@ impl TemperatureUnits {
         }
     }
 
-    fn scale(&self) -> TemperatureScale {
+    pub fn scale(&self) -> TemperatureScale {
         match self {
             Self::Celsius | Self::CelsiusTimes100 => TemperatureScale::Celsius,
             Self::Fahrenheit | Self::FahrenheitTimes100 => TemperatureScale::Fahrenheit,
@@ -81,6 +81,14 @@ impl TemperatureScale {
             Self::Fahrenheit => UNIT_FAHRENHEIT,
         }
     }
+
+    /// The name of the scale as used by the Platform API
+    pub fn platform_unit_name(&self) -> &'static str {
+        match self {
+            Self::Celsius => "Celsius",
+            Self::Fahrenheit => "Fahrenheit",
+        }
+    }
 }
 
 impl FromStr for TemperatureScale {
@@ -141,6 +149,10 @@ impl TemperatureValue {
         self.value
     }
 
+    pub fn unit(&self) -> TemperatureUnits {
+        self.unit
+    }
+
     /// Normalize away scaled temperature units
     pub fn normalize(&self) -> Self {
         let normalized = self.value / self.unit.factor();
@@ -149,7 +161,7 @@ impl TemperatureValue {
 
     pub fn as_unit(&self, unit: TemperatureUnits) -> Self {
         if self.unit == unit {
-            return self.clone();
+            return *self;
         }
 
         let normalized = self.value / self.unit.factor();
@@ -196,7 +208,7 @@ fn atoi<F: FromStr>(input: &str) -> Result<(F, &str), <F as FromStr>::Err> {
     let input = input.trim();
     let i = input
         .find(|c: char| !c.is_numeric() && c != '.')
-        .unwrap_or_else(|| input.len());
+        .unwrap_or(input.len());
     let number = input[..i].parse::<F>()?;
     Ok((number, input[i..].trim()))
 }
@@ -286,4 +298,18 @@ mod test {
             24.
         );
     }
+
+    #[test]
+    fn fahrenheit_round_trip() {
+        // Values are published with two decimal places; a whole
+        // number of degrees Fahrenheit that was converted to Celsius
+        // must be published as exactly that number again
+        for f in -40..=230 {
+            let value = TemperatureValue::with_fahrenheit(f as f64)
+                .as_unit(TemperatureUnits::CelsiusTimes100)
+                .as_unit(TemperatureUnits::Fahrenheit)
+                .value();
+            assert_eq!(format!("{value:.2}"), format!("{f}.00"));
+        }
+    }
 }
@@ -1,5 +1,5 @@
 #![allow(unused)]
-use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
+use crate::cache::{cache_get, load_value, store_value, CacheComputeResult, CacheGetOptions};
 use crate::lan_api::{boolean_int, truthy};
 use crate::opt_env_var;
 use crate::platform_api::{
@@ -10,7 +10,10 @@ use reqwest::Method;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
+use std::future::Future;
+use std::io::{IsTerminal, Write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use uuid::Uuid;
 
@@ -66,7 +69,7 @@ pub fn ms_timestamp() -> String {
         .to_string()
 }
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Clone, Debug)]
 pub struct UndocApiArguments {
     /// The email address you registered with Govee.
     /// If not passed here, it will be read from
@@ -91,6 +94,20 @@ pub struct UndocApiArguments {
     /// Where to find the AWS root CA certificate
     #[arg(long, global = true, default_value = "AmazonRootCA1.pem")]
     pub amazon_root_ca: PathBuf,
+
+    /// The verification code that Govee emailed to you, if your
+    /// account has two-factor authentication enabled.
+    /// If not passed here, it will be read from
+    /// the GOVEE_VERIFICATION_CODE environment variable.
+    #[arg(long, global = true)]
+    pub govee_verification_code: Option<String>,
+
+    /// When a verification code is needed and we are not running
+    /// interactively, wait for the code to be written to this file.
+    /// If not passed here, it will be read from
+    /// the GOVEE_VERIFICATION_CODE_FILE environment variable.
+    #[arg(long, global = true)]
+    pub govee_verification_code_file: Option<PathBuf>,
 }
 
 impl UndocApiArguments {
@@ -126,11 +143,145 @@ impl UndocApiArguments {
         })
     }
 
+    pub fn verification(&self) -> anyhow::Result<Verification> {
+        Ok(Verification {
+            code: match &self.govee_verification_code {
+                Some(code) => Some(code.to_string()),
+                None => opt_env_var("GOVEE_VERIFICATION_CODE")?,
+            },
+            code_file: match &self.govee_verification_code_file {
+                Some(path) => Some(path.clone()),
+                None => opt_env_var("GOVEE_VERIFICATION_CODE_FILE")?,
+            },
+        })
+    }
+
     pub fn api_client(&self) -> anyhow::Result<GoveeUndocumentedApi> {
         let email = self.email()?;
         let password = self.password()?;
-        Ok(GoveeUndocumentedApi::new(email, password))
+        Ok(GoveeUndocumentedApi::new(email, password).with_verification(self.verification()?))
+    }
+}
+
+/// The session is retained for this long after its token expires,
+/// so that its refresh token can be used to renew it
+const SESSION_RETENTION: Duration = Duration::from_secs(86400 * 30);
+
+static LOGIN_COUNT: AtomicU64 = AtomicU64::new(0);
+static REFRESH_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// How many times the account session was established by logging
+/// in, vs. by refreshing the prior session
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccountSessionStats {
+    pub logins: u64,
+    pub refreshes: u64,
+}
+
+pub fn account_session_stats() -> AccountSessionStats {
+    AccountSessionStats {
+        logins: LOGIN_COUNT.load(Ordering::Relaxed),
+        refreshes: REFRESH_COUNT.load(Ordering::Relaxed),
+    }
+}
+
+/// Govee responds to a login with this status when the account
+/// has two-factor authentication enabled and a code is needed
+const VERIFICATION_REQUIRED_STATUS: u64 = 454;
+/// Embedded in the error from a login that needs a verification
+/// code, which is how we recognize it, even once it has been cached
+const VERIFICATION_REQUIRED: &str = "a verification code is required";
+/// How long to wait for a verification code to be written to the
+/// code file. Govee's codes expire after a few minutes anyway.
+const VERIFICATION_CODE_WAIT: Duration = Duration::from_secs(15 * 60);
+
+/// Where to find the code for an account that has two-factor
+/// authentication enabled
+#[derive(Clone, Debug, Default)]
+pub struct Verification {
+    code: Option<String>,
+    code_file: Option<PathBuf>,
+}
+
+impl Verification {
+    /// Obtains the code that Govee emailed to `email`, either from
+    /// the configuration, by prompting for it, or by waiting for it
+    /// to be written to the code file
+    async fn obtain_code(&self, email: &str) -> anyhow::Result<String> {
+        if let Some(code) = &self.code {
+            return Ok(code.trim().to_string());
+        }
+
+        if std::io::stdin().is_terminal() {
+            let email = email.to_string();
+            return tokio::task::spawn_blocking(move || {
+                print!("Enter the verification code that Govee emailed to {email}: ");
+                std::io::stdout().flush()?;
+                let mut code = String::new();
+                std::io::stdin().read_line(&mut code)?;
+                Ok(code.trim().to_string())
+            })
+            .await?;
+        }
+
+        let Some(path) = &self.code_file else {
+            anyhow::bail!(
+                "Govee emailed a verification code to {email}. Supply it via \
+                 --govee-verification-code or $GOVEE_VERIFICATION_CODE and restart, or \
+                 set $GOVEE_VERIFICATION_CODE_FILE so that you can provide codes \
+                 without restarting"
+            );
+        };
+
+        log::warn!(
+            "Govee emailed a verification code to {email}. \
+             Waiting for it to be written to {path:?}"
+        );
+        let deadline = tokio::time::Instant::now() + VERIFICATION_CODE_WAIT;
+        while tokio::time::Instant::now() < deadline {
+            if let Ok(code) = std::fs::read_to_string(path) {
+                let code = code.trim().to_string();
+                if !code.is_empty() {
+                    // The code can only be used once; don't try it again
+                    // the next time that we need to log in
+                    std::fs::remove_file(path).ok();
+                    return Ok(code);
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+        anyhow::bail!("timed out waiting for the verification code to be written to {path:?}");
+    }
+}
+
+/// Renews the `prior` session via `refresh` if it has a refresh token,
+/// only resorting to `login` if it doesn't, or if the refresh was rejected
+async fn renew_session<T, R, RFut, L, LFut>(
+    prior: Option<LoginAccountResponse>,
+    refresh: R,
+    login: L,
+) -> anyhow::Result<T>
+where
+    R: FnOnce(LoginAccountResponse, String) -> RFut,
+    RFut: Future<Output = anyhow::Result<T>>,
+    L: FnOnce() -> LFut,
+    LFut: Future<Output = anyhow::Result<T>>,
+{
+    if let Some(session) = prior {
+        if let Some(refresh_token) = session.refresh_token.clone() {
+            match refresh(session, (*refresh_token).clone()).await {
+                Ok(result) => return Ok(result),
+                Err(err) => {
+                    log::warn!("Unable to refresh the Govee account session: {err:#}");
+                }
+            }
+        }
     }
+    login().await
+}
+
+fn is_verification_required(err: &anyhow::Error) -> bool {
+    format!("{err:#}").contains(VERIFICATION_REQUIRED)
 }
 
 #[derive(Clone)]
@@ -138,6 +289,7 @@ pub struct GoveeUndocumentedApi {
     email: String,
     password: String,
     client_id: String,
+    verification: Verification,
 }
 
 impl GoveeUndocumentedApi {
@@ -150,9 +302,19 @@ impl GoveeUndocumentedApi {
             email,
             password,
             client_id,
+            verification: Verification::default(),
         }
     }
 
+    pub fn with_verification(mut self, verification: Verification) -> Self {
+        self.verification = verification;
+        self
+    }
+
+    pub fn has_credentials(&self, email: &str, password: &str) -> bool {
+        self.email == email && self.password == password
+    }
+
     #[allow(unused)]
     pub async fn get_iot_key(&self, token: &str) -> anyhow::Result<IotKey> {
         cache_get(
@@ -195,11 +357,27 @@ impl GoveeUndocumentedApi {
         .await
     }
 
+    pub fn invalidate_iot_key(&self) {
+        crate::cache::invalidate_key("undoc-api", "iot-key").ok();
+    }
+
     pub fn invalidate_account_login(&self) {
         crate::cache::invalidate_key("undoc-api", "account-info").ok();
     }
 
-    async fn login_account_impl(&self) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
+    async fn login_account_impl(
+        &self,
+        code: Option<&str>,
+    ) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
+        let mut body = serde_json::json!({
+            "email": self.email,
+            "password": self.password,
+            "client": &self.client_id,
+        });
+        if let Some(code) = code {
+            body["code"] = code.into();
+        }
+
         let response = reqwest::Client::builder()
             .timeout(Duration::from_secs(30))
             .build()?
@@ -207,11 +385,7 @@ impl GoveeUndocumentedApi {
                 Method::POST,
                 "https://app2.govee.com/account/rest/account/v1/login",
             )
-            .json(&serde_json::json!({
-                "email": self.email,
-                "password": self.password,
-                "client": &self.client_id,
-            }))
+            .json(&body)
             .send()
             .await?;
 
@@ -220,16 +394,181 @@ impl GoveeUndocumentedApi {
         #[derive(Deserialize, Serialize, Debug)]
         #[allow(non_snake_case, dead_code)]
         struct Response {
-            client: LoginAccountResponse,
+            client: Option<LoginAccountResponse>,
             message: String,
             status: u64,
         }
 
-        let ttl = Duration::from_secs(resp.client.token_expire_cycle as u64);
-        Ok(CacheComputeResult::WithTtl(resp.client, ttl))
+        match (resp.client, code) {
+            (Some(client), _) => {
+                LOGIN_COUNT.fetch_add(1, Ordering::Relaxed);
+                log::info!(
+                    "Logged in to the Govee account {:?}",
+                    account_session_stats()
+                );
+                self.remember_session(client)
+            }
+            (None, Some(_)) => anyhow::bail!(
+                "The verification code was not accepted; it may be wrong or have expired, \
+                 in which case a new one is needed. Govee said: {} (status {})",
+                resp.message,
+                resp.status
+            ),
+            (None, None) if resp.status == VERIFICATION_REQUIRED_STATUS => {
+                anyhow::bail!("{VERIFICATION_REQUIRED} to log in: {}", resp.message)
+            }
+            (None, None) => {
+                anyhow::bail!("login failed with status {}: {}", resp.status, resp.message)
+            }
+        }
+    }
+
+    /// Persists the session, including its refresh token, as a single
+    /// cache entry, so that it is either entirely updated or not at all
+    fn remember_session(
+        &self,
+        session: LoginAccountResponse,
+    ) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
+        store_value("undoc-api", "account-session", &session, SESSION_RETENTION)?;
+        let ttl = Duration::from_secs(session.token_expire_cycle as u64);
+        Ok(CacheComputeResult::WithTtl(session, ttl))
     }
 
-    pub async fn login_account_cached(&self) -> anyhow::Result<LoginAccountResponse> {
+    /// Renews the prior session via its refresh token, the same way
+    /// that the app does, rather than logging in again
+    async fn refresh_account_session(
+        &self,
+        session: LoginAccountResponse,
+        refresh_token: &str,
+    ) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?
+            .request(
+                Method::POST,
+                "https://app2.govee.com/account/rest/v1/user/refresh-tokens",
+            )
+            .header("Authorization", format!("Bearer {}", *session.token))
+            .header("appVersion", APP_VERSION)
+            .header("clientId", &self.client_id)
+            .header("clientType", "1")
+            .header("iotVersion", "0")
+            .header("timestamp", ms_timestamp())
+            .header("User-Agent", user_agent())
+            .json(&serde_json::json!({
+                "refreshToken": refresh_token,
+            }))
+            .send()
+            .await?;
+
+        #[derive(Deserialize, Debug)]
+        #[serde(rename_all = "camelCase")]
+        struct Tokens {
+            token: Redacted<String>,
+            refresh_token: Option<Redacted<String>>,
+            token_expire_cycle: Option<u32>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Response {
+            data: Option<Tokens>,
+            message: String,
+            status: u64,
+        }
+
+        let resp: Response = http_response_body(response).await?;
+        let Some(tokens) = resp.data else {
+            anyhow::bail!(
+                "refresh was rejected with status {}: {}",
+                resp.status,
+                resp.message
+            );
+        };
+
+        REFRESH_COUNT.fetch_add(1, Ordering::Relaxed);
+        log::info!(
+            "Refreshed the Govee account session {:?}",
+            account_session_stats()
+        );
+        self.remember_session(LoginAccountResponse {
+            token: tokens.token,
+            refresh_token: tokens.refresh_token.or(session.refresh_token.clone()),
+            token_expire_cycle: tokens
+                .token_expire_cycle
+                .unwrap_or(session.token_expire_cycle),
+            ..session
+        })
+    }
+
+    /// Establishes a new session, refreshing the prior session for
+    /// this account if we have one, and logging in otherwise
+    async fn renew_account_session(
+        &self,
+    ) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
+        let prior = load_value::<LoginAccountResponse>("undoc-api", "account-session")
+            .unwrap_or_default()
+            .filter(|session| *session.client == self.client_id);
+        renew_session(
+            prior,
+            |session, refresh_token| async move {
+                self.refresh_account_session(session, &refresh_token).await
+            },
+            || self.login_account_impl(None),
+        )
+        .await
+    }
+
+    /// Asks Govee to email a verification code for the account
+    async fn request_verification_code(&self) -> anyhow::Result<()> {
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?
+            .request(
+                Method::POST,
+                "https://app2.govee.com/account/rest/account/v1/verification",
+            )
+            .json(&serde_json::json!({
+                "type": 8,
+                "email": self.email,
+            }))
+            .send()
+            .await?;
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Response {
+            message: String,
+            status: u64,
+        }
+
+        let resp: Response = http_response_body(response).await?;
+        if resp.status != 200 {
+            anyhow::bail!(
+                "requesting a verification code failed with status {}: {}",
+                resp.status,
+                resp.message
+            );
+        }
+        Ok(())
+    }
+
+    /// Logs in with the verification code, once Govee has told us
+    /// that the account needs one
+    async fn login_account_with_code(
+        &self,
+    ) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
+        if self.verification.code.is_none() {
+            self.request_verification_code().await?;
+        }
+        let code = self.verification.obtain_code(&self.email).await?;
+        self.login_account_impl(Some(&code)).await
+    }
+
+    async fn login_account_cached_impl(
+        &self,
+        login: impl Future<Output = anyhow::Result<CacheComputeResult<LoginAccountResponse>>>,
+    ) -> anyhow::Result<LoginAccountResponse> {
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
@@ -239,18 +578,52 @@ impl GoveeUndocumentedApi {
                 negative_ttl: FIFTEEN_MINS,
                 allow_stale: false,
             },
-            async { self.login_account_impl().await },
+            login,
         )
         .await
     }
 
-    #[allow(dead_code)]
+    pub async fn login_account_cached(&self) -> anyhow::Result<LoginAccountResponse> {
+        match self
+            .login_account_cached_impl(self.renew_account_session())
+            .await
+        {
+            Err(err) if is_verification_required(&err) => {
+                log::info!("The Govee account has two-factor authentication enabled");
+                self.invalidate_account_login();
+                self.login_account_cached_impl(self.login_account_with_code())
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    /// Logs in without consulting the cache. The new session replaces
+    /// the cached session, so that an account with two-factor
+    /// authentication doesn't need another code until it expires.
     pub async fn login_account(&self) -> anyhow::Result<LoginAccountResponse> {
-        let value = self.login_account_impl().await?;
-        Ok(value.into_inner())
+        let result = match self.login_account_impl(None).await {
+            Err(err) if is_verification_required(&err) => self.login_account_with_code().await?,
+            result => result?,
+        };
+        self.invalidate_account_login();
+        self.login_account_cached_impl(async { Ok(result) }).await
     }
 
     pub async fn get_device_list(&self, token: &str) -> anyhow::Result<DevicesResponse> {
+        let response = self.request_device_list(token).await?;
+        let resp: DevicesResponse = http_response_body(response).await?;
+
+        Ok(resp)
+    }
+
+    /// Returns the device list without interpreting it, for diagnostics
+    pub async fn get_device_list_json(&self, token: &str) -> anyhow::Result<JsonValue> {
+        let response = self.request_device_list(token).await?;
+        http_response_body(response).await
+    }
+
+    async fn request_device_list(&self, token: &str) -> anyhow::Result<reqwest::Response> {
         let response = reqwest::Client::builder()
             .timeout(Duration::from_secs(30))
             .build()?
@@ -272,9 +645,7 @@ impl GoveeUndocumentedApi {
             self.invalidate_account_login();
         }
 
-        let resp: DevicesResponse = http_response_body(response).await?;
-
-        Ok(resp)
+        Ok(response)
     }
 
     pub fn invalidate_community_login(&self) {
@@ -339,6 +710,12 @@ impl GoveeUndocumentedApi {
         .await
     }
 
+    /// Discards the cached scene metadata for sku, so that it is
+    /// fetched when next requested
+    pub fn invalidate_scenes_for_device(sku: &str) -> anyhow::Result<()> {
+        crate::cache::invalidate_key("undoc-api", &format!("scenes-{sku}"))
+    }
+
     pub async fn get_scenes_for_device(sku: &str) -> anyhow::Result<Vec<LightEffectCategory>> {
         let key = format!("scenes-{sku}");
 
@@ -384,7 +761,7 @@ impl GoveeUndocumentedApi {
 
         for c in catalog {
             for s in c.scenes {
-                if let Some(param_id) = s.light_effects.get(0).map(|e| e.scence_param_id) {
+                if let Some(param_id) = s.light_effects.first().map(|e| e.scence_param_id) {
                     options.push(EnumOption {
                         name: s.scene_name,
                         value: json!({
@@ -461,6 +838,7 @@ impl GoveeUndocumentedApi {
                 }
 
                 let name = format!("One-Click: {}: {}", group.name, oc.name);
+                let id = oc.rule_id();
 
                 let mut entries = vec![];
                 for rule in oc.iot_rules {
@@ -470,7 +848,7 @@ impl GoveeUndocumentedApi {
                     }
                 }
 
-                result.push(ParsedOneClick { name, entries });
+                result.push(ParsedOneClick { id, name, entries });
             }
         }
         Ok(result)
@@ -479,6 +857,7 @@ impl GoveeUndocumentedApi {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ParsedOneClick {
+    pub id: String,
     pub name: String,
     pub entries: Vec<ParsedOneClickEntry>,
 }
@@ -631,6 +1010,22 @@ pub struct OneClick {
     pub iot_rules: Vec<OneClickIotRule>,
 }
 
+impl OneClick {
+    /// Returns a stable identifier for the rule. The response has no
+    /// explicit id field: tap-to-run rules are identified by their
+    /// siri engine id, while preset rules such as "Get Home" only
+    /// have a preset id.
+    pub fn rule_id(&self) -> String {
+        if self.siri_engine_id != 0 {
+            self.siri_engine_id.to_string()
+        } else if self.preset_id != 0 {
+            format!("preset-{}", self.preset_id)
+        } else {
+            crate::service::hass::topic_safe_string(&self.name)
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
@@ -765,6 +1160,20 @@ pub struct DeviceEntry {
 }
 
 impl DeviceEntry {
+    /// Returns the firmware version that the device is running,
+    /// if it reports one
+    pub fn firmware_version(&self) -> Option<&str> {
+        Some(self.version_soft.as_str())
+            .filter(|v| !v.is_empty())
+            .or_else(|| {
+                self.device_ext
+                    .device_settings
+                    .version_soft
+                    .as_deref()
+                    .filter(|v| !v.is_empty())
+            })
+    }
+
     pub fn device_topic(&self) -> anyhow::Result<&str> {
         self.device_ext
             .device_settings
@@ -862,6 +1271,18 @@ pub struct DeviceSettings {
     /// eg: Glide Hexa. Value is base64 encoded data
     pub shapes: Option<String>,
     pub support_ble_broad_v3: Option<bool>,
+
+    /// Present for sensors that reach the cloud via a
+    /// bluetooth gateway, such as the H5151
+    pub gateway_info: Option<GatewayInfo>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayInfo {
+    pub sku: Option<String>,
+    /// The device id of the gateway
+    pub device: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -929,6 +1350,82 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn firmware_version() {
+        let mut resp: DevicesResponse =
+            from_json(include_str!("../test-data/undoc-device-list.json")).unwrap();
+        let entry = &mut resp.devices[0];
+        k9::assert_equal!(entry.firmware_version(), Some("2.04.05"));
+
+        entry.version_soft.clear();
+        entry.device_ext.device_settings.version_soft = None;
+        k9::assert_equal!(entry.firmware_version(), None);
+    }
+
+    #[tokio::test]
+    async fn verification_code() {
+        // The marker survives being cached and reloaded as text
+        let err = anyhow::anyhow!("{VERIFICATION_REQUIRED} to log in: Please verify");
+        assert!(is_verification_required(&anyhow::anyhow!("{err:#}")));
+        assert!(!is_verification_required(&anyhow::anyhow!(
+            "login failed with status 400: Incorrect password"
+        )));
+
+        let configured = Verification {
+            code: Some(" 654321 ".to_string()),
+            code_file: None,
+        };
+        k9::assert_equal!(
+            configured.obtain_code("me@example.com").await.unwrap(),
+            "654321"
+        );
+    }
+
+    #[tokio::test]
+    async fn session_renewal() {
+        let session = |refresh_token: Option<&str>| -> LoginAccountResponse {
+            serde_json::from_value(serde_json::json!({
+                "A": "a",
+                "B": "b",
+                "accountId": 1,
+                "client": "client",
+                "isSavvyUser": false,
+                "refreshToken": refresh_token,
+                "token": "token",
+                "tokenExpireCycle": 3600,
+                "topic": "topic",
+            }))
+            .unwrap()
+        };
+        let renew = |prior: Option<LoginAccountResponse>, accept_refresh: bool| async move {
+            renew_session(
+                prior,
+                |_, refresh_token| async move {
+                    if accept_refresh {
+                        Ok(format!("refreshed via {refresh_token}"))
+                    } else {
+                        anyhow::bail!("refresh was rejected with status 401")
+                    }
+                },
+                || async { Ok("logged in".to_string()) },
+            )
+            .await
+            .unwrap()
+        };
+
+        k9::assert_equal!(
+            renew(Some(session(Some("refresh-me"))), true).await,
+            "refreshed via refresh-me"
+        );
+        // We only log in again if the refresh is rejected, or can't be tried
+        k9::assert_equal!(
+            renew(Some(session(Some("refresh-me"))), false).await,
+            "logged in"
+        );
+        k9::assert_equal!(renew(Some(session(None)), true).await, "logged in");
+        k9::assert_equal!(renew(None, true).await, "logged in");
+    }
+
     #[test]
     fn get_one_click() {
         let resp: OneClickResponse =
@@ -936,6 +1433,19 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn one_click_rule_ids() {
+        let resp: OneClickResponse =
+            from_json(include_str!("../test-data/undoc-one-click.json")).unwrap();
+        let ids: Vec<String> = resp
+            .data
+            .components
+            .iter()
+            .flat_map(|c| c.one_clicks.iter().map(|oc| oc.rule_id()))
+            .collect();
+        k9::assert_equal!(ids, vec!["1234".to_string(), "preset-1101".to_string()]);
+    }
+
     #[test]
     fn issue36() {
         let resp: OneClickResponse =
@@ -962,4 +1472,21 @@ mod test {
             from_json(include_str!("../test-data/undoc-device-list-issue-21.json")).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
+
+    #[test]
+    fn gateway_info() {
+        let resp: DevicesResponse =
+            from_json(include_str!("../test-data/undoc-device-list-gateway.json")).unwrap();
+        k9::assert_equal!(
+            resp.devices[0].device_ext.device_settings.gateway_info,
+            None
+        );
+        k9::assert_equal!(
+            resp.devices[1].device_ext.device_settings.gateway_info,
+            Some(GatewayInfo {
+                sku: Some("H5151".to_string()),
+                device: Some("1A:2B:CF:00:00:00:00:51".to_string()),
+            })
+        );
+    }
 }